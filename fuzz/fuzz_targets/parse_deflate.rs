@@ -0,0 +1,29 @@
+//! Parses arbitrary input as a deflate stream whose size was declared by a header, which must
+//! fail cleanly instead of panicking or reading past the declared size. Run with
+//! `cargo fuzz run parse_deflate` from the root of the repo.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use preflate_rs::deflate_parser::DeflateParser;
+
+fuzz_target!(|data: &[u8]| {
+    // the first byte picks how much of the rest the header claims, so that streams that are
+    // cut off by the declared size get fuzzed as well
+    let Some((&declared, data)) = data.split_first() else {
+        return;
+    };
+    let compressed_size = data.len() as u64 * u64::from(declared) / 255;
+
+    let mut parser = DeflateParser::with_compressed_size(data, compressed_size);
+    let mut plain_text_len = 0;
+    while let Ok(Some(block)) = parser.next_block() {
+        assert!(parser.bit_position() <= compressed_size * 8);
+        plain_text_len += block.uncompressed_len as usize;
+
+        // the parser doesn't have a limit on the plain text
+        if plain_text_len > 64 << 20 {
+            break;
+        }
+    }
+});
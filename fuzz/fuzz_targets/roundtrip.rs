@@ -0,0 +1,19 @@
+//! Decompresses and recompresses arbitrary input and fails on any stream that is accepted but
+//! can't be recreated exactly. Run with `cargo fuzz run roundtrip` from the root of the repo.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use preflate_rs::{preflate_config::PreflateConfig, roundtrip_check_with_config, RoundtripOutcome};
+
+fuzz_target!(|data: &[u8]| {
+    // small inputs can expand to gigabytes, which would only report running out of memory
+    let config = PreflateConfig {
+        max_plaintext_size: 64 << 20,
+        ..PreflateConfig::default()
+    };
+
+    if let RoundtripOutcome::Mismatch(e) = roundtrip_check_with_config(data, &config) {
+        panic!("stream didn't roundtrip: {:?}", e);
+    }
+});
@@ -0,0 +1,327 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Recording of every action of the predictor (including the verify states with the checksums
+//! of the predictor state) to a file, and replaying such a recording while recompressing. When
+//! the recompression doesn't give back the original stream, the replay stops at the first
+//! action that the recreated stream asks for differently than it was recorded, and reports
+//! which block and token that was.
+//!
+//! The recording uses the line format of [`crate::corrections_text`], without the original line.
+
+use std::{
+    fmt::Display,
+    io::{BufRead, Write},
+};
+
+use crate::{
+    corrections_text::write_action,
+    match_predictor::ZlibMatchPredictor,
+    preflate_config::PreflateConfig,
+    preflate_error::PreflateError,
+    process::{read_deflate_with_predictor, write_deflate},
+    statistical_codec::{
+        CodecAction, CodecCorrection, CodecMisprediction, CountNonDefaultActions,
+        PredictionDecoder, PredictionEncoder,
+    },
+};
+
+/// writes every action as a line of text as it is encoded
+pub struct ActionLogEncoder<W> {
+    writer: W,
+    line: String,
+    error: Option<std::io::Error>,
+}
+
+impl<W: Write> ActionLogEncoder<W> {
+    pub fn new(writer: W) -> Self {
+        ActionLogEncoder {
+            writer,
+            line: String::new(),
+            error: None,
+        }
+    }
+
+    /// returns the writer, or the first error that writing to it failed with
+    pub fn into_inner(self) -> std::io::Result<W> {
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(self.writer),
+        }
+    }
+
+    fn write(&mut self, action: CodecAction) {
+        if self.error.is_some() {
+            return;
+        }
+
+        self.line.clear();
+        write_action(&mut self.line, &action);
+        if let Err(e) = self.writer.write_all(self.line.as_bytes()) {
+            self.error = Some(e);
+        }
+    }
+}
+
+impl<W: Write> PredictionEncoder for ActionLogEncoder<W> {
+    fn encode_correction(&mut self, action: CodecCorrection, value: u32) {
+        self.write(CodecAction::Correction(action, value));
+    }
+
+    fn encode_bucket_correction(&mut self, action: CodecCorrection, bucket: u8, value: u32) {
+        self.write(CodecAction::BucketCorrection(action, bucket, value));
+    }
+
+    fn encode_misprediction(&mut self, action: CodecMisprediction, value: bool) {
+        self.write(CodecAction::Misprediction(action, value));
+    }
+
+    fn encode_value(&mut self, value: u16, max_bits: u8) {
+        self.write(CodecAction::Value(value, max_bits));
+    }
+
+    fn encode_verify_state(&mut self, message: &'static str, checksum: u64) {
+        self.write(CodecAction::VerifyState(message, checksum));
+    }
+
+    fn finish(&mut self) {
+        if let (None, Err(e)) = (&self.error, self.writer.flush()) {
+            self.error = Some(e);
+        }
+    }
+
+    /// the log isn't entropy coded, so there are no statistics
+    fn statistics(&self) -> CountNonDefaultActions {
+        CountNonDefaultActions::default()
+    }
+}
+
+/// the first action that was asked for differently than it was recorded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// index of the action in the recording
+    pub action_index: usize,
+    /// the block that was being recreated, if the first block had been started
+    pub block: Option<usize>,
+    /// the token of the block that was being recreated, if the tokens had been started
+    pub token: Option<usize>,
+    /// the recorded line, or None if the recording ended before
+    pub recorded: Option<String>,
+    /// the action that was asked for, with 0 in place of the value that it expected back
+    /// (verify states have the checksum of the recreated state)
+    pub requested: String,
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "action {}", self.action_index)?;
+        if let Some(block) = self.block {
+            write!(f, " (block {}", block)?;
+            if let Some(token) = self.token {
+                write!(f, " token {}", token)?;
+            }
+            write!(f, ")")?;
+        }
+        write!(
+            f,
+            ": recorded {}, requested {}",
+            self.recorded.as_deref().unwrap_or("end of recording"),
+            self.requested
+        )
+    }
+}
+
+/// Returns the actions of a recording to the predictor and compares each of them to what the
+/// predictor asks for. After the first difference, everything decodes as 0 or false.
+pub struct ActionReplayDecoder<R> {
+    lines: std::io::Lines<R>,
+    action_index: usize,
+    block: Option<usize>,
+    token: Option<usize>,
+    divergence: Option<Divergence>,
+}
+
+impl<R: BufRead> ActionReplayDecoder<R> {
+    pub fn new(reader: R) -> Self {
+        ActionReplayDecoder {
+            lines: reader.lines(),
+            action_index: 0,
+            block: None,
+            token: None,
+            divergence: None,
+        }
+    }
+
+    /// the first difference between the recording and the recompression, if there was one
+    pub fn divergence(&self) -> Option<&Divergence> {
+        self.divergence.as_ref()
+    }
+
+    /// Takes the next recorded line and compares it with the requested action, of which
+    /// everything but the value has to match. Returns the recorded value.
+    fn replay(&mut self, requested: CodecAction) -> u32 {
+        if self.divergence.is_some() {
+            return 0;
+        }
+
+        if let CodecAction::VerifyState(message, _) = requested {
+            match message {
+                "blocktypestart" => {
+                    self.block = Some(self.block.map_or(0, |b| b + 1));
+                    self.token = None;
+                }
+                "token" => self.token = Some(self.token.map_or(0, |t| t + 1)),
+                _ => {}
+            }
+        }
+
+        let mut requested_line = String::new();
+        write_action(&mut requested_line, &requested);
+        let requested_line = requested_line.trim_end();
+
+        let recorded = self
+            .lines
+            .by_ref()
+            .map_while(Result::ok)
+            .find(|line| !line.trim().is_empty());
+
+        let value = recorded.as_deref().and_then(|recorded| {
+            if matches!(requested, CodecAction::VerifyState(..)) {
+                (recorded.trim() == requested_line).then_some(0)
+            } else {
+                let (recorded_key, value) = split_value(recorded)?;
+                let (requested_key, _) = split_value(requested_line)?;
+                if recorded_key == requested_key {
+                    value.parse().ok()
+                } else {
+                    None
+                }
+            }
+        });
+
+        if value.is_none() {
+            self.divergence = Some(Divergence {
+                action_index: self.action_index,
+                block: self.block,
+                token: self.token,
+                recorded,
+                requested: requested_line.to_string(),
+            });
+        }
+
+        self.action_index += 1;
+        value.unwrap_or(0)
+    }
+}
+
+/// splits a line into the part that the decoder asks for and the value that it gets back
+fn split_value(line: &str) -> Option<(String, &str)> {
+    let fields: Vec<&str> = line.split_ascii_whitespace().collect();
+    match fields.as_slice() {
+        ["value", value, max_bits] => Some((format!("value {}", max_bits), value)),
+        [key @ .., value] if !key.is_empty() => Some((key.join(" "), value)),
+        _ => None,
+    }
+}
+
+impl<R: BufRead> PredictionDecoder for ActionReplayDecoder<R> {
+    fn decode_value(&mut self, max_bits_orig: u8) -> u16 {
+        self.replay(CodecAction::Value(0, max_bits_orig)) as u16
+    }
+
+    fn decode_correction(&mut self, correction: CodecCorrection) -> u32 {
+        self.replay(CodecAction::Correction(correction, 0))
+    }
+
+    fn decode_bucket_correction(&mut self, correction: CodecCorrection, bucket: u8) -> u32 {
+        self.replay(CodecAction::BucketCorrection(correction, bucket, 0))
+    }
+
+    fn decode_misprediction(&mut self, misprediction: CodecMisprediction) -> bool {
+        self.replay(CodecAction::Misprediction(misprediction, false)) != 0
+    }
+
+    fn decode_verify_state(&mut self, message: &'static str, checksum: u64) {
+        self.replay(CodecAction::VerifyState(message, checksum));
+    }
+}
+
+/// Decompresses the stream with prediction and writes every action of the predictor to the
+/// log. Returns the plain text, which is needed to replay the log.
+pub fn record_actions(
+    compressed_data: &[u8],
+    config: &PreflateConfig,
+    log: impl Write,
+) -> Result<Vec<u8>, PreflateError> {
+    let mut encoder = ActionLogEncoder::new(log);
+
+    let (_, _, plain_text, _, _, _) = read_deflate_with_predictor(
+        compressed_data,
+        &mut encoder,
+        0,
+        &ZlibMatchPredictor::default(),
+        config,
+        &mut |_| {},
+        &mut (),
+    )?;
+    encoder.finish();
+    encoder.into_inner()?;
+
+    Ok(plain_text)
+}
+
+/// Recompresses the plain text with the actions of a log written by record_actions. Fails
+/// with a Mismatch that describes the first divergence if the predictor asks for anything
+/// differently than it was recorded.
+pub fn replay_actions(plain_text: &[u8], log: impl BufRead) -> Result<Vec<u8>, PreflateError> {
+    let mut decoder = ActionReplayDecoder::new(log);
+
+    let result = write_deflate(plain_text, &mut decoder);
+    if let Some(divergence) = decoder.divergence() {
+        return Err(PreflateError::Mismatch(anyhow::anyhow!(
+            "replay diverged at {}",
+            divergence
+        )));
+    }
+
+    Ok(result?.0)
+}
+
+#[test]
+fn replay_finds_first_divergence() {
+    let compressed_data = crate::process::read_file("compressed_zlib_level1.deflate");
+
+    let mut log = Vec::new();
+    let plain_text =
+        record_actions(&compressed_data, &PreflateConfig::default(), &mut log).unwrap();
+
+    assert_eq!(
+        replay_actions(&plain_text, &log[..]).unwrap(),
+        compressed_data
+    );
+
+    // change the plain text, which changes the predicted tokens from there on
+    let mut changed = plain_text.clone();
+    changed[plain_text.len() / 2] ^= 0x55;
+
+    let mut decoder = ActionReplayDecoder::new(&log[..]);
+    let _ = write_deflate(&changed, &mut decoder);
+    let divergence = decoder.divergence().unwrap();
+    assert!(divergence.block.is_some());
+    assert!(divergence.recorded.is_some());
+
+    assert!(matches!(
+        replay_actions(&changed, &log[..]),
+        Err(PreflateError::Mismatch(_))
+    ));
+
+    // a log that was cut off ends before the recompression does
+    let cut = &log[..log.len() / 2];
+    let cut = &cut[..cut.iter().rposition(|&b| b == b'\n').unwrap() + 1];
+    let mut decoder = ActionReplayDecoder::new(cut);
+    let _ = write_deflate(&plain_text, &mut decoder);
+    assert_eq!(decoder.divergence().unwrap().recorded, None);
+}
@@ -0,0 +1,145 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{collections::BTreeMap, time::Duration};
+
+use crate::preflate_error::PreflateError;
+
+/// what happened to a single entry of a container
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum EntryOutcome {
+    /// the entry was decompressed and can be recreated from the plain text and corrections
+    Processed,
+    /// the entry was not a valid deflate stream, so it was left untouched
+    Skipped,
+    /// the entry was a valid deflate stream, but we couldn't predict it, so it has to be stored as is
+    Fallback,
+}
+
+impl EntryOutcome {
+    /// classifies the error returned when decompressing an entry
+    pub fn from_error(e: &PreflateError) -> Self {
+        match e {
+            PreflateError::ReadDeflate(_)
+            | PreflateError::ReadBlock(..)
+            | PreflateError::CorruptDeflate { .. }
+            | PreflateError::Truncated(_) => EntryOutcome::Skipped,
+            _ => EntryOutcome::Fallback,
+        }
+    }
+}
+
+/// Aggregates the results of processing all the entries of a container
+/// (for example all the deflate streams in a zip file).
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ArchiveSummary {
+    pub entries_processed: u32,
+    pub entries_skipped: u32,
+    pub entries_fallback: u32,
+
+    /// total size of the compressed data of the processed entries
+    pub total_compressed_bytes: u64,
+
+    /// total size of the plain text of the processed entries
+    pub total_plain_text_bytes: u64,
+
+    /// total size of the correction data needed to recreate the processed entries
+    pub total_correction_bytes: u64,
+
+    /// number of processed entries grouped by the encoder we think produced them
+    pub encoder_mix: BTreeMap<String, u32>,
+
+    /// total time spent processing all the entries
+    pub elapsed: Duration,
+}
+
+impl ArchiveSummary {
+    /// records an entry that was successfully processed
+    pub fn record_processed(
+        &mut self,
+        compressed_size: usize,
+        plain_text_size: usize,
+        correction_size: usize,
+        encoder_label: &str,
+        elapsed: Duration,
+    ) {
+        self.entries_processed += 1;
+        self.total_compressed_bytes += compressed_size as u64;
+        self.total_plain_text_bytes += plain_text_size as u64;
+        self.total_correction_bytes += correction_size as u64;
+        *self
+            .encoder_mix
+            .entry(encoder_label.to_string())
+            .or_default() += 1;
+        self.elapsed += elapsed;
+    }
+
+    /// records an entry that could not be processed
+    pub fn record_failed(&mut self, outcome: EntryOutcome, elapsed: Duration) {
+        match outcome {
+            EntryOutcome::Processed => panic!("use record_processed for processed entries"),
+            EntryOutcome::Skipped => self.entries_skipped += 1,
+            EntryOutcome::Fallback => self.entries_fallback += 1,
+        }
+        self.elapsed += elapsed;
+    }
+
+    /// combines the summary of another container into this one
+    pub fn merge(&mut self, other: &ArchiveSummary) {
+        self.entries_processed += other.entries_processed;
+        self.entries_skipped += other.entries_skipped;
+        self.entries_fallback += other.entries_fallback;
+        self.total_compressed_bytes += other.total_compressed_bytes;
+        self.total_plain_text_bytes += other.total_plain_text_bytes;
+        self.total_correction_bytes += other.total_correction_bytes;
+        for (k, v) in &other.encoder_mix {
+            *self.encoder_mix.entry(k.clone()).or_default() += v;
+        }
+        self.elapsed += other.elapsed;
+    }
+
+    /// total number of entries that were seen
+    pub fn entries_total(&self) -> u32 {
+        self.entries_processed + self.entries_skipped + self.entries_fallback
+    }
+
+    /// serializes the summary as JSON
+    #[cfg(feature = "serde")]
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+#[test]
+fn summary_aggregation() {
+    let mut a = ArchiveSummary::default();
+    a.record_processed(100, 300, 10, "zlib-slow-128", Duration::from_millis(5));
+    a.record_processed(50, 200, 4, "zlib-slow-128", Duration::from_millis(5));
+    a.record_failed(EntryOutcome::Skipped, Duration::from_millis(1));
+
+    let mut b = ArchiveSummary::default();
+    b.record_processed(10, 20, 3, "miniz-fast", Duration::from_millis(2));
+    b.record_failed(EntryOutcome::Fallback, Duration::from_millis(1));
+
+    a.merge(&b);
+
+    assert_eq!(a.entries_total(), 5);
+    assert_eq!(a.entries_processed, 3);
+    assert_eq!(a.total_compressed_bytes, 160);
+    assert_eq!(a.total_correction_bytes, 17);
+    assert_eq!(a.encoder_mix["zlib-slow-128"], 2);
+    assert_eq!(a.encoder_mix["miniz-fast"], 1);
+    assert_eq!(a.elapsed, Duration::from_millis(14));
+
+    #[cfg(feature = "serde")]
+    {
+        let json = a.to_json();
+        let back: ArchiveSummary = serde_json::from_str(&json).unwrap();
+        assert_eq!(a, back);
+    }
+}
@@ -0,0 +1,266 @@
+//! `preflate` expands the deflate data of a file into its plain text and the corrections that are
+//! needed to recreate it, and restores the original file from that. Raw deflate streams, zlib
+//! streams, gzip files and zip archives are supported, the kind is detected from the header.
+//!
+//! The expanded file consists of the magic `PFLX`, the format version (1), the kind of the input
+//! (0 raw deflate, 1 zlib, 2 gzip, 3 zip), the plain text and the corrections (both prefixed
+//! with their length as a little endian u64) and the data that followed the stream in the input.
+
+use std::{
+    fs,
+    io::{Cursor, Read},
+    path::PathBuf,
+};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use clap::{Parser, Subcommand};
+use preflate_rs::{
+    archive_summary::ArchiveSummary,
+    decompress_deflate_stream_with_config, decompress_gzip_stream, decompress_zlib_stream,
+    preflate_config::PreflateConfig,
+    preflate_error::PreflateError,
+    recompress_deflate_stream_with_config, recompress_gzip_stream, recompress_zlib_stream,
+    zip_archive::{expand_zip_archive, restore_zip_archive},
+    DecompressResult,
+};
+
+const MAGIC: &[u8; 4] = b"PFLX";
+const FORMAT_VERSION: u8 = 1;
+
+#[derive(Debug, Parser)]
+#[clap(name = "preflate")]
+struct Preflate {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// expand a deflate, zlib, gzip or zip file
+    Expand { input: PathBuf, output: PathBuf },
+    /// restore the original file from an expanded file
+    Restore { input: PathBuf, output: PathBuf },
+    /// check that the file can be expanded and restored byte for byte
+    Verify { input: PathBuf },
+    /// print how well the file expands and what it was compressed with
+    Stat { input: PathBuf },
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum InputKind {
+    Deflate = 0,
+    Zlib = 1,
+    Gzip = 2,
+    Zip = 3,
+}
+
+impl InputKind {
+    fn detect(data: &[u8]) -> Self {
+        if data.starts_with(b"PK\x03\x04") || data.starts_with(b"PK\x05\x06") {
+            InputKind::Zip
+        } else if data.starts_with(&[0x1f, 0x8b]) {
+            InputKind::Gzip
+        } else if data.len() >= 2
+            && data[0] & 0x0f == 8
+            && (u16::from(data[0]) << 8 | u16::from(data[1])) % 31 == 0
+        {
+            InputKind::Zlib
+        } else {
+            InputKind::Deflate
+        }
+    }
+
+    fn from_byte(b: u8) -> Option<Self> {
+        [
+            InputKind::Deflate,
+            InputKind::Zlib,
+            InputKind::Gzip,
+            InputKind::Zip,
+        ]
+        .into_iter()
+        .find(|&k| k as u8 == b)
+    }
+}
+
+/// the input with its deflate data expanded
+struct Expanded {
+    kind: InputKind,
+    plain_text: Vec<u8>,
+    corrections: Vec<u8>,
+    /// the data after the end of the stream, which is kept as it is
+    trailing: Vec<u8>,
+    /// the details of a single stream
+    result: Option<DecompressResult>,
+    /// the outcome of each of the entries of a zip archive
+    summary: Option<ArchiveSummary>,
+}
+
+impl Expanded {
+    fn expand(data: &[u8], config: &PreflateConfig) -> Result<Self, PreflateError> {
+        let kind = InputKind::detect(data);
+        let result = match kind {
+            InputKind::Zip => {
+                let expanded = expand_zip_archive(data, config)?;
+                return Ok(Expanded {
+                    kind,
+                    plain_text: expanded.plain_text,
+                    corrections: expanded.corrections,
+                    trailing: Vec::new(),
+                    result: None,
+                    summary: Some(expanded.summary),
+                });
+            }
+            InputKind::Gzip => decompress_gzip_stream(data, config)?,
+            InputKind::Zlib => decompress_zlib_stream(data, config)?,
+            InputKind::Deflate => decompress_deflate_stream_with_config(data, config)?,
+        };
+
+        Ok(Expanded {
+            kind,
+            plain_text: result.plain_text.clone(),
+            corrections: result.cabac_encoded.clone(),
+            trailing: data[result.compressed_processed..].to_vec(),
+            result: Some(result),
+            summary: None,
+        })
+    }
+
+    fn restore(&self, config: &PreflateConfig) -> Result<Vec<u8>, PreflateError> {
+        let (plain_text, corrections) = (&self.plain_text, &self.corrections);
+        let mut original = match self.kind {
+            InputKind::Zip => restore_zip_archive(plain_text, corrections, config)?,
+            InputKind::Gzip => recompress_gzip_stream(plain_text, corrections, config)?,
+            InputKind::Zlib => recompress_zlib_stream(plain_text, corrections, config)?,
+            InputKind::Deflate => {
+                recompress_deflate_stream_with_config(plain_text, corrections, config)?
+            }
+        };
+        original.extend_from_slice(&self.trailing);
+        Ok(original)
+    }
+
+    fn write(&self) -> Vec<u8> {
+        let mut output = MAGIC.to_vec();
+        output.push(FORMAT_VERSION);
+        output.push(self.kind as u8);
+        for part in [&self.plain_text, &self.corrections] {
+            output.extend_from_slice(&(part.len() as u64).to_le_bytes());
+            output.extend_from_slice(part);
+        }
+        output.extend_from_slice(&self.trailing);
+        output
+    }
+
+    fn read(data: &[u8]) -> anyhow::Result<Self> {
+        let invalid = || anyhow::anyhow!("not a file written by preflate expand");
+
+        let mut reader = Cursor::new(data);
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic).map_err(|_| invalid())?;
+        if &magic != MAGIC || reader.read_u8()? != FORMAT_VERSION {
+            return Err(invalid());
+        }
+        let kind = InputKind::from_byte(reader.read_u8()?).ok_or_else(invalid)?;
+
+        let mut read_part = || -> anyhow::Result<Vec<u8>> {
+            let len = reader.read_u64::<LittleEndian>()?;
+            let start = reader.position() as usize;
+            let part = data
+                .get(start..)
+                .and_then(|d| d.get(..usize::try_from(len).ok()?))
+                .ok_or_else(invalid)?;
+            reader.set_position(reader.position() + len);
+            Ok(part.to_vec())
+        };
+        let plain_text = read_part()?;
+        let corrections = read_part()?;
+
+        Ok(Expanded {
+            kind,
+            plain_text,
+            corrections,
+            trailing: data[reader.position() as usize..].to_vec(),
+            result: None,
+            summary: None,
+        })
+    }
+}
+
+fn main_with_result() -> anyhow::Result<()> {
+    let args = Preflate::parse();
+    let config = PreflateConfig::default();
+
+    match args.command {
+        Command::Expand { input, output } => {
+            let expanded = Expanded::expand(&fs::read(input)?, &config)?;
+            fs::write(output, expanded.write())?;
+        }
+        Command::Restore { input, output } => {
+            let expanded = Expanded::read(&fs::read(input)?)?;
+            fs::write(output, expanded.restore(&config)?)?;
+        }
+        Command::Verify { input } => {
+            let data = fs::read(input)?;
+            let expanded = Expanded::expand(&data, &config)?;
+
+            // the file is read back the way restore gets it
+            let restored = Expanded::read(&expanded.write())?.restore(&config)?;
+            if restored != data {
+                return Err(PreflateError::Mismatch(anyhow::anyhow!(
+                    "restored file does not match the original"
+                ))
+                .into());
+            }
+            println!("ok: {0:?}, {1} bytes", expanded.kind, data.len());
+        }
+        Command::Stat { input } => {
+            let data = fs::read(input)?;
+            let expanded = Expanded::expand(&data, &config)?;
+
+            println!("kind: {0:?}", expanded.kind);
+            println!("input: {0} bytes", data.len());
+            println!("plain text: {0} bytes", expanded.plain_text.len());
+            println!(
+                "corrections: {0} bytes ({1:.2}% of the input)",
+                expanded.corrections.len(),
+                expanded.corrections.len() as f64 * 100.0 / data.len().max(1) as f64
+            );
+
+            if let Some(result) = &expanded.result {
+                println!("parameters: {0:?}", result.parameters);
+                result.statistics.print();
+            }
+            if let Some(summary) = &expanded.summary {
+                println!(
+                    "entries: {0} processed, {1} skipped, {2} fallback",
+                    summary.entries_processed, summary.entries_skipped, summary.entries_fallback
+                );
+                for (encoder, count) in &summary.encoder_mix {
+                    println!("  {0}: {1}", encoder, count);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = main_with_result() {
+        match e.root_cause().downcast_ref::<PreflateError>() {
+            Some(x) => {
+                eprintln!(
+                    "error code: {0} ({1:?}) {2}",
+                    x.to_code(),
+                    x.error_code(),
+                    x.message()
+                );
+                std::process::exit(x.to_code() as i32);
+            }
+            None => {
+                eprintln!("error: {0}", e);
+                std::process::exit(-2);
+            }
+        }
+    }
+}
@@ -1,20 +1,45 @@
 use preflate_rs::preflate_error::PreflateError;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use std::{fs::File, io::Read, path::PathBuf};
 
+mod serve;
+mod train_dictionary;
+
 /// A very simple utility to search for a string across multiple files.
 #[derive(Debug, Parser)]
 #[clap(name = "preflate_util")]
 pub struct PreflateUtil {
     #[clap(long, short = 'i')]
-    input_file: PathBuf,
+    input_file: Option<PathBuf>,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// keep running and process expand and reconstruct requests from stdin or a local socket
+    Serve(serve::ServeArgs),
+
+    /// train a model dictionary for the adaptive probability model on a set of files
+    TrainDictionary(train_dictionary::TrainDictionaryArgs),
 }
 
 fn main_with_result() -> anyhow::Result<()> {
     let args = PreflateUtil::parse();
 
-    let input_file = args.input_file;
+    match args.command {
+        Some(Command::Serve(serve_args)) => return serve::run(serve_args),
+        Some(Command::TrainDictionary(train_args)) => return train_dictionary::run(train_args),
+        None => {}
+    }
+
+    let Some(input_file) = args.input_file else {
+        return Err(anyhow::anyhow!(
+            "either --input-file or a subcommand is required"
+        ));
+    };
 
     let mut f = File::open(input_file)?;
 
@@ -38,8 +63,13 @@ fn main() {
         Err(e) => match e.root_cause().downcast_ref::<PreflateError>() {
             // try to extract the exit code if it was a well known error
             Some(x) => {
-                eprintln!("error code: {0:?}", x);
-                std::process::exit(-1);
+                eprintln!(
+                    "error code: {0} ({1:?}) {2}",
+                    x.to_code(),
+                    x.error_code(),
+                    x.message()
+                );
+                std::process::exit(x.to_code() as i32);
             }
             None => {
                 eprintln!("unknown error {0:?}", e);
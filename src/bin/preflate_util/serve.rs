@@ -0,0 +1,253 @@
+//! `preflate_util serve` keeps running and processes requests from stdin (or from connections to
+//! a local socket), so that other processes can expand and reconstruct deflate streams without
+//! paying for the process startup and a cold stream cache for every file.
+//!
+//! All integers are little endian and every payload is prefixed with its length as a u64.
+//!
+//! Requests:
+//! - `id: u32, b'E', compressed`: expand a deflate stream
+//! - `id: u32, b'R', plain text, corrections`: reconstruct a deflate stream
+//!
+//! Responses carry the id of their request, since the requests are processed in parallel and
+//! the responses are written as soon as they are done:
+//! - `id: u32, 0, plain text, corrections, compressed_processed: u64` for an expand
+//! - `id: u32, 0, compressed` for a reconstruct
+//! - `id: u32, 1, error code: u32, message` if the request failed
+
+use std::{
+    io::{BufReader, BufWriter, Read, Write},
+    net::{SocketAddr, TcpListener},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use clap::Args;
+use preflate_rs::{
+    decompress_deflate_stream_with_config, preflate_config::PreflateConfig,
+    recompress_deflate_stream_with_config, stream_cache::LruStreamCache,
+};
+
+#[derive(Debug, Args)]
+pub struct ServeArgs {
+    /// listen on this loopback address (for example 127.0.0.1:7878) instead of using stdin and stdout
+    #[clap(long)]
+    listen: Option<SocketAddr>,
+
+    /// number of worker threads, defaults to the number of cores
+    #[clap(long)]
+    threads: Option<usize>,
+
+    /// number of expanded streams that are kept in the cache
+    #[clap(long, default_value_t = 64)]
+    cache_streams: usize,
+}
+
+enum Request {
+    Expand(Vec<u8>),
+    Reconstruct {
+        plain_text: Vec<u8>,
+        corrections: Vec<u8>,
+    },
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// how many jobs can wait for each thread of the pool before the connections stop reading requests
+const QUEUED_JOBS_PER_THREAD: usize = 2;
+
+/// A fixed number of threads that run the jobs of all connections. The queue is bounded, so that
+/// a client that sends requests faster than they are processed doesn't fill up the memory with
+/// their payloads.
+struct ThreadPool {
+    sender: Option<mpsc::SyncSender<Job>>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl ThreadPool {
+    fn new(threads: usize) -> Self {
+        let threads = threads.max(1);
+        let (sender, receiver) = mpsc::sync_channel::<Job>(threads * QUEUED_JOBS_PER_THREAD);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..threads)
+            .map(|_| {
+                let receiver = receiver.clone();
+                thread::spawn(move || loop {
+                    // the lock is released before the job runs
+                    let job = receiver.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => break,
+                    }
+                })
+            })
+            .collect();
+
+        ThreadPool {
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+    /// queues the job, waiting while the queue is full
+    fn execute(&self, job: impl FnOnce() + Send + 'static) {
+        self.sender.as_ref().unwrap().send(Box::new(job)).unwrap();
+    }
+}
+
+impl Drop for ThreadPool {
+    /// waits for the jobs that are still queued
+    fn drop(&mut self) {
+        self.sender.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+pub fn run(args: ServeArgs) -> anyhow::Result<()> {
+    let threads = args
+        .threads
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()));
+
+    // the config (and with it the stream cache) is shared by all requests
+    let config = Arc::new(PreflateConfig {
+        stream_cache: Some(Arc::new(LruStreamCache::new(args.cache_streams))),
+        ..PreflateConfig::default()
+    });
+
+    let pool = Arc::new(ThreadPool::new(threads));
+
+    match args.listen {
+        None => {
+            let output = Arc::new(Mutex::new(BufWriter::new(std::io::stdout())));
+            serve_connection(std::io::stdin().lock(), output, &pool, &config)
+        }
+        Some(addr) => {
+            if !addr.ip().is_loopback() {
+                return Err(anyhow::anyhow!("only loopback addresses can be served"));
+            }
+
+            let listener = TcpListener::bind(addr)?;
+            eprintln!("listening on {}", listener.local_addr()?);
+
+            for stream in listener.incoming() {
+                let stream = stream?;
+                let output = Arc::new(Mutex::new(BufWriter::new(stream.try_clone()?)));
+                let (pool, config) = (pool.clone(), config.clone());
+
+                thread::spawn(move || {
+                    if let Err(e) = serve_connection(stream, output, &pool, &config) {
+                        eprintln!("connection closed: {0}", e);
+                    }
+                });
+            }
+            Ok(())
+        }
+    }
+}
+
+/// reads requests until the input ends and hands each of them to the pool
+fn serve_connection<R: Read, W: Write + Send + 'static>(
+    input: R,
+    output: Arc<Mutex<W>>,
+    pool: &ThreadPool,
+    config: &Arc<PreflateConfig>,
+) -> anyhow::Result<()> {
+    let mut input = BufReader::new(input);
+
+    while let Some((id, request)) = read_request(&mut input)? {
+        let (output, config) = (output.clone(), config.clone());
+
+        pool.execute(move || {
+            let response = process_request(id, request, &config);
+
+            let mut output = output.lock().unwrap();
+            let written = output.write_all(&response).and_then(|_| output.flush());
+            if let Err(e) = written {
+                eprintln!("failed to write response {0}: {1}", id, e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// reads the next request, or None if the input ended before it
+fn read_request(input: &mut impl Read) -> anyhow::Result<Option<(u32, Request)>> {
+    let mut id = [0; 4];
+    match input.read_exact(&mut id) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let id = u32::from_le_bytes(id);
+
+    let request = match input.read_u8()? {
+        b'E' => Request::Expand(read_payload(input)?),
+        b'R' => Request::Reconstruct {
+            plain_text: read_payload(input)?,
+            corrections: read_payload(input)?,
+        },
+        op => return Err(anyhow::anyhow!("unknown request {0:#x} for id {1}", op, id)),
+    };
+
+    Ok(Some((id, request)))
+}
+
+fn read_payload(input: &mut impl Read) -> std::io::Result<Vec<u8>> {
+    let len = input.read_u64::<LittleEndian>()?;
+
+    // the data is read in pieces, so that a damaged length doesn't allocate everything up front
+    let mut payload = Vec::new();
+    input.take(len).read_to_end(&mut payload)?;
+    if payload.len() as u64 != len {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(payload)
+}
+
+fn write_payload(output: &mut Vec<u8>, payload: &[u8]) {
+    output.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    output.extend_from_slice(payload);
+}
+
+/// processes the request and returns the response, which is written as a whole afterwards so
+/// that the output is only locked while writing
+fn process_request(id: u32, request: Request, config: &PreflateConfig) -> Vec<u8> {
+    let result = match request {
+        Request::Expand(compressed) => decompress_deflate_stream_with_config(&compressed, config)
+            .map(|r| {
+                let mut body = Vec::new();
+                write_payload(&mut body, &r.plain_text);
+                write_payload(&mut body, &r.cabac_encoded);
+                body.extend_from_slice(&(r.compressed_processed as u64).to_le_bytes());
+                body
+            }),
+        Request::Reconstruct {
+            plain_text,
+            corrections,
+        } => recompress_deflate_stream_with_config(&plain_text, &corrections, config).map(
+            |compressed| {
+                let mut body = Vec::new();
+                write_payload(&mut body, &compressed);
+                body
+            },
+        ),
+    };
+
+    let mut response = id.to_le_bytes().to_vec();
+    match result {
+        Ok(body) => {
+            response.push(0);
+            response.extend_from_slice(&body);
+        }
+        Err(e) => {
+            response.push(1);
+            response.extend_from_slice(&e.to_code().to_le_bytes());
+            write_payload(&mut response, e.message().as_bytes());
+        }
+    }
+    response
+}
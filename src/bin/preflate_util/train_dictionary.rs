@@ -0,0 +1,60 @@
+//! `preflate_util train-dictionary` trains a model dictionary on a set of files, which can then
+//! be given to the decompression and recompression of similar streams in
+//! `PreflateConfig::model_dictionary`.
+
+use std::path::PathBuf;
+
+use clap::Args;
+use preflate_rs::{model_dictionary::ModelDictionaryTrainer, nested_streams::scan_for_streams};
+
+#[derive(Debug, Args)]
+pub struct TrainDictionaryArgs {
+    /// where the dictionary is written
+    #[clap(long, short = 'o')]
+    output: PathBuf,
+
+    /// train on the zlib, gzip and zip streams found in the files instead of reading each file
+    /// as a raw deflate stream
+    #[clap(long)]
+    scan: bool,
+
+    /// the files to train on
+    #[clap(required = true)]
+    files: Vec<PathBuf>,
+}
+
+/// the files are scanned for streams in chunks of this size
+const SCAN_CHUNK_SIZE: usize = 16 << 20;
+
+pub fn run(args: TrainDictionaryArgs) -> anyhow::Result<()> {
+    let mut trainer = ModelDictionaryTrainer::new();
+
+    for file in args.files.iter() {
+        let content = std::fs::read(file)?;
+
+        let streams = if args.scan {
+            scan_for_streams(&content, SCAN_CHUNK_SIZE, 0)
+                .into_iter()
+                .map(|s| &content[s.offset..s.offset + s.compressed_len])
+                .collect()
+        } else {
+            vec![&content[..]]
+        };
+
+        // streams that can't be predicted are left out, since the dictionary is only an
+        // optimization
+        for stream in streams {
+            if let Err(e) = trainer.add_stream(stream) {
+                eprintln!("skipping a stream of {}: {}", file.display(), e.message());
+            }
+        }
+    }
+
+    if trainer.streams() == 0 {
+        return Err(anyhow::anyhow!("no streams to train the dictionary on"));
+    }
+
+    eprintln!("trained on {} streams", trainer.streams());
+    std::fs::write(&args.output, trainer.finish().to_bytes())?;
+    Ok(())
+}
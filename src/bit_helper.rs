@@ -8,6 +8,37 @@ pub fn bit_length(n: u32) -> u32 {
     32 - n.leading_zeros()
 }
 
+/// number of bytes at the start of data that are equal to b. This compares a word at a time,
+/// since it gets called for every position inside long runs of the same byte.
+pub fn run_length(data: &[u8], b: u8) -> usize {
+    let pattern = u64::from_le_bytes([b; 8]);
+
+    let mut chunks = data.chunks_exact(8);
+    let mut len = 0;
+    for chunk in &mut chunks {
+        let diff = u64::from_le_bytes(chunk.try_into().unwrap()) ^ pattern;
+        if diff != 0 {
+            return len + (diff.trailing_zeros() / 8) as usize;
+        }
+        len += 8;
+    }
+
+    len + chunks.remainder().iter().take_while(|&&x| x == b).count()
+}
+
+#[test]
+fn test_run_length() {
+    for len in 0..40 {
+        for total in len..45 {
+            let mut data = vec![7u8; total];
+            if len < total {
+                data[len] = 8;
+            }
+            assert_eq!(run_length(&data, 7), len, "len {} total {}", len, total);
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Default)]
 pub struct DebugHash {
     hash: u64,
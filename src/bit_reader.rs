@@ -8,6 +8,8 @@ use std::io::Read;
 
 use byteorder::ReadBytesExt;
 
+use crate::preflate_error::DeflateError;
+
 pub trait ReadBits {
     fn get(&mut self, cbit: u32) -> anyhow::Result<u32>;
 }
@@ -16,6 +18,9 @@ pub struct BitReader<R> {
     binary_reader: R,
     bits_read: u32,
     bit_count: u32,
+    bytes_read: u64,
+    /// number of bytes that may be read from the input
+    limit: u64,
 }
 
 impl<R: Read> ReadBits for BitReader<R> {
@@ -30,9 +35,37 @@ impl<R: Read> BitReader<R> {
             binary_reader,
             bits_read: 0,
             bit_count: 0,
+            bytes_read: 0,
+            limit: u64::MAX,
+        }
+    }
+
+    /// Reader that fails instead of reading more than limit bytes from the input. This is for
+    /// input whose size was declared by an untrusted header (such as the compressed size of a
+    /// zip entry), so that a stream that claims to be shorter than it is can't read into the
+    /// data that follows it.
+    pub fn with_limit(binary_reader: R, limit: u64) -> Self {
+        BitReader {
+            limit,
+            ..BitReader::new(binary_reader)
         }
     }
 
+    /// number of bits that have been consumed from the input
+    pub fn bit_position(&self) -> u64 {
+        self.bytes_read * 8 - u64::from(self.bit_count)
+    }
+
+    fn next_byte(&mut self) -> anyhow::Result<u8> {
+        if self.bytes_read >= self.limit {
+            return Err(DeflateError::PastDeclaredSize.into());
+        }
+
+        let result = self.binary_reader.read_u8()?;
+        self.bytes_read += 1;
+        Ok(result)
+    }
+
     /// Clear out the buffer and reset the position to the byte after the "current" position. Tricky since we may have more than 8 bits buffered.
     pub fn flush_buffer_to_byte_boundary(&mut self) {
         self.bit_count = 0;
@@ -47,8 +80,7 @@ impl<R: Read> BitReader<R> {
             return Err(anyhow::Error::msg("BitReader Error: Attempt to read bytes without first calling FlushBufferToByteBoundary"));
         }
 
-        let result = self.binary_reader.read_u8()?;
-        Ok(result)
+        self.next_byte()
     }
 
     /// Read cbit bits from the input stream return
@@ -72,7 +104,7 @@ impl<R: Read> BitReader<R> {
 
             // Ensure the buffer is has at least 1 bit in it.
             if self.bit_count == 0 {
-                self.bits_read = self.binary_reader.read_u8()? as u32;
+                self.bits_read = self.next_byte()? as u32;
                 self.bit_count = 8;
             }
 
@@ -93,3 +125,17 @@ impl<R: Read> BitReader<R> {
         Ok(wret)
     }
 }
+
+#[test]
+fn bit_reader_limit() {
+    let data = [0xa5u8, 0x5a, 0xff, 0xff];
+
+    let mut reader = BitReader::with_limit(&data[..], 2);
+    assert_eq!(reader.get(4).unwrap(), 0x5);
+    assert_eq!(reader.get(12).unwrap(), 0x5aa);
+
+    // the input continues, but not within the limit
+    assert!(reader.get(1).is_err());
+    assert!(reader.read_byte().is_err());
+    assert_eq!(reader.bit_position(), 16);
+}
@@ -0,0 +1,159 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Notifications for each block that was predicted or recreated, so that tools can show the
+//! progress through a stream and which blocks needed many corrections.
+
+use crate::{
+    deflate_parser::DeflateBlockType,
+    progress::{CancellationToken, Progress},
+    statistical_codec::{
+        CodecCorrection, CodecMisprediction, CountNonDefaultActions, PredictionDecoder,
+        PredictionEncoder,
+    },
+};
+
+/// what happened to one block of the stream
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct BlockSummary {
+    /// index of the block in the stream
+    pub index: usize,
+    pub block_type: DeflateBlockType,
+    /// number of literals and references in the block
+    pub token_count: usize,
+    /// number of bytes of plain text that the block decodes to
+    pub uncompressed_len: u32,
+    /// number of tokens where the prediction was wrong
+    pub mispredictions: u32,
+    /// number of corrections that weren't 0, including the ones of mispredicted tokens
+    pub corrections: u32,
+}
+
+/// Receives a summary of each block in the order of the stream. The tree of a dynamic block is
+/// predicted after the tokens, so its corrections aren't part of the summary.
+pub trait BlockObserver {
+    /// called when the tokens of a block were predicted while decompressing
+    fn block_predicted(&mut self, _summary: &BlockSummary) {}
+
+    /// called when the tokens of a block were recreated while recompressing
+    fn block_recreated(&mut self, _summary: &BlockSummary) {}
+
+    /// called after each block with how far the processing of the stream has gotten
+    fn progress(&mut self, _progress: &Progress) {}
+
+    /// the token that stops the processing before the next block once it is cancelled
+    fn cancellation(&self) -> Option<&CancellationToken> {
+        None
+    }
+}
+
+/// ignores all the blocks
+impl BlockObserver for () {}
+
+/// collects the summaries of all the blocks
+impl BlockObserver for Vec<BlockSummary> {
+    fn block_predicted(&mut self, summary: &BlockSummary) {
+        self.push(*summary);
+    }
+
+    fn block_recreated(&mut self, summary: &BlockSummary) {
+        self.push(*summary);
+    }
+}
+
+/// only stops the processing when the token is cancelled
+impl BlockObserver for CancellationToken {
+    fn cancellation(&self) -> Option<&CancellationToken> {
+        Some(self)
+    }
+}
+
+/// passes the actions on to the inner encoder or decoder and counts the ones that are
+/// mispredictions or corrections
+pub(crate) struct CountingCodec<'a, C: ?Sized> {
+    pub inner: &'a mut C,
+    pub mispredictions: u32,
+    pub corrections: u32,
+}
+
+impl<'a, C: ?Sized> CountingCodec<'a, C> {
+    pub fn new(inner: &'a mut C) -> Self {
+        CountingCodec {
+            inner,
+            mispredictions: 0,
+            corrections: 0,
+        }
+    }
+
+    fn count_misprediction(&mut self, wrong: bool) {
+        self.mispredictions += u32::from(wrong);
+    }
+
+    fn count_correction(&mut self, value: u32) {
+        self.corrections += u32::from(value != 0);
+    }
+}
+
+impl<C: PredictionEncoder + ?Sized> PredictionEncoder for CountingCodec<'_, C> {
+    fn encode_correction(&mut self, action: CodecCorrection, value: u32) {
+        self.count_correction(value);
+        self.inner.encode_correction(action, value);
+    }
+
+    fn encode_bucket_correction(&mut self, action: CodecCorrection, bucket: u8, value: u32) {
+        self.count_correction(value);
+        self.inner.encode_bucket_correction(action, bucket, value);
+    }
+
+    fn encode_misprediction(&mut self, action: CodecMisprediction, value: bool) {
+        self.count_misprediction(value);
+        self.inner.encode_misprediction(action, value);
+    }
+
+    fn encode_value(&mut self, value: u16, max_bits: u8) {
+        self.inner.encode_value(value, max_bits);
+    }
+
+    fn encode_verify_state(&mut self, message: &'static str, checksum: u64) {
+        self.inner.encode_verify_state(message, checksum);
+    }
+
+    fn finish(&mut self) {
+        self.inner.finish();
+    }
+
+    fn statistics(&self) -> CountNonDefaultActions {
+        self.inner.statistics()
+    }
+}
+
+impl<C: PredictionDecoder + ?Sized> PredictionDecoder for CountingCodec<'_, C> {
+    fn decode_value(&mut self, max_bits_orig: u8) -> u16 {
+        self.inner.decode_value(max_bits_orig)
+    }
+
+    fn decode_correction(&mut self, correction: CodecCorrection) -> u32 {
+        let value = self.inner.decode_correction(correction);
+        self.count_correction(value);
+        value
+    }
+
+    fn decode_bucket_correction(&mut self, correction: CodecCorrection, bucket: u8) -> u32 {
+        let value = self.inner.decode_bucket_correction(correction, bucket);
+        self.count_correction(value);
+        value
+    }
+
+    fn decode_misprediction(&mut self, misprediction: CodecMisprediction) -> bool {
+        let wrong = self.inner.decode_misprediction(misprediction);
+        self.count_misprediction(wrong);
+        wrong
+    }
+
+    fn decode_verify_state(&mut self, message: &'static str, checksum: u64) {
+        self.inner.decode_verify_state(message, checksum);
+    }
+}
@@ -4,13 +4,28 @@
  *  This software incorporates material from third parties. See NOTICE.txt for details.
  *--------------------------------------------------------------------------------------------*/
 
-use cabac::traits::{CabacReader, CabacWriter};
+use std::{
+    io::{Read, Write},
+    sync::{Arc, Mutex},
+};
+
+use cabac::{
+    debug::DebugContext,
+    h265::H265Context,
+    traits::{CabacReader, CabacWriter},
+    vp8::VP8Context,
+};
 
 use crate::{
     bit_helper::bit_length,
+    lzma_coder::{LzmaContext, LzmaWriter},
+    model_dictionary::ModelDictionary,
+    preflate_config::{CabacBackend, ModelReset, PreflateConfig, ProbabilityModel},
+    preflate_error::PreflateError,
+    static_cabac::{CountingContext, CountingWriter, StaticContext, StaticReader, StaticWriter},
     statistical_codec::{
-        CodecCorrection, CodecMisprediction, CountNonDefaultActions, PredictionDecoder,
-        PredictionEncoder,
+        drive_encoder, CodecAction, CodecCorrection, CodecMisprediction, CountNonDefaultActions,
+        PredictionDecoder, PredictionEncoder, CONTEXT_SCHEME_VERSION, MAX_CORRECTION_BUCKETS,
     },
 };
 
@@ -24,22 +39,39 @@ pub fn encode_difference(pred_val: u32, act_val: u32) -> u32 {
     }
 }
 
-/// decodes the result of the previous calculation
-pub fn decode_difference(pred_val: u32, encoded_val: u32) -> u32 {
+/// Decodes the result of the previous calculation. Returns None if the value would be out of
+/// the range of u32, which only happens if the corrections don't belong to the plain text.
+pub fn decode_difference(pred_val: u32, encoded_val: u32) -> Option<u32> {
     if encoded_val & 1 == 0 {
-        pred_val - (encoded_val >> 1)
+        pred_val.checked_sub(encoded_val >> 1)
     } else {
-        pred_val + (encoded_val >> 1)
+        pred_val.checked_add(encoded_val >> 1)
     }
 }
 
+/// decode_difference for values that come from corrections that may not belong to the plain
+/// text, which fails instead of going out of range
+pub fn checked_decode_difference(pred_val: u32, encoded_val: u32) -> anyhow::Result<u32> {
+    decode_difference(pred_val, encoded_val).ok_or_else(|| {
+        anyhow::anyhow!(
+            "correction {} goes out of range from {}",
+            encoded_val,
+            pred_val
+        )
+    })
+}
+
 #[test]
 fn test_encode_decode_difference() {
     for i in 0..10 {
-        assert_eq!(i, decode_difference(0, encode_difference(0, i)));
-        assert_eq!(i, decode_difference(10, encode_difference(10, i)));
-        assert_eq!(i, decode_difference(100, encode_difference(100, i)));
+        assert_eq!(Some(i), decode_difference(0, encode_difference(0, i)));
+        assert_eq!(Some(i), decode_difference(10, encode_difference(10, i)));
+        assert_eq!(Some(i), decode_difference(100, encode_difference(100, i)));
     }
+
+    // corrections that don't belong to the plain text can go out of range
+    assert_eq!(decode_difference(3, encode_difference(10, 0)), None);
+    assert_eq!(decode_difference(u32::MAX, 3), None);
 }
 
 #[derive(Default)]
@@ -51,12 +83,1119 @@ struct PredictionCabacContext<CTX> {
     correction: [[CTX; 8]; CodecCorrection::MAX as usize],
     correction_bits: [[CTX; 8]; CodecCorrection::MAX as usize],
 
+    /// separate contexts for each bucket of the corrections that are encoded with a bucket
+    bucket_correction: [[[CTX; 8]; MAX_CORRECTION_BUCKETS]; CodecCorrection::MAX as usize],
+    bucket_correction_bits: [[[CTX; 8]; MAX_CORRECTION_BUCKETS]; CodecCorrection::MAX as usize],
+
+    /// Whether a bucket correction is not 0. This is coded with the context of the bucket
+    /// instead of being part of the default actions, whose contexts are shared by everything,
+    /// so that the bucket can tell how likely the correction is.
+    bucket_nonzero: [[CTX; MAX_CORRECTION_BUCKETS]; CodecCorrection::MAX as usize],
+
     non_default_ops_mis: [u32; CodecMisprediction::MAX as usize],
 
     bypass_bits: u32,
     //debug_ops: VecDeque<DebugOps>,
 }
 
+/// probabilities used by the static mode. These are measured over all the corrections of the
+/// stream and stored in front of them, since the statistics differ too much between streams for
+/// a single built in table to work well. The correction contexts share one set of probabilities
+/// to keep this small.
+struct StaticProbabilities {
+    default_encoding: [StaticContext; 16],
+    default_encoding_nbits: [StaticContext; 16],
+    correction: [StaticContext; 8],
+    correction_bits: [StaticContext; 8],
+    bucket_nonzero: [StaticContext; 1],
+}
+
+impl StaticProbabilities {
+    fn train(actions: &[CodecAction]) -> Self {
+        let mut encoder = PredictionEncoderCabac::new(CountingWriter);
+        drive_encoder(&mut encoder, actions);
+        encoder.finish();
+
+        let c = &encoder.context;
+
+        let mut correction = [CountingContext::default(); 8];
+        let mut correction_bits = [CountingContext::default(); 8];
+        let mut add = |a: &[CountingContext; 8], b: &[CountingContext; 8]| {
+            for i in 0..8 {
+                correction[i].add(&a[i]);
+                correction_bits[i].add(&b[i]);
+            }
+        };
+
+        for (a, b) in c.correction.iter().zip(c.correction_bits.iter()) {
+            add(a, b);
+        }
+        for (a, b) in c
+            .bucket_correction
+            .iter()
+            .flatten()
+            .zip(c.bucket_correction_bits.iter().flatten())
+        {
+            add(a, b);
+        }
+
+        let mut bucket_nonzero = CountingContext::default();
+        for b in c.bucket_nonzero.iter().flatten() {
+            bucket_nonzero.add(b);
+        }
+
+        StaticProbabilities {
+            default_encoding: c.default_encoding.map(|x| x.to_static()),
+            default_encoding_nbits: c.default_encoding_nbits.map(|x| x.to_static()),
+            correction: correction.map(|x| x.to_static()),
+            correction_bits: correction_bits.map(|x| x.to_static()),
+            bucket_nonzero: [bucket_nonzero.to_static()],
+        }
+    }
+
+    fn contexts_mut(&mut self) -> impl Iterator<Item = &mut StaticContext> {
+        self.default_encoding
+            .iter_mut()
+            .chain(self.default_encoding_nbits.iter_mut())
+            .chain(self.correction.iter_mut())
+            .chain(self.correction_bits.iter_mut())
+            .chain(self.bucket_nonzero.iter_mut())
+    }
+
+    /// contexts that were never used (or are balanced) only take a single bit
+    fn write<W: CabacWriter<StaticContext>>(&mut self, writer: &mut W) {
+        for ctx in self.contexts_mut() {
+            let p = ctx.probability();
+            writer.put_bypass(p != 128).unwrap();
+            if p != 128 {
+                PredictionCabacContext::<StaticContext>::write_bypass(p.into(), 8, writer);
+            }
+        }
+    }
+
+    fn read<R: CabacReader<StaticContext>>(reader: &mut R) -> Self {
+        let mut r = StaticProbabilities {
+            default_encoding: Default::default(),
+            default_encoding_nbits: Default::default(),
+            correction: Default::default(),
+            correction_bits: Default::default(),
+            bucket_nonzero: Default::default(),
+        };
+
+        for ctx in r.contexts_mut() {
+            if reader.get_bypass().unwrap() {
+                *ctx = StaticContext::new(PredictionCabacContext::<StaticContext>::read_bypass(
+                    8, reader,
+                ) as u8);
+            }
+        }
+
+        r
+    }
+
+    fn to_context(&self) -> PredictionCabacContext<StaticContext> {
+        let mut c = PredictionCabacContext {
+            default_encoding: self.default_encoding,
+            default_encoding_nbits: self.default_encoding_nbits,
+            ..Default::default()
+        };
+
+        c.correction.fill(self.correction);
+        c.correction_bits.fill(self.correction_bits);
+        for b in c.bucket_correction.iter_mut() {
+            b.fill(self.correction);
+        }
+        for b in c.bucket_correction_bits.iter_mut() {
+            b.fill(self.correction_bits);
+        }
+        for b in c.bucket_nonzero.iter_mut() {
+            b.fill(self.bucket_nonzero[0]);
+        }
+
+        c
+    }
+}
+
+/// encodes the recorded actions with static probabilities that were trained on the
+/// actions themselves. The result can be decoded with PredictionDecoderCabac::new_static.
+pub fn encode_static_corrections<W: Write>(
+    actions: &[CodecAction],
+    writer: W,
+) -> CountNonDefaultActions {
+    let mut probabilities = StaticProbabilities::train(actions);
+
+    let mut writer = StaticWriter::new(writer);
+    probabilities.write(&mut writer);
+
+    let mut encoder = PredictionEncoderCabac {
+        context: probabilities.to_context(),
+        count: CountNonDefaultActions::default(),
+        model_reset: ModelReset::Keep,
+        writer,
+    };
+    drive_encoder(&mut encoder, actions);
+    encoder.finish();
+    encoder.statistics()
+}
+
+/// Set in the header byte of the corrections (together with the probability model) when the
+/// mispredictions and the corrections are stored as two separate streams. The mispredictions are
+/// almost all false, so on their own they turn into long runs that are cheap to encode and decode,
+/// and the corrections don't have their runs of zeros broken up by the mispredictions.
+pub const SPLIT_CHANNELS: u8 = 0x80;
+
+/// sends the mispredictions to one encoder and everything else to another one
+pub struct SplitPredictionEncoder<M, C> {
+    pub mispredictions: M,
+    pub corrections: C,
+}
+
+impl<M: PredictionEncoder, C: PredictionEncoder> PredictionEncoder
+    for SplitPredictionEncoder<M, C>
+{
+    fn encode_correction(&mut self, action: CodecCorrection, value: u32) {
+        self.corrections.encode_correction(action, value);
+    }
+
+    fn encode_bucket_correction(&mut self, action: CodecCorrection, bucket: u8, value: u32) {
+        self.corrections
+            .encode_bucket_correction(action, bucket, value);
+    }
+
+    fn encode_misprediction(&mut self, action: CodecMisprediction, value: bool) {
+        self.mispredictions.encode_misprediction(action, value);
+    }
+
+    fn encode_value(&mut self, value: u16, max_bits: u8) {
+        self.corrections.encode_value(value, max_bits);
+    }
+
+    /// both encoders need to see the block boundaries in case they reset their model
+    fn encode_verify_state(&mut self, message: &'static str, checksum: u64) {
+        self.mispredictions.encode_verify_state(message, checksum);
+        self.corrections.encode_verify_state(message, checksum);
+    }
+
+    fn finish(&mut self) {
+        self.mispredictions.finish();
+        self.corrections.finish();
+    }
+
+    fn statistics(&self) -> CountNonDefaultActions {
+        let mut statistics = self.mispredictions.statistics();
+        statistics.add(&self.corrections.statistics());
+        statistics
+    }
+}
+
+/// reads the two streams written by SplitPredictionEncoder
+pub struct SplitPredictionDecoder<M, C> {
+    pub mispredictions: M,
+    pub corrections: C,
+}
+
+impl<M: PredictionDecoder, C: PredictionDecoder> PredictionDecoder
+    for SplitPredictionDecoder<M, C>
+{
+    fn decode_value(&mut self, max_bits_orig: u8) -> u16 {
+        self.corrections.decode_value(max_bits_orig)
+    }
+
+    fn decode_correction(&mut self, correction: CodecCorrection) -> u32 {
+        self.corrections.decode_correction(correction)
+    }
+
+    fn decode_bucket_correction(&mut self, correction: CodecCorrection, bucket: u8) -> u32 {
+        self.corrections
+            .decode_bucket_correction(correction, bucket)
+    }
+
+    fn decode_misprediction(&mut self, misprediction: CodecMisprediction) -> bool {
+        self.mispredictions.decode_misprediction(misprediction)
+    }
+
+    fn decode_verify_state(&mut self, message: &'static str, checksum: u64) {
+        self.mispredictions.decode_verify_state(message, checksum);
+        self.corrections.decode_verify_state(message, checksum);
+    }
+}
+
+/// writes the two streams after each other, prefixed by the length of the mispredictions
+pub fn join_channels(output: &mut Vec<u8>, mispredictions: &[u8], corrections: &[u8]) {
+    output.extend_from_slice(&(mispredictions.len() as u32).to_le_bytes());
+    output.extend_from_slice(mispredictions);
+    output.extend_from_slice(corrections);
+}
+
+/// returns the mispredictions and the corrections streams written by join_channels
+pub fn split_channels(data: &[u8]) -> std::io::Result<(&[u8], &[u8])> {
+    let invalid = || {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "truncated misprediction stream",
+        )
+    };
+
+    let len = u32::from_le_bytes(data.get(0..4).ok_or_else(invalid)?.try_into().unwrap());
+    let rest = &data[4..];
+    if len as usize > rest.len() {
+        return Err(invalid());
+    }
+    Ok(rest.split_at(len as usize))
+}
+
+/// maximum number of bytes in each checksummed segment of the framed corrections
+const SEGMENT_SIZE: usize = 1 << 16;
+
+/// version of the layout of the corrections (the header byte, the original stream info and
+/// what follows them), which is recorded in front of the header byte. Up to version 1, the
+/// coded corrections started with the context scheme as an 8 bit value.
+pub const CORRECTIONS_FORMAT_VERSION: u8 = 2;
+
+/// the last format version whose coded corrections start with the context scheme
+const CODED_SCHEME_FORMAT_VERSION: u8 = 1;
+
+/// Starts the version info in front of the header byte. It has all the bits of the backend set,
+/// which no header byte has, so corrections written before the version was recorded are still
+/// recognized by their header byte. It doesn't have VERBATIM_TAIL set either.
+const VERSION_MARKER: u8 = 0x06;
+
+/// the marker, the format version and the context scheme as u16
+const VERSION_INFO_SIZE: usize = 4;
+
+/// the versions that the corrections were written with
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct CorrectionsVersion {
+    /// CORRECTIONS_FORMAT_VERSION, 0 for corrections written before the version was recorded
+    pub format: u8,
+    /// the CONTEXT_SCHEME_VERSION of the predictor and codec, None if it isn't recorded outside
+    /// of the coded corrections, 0 for the contexts of the original layout
+    pub context_scheme: Option<u16>,
+}
+
+impl CorrectionsVersion {
+    pub const CURRENT: CorrectionsVersion = CorrectionsVersion {
+        format: CORRECTIONS_FORMAT_VERSION,
+        context_scheme: Some(CONTEXT_SCHEME_VERSION),
+    };
+
+    /// the corrections that were written before there was a header byte or framing, which
+    /// are a single VP8 stream that starts with the parameters
+    pub const ORIGINAL_LAYOUT: CorrectionsVersion = CorrectionsVersion {
+        format: 0,
+        context_scheme: Some(0),
+    };
+
+    /// Checks that there is a decoder for corrections of this version. Corrections that
+    /// don't record the context scheme are checked by check_coded_scheme once the decoder
+    /// has been created.
+    pub fn check_supported(&self) -> Result<(), PreflateError> {
+        match (self.format, self.context_scheme) {
+            (0, None | Some(0))
+            | (1..=CORRECTIONS_FORMAT_VERSION, Some(CONTEXT_SCHEME_VERSION)) => Ok(()),
+            (1..=CORRECTIONS_FORMAT_VERSION, Some(scheme)) => {
+                Err(PreflateError::RecompressFailed(anyhow::anyhow!(
+                    "corrections use context scheme version {}, expected {}",
+                    scheme,
+                    CONTEXT_SCHEME_VERSION
+                )))
+            }
+            (format, _) => Err(PreflateError::RecompressFailed(anyhow::anyhow!(
+                "corrections use format version {}, expected at most {}",
+                format,
+                CORRECTIONS_FORMAT_VERSION
+            ))),
+        }
+    }
+}
+
+/// Reads the context scheme from the start of the coded corrections of the format versions that
+/// wrote it there, and checks that it is the one that we understand, since corrections of
+/// another scheme would decode into garbage. The newer versions only record it in front of
+/// the header byte, where it is checked without decoding anything.
+pub(crate) fn check_coded_scheme<D: PredictionDecoder>(
+    decoder: &mut D,
+    version: &CorrectionsVersion,
+) -> Result<(), PreflateError> {
+    if version.format > CODED_SCHEME_FORMAT_VERSION {
+        return Ok(());
+    }
+
+    let scheme = decoder.decode_value(8);
+    if scheme != CONTEXT_SCHEME_VERSION {
+        return Err(PreflateError::RecompressFailed(anyhow::anyhow!(
+            "corrections use context scheme version {}, expected {}",
+            scheme,
+            CONTEXT_SCHEME_VERSION
+        )));
+    }
+    Ok(())
+}
+
+/// Splits the corrections (starting with the header byte) into segments that are each prefixed
+/// by their length and followed by their crc32, so that damage can be detected before anything
+/// is decoded. The last segment is an empty end marker whose checksum covers all the
+/// corrections, which catches truncation at a segment boundary and segments that were dropped
+/// or reordered. The current CorrectionsVersion is written in front of the header byte.
+pub fn frame_corrections(corrections: &[u8]) -> Vec<u8> {
+    let mut versioned = Vec::with_capacity(VERSION_INFO_SIZE + corrections.len());
+    versioned.extend_from_slice(&[VERSION_MARKER, CORRECTIONS_FORMAT_VERSION]);
+    versioned.extend_from_slice(&CONTEXT_SCHEME_VERSION.to_le_bytes());
+    versioned.extend_from_slice(corrections);
+    let corrections = &versioned[..];
+
+    let mut output =
+        Vec::with_capacity(corrections.len() + (corrections.len() / SEGMENT_SIZE + 2) * 8);
+
+    for segment in corrections.chunks(SEGMENT_SIZE) {
+        output.extend_from_slice(&(segment.len() as u32).to_le_bytes());
+        output.extend_from_slice(segment);
+        output.extend_from_slice(&crc32fast::hash(segment).to_le_bytes());
+    }
+
+    output.extend_from_slice(&0u32.to_le_bytes());
+    output.extend_from_slice(&crc32fast::hash(corrections).to_le_bytes());
+    output
+}
+
+/// Checks the framing written by frame_corrections and returns the corrections starting with
+/// the header byte. Fails with CorruptCorrections and the offset of the segment that is damaged,
+/// or with RecompressFailed if the corrections were written with a version that can't be decoded.
+pub fn unframe_corrections(framed: &[u8]) -> Result<Vec<u8>, PreflateError> {
+    Ok(unframe_supported_corrections(framed)?.1)
+}
+
+/// same as unframe_corrections, along with the version that the corrections were written with
+pub(crate) fn unframe_supported_corrections(
+    framed: &[u8],
+) -> Result<(CorrectionsVersion, Vec<u8>), PreflateError> {
+    let (version, corrections) = unframe_versioned_corrections(framed)?;
+    version.check_supported()?;
+    Ok((version, corrections))
+}
+
+/// the version that the corrections were written with, without decoding them
+pub fn corrections_version(framed: &[u8]) -> Result<CorrectionsVersion, PreflateError> {
+    if is_unframed_corrections(framed) {
+        return Ok(CorrectionsVersion::ORIGINAL_LAYOUT);
+    }
+    Ok(unframe_versioned_corrections(framed)?.0)
+}
+
+/// Whether the corrections are of the original layout, which was a single VP8 stream
+/// without any framing. Framed corrections start with the length of their first segment,
+/// which the VP8 stream is very unlikely to look like, since its first bytes code the
+/// parameters.
+pub(crate) fn is_unframed_corrections(corrections: &[u8]) -> bool {
+    corrections
+        .get(0..4)
+        .is_some_and(|len| u32::from_le_bytes(len.try_into().unwrap()) as usize > SEGMENT_SIZE)
+}
+
+/// the header byte of the framed corrections, which is at the start of the first segment
+/// unless it is preceded by the version info
+pub(crate) fn framed_header_byte(framed: &[u8]) -> Option<u8> {
+    let len = u32::from_le_bytes(framed.get(0..4)?.try_into().unwrap()) as usize;
+    if len > SEGMENT_SIZE {
+        return None;
+    }
+    let first = framed.get(4..4 + len)?;
+    match first {
+        [VERSION_MARKER, _, _, _, header, ..] => Some(*header),
+        [VERSION_MARKER, ..] => None,
+        [header, ..] => Some(*header),
+        [] => None,
+    }
+}
+
+fn unframe_versioned_corrections(
+    framed: &[u8],
+) -> Result<(CorrectionsVersion, Vec<u8>), PreflateError> {
+    let mut corrections = unframe_segments(framed)?;
+
+    if corrections[0] != VERSION_MARKER {
+        let legacy = CorrectionsVersion {
+            format: 0,
+            context_scheme: None,
+        };
+        return Ok((legacy, corrections));
+    }
+
+    // the framing checksums cover the version info as well, so it isn't damaged
+    if corrections.len() <= VERSION_INFO_SIZE {
+        return Err(PreflateError::CorruptCorrections(
+            4,
+            anyhow::anyhow!("missing header byte"),
+        ));
+    }
+    let version = CorrectionsVersion {
+        format: corrections[1],
+        context_scheme: Some(u16::from_le_bytes([corrections[2], corrections[3]])),
+    };
+    corrections.drain(..VERSION_INFO_SIZE);
+    Ok((version, corrections))
+}
+
+fn unframe_segments(framed: &[u8]) -> Result<Vec<u8>, PreflateError> {
+    let corrupt = |offset, message: &str| {
+        PreflateError::CorruptCorrections(offset, anyhow::anyhow!("{}", message))
+    };
+
+    let read_u32 = |offset: usize| {
+        framed
+            .get(offset..offset + 4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+    };
+
+    let mut corrections = Vec::with_capacity(framed.len());
+
+    let mut offset = 0;
+    loop {
+        let len = read_u32(offset).ok_or_else(|| corrupt(offset, "missing end marker"))? as usize;
+        if len > SEGMENT_SIZE {
+            return Err(corrupt(offset, "segment length out of range"));
+        }
+
+        let start = offset + 4;
+        let segment = framed
+            .get(start..start + len)
+            .ok_or_else(|| corrupt(offset, "truncated segment"))?;
+        let checksum =
+            read_u32(start + len).ok_or_else(|| corrupt(offset, "truncated segment checksum"))?;
+
+        if len == 0 {
+            if corrections.is_empty() {
+                return Err(corrupt(offset, "missing header byte"));
+            }
+            if checksum != crc32fast::hash(&corrections) {
+                return Err(corrupt(offset, "end marker checksum mismatch"));
+            }
+            if start + 4 != framed.len() {
+                return Err(corrupt(start + 4, "trailing data after end marker"));
+            }
+            return Ok(corrections);
+        }
+
+        if checksum != crc32fast::hash(segment) {
+            return Err(corrupt(offset, "segment checksum mismatch"));
+        }
+
+        corrections.extend_from_slice(segment);
+        offset = start + len + 4;
+    }
+}
+
+/// Set in the header byte of the corrections when they are written to separate planes by the
+/// PlanePredictionEncoder instead of being arithmetic coded. The probability model, backend,
+/// model reset and split channels settings then don't apply.
+pub const PLANES: u8 = 0x40;
+
+/// Set in the header byte of the corrections when the stream couldn't be predicted to the end
+/// and the rest of it was stored as it is. These corrections have their own layout (see
+/// verbatim_tail) and are never passed to one of the decoders.
+pub const VERBATIM_TAIL: u8 = 0x08;
+
+/// the bits of the header byte that contain each setting
+const PROBABILITY_MODEL_MASK: u8 = 0x01;
+const BACKEND_SHIFT: u8 = 1;
+const BACKEND_MASK: u8 = 0x06;
+const MODEL_RESET_SHIFT: u8 = 4;
+const MODEL_RESET_MASK: u8 = 0x30;
+
+/// the settings that are stored in the first byte of the cabac corrections,
+/// which tell the decoder how the rest was written
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct CorrectionsHeader {
+    pub probability_model: ProbabilityModel,
+    pub backend: CabacBackend,
+    pub split_channels: bool,
+    pub model_reset: ModelReset,
+    pub planes: bool,
+}
+
+impl CorrectionsHeader {
+    pub fn from_config(config: &PreflateConfig) -> Self {
+        CorrectionsHeader {
+            probability_model: config.probability_model,
+            backend: config.cabac_backend,
+            split_channels: config.split_channels,
+            model_reset: config.model_reset,
+            planes: config.correction_planes,
+        }
+    }
+
+    pub fn to_byte(self) -> u8 {
+        self.probability_model as u8
+            | (self.backend as u8) << BACKEND_SHIFT
+            | (self.model_reset as u8) << MODEL_RESET_SHIFT
+            | if self.split_channels {
+                SPLIT_CHANNELS
+            } else {
+                0
+            }
+            | if self.planes { PLANES } else { 0 }
+    }
+
+    /// returns None if the byte contains a setting that this version doesn't know about
+    pub fn from_byte(header: u8) -> Option<Self> {
+        let known =
+            PROBABILITY_MODEL_MASK | BACKEND_MASK | MODEL_RESET_MASK | SPLIT_CHANNELS | PLANES;
+        if header & !known != 0 {
+            return None;
+        }
+
+        Some(CorrectionsHeader {
+            probability_model: match header & PROBABILITY_MODEL_MASK {
+                0 => ProbabilityModel::Adaptive,
+                _ => ProbabilityModel::Static,
+            },
+            backend: match (header & BACKEND_MASK) >> BACKEND_SHIFT {
+                0 => CabacBackend::Vp8,
+                1 => CabacBackend::H265,
+                2 => CabacBackend::Lzma,
+                _ => return None,
+            },
+            split_channels: header & SPLIT_CHANNELS != 0,
+            model_reset: match (header & MODEL_RESET_MASK) >> MODEL_RESET_SHIFT {
+                0 => ModelReset::Keep,
+                1 => ModelReset::Reset,
+                2 => ModelReset::Rescale,
+                _ => return None,
+            },
+            planes: header & PLANES != 0,
+        })
+    }
+}
+
+/// The length and crc32 of the deflate stream that the corrections recreate. This is stored right
+/// after the header byte so that a recompressed stream can be verified without the original,
+/// which also catches corrections that are paired with the wrong plain text.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct OriginalStream {
+    pub length: u64,
+    pub crc32: u32,
+}
+
+impl OriginalStream {
+    /// number of bytes that the original stream info takes up in the corrections
+    pub const SIZE: usize = 12;
+
+    pub fn of(compressed_data: &[u8]) -> Self {
+        OriginalStream {
+            length: compressed_data.len() as u64,
+            crc32: crc32fast::hash(compressed_data),
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[0..8].copy_from_slice(&self.length.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.crc32.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes = bytes.get(0..Self::SIZE)?;
+        Some(OriginalStream {
+            length: u64::from_le_bytes(bytes[0..8].try_into().unwrap()),
+            crc32: u32::from_le_bytes(bytes[8..12].try_into().unwrap()),
+        })
+    }
+
+    /// checks that the recompressed stream is the one that the corrections were created from
+    pub fn verify(&self, recompressed: &[u8]) -> Result<(), PreflateError> {
+        self.verify_info(OriginalStream::of(recompressed))
+    }
+
+    /// same as verify, for a stream that was recompressed in chunks and isn't kept
+    pub fn verify_info(&self, recompressed: OriginalStream) -> Result<(), PreflateError> {
+        if *self != recompressed {
+            return Err(PreflateError::Mismatch(anyhow::anyhow!(
+                "recompressed data has length {} and crc32 {:08x}, expected length {} and crc32 {:08x}",
+                recompressed.length,
+                recompressed.crc32,
+                self.length,
+                self.crc32
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Creates the encoder for the adaptive model with the backend and the layout of the header and
+/// evaluates the expression with it. The corrections are appended to the output once the
+/// expression has been evaluated (which has to consume the encoder).
+macro_rules! with_adaptive_encoder {
+    ($header:expr, $output:expr, |$encoder:ident| $body:expr) => {
+        $crate::cabac_codec::with_adaptive_encoder!($header, None, $output, |$encoder| $body)
+    };
+    ($header:expr, $dictionary:expr, $output:expr, |$encoder:ident| $body:expr) => {{
+        use cabac::{h265::H265Writer, vp8::VP8Writer};
+        use $crate::cabac_codec::{join_channels, PredictionEncoderCabac, SplitPredictionEncoder};
+        use $crate::lzma_coder::LzmaWriter;
+        use $crate::preflate_config::CabacBackend;
+
+        let header: CorrectionsHeader = $header;
+        let dictionary: Option<&$crate::model_dictionary::ModelDictionary> = $dictionary;
+        let output: &mut Vec<u8> = $output;
+        let mut mispredictions = Vec::new();
+        let mut corrections = Vec::new();
+
+        match (header.backend, header.split_channels) {
+            (CabacBackend::Vp8, false) => {
+                let mut $encoder = PredictionEncoderCabac::new(VP8Writer::new(output).unwrap())
+                    .with_model_reset(header.model_reset)
+                    .with_model_dictionary(dictionary);
+                $body
+            }
+            (CabacBackend::H265, false) => {
+                let mut $encoder = PredictionEncoderCabac::new(H265Writer::new(output))
+                    .with_model_reset(header.model_reset)
+                    .with_model_dictionary(dictionary);
+                $body
+            }
+            (CabacBackend::Lzma, false) => {
+                let mut $encoder = PredictionEncoderCabac::new(LzmaWriter::new(output))
+                    .with_model_reset(header.model_reset)
+                    .with_model_dictionary(dictionary);
+                $body
+            }
+            (CabacBackend::Vp8, true) => {
+                let r = {
+                    let mut $encoder = SplitPredictionEncoder {
+                        mispredictions: PredictionEncoderCabac::new(
+                            VP8Writer::new(&mut mispredictions).unwrap(),
+                        )
+                        .with_model_reset(header.model_reset)
+                        .with_model_dictionary(dictionary),
+                        corrections: PredictionEncoderCabac::new(
+                            VP8Writer::new(&mut corrections).unwrap(),
+                        )
+                        .with_model_reset(header.model_reset)
+                        .with_model_dictionary(dictionary),
+                    };
+                    $body
+                };
+                join_channels(output, &mispredictions, &corrections);
+                r
+            }
+            (CabacBackend::H265, true) => {
+                let r = {
+                    let mut $encoder = SplitPredictionEncoder {
+                        mispredictions: PredictionEncoderCabac::new(H265Writer::new(
+                            &mut mispredictions,
+                        ))
+                        .with_model_reset(header.model_reset)
+                        .with_model_dictionary(dictionary),
+                        corrections: PredictionEncoderCabac::new(H265Writer::new(&mut corrections))
+                            .with_model_reset(header.model_reset)
+                            .with_model_dictionary(dictionary),
+                    };
+                    $body
+                };
+                join_channels(output, &mispredictions, &corrections);
+                r
+            }
+            (CabacBackend::Lzma, true) => {
+                let r = {
+                    let mut $encoder = SplitPredictionEncoder {
+                        mispredictions: PredictionEncoderCabac::new(LzmaWriter::new(
+                            &mut mispredictions,
+                        ))
+                        .with_model_reset(header.model_reset)
+                        .with_model_dictionary(dictionary),
+                        corrections: PredictionEncoderCabac::new(LzmaWriter::new(&mut corrections))
+                            .with_model_reset(header.model_reset)
+                            .with_model_dictionary(dictionary),
+                    };
+                    $body
+                };
+                join_channels(output, &mispredictions, &corrections);
+                r
+            }
+        }
+    }};
+}
+
+pub(crate) use with_adaptive_encoder;
+
+/// Output that an encoder can own while it is kept between calls, and that is taken out once the
+/// encoder is finished.
+#[derive(Clone, Default)]
+struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+impl SharedOutput {
+    fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut self.0.lock().unwrap())
+    }
+}
+
+impl Write for SharedOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The encoder that with_adaptive_encoder creates for the header, for corrections that are
+/// written over several calls (see streaming::DeflateStreamDecompressor) and so can't borrow
+/// the output.
+pub(crate) struct OwnedAdaptiveEncoder {
+    encoder: Box<dyn PredictionEncoder + Send + Sync>,
+    mispredictions: SharedOutput,
+    /// the channel of the corrections, if the header splits them from the mispredictions
+    corrections: Option<SharedOutput>,
+}
+
+impl OwnedAdaptiveEncoder {
+    pub fn new(header: CorrectionsHeader, dictionary: Option<&ModelDictionary>) -> Self {
+        use cabac::{h265::H265Writer, vp8::VP8Writer};
+
+        fn configured<W: CabacWriter<CTX>, CTX: Default + ResettableContext>(
+            writer: W,
+            header: CorrectionsHeader,
+            dictionary: Option<&ModelDictionary>,
+        ) -> PredictionEncoderCabac<W, CTX> {
+            PredictionEncoderCabac::new(writer)
+                .with_model_reset(header.model_reset)
+                .with_model_dictionary(dictionary)
+        }
+
+        let mispredictions = SharedOutput::default();
+        let corrections = header.split_channels.then(SharedOutput::default);
+        let m = mispredictions.clone();
+
+        let encoder: Box<dyn PredictionEncoder + Send + Sync> =
+            match (header.backend, corrections.clone()) {
+                (CabacBackend::Vp8, None) => Box::new(configured::<_, VP8Context>(
+                    VP8Writer::new(m).unwrap(),
+                    header,
+                    dictionary,
+                )),
+                (CabacBackend::H265, None) => Box::new(configured::<_, H265Context>(
+                    H265Writer::new(m),
+                    header,
+                    dictionary,
+                )),
+                (CabacBackend::Lzma, None) => Box::new(configured::<_, LzmaContext>(
+                    LzmaWriter::new(m),
+                    header,
+                    dictionary,
+                )),
+                (CabacBackend::Vp8, Some(c)) => Box::new(SplitPredictionEncoder {
+                    mispredictions: configured::<_, VP8Context>(
+                        VP8Writer::new(m).unwrap(),
+                        header,
+                        dictionary,
+                    ),
+                    corrections: configured::<_, VP8Context>(
+                        VP8Writer::new(c).unwrap(),
+                        header,
+                        dictionary,
+                    ),
+                }),
+                (CabacBackend::H265, Some(c)) => Box::new(SplitPredictionEncoder {
+                    mispredictions: configured::<_, H265Context>(
+                        H265Writer::new(m),
+                        header,
+                        dictionary,
+                    ),
+                    corrections: configured::<_, H265Context>(
+                        H265Writer::new(c),
+                        header,
+                        dictionary,
+                    ),
+                }),
+                (CabacBackend::Lzma, Some(c)) => Box::new(SplitPredictionEncoder {
+                    mispredictions: configured::<_, LzmaContext>(
+                        LzmaWriter::new(m),
+                        header,
+                        dictionary,
+                    ),
+                    corrections: configured::<_, LzmaContext>(
+                        LzmaWriter::new(c),
+                        header,
+                        dictionary,
+                    ),
+                }),
+            };
+
+        OwnedAdaptiveEncoder {
+            encoder,
+            mispredictions,
+            corrections,
+        }
+    }
+
+    pub fn encoder(&mut self) -> &mut (dyn PredictionEncoder + Send + Sync) {
+        &mut *self.encoder
+    }
+
+    /// finishes the encoder and appends what it wrote to the output, the same way as
+    /// with_adaptive_encoder, and returns its statistics
+    pub fn finish_into(mut self, output: &mut Vec<u8>) -> CountNonDefaultActions {
+        self.encoder.finish();
+        let statistics = self.encoder.statistics();
+        drop(self.encoder);
+
+        match self.corrections {
+            Some(corrections) => {
+                join_channels(output, &self.mispredictions.take(), &corrections.take())
+            }
+            None => output.extend_from_slice(&self.mispredictions.take()),
+        }
+        statistics
+    }
+}
+
+/// Creates the decoder for the layout and probability model that are recorded in the header
+/// byte of the corrections and evaluates the expression with it (optionally along with the
+/// OriginalStream info that follows the header byte). Returns an error from the enclosing
+/// function if the framing is damaged, the header is unknown or the streams are truncated.
+macro_rules! with_cabac_decoder {
+    ($corrections:expr, |$decoder:ident| $body:expr) => {
+        $crate::cabac_codec::with_cabac_decoder!($corrections, |$decoder, _original| $body)
+    };
+    ($corrections:expr, |$decoder:ident, $original:ident| $body:expr) => {
+        $crate::cabac_codec::with_cabac_decoder!($corrections, None, |$decoder, $original| $body)
+    };
+    ($corrections:expr, $dictionary:expr, |$decoder:ident, $original:ident| $body:expr) => {
+        $crate::cabac_codec::with_cabac_decoder!(
+            @with std::io::Cursor::new,
+            |planes| planes,
+            $corrections,
+            $dictionary,
+            |$decoder, $original| $body
+        )
+    };
+    // cursor and planes turn the borrowed data into what the decoders read from
+    (@with $cursor:expr, $planes:expr, $corrections:expr, $dictionary:expr, |$decoder:ident, $original:ident| $body:expr) => {{
+        use cabac::{h265::H265Reader, vp8::VP8Reader};
+        use $crate::cabac_codec::{
+            check_coded_scheme, is_unframed_corrections, split_channels,
+            unframe_supported_corrections, CorrectionsHeader, OriginalStream,
+            PredictionDecoderCabac, SplitPredictionDecoder,
+        };
+        use $crate::lzma_coder::LzmaReader;
+        use $crate::preflate_config::{CabacBackend, ProbabilityModel};
+
+        // the corrections of the original layout have their own decoder, which only recreates
+        // the whole stream
+        if is_unframed_corrections($corrections) {
+            return Err($crate::preflate_error::PreflateError::RecompressFailed(
+                anyhow::anyhow!("corrections of the original layout can only be recompressed"),
+            ));
+        }
+
+        // check the framing first so that damage is reported before anything is decoded
+        let (version, unframed) = unframe_supported_corrections($corrections)?;
+        let (header, rest) = match unframed
+            .split_first()
+            .and_then(|(&h, rest)| Some((CorrectionsHeader::from_byte(h)?, rest)))
+        {
+            Some(h) => h,
+            None => {
+                return Err($crate::preflate_error::PreflateError::RecompressFailed(
+                    anyhow::anyhow!("corrections have an unknown probability model"),
+                ))
+            }
+        };
+        let ($original, rest) = match OriginalStream::from_bytes(rest) {
+            Some(original) => (original, &rest[OriginalStream::SIZE..]),
+            None => {
+                return Err($crate::preflate_error::PreflateError::RecompressFailed(
+                    anyhow::anyhow!("corrections are missing the original stream info"),
+                ))
+            }
+        };
+        let model_reset = header.model_reset;
+        let dictionary: Option<&$crate::model_dictionary::ModelDictionary> = $dictionary;
+
+        if header.planes {
+            let mut $decoder = ($planes)($crate::plane_codec::PlanePredictionDecoder::new(rest)?);
+            check_coded_scheme(&mut $decoder, &version)?;
+            $body
+        } else {
+            // the static model has its own coder and doesn't adapt, so
+            // the backend and the model reset only matter for the adaptive one
+            match (
+                header.probability_model,
+                header.backend,
+                header.split_channels,
+            ) {
+                (ProbabilityModel::Static, _, false) => {
+                    let mut $decoder = PredictionDecoderCabac::new_static(($cursor)(rest))?;
+                    check_coded_scheme(&mut $decoder, &version)?;
+                    $body
+                }
+                (ProbabilityModel::Static, _, true) => {
+                    let (m, c) = split_channels(rest)?;
+                    let mut $decoder = SplitPredictionDecoder {
+                        mispredictions: PredictionDecoderCabac::new_static(($cursor)(m))?,
+                        corrections: PredictionDecoderCabac::new_static(($cursor)(c))?,
+                    };
+                    check_coded_scheme(&mut $decoder, &version)?;
+                    $body
+                }
+                (ProbabilityModel::Adaptive, CabacBackend::Vp8, false) => {
+                    let mut $decoder =
+                        PredictionDecoderCabac::new(VP8Reader::new(($cursor)(rest))?)
+                            .with_model_reset(model_reset)
+                            .with_model_dictionary(dictionary)?;
+                    check_coded_scheme(&mut $decoder, &version)?;
+                    $body
+                }
+                (ProbabilityModel::Adaptive, CabacBackend::H265, false) => {
+                    let mut $decoder =
+                        PredictionDecoderCabac::new(H265Reader::new(($cursor)(rest))?)
+                            .with_model_reset(model_reset)
+                            .with_model_dictionary(dictionary)?;
+                    check_coded_scheme(&mut $decoder, &version)?;
+                    $body
+                }
+                (ProbabilityModel::Adaptive, CabacBackend::Lzma, false) => {
+                    let mut $decoder =
+                        PredictionDecoderCabac::new(LzmaReader::new(($cursor)(rest))?)
+                            .with_model_reset(model_reset)
+                            .with_model_dictionary(dictionary)?;
+                    check_coded_scheme(&mut $decoder, &version)?;
+                    $body
+                }
+                (ProbabilityModel::Adaptive, CabacBackend::Vp8, true) => {
+                    let (m, c) = split_channels(rest)?;
+                    let mut $decoder = SplitPredictionDecoder {
+                        mispredictions: PredictionDecoderCabac::new(VP8Reader::new(($cursor)(
+                            m,
+                        ))?)
+                        .with_model_reset(model_reset)
+                        .with_model_dictionary(dictionary)?,
+                        corrections: PredictionDecoderCabac::new(VP8Reader::new(($cursor)(c))?)
+                            .with_model_reset(model_reset)
+                            .with_model_dictionary(dictionary)?,
+                    };
+                    check_coded_scheme(&mut $decoder, &version)?;
+                    $body
+                }
+                (ProbabilityModel::Adaptive, CabacBackend::H265, true) => {
+                    let (m, c) = split_channels(rest)?;
+                    let mut $decoder = SplitPredictionDecoder {
+                        mispredictions: PredictionDecoderCabac::new(H265Reader::new(($cursor)(
+                            m,
+                        ))?)
+                        .with_model_reset(model_reset)
+                        .with_model_dictionary(dictionary)?,
+                        corrections: PredictionDecoderCabac::new(H265Reader::new(($cursor)(c))?)
+                            .with_model_reset(model_reset)
+                            .with_model_dictionary(dictionary)?,
+                    };
+                    check_coded_scheme(&mut $decoder, &version)?;
+                    $body
+                }
+                (ProbabilityModel::Adaptive, CabacBackend::Lzma, true) => {
+                    let (m, c) = split_channels(rest)?;
+                    let mut $decoder = SplitPredictionDecoder {
+                        mispredictions: PredictionDecoderCabac::new(LzmaReader::new(($cursor)(
+                            m,
+                        ))?)
+                        .with_model_reset(model_reset)
+                        .with_model_dictionary(dictionary)?,
+                        corrections: PredictionDecoderCabac::new(LzmaReader::new(($cursor)(c))?)
+                            .with_model_reset(model_reset)
+                            .with_model_dictionary(dictionary)?,
+                    };
+                    check_coded_scheme(&mut $decoder, &version)?;
+                    $body
+                }
+            }
+        }
+    }};
+}
+
+pub(crate) use with_cabac_decoder;
+
+/// the decoder that owned_cabac_decoder returns
+pub(crate) type OwnedPredictionDecoder = Box<dyn PredictionDecoder + Send + Sync>;
+
+/// Same decoder as with_cabac_decoder, but it reads from a copy of the corrections, so that it
+/// can be kept between the calls of a recompressor that gets the plain text in chunks.
+pub(crate) fn owned_cabac_decoder(
+    corrections: &[u8],
+    dictionary: Option<&ModelDictionary>,
+) -> Result<(OwnedPredictionDecoder, OriginalStream), PreflateError> {
+    with_cabac_decoder!(
+        @with |data: &[u8]| std::io::Cursor::new(data.to_vec()),
+        crate::plane_codec::PlanePredictionDecoder::into_owned,
+        corrections,
+        dictionary,
+        |decoder, original| Ok((Box::new(decoder) as OwnedPredictionDecoder, original))
+    )
+}
+
+/// same as encode_static_corrections, but trains separate probabilities for the
+/// mispredictions and the corrections and writes them as two streams
+pub fn encode_static_corrections_split(
+    actions: &[CodecAction],
+    output: &mut Vec<u8>,
+) -> CountNonDefaultActions {
+    let (mispredictions, corrections): (Vec<CodecAction>, Vec<CodecAction>) = actions
+        .iter()
+        .partition(|a| matches!(a, CodecAction::Misprediction(..)));
+
+    let mut misprediction_stream = Vec::new();
+    let mut statistics = encode_static_corrections(&mispredictions, &mut misprediction_stream);
+
+    let mut correction_stream = Vec::new();
+    statistics.add(&encode_static_corrections(
+        &corrections,
+        &mut correction_stream,
+    ));
+
+    join_channels(output, &misprediction_stream, &correction_stream);
+    statistics
+}
+
+impl<CTX: ResettableContext> PredictionCabacContext<CTX> {
+    fn contexts_mut(&mut self) -> impl Iterator<Item = &mut CTX> {
+        self.default_encoding
+            .iter_mut()
+            .chain(self.default_encoding_nbits.iter_mut())
+            .chain(self.correction.iter_mut().flatten())
+            .chain(self.correction_bits.iter_mut().flatten())
+            .chain(self.bucket_correction.iter_mut().flatten().flatten())
+            .chain(self.bucket_correction_bits.iter_mut().flatten().flatten())
+            .chain(self.bucket_nonzero.iter_mut().flatten())
+    }
+
+    /// starts the contexts with the probabilities of a dictionary. Contexts that weren't used
+    /// while training have a probability of 128 and keep their initial state.
+    fn seed(&mut self, probabilities: &[u8]) {
+        debug_assert_eq!(self.contexts_mut().count(), probabilities.len());
+
+        for (ctx, &p) in self.contexts_mut().zip(probabilities) {
+            if p != 128 {
+                ctx.seed(p);
+            }
+        }
+    }
+
+    /// Called at the start of each block. The run of default actions has to be written out
+    /// by the encoder before this, since the decoder reads the length of the run with the
+    /// contexts that were current at the start of the run.
+    fn reset_model(&mut self, model_reset: ModelReset) {
+        debug_assert_eq!(self.default_count, 0);
+
+        match model_reset {
+            ModelReset::Keep => {}
+            ModelReset::Reset => self.contexts_mut().for_each(|c| c.reset()),
+            ModelReset::Rescale => self.contexts_mut().for_each(|c| c.rescale()),
+        }
+    }
+}
+
 impl<CTX> PredictionCabacContext<CTX> {
     fn write_bypass<W: CabacWriter<CTX>>(value: u32, max_bits: u8, writer: &mut W) {
         for i in (0..max_bits).rev() {
@@ -178,6 +1317,32 @@ impl<CTX> PredictionCabacContext<CTX> {
         }
     }
 
+    fn encode_bucket_correction<W: CabacWriter<CTX>>(
+        &mut self,
+        val: u32,
+        context: CodecCorrection,
+        bucket: u8,
+        writer: &mut W,
+    ) {
+        if self.default_count > 0 {
+            self.write_default(writer);
+        }
+
+        let bucket = usize::from(bucket);
+        writer
+            .put(val != 0, &mut self.bucket_nonzero[context as usize][bucket])
+            .unwrap();
+
+        if val != 0 {
+            Self::write_exp_encoded(
+                val,
+                &mut self.bucket_correction[context as usize][bucket],
+                &mut self.bucket_correction_bits[context as usize][bucket],
+                writer,
+            );
+        }
+    }
+
     fn flush_encode(&mut self, writer: &mut impl CabacWriter<CTX>) {
         if self.default_count > 0 {
             self.write_default(writer);
@@ -250,11 +1415,33 @@ impl<CTX> PredictionCabacContext<CTX> {
             )
         }
     }
+
+    fn decode_bucket_correction<R: CabacReader<CTX>>(
+        &mut self,
+        context: CodecCorrection,
+        bucket: u8,
+        reader: &mut R,
+    ) -> u32 {
+        let bucket = usize::from(bucket);
+        if reader
+            .get(&mut self.bucket_nonzero[context as usize][bucket])
+            .unwrap()
+        {
+            Self::read_exp_value(
+                &mut self.bucket_correction[context as usize][bucket],
+                &mut self.bucket_correction_bits[context as usize][bucket],
+                reader,
+            )
+        } else {
+            0
+        }
+    }
 }
 
 pub struct PredictionEncoderCabac<W, CTX> {
     context: PredictionCabacContext<CTX>,
     count: CountNonDefaultActions,
+    model_reset: ModelReset,
     writer: W,
 }
 
@@ -264,9 +1451,30 @@ impl<W: CabacWriter<CTX>, CTX: Default> PredictionEncoderCabac<W, CTX> {
             context: PredictionCabacContext::<CTX>::default(),
             writer,
             count: CountNonDefaultActions::default(),
+            model_reset: ModelReset::Keep,
         }
     }
 
+    /// sets what happens to the probabilities at the start of each block
+    pub fn with_model_reset(mut self, model_reset: ModelReset) -> Self {
+        self.model_reset = model_reset;
+        self
+    }
+
+    /// Starts the contexts with the probabilities of the dictionary. Whether a dictionary was
+    /// used and its id are written first, so that the decoder knows which one it needs.
+    pub fn with_model_dictionary(mut self, dictionary: Option<&ModelDictionary>) -> Self
+    where
+        CTX: ResettableContext,
+    {
+        self.writer.put_bypass(dictionary.is_some()).unwrap();
+        if let Some(dictionary) = dictionary {
+            PredictionCabacContext::<CTX>::write_bypass(dictionary.id(), 32, &mut self.writer);
+            self.context.seed(dictionary.probabilities());
+        }
+        self
+    }
+
     /// for debugging
     #[allow(dead_code)]
     pub fn print(&self) {
@@ -275,22 +1483,217 @@ impl<W: CabacWriter<CTX>, CTX: Default> PredictionEncoderCabac<W, CTX> {
     }
 }
 
-impl<W: CabacWriter<CTX>, CTX> PredictionEncoder for PredictionEncoderCabac<W, CTX> {
+impl PredictionEncoderCabac<CountingWriter, CountingContext> {
+    /// encoder that only counts the bits of each context, which trains a ModelDictionary
+    pub(crate) fn new_counting() -> Self {
+        Self::new(CountingWriter)
+    }
+
+    /// number of contexts, which is the number of probabilities in a ModelDictionary
+    pub(crate) fn context_count() -> usize {
+        PredictionCabacContext::<CountingContext>::default()
+            .contexts_mut()
+            .count()
+    }
+
+    /// ends the stream that is being counted, so that the next one starts with a new run of
+    /// default actions
+    pub(crate) fn end_stream(&mut self) {
+        self.context.flush_encode(&mut self.writer);
+    }
+
+    /// the probability of each context that best fits the bits that were counted in it
+    pub(crate) fn counted_probabilities(&mut self) -> Vec<u8> {
+        self.context
+            .contexts_mut()
+            .map(|c| c.to_static().probability())
+            .collect()
+    }
+}
+
+/// contexts that can tell the probability they are going to use for the next bit,
+/// which is used to measure how many bits each codec context costs
+pub trait ContextProbability {
+    /// probability of the next bit being zero (out of 256)
+    fn probability_of_zero(&self) -> u8;
+}
+
+impl ContextProbability for VP8Context {
+    fn probability_of_zero(&self) -> u8 {
+        self.get_probability()
+    }
+}
+
+impl ContextProbability for StaticContext {
+    fn probability_of_zero(&self) -> u8 {
+        self.probability()
+    }
+}
+
+impl ContextProbability for LzmaContext {
+    fn probability_of_zero(&self) -> u8 {
+        self.probability()
+    }
+}
+
+/// the probability of the H.265 contexts isn't accessible, so the cost can't be measured
+impl ContextProbability for H265Context {
+    fn probability_of_zero(&self) -> u8 {
+        128
+    }
+}
+
+/// the debug and counting writers don't compress, so just count every bit as a full bit
+impl ContextProbability for DebugContext {
+    fn probability_of_zero(&self) -> u8 {
+        128
+    }
+}
+
+impl ContextProbability for CountingContext {
+    fn probability_of_zero(&self) -> u8 {
+        128
+    }
+}
+
+/// contexts that can forget what they have learned at the start of a block
+pub trait ResettableContext {
+    /// goes back to the initial probability
+    fn reset(&mut self) {}
+
+    /// keeps the current probability, but with the weight of only a few observations
+    fn rescale(&mut self) {}
+
+    /// starts over with the probability of zero (out of 256) that a model dictionary has
+    /// for this context, with the same weight as a rescaled context
+    fn seed(&mut self, _probability: u8) {}
+}
+
+/// number of observations that a rescaled context is worth
+const RESCALED_OBSERVATIONS: u32 = 16;
+
+impl ResettableContext for VP8Context {
+    fn reset(&mut self) {
+        *self = VP8Context::default();
+    }
+
+    fn rescale(&mut self) {
+        self.seed(self.get_probability());
+    }
+
+    fn seed(&mut self, probability: u8) {
+        // the counts can't be set directly, so they are rebuilt by replaying observations
+        // in the same proportion as the probability
+        let zeros = ((u32::from(probability) * RESCALED_OBSERVATIONS + 128) >> 8)
+            .clamp(1, RESCALED_OBSERVATIONS - 1);
+
+        *self = VP8Context::default();
+        for _ in 1..zeros {
+            self.record_and_update_false_obs();
+        }
+        for _ in 1..RESCALED_OBSERVATIONS - zeros {
+            self.record_and_update_true_obs();
+        }
+    }
+}
+
+/// The state of the H.265 contexts is private, so a rescaled context starts over as well and
+/// a model dictionary has no effect. The state machine adapts quickly anyway.
+impl ResettableContext for H265Context {
+    fn reset(&mut self) {
+        *self = H265Context::default();
+    }
+
+    fn rescale(&mut self) {
+        self.reset();
+    }
+}
+
+/// The probability of an LZMA context doesn't carry a number of observations, and it adapts
+/// at the same rate however long it has been used, so a rescaled context keeps it as it is.
+impl ResettableContext for LzmaContext {
+    fn reset(&mut self) {
+        *self = LzmaContext::default();
+    }
+
+    fn seed(&mut self, probability: u8) {
+        *self = LzmaContext::with_probability(probability);
+    }
+}
+
+/// the static probabilities don't adapt, so there is nothing to reset
+impl ResettableContext for StaticContext {}
+
+impl ResettableContext for DebugContext {}
+
+impl ResettableContext for CountingContext {}
+
+/// wraps the writer and adds up the cost of each bit before passing it on
+struct CostMeasuringWriter<'a, W> {
+    writer: &'a mut W,
+    bits: f64,
+}
+
+impl<'a, W> CostMeasuringWriter<'a, W> {
+    fn new(writer: &'a mut W) -> Self {
+        CostMeasuringWriter { writer, bits: 0.0 }
+    }
+}
+
+impl<W: CabacWriter<CTX>, CTX: ContextProbability> CabacWriter<CTX> for CostMeasuringWriter<'_, W> {
+    fn put_bypass(&mut self, bin_value: bool) -> std::io::Result<()> {
+        self.bits += 1.0;
+        self.writer.put_bypass(bin_value)
+    }
+
+    fn put(&mut self, value: bool, cur_ctx: &mut CTX) -> std::io::Result<()> {
+        // a probability of 0 still leaves the smallest possible split for the one
+        let p = f64::from(cur_ctx.probability_of_zero().max(1)) / 256.0;
+        self.bits -= if value { 1.0 - p } else { p }.log2();
+        self.writer.put(value, cur_ctx)
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        self.writer.finish()
+    }
+}
+
+impl<W: CabacWriter<CTX>, CTX: ContextProbability + ResettableContext> PredictionEncoder
+    for PredictionEncoderCabac<W, CTX>
+{
     fn encode_value(&mut self, value: u16, max_bits: u8) {
-        self.context.encode_value(value, max_bits, &mut self.writer);
+        let mut writer = CostMeasuringWriter::new(&mut self.writer);
+        self.context.encode_value(value, max_bits, &mut writer);
+        self.count.record_value_cost(writer.bits);
     }
 
-    fn encode_verify_state(&mut self, _message: &'static str, _checksum: u64) {}
+    fn encode_verify_state(&mut self, message: &'static str, _checksum: u64) {
+        if message == "blocktypestart" && self.model_reset != ModelReset::Keep {
+            self.context.flush_encode(&mut self.writer);
+            self.context.reset_model(self.model_reset);
+        }
+    }
 
     fn encode_correction(&mut self, action: CodecCorrection, value: u32) {
+        let mut writer = CostMeasuringWriter::new(&mut self.writer);
+        self.context.encode_correction(value, action, &mut writer);
+        self.count.record_correction_cost(action, writer.bits);
+        self.count.record_correction(action, value);
+    }
+
+    fn encode_bucket_correction(&mut self, action: CodecCorrection, bucket: u8, value: u32) {
+        let mut writer = CostMeasuringWriter::new(&mut self.writer);
         self.context
-            .encode_correction(value, action, &mut self.writer);
+            .encode_bucket_correction(value, action, bucket, &mut writer);
+        self.count.record_correction_cost(action, writer.bits);
         self.count.record_correction(action, value);
     }
 
     fn encode_misprediction(&mut self, action: CodecMisprediction, value: bool) {
+        let mut writer = CostMeasuringWriter::new(&mut self.writer);
         self.context
-            .encode_misprediction(value, action, &mut self.writer);
+            .encode_misprediction(value, action, &mut writer);
+        self.count.record_misprediction_cost(action, writer.bits);
         self.count.record_misprediction(action, value);
     }
 
@@ -298,10 +1701,15 @@ impl<W: CabacWriter<CTX>, CTX> PredictionEncoder for PredictionEncoderCabac<W, C
         self.context.flush_encode(&mut self.writer);
         self.writer.finish().unwrap();
     }
+
+    fn statistics(&self) -> CountNonDefaultActions {
+        self.count.clone()
+    }
 }
 
 pub struct PredictionDecoderCabac<R, CTX> {
     context: PredictionCabacContext<CTX>,
+    model_reset: ModelReset,
     reader: R,
 }
 
@@ -309,21 +1717,86 @@ impl<R: CabacReader<CTX>, CTX: Default> PredictionDecoderCabac<R, CTX> {
     pub fn new(reader: R) -> Self {
         Self {
             context: PredictionCabacContext::<CTX>::default(),
+            model_reset: ModelReset::Keep,
             reader,
         }
     }
+
+    /// has to match the setting of the encoder
+    pub fn with_model_reset(mut self, model_reset: ModelReset) -> Self {
+        self.model_reset = model_reset;
+        self
+    }
+
+    /// Reads whether the encoder used a model dictionary and starts the contexts with its
+    /// probabilities. Fails if the corrections need a dictionary and a different one (or none)
+    /// was given. A dictionary that the corrections don't need is ignored.
+    pub fn with_model_dictionary(
+        mut self,
+        dictionary: Option<&ModelDictionary>,
+    ) -> Result<Self, PreflateError>
+    where
+        CTX: ResettableContext,
+    {
+        if !self.reader.get_bypass()? {
+            return Ok(self);
+        }
+
+        let id = PredictionCabacContext::<CTX>::read_bypass(32, &mut self.reader);
+        match dictionary {
+            Some(dictionary) if dictionary.id() == id => {
+                self.context.seed(dictionary.probabilities());
+                Ok(self)
+            }
+            Some(dictionary) => Err(PreflateError::RecompressFailed(anyhow::anyhow!(
+                "corrections need model dictionary {:08x}, but {:08x} was given",
+                id,
+                dictionary.id()
+            ))),
+            None => Err(PreflateError::RecompressFailed(anyhow::anyhow!(
+                "corrections need model dictionary {:08x}, which wasn't given",
+                id
+            ))),
+        }
+    }
+}
+
+impl<R: Read> PredictionDecoderCabac<StaticReader<R>, StaticContext> {
+    /// decoder for corrections written by encode_static_corrections
+    pub fn new_static(reader: R) -> std::io::Result<Self> {
+        let mut reader = StaticReader::new(reader)?;
+        let probabilities = StaticProbabilities::read(&mut reader);
+
+        Ok(Self {
+            context: probabilities.to_context(),
+            model_reset: ModelReset::Keep,
+            reader,
+        })
+    }
 }
 
-impl<R: CabacReader<CTX>, CTX> PredictionDecoder for PredictionDecoderCabac<R, CTX> {
+impl<R: CabacReader<CTX>, CTX: ResettableContext> PredictionDecoder
+    for PredictionDecoderCabac<R, CTX>
+{
     fn decode_value(&mut self, max_bits_orig: u8) -> u16 {
         self.context.decode_value(max_bits_orig, &mut self.reader)
     }
-    fn decode_verify_state(&mut self, _message: &'static str, _checksum: u64) {}
+
+    fn decode_verify_state(&mut self, message: &'static str, _checksum: u64) {
+        if message == "blocktypestart" {
+            self.context.reset_model(self.model_reset);
+        }
+    }
 
     fn decode_correction(&mut self, correction: CodecCorrection) -> u32 {
         self.context.decode_correction(correction, &mut self.reader)
     }
 
+    fn decode_bucket_correction(&mut self, correction: CodecCorrection, bucket: u8) -> u32 {
+        self.context
+            .decode_bucket_correction(correction, bucket, &mut self.reader)
+    }
+
     fn decode_misprediction(&mut self, misprediction: CodecMisprediction) -> bool {
         self.context
             .decode_misprediction(misprediction, &mut self.reader)
@@ -332,7 +1805,7 @@ impl<R: CabacReader<CTX>, CTX> PredictionDecoder for PredictionDecoderCabac<R, C
 
 #[test]
 fn roundtree_cabac_decoding() {
-    use crate::statistical_codec::{drive_encoder, verify_decoder, CodecAction};
+    use crate::statistical_codec::{check_decoder, drive_encoder, verify_decoder, CodecAction};
     use cabac::vp8::{VP8Reader, VP8Writer};
     use std::io::Cursor;
 
@@ -344,6 +1817,9 @@ fn roundtree_cabac_decoding() {
         CodecAction::Correction(CodecCorrection::TokenCount, 100000),
         CodecAction::Correction(CodecCorrection::BlockTypeCorrection, 5),
         CodecAction::Correction(CodecCorrection::DistAfterLenCorrection, 0),
+        CodecAction::BucketCorrection(CodecCorrection::LenCorrection, 3, 7),
+        CodecAction::BucketCorrection(CodecCorrection::LenCorrection, 0, 0),
+        CodecAction::BucketCorrection(CodecCorrection::LenCorrection, 15, 2),
     ];
 
     let mut encoder = PredictionEncoderCabac::new(VP8Writer::new(&mut buffer).unwrap());
@@ -355,6 +1831,12 @@ fn roundtree_cabac_decoding() {
     let mut decoder = PredictionDecoderCabac::new(VP8Reader::new(Cursor::new(&buffer)).unwrap());
 
     verify_decoder(&mut decoder, &test_codec_actions);
+
+    // corrections that don't decode to the recorded actions are an error rather than a panic
+    let mut changed = test_codec_actions;
+    changed[3] = CodecAction::Correction(CodecCorrection::BlockTypeCorrection, 4);
+    let mut decoder = PredictionDecoderCabac::new(VP8Reader::new(Cursor::new(&buffer)).unwrap());
+    assert!(check_decoder(&mut decoder, &changed).is_err());
 }
 
 #[cfg(test)]
@@ -388,7 +1870,7 @@ fn roundtree_cabac_correction() {
         Operation::Correction(9, CodecCorrection::LenCorrection),
         Operation::Misprediction(false, CodecMisprediction::DistanceCountMisprediction),
         Operation::Correction(100000, CodecCorrection::TokenCount),
-        Operation::Misprediction(false, CodecMisprediction::IrregularLen258),
+        Operation::Correction(1, CodecCorrection::IrregularEncoding),
         Operation::Value(10, 4),
         Operation::Misprediction(false, CodecMisprediction::DistanceCountMisprediction),
         //Operation::Misprediction(true, CodecMisprediction::DistanceCountMisprediction),
@@ -504,3 +1986,104 @@ fn roundtrip_cabac_write_value() {
         );
     }
 }
+
+#[test]
+fn corrections_header_roundtrip() {
+    for b in 0..=255u8 {
+        if let Some(header) = CorrectionsHeader::from_byte(b) {
+            assert_eq!(header.to_byte(), b);
+        }
+    }
+
+    // the header written by older versions only contained the probability model
+    assert_eq!(
+        CorrectionsHeader::from_byte(1),
+        Some(CorrectionsHeader {
+            probability_model: ProbabilityModel::Static,
+            backend: CabacBackend::Vp8,
+            split_channels: false,
+            model_reset: ModelReset::Keep,
+            planes: false,
+        })
+    );
+
+    // unknown backends and unused bits are rejected
+    assert_eq!(CorrectionsHeader::from_byte(0x06), None);
+    assert_eq!(CorrectionsHeader::from_byte(0x08), None);
+}
+
+#[test]
+fn corrections_version_dispatch() {
+    let framed = frame_corrections(&[1, 2, 3]);
+    assert_eq!(
+        corrections_version(&framed).unwrap(),
+        CorrectionsVersion::CURRENT
+    );
+    assert_eq!(framed_header_byte(&framed), Some(1));
+    assert_eq!(unframe_corrections(&framed).unwrap(), [1, 2, 3]);
+
+    // corrections from before the version was recorded start with the header byte
+    let legacy = frame_without_version(&[1, 2, 3]);
+    assert_eq!(
+        corrections_version(&legacy).unwrap(),
+        CorrectionsVersion {
+            format: 0,
+            context_scheme: None
+        }
+    );
+    assert_eq!(framed_header_byte(&legacy), Some(1));
+    assert_eq!(unframe_corrections(&legacy).unwrap(), [1, 2, 3]);
+
+    // the original layout has no framing, so it has no header byte either
+    let original = [0x00, 0x4d, 0x6a, 0xa6, 0x12, 0x34];
+    assert!(is_unframed_corrections(&original));
+    assert!(!is_unframed_corrections(&framed));
+    assert_eq!(framed_header_byte(&original), None);
+    assert_eq!(
+        corrections_version(&original).unwrap(),
+        CorrectionsVersion::ORIGINAL_LAYOUT
+    );
+
+    // a scheme or format that there is no decoder for is rejected before decoding
+    for version in [
+        [VERSION_MARKER, CORRECTIONS_FORMAT_VERSION, 5, 0],
+        [VERSION_MARKER, CORRECTIONS_FORMAT_VERSION + 1, 6, 0],
+    ] {
+        let framed = frame_without_version(&[&version[..], &[1, 2, 3]].concat());
+        assert!(matches!(
+            unframe_corrections(&framed),
+            Err(PreflateError::RecompressFailed(_))
+        ));
+    }
+
+    /// frames the data without adding the version info
+    fn frame_without_version(data: &[u8]) -> Vec<u8> {
+        let mut framed = (data.len() as u32).to_le_bytes().to_vec();
+        framed.extend_from_slice(data);
+        framed.extend_from_slice(&crc32fast::hash(data).to_le_bytes());
+        framed.extend_from_slice(&0u32.to_le_bytes());
+        framed.extend_from_slice(&crc32fast::hash(data).to_le_bytes());
+        framed
+    }
+}
+
+#[test]
+fn framed_corrections_roundtrip() {
+    for len in [1, 2, SEGMENT_SIZE, SEGMENT_SIZE + 1, SEGMENT_SIZE * 2 + 7] {
+        let corrections: Vec<u8> = (0..len).map(|i| (i * 7) as u8).collect();
+        let framed = frame_corrections(&corrections);
+        assert_eq!(unframe_corrections(&framed).unwrap(), corrections);
+
+        // damage to the second segment is reported at the offset where it starts
+        if len > SEGMENT_SIZE + 1 {
+            let mut damaged = framed.clone();
+            damaged[SEGMENT_SIZE + 20] ^= 1;
+            match unframe_corrections(&damaged) {
+                Err(PreflateError::CorruptCorrections(offset, _)) => {
+                    assert_eq!(offset, SEGMENT_SIZE + 8)
+                }
+                r => panic!("unexpected result {:?}", r.map(|v| v.len())),
+            }
+        }
+    }
+}
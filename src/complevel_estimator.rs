@@ -4,13 +4,20 @@
  *  This software incorporates material from third parties. See NOTICE.txt for details.
  *--------------------------------------------------------------------------------------------*/
 
+use crate::compressor_profile::CompressorProfile;
 use crate::hash_chain::{
-    HashChain, MiniZHash, RotatingHashTrait, ZlibRotatingHash, HASH_ALGORITHM_ZLIB,
+    Crc32Hash, HashChain, IsalHash, LibdeflateHash3, LibdeflateHash4, MiniZHash, RotatingHashTrait,
+    SevenZipHash, ZlibRotatingHash, HASH_ALGORITHM_7ZIP, HASH_ALGORITHM_CRC32, HASH_ALGORITHM_ISAL,
+    HASH_ALGORITHM_LIBDEFLATE3, HASH_ALGORITHM_LIBDEFLATE4, HASH_ALGORITHM_MINIZ_FAST,
+    HASH_ALGORITHM_ZLIB,
 };
-use crate::preflate_constants;
+use crate::huffman_calc::HufftreeBitCalc;
+use crate::preflate_constants::{self, MAX_MATCH};
 use crate::preflate_input::PreflateInput;
-use crate::preflate_parse_config::{FAST_PREFLATE_PARSER_SETTINGS, SLOW_PREFLATE_PARSER_SETTINGS};
-use crate::preflate_token::{BlockType, PreflateToken, PreflateTokenBlock, PreflateTokenReference};
+use crate::preflate_parse_config::{ParserConfigRegistry, PreflateParserConfig};
+use crate::preflate_token::{
+    BlockType, FlushMarker, PreflateToken, PreflateTokenBlock, PreflateTokenReference,
+};
 
 #[derive(Default)]
 pub struct CompLevelInfo {
@@ -37,10 +44,23 @@ struct CandidateInfo<H: RotatingHashTrait> {
     skip_length: u32,
     max_chain_found: u32,
     hash_chain: HashChain<H>,
+
+    /// The longest distances found at the head of the hash chain of this candidate and further
+    /// down the chain. A compressor that skips inserting positions finds matches at the head
+    /// that are further down the complete chain, so this has to be tracked per candidate.
+    longest_dist_at_hop_0: u32,
+    longest_dist_at_hop_1_plus: u32,
+
+    /// the number of literals emitted where the head of the chain had a match of 4 bytes or
+    /// more, which a greedy parser would have taken, and the number of matches
+    skipped_matches: u32,
+    references: u32,
 }
 
 trait CandidateInfoTrait {
     fn update_hash(&mut self, len: u32, input: &PreflateInput);
+    /// updates the hash chain the way zlib does at a flush marker
+    fn apply_flush_marker(&mut self, flush: FlushMarker, input: &PreflateInput);
     fn skip_or_update_hash(&mut self, len: u32, input: &PreflateInput);
     fn match_depth(
         &mut self,
@@ -49,10 +69,17 @@ trait CandidateInfoTrait {
         input: &PreflateInput,
     ) -> bool;
     fn max_chain_found(&self) -> u32;
+    /// the longest distances at hop 0 and at hop 1 or further
+    fn longest_dists(&self) -> (u32, u32);
     fn hash_mask(&self) -> u16;
     fn hash_shift(&self) -> u32;
     fn skip_length(&self) -> u32;
     fn hash_algorithm(&self) -> u16;
+    /// called for each literal before the hash is updated
+    fn check_literal(&mut self, window_size: u32, input: &PreflateInput);
+    /// whether the literals skipped so many matches at the head of the chain that the stream
+    /// was parsed lazily
+    fn skips_matches(&self) -> bool;
 }
 
 impl<H: RotatingHashTrait + Default> CandidateInfoTrait for CandidateInfo<H> {
@@ -60,12 +87,22 @@ impl<H: RotatingHashTrait + Default> CandidateInfoTrait for CandidateInfo<H> {
         self.hash_chain.update_hash::<true>(len, input);
     }
 
+    fn apply_flush_marker(&mut self, flush: FlushMarker, input: &PreflateInput) {
+        self.hash_chain.apply_flush_marker::<true>(flush, input);
+    }
+
     fn match_depth(
         &mut self,
         token: &PreflateTokenReference,
         window_size: u32,
         input: &PreflateInput,
     ) -> bool {
+        // a compressor that hashes more bytes than the length of the match could never have found it
+        if token.len() < H::NUM_HASH_BYTES || input.remaining() < H::NUM_HASH_BYTES {
+            return false;
+        }
+
+        self.references += 1;
         let hash_head = self.hash_chain.cur_hash(input);
 
         let mdepth = self
@@ -77,6 +114,13 @@ impl<H: RotatingHashTrait + Default> CandidateInfoTrait for CandidateInfo<H> {
         // of each reference to the hash table, not every subsequent byte.
         if mdepth != 0xffff {
             self.max_chain_found = std::cmp::max(self.max_chain_found, mdepth);
+            if mdepth == 0 {
+                self.longest_dist_at_hop_0 =
+                    std::cmp::max(self.longest_dist_at_hop_0, token.dist());
+            } else {
+                self.longest_dist_at_hop_1_plus =
+                    std::cmp::max(self.longest_dist_at_hop_1_plus, token.dist());
+            }
             true
         } else {
             println!(
@@ -101,6 +145,10 @@ impl<H: RotatingHashTrait + Default> CandidateInfoTrait for CandidateInfo<H> {
         self.max_chain_found
     }
 
+    fn longest_dists(&self) -> (u32, u32) {
+        (self.longest_dist_at_hop_0, self.longest_dist_at_hop_1_plus)
+    }
+
     fn hash_mask(&self) -> u16 {
         self.hash_mask
     }
@@ -116,6 +164,36 @@ impl<H: RotatingHashTrait + Default> CandidateInfoTrait for CandidateInfo<H> {
     fn hash_algorithm(&self) -> u16 {
         H::hash_algorithm()
     }
+
+    fn check_literal(&mut self, window_size: u32, input: &PreflateInput) {
+        // the candidates that skip positions are ruled out by the matches that they can't find.
+        // Matches of length 3 are left out, since some compressors discard the far ones.
+        if self.skip_length < MAX_MATCH || input.remaining() < 4 {
+            return;
+        }
+
+        let hash = self.hash_chain.cur_hash(input);
+        if self.hash_chain.get_head(hash) == 0 {
+            return;
+        }
+
+        let max_dist = std::cmp::min(window_size - preflate_constants::MIN_LOOKAHEAD, input.pos());
+        let head = self
+            .hash_chain
+            .iterate_from_head(hash, input.pos(), max_dist);
+        if head.valid()
+            && head.dist() != 0
+            && input.cur_window(-(head.dist() as i32), 4) == input.cur_window(0, 4)
+        {
+            self.skipped_matches += 1;
+        }
+    }
+
+    fn skips_matches(&self) -> bool {
+        // same threshold as the profiles, a greedy parser still skips a few matches where it
+        // ran short of lookahead
+        self.skipped_matches > self.references / 64
+    }
 }
 
 struct CompLevelEstimatorState<'a> {
@@ -128,8 +206,14 @@ struct CompLevelEstimatorState<'a> {
     // matches, and at the end we pick the best candidate.
     fast_candidates: Vec<Box<dyn CandidateInfoTrait>>,
 
-    blocks: &'a Vec<PreflateTokenBlock>,
-    wsize: u16,
+    blocks: &'a [PreflateTokenBlock],
+    /// number of blocks at the start that only fill the hash chains, without being checked
+    history_blocks: usize,
+    /// the registered settings followed by the levels of zlib, or of miniz if the stream
+    /// has its huffman trees
+    fast_configs: Vec<PreflateParserConfig>,
+    slow_configs: Vec<PreflateParserConfig>,
+    wsize: u32,
     reference_count: u32,
     unfound_references: u32,
     slow_max_chain_depth: u32,
@@ -145,7 +229,10 @@ impl<'a> CompLevelEstimatorState<'a> {
         wbits: u32,
         mem_level: u32,
         plain_text: &'a [u8],
-        blocks: &'a Vec<PreflateTokenBlock>,
+        blocks: &'a [PreflateTokenBlock],
+        history_blocks: usize,
+        parser_configs: &ParserConfigRegistry,
+        huff_calc: HufftreeBitCalc,
     ) -> Self {
         let hash_bits = mem_level + 7;
         let mem_hash_shift = (hash_bits + 2) / 3;
@@ -156,16 +243,38 @@ impl<'a> CompLevelEstimatorState<'a> {
             hashparameters.push((5, 32767));
         }
 
+        // miniz has its own levels, whose greedy parser inserts all the positions of a match
+        let (fast_configs, slow_configs): (Vec<_>, Vec<_>) = if huff_calc == HufftreeBitCalc::Miniz
+        {
+            (
+                parser_configs.miniz_fast_configs().copied().collect(),
+                parser_configs.miniz_slow_configs().copied().collect(),
+            )
+        } else {
+            (
+                parser_configs.fast_configs().copied().collect(),
+                parser_configs.slow_configs().copied().collect(),
+            )
+        };
+
         let mut fast_candidates: Vec<Box<dyn CandidateInfoTrait>> = Vec::new();
 
-        // add the ZlibRotatingHash candidates
-        for config in &FAST_PREFLATE_PARSER_SETTINGS {
+        // add the ZlibRotatingHash candidates, one for each length of matches that are inserted
+        let mut skip_lengths: Vec<u32> = fast_configs.iter().map(|c| c.max_lazy).collect();
+        skip_lengths.sort_unstable();
+        skip_lengths.dedup();
+
+        for skip_length in skip_lengths {
             for &(hash_shift, hash_mask) in hashparameters.iter() {
                 fast_candidates.push(Box::new(CandidateInfo {
-                    skip_length: config.max_lazy,
+                    skip_length,
                     hash_mask,
                     hash_shift,
                     max_chain_found: 0,
+                    longest_dist_at_hop_0: 0,
+                    longest_dist_at_hop_1_plus: 0,
+                    skipped_matches: 0,
+                    references: 0,
                     hash_chain: HashChain::<ZlibRotatingHash>::new(hash_shift, hash_mask),
                 }));
             }
@@ -176,14 +285,46 @@ impl<'a> CompLevelEstimatorState<'a> {
             hash_shift: 5,
             hash_mask: 32767,
             max_chain_found: 0,
+            longest_dist_at_hop_0: 0,
+            longest_dist_at_hop_1_plus: 0,
+            skipped_matches: 0,
+            references: 0,
             hash_chain: HashChain::<MiniZHash>::new(5, 32767),
         }));
 
+        // 4 byte hashes of compressors that insert every position into the hash table
+        fast_candidates.push(Box::new(CandidateInfo {
+            skip_length: MAX_MATCH,
+            hash_shift: 5,
+            hash_mask: 32767,
+            max_chain_found: 0,
+            longest_dist_at_hop_0: 0,
+            longest_dist_at_hop_1_plus: 0,
+            skipped_matches: 0,
+            references: 0,
+            hash_chain: HashChain::<LibdeflateHash4>::new(5, 32767),
+        }));
+
+        fast_candidates.push(Box::new(CandidateInfo {
+            skip_length: MAX_MATCH,
+            hash_shift: 5,
+            hash_mask: 32767,
+            max_chain_found: 0,
+            longest_dist_at_hop_0: 0,
+            longest_dist_at_hop_1_plus: 0,
+            skipped_matches: 0,
+            references: 0,
+            hash_chain: HashChain::<Crc32Hash>::new(5, 32767),
+        }));
+
         CompLevelEstimatorState {
             slow_hash: HashChain::new(5, 32767),
             input: PreflateInput::new(plain_text),
             fast_candidates,
             blocks,
+            history_blocks,
+            fast_configs,
+            slow_configs,
             wsize: 1 << wbits,
             reference_count: 0,
             unfound_references: 0,
@@ -205,6 +346,16 @@ impl<'a> CompLevelEstimatorState<'a> {
         self.input.advance(len);
     }
 
+    /// updates the hash chains the way zlib does at a flush marker
+    fn apply_flush_marker(&mut self, flush: FlushMarker) {
+        for c in &mut self.fast_candidates {
+            c.apply_flush_marker(flush, &self.input);
+        }
+
+        self.slow_hash
+            .apply_flush_marker::<true>(flush, &self.input);
+    }
+
     pub fn update_or_skip_hash(&mut self, len: u32) {
         for c in &mut self.fast_candidates {
             c.skip_or_update_hash(len, &self.input);
@@ -256,14 +407,23 @@ impl<'a> CompLevelEstimatorState<'a> {
     }
 
     fn check_dump(&mut self) {
-        for (_i, b) in self.blocks.iter().enumerate() {
+        for (i, b) in self.blocks.iter().enumerate() {
             if b.block_type == BlockType::Stored {
                 self.update_hash(b.uncompressed_len);
+                self.apply_flush_marker(b.flush);
                 continue;
             }
             for (_j, t) in b.tokens.iter().enumerate() {
                 match t {
+                    PreflateToken::Literal if i < self.history_blocks => self.update_hash(1),
+                    PreflateToken::Reference(r) if i < self.history_blocks => {
+                        self.update_or_skip_hash(r.len())
+                    }
                     PreflateToken::Literal => {
+                        let window_size = self.window_size();
+                        for c in &mut self.fast_candidates {
+                            c.check_literal(window_size, &self.input);
+                        }
                         self.update_hash(1);
                     }
                     PreflateToken::Reference(r) => {
@@ -287,6 +447,13 @@ impl<'a> CompLevelEstimatorState<'a> {
 
         let mut hash_algorithm = HASH_ALGORITHM_ZLIB;
 
+        let mut longest_dist_at_hop_0 = self.longest_dist_at_hop_0;
+        let mut longest_dist_at_hop_1_plus = self.longest_dist_at_hop_1_plus;
+
+        // candidates that insert all the positions are never ruled out by a match, so a lazy
+        // parser is told apart by the matches that it skips
+        self.fast_candidates.retain(|c| !c.skips_matches());
+
         if !self.fast_candidates.is_empty() {
             let candidate = self
                 .fast_candidates
@@ -300,8 +467,9 @@ impl<'a> CompLevelEstimatorState<'a> {
             max_chain = candidate.max_chain_found();
             max_lazy = candidate.skip_length();
             hash_algorithm = candidate.hash_algorithm();
+            (longest_dist_at_hop_0, longest_dist_at_hop_1_plus) = candidate.longest_dists();
 
-            for config in &FAST_PREFLATE_PARSER_SETTINGS {
+            for config in &self.fast_configs {
                 if candidate.max_chain_found() <= config.max_chain
                     && candidate.skip_length() <= config.max_lazy
                 {
@@ -313,7 +481,7 @@ impl<'a> CompLevelEstimatorState<'a> {
                 }
             }
         } else {
-            for config in &SLOW_PREFLATE_PARSER_SETTINGS {
+            for config in &self.slow_configs {
                 if self.slow_max_chain_depth <= config.max_chain {
                     good_length = config.good_length;
                     max_lazy = config.max_lazy;
@@ -324,10 +492,11 @@ impl<'a> CompLevelEstimatorState<'a> {
             }
         }
 
-        let very_far_matches = self.longest_dist_at_hop_0
+        // zlib only takes matches at the head of the chain up to MAX_DIST and stops following
+        // the chain before MAX_DIST, which is close to the end of the window for small windows
+        let very_far_matches = longest_dist_at_hop_0
             > self.window_size() - preflate_constants::MIN_LOOKAHEAD
-            || self.longest_dist_at_hop_1_plus
-                >= self.window_size() - preflate_constants::MIN_LOOKAHEAD;
+            || longest_dist_at_hop_1_plus >= self.window_size() - preflate_constants::MIN_LOOKAHEAD;
 
         CompLevelInfo {
             reference_count: self.reference_count,
@@ -351,17 +520,213 @@ impl<'a> CompLevelEstimatorState<'a> {
     }
 
     fn window_size(&self) -> u32 {
-        self.wsize.into()
+        self.wsize
     }
 }
 
+/// Estimates the level of the compressor from the blocks, where the first history_blocks of them
+/// only fill the hash chains since they were compressed with other settings.
 pub fn estimate_preflate_comp_level(
     wbits: u32,
     mem_level: u32,
     plain_text: &[u8],
-    blocks: &Vec<PreflateTokenBlock>,
+    blocks: &[PreflateTokenBlock],
+    history_blocks: usize,
+    parser_configs: &ParserConfigRegistry,
+    huff_calc: HufftreeBitCalc,
 ) -> CompLevelInfo {
-    let mut state = CompLevelEstimatorState::new(wbits, mem_level, plain_text, blocks);
+    let mut state = CompLevelEstimatorState::new(
+        wbits,
+        mem_level,
+        plain_text,
+        blocks,
+        history_blocks,
+        parser_configs,
+        huff_calc,
+    );
     state.check_dump();
     state.recommend()
 }
+
+/// what following the hash chain of a compressor profile through a stream found
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ProfileChainInfo {
+    /// the deepest position in the chain that a match was found at
+    pub max_chain_depth: u32,
+    pub very_far_matches: bool,
+    pub match_to_start: bool,
+    /// number of literals emitted where the head of the chain had a match, which a greedy
+    /// parser would have taken (only counted for profiles with a greedy parser)
+    pub skipped_matches: u32,
+    /// number of matches in the stream
+    pub references: u32,
+    /// the deepest position in the chain that a match of length 3 was found at
+    pub max_depth_3_matches: u32,
+    /// number of matches that stop before the end of what matches at their distance, which
+    /// only optimal parsers do where a shorter match is cheaper to encode
+    pub shortened_matches: u32,
+}
+
+/// Follows the hash chain of the profile through the stream, or returns None if one of
+/// the matches could never have been found with this hash chain. The first history_blocks
+/// only fill the hash chain.
+pub fn profile_chain_info(
+    wbits: u32,
+    plain_text: &[u8],
+    blocks: &[PreflateTokenBlock],
+    history_blocks: usize,
+    profile: &CompressorProfile,
+) -> Option<ProfileChainInfo> {
+    match profile.hash_algorithm {
+        HASH_ALGORITHM_MINIZ_FAST => {
+            chain_info::<MiniZHash>(wbits, plain_text, blocks, history_blocks, profile)
+        }
+        HASH_ALGORITHM_LIBDEFLATE4 => {
+            chain_info::<LibdeflateHash4>(wbits, plain_text, blocks, history_blocks, profile)
+        }
+        HASH_ALGORITHM_CRC32 => {
+            chain_info::<Crc32Hash>(wbits, plain_text, blocks, history_blocks, profile)
+        }
+        HASH_ALGORITHM_7ZIP => {
+            chain_info::<SevenZipHash>(wbits, plain_text, blocks, history_blocks, profile)
+        }
+        HASH_ALGORITHM_LIBDEFLATE3 => {
+            chain_info::<LibdeflateHash3>(wbits, plain_text, blocks, history_blocks, profile)
+        }
+        HASH_ALGORITHM_ISAL => {
+            chain_info::<IsalHash>(wbits, plain_text, blocks, history_blocks, profile)
+        }
+        _ => chain_info::<ZlibRotatingHash>(wbits, plain_text, blocks, history_blocks, profile),
+    }
+}
+
+fn chain_info<H: RotatingHashTrait>(
+    wbits: u32,
+    plain_text: &[u8],
+    blocks: &[PreflateTokenBlock],
+    history_blocks: usize,
+    profile: &CompressorProfile,
+) -> Option<ProfileChainInfo> {
+    let window_size = 1u32 << wbits;
+    let max_dist = window_size - preflate_constants::MIN_LOOKAHEAD;
+
+    let mut input = PreflateInput::new(plain_text);
+    let mut hash_chain = HashChain::<H>::new(profile.hash_shift, profile.hash_mask);
+    let mut info = ProfileChainInfo {
+        max_chain_depth: 0,
+        very_far_matches: false,
+        match_to_start: false,
+        skipped_matches: 0,
+        references: 0,
+        max_depth_3_matches: 0,
+        shortened_matches: 0,
+    };
+
+    // the furthest match at the head of the chain that the compressor would take
+    let max_head_dist = if profile.quirks.very_far_matches {
+        window_size
+    } else {
+        max_dist
+    };
+
+    for (i, b) in blocks.iter().enumerate() {
+        if b.block_type == BlockType::Stored {
+            hash_chain.update_hash::<true>(b.uncompressed_len, &input);
+            input.advance(b.uncompressed_len);
+            hash_chain.apply_flush_marker::<true>(b.flush, &input);
+            continue;
+        }
+
+        for t in &b.tokens {
+            let r = match t {
+                PreflateToken::Reference(r) if i < history_blocks => {
+                    if profile.inserts_match(r.len()) {
+                        hash_chain.update_hash::<true>(r.len(), &input);
+                    } else {
+                        hash_chain.skip_hash::<true>(r.len(), &input);
+                    }
+                    input.advance(r.len());
+                    continue;
+                }
+                PreflateToken::Literal => {
+                    if profile.fast_parser && i >= history_blocks {
+                        info.skipped_matches +=
+                            u32::from(head_has_match(&hash_chain, &input, max_head_dist, profile));
+                    }
+                    hash_chain.update_hash::<true>(1, &input);
+                    input.advance(1);
+                    continue;
+                }
+                PreflateToken::Reference(r) => r,
+            };
+
+            if r.len() < H::NUM_HASH_BYTES
+                || input.remaining() < H::NUM_HASH_BYTES
+                || r.dist() > input.pos()
+            {
+                return None;
+            }
+
+            let depth = hash_chain.match_depth(hash_chain.cur_hash(&input), r, window_size, &input);
+            if depth >= 0x8001 {
+                return None;
+            }
+
+            // same limits as in recommend, the head of the chain may be one further away
+            info.max_chain_depth = std::cmp::max(info.max_chain_depth, depth);
+            info.very_far_matches |= r.dist() > max_dist || (depth > 0 && r.dist() == max_dist);
+            info.match_to_start |= r.dist() == input.pos();
+            info.references += 1;
+            if r.len() == 3 {
+                info.max_depth_3_matches = std::cmp::max(info.max_depth_3_matches, depth);
+            }
+            if r.len() < preflate_constants::MAX_MATCH
+                && r.len() < input.remaining()
+                && input.cur_char(r.len() as i32)
+                    == input.cur_char(r.len() as i32 - r.dist() as i32)
+            {
+                info.shortened_matches += 1;
+            }
+
+            if profile.inserts_match(r.len()) {
+                hash_chain.update_hash::<true>(r.len(), &input);
+            } else {
+                hash_chain.skip_hash::<true>(r.len(), &input);
+            }
+            input.advance(r.len());
+        }
+    }
+
+    Some(info)
+}
+
+/// whether the entry at the head of the hash chain is a match that the compressor would take
+fn head_has_match<H: RotatingHashTrait>(
+    hash_chain: &HashChain<H>,
+    input: &PreflateInput,
+    max_dist: u32,
+    profile: &CompressorProfile,
+) -> bool {
+    let len = std::cmp::max(preflate_constants::MIN_MATCH, H::NUM_HASH_BYTES);
+    if input.remaining() < len {
+        return false;
+    }
+
+    let hash = hash_chain.cur_hash(input);
+    if hash_chain.get_head(hash) == 0 {
+        return false;
+    }
+
+    let max_dist = std::cmp::min(
+        max_dist,
+        input.pos() - u32::from(!profile.quirks.matches_to_start),
+    );
+    let head = hash_chain.iterate_from_head(hash, input.pos(), max_dist);
+    if !head.valid() || head.dist() == 0 {
+        return false;
+    }
+
+    let dist = head.dist();
+    input.cur_window(-(dist as i32), len) == input.cur_window(0, len)
+        && (len > 3 || dist <= u32::from(profile.quirks.max_dist_3_matches))
+}
@@ -0,0 +1,773 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Compressor profiles describe everything the predictor needs to know about a particular
+//! compressor: its parser settings, hash function, huffman tree construction and the ways in
+//! which its match finder differs from zlib. If it is known which compressor produced a stream,
+//! selecting its profile by name skips the estimation of these from the stream.
+//!
+//! There is also a built in table with the profiles of common compressors. If no profile is
+//! selected, the estimator looks for a built in profile that fits the stream before falling back
+//! to the generic estimation. Compressors whose match finders the predictor can't reproduce (such
+//! as the 4 byte hashes of zlib-ng and Go) are left to the generic estimation. The optimal
+//! parsers of 7-Zip and libdeflate can't be reproduced either, but their profiles still select
+//! their hash and huffman trees, which leaves only the choice of the matches to be corrected.
+//! Zopfli is detected by the matches that it shortens, and predicted with an optimal parse of
+//! its own. Intel ISA-L builds its huffman trees in a way that none of the calculations
+//! reproduces, so its profiles accept any trees and are told apart by their hash instead.
+
+use std::sync::OnceLock;
+
+pub use crate::huffman_calc::HufftreeBitCalc;
+use crate::{
+    complevel_estimator::ProfileChainInfo,
+    huffman_calc::calc_bit_lengths,
+    preflate_parameter_estimator::{PreflateParameters, PreflateStrategy},
+    preflate_parse_config::{
+        PreflateParserConfig, FAST_PREFLATE_PARSER_SETTINGS, LIBDEFLATE_PARSER_SETTINGS,
+        MINIZ_PARSER_SETTINGS, SLOW_PREFLATE_PARSER_SETTINGS,
+    },
+    preflate_token::{BlockType, PreflateToken, PreflateTokenBlock},
+    rotating_hash::{
+        HASH_ALGORITHM_7ZIP, HASH_ALGORITHM_CRC32, HASH_ALGORITHM_ISAL, HASH_ALGORITHM_LIBDEFLATE3,
+        HASH_ALGORITHM_LIBDEFLATE4, HASH_ALGORITHM_MINIZ_FAST, HASH_ALGORITHM_ZLIB,
+    },
+    statistical_codec::{CodecCorrection, CodecMisprediction, CountNonDefaultActions},
+};
+
+/// how the parser of a compressor chooses the length of its matches
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MatchLengths {
+    /// streams with either kind of match lengths are accepted
+    Any,
+    /// every match is as long as what matches at its distance, like the lazy and greedy parsers
+    Longest,
+    /// an optimal parser that shortens matches where the bytes after them are cheaper to
+    /// encode otherwise
+    Shortened,
+}
+
+/// The ways in which the match finder of a compressor differs from the one of zlib. These are
+/// what the compressor allows, the parameters only use them if a stream actually needs them.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct StrategyQuirks {
+    /// matches can refer back to the first byte of the data, which zlib never does
+    pub matches_to_start: bool,
+    /// matches can go all the way to the end of the window instead of stopping MIN_LOOKAHEAD
+    /// bytes before it
+    pub very_far_matches: bool,
+    /// matches of length 3 that are further away than this are discarded (TOO_FAR in zlib)
+    pub max_dist_3_matches: u16,
+    /// matches of length 3 are only found this deep in the hash chain, for compressors that
+    /// look them up in a separate table that only keeps the most recent positions
+    pub max_depth_3_matches: u16,
+    /// whether the parser takes the longest match or shortens some of them
+    pub match_lengths: MatchLengths,
+    /// the dynamic huffman trees aren't built from the symbol counts in a way that one of the
+    /// calculations reproduces, so streams with any trees fit and the trees are corrected
+    pub any_trees: bool,
+}
+
+impl Default for StrategyQuirks {
+    fn default() -> Self {
+        StrategyQuirks {
+            matches_to_start: false,
+            very_far_matches: false,
+            max_dist_3_matches: 4096,
+            max_depth_3_matches: u16::MAX,
+            match_lengths: MatchLengths::Any,
+            any_trees: false,
+        }
+    }
+}
+
+/// a complete description of how a compressor produces its deflate streams
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CompressorProfile {
+    /// the name by which the profile is selected in the PreflateConfig
+    pub name: String,
+    /// the good/lazy/nice/chain settings of the level
+    pub parser_config: PreflateParserConfig,
+    /// whether the level uses the greedy parser, which doesn't insert all the positions
+    /// of long matches into the hash table, instead of the lazy one
+    pub fast_parser: bool,
+    /// one of the HASH_ALGORITHM constants in rotating_hash
+    pub hash_algorithm: u16,
+    pub hash_shift: u32,
+    pub hash_mask: u16,
+    /// how the bit lengths of the dynamic huffman trees are calculated
+    pub huff_calc: HufftreeBitCalc,
+    pub quirks: StrategyQuirks,
+}
+
+impl CompressorProfile {
+    /// a profile of zlib with the default memory level and the given parser settings
+    pub fn zlib(name: &str, parser_config: PreflateParserConfig, fast_parser: bool) -> Self {
+        CompressorProfile {
+            name: name.to_string(),
+            parser_config,
+            fast_parser,
+            hash_algorithm: HASH_ALGORITHM_ZLIB,
+            hash_shift: 5,
+            hash_mask: 32767,
+            huff_calc: HufftreeBitCalc::Zlib,
+            quirks: StrategyQuirks {
+                // zlib only discards far away matches of length 3 in the lazy parser
+                max_dist_3_matches: if fast_parser { 32768 } else { 4096 },
+                match_lengths: MatchLengths::Longest,
+                ..StrategyQuirks::default()
+            },
+        }
+    }
+
+    /// A profile of miniz (and miniz_oxide) at the given level. The fastest level uses a
+    /// separate compressor with its own hash that only looks at the head of the chain.
+    pub fn miniz(name: &str, level: u32) -> Self {
+        let level = level.clamp(1, 9);
+        let parser_config = MINIZ_PARSER_SETTINGS[level as usize - 1];
+        let hash_algorithm = if level == 1 {
+            HASH_ALGORITHM_MINIZ_FAST
+        } else {
+            HASH_ALGORITHM_ZLIB
+        };
+
+        CompressorProfile {
+            name: name.to_string(),
+            parser_config,
+            // levels up to 3 use greedy parsing
+            fast_parser: level <= 3,
+            hash_algorithm,
+            hash_shift: 5,
+            hash_mask: 32767,
+            huff_calc: HufftreeBitCalc::Miniz,
+            quirks: StrategyQuirks {
+                matches_to_start: false,
+                // the fast compressor doesn't take matches of the full window size
+                very_far_matches: level > 1,
+                max_dist_3_matches: 8191,
+                match_lengths: MatchLengths::Longest,
+                ..StrategyQuirks::default()
+            },
+        }
+    }
+
+    /// A profile of the deflate encoder of 7-Zip at the given level. 7-Zip finds its matches
+    /// with a binary tree instead of a hash chain and chooses between them with an optimal
+    /// parser, so the parser settings only approximate it: the predictor follows the chain as
+    /// deep as the binary tree of 7-Zip searches and takes the longest match.
+    pub fn seven_zip(name: &str, level: u32) -> Self {
+        let level = level.clamp(1, 9);
+        // the number of fast bytes (-fb) of each level, which is the match length that is
+        // taken without searching further
+        let fast_bytes = match level {
+            1..=6 => 32,
+            7..=8 => 64,
+            _ => 128,
+        };
+        // the levels up to 4 use a single optimization pass, which is closest to greedy parsing
+        let fast_parser = level <= 4;
+
+        CompressorProfile {
+            name: name.to_string(),
+            parser_config: PreflateParserConfig {
+                good_length: 258,
+                max_lazy: if fast_parser { 258 } else { fast_bytes },
+                nice_length: fast_bytes,
+                max_chain: 4096,
+            },
+            fast_parser,
+            hash_algorithm: HASH_ALGORITHM_7ZIP,
+            hash_shift: 5,
+            hash_mask: 0xffff,
+            huff_calc: HufftreeBitCalc::SevenZip,
+            quirks: StrategyQuirks {
+                matches_to_start: true,
+                very_far_matches: true,
+                max_dist_3_matches: 32768,
+                ..StrategyQuirks::default()
+            },
+        }
+    }
+
+    /// A profile of libdeflate at the given level (1 to 12). libdeflate looks up the matches of
+    /// length 3 in a separate table that only keeps the most recent position of each hash, and
+    /// the longer ones in a chain (or binary tree for the near optimal levels) of 4 byte hashes.
+    /// The profile follows a single chain of its 3 byte hash, which contains all of these, but
+    /// the matches from the chain of 4 byte hashes usually lie deeper in it than the search
+    /// depth of the level, so only the streams of level 1, which keeps just two positions for
+    /// each hash, fit. The other levels are left to the generic estimation.
+    pub fn libdeflate(name: &str, level: u32) -> Self {
+        let level = level.clamp(1, 12);
+        let parser_config = LIBDEFLATE_PARSER_SETTINGS[level as usize - 1];
+
+        let (hash_algorithm, hash_mask, max_dist_3_matches, max_depth_3_matches) = match level {
+            // the fastest level only finds matches of 4 bytes or more
+            1 => (HASH_ALGORITHM_LIBDEFLATE4, 0x7fff, 32768, 0),
+            // the predictor only discards far away matches of length 3 in the lazy parser
+            2..=4 => (HASH_ALGORITHM_LIBDEFLATE3, 0x7fff, 32768, 0),
+            5..=9 => (HASH_ALGORITHM_LIBDEFLATE3, 0x7fff, 8192, 0),
+            // the table of the near optimal levels is larger and keeps two positions per hash
+            _ => (HASH_ALGORITHM_LIBDEFLATE3, 0xffff, 32768, 1),
+        };
+
+        CompressorProfile {
+            name: name.to_string(),
+            parser_config,
+            fast_parser: level <= 4,
+            hash_algorithm,
+            hash_shift: 5,
+            hash_mask,
+            huff_calc: HufftreeBitCalc::Libdeflate,
+            quirks: StrategyQuirks {
+                matches_to_start: true,
+                very_far_matches: true,
+                max_dist_3_matches,
+                max_depth_3_matches,
+                match_lengths: if level <= 9 {
+                    MatchLengths::Longest
+                } else {
+                    MatchLengths::Any
+                },
+                ..StrategyQuirks::default()
+            },
+        }
+    }
+
+    /// A profile of Zopfli, which finds its matches with the hash of zlib in a chain of up to
+    /// 8192 entries and chooses between them with an iterated optimal parse. The predictor
+    /// replays an optimal parse with a fixed cost model over a short lookahead, which chooses
+    /// the shorter matches that Zopfli takes more often than the longest match does. Zopfli
+    /// also changes the lengths of its optimal codes to make the trees shorter to store, which
+    /// the calculation doesn't reproduce, so the trees are left to be corrected.
+    pub fn zopfli(name: &str) -> Self {
+        CompressorProfile {
+            name: name.to_string(),
+            parser_config: PreflateParserConfig {
+                good_length: 258,
+                max_lazy: 258,
+                nice_length: 258,
+                max_chain: 8192,
+            },
+            fast_parser: false,
+            hash_algorithm: HASH_ALGORITHM_ZLIB,
+            hash_shift: 5,
+            hash_mask: 32767,
+            huff_calc: HufftreeBitCalc::Zopfli,
+            quirks: StrategyQuirks {
+                matches_to_start: true,
+                very_far_matches: true,
+                max_dist_3_matches: 32768,
+                match_lengths: MatchLengths::Shortened,
+                any_trees: true,
+                ..StrategyQuirks::default()
+            },
+        }
+    }
+
+    /// A profile of Intel ISA-L at the given level (0 to 3). ISA-L hashes 4 bytes with the crc32
+    /// instruction and keeps only the most recent position of each hash, without a chain, so it
+    /// looks at a single candidate and never finds matches of length 3. The predictor does the
+    /// same by only looking at the head of the chain, but it inserts every position of a match
+    /// where ISA-L only inserts the first few, which is left to the corrections. Level 0 writes
+    /// the same precomputed trees for every block and the other levels build their own, so the
+    /// trees are left to be corrected as well.
+    pub fn isal(name: &str, level: u32) -> Self {
+        let level = level.min(3);
+
+        CompressorProfile {
+            name: name.to_string(),
+            parser_config: PreflateParserConfig {
+                good_length: 258,
+                max_lazy: 258,
+                nice_length: 258,
+                max_chain: 1,
+            },
+            fast_parser: true,
+            hash_algorithm: HASH_ALGORITHM_ISAL,
+            hash_shift: 5,
+            // level 0 has a table of 8K entries, the others one as large as the window
+            hash_mask: if level == 0 { 0x1fff } else { 0x7fff },
+            huff_calc: HufftreeBitCalc::SevenZip,
+            quirks: StrategyQuirks {
+                matches_to_start: true,
+                very_far_matches: true,
+                max_dist_3_matches: 0,
+                // levels 2 and 3 choose between the matches of the following positions
+                match_lengths: if level <= 1 {
+                    MatchLengths::Longest
+                } else {
+                    MatchLengths::Any
+                },
+                any_trees: true,
+                ..StrategyQuirks::default()
+            },
+        }
+    }
+
+    /// whether the predictor can find all the matches of the stream with this profile, given
+    /// what following its hash chain through the stream found
+    pub(crate) fn fits_chain(&self, chain: &ProfileChainInfo) -> bool {
+        chain.max_chain_depth <= self.parser_config.max_chain
+            && (self.quirks.very_far_matches || !chain.very_far_matches)
+            && (self.quirks.matches_to_start || !chain.match_to_start)
+    }
+
+    /// whether the stream was parsed the way this compressor parses, which tells apart
+    /// compressors that find the same matches
+    pub(crate) fn parses_like(&self, chain: &ProfileChainInfo) -> bool {
+        // a lazy parser skips a match every few references, whereas a greedy parser only
+        // does so where the encoder ran short of lookahead (e.g. at input buffer boundaries)
+        !(self.fast_parser && chain.skipped_matches > chain.references / 64)
+            && chain.max_depth_3_matches <= u32::from(self.quirks.max_depth_3_matches)
+            && match (
+                self.quirks.match_lengths,
+                chain.shortened_matches > chain.references / 64,
+            ) {
+                (MatchLengths::Longest, shortened) => !shortened,
+                (MatchLengths::Shortened, shortened) => shortened,
+                (MatchLengths::Any, _) => true,
+            }
+    }
+
+    /// whether all the positions of a match of the given length are inserted into the hash table
+    pub(crate) fn inserts_match(&self, len: u32) -> bool {
+        !self.fast_parser || len <= self.parser_config.max_lazy
+    }
+
+    /// the settings that a stream was predicted with, named after its encoder label
+    pub(crate) fn from_parameters(params: &PreflateParameters) -> Self {
+        CompressorProfile {
+            name: params.encoder_label(),
+            parser_config: PreflateParserConfig {
+                good_length: params.good_length,
+                max_lazy: params.max_lazy,
+                nice_length: params.nice_length,
+                max_chain: params.max_chain,
+            },
+            fast_parser: params.is_fast_compressor,
+            hash_algorithm: params.hash_algorithm,
+            hash_shift: params.hash_shift,
+            hash_mask: params.hash_mask,
+            huff_calc: params.huff_calc,
+            quirks: StrategyQuirks {
+                matches_to_start: params.matches_to_start_detected,
+                very_far_matches: params.very_far_matches_detected,
+                max_dist_3_matches: params.max_dist_3_matches,
+                max_depth_3_matches: params.max_depth_3_matches,
+                match_lengths: match params.strategy {
+                    PreflateStrategy::Optimal => MatchLengths::Shortened,
+                    _ => MatchLengths::Any,
+                },
+                ..StrategyQuirks::default()
+            },
+        }
+    }
+
+    /// Suggests settings to retry a stream with, given the statistics of a run with this
+    /// profile, with the most promising ones first. Each suggestion changes one setting
+    /// depending on which corrections were the most expensive, so an outer loop can register
+    /// them, select them one after the other and keep the smallest result (repeating with the
+    /// statistics of the best one). Selecting a suggestion fails for streams with matches that
+    /// it rules out, such as a chain that is too short.
+    pub fn refinements(&self, statistics: &CountNonDefaultActions) -> Vec<CompressorProfile> {
+        // only the cabac encoders know the bits, otherwise fall back to how often each happened
+        let use_bits = statistics.total_bits() > 0.0;
+        let misprediction = |m: CodecMisprediction| {
+            if use_bits {
+                statistics.mispredictions_cost[m as usize].bits
+            } else {
+                f64::from(statistics.mispredictions_count[m as usize])
+            }
+        };
+        let correction = |c: CodecCorrection| {
+            if use_bits {
+                statistics.corrections_cost[c as usize].bits
+            } else {
+                f64::from(statistics.corrections_count[c as usize])
+            }
+        };
+
+        // the compressor found matches that the predictor didn't
+        let missed = misprediction(CodecMisprediction::LiteralPredictionWrong);
+        // the predictor found matches that the compressor didn't take
+        let extra = misprediction(CodecMisprediction::ReferencePredictionWrong);
+        // the compressor searched further or stopped earlier
+        let length = correction(CodecCorrection::LenCorrection)
+            + correction(CodecCorrection::DistAfterLenCorrection);
+        // the same length was found at a different position of the chain
+        let distance = correction(CodecCorrection::DistOnlyCorrection);
+
+        let config = self.parser_config;
+        let mut candidates = Vec::new();
+
+        if config.max_chain < 4096 {
+            let max_chain = (config.max_chain * 2).clamp(4, 4096);
+            candidates.push((
+                missed + length,
+                self.refined(&format!("chain-{}", max_chain), |p| {
+                    p.parser_config.max_chain = max_chain
+                }),
+            ));
+        }
+        if config.max_chain > 4 {
+            let max_chain = config.max_chain / 2;
+            candidates.push((
+                extra,
+                self.refined(&format!("chain-{}", max_chain), |p| {
+                    p.parser_config.max_chain = max_chain
+                }),
+            ));
+        }
+
+        if self.fast_parser {
+            candidates.push((
+                extra + length,
+                self.refined("lazy", |p| p.fast_parser = false),
+            ));
+        } else {
+            if config.max_lazy < 258 {
+                let max_lazy = (config.max_lazy * 2).clamp(4, 258);
+                candidates.push((
+                    extra,
+                    self.refined(&format!("lazy-{}", max_lazy), |p| {
+                        p.parser_config.max_lazy = max_lazy
+                    }),
+                ));
+            }
+            candidates.push((missed, self.refined("greedy", |p| p.fast_parser = true)));
+        }
+
+        for (hash_algorithm, hash_mask, label) in [
+            (HASH_ALGORITHM_ZLIB, 32767, "zlib"),
+            (HASH_ALGORITHM_MINIZ_FAST, 32767, "miniz-fast"),
+            (HASH_ALGORITHM_LIBDEFLATE4, 32767, "libdeflate4"),
+            (HASH_ALGORITHM_CRC32, 32767, "crc32"),
+            (HASH_ALGORITHM_7ZIP, 0xffff, "7zip"),
+            (HASH_ALGORITHM_LIBDEFLATE3, 32767, "libdeflate3"),
+            (HASH_ALGORITHM_ISAL, 32767, "isal"),
+        ] {
+            if hash_algorithm != self.hash_algorithm {
+                candidates.push((
+                    distance + missed / 2.0,
+                    self.refined(&format!("hash-{}", label), |p| {
+                        p.hash_algorithm = hash_algorithm;
+                        p.hash_shift = 5;
+                        p.hash_mask = hash_mask;
+                    }),
+                ));
+            }
+        }
+
+        // the sort is stable, so equally expensive suggestions stay in the order above
+        candidates.retain(|(score, _)| *score > 0.0);
+        candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+        candidates.into_iter().map(|(_, p)| p).collect()
+    }
+
+    /// a copy of the profile with one setting changed, which is appended to the name
+    fn refined(&self, change: &str, f: impl FnOnce(&mut CompressorProfile)) -> CompressorProfile {
+        let mut profile = CompressorProfile {
+            name: format!("{}+{}", self.name, change),
+            ..self.clone()
+        };
+        f(&mut profile);
+        profile
+    }
+
+    /// false if the fingerprint rules out that the stream was produced by this compressor.
+    /// The hash chains still need to be checked if this returns true.
+    pub(crate) fn fits_fingerprint(&self, fingerprint: &StreamFingerprint) -> bool {
+        (self.quirks.any_trees || fingerprint.huff_calcs.contains(&self.huff_calc))
+            && fingerprint.longest_len_3_dist <= u32::from(self.quirks.max_dist_3_matches)
+    }
+}
+
+/// The profiles of the compressors whose streams the predictor can recreate exactly. The
+/// estimator uses the first one that fits a stream, so each group is ordered by increasing
+/// chain length and the aliases come after the profile that they are the same as.
+pub fn builtin_profiles() -> &'static [CompressorProfile] {
+    static PROFILES: OnceLock<Vec<CompressorProfile>> = OnceLock::new();
+
+    PROFILES.get_or_init(|| {
+        let mut profiles = Vec::new();
+
+        // zlib 1.2.x with the default memory level, levels 1-3 use deflate_fast
+        for (i, config) in FAST_PREFLATE_PARSER_SETTINGS[1..].iter().enumerate() {
+            profiles.push(CompressorProfile::zlib(
+                &format!("zlib-{}", i + 1),
+                *config,
+                true,
+            ));
+        }
+        for (i, config) in SLOW_PREFLATE_PARSER_SETTINGS.iter().enumerate() {
+            profiles.push(CompressorProfile::zlib(
+                &format!("zlib-{}", i + 4),
+                *config,
+                false,
+            ));
+        }
+
+        for level in 1..=9 {
+            profiles.push(CompressorProfile::miniz(&format!("miniz-{}", level), level));
+        }
+
+        profiles.push(CompressorProfile::zopfli("zopfli"));
+
+        // also before 7-Zip, since they accept any trees but only 4 byte matches at the head
+        for level in 0..=3 {
+            profiles.push(CompressorProfile::isal(&format!("isal-{}", level), level));
+        }
+
+        // before 7-Zip, since its profiles accept about any stream with the same trees
+        for level in 1..=12 {
+            profiles.push(CompressorProfile::libdeflate(
+                &format!("libdeflate-{}", level),
+                level,
+            ));
+        }
+
+        for level in 1..=9 {
+            profiles.push(CompressorProfile::seven_zip(
+                &format!("7zip-{}", level),
+                level,
+            ));
+        }
+
+        // System.IO.Compression on the versions of .NET that use zlib
+        let mut aliases = vec![
+            ("dotnet-fastest".to_string(), "zlib-1".to_string()),
+            ("dotnet-optimal".to_string(), "zlib-6".to_string()),
+            ("dotnet-smallest".to_string(), "zlib-9".to_string()),
+        ];
+        // java.util.zip.Deflater and the zip, jar and gzip streams built on it, which run the
+        // zlib bundled with the JDK or the one of the system. DeflaterOutputStream passes on its input in small pieces,
+        // which zlib buffers, so the streams are the same as the ones of zlib (the default
+        // level is 6).
+        for level in 1..=9 {
+            aliases.push((format!("java-{}", level), format!("zlib-{}", level)));
+        }
+        for (alias, level) in aliases {
+            let profile = profiles.iter().find(|p| p.name == level).unwrap();
+            profiles.push(CompressorProfile {
+                name: alias,
+                ..profile.clone()
+            });
+        }
+
+        profiles
+    })
+}
+
+/// Properties of a stream that can be determined without following the hash chains, and
+/// which quickly rule out most of the profiles.
+pub(crate) struct StreamFingerprint {
+    /// the huffman calculations that reproduce all the dynamic trees of the stream
+    huff_calcs: Vec<HufftreeBitCalc>,
+    longest_len_3_dist: u32,
+}
+
+impl StreamFingerprint {
+    pub fn new(blocks: &[PreflateTokenBlock]) -> Self {
+        let huff_calcs = [
+            HufftreeBitCalc::Zlib,
+            HufftreeBitCalc::Miniz,
+            HufftreeBitCalc::SevenZip,
+            HufftreeBitCalc::Zopfli,
+            HufftreeBitCalc::Libdeflate,
+        ]
+        .into_iter()
+        .filter(|&calc| {
+            blocks
+                .iter()
+                .filter(|b| b.block_type == BlockType::DynamicHuff)
+                .all(|b| {
+                    let (literals, distances) = b.huffman_encoding.get_literal_distance_lengths();
+                    same_bit_lengths(calc, &b.freq.literal_codes, &literals)
+                        && same_bit_lengths(calc, &b.freq.distance_codes, &distances)
+                })
+        })
+        .collect();
+
+        let longest_len_3_dist = blocks
+            .iter()
+            .flat_map(|b| b.tokens.iter())
+            .filter_map(|t| match t {
+                PreflateToken::Reference(r) if r.len() == 3 => Some(r.dist()),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0);
+
+        StreamFingerprint {
+            huff_calcs,
+            longest_len_3_dist,
+        }
+    }
+
+    /// whether the calculation reproduces all the dynamic trees of the stream
+    pub fn reproduces_trees(&self, calc: HufftreeBitCalc) -> bool {
+        self.huff_calcs.contains(&calc)
+    }
+
+    /// the huffman calculation that reproduces the trees of the stream, zlib if none does
+    pub fn huff_calc(&self) -> HufftreeBitCalc {
+        self.huff_calcs
+            .first()
+            .copied()
+            .unwrap_or(HufftreeBitCalc::Zlib)
+    }
+}
+
+/// whether the calculation gives the bit lengths of the tree, which may have trailing zeros
+fn same_bit_lengths(calc: HufftreeBitCalc, freq: &[u16], bit_lengths: &[u8]) -> bool {
+    let mut calculated = calc_bit_lengths(calc, freq, 15);
+    if calculated.len() > bit_lengths.len() {
+        return false;
+    }
+    calculated.resize(bit_lengths.len(), 0);
+    calculated == bit_lengths
+}
+
+/// The profiles that can be selected by name in the PreflateConfig, in addition to the built
+/// in ones. The selected profile is stored in the corrections, so the profiles don't need to be
+/// registered for recompressing.
+#[derive(Debug, Clone, Default)]
+pub struct CompressorProfileRegistry {
+    profiles: Vec<CompressorProfile>,
+}
+
+impl CompressorProfileRegistry {
+    /// registers a profile, replacing a previously registered profile with the same name
+    pub fn register(&mut self, profile: CompressorProfile) {
+        self.profiles.retain(|p| p.name != profile.name);
+        self.profiles.push(profile);
+    }
+
+    /// looks up the profile with the given name, the registered profiles take
+    /// priority over the built in ones
+    pub fn find(&self, name: &str) -> Option<&CompressorProfile> {
+        self.profiles().find(|p| p.name == name)
+    }
+
+    /// the registered profiles in the order in which they were registered, followed by the
+    /// built in ones
+    pub fn profiles(&self) -> impl Iterator<Item = &CompressorProfile> {
+        self.profiles.iter().chain(builtin_profiles().iter())
+    }
+
+    /// The profile of the compressor that a stream was detected to come from, which is the first
+    /// one with the hash, parser settings and huffman trees that the stream was predicted with.
+    /// None if the parameters were estimated without one of the profiles fitting the stream.
+    pub fn identify(&self, params: &PreflateParameters) -> Option<&CompressorProfile> {
+        self.profiles().find(|p| {
+            p.hash_algorithm == params.hash_algorithm
+                && p.hash_shift == params.hash_shift
+                && p.hash_mask == params.hash_mask
+                && p.fast_parser == params.is_fast_compressor
+                && p.huff_calc == params.huff_calc
+                && p.parser_config.good_length == params.good_length
+                && p.parser_config.max_lazy == params.max_lazy
+                && p.parser_config.nice_length == params.nice_length
+                && p.parser_config.max_chain == params.max_chain
+                && (p.quirks.match_lengths == MatchLengths::Shortened)
+                    == matches!(params.strategy, PreflateStrategy::Optimal)
+        })
+    }
+}
+
+#[test]
+fn register_replaces_profile_with_same_name() {
+    let config = PreflateParserConfig {
+        good_length: 8,
+        max_lazy: 16,
+        nice_length: 128,
+        max_chain: 128,
+    };
+
+    let mut registry = CompressorProfileRegistry::default();
+    registry.register(CompressorProfile::zlib("mine", config, false));
+    registry.register(CompressorProfile::zlib("other", config, false));
+    registry.register(CompressorProfile::zlib("mine", config, true));
+
+    assert_eq!(registry.profiles().count(), 2 + builtin_profiles().len());
+    assert!(registry.find("mine").unwrap().fast_parser);
+    assert!(registry.find("unknown").is_none());
+
+    // registered profiles shadow the built in ones
+    registry.register(CompressorProfile::zlib("zlib-1", config, false));
+    assert!(!registry.find("zlib-1").unwrap().fast_parser);
+    assert!(registry.find("zlib-2").unwrap().fast_parser);
+}
+
+#[test]
+fn builtin_profile_names_are_unique() {
+    let profiles = builtin_profiles();
+    for (i, p) in profiles.iter().enumerate() {
+        assert!(
+            profiles[..i].iter().all(|q| q.name != p.name),
+            "{} is duplicated",
+            p.name
+        );
+    }
+
+    assert_eq!(
+        profiles
+            .iter()
+            .find(|p| p.name == "dotnet-optimal")
+            .unwrap()
+            .parser_config,
+        SLOW_PREFLATE_PARSER_SETTINGS[2]
+    );
+}
+
+/// the Java profiles are the levels of zlib under another name, so a stream written with
+/// java.util.zip is identified as zlib
+#[test]
+fn java_profiles_are_zlib_levels() {
+    let registry = CompressorProfileRegistry::default();
+    for level in 1..=9 {
+        let java = registry.find(&format!("java-{}", level)).unwrap();
+        let zlib = registry.find(&format!("zlib-{}", level)).unwrap();
+        assert_eq!(java.parser_config, zlib.parser_config);
+        assert_eq!(java.quirks, zlib.quirks);
+    }
+}
+
+#[test]
+fn refinements_follow_the_expensive_corrections() {
+    let profile = CompressorProfile::zlib("z", SLOW_PREFLATE_PARSER_SETTINGS[2], false);
+
+    // nothing to improve
+    assert!(profile
+        .refinements(&CountNonDefaultActions::default())
+        .is_empty());
+
+    // missed matches first of all ask for a longer chain
+    let mut statistics = CountNonDefaultActions::default();
+    statistics.record_misprediction(CodecMisprediction::LiteralPredictionWrong, true);
+    let refined = profile.refinements(&statistics);
+    assert_eq!(refined[0].name, "z+chain-256");
+    assert_eq!(refined[0].parser_config.max_chain, 256);
+
+    // the same length at a different distance points to another hash function
+    let mut statistics = CountNonDefaultActions::default();
+    statistics.record_correction(CodecCorrection::DistOnlyCorrection, 3);
+    let refined = profile.refinements(&statistics);
+    assert_eq!(refined.len(), 6);
+    assert!(refined.iter().all(
+        |p| p.hash_algorithm != HASH_ALGORITHM_ZLIB && p.parser_config == profile.parser_config
+    ));
+
+    // matches that the compressor didn't take may mean that it is more lazy
+    let mut statistics = CountNonDefaultActions::default();
+    statistics.record_misprediction(CodecMisprediction::ReferencePredictionWrong, true);
+    let names: Vec<_> = profile
+        .refinements(&statistics)
+        .into_iter()
+        .map(|p| p.name)
+        .collect();
+    assert_eq!(names, ["z+chain-64", "z+lazy-32"]);
+}
@@ -0,0 +1,109 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Expansion of whole files that contain deflate streams at known positions. The streams are
+//! replaced by their plain text and everything else in the file is kept as it is, so that
+//! the file can be recreated byte for byte from the expanded file and the corrections.
+
+use std::io::Cursor;
+
+use crate::{
+    archive_summary::{ArchiveSummary, EntryOutcome},
+    nested_streams::{
+        expand_streams, read_streams, restore_streams, write_streams, EmbeddedStreamKind,
+    },
+    preflate_config::PreflateConfig,
+    preflate_error::PreflateError,
+};
+
+/// where the raw deflate data of a stream is located in a file
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct StreamExtent {
+    /// offset of the first byte of the deflate data
+    pub offset: usize,
+    /// number of bytes that belong to the stream, the deflate data may end before this
+    pub length: usize,
+}
+
+/// result of expand_file
+pub struct ExpandedFile {
+    /// the file with each of the streams that could be processed replaced by its plain text
+    pub plain_text: Vec<u8>,
+    /// the corrections needed to recreate the file from the plain text
+    pub corrections: Vec<u8>,
+    /// what happened to each of the streams
+    pub summary: ArchiveSummary,
+}
+
+/// Expands the deflate streams at the given extents of the file. Streams that can't be
+/// processed are left in the file as they are. The nested streams inside of the streams
+/// are expanded as well if the config asks for it.
+pub fn expand_file(
+    data: &[u8],
+    extents: impl IntoIterator<Item = StreamExtent>,
+    config: &PreflateConfig,
+) -> ExpandedFile {
+    expand_file_with_kinds(data, extents.into_iter().map(|e| (e, None)), config, 1)
+}
+
+/// like expand_file, but with the kind of each stream if it is known, which is needed
+/// for the streams that aren't regular deflate, and the number of threads that expand
+/// the streams (0 uses all the available cores)
+pub(crate) fn expand_file_with_kinds(
+    data: &[u8],
+    extents: impl IntoIterator<Item = (StreamExtent, Option<EmbeddedStreamKind>)>,
+    config: &PreflateConfig,
+    threads: usize,
+) -> ExpandedFile {
+    let mut summary = ArchiveSummary::default();
+
+    let mut extents: Vec<_> = extents.into_iter().collect();
+    extents.sort_by_key(|(e, _)| e.offset);
+
+    let ranges = extents.into_iter().filter_map(|(e, kind)| {
+        let range = e.offset..e.offset.checked_add(e.length)?;
+        if range.end > data.len() {
+            summary.record_failed(EntryOutcome::Skipped, Default::default());
+            return None;
+        }
+        Some((range, kind))
+    });
+
+    // the summary is also borrowed by the filter above, so collect first
+    let ranges: Vec<_> = ranges.collect();
+    let (plain_text, nested) = expand_streams(
+        data,
+        ranges,
+        config,
+        config.nested_depth,
+        false,
+        threads,
+        &mut summary,
+    );
+
+    let mut corrections = Vec::new();
+    write_streams(&mut corrections, &nested);
+
+    ExpandedFile {
+        plain_text,
+        corrections,
+        summary,
+    }
+}
+
+/// recreates the original file from the result of expand_file. The config needs
+/// to use the same codec as the one that was used for expanding.
+pub fn recreate_file(
+    plain_text: &[u8],
+    corrections: &[u8],
+    config: &PreflateConfig,
+) -> Result<Vec<u8>, PreflateError> {
+    let nested = read_streams(&mut Cursor::new(corrections)).map_err(|e| {
+        PreflateError::RecompressFailed(anyhow::anyhow!("invalid file corrections: {}", e))
+    })?;
+
+    restore_streams(plain_text, &nested, config)
+}
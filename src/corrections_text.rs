@@ -0,0 +1,273 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Canonical text form of the corrections, one action per line. This is meant for golden files
+//! and for reviewing how a change in the predictor affects the corrections, since the cabac data
+//! itself can't be diffed in a meaningful way.
+//!
+//! The format is:
+//! ```text
+//! original <length of the deflate stream> <crc32 as 8 hex digits>
+//! verify <message> <checksum as 16 hex digits>
+//! misprediction <context> <0|1>
+//! correction <context> <value>
+//! bucket-correction <context> <bucket> <value>
+//! value <value> <max_bits>
+//! ```
+//!
+//! The original line comes first and appears exactly once.
+
+use std::fmt::Write;
+
+use crate::{
+    cabac_codec::{
+        frame_corrections, with_adaptive_encoder, with_cabac_decoder, CorrectionsHeader,
+        OriginalStream,
+    },
+    preflate_config::PreflateConfig,
+    preflate_error::PreflateError,
+    process::write_deflate,
+    statistical_codec::{
+        CodecAction, CodecCorrection, CodecMisprediction, PredictionDecoder, PredictionEncoder,
+        ALL_CORRECTIONS, ALL_MISPREDICTIONS,
+    },
+};
+
+/// decoder that records every action that the inner decoder returns
+struct RecordingDecoder<D> {
+    inner: D,
+    actions: Vec<CodecAction>,
+}
+
+impl<D: PredictionDecoder> PredictionDecoder for RecordingDecoder<D> {
+    fn decode_value(&mut self, max_bits_orig: u8) -> u16 {
+        let value = self.inner.decode_value(max_bits_orig);
+        self.actions.push(CodecAction::Value(value, max_bits_orig));
+        value
+    }
+
+    fn decode_correction(&mut self, correction: CodecCorrection) -> u32 {
+        let value = self.inner.decode_correction(correction);
+        self.actions
+            .push(CodecAction::Correction(correction, value));
+        value
+    }
+
+    fn decode_bucket_correction(&mut self, correction: CodecCorrection, bucket: u8) -> u32 {
+        let value = self.inner.decode_bucket_correction(correction, bucket);
+        self.actions
+            .push(CodecAction::BucketCorrection(correction, bucket, value));
+        value
+    }
+
+    fn decode_misprediction(&mut self, misprediction: CodecMisprediction) -> bool {
+        let value = self.inner.decode_misprediction(misprediction);
+        self.actions
+            .push(CodecAction::Misprediction(misprediction, value));
+        value
+    }
+
+    fn decode_verify_state(&mut self, message: &'static str, checksum: u64) {
+        self.inner.decode_verify_state(message, checksum);
+        self.actions
+            .push(CodecAction::VerifyState(message, checksum));
+    }
+}
+
+/// Decodes the cabac corrections and returns them in the canonical text form. The plain text
+/// is needed since the decoder only knows which action comes next by replaying the stream.
+pub fn dump_corrections(plain_text: &[u8], cabac_encoded: &[u8]) -> Result<String, PreflateError> {
+    let (original, actions) = with_cabac_decoder!(cabac_encoded, |decoder, original| (
+        original,
+        record_actions(plain_text, &mut decoder)?
+    ));
+
+    Ok(format!(
+        "original {} {:08x}\n{}",
+        original.length,
+        original.crc32,
+        format_actions(&actions)
+    ))
+}
+
+fn record_actions<D: PredictionDecoder>(
+    plain_text: &[u8],
+    decoder: D,
+) -> Result<Vec<CodecAction>, PreflateError> {
+    let mut decoder = RecordingDecoder {
+        inner: decoder,
+        actions: Vec::new(),
+    };
+
+    write_deflate(plain_text, &mut decoder)?;
+
+    Ok(decoder.actions)
+}
+
+/// Parses the canonical text form and encodes it back into cabac corrections. The verify
+/// lines are accepted but not needed, since the cabac format doesn't store them. The result
+/// uses the layout that decompress_deflate_stream writes with the default config.
+pub fn import_corrections(text: &str) -> Result<Vec<u8>, PreflateError> {
+    let invalid_line = |line_number: usize, line: &str| {
+        PreflateError::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("invalid corrections line {}: {}", line_number + 1, line),
+        ))
+    };
+
+    let first_line = text.lines().next().unwrap_or_default();
+    let original = parse_original(first_line).ok_or_else(|| invalid_line(0, first_line))?;
+
+    let header = CorrectionsHeader::from_config(&PreflateConfig::default());
+    let mut cabac_encoded = vec![header.to_byte()];
+    cabac_encoded.extend_from_slice(&original.to_bytes());
+
+    with_adaptive_encoder!(header, &mut cabac_encoded, |encoder| {
+        for (line_number, line) in text.lines().enumerate().skip(1) {
+            match parse_line(line) {
+                Some(Some(CodecAction::Misprediction(context, value))) => {
+                    encoder.encode_misprediction(context, value)
+                }
+                Some(Some(CodecAction::Correction(context, value))) => {
+                    encoder.encode_correction(context, value)
+                }
+                Some(Some(CodecAction::BucketCorrection(context, bucket, value))) => {
+                    encoder.encode_bucket_correction(context, bucket, value)
+                }
+                Some(Some(CodecAction::Value(value, max_bits))) => {
+                    encoder.encode_value(value, max_bits)
+                }
+                Some(Some(CodecAction::VerifyState(..))) | Some(None) => {}
+                None => return Err(invalid_line(line_number, line)),
+            }
+        }
+
+        encoder.finish();
+    });
+
+    Ok(frame_corrections(&cabac_encoded))
+}
+
+fn format_actions(actions: &[CodecAction]) -> String {
+    let mut text = String::new();
+    for action in actions {
+        write_action(&mut text, action);
+    }
+    text
+}
+
+/// appends the line of a single action, including the newline
+pub(crate) fn write_action(text: &mut String, action: &CodecAction) {
+    match action {
+        CodecAction::Misprediction(context, value) => {
+            writeln!(text, "misprediction {:?} {}", context, u8::from(*value))
+        }
+        CodecAction::Correction(context, value) => {
+            writeln!(text, "correction {:?} {}", context, value)
+        }
+        CodecAction::BucketCorrection(context, bucket, value) => {
+            writeln!(text, "bucket-correction {:?} {} {}", context, bucket, value)
+        }
+        CodecAction::Value(value, max_bits) => writeln!(text, "value {} {}", value, max_bits),
+        CodecAction::VerifyState(message, checksum) => {
+            writeln!(text, "verify {} {:016x}", message, checksum)
+        }
+    }
+    .unwrap();
+}
+
+/// parses the line with the length and the crc32 of the original deflate stream
+fn parse_original(line: &str) -> Option<OriginalStream> {
+    let mut fields = line.split_ascii_whitespace();
+    if fields.next()? != "original" {
+        return None;
+    }
+    let original = OriginalStream {
+        length: fields.next()?.parse().ok()?,
+        crc32: u32::from_str_radix(fields.next()?, 16).ok()?,
+    };
+    fields.next().is_none().then_some(original)
+}
+
+/// parses a single line. Returns None if the line is invalid, Some(None) for verify
+/// or empty lines, which don't carry any information for the encoder.
+fn parse_line(line: &str) -> Option<Option<CodecAction>> {
+    let mut fields = line.split_ascii_whitespace();
+    let Some(kind) = fields.next() else {
+        return Some(None);
+    };
+    let (a, b) = (fields.next()?, fields.next()?);
+    let c = fields.next();
+    if fields.next().is_some() || (c.is_some() != (kind == "bucket-correction")) {
+        return None;
+    }
+
+    let action = match kind {
+        "misprediction" => CodecAction::Misprediction(
+            *ALL_MISPREDICTIONS
+                .iter()
+                .find(|x| format!("{:?}", x) == a)?,
+            match b {
+                "0" => false,
+                "1" => true,
+                _ => return None,
+            },
+        ),
+        "correction" => CodecAction::Correction(
+            *ALL_CORRECTIONS.iter().find(|x| format!("{:?}", x) == a)?,
+            b.parse().ok()?,
+        ),
+        "bucket-correction" => CodecAction::BucketCorrection(
+            *ALL_CORRECTIONS.iter().find(|x| format!("{:?}", x) == a)?,
+            b.parse().ok()?,
+            c?.parse().ok()?,
+        ),
+        "value" => CodecAction::Value(a.parse().ok()?, b.parse().ok()?),
+        "verify" => {
+            u64::from_str_radix(b, 16).ok()?;
+            return Some(None);
+        }
+        _ => return None,
+    };
+
+    Some(Some(action))
+}
+
+#[test]
+fn roundtrip_corrections_text() {
+    use crate::process::read_file;
+
+    let compressed_data = read_file("compressed_zlib_level1.deflate");
+    let result = crate::decompress_deflate_stream(&compressed_data, true).unwrap();
+
+    let text = dump_corrections(&result.plain_text, &result.cabac_encoded).unwrap();
+    assert!(text.starts_with(&format!(
+        "original {} {:08x}\n",
+        compressed_data.len(),
+        crc32fast::hash(&compressed_data)
+    )));
+    assert!(text.contains("\nverify blocktypestart 0000000000000000\n"));
+
+    let reimported = import_corrections(&text).unwrap();
+    assert_eq!(reimported, result.cabac_encoded);
+
+    // the dump is canonical, so dumping the reimported data gives the same text
+    assert_eq!(
+        dump_corrections(&result.plain_text, &reimported).unwrap(),
+        text
+    );
+}
+
+#[test]
+fn import_corrections_rejects_garbage() {
+    assert!(import_corrections("original 10 0\ncorrection NoSuchCorrection 1\n").is_err());
+    assert!(import_corrections("original 10 0\nmisprediction EOFMisprediction 2\n").is_err());
+    assert!(import_corrections("original 10 0\nvalue 1\n").is_err());
+
+    // the original line is required
+    assert!(import_corrections("value 1 8\n").is_err());
+    assert!(import_corrections("original 10\nvalue 1 8\n").is_err());
+}
@@ -0,0 +1,305 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! The deflate reader that the prediction is built on, as a parser of its own. It reads a
+//! stream block by block and returns the header, huffman tables and symbols of each block
+//! along with the plain text. It is strict: invalid codes, distances that go back before the
+//! start of the stream and stored blocks with a wrong NLEN (unless allowed) are errors.
+
+use std::io::Read;
+
+use crate::{
+    deflate_reader::DeflateReader,
+    huffman_encoding::{HuffmanOriginalEncoding, TreeCodeType},
+    preflate_constants::NONLEN_CODE_COUNT,
+    preflate_error::PreflateError,
+    preflate_token::{BlockType, PreflateToken},
+    statistical_codec::BlockCost,
+};
+
+/// the kind of a deflate block (BTYPE)
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DeflateBlockType {
+    Stored,
+    StaticHuffman,
+    DynamicHuffman,
+}
+
+impl From<BlockType> for DeflateBlockType {
+    fn from(block_type: BlockType) -> Self {
+        match block_type {
+            BlockType::Stored => DeflateBlockType::Stored,
+            BlockType::StaticHuff => DeflateBlockType::StaticHuffman,
+            BlockType::DynamicHuff => DeflateBlockType::DynamicHuffman,
+        }
+    }
+}
+
+/// one entry of the run length encoded code lengths in the header of a dynamic block
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CodeLengthSymbol {
+    /// symbols 0-15, a code length
+    Length(u8),
+    /// symbol 16, the previous code length repeated 3-6 times
+    RepeatPrevious(u8),
+    /// symbol 17, a code length of 0 repeated 3-10 times
+    RepeatZeroShort(u8),
+    /// symbol 18, a code length of 0 repeated 11-138 times
+    RepeatZeroLong(u8),
+}
+
+/// the header of a dynamic huffman block as it was written
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DynamicHeader {
+    /// number of literal/length codes, which is HLIT + 257
+    pub hlit: usize,
+    /// number of distance codes, which is HDIST + 1
+    pub hdist: usize,
+    /// number of code length codes that were written, which is HCLEN + 4
+    pub hclen: usize,
+    /// the bit lengths of the code length alphabet, indexed by symbol (0-18) rather than in the
+    /// order they are written in
+    pub code_length_code_lengths: [u8; 19],
+    /// the code lengths of the literal/length and distance codes as they were encoded
+    pub code_length_symbols: Vec<CodeLengthSymbol>,
+    /// the decoded bit lengths of the hlit literal/length codes
+    pub literal_lengths: Vec<u8>,
+    /// the decoded bit lengths of the hdist distance codes
+    pub distance_lengths: Vec<u8>,
+}
+
+impl DynamicHeader {
+    fn from_encoding(encoding: &HuffmanOriginalEncoding) -> Self {
+        let (literal_lengths, distance_lengths) = encoding.get_literal_distance_lengths();
+
+        DynamicHeader {
+            hlit: encoding.num_literals,
+            hdist: encoding.num_dist,
+            hclen: encoding.num_code_lengths,
+            code_length_code_lengths: encoding.code_lengths,
+            code_length_symbols: encoding
+                .lengths
+                .iter()
+                .map(|&(code, value)| match code {
+                    TreeCodeType::Code => CodeLengthSymbol::Length(value),
+                    TreeCodeType::Repeat => CodeLengthSymbol::RepeatPrevious(value),
+                    TreeCodeType::ZeroShort => CodeLengthSymbol::RepeatZeroShort(value),
+                    TreeCodeType::ZeroLong => CodeLengthSymbol::RepeatZeroLong(value),
+                })
+                .collect(),
+            literal_lengths,
+            distance_lengths,
+        }
+    }
+}
+
+/// a symbol of a huffman block, the end of block symbol is left out
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DeflateSymbol {
+    Literal(u8),
+    Match {
+        length: u32,
+        distance: u32,
+        /// the literal/length symbol (257-285) that the length was written with. A length
+        /// of 258 can also be written as 284 with all extra bits set.
+        length_code: u16,
+    },
+}
+
+/// one block of a deflate stream
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedBlock {
+    pub block_type: DeflateBlockType,
+    /// whether BFINAL was set
+    pub last: bool,
+    /// where the plain text of the block starts in the plain text of the stream
+    pub plain_text_start: usize,
+    /// number of bytes of plain text that the block decodes to
+    pub uncompressed_len: u32,
+    /// the literals and matches of a huffman block, empty for a stored block
+    pub symbols: Vec<DeflateSymbol>,
+    /// the huffman header of a dynamic block
+    pub dynamic_header: Option<DynamicHeader>,
+    /// the value of the bits that pad a stored block to the next byte
+    pub padding_bits: u8,
+    /// the number of bits of the header, tree and symbols. The correction bits are always 0.
+    pub cost: BlockCost,
+}
+
+/// Reads the blocks of a deflate stream one at a time. The parser can also be used as an
+/// iterator over the blocks, which ends after the final block or the first error.
+pub struct DeflateParser<R> {
+    reader: DeflateReader<R>,
+    blocks_read: usize,
+    finished: bool,
+}
+
+impl<R: Read> DeflateParser<R> {
+    pub fn new(compressed_data: R) -> Self {
+        DeflateParser {
+            reader: DeflateReader::new(compressed_data),
+            blocks_read: 0,
+            finished: false,
+        }
+    }
+
+    /// Parser for untrusted input whose compressed size was declared by a header, such as the
+    /// compressed size of a zip entry. A stream that doesn't end within that size fails to
+    /// parse instead of reading the data after it.
+    pub fn with_compressed_size(compressed_data: R, compressed_size: u64) -> Self {
+        DeflateParser {
+            reader: DeflateReader::with_compressed_size(compressed_data, compressed_size),
+            blocks_read: 0,
+            finished: false,
+        }
+    }
+
+    /// accept stored blocks whose NLEN isn't the complement of LEN instead of failing
+    pub fn set_lenient_stored_len(&mut self, lenient: bool) {
+        self.reader.set_lenient_stored_len(lenient);
+    }
+
+    /// read the stream as deflate64
+    pub fn set_deflate64(&mut self, deflate64: bool) {
+        self.reader.set_deflate64(deflate64);
+    }
+
+    /// Reads the next block, or returns None once the final block has been read or a
+    /// block failed to parse.
+    pub fn next_block(&mut self) -> Result<Option<ParsedBlock>, PreflateError> {
+        if self.finished {
+            return Ok(None);
+        }
+
+        let plain_text_start = self.reader.plain_text().len();
+        let mut last = false;
+        let block = match self.reader.read_block(&mut last) {
+            Ok(block) => block,
+            Err(e) => {
+                self.finished = true;
+                return Err(PreflateError::read_block(self.blocks_read, e));
+            }
+        };
+
+        self.blocks_read += 1;
+        self.finished = last;
+
+        let plain_text = &self.reader.plain_text()[plain_text_start..];
+        let mut position = 0;
+        let symbols = block
+            .tokens
+            .iter()
+            .map(|token| match token {
+                PreflateToken::Literal => {
+                    position += 1;
+                    DeflateSymbol::Literal(plain_text[position - 1])
+                }
+                PreflateToken::Reference(r) => {
+                    position += r.len() as usize;
+                    DeflateSymbol::Match {
+                        length: r.len(),
+                        distance: r.dist(),
+                        length_code: (NONLEN_CODE_COUNT + r.irregular().length_code(r.len()).0)
+                            as u16,
+                    }
+                }
+            })
+            .collect();
+
+        Ok(Some(ParsedBlock {
+            block_type: block.block_type.into(),
+            last,
+            plain_text_start,
+            uncompressed_len: plain_text.len() as u32,
+            symbols,
+            dynamic_header: (block.block_type == BlockType::DynamicHuff)
+                .then(|| DynamicHeader::from_encoding(&block.huffman_encoding)),
+            padding_bits: block.padding_bits,
+            cost: self.reader.last_block_cost(),
+        }))
+    }
+
+    /// whether the final block has been read
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// the plain text of all the blocks read so far
+    pub fn plain_text(&self) -> &[u8] {
+        self.reader.plain_text()
+    }
+
+    /// takes the plain text of all the blocks read so far
+    pub fn into_plain_text(mut self) -> Vec<u8> {
+        self.reader.move_plain_text()
+    }
+
+    /// number of bits that have been consumed from the compressed data
+    pub fn bit_position(&self) -> u64 {
+        self.reader.bit_position()
+    }
+}
+
+impl<R: Read> Iterator for DeflateParser<R> {
+    type Item = Result<ParsedBlock, PreflateError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_block().transpose()
+    }
+}
+
+#[test]
+fn parse_symbols_of_static_block() {
+    // "abcabcabc" compressed by zlib with fixed huffman codes, which found the match one byte late
+    let compressed_data = [0x4b, 0x4c, 0x4a, 0x4e, 0x04, 0x23, 0x00];
+
+    let mut parser = DeflateParser::new(&compressed_data[..]);
+    let block = parser.next_block().unwrap().unwrap();
+
+    assert_eq!(block.block_type, DeflateBlockType::StaticHuffman);
+    assert!(block.last);
+    assert_eq!(
+        block.symbols,
+        [
+            DeflateSymbol::Literal(b'a'),
+            DeflateSymbol::Literal(b'b'),
+            DeflateSymbol::Literal(b'c'),
+            DeflateSymbol::Literal(b'a'),
+            DeflateSymbol::Match {
+                length: 5,
+                distance: 3,
+                length_code: 259
+            }
+        ]
+    );
+    assert!(parser.next_block().unwrap().is_none());
+    assert_eq!(parser.into_plain_text(), b"abcabcabc");
+
+    // a stored block of 1 byte whose NLEN is 0 instead of 0xfffe
+    let stored = [0x01, 0x01, 0x00, 0x00, 0x00, b'x'];
+    let mut parser = DeflateParser::new(&stored[..]);
+    assert!(parser.next_block().is_err());
+    assert!(parser.next().is_none());
+
+    let mut parser = DeflateParser::new(&stored[..]);
+    parser.set_lenient_stored_len(true);
+    let blocks: Vec<_> = parser.by_ref().collect::<Result<_, _>>().unwrap();
+    assert_eq!(blocks[0].block_type, DeflateBlockType::Stored);
+    assert_eq!(parser.plain_text(), b"x");
+}
+
+#[test]
+fn parse_within_compressed_size() {
+    let compressed_data = [0x4b, 0x4c, 0x4a, 0x4e, 0x04, 0x23, 0x00, 0xff, 0xff];
+
+    let parser = DeflateParser::with_compressed_size(&compressed_data[..], 7);
+    assert_eq!(parser.count(), 1);
+
+    // the block continues past the declared size, so it fails even though the data is there
+    let mut parser = DeflateParser::with_compressed_size(&compressed_data[..], 5);
+    assert!(parser.next_block().is_err());
+    assert!(parser.next_block().unwrap().is_none());
+}
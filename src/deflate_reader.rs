@@ -12,7 +12,9 @@ use crate::{
     bit_reader::BitReader,
     huffman_encoding::{HuffmanOriginalEncoding, HuffmanReader},
     preflate_constants,
-    preflate_token::{BlockType, PreflateTokenBlock},
+    preflate_error::{DeflateError, PreflateError, ResourceLimit},
+    preflate_token::{BlockType, FlushMarker, IrregularEncoding, PreflateTokenBlock},
+    statistical_codec::BlockCost,
 };
 
 /// Used to read binary data in deflate format and convert it to plaintext and a list of tokenized blocks
@@ -20,26 +22,131 @@ use crate::{
 pub struct DeflateReader<R> {
     input: BitReader<R>,
     plain_text: Vec<u8>,
+    lenient_stored_len: bool,
+    deflate64: bool,
+    max_plain_text_size: usize,
+    last_block_cost: BlockCost,
+    /// the block that the data ended in, which the next read_block continues
+    partial: Option<PartialBlock>,
+}
+
+/// What was read of a block before the data ended partway through its tokens or its stored
+/// bytes. Once more of the data has arrived, a reader that starts at bit_position continues
+/// the block (see DeflateReader::resume_block), so that it doesn't have to be read again from
+/// its start.
+pub struct PartialBlock {
+    block: PreflateTokenBlock,
+    last: bool,
+    /// None for a stored block
+    decoder: Option<HuffmanReader>,
+    /// the bytes of a stored block that are still to come
+    stored_len: u32,
+    cost: BlockCost,
+    earliest_reference: i32,
+    cur_pos: i32,
+    bit_position: u64,
+}
+
+impl PartialBlock {
+    /// where the rest of the block starts, in the bit positions of the reader that read it
+    pub fn bit_position(&self) -> u64 {
+        self.bit_position
+    }
 }
 
 impl<R: Read> DeflateReader<R> {
     pub fn new(compressed_text: R) -> Self {
+        Self::from_bit_reader(BitReader::new(compressed_text))
+    }
+
+    /// Reader for a stream whose size was declared by an untrusted header. Reading the blocks
+    /// fails instead of reading more than compressed_size bytes of the input.
+    pub fn with_compressed_size(compressed_text: R, compressed_size: u64) -> Self {
+        Self::from_bit_reader(BitReader::with_limit(compressed_text, compressed_size))
+    }
+
+    fn from_bit_reader(input: BitReader<R>) -> Self {
         DeflateReader {
-            input: BitReader::new(compressed_text),
+            input,
             plain_text: Vec::new(),
+            lenient_stored_len: false,
+            deflate64: false,
+            max_plain_text_size: usize::MAX,
+            last_block_cost: BlockCost::default(),
+            partial: None,
+        }
+    }
+
+    /// Continues reading a stream at the start of a block, where plain_text is what the
+    /// blocks before it decoded to (needed for the matches that refer back into it).
+    pub fn with_plain_text(compressed_text: R, plain_text: Vec<u8>) -> Self {
+        DeflateReader {
+            plain_text,
+            ..DeflateReader::new(compressed_text)
+        }
+    }
+
+    /// Same as with_plain_text, for a stream whose size was declared by an untrusted header
+    /// (see with_compressed_size), where compressed_size is what is left of it.
+    pub fn with_plain_text_and_size(
+        compressed_text: R,
+        plain_text: Vec<u8>,
+        compressed_size: u64,
+    ) -> Self {
+        DeflateReader {
+            plain_text,
+            ..DeflateReader::with_compressed_size(compressed_text, compressed_size)
         }
     }
 
+    /// the plain text decoded so far, which includes the part of a block that failed to decode
+    pub fn plain_text(&self) -> &[u8] {
+        &self.plain_text
+    }
+
+    /// number of bits that have been consumed from the compressed data
+    pub fn bit_position(&self) -> u64 {
+        self.input.bit_position()
+    }
+
+    /// skips the given number of bits (less than 8) of the compressed data
+    pub fn skip_bits(&mut self, cbits: u32) -> anyhow::Result<()> {
+        self.read_bits(cbits)?;
+        Ok(())
+    }
+
+    /// accept stored blocks whose NLEN isn't the complement of LEN instead of failing
+    pub fn set_lenient_stored_len(&mut self, lenient: bool) {
+        self.lenient_stored_len = lenient;
+    }
+
+    /// read the stream as deflate64, which has a 64K window, 16 extra bits for length code 285
+    /// and two more distance codes
+    pub fn set_deflate64(&mut self, deflate64: bool) {
+        self.deflate64 = deflate64;
+    }
+
+    /// stop with PreflateError::LimitExceeded once the plain text gets larger than this
+    pub fn set_max_plain_text_size(&mut self, max: usize) {
+        self.max_plain_text_size = max;
+    }
+
+    /// fails if adding len more bytes would make the plain text larger than the limit
+    fn check_plain_text_size(&self, len: usize) -> anyhow::Result<()> {
+        PreflateError::check_limit(
+            ResourceLimit::PlainTextSize,
+            (self.plain_text.len() + len) as u64,
+            self.max_plain_text_size as u64,
+        )
+        .map_err(anyhow::Error::new)
+    }
+
     /// reads the padding at the end of the file
     pub fn read_eof_padding(&mut self) -> u8 {
         let padding_bit_count = 8 - self.input.bit_position_in_current_byte() as u8;
         self.input.get(padding_bit_count.into()).unwrap() as u8
     }
 
-    pub fn get_plain_text(&self) -> &[u8] {
-        &self.plain_text
-    }
-
     /// moves ownership out of block reader
     pub fn move_plain_text(&mut self) -> Vec<u8> {
         std::mem::take(&mut self.plain_text)
@@ -65,15 +172,69 @@ impl<R: Read> DeflateReader<R> {
         }
     }
 
+    /// the number of bits that the parts of the last block that was read took up
+    pub fn last_block_cost(&self) -> BlockCost {
+        self.last_block_cost
+    }
+
+    /// After read_block failed because the data ended within the tokens or the stored bytes
+    /// of a block, returns what was read of it. The plain text of the tokens that were read
+    /// stays in the plain text of the reader.
+    pub fn take_partial_block(&mut self) -> Option<PartialBlock> {
+        self.partial.take()
+    }
+
+    /// Continues a block that take_partial_block returned, where the reader starts at the
+    /// bit_position of the block and the plain text is the one of the reader that read it.
+    /// The next read_block reads the rest of the block.
+    pub fn resume_block(&mut self, partial: PartialBlock) {
+        self.partial = Some(partial);
+    }
+
     pub fn read_block(&mut self, last: &mut bool) -> anyhow::Result<PreflateTokenBlock> {
-        let mut blk;
+        let mut partial = match self.partial.take() {
+            Some(partial) => partial,
+            None => self.read_block_header(last)?,
+        };
+        *last = partial.last;
+
+        let start = self.bit_position();
+        partial.bit_position = start;
+        let result = self.read_block_contents(&mut partial);
+        // the tokens of the block up to the one that the data ended in
+        let end = if result.is_ok() {
+            self.bit_position()
+        } else {
+            partial.bit_position
+        };
+        partial.cost.token_bits += end - start;
+
+        match result {
+            Ok(()) => {
+                self.last_block_cost = BlockCost {
+                    token_count: partial.block.tokens.len() as u32,
+                    ..partial.cost
+                };
+                Ok(partial.block)
+            }
+            Err(e) => {
+                self.partial = Some(partial);
+                Err(e)
+            }
+        }
+    }
+
+    /// reads the block up to its tokens or its stored bytes
+    fn read_block_header(&mut self, last: &mut bool) -> anyhow::Result<PartialBlock> {
+        let start = self.bit_position();
 
         *last = self.read_bit()?;
         let mode = self.read_bits(2)?;
+        let header_end = self.bit_position();
 
-        match mode {
+        let (blk, decoder, stored_len) = match mode {
             0 => {
-                blk = PreflateTokenBlock::new(BlockType::Stored);
+                let mut blk = PreflateTokenBlock::new(BlockType::Stored);
                 blk.block_type = BlockType::Stored;
                 let padding_bit_count = 8 - self.input.bit_position_in_current_byte() as u8;
                 blk.padding_bits = self.read_bits(padding_bit_count.into())? as u8;
@@ -81,91 +242,158 @@ impl<R: Read> DeflateReader<R> {
                 let len = self.read_bits(16)?;
                 let ilen = self.read_bits(16)?;
                 if (len ^ ilen) != 0xffff {
-                    return Err(anyhow::Error::msg("Blocllength mismatch"));
+                    if !self.lenient_stored_len {
+                        return Err(DeflateError::StoredLengthMismatch.into());
+                    }
+                    blk.nlen_mismatch = (len ^ ilen ^ 0xffff) as u16;
                 }
                 blk.uncompressed_len = len;
                 blk.context_len = 0;
-
-                self.input.flush_buffer_to_byte_boundary();
-
-                for _i in 0..len {
-                    let b = self.input.read_byte()?;
-                    self.write_literal(b);
+                if len == 0 {
+                    blk.flush = FlushMarker::Sync;
                 }
 
-                Ok(blk)
+                self.input.flush_buffer_to_byte_boundary();
+                self.check_plain_text_size(len as usize)?;
+                (blk, None, len)
             }
-            1 => {
-                blk = PreflateTokenBlock::new(BlockType::StaticHuff);
-                let decoder = HuffmanReader::create_fixed()?;
-                self.decode_block(&decoder, &mut blk)?;
-                Ok(blk)
+            1 => (
+                PreflateTokenBlock::new(BlockType::StaticHuff),
+                Some(HuffmanReader::create_fixed()?),
+                0,
+            ),
+            2 => {
+                let mut blk = PreflateTokenBlock::new(BlockType::DynamicHuff);
+                blk.huffman_encoding = HuffmanOriginalEncoding::read(&mut self.input)?;
+                let decoder = HuffmanReader::create_from_original_encoding(&blk.huffman_encoding)?;
+                (blk, Some(decoder), 0)
             }
 
-            2 => {
-                blk = PreflateTokenBlock::new(BlockType::DynamicHuff);
+            _ => return Err(DeflateError::InvalidBlockType.into()),
+        };
 
-                blk.huffman_encoding = HuffmanOriginalEncoding::read(&mut self.input)?;
+        // a stored block has no trees, and the bits up to its length belong to the header
+        let tree_end = self.bit_position();
+        let header_end = if decoder.is_some() {
+            header_end
+        } else {
+            tree_end
+        };
 
-                let decoder = HuffmanReader::create_from_original_encoding(&blk.huffman_encoding)?;
+        Ok(PartialBlock {
+            cost: BlockCost {
+                header_bits: header_end - start,
+                tree_bits: tree_end - header_end,
+                block_type: Some(blk.block_type.into()),
+                ..BlockCost::default()
+            },
+            block: blk,
+            last: *last,
+            decoder,
+            stored_len,
+            earliest_reference: i32::MAX,
+            cur_pos: 0,
+            bit_position: tree_end,
+        })
+    }
 
-                self.decode_block(&decoder, &mut blk)
-                    .with_context(|| "decode_block dyn")?;
-                Ok(blk)
+    /// Reads the tokens or the stored bytes of the block. If the data ends within them, the
+    /// bit_position of partial is where the token or the byte that was cut off starts.
+    fn read_block_contents(&mut self, partial: &mut PartialBlock) -> anyhow::Result<()> {
+        let Some(decoder) = partial.decoder.take() else {
+            while partial.stored_len > 0 {
+                let b = self.input.read_byte()?;
+                self.write_literal(b);
+                partial.stored_len -= 1;
+                partial.bit_position = self.bit_position();
             }
+            return Ok(());
+        };
 
-            _ => Err(anyhow::Error::msg("Invalid block type")),
+        let result = self.decode_block(&decoder, partial);
+        partial.decoder = Some(decoder);
+        if partial.block.block_type == BlockType::DynamicHuff {
+            result.with_context(|| "decode_block dyn")
+        } else {
+            result
         }
     }
 
     fn decode_block(
         &mut self,
         decoder: &HuffmanReader,
-        blk: &mut PreflateTokenBlock,
+        partial: &mut PartialBlock,
     ) -> anyhow::Result<()> {
-        let mut earliest_reference = i32::MAX;
-        let mut cur_pos = 0;
+        let blk = &mut partial.block;
 
         loop {
+            partial.bit_position = self.bit_position();
+
             let lit_len: u32 = decoder.fetch_next_literal_code(&mut self.input)?.into();
             if lit_len < 256 {
+                self.check_plain_text_size(1)?;
                 self.write_literal(lit_len as u8);
                 blk.add_literal(lit_len as u8);
-                cur_pos += 1;
+                partial.cur_pos += 1;
             } else if lit_len == 256 {
-                blk.uncompressed_len = cur_pos as u32;
-                blk.context_len = -earliest_reference;
+                blk.uncompressed_len = partial.cur_pos as u32;
+                blk.context_len = -partial.earliest_reference;
                 break;
             } else {
                 let lcode: u32 = lit_len - preflate_constants::NONLEN_CODE_COUNT as u32;
                 if lcode >= preflate_constants::LEN_CODE_COUNT as u32 {
-                    return Err(anyhow::Error::msg("Invalid length code"));
+                    return Err(DeflateError::InvalidLengthCode.into());
                 }
-                let len: u32 = preflate_constants::MIN_MATCH
-                    + preflate_constants::LENGTH_BASE_TABLE[lcode as usize] as u32
-                    + self
-                        .read_bits(preflate_constants::LENGTH_EXTRA_TABLE[lcode as usize].into())?;
+                let (len, irregular) = if self.deflate64
+                    && lcode == preflate_constants::LEN_CODE_COUNT as u32 - 1
+                {
+                    // only the lengths above 258 need the 16 extra bits, the shorter ones
+                    // could have been written with the regular length codes as well
+                    let len = preflate_constants::MIN_MATCH
+                        + self.read_bits(preflate_constants::DEFLATE64_LONG_LENGTH_EXTRA.into())?;
+                    if len > preflate_constants::MAX_MATCH {
+                        (len, IrregularEncoding::Canonical)
+                    } else {
+                        (len, IrregularEncoding::Deflate64Length)
+                    }
+                } else {
+                    let len: u32 = preflate_constants::MIN_MATCH
+                        + preflate_constants::LENGTH_BASE_TABLE[lcode as usize] as u32
+                        + self.read_bits(
+                            preflate_constants::LENGTH_EXTRA_TABLE[lcode as usize].into(),
+                        )?;
+
+                    // length of 258 can be encoded two ways: 284 with 5 one bits (non-standard) or as 285 with 0 extra bits (standard)
+                    (
+                        len,
+                        IrregularEncoding::from_length_code(len, lcode as usize),
+                    )
+                };
 
-                // length of 258 can be encoded two ways: 284 with 5 one bits (non-standard) or as 285 with 0 extra bits (standard)
-                let irregular_258 =
-                    len == 258 && lcode != preflate_constants::LEN_CODE_COUNT as u32 - 1;
+                let dist_code_count = if self.deflate64 {
+                    preflate_constants::DEFLATE64_DIST_CODE_COUNT
+                } else {
+                    preflate_constants::DIST_CODE_COUNT
+                };
+                self.check_plain_text_size(len as usize)?;
 
                 let dcode = decoder.fetch_next_distance_char(&mut self.input)? as u32;
-                if dcode >= preflate_constants::DIST_CODE_COUNT as u32 {
-                    return Err(anyhow::Error::msg("Invalid distance code"));
+                if dcode >= dist_code_count as u32 {
+                    return Err(DeflateError::InvalidDistanceCode.into());
                 }
                 let dist = 1
                     + preflate_constants::DIST_BASE_TABLE[dcode as usize] as u32
                     + self
                         .read_bits(preflate_constants::DIST_EXTRA_TABLE[dcode as usize].into())?;
                 if dist as usize > self.plain_text.len() {
-                    return Err(anyhow::Error::msg("Invalid distance"));
+                    return Err(DeflateError::DistanceTooFar.into());
                 }
                 self.write_reference(dist, len);
-                blk.add_reference(len, dist, irregular_258);
+                blk.add_reference(len, dist, irregular);
 
-                earliest_reference = std::cmp::min(earliest_reference, cur_pos - (dist as i32));
-                cur_pos += len as i32;
+                partial.earliest_reference =
+                    std::cmp::min(partial.earliest_reference, partial.cur_pos - (dist as i32));
+                partial.cur_pos += len as i32;
             }
         }
         Ok(())
@@ -9,10 +9,7 @@ use anyhow::Result;
 use crate::{
     bit_writer::BitWriter,
     huffman_encoding::HuffmanWriter,
-    preflate_constants::{
-        quantize_distance, quantize_length, DIST_BASE_TABLE, DIST_EXTRA_TABLE, LENGTH_BASE_TABLE,
-        LENGTH_EXTRA_TABLE, LITLEN_CODE_COUNT, MIN_MATCH, NONLEN_CODE_COUNT,
-    },
+    preflate_constants::{quantize_distance, DIST_BASE_TABLE, DIST_EXTRA_TABLE, NONLEN_CODE_COUNT},
     preflate_token::{BlockType, PreflateToken, PreflateTokenBlock},
 };
 
@@ -40,12 +37,32 @@ impl<'a> DeflateWriter<'a> {
         }
     }
 
+    /// Continues a stream where the writer before it stopped, with the bits of its last byte
+    /// that weren't written yet (see into_pending_bits) and the plain text of the next block.
+    pub fn with_pending_bits(plain_text: &'a [u8], bitwriter: BitWriter) -> Self {
+        Self {
+            bitwriter,
+            ..Self::new(plain_text)
+        }
+    }
+
+    /// the bits of the last byte that aren't in the output yet, which has to be detached first
+    pub fn into_pending_bits(self) -> BitWriter {
+        debug_assert!(self.output.is_empty());
+        self.bitwriter
+    }
+
     pub fn detach_output(&mut self) -> Vec<u8> {
         let mut o = Vec::new();
         o.append(&mut self.output);
         o
     }
 
+    /// number of bytes of output that haven't been detached yet
+    pub fn output_len(&self) -> usize {
+        self.output.len()
+    }
+
     pub fn encode_block(&mut self, block: &PreflateTokenBlock, last: bool) -> Result<()> {
         self.bitwriter.write(last as u32, 1, &mut self.output);
         match block.block_type {
@@ -56,8 +73,9 @@ impl<'a> DeflateWriter<'a> {
 
                 self.output
                     .extend_from_slice(&(block.uncompressed_len as u16).to_le_bytes());
-                self.output
-                    .extend_from_slice(&(!block.uncompressed_len as u16).to_le_bytes());
+                self.output.extend_from_slice(
+                    &(!block.uncompressed_len as u16 ^ block.nlen_mismatch).to_le_bytes(),
+                );
 
                 self.output.extend_from_slice(
                     &self.plain_text[self.plain_text_index
@@ -108,45 +126,34 @@ impl<'a> DeflateWriter<'a> {
                     index += 1;
                 }
                 PreflateToken::Reference(reference) => {
-                    if reference.get_irregular258() {
-                        huffman_writer.write_literal(
-                            &mut self.bitwriter,
-                            &mut self.output,
-                            LITLEN_CODE_COUNT as u16 - 2,
-                        );
-                        self.bitwriter.write(5, 31, &mut self.output);
-                    } else {
-                        let lencode = quantize_length(reference.len());
-                        huffman_writer.write_literal(
-                            &mut self.bitwriter,
-                            &mut self.output,
-                            NONLEN_CODE_COUNT as u16 + lencode as u16,
-                        );
+                    let (lencode, lenextra_value) =
+                        reference.irregular().length_code(reference.len());
+                    huffman_writer.write_literal(
+                        &mut self.bitwriter,
+                        &mut self.output,
+                        NONLEN_CODE_COUNT as u16 + lencode as u16,
+                    );
 
-                        let lenextra = LENGTH_EXTRA_TABLE[lencode];
-                        if lenextra > 0 {
-                            self.bitwriter.write(
-                                reference.len() - MIN_MATCH - LENGTH_BASE_TABLE[lencode] as u32,
-                                lenextra.into(),
-                                &mut self.output,
-                            );
-                        }
-
-                        let distcode = quantize_distance(reference.dist());
-                        huffman_writer.write_distance(
-                            &mut self.bitwriter,
+                    let lenextra = reference.irregular().length_extra_bits(reference.len());
+                    if lenextra > 0 {
+                        self.bitwriter
+                            .write(lenextra_value, lenextra.into(), &mut self.output);
+                    }
+
+                    let distcode = quantize_distance(reference.dist());
+                    huffman_writer.write_distance(
+                        &mut self.bitwriter,
+                        &mut self.output,
+                        distcode as u16,
+                    );
+
+                    let distextra = DIST_EXTRA_TABLE[distcode];
+                    if distextra > 0 {
+                        self.bitwriter.write(
+                            reference.dist() - 1 - DIST_BASE_TABLE[distcode] as u32,
+                            distextra.into(),
                             &mut self.output,
-                            distcode as u16,
                         );
-
-                        let distextra = DIST_EXTRA_TABLE[distcode];
-                        if distextra > 0 {
-                            self.bitwriter.write(
-                                reference.dist() - 1 - DIST_BASE_TABLE[distcode] as u32,
-                                distextra.into(),
-                                &mut self.output,
-                            );
-                        }
                     }
 
                     index += reference.len() as usize;
@@ -159,3 +166,89 @@ impl<'a> DeflateWriter<'a> {
         huffman_writer.write_literal(&mut self.bitwriter, &mut self.output, 256);
     }
 }
+
+/// A deflate64 stream with a single static huffman block, returns the compressed data and
+/// its plain text. It has matches that are further than 32K back and longer than 258 bytes,
+/// along with lengths of 258 and below that are written with the 16 extra bits of deflate64.
+#[cfg(test)]
+pub(crate) fn deflate64_test_stream() -> (Vec<u8>, Vec<u8>) {
+    use crate::preflate_token::IrregularEncoding;
+
+    // bytes that hardly ever match, so that only the matches below are found
+    let mut seed = 12345u32;
+    let mut plain_text: Vec<u8> = (0..50000)
+        .map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as u8
+        })
+        .collect();
+
+    let mut block = PreflateTokenBlock::new(BlockType::StaticHuff);
+    for &b in &plain_text {
+        block.add_literal(b);
+    }
+    for (len, dist, irregular) in [
+        (300, 50000, IrregularEncoding::Canonical),
+        (5000, 35000, IrregularEncoding::Canonical),
+        (258, 10000, IrregularEncoding::Deflate64Length),
+        (258, 40000, IrregularEncoding::Len258As284),
+        (100, 20000, IrregularEncoding::Deflate64Length),
+        (100, 30000, IrregularEncoding::Canonical),
+    ] {
+        let start = plain_text.len() - dist as usize;
+        for i in 0..len as usize {
+            plain_text.push(plain_text[start + i]);
+        }
+        block.add_reference(len, dist, irregular);
+    }
+    block.uncompressed_len = plain_text.len() as u32;
+
+    let mut writer = DeflateWriter::new(&plain_text);
+    writer.encode_block(&block, true).unwrap();
+    writer.flush_with_padding(0);
+    (writer.detach_output(), plain_text)
+}
+
+#[test]
+fn deflate64_roundtrip() {
+    use crate::{
+        decompress_deflate_stream_with_config,
+        deflate_reader::DeflateReader,
+        preflate_config::PreflateConfig,
+        preflate_token::{IrregularEncoding, PreflateToken},
+        recompress_deflate_stream,
+    };
+
+    let (compressed_data, plain_text) = deflate64_test_stream();
+
+    // the tokens read back are the ones that were written
+    let mut reader = DeflateReader::new(&compressed_data[..]);
+    reader.set_deflate64(true);
+    let mut last = false;
+    let block = reader.read_block(&mut last).unwrap();
+    assert!(last);
+    assert_eq!(reader.plain_text(), &plain_text[..]);
+    assert_eq!(block.tokens.len(), 50006);
+    assert_eq!(
+        block.tokens[50004],
+        PreflateToken::new_reference(100, 20000, IrregularEncoding::Deflate64Length)
+    );
+
+    // as regular deflate, the long length code is read as 258 followed by garbage
+    assert!(crate::decompress_deflate_stream(&compressed_data, true)
+        .map_or(true, |r| r.plain_text != plain_text));
+
+    let config = PreflateConfig {
+        deflate64: true,
+        ..PreflateConfig::default()
+    };
+    let r = decompress_deflate_stream_with_config(&compressed_data, &config).unwrap();
+    assert_eq!(r.plain_text, plain_text);
+    assert_eq!(r.parameters.window_bits, 16);
+    assert!(r.parameters.deflate64_short_lengths);
+    assert_eq!(r.compressed_processed, compressed_data.len());
+    assert_eq!(
+        recompress_deflate_stream(&r.plain_text, &r.cabac_encoded).unwrap(),
+        compressed_data
+    );
+}
@@ -0,0 +1,227 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Git packfiles start with "PACK", the version and the number of objects, followed by the
+//! objects and a SHA-1 of everything before it. Each object has a variable length header with
+//! its type and inflated size, deltas then have the offset or the name of their base, and the
+//! rest of the object is a zlib stream. The end of each stream is only known by decoding it.
+//!
+//! Only the deflate data of the objects is expanded, the headers and the adler32 of each
+//! object as well as the trailing SHA-1 are kept as they are, so the pack is recreated byte for
+//! byte with container::recreate_file.
+
+use std::{io::Cursor, ops::Range};
+
+use crate::{
+    container::{expand_file, ExpandedFile, StreamExtent},
+    deflate_reader::DeflateReader,
+    nested_streams::is_zlib_header,
+    preflate_config::PreflateConfig,
+    preflate_error::PreflateError,
+};
+
+const PACK_SIGNATURE: &[u8; 4] = b"PACK";
+const HEADER_SIZE: usize = 12;
+const SHA1_SIZE: usize = 20;
+
+pub const OBJ_COMMIT: u8 = 1;
+pub const OBJ_TREE: u8 = 2;
+pub const OBJ_BLOB: u8 = 3;
+pub const OBJ_TAG: u8 = 4;
+/// a delta against the object the given number of bytes before it in the pack
+pub const OBJ_OFS_DELTA: u8 = 6;
+/// a delta against the object with the given SHA-1
+pub const OBJ_REF_DELTA: u8 = 7;
+
+/// an object of a packfile
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PackObject {
+    /// offset of the header of the object in the pack
+    pub offset: usize,
+    pub object_type: u8,
+    /// size of the object or the delta once it is inflated
+    pub size: u64,
+    /// where the zlib stream of the object is in the pack, including the adler32
+    pub zlib_data: Range<usize>,
+}
+
+/// Returns the objects of a packfile. This fails if one of the zlib streams can't be decoded,
+/// since the next object can't be found without knowing where the stream ends.
+pub fn pack_objects(data: &[u8]) -> Result<Vec<PackObject>, PreflateError> {
+    if data.len() < HEADER_SIZE + SHA1_SIZE || !data.starts_with(PACK_SIGNATURE) {
+        return Err(invalid_data("no pack header"));
+    }
+    let version = u32::from_be_bytes(data[4..8].try_into().unwrap());
+    if version != 2 && version != 3 {
+        return Err(invalid_data("unsupported version"));
+    }
+    let count = u32::from_be_bytes(data[8..12].try_into().unwrap());
+
+    let end = data.len() - SHA1_SIZE;
+    let mut objects = Vec::new();
+    let mut pos = HEADER_SIZE;
+    for _ in 0..count {
+        let object = read_object(&data[..end], pos)?;
+        pos = object.zlib_data.end;
+        objects.push(object);
+    }
+
+    if pos != end {
+        return Err(invalid_data("unexpected data after the objects"));
+    }
+    Ok(objects)
+}
+
+fn read_object(data: &[u8], offset: usize) -> Result<PackObject, PreflateError> {
+    let truncated = || invalid_data("truncated object header");
+
+    // type and size, the size continues in 7 bit groups as long as the top bit is set
+    let mut pos = offset;
+    let mut b = *data.get(pos).ok_or_else(truncated)?;
+    pos += 1;
+    let object_type = (b >> 4) & 7;
+    let mut size = u64::from(b & 0x0f);
+    let mut shift = 4;
+    while b & 0x80 != 0 {
+        b = *data.get(pos).ok_or_else(truncated)?;
+        pos += 1;
+        if shift > 57 {
+            return Err(invalid_data("object size is too large"));
+        }
+        size |= u64::from(b & 0x7f) << shift;
+        shift += 7;
+    }
+
+    match object_type {
+        OBJ_COMMIT | OBJ_TREE | OBJ_BLOB | OBJ_TAG => {}
+        OBJ_OFS_DELTA => {
+            // the base offset, which only needs to be skipped
+            loop {
+                let b = *data.get(pos).ok_or_else(truncated)?;
+                pos += 1;
+                if b & 0x80 == 0 {
+                    break;
+                }
+            }
+        }
+        OBJ_REF_DELTA => pos += SHA1_SIZE,
+        _ => return Err(invalid_data("unknown object type")),
+    }
+
+    let zlib = data.get(pos..).ok_or_else(truncated)?;
+    if !is_zlib_header(zlib) {
+        return Err(invalid_data("object isn't zlib compressed"));
+    }
+
+    let mut reader = DeflateReader::new(Cursor::new(&zlib[2..]));
+    let mut last = false;
+    while !last {
+        reader
+            .read_block(&mut last)
+            .map_err(|_| invalid_data("object can't be inflated"))?;
+    }
+    if reader.plain_text().len() as u64 != size {
+        return Err(invalid_data("inflated object doesn't match its size"));
+    }
+
+    let zlib_end = pos + 2 + ((reader.bit_position() + 7) / 8) as usize + 4;
+    if zlib_end > data.len() {
+        return Err(invalid_data("truncated object"));
+    }
+
+    Ok(PackObject {
+        offset,
+        object_type,
+        size,
+        zlib_data: pos..zlib_end,
+    })
+}
+
+/// returns the extents of the deflate data of all the objects of the pack
+pub fn pack_deflate_extents(data: &[u8]) -> Result<Vec<StreamExtent>, PreflateError> {
+    Ok(pack_objects(data)?
+        .into_iter()
+        .map(|o| StreamExtent {
+            offset: o.zlib_data.start + 2,
+            length: o.zlib_data.len() - 6,
+        })
+        .collect())
+}
+
+/// Expands the zlib streams of all the objects of a git packfile. The pack can be recreated
+/// byte for byte with container::recreate_file, including the SHA-1 at the end.
+pub fn expand_git_pack(
+    data: &[u8],
+    config: &PreflateConfig,
+) -> Result<ExpandedFile, PreflateError> {
+    Ok(expand_file(data, pack_deflate_extents(data)?, config))
+}
+
+fn invalid_data(message: &str) -> PreflateError {
+    PreflateError::Io(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("invalid git pack: {}", message),
+    ))
+}
+
+#[test]
+fn git_pack_roundtrip() {
+    use std::io::Write;
+
+    fn write_object(pack: &mut Vec<u8>, object_type: u8, base: &[u8], content: &[u8]) {
+        let mut size = content.len();
+        let mut b = (object_type << 4) | (size & 0x0f) as u8;
+        size >>= 4;
+        while size > 0 {
+            pack.push(b | 0x80);
+            b = (size & 0x7f) as u8;
+            size >>= 7;
+        }
+        pack.push(b);
+        pack.extend_from_slice(base);
+
+        let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::new(6));
+        encoder.write_all(content).unwrap();
+        pack.extend_from_slice(&encoder.finish().unwrap());
+    }
+
+    let blob = crate::process::read_file("sample1.bin");
+    let mut pack = PACK_SIGNATURE.to_vec();
+    pack.extend_from_slice(&2u32.to_be_bytes());
+    pack.extend_from_slice(&3u32.to_be_bytes());
+    write_object(&mut pack, OBJ_BLOB, &[], &blob);
+    let delta_offset = pack.len();
+    write_object(
+        &mut pack,
+        OBJ_OFS_DELTA,
+        &[0x81, 0x05],
+        b"\x10\x12\x90\x10copy copy copy",
+    );
+    write_object(&mut pack, OBJ_REF_DELTA, &[0xab; SHA1_SIZE], b"tree 1234");
+    // the checksum is kept as it is, so it doesn't need to be a real SHA-1 here
+    pack.extend_from_slice(&[0x5a; SHA1_SIZE]);
+
+    let objects = pack_objects(&pack).unwrap();
+    assert_eq!(objects.len(), 3);
+    assert_eq!(objects[0].size, blob.len() as u64);
+    assert_eq!(objects[1].offset, delta_offset);
+    assert_eq!(objects[1].object_type, OBJ_OFS_DELTA);
+    assert_eq!(objects[2].object_type, OBJ_REF_DELTA);
+
+    let config = PreflateConfig::default();
+    let expanded = expand_git_pack(&pack, &config).unwrap();
+    assert_eq!(expanded.summary.entries_processed, 3);
+    assert!(expanded.plain_text.ends_with(&[0x5a; SHA1_SIZE]));
+    assert_eq!(
+        crate::container::recreate_file(&expanded.plain_text, &expanded.corrections, &config)
+            .unwrap(),
+        pack
+    );
+
+    // an object count that doesn't match the objects
+    pack[11] = 4;
+    assert!(pack_objects(&pack).is_err());
+}
@@ -0,0 +1,158 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! The header of a gzip member (RFC 1952). Many .gz files carry the original file name, a comment
+//! or tool specific extra fields, so all the fields are kept exactly as they were read (including
+//! a header crc that doesn't match) and written back byte for byte, rather than normalized.
+
+const FTEXT: u8 = 0x01;
+const FHCRC: u8 = 0x02;
+const FEXTRA: u8 = 0x04;
+const FNAME: u8 = 0x08;
+const FCOMMENT: u8 = 0x10;
+
+/// the fields of a gzip member header, in the order in which they are stored
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct GzipHeader {
+    /// the FTEXT flag, which is only a hint for the decompressor
+    pub text: bool,
+    pub mtime: u32,
+    /// extra flags, usually 2 for the best compression and 4 for the fastest
+    pub xfl: u8,
+    /// the operating system that the file was compressed on
+    pub os: u8,
+    /// the contents of the FEXTRA field without its length
+    pub extra: Option<Vec<u8>>,
+    /// the original file name without the NUL terminator
+    pub name: Option<Vec<u8>>,
+    /// the comment without the NUL terminator
+    pub comment: Option<Vec<u8>>,
+    /// the stored crc16 of the header, which is kept even if it doesn't match
+    pub header_crc: Option<u16>,
+}
+
+impl GzipHeader {
+    /// Parses the header at the start of the data and returns it along with its length.
+    /// Returns None if there is no complete header with the deflate method and without
+    /// reserved flags.
+    pub fn parse(data: &[u8]) -> Option<(GzipHeader, usize)> {
+        if data.len() < 10 || data[0..3] != [0x1f, 0x8b, 8] || data[3] & 0xe0 != 0 {
+            return None;
+        }
+
+        let flags = data[3];
+        let mut header = GzipHeader {
+            text: flags & FTEXT != 0,
+            mtime: u32::from_le_bytes(data[4..8].try_into().unwrap()),
+            xfl: data[8],
+            os: data[9],
+            ..GzipHeader::default()
+        };
+        let mut offset = 10;
+
+        if flags & FEXTRA != 0 {
+            let extra_len = usize::from(u16::from_le_bytes(
+                data.get(offset..offset + 2)?.try_into().unwrap(),
+            ));
+            header.extra = Some(data.get(offset + 2..offset + 2 + extra_len)?.to_vec());
+            offset += 2 + extra_len;
+        }
+
+        for (flag, field) in [(FNAME, &mut header.name), (FCOMMENT, &mut header.comment)] {
+            if flags & flag != 0 {
+                let len = data.get(offset..)?.iter().position(|&b| b == 0)?;
+                *field = Some(data[offset..offset + len].to_vec());
+                offset += len + 1;
+            }
+        }
+
+        if flags & FHCRC != 0 {
+            header.header_crc = Some(u16::from_le_bytes(
+                data.get(offset..offset + 2)?.try_into().unwrap(),
+            ));
+            offset += 2;
+        }
+
+        Some((header, offset))
+    }
+
+    /// writes the header exactly as it was parsed
+    pub fn write(&self, output: &mut Vec<u8>) {
+        let flags = [
+            (FTEXT, self.text),
+            (FHCRC, self.header_crc.is_some()),
+            (FEXTRA, self.extra.is_some()),
+            (FNAME, self.name.is_some()),
+            (FCOMMENT, self.comment.is_some()),
+        ]
+        .into_iter()
+        .filter(|&(_, set)| set)
+        .fold(0, |flags, (flag, _)| flags | flag);
+
+        output.extend_from_slice(&[0x1f, 0x8b, 8, flags]);
+        output.extend_from_slice(&self.mtime.to_le_bytes());
+        output.extend_from_slice(&[self.xfl, self.os]);
+
+        if let Some(extra) = &self.extra {
+            output.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+            output.extend_from_slice(extra);
+        }
+
+        for field in [&self.name, &self.comment].into_iter().flatten() {
+            output.extend_from_slice(field);
+            output.push(0);
+        }
+
+        if let Some(header_crc) = self.header_crc {
+            output.extend_from_slice(&header_crc.to_le_bytes());
+        }
+    }
+
+    /// the crc16 that the FHCRC field should contain for this header, which is the
+    /// lower half of the crc32 of all the bytes of the header before it
+    pub fn calculate_header_crc(&self) -> u16 {
+        let mut bytes = Vec::new();
+        self.write(&mut bytes);
+        if self.header_crc.is_some() {
+            bytes.truncate(bytes.len() - 2);
+        }
+        crc32fast::hash(&bytes) as u16
+    }
+}
+
+#[test]
+fn roundtrip_gzip_header() {
+    let header = GzipHeader {
+        text: true,
+        mtime: 0x12345678,
+        xfl: 2,
+        os: 11,
+        extra: Some(b"AP\x02\x00hi".to_vec()),
+        name: Some(b"file.txt".to_vec()),
+        comment: Some(b"".to_vec()),
+        // deliberately wrong, it has to be kept as it is
+        header_crc: Some(0xbeef),
+    };
+
+    let mut bytes = Vec::new();
+    header.write(&mut bytes);
+    bytes.extend_from_slice(b"deflate data");
+
+    let (parsed, len) = GzipHeader::parse(&bytes).unwrap();
+    assert_eq!(parsed, header);
+    assert_eq!(&bytes[len..], b"deflate data");
+    assert_ne!(parsed.calculate_header_crc(), 0xbeef);
+
+    let mut rewritten = Vec::new();
+    parsed.write(&mut rewritten);
+    assert_eq!(rewritten, bytes[..len]);
+
+    // truncated inside of the name
+    assert_eq!(GzipHeader::parse(&bytes[..20]), None);
+    // reserved flag set
+    bytes[3] |= 0x80;
+    assert_eq!(GzipHeader::parse(&bytes), None);
+}
@@ -0,0 +1,273 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Deflate streams in a gzip file (RFC 1952), including files with several members such as the
+//! concatenation of .gz files or the output of bgzip. The header bytes of each member are
+//! stored in the corrections as they were read, so all the fields (name, comment, extra field,
+//! header crc, mtime and os) come back byte for byte. The trailer is recreated from the plain
+//! text if it matches.
+//!
+//! All numbers in the corrections are little endian u32. They start with the number of members,
+//! followed by a record for each member: the length of its plain text, the length of the header
+//! and the header itself, how the trailer is recreated (0: the crc32 and length of the plain
+//! text, 1: the 8 bytes that follow since they don't match, 2: the member has no trailer) and
+//! the length and corrections of the deflate stream.
+
+use crate::{
+    decompress_deflate_stream_with_config,
+    gzip_header::GzipHeader,
+    nested_streams::{gzip_trailer, GZIP_TRAILER_SIZE},
+    preflate_config::PreflateConfig,
+    preflate_error::PreflateError,
+    recompress_deflate_stream_with_config, DecompressResult,
+};
+
+const TRAILER_MATCHES: u8 = 0;
+const TRAILER_STORED: u8 = 1;
+const TRAILER_MISSING: u8 = 2;
+
+/// a member that was decompressed along with its record in the corrections
+struct Member {
+    result: DecompressResult,
+    record: Vec<u8>,
+    /// length of the member including the header and trailer
+    len: usize,
+    has_trailer: bool,
+}
+
+fn decompress_member(data: &[u8], config: &PreflateConfig) -> Result<Member, PreflateError> {
+    let (_, header_len) = GzipHeader::parse(data).ok_or_else(|| {
+        PreflateError::ReadDeflate(anyhow::anyhow!("data doesn't start with a gzip header"))
+    })?;
+
+    let result = decompress_deflate_stream_with_config(&data[header_len..], config)?;
+
+    let mut record = (result.plain_text.len() as u32).to_le_bytes().to_vec();
+    record.extend_from_slice(&(header_len as u32).to_le_bytes());
+    record.extend_from_slice(&data[..header_len]);
+
+    let end = header_len + result.compressed_processed;
+    let (len, has_trailer) = match data.get(end..end + GZIP_TRAILER_SIZE) {
+        Some(trailer) if trailer == gzip_trailer(&result.plain_text) => {
+            record.push(TRAILER_MATCHES);
+            (end + GZIP_TRAILER_SIZE, true)
+        }
+        Some(trailer) => {
+            record.push(TRAILER_STORED);
+            record.extend_from_slice(trailer);
+            (end + GZIP_TRAILER_SIZE, true)
+        }
+        None => {
+            record.push(TRAILER_MISSING);
+            (end, false)
+        }
+    };
+    record.extend_from_slice(&(result.cabac_encoded.len() as u32).to_le_bytes());
+    record.extend_from_slice(&result.cabac_encoded);
+
+    Ok(Member {
+        result,
+        record,
+        len,
+        has_trailer,
+    })
+}
+
+/// Decompresses a gzip file with one or more members. The plain text is the plain text of all
+/// the members one after the other, the statistics are added up over the members and the
+/// profile is the one of the first member. Members are read as long as the previous one had its
+/// trailer and the data that follows is a valid member, anything after that isn't part of
+/// compressed_processed.
+pub fn decompress_gzip_stream(
+    compressed_data: &[u8],
+    config: &PreflateConfig,
+) -> Result<DecompressResult, PreflateError> {
+    let first = decompress_member(compressed_data, config)?;
+
+    let mut result = first.result;
+    let mut records = vec![first.record];
+    let mut pos = first.len;
+    let mut has_trailer = first.has_trailer;
+
+    while has_trailer && pos < compressed_data.len() {
+        let Ok(member) = decompress_member(&compressed_data[pos..], config) else {
+            break;
+        };
+
+        result
+            .plain_text
+            .extend_from_slice(&member.result.plain_text);
+        result.statistics.add(&member.result.statistics);
+        records.push(member.record);
+        pos += member.len;
+        has_trailer = member.has_trailer;
+    }
+
+    let mut cabac_encoded = (records.len() as u32).to_le_bytes().to_vec();
+    for record in records {
+        cabac_encoded.extend_from_slice(&record);
+    }
+
+    Ok(DecompressResult {
+        plain_text_crc32: crc32fast::hash(&result.plain_text),
+        cabac_encoded,
+        compressed_processed: pos,
+        ..result
+    })
+}
+
+fn invalid_members() -> PreflateError {
+    PreflateError::RecompressFailed(anyhow::anyhow!("invalid gzip members in the corrections"))
+}
+
+/// takes the next len bytes from the corrections
+fn take<'a>(corrections: &mut &'a [u8], len: usize) -> Result<&'a [u8], PreflateError> {
+    let value = corrections.get(..len).ok_or_else(invalid_members)?;
+    *corrections = &corrections[len..];
+    Ok(value)
+}
+
+fn take_u32(corrections: &mut &[u8]) -> Result<usize, PreflateError> {
+    Ok(u32::from_le_bytes(take(corrections, 4)?.try_into().unwrap()) as usize)
+}
+
+/// recreates a gzip file that was decompressed with decompress_gzip_stream
+pub fn recompress_gzip_stream(
+    plain_text: &[u8],
+    corrections: &[u8],
+    config: &PreflateConfig,
+) -> Result<Vec<u8>, PreflateError> {
+    let mut corrections = corrections;
+    let member_count = take_u32(&mut corrections)?;
+
+    let mut output = Vec::new();
+    let mut plain_text_pos = 0;
+    for _ in 0..member_count {
+        let plain_text_len = take_u32(&mut corrections)?;
+        let member_plain_text = plain_text
+            .get(plain_text_pos..plain_text_pos + plain_text_len)
+            .ok_or_else(invalid_members)?;
+        plain_text_pos += plain_text_len;
+
+        let header_len = take_u32(&mut corrections)?;
+        let header = take(&mut corrections, header_len)?;
+        if GzipHeader::parse(header).map(|(_, len)| len) != Some(header_len) {
+            return Err(invalid_members());
+        }
+
+        let trailer = match take(&mut corrections, 1)?[0] {
+            TRAILER_MATCHES => Some(gzip_trailer(member_plain_text)),
+            TRAILER_STORED => Some(
+                take(&mut corrections, GZIP_TRAILER_SIZE)?
+                    .try_into()
+                    .unwrap(),
+            ),
+            TRAILER_MISSING => None,
+            _ => return Err(invalid_members()),
+        };
+
+        let deflate_len = take_u32(&mut corrections)?;
+        let deflate_corrections = take(&mut corrections, deflate_len)?;
+
+        output.extend_from_slice(header);
+        output.extend_from_slice(&recompress_deflate_stream_with_config(
+            member_plain_text,
+            deflate_corrections,
+            config,
+        )?);
+        if let Some(trailer) = trailer {
+            output.extend_from_slice(&trailer);
+        }
+    }
+
+    if plain_text_pos != plain_text.len() {
+        return Err(invalid_members());
+    }
+
+    Ok(output)
+}
+
+#[test]
+fn gzip_stream_roundtrip() {
+    use std::io::Write;
+
+    let plain_text = crate::process::read_file("sample1.bin");
+    let config = PreflateConfig::default();
+
+    let mut encoder = flate2::GzBuilder::new()
+        .filename("sample1.bin")
+        .comment("a comment")
+        .extra(b"AP\x02\x00hi".to_vec())
+        .mtime(0x12345678)
+        .operating_system(11)
+        .write(Vec::new(), flate2::Compression::new(9));
+    encoder.write_all(&plain_text).unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    let r = decompress_gzip_stream(&compressed, &config).unwrap();
+    assert_eq!(r.plain_text, plain_text);
+    assert_eq!(r.compressed_processed, compressed.len());
+    assert_eq!(
+        recompress_gzip_stream(&r.plain_text, &r.cabac_encoded, &config).unwrap(),
+        compressed
+    );
+
+    // a trailer that doesn't match and a missing trailer are kept as they were
+    let mut damaged = compressed.clone();
+    *damaged.last_mut().unwrap() ^= 1;
+    let truncated = &compressed[..compressed.len() - GZIP_TRAILER_SIZE];
+    for data in [&damaged[..], truncated] {
+        let r = decompress_gzip_stream(data, &config).unwrap();
+        assert_eq!(r.compressed_processed, data.len());
+        assert_eq!(
+            recompress_gzip_stream(&r.plain_text, &r.cabac_encoded, &config).unwrap(),
+            data
+        );
+    }
+
+    assert!(decompress_gzip_stream(&compressed[1..], &config).is_err());
+}
+
+#[test]
+fn gzip_members_roundtrip() {
+    use std::io::Write;
+
+    let config = PreflateConfig::default();
+    let gzip = |data: &[u8], level| {
+        let mut encoder =
+            flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::new(level));
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    };
+
+    // like cat a.gz b.gz, followed by the empty member that bgzip ends its files with
+    let first = crate::process::read_file("sample1.bin");
+    let second = b"the second member of the file, the second member".to_vec();
+    let mut file = gzip(&first, 6);
+    file.extend_from_slice(&gzip(&second, 1));
+    file.extend_from_slice(&gzip(b"", 6));
+
+    let r = decompress_gzip_stream(&file, &config).unwrap();
+    assert_eq!(r.plain_text, [&first[..], &second[..]].concat());
+    assert_eq!(r.compressed_processed, file.len());
+    assert_eq!(
+        recompress_gzip_stream(&r.plain_text, &r.cabac_encoded, &config).unwrap(),
+        file
+    );
+
+    // data after the last member isn't part of the file
+    let members_len = file.len();
+    file.extend_from_slice(b"not a member");
+    let r = decompress_gzip_stream(&file, &config).unwrap();
+    assert_eq!(r.compressed_processed, members_len);
+    assert_eq!(
+        recompress_gzip_stream(&r.plain_text, &r.cabac_encoded, &config).unwrap(),
+        file[..members_len]
+    );
+
+    // the plain text has to match the lengths of the members
+    assert!(recompress_gzip_stream(&r.plain_text[1..], &r.cabac_encoded, &config).is_err());
+}
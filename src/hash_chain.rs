@@ -7,9 +7,15 @@
 use default_boxed::DefaultBoxed;
 
 use crate::{
-    bit_helper::DebugHash, preflate_input::PreflateInput, preflate_token::PreflateTokenReference,
+    bit_helper::DebugHash,
+    preflate_constants::MIN_MATCH,
+    preflate_error::{TokenError, TokenErrorKind},
+    preflate_input::PreflateInput,
+    preflate_token::{FlushMarker, PreflateTokenReference},
 };
 
+pub use crate::rotating_hash::*;
+
 pub struct HashIterator<'a> {
     chain: &'a [u16],
     ref_pos: u32,
@@ -78,63 +84,38 @@ struct HashTable {
     prev: [u16; 65536],
 }
 
-pub struct HashChain<H: RotatingHashTrait> {
-    hash_table: Box<HashTable>,
-    hash_shift: u32,
-    running_hash: H,
-    hash_mask: u16,
+/// contents of a hash chain at some point of the input, which can be used to continue
+/// predicting from there without replaying the input before it
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HashChainSnapshot {
+    head: Vec<u16>,
+    prev: Vec<u16>,
+    running_hash: u32,
     total_shift: i32,
 }
 
-pub const HASH_ALGORITHM_ZLIB: u16 = 0;
-pub const HASH_ALGORITHM_MINIZ_FAST: u16 = 1;
-
-#[derive(Default, Debug, Copy, Clone)]
-pub struct ZlibRotatingHash {
-    hash: u16,
-}
-
-pub trait RotatingHashTrait: Default + Copy + Clone {
-    fn hash(&self, mask: u16) -> u16;
-    fn append(&self, c: u8, hash_shift: u32) -> Self;
-    fn hash_algorithm() -> u16;
-}
-
-impl RotatingHashTrait for ZlibRotatingHash {
-    fn hash(&self, mask: u16) -> u16 {
-        self.hash & mask
-    }
-
-    fn append(&self, c: u8, hash_shift: u32) -> ZlibRotatingHash {
-        ZlibRotatingHash {
-            hash: (self.hash << hash_shift) ^ u16::from(c),
-        }
+impl HashChainSnapshot {
+    /// number of bytes at the start of the input that none of the positions in the chains
+    /// refer to
+    pub fn unreferenced_len(&self) -> u32 {
+        (self.total_shift + 1).max(0) as u32
     }
 
-    fn hash_algorithm() -> u16 {
-        HASH_ALGORITHM_ZLIB
+    /// moves the positions in the chains back for an input whose first len bytes (at most
+    /// unreferenced_len) were dropped
+    pub fn drop_leading(&mut self, len: u32) {
+        debug_assert!(len <= self.unreferenced_len());
+        self.total_shift -= len as i32;
     }
 }
 
-#[derive(Default, Copy, Clone)]
-pub struct MiniZHash {
-    hash: u32,
-}
-
-impl RotatingHashTrait for MiniZHash {
-    fn hash(&self, _mask: u16) -> u16 {
-        ((self.hash ^ (self.hash >> 11)) & 0x7fff) as u16
-    }
-
-    fn append(&self, c: u8, _hash_shift: u32) -> Self {
-        MiniZHash {
-            hash: (c as u32) << 16 | (self.hash >> 8),
-        }
-    }
-
-    fn hash_algorithm() -> u16 {
-        HASH_ALGORITHM_MINIZ_FAST
-    }
+pub struct HashChain<H: RotatingHashTrait> {
+    hash_table: Box<HashTable>,
+    hash_shift: u32,
+    running_hash: H,
+    hash_mask: u16,
+    total_shift: i32,
 }
 
 impl<H: RotatingHashTrait> HashChain<H> {
@@ -151,6 +132,35 @@ impl<H: RotatingHashTrait> HashChain<H> {
         }
     }
 
+    /// Copies the chains into a snapshot. The chain depth is left out, since it is
+    /// only maintained while estimating the parameters.
+    pub fn snapshot(&self) -> HashChainSnapshot {
+        HashChainSnapshot {
+            head: self.hash_table.head[..=usize::from(self.hash_mask)].to_vec(),
+            prev: self.hash_table.prev.to_vec(),
+            running_hash: self.running_hash.state(),
+            total_shift: self.total_shift,
+        }
+    }
+
+    /// recreates the hash chain from a snapshot that was taken with the same parameters
+    pub fn from_snapshot(
+        hash_shift: u32,
+        hash_mask: u16,
+        snapshot: &HashChainSnapshot,
+    ) -> Result<Self, TokenError> {
+        if snapshot.head.len() != usize::from(hash_mask) + 1 || snapshot.prev.len() != 65536 {
+            return Err(TokenErrorKind::InvalidSnapshot.into());
+        }
+
+        let mut r = Self::new(hash_shift, hash_mask);
+        r.hash_table.head[..snapshot.head.len()].copy_from_slice(&snapshot.head);
+        r.hash_table.prev.copy_from_slice(&snapshot.prev);
+        r.running_hash = H::from_state(snapshot.running_hash);
+        r.total_shift = snapshot.total_shift;
+        Ok(r)
+    }
+
     #[allow(dead_code)]
     pub fn checksum(&self, checksum: &mut DebugHash) {
         checksum.update_slice(&self.hash_table.chain_depth);
@@ -161,6 +171,9 @@ impl<H: RotatingHashTrait> HashChain<H> {
         checksum.update(self.total_shift);
     }
 
+    /// offset of the last byte covered by the hash of the current position
+    const LOOKAHEAD: u32 = H::NUM_HASH_BYTES - 1;
+
     fn next_hash(&self, b: u8) -> H {
         self.running_hash.append(b, self.hash_shift)
     }
@@ -175,8 +188,80 @@ impl<H: RotatingHashTrait> HashChain<H> {
         self.running_hash = self.running_hash.append(b, self.hash_shift);
     }
 
+    /// Updates the chains at a flush marker the way zlib does. A full flush clears the hash table,
+    /// while after a sync flush the last positions that were left out are inserted.
+    pub fn apply_flush_marker<const MAINTAIN_DEPTH: bool>(
+        &mut self,
+        flush: FlushMarker,
+        input: &PreflateInput,
+    ) {
+        match flush {
+            FlushMarker::None => {}
+            FlushMarker::Sync => self.insert_flush_tail::<MAINTAIN_DEPTH>(input),
+            FlushMarker::Full => self.reset(),
+        }
+    }
+
+    /// Empties the hash chains. The running hash and the chain depths are kept, since the chains
+    /// are only entered through the heads.
+    fn reset(&mut self) {
+        self.hash_table.head[..=usize::from(self.hash_mask)].fill(0);
+    }
+
+    /// Inserts the last MIN_MATCH - 1 positions before the current one that are missing from the
+    /// chains. At a sync flush, the lookahead of zlib was too short to insert these when they were
+    /// reached, so it inserts them once more input arrives, even if they were skipped because
+    /// they were inside a long match.
+    fn insert_flush_tail<const MAINTAIN_DEPTH: bool>(&mut self, input: &PreflateInput) {
+        let tail = std::cmp::min(MIN_MATCH - 1, input.pos());
+        for offset in (1..=tail).rev() {
+            if input.remaining() + offset < H::NUM_HASH_BYTES {
+                break;
+            }
+
+            let h = (0..H::NUM_HASH_BYTES)
+                .fold(H::default(), |h, i| {
+                    h.append(input.cur_char(i as i32 - offset as i32), self.hash_shift)
+                })
+                .hash(self.hash_mask);
+            let p = (input.pos() - offset) as i32 - self.total_shift;
+            if p <= 0 {
+                continue;
+            }
+            let p = p as u16;
+
+            // only the positions after it can be in front of it in the chain, which is where
+            // it is linked in so that the chain stays in order
+            let mut after = None;
+            let mut node = self.hash_table.head[usize::from(h)];
+            while node > p {
+                after = Some(node);
+                node = self.hash_table.prev[usize::from(node)];
+            }
+            if node == p {
+                continue;
+            }
+
+            if MAINTAIN_DEPTH {
+                self.hash_table.chain_depth[usize::from(p)] =
+                    self.hash_table.chain_depth[usize::from(node)] + 1;
+            }
+
+            self.hash_table.prev[usize::from(p)] = node;
+            match after {
+                Some(after) => self.hash_table.prev[usize::from(after)] = p,
+                None => self.hash_table.head[usize::from(h)] = p,
+            }
+        }
+    }
+
+    /// true if an update at the given position will first shift the hash table down
+    pub fn needs_reshift(&self, pos: u32) -> bool {
+        pos as i32 - self.total_shift >= 0xfe00
+    }
+
     fn reshift_if_necessary<const MAINTAIN_DEPTH: bool>(&mut self, input: &PreflateInput) {
-        if input.pos() as i32 - self.total_shift >= 0xfe00 {
+        if self.needs_reshift(input.pos()) {
             const DELTA: usize = 0x7e00;
             for i in 0..=self.hash_mask as usize {
                 self.hash_table.head[i] = self.hash_table.head[i].saturating_sub(DELTA as u16);
@@ -204,7 +289,7 @@ impl<H: RotatingHashTrait> HashChain<H> {
         let mut chains: Vec<Vec<u16>> = Vec::new();
         chains.resize(self.hash_mask as usize + 1, Vec::new());
 
-        let mut start_delay = 2;
+        let mut start_delay = Self::LOOKAHEAD;
 
         while start_pos - 1 <= input.pos() as i32 {
             hash = hash.append(
@@ -216,7 +301,7 @@ impl<H: RotatingHashTrait> HashChain<H> {
                 start_delay -= 1;
             } else {
                 chains[hash.hash(self.hash_mask) as usize]
-                    .push((start_pos - 2 - self.total_shift as i32) as u16);
+                    .push((start_pos - Self::LOOKAHEAD as i32 - self.total_shift as i32) as u16);
             }
 
             start_pos += 1;
@@ -279,11 +364,24 @@ impl<H: RotatingHashTrait> HashChain<H> {
     }
 
     pub fn cur_hash(&self, input: &PreflateInput) -> H {
-        self.next_hash(input.cur_char(2))
+        self.next_hash(input.cur_char(Self::LOOKAHEAD as i32))
     }
 
     pub fn cur_plus_1_hash(&self, input: &PreflateInput) -> H {
-        self.next_hash_double(input.cur_char(2), input.cur_char(3))
+        self.next_hash_double(
+            input.cur_char(Self::LOOKAHEAD as i32),
+            input.cur_char(Self::LOOKAHEAD as i32 + 1),
+        )
+    }
+
+    /// the hash of the position offset bytes after the current one
+    pub fn cur_plus_n_hash(&self, input: &PreflateInput, offset: u32) -> H {
+        (0..=offset).fold(self.running_hash, |h, i| {
+            h.append(
+                input.cur_char((Self::LOOKAHEAD + i) as i32),
+                self.hash_shift,
+            )
+        })
     }
 
     pub fn hash_equal(&self, a: H, b: H) -> bool {
@@ -296,9 +394,13 @@ impl<H: RotatingHashTrait> HashChain<H> {
         input: &PreflateInput,
     ) {
         if length > 0x180 {
+            // the chunks need to be inserted at their own positions, so that the table is
+            // reshifted before it runs out of positions
+            let mut input = *input;
             while length > 0 {
                 let blk = std::cmp::min(length, 0x180);
-                self.update_hash::<MAINTAIN_DEPTH>(blk, input);
+                self.update_hash::<MAINTAIN_DEPTH>(blk, &input);
+                input.advance(blk);
                 length -= blk;
             }
             return;
@@ -308,12 +410,12 @@ impl<H: RotatingHashTrait> HashChain<H> {
 
         let pos = (input.pos() as i32 - self.total_shift) as u16;
 
-        let limit = std::cmp::min(length + 2, input.remaining()) as u16;
+        let limit = std::cmp::min(length + Self::LOOKAHEAD, input.remaining()) as u16;
 
-        for i in 2..limit {
+        for i in Self::LOOKAHEAD as u16..limit {
             self.update_running_hash(input.cur_char(i as i32));
             let h = self.running_hash.hash(self.hash_mask);
-            let p = pos + i - 2;
+            let p = pos + i - Self::LOOKAHEAD as u16;
 
             if MAINTAIN_DEPTH {
                 self.hash_table.chain_depth[usize::from(p)] = self.hash_table.chain_depth
@@ -335,8 +437,8 @@ impl<H: RotatingHashTrait> HashChain<H> {
         let pos = input.pos() as i32;
 
         let remaining = input.remaining();
-        if remaining > 2 {
-            self.update_running_hash(input.cur_char(2));
+        if remaining > Self::LOOKAHEAD {
+            self.update_running_hash(input.cur_char(Self::LOOKAHEAD as i32));
             let h = self.running_hash.hash(self.hash_mask);
             let p = pos - self.total_shift;
 
@@ -357,11 +459,13 @@ impl<H: RotatingHashTrait> HashChain<H> {
             self.hash_table.prev[p as usize] = self.hash_table.head[h as usize];
             self.hash_table.head[h as usize] = p as u16;
 
-            if remaining > l {
-                self.update_running_hash(input.cur_char(l as i32));
-                if remaining > l + 1 {
-                    self.update_running_hash(input.cur_char(l as i32 + 1));
+            // bring the running hash up to date with the bytes before
+            // the lookahead of the position after the skipped data
+            for i in l..l + Self::LOOKAHEAD {
+                if remaining <= i {
+                    break;
                 }
+                self.update_running_hash(input.cur_char(i as i32));
             }
         }
 
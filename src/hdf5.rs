@@ -0,0 +1,55 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! HDF5 stores each chunk of a dataset that uses the deflate filter as a separate zlib stream.
+//! The chunk index of a dataset (usually a B-tree) lists the address and size of each chunk,
+//! which is all that is needed to expand the chunks in place.
+
+use crate::{
+    container::{expand_file, ExpandedFile, StreamExtent},
+    nested_streams::is_zlib_header,
+    preflate_config::PreflateConfig,
+};
+
+/// a chunk of a dataset as listed in the chunk index of the dataset
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Hdf5Chunk {
+    /// file offset of the chunk (the address from the chunk index plus the base address of the file)
+    pub address: u64,
+    /// size of the chunk after filtering
+    pub size: u64,
+}
+
+/// size of the zlib header and adler32 trailer around the deflate data of each chunk
+const ZLIB_OVERHEAD: u64 = 2 + 4;
+
+/// Returns the extents of the deflate data for each of the chunks that are zlib streams.
+/// Chunks that are outside of the file or that were stored without the deflate filter
+/// (the filter mask of a chunk can disable it) are left out.
+pub fn hdf5_chunk_extents(file: &[u8], chunks: &[Hdf5Chunk]) -> Vec<StreamExtent> {
+    chunks
+        .iter()
+        .filter(|c| {
+            c.size > ZLIB_OVERHEAD
+                && c.address.saturating_add(c.size) <= file.len() as u64
+                && is_zlib_header(&file[c.address as usize..(c.address + c.size) as usize])
+        })
+        .map(|c| StreamExtent {
+            offset: c.address as usize + 2,
+            length: (c.size - ZLIB_OVERHEAD) as usize,
+        })
+        .collect()
+}
+
+/// Expands all the deflate compressed chunks of an HDF5 file. The file can be recreated
+/// byte for byte with container::recreate_file.
+pub fn expand_hdf5_chunks(
+    file: &[u8],
+    chunks: &[Hdf5Chunk],
+    config: &PreflateConfig,
+) -> ExpandedFile {
+    expand_file(file, hdf5_chunk_extents(file, chunks), config)
+}
@@ -4,13 +4,35 @@
  *  This software incorporates material from third parties. See NOTICE.txt for details.
  *--------------------------------------------------------------------------------------------*/
 
-#[allow(dead_code)]
+//! The calculation of the bit lengths of the huffman trees from the symbol frequencies, in the
+//! same way as the compressors do it. Compressors that use the same algorithm build exactly the
+//! same trees, so this can be used to find out which algorithm produced the trees of a stream,
+//! or to check a new implementation against the trees of real streams.
+
+use crate::preflate_constants::DEFLATE64_DIST_CODE_COUNT;
+pub use crate::preflate_token::TokenFrequency;
+
+/// the algorithm that a compressor uses to calculate the bit lengths of its huffman trees
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum HufftreeBitCalc {
+    /// the heap based construction of zlib's trees.c
     Zlib,
+    /// the in-place minimum redundancy calculation over the sorted frequencies used by miniz
     Miniz,
+    /// the two queue construction of Huffman_Generate in 7-Zip, which shortens the longest codes
+    /// by moving leaves from the deepest level that still fits within the limit
+    SevenZip,
+    /// the optimal length limited codes of the boundary package-merge in Zopfli
+    Zopfli,
+    /// the same construction as 7-Zip, except that libdeflate limits the literal/length codes
+    /// to 14 bits
+    Libdeflate,
 }
 
+/// Calculates the bit length of each symbol from how often it is used, limiting the codes to
+/// code_size_limit bits (15 for the literal/length and distance trees, 7 for the tree that
+/// encodes them). Unused symbols get a length of 0, and the trailing ones are left out.
 pub fn calc_bit_lengths(
     bit_calc: HufftreeBitCalc,
     sym_count: &[u16],
@@ -19,6 +41,17 @@ pub fn calc_bit_lengths(
     match bit_calc {
         HufftreeBitCalc::Zlib => calc_zlib::calc_bit_lengths(sym_count, code_size_limit),
         HufftreeBitCalc::Miniz => calc_minzoxide::calc_bit_lengths(sym_count, code_size_limit),
+        HufftreeBitCalc::SevenZip => calc_7zip::calc_bit_lengths(sym_count, code_size_limit),
+        HufftreeBitCalc::Zopfli => calc_zopfli::calc_bit_lengths(sym_count, code_size_limit),
+        HufftreeBitCalc::Libdeflate => {
+            // the literal/length tree is the only one with more symbols than there are distances
+            let code_size_limit = if sym_count.len() > DEFLATE64_DIST_CODE_COUNT {
+                code_size_limit.min(14)
+            } else {
+                code_size_limit
+            };
+            calc_7zip::calc_bit_lengths(sym_count, code_size_limit)
+        }
     }
 }
 
@@ -512,3 +545,238 @@ mod calc_zlib {
         );
     }
 }
+
+mod calc_7zip {
+    /// the symbol is kept in the low bits of each entry, the frequency or the index of the
+    /// parent in the high bits
+    const NUM_BITS: u32 = 10;
+    const MASK: u64 = (1 << NUM_BITS) - 1;
+
+    pub fn calc_bit_lengths(sym_freq: &[u16], max_bits: usize) -> Vec<u8> {
+        // sorted by frequency, and by symbol for the same frequency
+        let mut p: Vec<u64> = sym_freq
+            .iter()
+            .enumerate()
+            .filter(|(_, &freq)| freq != 0)
+            .map(|(i, &freq)| (u64::from(freq) << NUM_BITS) | i as u64)
+            .collect();
+        p.sort_unstable();
+
+        let num = p.len();
+        let max_used = p
+            .iter()
+            .map(|&x| (x & MASK) as usize + 1)
+            .max()
+            .unwrap_or(0);
+
+        if num < 2 {
+            // give a second symbol a length of 1 so that the tree is complete
+            let max_code = match p.first() {
+                Some(&x) if x & MASK != 0 => (x & MASK) as usize,
+                _ => 1,
+            };
+            let mut lens = vec![0u8; max_code + 1];
+            lens[0] = 1;
+            lens[max_code] = 1;
+            return lens;
+        }
+
+        // merge the two least frequent nodes, taking the leaves before the internal nodes
+        // with the same frequency. The internal nodes are stored in the entries of the
+        // leaves that were already used, which keep their symbol in the low bits.
+        let (mut i, mut b, mut e) = (0, 0, 0);
+        let next = |p: &[u64], i: &mut usize, b: &mut usize, e: usize| {
+            if *i != num && (*b == e || (p[*i] >> NUM_BITS) <= (p[*b] >> NUM_BITS)) {
+                *i += 1;
+                *i - 1
+            } else {
+                *b += 1;
+                *b - 1
+            }
+        };
+        loop {
+            let n = next(&p, &mut i, &mut b, e);
+            let mut freq = p[n] & !MASK;
+            p[n] = (p[n] & MASK) | ((e as u64) << NUM_BITS);
+
+            let m = next(&p, &mut i, &mut b, e);
+            freq += p[m] & !MASK;
+            p[m] = (p[m] & MASK) | ((e as u64) << NUM_BITS);
+
+            p[e] = (p[e] & MASK) | freq;
+            e += 1;
+            if num - e <= 1 {
+                break;
+            }
+        }
+
+        // the depth of each internal node from the root down, counting how many leaves end
+        // up at each length. Nodes that are too deep are moved below the deepest leaf that
+        // is still short enough.
+        let mut len_counters = vec![0u32; max_bits + 2];
+        e -= 1;
+        p[e] &= MASK;
+        len_counters[1] = 2;
+        while e > 0 {
+            e -= 1;
+            let mut len = (p[(p[e] >> NUM_BITS) as usize] >> NUM_BITS) as usize + 1;
+            p[e] = (p[e] & MASK) | ((len as u64) << NUM_BITS);
+            if len >= max_bits {
+                len = max_bits - 1;
+                while len_counters[len] == 0 {
+                    len -= 1;
+                }
+            }
+            len_counters[len] -= 1;
+            len_counters[len + 1] += 2;
+        }
+
+        // the least frequent symbols get the longest codes
+        let mut lens = vec![0u8; max_used];
+        let mut i = 0;
+        for len in (1..=max_bits).rev() {
+            for _ in 0..len_counters[len] {
+                lens[(p[i] & MASK) as usize] = len as u8;
+                i += 1;
+            }
+        }
+
+        lens
+    }
+
+    #[test]
+    fn simple_tree() {
+        assert_eq!(calc_bit_lengths(&[100, 50, 25], 15), [1, 2, 2]);
+        assert_eq!(calc_bit_lengths(&[0, 0, 7], 15), [1, 0, 1]);
+        assert_eq!(calc_bit_lengths(&[7, 0, 0], 15), [1, 1]);
+    }
+
+    /// the limited codes still form a complete tree
+    #[test]
+    fn limited_tree_is_complete() {
+        let freq: Vec<u16> = (0..19).map(|i| 1 << (i % 16)).collect();
+        let lens = calc_bit_lengths(&freq, 7);
+        assert!(lens.iter().all(|&l| l > 0 && l <= 7));
+
+        let kraft: u32 = lens.iter().map(|&l| 1 << (7 - l)).sum();
+        assert_eq!(kraft, 1 << 7);
+    }
+}
+
+mod calc_zopfli {
+    /// an entry of one of the package-merge lists
+    #[derive(Copy, Clone)]
+    struct Item {
+        weight: u64,
+        leaf: bool,
+    }
+
+    pub fn calc_bit_lengths(sym_freq: &[u16], max_bits: usize) -> Vec<u8> {
+        // sorted by frequency, and by symbol for the same frequency
+        let mut leaves: Vec<(u16, usize)> = sym_freq
+            .iter()
+            .enumerate()
+            .filter(|(_, &freq)| freq != 0)
+            .map(|(i, &freq)| (freq, i))
+            .collect();
+        leaves.sort_by_key(|&(freq, _)| freq);
+
+        if leaves.len() < 2 {
+            // Zopfli adds a second distance code for the decoders that need two of them
+            let max_code = match leaves.first() {
+                Some(&(_, sym)) if sym != 0 => sym,
+                _ => 1,
+            };
+            let mut lens = vec![0u8; max_code + 1];
+            lens[0] = 1;
+            lens[max_code] = 1;
+            return lens;
+        }
+
+        let leaf_items: Vec<Item> = leaves
+            .iter()
+            .map(|&(freq, _)| Item {
+                weight: freq.into(),
+                leaf: true,
+            })
+            .collect();
+
+        // the list of the longest codes only has the leaves, each of the shorter ones merges
+        // the leaves with the pairs of the list below it. On equal weights the pair comes
+        // first, like in the boundary package-merge of Zopfli.
+        let max_bits = max_bits.min(leaves.len() - 1);
+        let mut lists = vec![leaf_items.clone()];
+        for _ in 1..max_bits {
+            let below = lists.last().unwrap();
+            let mut merged = Vec::with_capacity(leaf_items.len() + below.len() / 2);
+            let mut packages = below.chunks_exact(2).map(|p| Item {
+                weight: p[0].weight + p[1].weight,
+                leaf: false,
+            });
+            let mut next_package = packages.next();
+            for &leaf in &leaf_items {
+                while let Some(p) = next_package.filter(|p| p.weight <= leaf.weight) {
+                    merged.push(p);
+                    next_package = packages.next();
+                }
+                merged.push(leaf);
+            }
+            merged.extend(next_package);
+            merged.extend(packages);
+            lists.push(merged);
+        }
+
+        // take the 2n-2 cheapest entries of the list of the shortest codes, and the entries of
+        // the pairs that they contain from the lists below. Each leaf that is taken from a list
+        // makes its code one bit longer.
+        let mut leaf_lengths = vec![0u8; leaves.len()];
+        let mut take = 2 * leaves.len() - 2;
+        for list in lists.iter().rev() {
+            let taken = &list[..take];
+            let num_leaves = taken.iter().filter(|i| i.leaf).count();
+            for l in &mut leaf_lengths[..num_leaves] {
+                *l += 1;
+            }
+            take = 2 * (taken.len() - num_leaves);
+            if take == 0 {
+                break;
+            }
+        }
+
+        let mut lens = vec![0u8; leaves.iter().map(|&(_, sym)| sym + 1).max().unwrap()];
+        for (&(_, sym), &len) in leaves.iter().zip(leaf_lengths.iter()) {
+            lens[sym] = len;
+        }
+        lens
+    }
+
+    #[test]
+    fn optimal_lengths() {
+        assert_eq!(calc_bit_lengths(&[1, 1, 2, 4], 15), [3, 3, 2, 1]);
+        assert_eq!(calc_bit_lengths(&[0, 5, 0, 5], 15), [0, 1, 0, 1]);
+        assert_eq!(calc_bit_lengths(&[0, 0, 7], 15), [1, 0, 1]);
+
+        // limited to 2 bits, every symbol gets the same length
+        assert_eq!(calc_bit_lengths(&[1, 1, 2, 4], 2), [2, 2, 2, 2]);
+    }
+
+    /// the limited codes still form a complete tree and are optimal
+    #[test]
+    fn limited_tree_is_complete() {
+        let freq: Vec<u16> = (0..19).map(|i| 1 << (i % 16)).collect();
+        let lens = calc_bit_lengths(&freq, 7);
+        assert!(lens.iter().all(|&l| l > 0 && l <= 7));
+
+        let kraft: u32 = lens.iter().map(|&l| 1 << (7 - l)).sum();
+        assert_eq!(kraft, 1 << 7);
+
+        // never worse than the heuristic limiting of 7-Zip
+        let cost = |lens: &[u8]| -> u64 {
+            lens.iter()
+                .zip(freq.iter())
+                .map(|(&l, &f)| u64::from(l) * u64::from(f))
+                .sum()
+        };
+        assert!(cost(&lens) <= cost(&super::calc_7zip::calc_bit_lengths(&freq, 7)));
+    }
+}
@@ -11,6 +11,7 @@ use crate::{
     bit_writer::BitWriter,
     huffman_helper::{calc_huffman_codes, calculate_huffman_code_tree, decode_symbol},
     preflate_constants::TREE_CODE_ORDER_TABLE,
+    preflate_error::DeflateError,
 };
 
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
@@ -92,7 +93,7 @@ impl HuffmanOriginalEncoding {
                     16 => TreeCodeType::Repeat,
                     17 => TreeCodeType::ZeroShort,
                     18 => TreeCodeType::ZeroLong,
-                    _ => return Err(anyhow::Error::msg("Invalid code length")),
+                    _ => return Err(DeflateError::InvalidHuffmanTree.into()),
                 };
 
                 let (sub, bits) = Self::get_tree_code_adjustment(tree_code);
@@ -105,9 +106,8 @@ impl HuffmanOriginalEncoding {
         }
 
         if codes_read != c_lengths_combined {
-            return Err(anyhow::Error::msg(
-                "Code table should be same size as hdist + hlit",
-            ));
+            // the code lengths run past the end of the table
+            return Err(DeflateError::InvalidHuffmanTree.into());
         }
 
         Ok(HuffmanOriginalEncoding {
@@ -221,7 +221,7 @@ impl HuffmanOriginalEncoding {
 
     /// returns the constants used to adjust the coding of tree code types
     /// (amount to subtract, #bits to encode)
-    const fn get_tree_code_adjustment(tree_code: TreeCodeType) -> (u8, u32) {
+    pub(crate) const fn get_tree_code_adjustment(tree_code: TreeCodeType) -> (u8, u32) {
         match tree_code {
             TreeCodeType::Repeat => (3, 2),
             TreeCodeType::ZeroShort => (3, 3),
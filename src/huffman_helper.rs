@@ -4,7 +4,7 @@
  *  This software incorporates material from third parties. See NOTICE.txt for details.
  *--------------------------------------------------------------------------------------------*/
 
-use crate::bit_reader::ReadBits;
+use crate::{bit_reader::ReadBits, preflate_error::DeflateError};
 use std::vec;
 
 /// Calculates Huffman code array given an array of Huffman Code Lengths using the RFC 1951 algorithm
@@ -102,7 +102,7 @@ fn is_valid_huffman_code_lengths(code_lengths: &[u8]) -> bool {
 ///	3. The root node index 'N' is rgHuffNodes.Length - 2. Search should start at that node.
 pub fn calculate_huffman_code_tree(code_lengths: &[u8]) -> anyhow::Result<Vec<i32>> {
     if !is_valid_huffman_code_lengths(code_lengths) {
-        return Err(anyhow::anyhow!("Invalid Huffman code lengths"));
+        return Err(DeflateError::InvalidHuffmanTree.into());
     }
 
     let mut c_codes: i32 = 0;
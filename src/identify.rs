@@ -0,0 +1,157 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Tells which compressor most likely produced a deflate stream, without predicting it. The
+//! stream is only decoded and its parameters estimated, and the compressor is the one of the
+//! built in profile with the same settings.
+
+use crate::{
+    compressor_profile::{CompressorProfile, CompressorProfileRegistry, StreamFingerprint},
+    preflate_error::PreflateError,
+    preflate_parameter_estimator::{PreflateParameters, PreflateStrategy},
+    preflate_token::{BlockType, PreflateToken},
+    process::parse_deflate,
+};
+
+/// the compressors that a stream can be identified as
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum Compressor {
+    /// the stream only has stored blocks, which any compressor writes at level 0
+    Stored,
+    Zlib {
+        level: u32,
+    },
+    /// miniz or miniz_oxide (which includes flate2 with its default backend)
+    Miniz {
+        level: u32,
+    },
+    Zopfli,
+    Isal {
+        level: u32,
+    },
+    Libdeflate {
+        level: u32,
+    },
+    SevenZip {
+        level: u32,
+    },
+    /// none of the built in profiles has the settings that the stream was estimated with
+    Unknown,
+}
+
+/// the compressor that a stream most likely comes from, see identify_deflate_compressor
+#[derive(Debug, Clone)]
+pub struct CompressorIdentity {
+    pub compressor: Compressor,
+    /// the built in profile of the compressor, None if the compressor is unknown or the stream
+    /// is stored
+    pub profile: Option<CompressorProfile>,
+    /// the parameters that were estimated from the stream
+    pub parameters: PreflateParameters,
+    /// How sure the identification is, from 0 for an unknown compressor to 1. This grows with
+    /// the number of matches in the stream, and is lower if the huffman trees can't tell the
+    /// compressors apart (there are no dynamic blocks, or the compressor builds its trees in a
+    /// way that isn't reproduced).
+    pub confidence: f32,
+}
+
+/// Decodes the stream and estimates its parameters to tell which compressor produced it. This is
+/// much faster than decompressing the stream with prediction, since nothing is predicted.
+pub fn identify_deflate_compressor(
+    compressed_data: &[u8],
+) -> Result<CompressorIdentity, PreflateError> {
+    let (_plain_text, blocks, parameters) = parse_deflate(compressed_data)?;
+
+    if let PreflateStrategy::Store = parameters.strategy {
+        return Ok(CompressorIdentity {
+            compressor: Compressor::Stored,
+            profile: None,
+            parameters,
+            confidence: 1.0,
+        });
+    }
+
+    let registry = CompressorProfileRegistry::default();
+    let Some(profile) = registry.identify(&parameters) else {
+        return Ok(CompressorIdentity {
+            compressor: Compressor::Unknown,
+            profile: None,
+            parameters,
+            confidence: 0.0,
+        });
+    };
+
+    let references = blocks
+        .iter()
+        .flat_map(|b| b.tokens.iter())
+        .filter(|t| matches!(t, PreflateToken::Reference(_)))
+        .count();
+
+    // the trees only tell the compressors apart if they were calculated from the symbol counts
+    let trees_identified = !profile.quirks.any_trees
+        && blocks
+            .iter()
+            .any(|b| b.block_type == BlockType::DynamicHuff)
+        && StreamFingerprint::new(&blocks).reproduces_trees(profile.huff_calc);
+
+    let evidence = references as f32 / (references as f32 + 256.0);
+    let confidence = if trees_identified {
+        evidence
+    } else {
+        0.75 * evidence
+    };
+
+    Ok(CompressorIdentity {
+        compressor: compressor_of_profile(&profile.name),
+        profile: Some(profile.clone()),
+        parameters,
+        confidence,
+    })
+}
+
+/// the compressor of a built in profile, which is named after the compressor and its level
+fn compressor_of_profile(name: &str) -> Compressor {
+    let (family, level) = match name.split_once('-') {
+        Some((family, level)) => (family, level.parse().ok()),
+        None => (name, None),
+    };
+
+    match (family, level) {
+        ("zlib", Some(level)) => Compressor::Zlib { level },
+        ("miniz", Some(level)) => Compressor::Miniz { level },
+        ("zopfli", _) => Compressor::Zopfli,
+        ("isal", Some(level)) => Compressor::Isal { level },
+        ("libdeflate", Some(level)) => Compressor::Libdeflate { level },
+        ("7zip", Some(level)) => Compressor::SevenZip { level },
+        _ => Compressor::Unknown,
+    }
+}
+
+#[test]
+fn profile_names() {
+    assert_eq!(
+        compressor_of_profile("zlib-6"),
+        Compressor::Zlib { level: 6 }
+    );
+    assert_eq!(
+        compressor_of_profile("isal-0"),
+        Compressor::Isal { level: 0 }
+    );
+    assert_eq!(compressor_of_profile("zopfli"), Compressor::Zopfli);
+    assert_eq!(compressor_of_profile("dotnet-optimal"), Compressor::Unknown);
+
+    // every built in profile that isn't an alias is known
+    for p in crate::compressor_profile::builtin_profiles() {
+        assert!(
+            compressor_of_profile(&p.name) != Compressor::Unknown
+                || p.name.starts_with("dotnet-")
+                || p.name.starts_with("java-"),
+            "{}",
+            p.name
+        );
+    }
+}
@@ -0,0 +1,66 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Describes how a deflate stream is laid out (the blocks, their sizes and the huffman headers
+//! of the dynamic blocks) without predicting it, for studying how an encoder behaves.
+
+use crate::{
+    deflate_parser::DeflateParser, preflate_error::PreflateError, statistical_codec::BlockCost,
+};
+
+pub use crate::deflate_parser::{
+    CodeLengthSymbol, DeflateBlockType as InspectBlockType, DynamicHeader,
+};
+
+/// the layout of one block of the stream
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockReport {
+    pub block_type: InspectBlockType,
+    /// whether BFINAL was set
+    pub last: bool,
+    /// number of bytes of plain text that the block decodes to
+    pub uncompressed_len: u32,
+    /// number of literals and references in the block
+    pub token_count: usize,
+    /// the number of bits of the header, tree and tokens. The correction bits are always 0,
+    /// since nothing is predicted.
+    pub cost: BlockCost,
+    /// the huffman header of a dynamic block
+    pub dynamic_header: Option<DynamicHeader>,
+}
+
+/// the layout of a deflate stream, see inspect_deflate_stream
+#[derive(Debug, Clone, PartialEq)]
+pub struct InspectReport {
+    pub blocks: Vec<BlockReport>,
+    /// number of bytes of the compressed data up to the end of the final block
+    pub compressed_processed: usize,
+    pub plain_text_len: usize,
+}
+
+/// Reads the blocks of a deflate stream and reports how each of them was encoded. This only
+/// decodes the stream, so it is much faster than decompressing it with prediction.
+pub fn inspect_deflate_stream(compressed_data: &[u8]) -> Result<InspectReport, PreflateError> {
+    let mut parser = DeflateParser::new(compressed_data);
+
+    let mut blocks = Vec::new();
+    while let Some(block) = parser.next_block()? {
+        blocks.push(BlockReport {
+            block_type: block.block_type,
+            last: block.last,
+            uncompressed_len: block.uncompressed_len,
+            token_count: block.symbols.len(),
+            cost: block.cost,
+            dynamic_header: block.dynamic_header,
+        });
+    }
+
+    Ok(InspectReport {
+        blocks,
+        compressed_processed: ((parser.bit_position() + 7) / 8) as usize,
+        plain_text_len: parser.plain_text().len(),
+    })
+}
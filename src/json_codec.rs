@@ -0,0 +1,231 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::io::{BufRead, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::statistical_codec::{
+    CodecCorrection, CodecMisprediction, CountNonDefaultActions, PredictionDecoder,
+    PredictionEncoder,
+};
+
+/// one line of the JSON output
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "action", rename_all = "snake_case")]
+enum JsonAction {
+    Misprediction {
+        position: u32,
+        context: CodecMisprediction,
+        value: bool,
+    },
+    Correction {
+        position: u32,
+        context: CodecCorrection,
+        value: u32,
+    },
+    BucketCorrection {
+        position: u32,
+        context: CodecCorrection,
+        bucket: u8,
+        value: u32,
+    },
+    Value {
+        position: u32,
+        value: u16,
+        max_bits: u8,
+    },
+    VerifyState {
+        position: u32,
+        message: String,
+        checksum: u64,
+    },
+}
+
+/// Null codec that writes every prediction action as a line of JSON instead of
+/// arithmetic coding it. The output is much larger than the cabac output, but it
+/// can be read (and diffed) by humans, which helps when debugging prediction regressions.
+pub struct JsonPredictionEncoder<W> {
+    writer: W,
+    position: u32,
+}
+
+impl<W: Write> JsonPredictionEncoder<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            position: 0,
+        }
+    }
+
+    /// the writer, once all the actions have been written
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn write(&mut self, action: JsonAction) {
+        serde_json::to_writer(&mut self.writer, &action).unwrap();
+        self.writer.write_all(b"\n").unwrap();
+        self.position += 1;
+    }
+}
+
+impl<W: Write> PredictionEncoder for JsonPredictionEncoder<W> {
+    fn encode_correction(&mut self, action: CodecCorrection, value: u32) {
+        self.write(JsonAction::Correction {
+            position: self.position,
+            context: action,
+            value,
+        });
+    }
+
+    fn encode_bucket_correction(&mut self, action: CodecCorrection, bucket: u8, value: u32) {
+        self.write(JsonAction::BucketCorrection {
+            position: self.position,
+            context: action,
+            bucket,
+            value,
+        });
+    }
+
+    fn encode_misprediction(&mut self, action: CodecMisprediction, value: bool) {
+        self.write(JsonAction::Misprediction {
+            position: self.position,
+            context: action,
+            value,
+        });
+    }
+
+    fn encode_value(&mut self, value: u16, max_bits: u8) {
+        self.write(JsonAction::Value {
+            position: self.position,
+            value,
+            max_bits,
+        });
+    }
+
+    fn encode_verify_state(&mut self, message: &'static str, checksum: u64) {
+        self.write(JsonAction::VerifyState {
+            position: self.position,
+            message: message.to_string(),
+            checksum,
+        });
+    }
+
+    fn finish(&mut self) {
+        self.writer.flush().unwrap();
+    }
+
+    /// the JSON output isn't entropy coded, so there are no statistics
+    fn statistics(&self) -> CountNonDefaultActions {
+        CountNonDefaultActions::default()
+    }
+}
+
+/// Reads back the output of the JsonPredictionEncoder. Since this is only used
+/// for debugging, any difference between what is requested and what was written panics.
+pub struct JsonPredictionDecoder<R> {
+    reader: R,
+    line: String,
+}
+
+impl<R: BufRead> JsonPredictionDecoder<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            line: String::new(),
+        }
+    }
+
+    fn read(&mut self) -> JsonAction {
+        self.line.clear();
+        self.reader.read_line(&mut self.line).unwrap();
+        serde_json::from_str(&self.line).unwrap()
+    }
+}
+
+impl<R: BufRead> PredictionDecoder for JsonPredictionDecoder<R> {
+    fn decode_value(&mut self, max_bits_orig: u8) -> u16 {
+        match self.read() {
+            JsonAction::Value {
+                value, max_bits, ..
+            } if max_bits == max_bits_orig => value,
+            x => panic!("expected value with {} bits, found {:?}", max_bits_orig, x),
+        }
+    }
+
+    fn decode_correction(&mut self, correction: CodecCorrection) -> u32 {
+        match self.read() {
+            JsonAction::Correction { context, value, .. } if context == correction => value,
+            x => panic!("expected {:?}, found {:?}", correction, x),
+        }
+    }
+
+    fn decode_bucket_correction(&mut self, correction: CodecCorrection, bucket: u8) -> u32 {
+        match self.read() {
+            JsonAction::BucketCorrection {
+                context,
+                bucket: b,
+                value,
+                ..
+            } if context == correction && b == bucket => value,
+            x => panic!(
+                "expected {:?} in bucket {}, found {:?}",
+                correction, bucket, x
+            ),
+        }
+    }
+
+    fn decode_misprediction(&mut self, misprediction: CodecMisprediction) -> bool {
+        match self.read() {
+            JsonAction::Misprediction { context, value, .. } if context == misprediction => value,
+            x => panic!("expected {:?}, found {:?}", misprediction, x),
+        }
+    }
+
+    fn decode_verify_state(&mut self, message: &'static str, checksum: u64) {
+        match self.read() {
+            JsonAction::VerifyState {
+                message: m,
+                checksum: c,
+                ..
+            } if m == message && c == checksum => {}
+            x => panic!(
+                "expected verify state {} {}, found {:?}",
+                message, checksum, x
+            ),
+        }
+    }
+}
+
+#[test]
+fn roundtrip_json_codec() {
+    use crate::statistical_codec::{drive_encoder, verify_decoder, CodecAction};
+
+    let actions = [
+        CodecAction::Value(200, 8),
+        CodecAction::VerifyState("start", 1234),
+        CodecAction::Misprediction(CodecMisprediction::LiteralPredictionWrong, true),
+        CodecAction::Correction(CodecCorrection::LenCorrection, 5),
+        CodecAction::Correction(CodecCorrection::DistOnlyCorrection, 0),
+        CodecAction::BucketCorrection(CodecCorrection::LenCorrection, 2, 4),
+    ];
+
+    let mut buffer = Vec::new();
+    let mut encoder = JsonPredictionEncoder::new(&mut buffer);
+    drive_encoder(&mut encoder, &actions);
+    encoder.finish();
+
+    let text = String::from_utf8(buffer.clone()).unwrap();
+    assert_eq!(text.lines().count(), actions.len());
+    assert_eq!(
+        text.lines().nth(3).unwrap(),
+        r#"{"action":"correction","position":3,"context":"LenCorrection","value":5}"#
+    );
+
+    let mut decoder = JsonPredictionDecoder::new(&buffer[..]);
+    verify_decoder(&mut decoder, &actions);
+}
@@ -0,0 +1,132 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! The contexts of the original layout. Every misprediction and correction that has its
+//! default value only adds to a run of defaults, whose length is coded before the next
+//! non-default one. Only the values of the corrections have their own contexts.
+
+use anyhow::Result;
+use cabac::traits::CabacReader;
+
+/// the mispredictions of the original layout
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CodecMisprediction {
+    EOFMisprediction,
+    LiteralPredictionWrong,
+    ReferencePredictionWrong,
+    IrregularLen258,
+    TreeCodeCountMisprediction,
+    LiteralCountMisprediction,
+    DistanceCountMisprediction,
+}
+
+/// the corrections of the original layout, in the order of their contexts
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CodecCorrection {
+    TokenCount,
+    NonZeroPadding,
+    BlockTypeCorrection,
+    LenCorrection,
+    DistOnlyCorrection,
+    DistAfterLenCorrection,
+    TreeCodeBitLengthCorrection,
+    LDTypeCorrection,
+    RepeatCountCorrection,
+    LDBitLengthCorrection,
+}
+
+const CORRECTION_COUNT: usize = CodecCorrection::LDBitLengthCorrection as usize + 1;
+
+pub struct PredictionDecoderCabac<R, CTX> {
+    default_count: u32,
+
+    default_encoding: [CTX; 16],
+    default_encoding_nbits: [CTX; 16],
+    correction: [[CTX; 8]; CORRECTION_COUNT],
+    correction_bits: [[CTX; 8]; CORRECTION_COUNT],
+
+    reader: R,
+}
+
+impl<R: CabacReader<CTX>, CTX: Default> PredictionDecoderCabac<R, CTX> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            default_count: 0,
+            default_encoding: std::array::from_fn(|_| CTX::default()),
+            default_encoding_nbits: std::array::from_fn(|_| CTX::default()),
+            correction: std::array::from_fn(|_| std::array::from_fn(|_| CTX::default())),
+            correction_bits: std::array::from_fn(|_| std::array::from_fn(|_| CTX::default())),
+            reader,
+        }
+    }
+
+    fn read_exp_value<const N: usize>(
+        context: &mut [CTX; N],
+        context_bits: &mut [CTX; N],
+        reader: &mut R,
+    ) -> Result<u32> {
+        let bits_found = reader.get_unary_encoded(context)?;
+
+        Ok(match bits_found {
+            0 => 0,
+            1 => 1,
+            _ => reader.get_n_bits(bits_found - 1, context_bits)? as u32 | (1 << (bits_found - 1)),
+        })
+    }
+
+    fn read_default(&mut self) -> Result<()> {
+        self.default_count = Self::read_exp_value(
+            &mut self.default_encoding,
+            &mut self.default_encoding_nbits,
+            &mut self.reader,
+        )?;
+        Ok(())
+    }
+
+    /// Counts down the run of defaults, reading the length of the next run once the current
+    /// one is used up. Returns true if the run ended, which means that this one isn't a default.
+    fn next_is_non_default(&mut self) -> Result<bool> {
+        if self.default_count == 0 {
+            self.read_default()?;
+        }
+
+        if self.default_count > 0 {
+            self.default_count -= 1;
+            Ok(false)
+        } else {
+            Ok(true)
+        }
+    }
+
+    pub fn decode_value(&mut self, max_bits: u8) -> Result<u16> {
+        // the encoder wrote out the run of defaults before every value
+        if self.default_count != 0 {
+            return Err(anyhow::anyhow!("value in the middle of a run of defaults"));
+        }
+
+        let mut value = 0;
+        for _ in 0..max_bits {
+            value = (value << 1) | u16::from(self.reader.get_bypass()?);
+        }
+        Ok(value)
+    }
+
+    pub fn decode_misprediction(&mut self, _misprediction: CodecMisprediction) -> Result<bool> {
+        self.next_is_non_default()
+    }
+
+    pub fn decode_correction(&mut self, correction: CodecCorrection) -> Result<u32> {
+        if !self.next_is_non_default()? {
+            return Ok(0);
+        }
+
+        Self::read_exp_value(
+            &mut self.correction[correction as usize],
+            &mut self.correction_bits[correction as usize],
+            &mut self.reader,
+        )
+    }
+}
@@ -0,0 +1,214 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+use default_boxed::DefaultBoxed;
+
+use crate::preflate_input::PreflateInput;
+
+pub struct HashIterator<'a> {
+    chain: &'a [u16],
+    ref_pos: u32,
+    max_dist: u32,
+    cur_pos: u32,
+    cur_dist: u32,
+}
+
+impl<'a> HashIterator<'a> {
+    fn new(chain: &'a [u16], ref_pos: u32, max_dist: u32, start_pos: u32) -> Self {
+        Self {
+            chain,
+            ref_pos,
+            max_dist,
+            cur_pos: start_pos,
+            cur_dist: ref_pos.wrapping_sub(start_pos),
+        }
+    }
+
+    pub fn valid(&self) -> bool {
+        self.cur_dist <= self.max_dist
+    }
+
+    pub fn dist(&self) -> u32 {
+        self.cur_dist
+    }
+
+    pub fn next(&mut self) -> bool {
+        self.cur_pos = self.chain[self.cur_pos as usize].into();
+        self.cur_dist = self.ref_pos.wrapping_sub(self.cur_pos);
+        self.cur_pos > 0 && self.cur_dist <= self.max_dist
+    }
+}
+
+#[derive(DefaultBoxed)]
+struct HashTable {
+    /// the head of the hash chain for each hash value
+    head: [u16; 65536],
+
+    /// the previous position in the chain for each position, or 0 at the end of the chain
+    prev: [u16; 65536],
+}
+
+pub trait RotatingHashTrait: Default + Copy + Clone {
+    fn hash(&self, mask: u16) -> u16;
+    fn append(&self, c: u8, hash_shift: u32) -> Self;
+}
+
+#[derive(Default, Debug, Copy, Clone)]
+pub struct ZlibRotatingHash {
+    hash: u16,
+}
+
+impl RotatingHashTrait for ZlibRotatingHash {
+    fn hash(&self, mask: u16) -> u16 {
+        self.hash & mask
+    }
+
+    fn append(&self, c: u8, hash_shift: u32) -> ZlibRotatingHash {
+        ZlibRotatingHash {
+            hash: (self.hash << hash_shift) ^ u16::from(c),
+        }
+    }
+}
+
+#[derive(Default, Copy, Clone)]
+pub struct MiniZHash {
+    hash: u32,
+}
+
+impl RotatingHashTrait for MiniZHash {
+    fn hash(&self, _mask: u16) -> u16 {
+        ((self.hash ^ (self.hash >> 11)) & 0x7fff) as u16
+    }
+
+    fn append(&self, c: u8, _hash_shift: u32) -> Self {
+        MiniZHash {
+            hash: (c as u32) << 16 | (self.hash >> 8),
+        }
+    }
+}
+
+pub struct HashChain<H: RotatingHashTrait> {
+    hash_table: Box<HashTable>,
+    hash_shift: u32,
+    running_hash: H,
+    hash_mask: u16,
+    total_shift: i32,
+}
+
+impl<H: RotatingHashTrait> HashChain<H> {
+    pub fn new(hash_shift: u32, hash_mask: u16) -> Self {
+        // total_shift starts at -8 since 0 marks the end of the hash chain
+        HashChain {
+            total_shift: -8,
+            hash_shift,
+            hash_mask,
+            hash_table: HashTable::default_boxed(),
+            running_hash: H::default(),
+        }
+    }
+
+    fn next_hash(&self, b: u8) -> H {
+        self.running_hash.append(b, self.hash_shift)
+    }
+
+    fn next_hash_double(&self, b1: u8, b2: u8) -> H {
+        self.running_hash
+            .append(b1, self.hash_shift)
+            .append(b2, self.hash_shift)
+    }
+
+    pub fn update_running_hash(&mut self, b: u8) {
+        self.running_hash = self.running_hash.append(b, self.hash_shift);
+    }
+
+    fn reshift_if_necessary(&mut self, input: &PreflateInput) {
+        if input.pos() as i32 - self.total_shift >= 0xfe00 {
+            const DELTA: usize = 0x7e00;
+            for i in 0..=self.hash_mask as usize {
+                self.hash_table.head[i] = self.hash_table.head[i].saturating_sub(DELTA as u16);
+            }
+
+            for i in DELTA..=65535 {
+                self.hash_table.prev[i - DELTA] =
+                    self.hash_table.prev[i].saturating_sub(DELTA as u16);
+            }
+
+            self.total_shift += DELTA as i32;
+        }
+    }
+
+    fn get_head(&self, hash: H) -> u32 {
+        self.hash_table.head[hash.hash(self.hash_mask) as usize].into()
+    }
+
+    pub fn iterate_from_head(&self, hash: H, ref_pos: u32, max_dist: u32) -> HashIterator<'_> {
+        HashIterator::new(
+            &self.hash_table.prev,
+            (ref_pos as i32 - self.total_shift) as u32,
+            max_dist,
+            self.get_head(hash),
+        )
+    }
+
+    pub fn cur_hash(&self, input: &PreflateInput) -> H {
+        self.next_hash(input.cur_char(2))
+    }
+
+    pub fn cur_plus_1_hash(&self, input: &PreflateInput) -> H {
+        self.next_hash_double(input.cur_char(2), input.cur_char(3))
+    }
+
+    pub fn hash_equal(&self, a: H, b: H) -> bool {
+        a.hash(self.hash_mask) == b.hash(self.hash_mask)
+    }
+
+    pub fn update_hash(&mut self, mut length: u32, input: &PreflateInput) {
+        if length > 0x180 {
+            while length > 0 {
+                let blk = std::cmp::min(length, 0x180);
+                self.update_hash(blk, input);
+                length -= blk;
+            }
+            return;
+        }
+
+        self.reshift_if_necessary(input);
+
+        let pos = (input.pos() as i32 - self.total_shift) as u16;
+
+        let limit = std::cmp::min(length + 2, input.remaining()) as u16;
+
+        for i in 2..limit {
+            self.update_running_hash(input.cur_char(i as i32));
+            let h = usize::from(self.running_hash.hash(self.hash_mask));
+            let p = pos + i - 2;
+
+            self.hash_table.prev[usize::from(p)] = self.hash_table.head[h];
+            self.hash_table.head[h] = p;
+        }
+    }
+
+    pub fn skip_hash(&mut self, l: u32, input: &PreflateInput) {
+        self.reshift_if_necessary(input);
+
+        let remaining = input.remaining();
+        if remaining > 2 {
+            self.update_running_hash(input.cur_char(2));
+            let h = usize::from(self.running_hash.hash(self.hash_mask));
+            let p = (input.pos() as i32 - self.total_shift) as usize;
+
+            self.hash_table.prev[p] = self.hash_table.head[h];
+            self.hash_table.head[h] = p as u16;
+
+            if remaining > l {
+                self.update_running_hash(input.cur_char(l as i32));
+                if remaining > l + 1 {
+                    self.update_running_hash(input.cur_char(l as i32 + 1));
+                }
+            }
+        }
+    }
+}
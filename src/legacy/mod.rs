@@ -0,0 +1,97 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Recompression of the corrections that were written before they were framed and had a
+//! header byte. These are a single VP8 stream that starts with the parameters, and they
+//! have to be predicted exactly the way the predictor did back then, so the predictor of
+//! that time is kept here as it was, only made to fail instead of panicking on garbage.
+//! Nothing in here is used to write corrections.
+
+mod cabac_codec;
+mod hash_chain;
+mod parameters;
+mod predictor_state;
+mod token_predictor;
+mod tree_predictor;
+
+use std::io::Cursor;
+
+use anyhow::Result;
+use cabac::{traits::CabacReader, vp8::VP8Reader};
+
+use self::{
+    cabac_codec::{CodecCorrection, CodecMisprediction, PredictionDecoderCabac},
+    hash_chain::{MiniZHash, RotatingHashTrait, ZlibRotatingHash},
+    parameters::{PreflateParameters, HASH_ALGORITHM_MINIZ_FAST},
+    token_predictor::TokenPredictor,
+    tree_predictor::recreate_tree_for_block,
+};
+use crate::{deflate_writer::DeflateWriter, preflate_token::BlockType};
+
+/// recompresses the plain text with corrections of the original layout
+pub(crate) fn recompress_legacy(plain_text: &[u8], corrections: &[u8]) -> Result<Vec<u8>> {
+    let mut decoder = PredictionDecoderCabac::new(VP8Reader::new(Cursor::new(corrections))?);
+
+    let params = PreflateParameters::read(&mut decoder)?;
+
+    // empty blocks don't use up any plain text, so garbage could otherwise describe them
+    // forever, but every block takes up at least a bit of the corrections
+    let max_blocks = plain_text.len() + corrections.len() * 8 + 1;
+
+    let mut deflate_writer = DeflateWriter::new(plain_text);
+    if params.hash_algorithm == HASH_ALGORITHM_MINIZ_FAST {
+        let token_predictor = TokenPredictor::<MiniZHash>::new(plain_text, &params);
+        recreate_blocks(
+            token_predictor,
+            max_blocks,
+            &mut decoder,
+            &mut deflate_writer,
+        )?;
+    } else {
+        let token_predictor = TokenPredictor::<ZlibRotatingHash>::new(plain_text, &params);
+        recreate_blocks(
+            token_predictor,
+            max_blocks,
+            &mut decoder,
+            &mut deflate_writer,
+        )?;
+    }
+
+    let padding = decoder.decode_correction(CodecCorrection::NonZeroPadding)?;
+    deflate_writer.flush_with_padding(padding as u8);
+
+    Ok(deflate_writer.detach_output())
+}
+
+fn recreate_blocks<H: RotatingHashTrait, R: CabacReader<CTX>, CTX: Default>(
+    mut token_predictor: TokenPredictor<H>,
+    max_blocks: usize,
+    decoder: &mut PredictionDecoderCabac<R, CTX>,
+    deflate_writer: &mut DeflateWriter,
+) -> Result<()> {
+    let mut block_count = 0;
+    let mut last = token_predictor.input_eof()
+        && !decoder.decode_misprediction(CodecMisprediction::EOFMisprediction)?;
+    while !last {
+        block_count += 1;
+        if block_count > max_blocks {
+            return Err(anyhow::anyhow!("too many blocks for the plain text"));
+        }
+
+        let mut block = token_predictor.recreate_block(decoder)?;
+
+        if block.block_type == BlockType::DynamicHuff {
+            block.huffman_encoding = recreate_tree_for_block(&block.freq, decoder)?;
+        }
+
+        last = token_predictor.input_eof()
+            && !decoder.decode_misprediction(CodecMisprediction::EOFMisprediction)?;
+
+        deflate_writer.encode_block(&block, last)?;
+    }
+
+    Ok(())
+}
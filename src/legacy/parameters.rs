@@ -0,0 +1,74 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+use anyhow::Result;
+use cabac::traits::CabacReader;
+
+use super::cabac_codec::PredictionDecoderCabac;
+
+pub const HASH_ALGORITHM_MINIZ_FAST: u16 = 1;
+
+/// the parameters at the start of the corrections of the original layout, leaving out the
+/// strategies, which the predictor didn't use
+#[derive(Debug, Copy, Clone)]
+pub struct PreflateParameters {
+    pub zlib_compatible: bool,
+    pub window_bits: u32,
+    pub hash_shift: u32,
+    pub hash_mask: u16,
+    pub max_token_count: u16,
+    pub max_dist_3_matches: u16,
+    pub very_far_matches_detected: bool,
+    pub matches_to_start_detected: bool,
+    pub log2_of_max_chain_depth_m1: u32,
+    pub is_fast_compressor: bool,
+    pub good_length: u32,
+    pub max_lazy: u32,
+    pub nice_length: u32,
+    pub max_chain: u32,
+    pub hash_algorithm: u16,
+}
+
+impl PreflateParameters {
+    /// Reads the parameters and checks that the predictor can work with them, since they
+    /// are only garbage if the corrections aren't of the original layout.
+    pub fn read<R: CabacReader<CTX>, CTX: Default>(
+        decoder: &mut PredictionDecoderCabac<R, CTX>,
+    ) -> Result<Self> {
+        let strategy = decoder.decode_value(4)?;
+        let huff_strategy = decoder.decode_value(4)?;
+        if strategy > 3 || huff_strategy > 2 {
+            return Err(anyhow::anyhow!("invalid strategy"));
+        }
+
+        let params = PreflateParameters {
+            zlib_compatible: decoder.decode_value(1)? != 0,
+            window_bits: decoder.decode_value(8)?.into(),
+            hash_shift: decoder.decode_value(8)?.into(),
+            hash_mask: decoder.decode_value(16)?,
+            max_token_count: decoder.decode_value(16)?,
+            max_dist_3_matches: decoder.decode_value(16)?,
+            very_far_matches_detected: decoder.decode_value(1)? != 0,
+            matches_to_start_detected: decoder.decode_value(1)? != 0,
+            log2_of_max_chain_depth_m1: decoder.decode_value(16)?.into(),
+            is_fast_compressor: decoder.decode_value(1)? != 0,
+            good_length: decoder.decode_value(16)?.into(),
+            max_lazy: decoder.decode_value(16)?.into(),
+            nice_length: decoder.decode_value(16)?.into(),
+            max_chain: decoder.decode_value(16)?.into(),
+            hash_algorithm: decoder.decode_value(16)?,
+        };
+
+        if !(8..=15).contains(&params.window_bits)
+            || params.hash_shift >= 16
+            || params.log2_of_max_chain_depth_m1 >= 16
+        {
+            return Err(anyhow::anyhow!("invalid parameters {:?}", params));
+        }
+
+        Ok(params)
+    }
+}
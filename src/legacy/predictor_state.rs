@@ -0,0 +1,250 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::cmp;
+
+use anyhow::Result;
+
+use super::{
+    hash_chain::{HashChain, RotatingHashTrait},
+    parameters::PreflateParameters,
+};
+use crate::{
+    preflate_constants::{MAX_MATCH, MIN_LOOKAHEAD, MIN_MATCH},
+    preflate_input::PreflateInput,
+};
+
+/// a match that was found, as its length and distance
+#[derive(Debug, Copy, Clone)]
+pub struct Match {
+    pub len: u32,
+    pub dist: u32,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum MatchResult {
+    Success(Match),
+    DistanceLargerThanHop0,
+    NoInput,
+    NoMoreMatchesFound,
+    MaxChainExceeded,
+}
+
+pub struct PredictorState<'a, H: RotatingHashTrait> {
+    hash: HashChain<H>,
+    input: PreflateInput<'a>,
+    params: PreflateParameters,
+    window_bytes: u32,
+}
+
+impl<'a, H: RotatingHashTrait> PredictorState<'a, H> {
+    pub fn new(uncompressed: &'a [u8], params: &PreflateParameters) -> Self {
+        Self {
+            hash: HashChain::new(params.hash_shift, params.hash_mask),
+            window_bytes: 1 << params.window_bits,
+            params: *params,
+            input: PreflateInput::new(uncompressed),
+        }
+    }
+
+    pub fn update_running_hash(&mut self, b: u8) {
+        self.hash.update_running_hash(b);
+    }
+
+    pub fn update_hash(&mut self, length: u32) {
+        self.hash.update_hash(length, &self.input);
+        self.input.advance(length);
+    }
+
+    pub fn skip_hash(&mut self, length: u32) {
+        self.hash.skip_hash(length, &self.input);
+        self.input.advance(length);
+    }
+
+    pub fn current_input_pos(&self) -> u32 {
+        self.input.pos()
+    }
+
+    pub fn input_cursor(&self) -> &[u8] {
+        self.input.cur_chars(0)
+    }
+
+    fn input_cursor_offset(&self, offset: i32) -> &[u8] {
+        self.input.cur_chars(offset)
+    }
+
+    fn window_size(&self) -> u32 {
+        self.window_bytes
+    }
+
+    fn total_input_size(&self) -> u32 {
+        self.input.size()
+    }
+
+    pub fn available_input_size(&self) -> u32 {
+        self.input.remaining()
+    }
+
+    pub fn hash_equal(&self, a: H, b: H) -> bool {
+        self.hash.hash_equal(a, b)
+    }
+
+    pub fn calculate_hash(&self) -> H {
+        self.hash.cur_hash(&self.input)
+    }
+
+    pub fn calculate_hash_next(&self) -> H {
+        self.hash.cur_plus_1_hash(&self.input)
+    }
+
+    fn prefix_compare(s1: &[u8], s2: &[u8], best_len: u32, max_len: u32) -> u32 {
+        debug_assert!(max_len >= 3 && s1.len() >= max_len as usize && s2.len() >= max_len as usize);
+
+        if s1[best_len as usize] != s2[best_len as usize] {
+            return 0;
+        }
+        if s1[0] != s2[0] || s1[1] != s2[1] || s1[2] != s2[2] {
+            return 0;
+        }
+
+        let mut match_len = 3;
+        for i in 3..max_len {
+            if s1[i as usize] != s2[i as usize] {
+                break;
+            }
+            match_len = i + 1;
+        }
+
+        match_len
+    }
+
+    pub fn match_token(&self, hash: H, prev_len: u32, offset: u32, max_depth: u32) -> MatchResult {
+        let start_pos = self.current_input_pos() + offset;
+        let max_len = cmp::min(self.total_input_size() - start_pos, MAX_MATCH);
+        if max_len < cmp::max(prev_len + 1, MIN_MATCH) {
+            return MatchResult::NoInput;
+        }
+
+        let max_dist_to_start = start_pos - u32::from(!self.params.matches_to_start_detected);
+
+        let cur_max_dist_hop0;
+        let cur_max_dist_hop1_plus;
+        if self.params.very_far_matches_detected {
+            cur_max_dist_hop0 = cmp::min(max_dist_to_start, self.window_size());
+            cur_max_dist_hop1_plus = cur_max_dist_hop0;
+        } else {
+            // the windows of 256 bytes that zlib rounds up to 512 wrap around here, as they
+            // did in the release builds that wrote these corrections
+            let max_dist: u32 = self.window_size().wrapping_sub(MIN_LOOKAHEAD);
+            cur_max_dist_hop0 = cmp::min(max_dist_to_start, max_dist);
+            cur_max_dist_hop1_plus = cmp::min(max_dist_to_start, max_dist.wrapping_sub(1));
+        }
+
+        let mut max_chain;
+        let nice_len;
+        if max_depth > 0 {
+            max_chain = max_depth;
+            nice_len = max_len;
+        } else {
+            max_chain = self.params.max_chain;
+            nice_len = cmp::min(self.params.nice_length, max_len);
+
+            if prev_len >= self.params.good_length {
+                max_chain >>= 2;
+            }
+        }
+
+        let mut chain_it = self
+            .hash
+            .iterate_from_head(hash, start_pos, cur_max_dist_hop1_plus);
+        // the very first entry in the hash chain can have a larger distance than the others
+        if chain_it.dist() > cur_max_dist_hop0 {
+            return MatchResult::DistanceLargerThanHop0;
+        }
+
+        let mut best_len = prev_len;
+        let mut best_match = None;
+        let input = self.input.cur_chars(offset as i32);
+        loop {
+            let dist = chain_it.dist();
+
+            let match_start = self.input.cur_chars(offset as i32 - dist as i32);
+
+            let match_length = Self::prefix_compare(match_start, input, best_len, max_len);
+            if match_length > best_len {
+                let m = Match {
+                    len: match_length,
+                    dist,
+                };
+
+                if match_length >= nice_len {
+                    return MatchResult::Success(m);
+                }
+
+                best_len = match_length;
+                best_match = Some(m);
+            }
+
+            if !chain_it.next() {
+                return match best_match {
+                    Some(m) => MatchResult::Success(m),
+                    None => MatchResult::NoMoreMatchesFound,
+                };
+            }
+
+            // a chain that was shortened to 0 by good_length wraps around to no limit
+            max_chain = max_chain.wrapping_sub(1);
+
+            if max_chain == 0 {
+                return match best_match {
+                    Some(m) => MatchResult::Success(m),
+                    None => MatchResult::MaxChainExceeded,
+                };
+            }
+        }
+    }
+
+    /// the distance of the match of length len that is the given number of hops down the
+    /// hash chain, counting only the positions that match that long
+    pub fn hop_match(&self, len: u32, hops: u32) -> Result<u32> {
+        let max_len = cmp::min(self.available_input_size(), MAX_MATCH);
+        if len < MIN_MATCH || max_len < len {
+            return Err(anyhow::anyhow!("not enough data left to match"));
+        }
+
+        let cur_pos = self.current_input_pos();
+        let cur_max_dist = cmp::min(cur_pos, self.window_size());
+
+        let hash = self.calculate_hash();
+
+        let mut chain_it = self.hash.iterate_from_head(hash, cur_pos, cur_max_dist);
+        if !chain_it.valid() {
+            return Err(anyhow::anyhow!("no match found"));
+        }
+
+        let mut current_hop = 0;
+
+        loop {
+            let match_length = Self::prefix_compare(
+                self.input_cursor_offset(-(chain_it.dist() as i32)),
+                self.input_cursor(),
+                len - 1,
+                len,
+            );
+
+            if match_length >= len {
+                current_hop += 1;
+                if current_hop == hops {
+                    return Ok(chain_it.dist());
+                }
+            }
+
+            if !chain_it.next() {
+                return Err(anyhow::anyhow!("no match found"));
+            }
+        }
+    }
+}
@@ -0,0 +1,307 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+use anyhow::{Context, Result};
+use cabac::traits::CabacReader;
+
+use super::{
+    cabac_codec::{CodecCorrection, CodecMisprediction, PredictionDecoderCabac},
+    hash_chain::RotatingHashTrait,
+    parameters::PreflateParameters,
+    predictor_state::{Match, MatchResult, PredictorState},
+};
+use crate::{
+    cabac_codec::checked_decode_difference,
+    preflate_constants::{MAX_MATCH, MIN_MATCH},
+    preflate_token::{BlockType, IrregularEncoding, PreflateToken, PreflateTokenBlock},
+};
+
+/// the token that the predictor expects next
+enum Prediction {
+    Literal,
+    Reference(Match),
+}
+
+pub struct TokenPredictor<'a, H: RotatingHashTrait> {
+    state: PredictorState<'a, H>,
+    params: PreflateParameters,
+    pending_reference: Option<Match>,
+    current_token_count: u32,
+    max_token_count: u32,
+}
+
+impl<'a, H: RotatingHashTrait> TokenPredictor<'a, H> {
+    pub fn new(uncompressed: &'a [u8], params: &PreflateParameters) -> Self {
+        let mut r = Self {
+            state: PredictorState::new(uncompressed, params),
+            params: *params,
+            pending_reference: None,
+            current_token_count: 0,
+            max_token_count: params.max_token_count.into(),
+        };
+
+        if r.state.available_input_size() >= 2 {
+            let b0 = r.state.input_cursor()[0];
+            let b1 = r.state.input_cursor()[1];
+
+            r.state.update_running_hash(b0);
+            r.state.update_running_hash(b1);
+        }
+
+        r
+    }
+
+    pub fn recreate_block<R: CabacReader<CTX>, CTX: Default>(
+        &mut self,
+        codec: &mut PredictionDecoderCabac<R, CTX>,
+    ) -> Result<PreflateTokenBlock> {
+        self.current_token_count = 0;
+        self.pending_reference = None;
+
+        const BT_STORED: u32 = BlockType::Stored as u32;
+        const BT_DYNAMICHUFF: u32 = BlockType::DynamicHuff as u32;
+        const BT_STATICHUFF: u32 = BlockType::StaticHuff as u32;
+
+        let bt = checked_decode_difference(
+            BT_DYNAMICHUFF,
+            codec.decode_correction(CodecCorrection::BlockTypeCorrection)?,
+        )?;
+        let mut block = match bt {
+            BT_STORED => {
+                let mut block = PreflateTokenBlock::new(BlockType::Stored);
+                block.uncompressed_len = codec.decode_value(16)?.into();
+                block.padding_bits =
+                    codec.decode_correction(CodecCorrection::NonZeroPadding)? as u8;
+
+                if block.uncompressed_len > self.state.available_input_size() {
+                    return Err(anyhow::anyhow!(
+                        "stored block of {} bytes is past the end of the plain text",
+                        block.uncompressed_len
+                    ));
+                }
+
+                self.state.update_hash(block.uncompressed_len);
+                return Ok(block);
+            }
+            BT_STATICHUFF => PreflateTokenBlock::new(BlockType::StaticHuff),
+            BT_DYNAMICHUFF => PreflateTokenBlock::new(BlockType::DynamicHuff),
+            _ => return Err(anyhow::anyhow!("Invalid block type {}", bt)),
+        };
+
+        let blocksize = match codec.decode_correction(CodecCorrection::TokenCount)? {
+            0 => self.max_token_count,
+            n => n - 1,
+        };
+
+        while !self.input_eof() && self.current_token_count < blocksize {
+            let mut predicted_ref = match self.predict_token() {
+                Prediction::Literal => {
+                    if !codec.decode_misprediction(CodecMisprediction::LiteralPredictionWrong)? {
+                        self.commit_literal(&mut block);
+                        continue;
+                    }
+
+                    self.repredict_reference().with_context(|| {
+                        format!(
+                            "repredict_reference token_count={:?}",
+                            self.current_token_count
+                        )
+                    })?
+                }
+                Prediction::Reference(r) => {
+                    if codec.decode_misprediction(CodecMisprediction::ReferencePredictionWrong)? {
+                        self.commit_literal(&mut block);
+                        continue;
+                    }
+
+                    r
+                }
+            };
+
+            let new_len = checked_decode_difference(
+                predicted_ref.len,
+                codec.decode_correction(CodecCorrection::LenCorrection)?,
+            )?;
+            if new_len != predicted_ref.len {
+                let hops = codec.decode_correction(CodecCorrection::DistAfterLenCorrection)?;
+
+                predicted_ref = Match {
+                    len: new_len,
+                    dist: self
+                        .state
+                        .hop_match(new_len, hops)
+                        .with_context(|| format!("hop_match l={} {:?}", new_len, predicted_ref))?,
+                };
+            } else {
+                let hops = codec.decode_correction(CodecCorrection::DistOnlyCorrection)?;
+                if hops != 0 {
+                    predicted_ref.dist = self
+                        .state
+                        .hop_match(predicted_ref.len, hops)
+                        .with_context(|| {
+                            format!("recalculate_distance token {}", self.current_token_count)
+                        })?;
+                }
+            }
+
+            let irregular = if predicted_ref.len == MAX_MATCH
+                && codec.decode_misprediction(CodecMisprediction::IrregularLen258)?
+            {
+                IrregularEncoding::Len258As284
+            } else {
+                IrregularEncoding::Canonical
+            };
+
+            self.commit_reference(predicted_ref, irregular, &mut block);
+        }
+
+        Ok(block)
+    }
+
+    pub fn input_eof(&self) -> bool {
+        self.state.available_input_size() == 0
+    }
+
+    fn predict_token(&mut self) -> Prediction {
+        if self.state.current_input_pos() == 0 || self.state.available_input_size() < MIN_MATCH {
+            return Prediction::Literal;
+        }
+
+        let hash = self.state.calculate_hash();
+
+        let m = if let Some(pending) = self.pending_reference {
+            MatchResult::Success(pending)
+        } else {
+            self.state.match_token(
+                hash,
+                0,
+                0,
+                if self.params.zlib_compatible {
+                    0
+                } else {
+                    1 << self.params.log2_of_max_chain_depth_m1
+                },
+            )
+        };
+
+        self.pending_reference = None;
+
+        let MatchResult::Success(match_token) = m else {
+            return Prediction::Literal;
+        };
+
+        if match_token.len < MIN_MATCH {
+            return Prediction::Literal;
+        }
+
+        if self.params.is_fast_compressor {
+            return Prediction::Reference(match_token);
+        }
+
+        // match is too small and far way to be worth encoding as a distance/length pair.
+        if match_token.len == 3 && match_token.dist > self.params.max_dist_3_matches.into() {
+            return Prediction::Literal;
+        }
+
+        // Check for a longer match that starts at the next byte, in which case we should
+        // just emit a literal instead of a distance/length pair.
+        if match_token.len < self.params.max_lazy
+            && self.state.available_input_size() >= match_token.len + 2
+        {
+            let hash_next = self.state.calculate_hash_next();
+
+            let mut match_next = self.state.match_token(
+                hash_next,
+                match_token.len,
+                1,
+                if self.params.zlib_compatible {
+                    0
+                } else {
+                    2 << self.params.log2_of_max_chain_depth_m1
+                },
+            );
+
+            if self.state.hash_equal(hash_next, hash) {
+                let max_size = std::cmp::min(self.state.available_input_size() - 1, MAX_MATCH);
+                let c = self.state.input_cursor();
+                let mut rle = 0;
+                while rle < max_size && c[1 + rle as usize] == c[0] {
+                    rle += 1;
+                }
+
+                let match_next_len = match match_next {
+                    MatchResult::Success(s) => s.len,
+                    _ => 0,
+                };
+
+                if rle > match_token.len && rle > match_next_len {
+                    match_next = MatchResult::Success(Match { len: rle, dist: 1 });
+                }
+            }
+
+            if let MatchResult::Success(m) = match_next {
+                if m.len > match_token.len {
+                    if self.params.zlib_compatible {
+                        self.pending_reference = Some(m);
+                    }
+                    return Prediction::Literal;
+                }
+            }
+        }
+
+        Prediction::Reference(match_token)
+    }
+
+    /// When the predicted token was a literal, but the actual token was a reference, try again
+    /// to find a match for the reference.
+    fn repredict_reference(&mut self) -> Result<Match> {
+        if self.state.current_input_pos() == 0 || self.state.available_input_size() < MIN_MATCH {
+            return Err(anyhow::anyhow!("Not enough space left to find a reference"));
+        }
+
+        let hash = self.state.calculate_hash();
+        let match_token =
+            self.state
+                .match_token(hash, 0, 0, 2 << self.params.log2_of_max_chain_depth_m1);
+
+        self.pending_reference = None;
+
+        match match_token {
+            MatchResult::Success(m) if m.len >= MIN_MATCH => Ok(m),
+            _ => Err(anyhow::anyhow!("Didnt find a match {:?}", match_token)),
+        }
+    }
+
+    fn commit_literal(&mut self, block: &mut PreflateTokenBlock) {
+        block.add_literal(self.state.input_cursor()[0]);
+        self.state.update_hash(1);
+        self.current_token_count += 1;
+    }
+
+    fn commit_reference(
+        &mut self,
+        m: Match,
+        irregular: IrregularEncoding,
+        block: &mut PreflateTokenBlock,
+    ) {
+        // the codes were counted as if every match had its regular encoding, which is what
+        // the trees were predicted from
+        block
+            .tokens
+            .push(PreflateToken::new_reference(m.len, m.dist, irregular));
+        block.freq.add_reference(m.len, m.dist);
+
+        // max_lazy is reused by the fast compressor to mean that if a match is larger than a
+        // certain size it should not be added to the dictionary in order to save on speed.
+        if self.params.is_fast_compressor && m.len > self.params.max_lazy {
+            self.state.skip_hash(m.len);
+        } else {
+            self.state.update_hash(m.len);
+        }
+
+        self.current_token_count += 1;
+    }
+}
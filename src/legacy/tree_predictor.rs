@@ -0,0 +1,232 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+use anyhow::Result;
+use cabac::traits::CabacReader;
+
+use super::cabac_codec::{CodecCorrection, CodecMisprediction, PredictionDecoderCabac};
+use crate::{
+    cabac_codec::checked_decode_difference,
+    huffman_calc::{calc_bit_lengths, HufftreeBitCalc},
+    huffman_encoding::{HuffmanOriginalEncoding, TreeCodeType},
+    preflate_constants::{CODETREE_CODE_COUNT, NONLEN_CODE_COUNT, TREE_CODE_ORDER_TABLE},
+    preflate_token::TokenFrequency,
+};
+
+/// the longest bit length of the literal/length and distance trees
+const MAX_CODE_BITS: u32 = 15;
+
+pub fn recreate_tree_for_block<R: CabacReader<CTX>, CTX: Default>(
+    freq: &TokenFrequency,
+    codec: &mut PredictionDecoderCabac<R, CTX>,
+) -> Result<HuffmanOriginalEncoding> {
+    let mut result: HuffmanOriginalEncoding = Default::default();
+
+    let mut bit_lengths = calc_bit_lengths(HufftreeBitCalc::Zlib, &freq.literal_codes, 15);
+
+    if codec.decode_misprediction(CodecMisprediction::LiteralCountMisprediction)? {
+        let corrected_num_literals = codec.decode_value(5)? as usize + NONLEN_CODE_COUNT;
+        bit_lengths.resize(corrected_num_literals, 0);
+    }
+
+    result.num_literals = bit_lengths.len();
+
+    let mut distance_code_lengths =
+        calc_bit_lengths(HufftreeBitCalc::Zlib, &freq.distance_codes, 15);
+
+    if codec.decode_misprediction(CodecMisprediction::DistanceCountMisprediction)? {
+        let corrected_num_distance = codec.decode_value(5)? as usize + 1;
+        distance_code_lengths.resize(corrected_num_distance, 0);
+    }
+
+    result.num_dist = distance_code_lengths.len();
+
+    // frequences are encoded as appended together as a single vector
+    bit_lengths.append(&mut distance_code_lengths);
+
+    result.lengths = reconstruct_ld_trees(codec, &bit_lengths)?;
+
+    let bl_freqs = calc_codetree_freq(&result.lengths);
+
+    let mut tc_code_tree = calc_bit_lengths(HufftreeBitCalc::Zlib, &bl_freqs, 7);
+
+    let mut tc_code_tree_len = calc_tc_lengths_without_trailing_zeros(&tc_code_tree);
+
+    if codec.decode_misprediction(CodecMisprediction::TreeCodeCountMisprediction)? {
+        tc_code_tree_len = codec.decode_value(4)? as usize + 4;
+    }
+
+    result.num_code_lengths = tc_code_tree_len;
+
+    // resize so that when we walk through in TREE_CODE_ORDER_TABLE order, we
+    // don't go out of range.
+    tc_code_tree.resize(CODETREE_CODE_COUNT, 0);
+
+    for i in 0..tc_code_tree_len {
+        result.code_lengths[TREE_CODE_ORDER_TABLE[i]] = checked_decode_difference(
+            tc_code_tree[TREE_CODE_ORDER_TABLE[i]].into(),
+            codec.decode_correction(CodecCorrection::TreeCodeBitLengthCorrection)?,
+        )? as u8;
+    }
+
+    Ok(result)
+}
+
+/// Since treecodes are encoded in a different order (see TREE_CODE_ORDER_TABLE) in
+/// order to optimize the chance of removing trailing zeros, we need to calculate
+/// the effective encoding size of the length codes. This starts from the bit lengths
+/// without their trailing unused symbols, which isn't where the order ends, but it is
+/// what the corrections were predicted with.
+fn calc_tc_lengths_without_trailing_zeros(bit_lengths: &[u8]) -> usize {
+    let mut len = bit_lengths.len();
+    while len > 4
+        && matches!(
+            bit_lengths.get(TREE_CODE_ORDER_TABLE[len - 1]),
+            None | Some(0)
+        )
+    {
+        len -= 1;
+    }
+
+    len
+}
+
+fn reconstruct_ld_trees<R: CabacReader<CTX>, CTX: Default>(
+    decoder: &mut PredictionDecoderCabac<R, CTX>,
+    sym_bit_len: &[u8],
+) -> Result<Vec<(TreeCodeType, u8)>> {
+    let mut symbols = sym_bit_len;
+    let mut prev_code = None;
+    let mut result: Vec<(TreeCodeType, u8)> = Vec::new();
+
+    while !symbols.is_empty() {
+        let predicted_tree_code_type = predict_code_type(symbols, prev_code);
+        prev_code = Some(symbols[0]);
+
+        const TC_CODE: u32 = TreeCodeType::Code as u32;
+        const TC_REPEAT: u32 = TreeCodeType::Repeat as u32;
+        const TC_ZERO_SHORT: u32 = TreeCodeType::ZeroShort as u32;
+        const TC_ZERO_LONG: u32 = TreeCodeType::ZeroLong as u32;
+
+        let tree_code_type = match checked_decode_difference(
+            predicted_tree_code_type as u32,
+            decoder.decode_correction(CodecCorrection::LDTypeCorrection)?,
+        )? {
+            TC_CODE => TreeCodeType::Code,
+            TC_REPEAT => TreeCodeType::Repeat,
+            TC_ZERO_SHORT => TreeCodeType::ZeroShort,
+            TC_ZERO_LONG => TreeCodeType::ZeroLong,
+            _ => return Err(anyhow::anyhow!("Reconstruction failed")),
+        };
+
+        let correction = if tree_code_type != TreeCodeType::Code {
+            CodecCorrection::RepeatCountCorrection
+        } else {
+            CodecCorrection::LDBitLengthCorrection
+        };
+        let tree_code_data = checked_decode_difference(
+            predict_code_data(symbols, tree_code_type).into(),
+            decoder.decode_correction(correction)?,
+        )? as u8;
+
+        result.push((tree_code_type, tree_code_data));
+
+        if tree_code_type == TreeCodeType::Code {
+            if u32::from(tree_code_data) > MAX_CODE_BITS {
+                return Err(anyhow::anyhow!("bit length {} is too long", tree_code_data));
+            }
+            symbols = &symbols[1..];
+        } else {
+            symbols = symbols
+                .get(tree_code_data as usize..)
+                .ok_or_else(|| anyhow::anyhow!("repeat runs past the end of the tree"))?;
+        }
+    }
+
+    Ok(result)
+}
+
+/// calculates the treecode frequence for the given block, which is used to
+/// to calculate the huffman tree for encoding the treecodes themselves
+fn calc_codetree_freq(codes: &[(TreeCodeType, u8)]) -> [u16; CODETREE_CODE_COUNT] {
+    let mut bl_freqs = [0u16; CODETREE_CODE_COUNT];
+
+    for (code, data) in codes.iter() {
+        match code {
+            TreeCodeType::Code => {
+                bl_freqs[*data as usize] += 1;
+            }
+            TreeCodeType::Repeat => {
+                bl_freqs[16] += 1;
+            }
+            TreeCodeType::ZeroShort => {
+                bl_freqs[17] += 1;
+            }
+            TreeCodeType::ZeroLong => {
+                bl_freqs[18] += 1;
+            }
+        }
+    }
+
+    bl_freqs
+}
+
+fn predict_code_type(sym_bit_len: &[u8], previous_code: Option<u8>) -> TreeCodeType {
+    let code = sym_bit_len[0];
+    if code == 0 {
+        let mut curlen = 1;
+        let max_cur_len = std::cmp::min(sym_bit_len.len(), 11);
+        while curlen < max_cur_len && sym_bit_len[curlen] == 0 {
+            curlen += 1;
+        }
+        if curlen >= 11 {
+            TreeCodeType::ZeroLong
+        } else if curlen >= 3 {
+            TreeCodeType::ZeroShort
+        } else {
+            TreeCodeType::Code
+        }
+    } else if let Some(code) = previous_code {
+        let mut curlen = 0;
+        while curlen < sym_bit_len.len() && sym_bit_len[curlen] == code {
+            curlen += 1;
+        }
+        if curlen >= 3 {
+            TreeCodeType::Repeat
+        } else {
+            TreeCodeType::Code
+        }
+    } else {
+        TreeCodeType::Code
+    }
+}
+
+fn predict_code_data(sym_bit_len: &[u8], code_type: TreeCodeType) -> u8 {
+    let code = sym_bit_len[0];
+    match code_type {
+        TreeCodeType::Code => code,
+        TreeCodeType::Repeat => {
+            let mut curlen = 3;
+            let max_cur_len = std::cmp::min(sym_bit_len.len(), 6);
+            while curlen < max_cur_len && sym_bit_len[curlen] == code {
+                curlen += 1;
+            }
+            curlen as u8
+        }
+        TreeCodeType::ZeroShort | TreeCodeType::ZeroLong => {
+            let (mut curlen, limit) = if code_type == TreeCodeType::ZeroShort {
+                (3, 10)
+            } else {
+                (11, 138)
+            };
+            let max_cur_len = std::cmp::min(sym_bit_len.len(), limit);
+            while curlen < max_cur_len && sym_bit_len[curlen] == 0 {
+                curlen += 1;
+            }
+            curlen as u8
+        }
+    }
+}
@@ -4,42 +4,181 @@
  *  This software incorporates material from third parties. See NOTICE.txt for details.
  *--------------------------------------------------------------------------------------------*/
 
+pub mod action_log;
+pub mod archive_summary;
 mod bit_helper;
 mod bit_reader;
 mod bit_writer;
+pub mod block_observer;
 mod cabac_codec;
 mod complevel_estimator;
+pub mod compressor_profile;
+pub mod container;
+pub mod corrections_text;
+pub mod deflate_parser;
 mod deflate_reader;
 mod deflate_writer;
+pub mod git_pack;
+pub mod gzip_header;
+pub mod gzip_stream;
 mod hash_chain;
-mod huffman_calc;
+pub mod hdf5;
+pub mod huffman_calc;
 mod huffman_encoding;
 mod huffman_helper;
+pub mod identify;
+pub mod inspect;
+#[cfg(feature = "serde")]
+pub mod json_codec;
+mod legacy;
+mod lzma_coder;
+pub mod manifest;
+pub mod mat_file;
+pub mod match_predictor;
+pub mod model_dictionary;
+pub mod nested_streams;
+pub mod osm_pbf;
+pub mod pack;
+pub mod pdf;
+pub mod pfl_file;
+pub mod plain_text_segments;
+mod plane_codec;
+pub mod png;
+pub mod prediction_actions;
+pub mod predictor_snapshot;
 mod predictor_state;
+pub mod preflate_config;
 mod preflate_constants;
 pub mod preflate_error;
 mod preflate_input;
 mod preflate_parameter_estimator;
-mod preflate_parse_config;
+pub mod preflate_parse_config;
+pub mod preflate_stats;
 mod preflate_stream_info;
 mod preflate_token;
 mod process;
-mod statistical_codec;
+pub mod progress;
+pub mod range_reader;
+pub mod rotating_hash;
+pub mod roundtrip;
+pub mod secondary_compression;
+mod static_cabac;
+pub mod statistical_codec;
+mod stopwatch;
+pub mod stream_cache;
+mod stream_metrics;
+pub mod streaming;
+pub mod test_vector;
 mod token_predictor;
 mod tree_predictor;
+pub mod truncated_stream;
+mod verbatim_tail;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+pub mod zip_archive;
+pub mod zlib_stream;
+
+pub use cabac_codec::{corrections_version, CorrectionsVersion, CORRECTIONS_FORMAT_VERSION};
+pub use gzip_stream::{decompress_gzip_stream, recompress_gzip_stream};
+pub use identify::{identify_deflate_compressor, CompressorIdentity};
+pub use model_dictionary::ModelDictionary;
+pub use prediction_actions::{
+    decompress_deflate_stream_to_actions, recompress_deflate_stream_from_actions, PredictionAction,
+    PredictionActions,
+};
+pub use preflate_parameter_estimator::{
+    PreflateHuffStrategy, PreflateParameters, PreflateStrategy,
+};
+pub use roundtrip::{roundtrip_check, roundtrip_check_with_config, RoundtripOutcome};
+pub use statistical_codec::{
+    CodecCorrection, CodecMisprediction, ContextCost, CountNonDefaultActions, PredictionDecoder,
+    PredictionEncoder, CONTEXT_SCHEME_VERSION,
+};
+pub use zlib_stream::{decompress_zlib_stream, recompress_zlib_stream};
+
+// The public types don't use any thread local state or interior mutability, so they can be
+// moved to or shared with worker threads and used inside of async executors. This fails
+// to compile if a type accidentally loses that property.
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+
+    assert_send_sync::<DecompressResult>();
+    assert_send_sync::<VerifiedDecompressResult>();
+    assert_send_sync::<PreflateError>();
+    assert_send_sync::<PreflateConfig>();
+    assert_send_sync::<CountNonDefaultActions>();
+    assert_send_sync::<ArchiveSummary>();
+    assert_send_sync::<manifest::Manifest>();
+    assert_send_sync::<manifest::EntryFailure>();
+    assert_send_sync::<nested_streams::CatalogEntry>();
+    assert_send_sync::<container::ExpandedFile>();
+    assert_send_sync::<inspect::InspectReport>();
+    assert_send_sync::<CompressorIdentity>();
+    assert_send_sync::<prediction_actions::RawDecompressResult>();
+    assert_send_sync::<deflate_parser::DeflateParser<&'static [u8]>>();
+    assert_send_sync::<action_log::Divergence>();
+    assert_send_sync::<test_vector::TestVector>();
+    assert_send_sync::<range_reader::ZipEntry>();
+    assert_send_sync::<pack::PackedFile>();
+    assert_send_sync::<pack::PreflateReader<std::fs::File>>();
+    assert_send_sync::<pack::PreflateWriter<std::fs::File>>();
+    assert_send_sync::<pfl_file::PflFile>();
+    assert_send_sync::<block_observer::BlockSummary>();
+    assert_send_sync::<progress::CancellationToken>();
+    assert_send_sync::<osm_pbf::PbfBlobIterator<'static>>();
+    assert_send_sync::<mat_file::MatElementIterator<'static>>();
+    assert_send_sync::<png::PngChunk>();
+    assert_send_sync::<ZlibMatchPredictor>();
+    assert_send_sync::<match_predictor::OptimalMatchPredictor>();
+    assert_send_sync::<predictor_snapshot::PredictorSnapshot>();
+    assert_send_sync::<stream_cache::LruStreamCache>();
+    assert_send_sync::<truncated_stream::StreamProgress>();
+    assert_send_sync::<streaming::DeflateStreamDecompressor>();
+    assert_send_sync::<streaming::DeflateStreamRecompressor<Vec<u8>>>();
+    #[cfg(feature = "async")]
+    assert_send_sync::<streaming::PreflateDecoder<&'static [u8]>>();
+    #[cfg(feature = "async")]
+    assert_send_sync::<streaming::PreflateEncoder<Vec<u8>>>();
+    assert_send_sync::<PreflateParameters>();
+    assert_send_sync::<CorrectionsVersion>();
+    assert_send_sync::<preflate_stats::PreflateStats>();
+    assert_send_sync::<secondary_compression::SecondaryCompressorRegistry>();
+    assert_send_sync::<git_pack::PackObject>();
+    assert_send_sync::<plain_text_segments::SegmentedDecompressResult<'static>>();
+    assert_send_sync::<match_predictor::PredictorState<'static, rotating_hash::ZlibRotatingHash>>();
+    #[cfg(feature = "serde")]
+    assert_send_sync::<json_codec::JsonPredictionEncoder<Vec<u8>>>();
+};
 
 use anyhow::{self};
-use cabac::{
-    debug::{DebugReader, DebugWriter},
-    vp8::{VP8Reader, VP8Writer},
+use archive_summary::{ArchiveSummary, EntryOutcome};
+use block_observer::BlockObserver;
+use cabac::debug::{DebugReader, DebugWriter};
+use compressor_profile::{CompressorProfile, CompressorProfileRegistry};
+use preflate_config::{CorrectionCodec, PreflateConfig, ProbabilityModel, VerifyMode};
+use preflate_error::{PreflateError, ResourceLimit};
+use progress::ConfigObserver;
+use std::{
+    io::{Cursor, Read},
+    sync::Arc,
 };
-use preflate_error::PreflateError;
-use std::io::Cursor;
 
 use crate::{
-    cabac_codec::{PredictionDecoderCabac, PredictionEncoderCabac},
-    process::{read_deflate, write_deflate},
-    statistical_codec::PredictionEncoder,
+    cabac_codec::{
+        encode_static_corrections, encode_static_corrections_split, frame_corrections,
+        with_adaptive_encoder, with_cabac_decoder, CorrectionsHeader, OriginalStream,
+        PredictionDecoderCabac, PredictionEncoderCabac,
+    },
+    match_predictor::{MatchPredictor, ZlibMatchPredictor},
+    plain_text_segments::{PlainTextSegments, SegmentedDecompressResult},
+    plane_codec::PlanePredictionEncoder,
+    process::{
+        read_deflate, read_deflate_with_predictor, verify_deflate_streaming, verify_sampled_blocks,
+        write_deflate, write_deflate_with_predictor,
+    },
+    statistical_codec::{check_decoder, CodecAction, VerifyPredictionEncoder},
+    stopwatch::Stopwatch,
+    stream_cache::{CachedStream, StreamKey},
 };
 
 /// result of decompress_deflate_stream
@@ -51,6 +190,32 @@ pub struct DecompressResult {
     /// the number of bytes that were processed from the compressed stream (this will be exactly the
     /// data that will be recreated using the cabac_encoded data)
     pub compressed_processed: usize,
+    /// how many corrections were needed and how many bits were spent on each kind of correction
+    pub statistics: CountNonDefaultActions,
+    /// CRC-32 of plain_text, so that a copy of the plain text that was passed on elsewhere can be
+    /// checked without keeping it around. For a gzip member this is the CRC of its trailer.
+    pub plain_text_crc32: u32,
+    /// the compressor settings that the stream was predicted with, see
+    /// CompressorProfile::refinements for retrying with better ones. This is the selected
+    /// profile, or the one of the compressor that the stream was detected to come from (such as
+    /// isal-1 or zlib-6), or else a profile named after the encoder label of the parameters.
+    pub profile: CompressorProfile,
+    /// the parameters that were estimated from the stream (window size, level, whether zlib
+    /// could have written it), which are stored at the start of the corrections
+    pub parameters: PreflateParameters,
+}
+
+/// result of decompress_deflate_stream_verified
+pub struct VerifiedDecompressResult {
+    pub result: DecompressResult,
+    /// whether the stream was recompressed from the plain text and corrections exactly as it was
+    pub verified: bool,
+    /// the offset of the first byte of the stream that was recompressed differently, which is
+    /// the end of the shorter one if one of them is cut off. None if the stream was verified or
+    /// couldn't be recompressed at all.
+    pub mismatch_offset: Option<usize>,
+    /// why recompressing failed, if it did
+    pub recompress_error: Option<PreflateError>,
 }
 
 /// decompresses a deflate stream and returns the plaintext and cabac_encoded data that can be used to reconstruct it
@@ -58,19 +223,458 @@ pub fn decompress_deflate_stream(
     compressed_data: &[u8],
     verify: bool,
 ) -> Result<DecompressResult, PreflateError> {
+    decompress_deflate_stream_with_config(
+        compressed_data,
+        &PreflateConfig {
+            verify: if verify {
+                VerifyMode::Full
+            } else {
+                VerifyMode::None
+            },
+            ..PreflateConfig::default()
+        },
+    )
+}
+
+/// decompresses a deflate stream using the options in the config, which allows
+/// choosing how much verification is done before returning
+pub fn decompress_deflate_stream_with_config(
+    compressed_data: &[u8],
+    config: &PreflateConfig,
+) -> Result<DecompressResult, PreflateError> {
+    if config.nested_depth > 0 {
+        return Ok(nested_streams::decompress_nested(
+            compressed_data,
+            config,
+            config.nested_depth,
+        )?
+        .0);
+    }
+
+    Ok(decompress_with_parameters(compressed_data, config)?.0)
+}
+
+/// decompresses each of the deflate streams (for example the entries of a container)
+/// and returns the individual results along with a summary over all of them
+pub fn decompress_deflate_streams<'a>(
+    streams: impl IntoIterator<Item = &'a [u8]>,
+    config: &PreflateConfig,
+) -> (Vec<Result<DecompressResult, PreflateError>>, ArchiveSummary) {
+    let mut summary = ArchiveSummary::default();
+
+    let results = streams
+        .into_iter()
+        .map(|compressed_data| {
+            let start = Stopwatch::start();
+            match decompress_with_parameters(compressed_data, config) {
+                Ok((result, params)) => {
+                    summary.record_processed(
+                        result.compressed_processed,
+                        result.plain_text.len(),
+                        result.cabac_encoded.len(),
+                        &params.encoder_label(),
+                        start.elapsed(),
+                    );
+                    Ok(result)
+                }
+                Err(e) => {
+                    summary.record_failed(EntryOutcome::from_error(&e), start.elapsed());
+                    Err(e)
+                }
+            }
+        })
+        .collect();
+
+    (results, summary)
+}
+
+/// decompresses a deflate stream using a custom match predictor to predict the tokens. The
+/// same predictor has to be passed to recompress_deflate_stream_with_predictor.
+pub fn decompress_deflate_stream_with_predictor<M: MatchPredictor + Clone>(
+    compressed_data: &[u8],
+    config: &PreflateConfig,
+    match_predictor: &M,
+) -> Result<DecompressResult, PreflateError> {
+    Ok(decompress_with_predictor(
+        compressed_data,
+        config,
+        match_predictor,
+        &mut |_| {},
+        &mut (),
+    )?
+    .0)
+}
+
+/// Same as decompress_deflate_stream_with_config, but tells the observer how well each block was
+/// predicted, for example to show the progress or where the corrections were spent. The stream
+/// cache of the config isn't used, since a cached stream isn't predicted again.
+pub fn decompress_deflate_stream_with_observer(
+    compressed_data: &[u8],
+    config: &PreflateConfig,
+    observer: &mut dyn BlockObserver,
+) -> Result<DecompressResult, PreflateError> {
+    Ok(decompress_with_predictor(
+        compressed_data,
+        config,
+        &ZlibMatchPredictor::default(),
+        &mut |_| {},
+        observer,
+    )?
+    .0)
+}
+
+/// Same as decompress_deflate_stream_with_config, but calls on_chunk with the plain text of each
+/// block as soon as it has been decoded, so that the caller can hash or upload the plain text
+/// without another pass over it. The chunks together are the plain text of the result. Streams
+/// with nested streams are expanded afterwards, so their plain text is passed in one chunk at the end.
+pub fn decompress_deflate_stream_with_chunks(
+    compressed_data: &[u8],
+    config: &PreflateConfig,
+    mut on_chunk: impl FnMut(&[u8]),
+) -> Result<DecompressResult, PreflateError> {
+    if config.nested_depth > 0 {
+        let result = decompress_deflate_stream_with_config(compressed_data, config)?;
+        on_chunk(&result.plain_text);
+        return Ok(result);
+    }
+
+    Ok(decompress_with_chunks(compressed_data, config, &mut on_chunk)?.0)
+}
+
+/// Same as decompress_deflate_stream_with_config, but the data of the stored blocks isn't copied
+/// into the plain text of the result. It borrows the compressed data instead, which saves memory
+/// for streams that consist mostly of stored blocks. Streams with nested streams are returned
+/// as a single owned segment.
+pub fn decompress_deflate_stream_segments<'a>(
+    compressed_data: &'a [u8],
+    config: &PreflateConfig,
+) -> Result<SegmentedDecompressResult<'a>, PreflateError> {
+    let mut block_lens = Vec::new();
+    let result = if config.nested_depth > 0 {
+        decompress_deflate_stream_with_config(compressed_data, config)?
+    } else {
+        decompress_with_chunks(compressed_data, config, &mut |chunk| {
+            block_lens.push(chunk.len())
+        })?
+        .0
+    };
+
+    Ok(SegmentedDecompressResult {
+        plain_text: PlainTextSegments::split(
+            compressed_data,
+            result.plain_text,
+            &block_lens,
+            &result.statistics.blocks,
+        ),
+        cabac_encoded: result.cabac_encoded,
+        compressed_processed: result.compressed_processed,
+        statistics: result.statistics,
+        plain_text_crc32: result.plain_text_crc32,
+        profile: result.profile,
+        parameters: result.parameters,
+    })
+}
+
+/// Decompresses the stream like decompress_deflate_stream_with_config, then recompresses it from
+/// the plain text and the corrections and compares the result with the original. Unlike
+/// VerifyMode::Full, a stream that doesn't recompress correctly still returns its result, with
+/// where the recompressed stream went wrong, so that the caller can decide what to do with it.
+/// The verify mode of the config is ignored.
+pub fn decompress_deflate_stream_verified(
+    compressed_data: &[u8],
+    config: &PreflateConfig,
+) -> Result<VerifiedDecompressResult, PreflateError> {
+    let config = PreflateConfig {
+        verify: VerifyMode::None,
+        ..config.clone()
+    };
+    let result = decompress_deflate_stream_with_config(compressed_data, &config)?;
+
+    let original = &compressed_data[..result.compressed_processed];
+    let (mismatch_offset, recompress_error) = match recompress_deflate_stream_with_config(
+        &result.plain_text,
+        &result.cabac_encoded,
+        &config,
+    ) {
+        Ok(recompressed) if recompressed == original => (None, None),
+        Ok(recompressed) => {
+            let offset = recompressed
+                .iter()
+                .zip(original)
+                .position(|(a, b)| a != b)
+                .unwrap_or(recompressed.len().min(original.len()));
+            (Some(offset), None)
+        }
+        Err(e) => (None, Some(e)),
+    };
+
+    Ok(VerifiedDecompressResult {
+        result,
+        verified: mismatch_offset.is_none() && recompress_error.is_none(),
+        mismatch_offset,
+        recompress_error,
+    })
+}
+
+/// Reads the plain text from a reader and recompresses the stream from it like
+/// recompress_deflate_stream_with_config, calling on_chunk with each chunk of the plain text as
+/// it is read.
+pub fn recompress_deflate_stream_from_reader(
+    mut plain_text_reader: impl Read,
+    corrections: &[u8],
+    config: &PreflateConfig,
+    mut on_chunk: impl FnMut(&[u8]),
+) -> Result<Vec<u8>, PreflateError> {
+    let mut plain_text = Vec::new();
+    let mut chunk = vec![0; 1 << 16];
+    loop {
+        let len = match plain_text_reader.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(PreflateError::Io(e)),
+        };
+        on_chunk(&chunk[..len]);
+        plain_text.extend_from_slice(&chunk[..len]);
+    }
+
+    recompress_deflate_stream_with_config(&plain_text, corrections, config)
+}
+
+fn decompress_with_parameters(
+    compressed_data: &[u8],
+    config: &PreflateConfig,
+) -> Result<(DecompressResult, PreflateParameters), PreflateError> {
+    decompress_with_chunks(compressed_data, config, &mut |_| {})
+}
+
+/// decompresses with the default match predictor and the cache of the config, if there is one
+fn decompress_with_chunks(
+    compressed_data: &[u8],
+    config: &PreflateConfig,
+    on_chunk: &mut dyn FnMut(&[u8]),
+) -> Result<(DecompressResult, PreflateParameters), PreflateError> {
+    let Some(cache) = &config.stream_cache else {
+        return decompress_with_predictor(
+            compressed_data,
+            config,
+            &ZlibMatchPredictor::default(),
+            on_chunk,
+            &mut (),
+        );
+    };
+
+    let key = StreamKey::of(compressed_data);
+    if let Some(cached) = cache.get(&key) {
+        // only the plain text is missing, which is much cheaper to get than the corrections
+        if let Some(plain_text) = cached.plain_text(compressed_data, config) {
+            on_chunk(&plain_text);
+            stream_metrics::record_cache_hit();
+            return Ok((
+                DecompressResult {
+                    plain_text_crc32: crc32fast::hash(&plain_text),
+                    plain_text,
+                    cabac_encoded: cached.corrections.clone(),
+                    compressed_processed: cached.compressed_processed,
+                    statistics: cached.statistics.clone(),
+                    profile: cached.profile.clone(),
+                    parameters: cached.params,
+                },
+                cached.params,
+            ));
+        }
+    }
+
+    let (result, params) = decompress_with_predictor(
+        compressed_data,
+        config,
+        &ZlibMatchPredictor::default(),
+        on_chunk,
+        &mut (),
+    )?;
+
+    cache.insert(
+        key,
+        Arc::new(CachedStream {
+            corrections: result.cabac_encoded.clone(),
+            compressed_processed: result.compressed_processed,
+            statistics: result.statistics.clone(),
+            profile: result.profile.clone(),
+            params,
+        }),
+    );
+
+    Ok((result, params))
+}
+
+fn decompress_with_predictor<M: MatchPredictor + Clone>(
+    compressed_data: &[u8],
+    config: &PreflateConfig,
+    match_predictor: &M,
+    on_chunk: &mut dyn FnMut(&[u8]),
+    observer: &mut dyn BlockObserver,
+) -> Result<(DecompressResult, PreflateParameters), PreflateError> {
+    let mut result = predict_and_verify(
+        compressed_data,
+        config,
+        match_predictor,
+        on_chunk,
+        &mut ConfigObserver::new(observer, config),
+    );
+
+    // all the blocks are read before any of them is predicted, so on_chunk already got the
+    // whole plain text when the prediction fails
+    if config.verbatim_fallback && matches!(config.codec, CorrectionCodec::Cabac) {
+        if let Some(block) = result.as_ref().err().and_then(PreflateError::failed_block) {
+            result = verbatim_tail::decompress_with_verbatim_tail(
+                compressed_data,
+                config,
+                match_predictor,
+                block,
+            );
+        }
+    }
+
+    // the corrections of a stored tail are as large as the tail, so this is checked last
+    result = result.and_then(|(mut r, params)| {
+        r.cabac_encoded = secondary_compression::compress_corrections(r.cabac_encoded, config)?;
+        PreflateError::check_limit(
+            ResourceLimit::CorrectionSize,
+            r.cabac_encoded.len() as u64,
+            config.max_correction_size as u64,
+        )?;
+        Ok((r, params))
+    });
+
+    match &result {
+        Ok((r, _)) => stream_metrics::record_stream(
+            stream_metrics::DECOMPRESS,
+            r.compressed_processed,
+            r.plain_text.len(),
+            r.cabac_encoded.len(),
+        ),
+        Err(e) => stream_metrics::record_failure(stream_metrics::DECOMPRESS, e),
+    }
+
+    result
+}
+
+fn predict_and_verify<M: MatchPredictor + Clone>(
+    compressed_data: &[u8],
+    config: &PreflateConfig,
+    match_predictor: &M,
+    on_chunk: &mut dyn FnMut(&[u8]),
+    observer: &mut dyn BlockObserver,
+) -> Result<(DecompressResult, PreflateParameters), PreflateError> {
     let mut cabac_encoded = Vec::new();
+    let mut sampled_actions = Vec::new();
 
-    let mut cabac_encoder =
-        PredictionEncoderCabac::new(VP8Writer::new(&mut cabac_encoded).unwrap());
-    let (compressed_processed, _params, plain_text, _original_blocks) =
-        read_deflate(compressed_data, &mut cabac_encoder, 0)?;
+    let predict_start = Stopwatch::start();
 
-    cabac_encoder.finish();
+    let (compressed_processed, params, plain_text, statistics) = match config.codec {
+        CorrectionCodec::Cabac => {
+            // the header byte tells the decoder how the rest of the corrections was written
+            let header = CorrectionsHeader::from_config(config);
+            cabac_encoded.push(header.to_byte());
 
-    if verify {
-        let mut cabac_decoder =
-            PredictionDecoderCabac::new(VP8Reader::new(Cursor::new(&cabac_encoded)).unwrap());
-        let (recompressed, _recreated_blocks) = write_deflate(&plain_text, &mut cabac_decoder)?;
+            // the length of the stream isn't known until it has been read, so leave room for it
+            cabac_encoded.resize(1 + OriginalStream::SIZE, 0);
+
+            let r = match config.probability_model {
+                _ if header.planes => {
+                    let mut encoder = PlanePredictionEncoder::new();
+                    let r = predict_stream(
+                        compressed_data,
+                        &mut encoder,
+                        config,
+                        match_predictor,
+                        on_chunk,
+                        observer,
+                        &mut sampled_actions,
+                    )?;
+                    encoder.write_planes(&mut cabac_encoded);
+                    r
+                }
+                ProbabilityModel::Adaptive => {
+                    with_adaptive_encoder!(
+                        header,
+                        config.model_dictionary.as_deref(),
+                        &mut cabac_encoded,
+                        |encoder| predict_stream(
+                            compressed_data,
+                            &mut encoder,
+                            config,
+                            match_predictor,
+                            on_chunk,
+                            observer,
+                            &mut sampled_actions,
+                        )?
+                    )
+                }
+                ProbabilityModel::Static => {
+                    // the probabilities are trained on the corrections of this stream,
+                    // so all of them need to be known before anything can be encoded
+                    let mut recorder = VerifyPredictionEncoder::new();
+                    let (processed, params, plain_text, recorded) = predict_stream(
+                        compressed_data,
+                        &mut recorder,
+                        config,
+                        match_predictor,
+                        on_chunk,
+                        observer,
+                        &mut sampled_actions,
+                    )?;
+
+                    // the recorder doesn't produce bits, so only the deflate bits of the blocks are known
+                    let mut statistics = if config.split_channels {
+                        encode_static_corrections_split(&recorder.actions(), &mut cabac_encoded)
+                    } else {
+                        encode_static_corrections(&recorder.actions(), &mut cabac_encoded)
+                    };
+                    statistics.blocks = recorded.blocks;
+                    (processed, params, plain_text, statistics)
+                }
+            };
+
+            cabac_encoded[1..1 + OriginalStream::SIZE]
+                .copy_from_slice(&OriginalStream::of(&compressed_data[..r.0]).to_bytes());
+            cabac_encoded = frame_corrections(&cabac_encoded);
+
+            // only some of the blocks were recreated, but all of the corrections are
+            // decoded, which is cheap without the predictor
+            if !sampled_actions.is_empty() {
+                verify_sampled_corrections(
+                    &cabac_encoded,
+                    &sampled_actions,
+                    &compressed_data[..r.0],
+                    config,
+                )?;
+            }
+            r
+        }
+        #[cfg(feature = "serde")]
+        CorrectionCodec::Json => predict_stream(
+            compressed_data,
+            json_codec::JsonPredictionEncoder::new(&mut cabac_encoded),
+            config,
+            match_predictor,
+            on_chunk,
+            observer,
+            &mut sampled_actions,
+        )?,
+    };
+
+    stream_metrics::record_phase("predict", predict_start.elapsed());
+    let verify_start = Stopwatch::start();
+
+    if config.verify == VerifyMode::Full {
+        let recompressed = recompress_with_predictor(
+            &plain_text,
+            &cabac_encoded,
+            config,
+            match_predictor,
+            &mut ConfigObserver::cancellation_only(&mut (), config),
+        )?;
 
         if recompressed[..] != compressed_data[..compressed_processed] {
             return Err(PreflateError::Mismatch(anyhow::anyhow!(
@@ -79,21 +683,347 @@ pub fn decompress_deflate_stream(
         }
     }
 
-    Ok(DecompressResult {
-        plain_text,
-        cabac_encoded,
-        compressed_processed,
+    if config.verify == VerifyMode::Streaming {
+        verify_streaming(
+            &plain_text,
+            &cabac_encoded,
+            &compressed_data[..compressed_processed],
+            config,
+            match_predictor,
+        )?;
+    }
+
+    if matches!(config.verify, VerifyMode::Full | VerifyMode::Streaming) {
+        stream_metrics::record_phase("verify", verify_start.elapsed());
+    }
+
+    let profile = profile_of(&params, config)?;
+
+    Ok((
+        DecompressResult {
+            plain_text_crc32: crc32fast::hash(&plain_text),
+            plain_text,
+            cabac_encoded,
+            compressed_processed,
+            statistics,
+            profile,
+            parameters: params,
+        },
+        params,
+    ))
+}
+
+/// the profile for DecompressResult::profile, which is the selected one, the one of the
+/// compressor that the parameters fit or else one named after them
+fn profile_of(
+    params: &PreflateParameters,
+    config: &PreflateConfig,
+) -> Result<CompressorProfile, PreflateError> {
+    Ok(match config.selected_profile()? {
+        Some(profile) => profile.clone(),
+        None => config
+            .compressor_profiles
+            .identify(params)
+            .cloned()
+            .unwrap_or_else(|| CompressorProfile::from_parameters(params)),
     })
 }
 
+/// recreates the stream from the corrections and compares it with the original as it is produced
+fn verify_streaming<M: MatchPredictor + Clone>(
+    plain_text: &[u8],
+    corrections: &[u8],
+    original: &[u8],
+    config: &PreflateConfig,
+    match_predictor: &M,
+) -> Result<(), PreflateError> {
+    match config.codec {
+        CorrectionCodec::Cabac => with_cabac_decoder!(
+            corrections,
+            config.model_dictionary.as_deref(),
+            |decoder, _original| {
+                verify_deflate_streaming(
+                    plain_text,
+                    original,
+                    &mut decoder,
+                    match_predictor,
+                    &mut ConfigObserver::cancellation_only(&mut (), config),
+                )
+            }
+        ),
+        #[cfg(feature = "serde")]
+        CorrectionCodec::Json => verify_deflate_streaming(
+            plain_text,
+            original,
+            &mut json_codec::JsonPredictionDecoder::new(corrections),
+            match_predictor,
+            &mut ConfigObserver::cancellation_only(&mut (), config),
+        ),
+    }
+}
+
+/// Decodes the cabac corrections of a stream of which only some of the blocks were recreated,
+/// and checks them against the actions that were recorded while predicting it, along with the
+/// length and crc32 of the stream that they recreate.
+fn verify_sampled_corrections(
+    corrections: &[u8],
+    actions: &[CodecAction],
+    original: &[u8],
+    config: &PreflateConfig,
+) -> Result<(), PreflateError> {
+    with_cabac_decoder!(
+        corrections,
+        config.model_dictionary.as_deref(),
+        |decoder, stored| {
+            stored.verify(original)?;
+            check_decoder(&mut decoder, actions)
+        }
+    )
+}
+
+/// Runs the prediction over the whole stream, writing the corrections to the encoder. When only
+/// some of the blocks are verified, the actions of all of them are put in sampled_actions, so
+/// that the coded corrections can be checked against them.
+fn predict_stream<E: PredictionEncoder, M: MatchPredictor + Clone>(
+    compressed_data: &[u8],
+    mut encoder: E,
+    config: &PreflateConfig,
+    match_predictor: &M,
+    on_chunk: &mut dyn FnMut(&[u8]),
+    observer: &mut dyn BlockObserver,
+    sampled_actions: &mut Vec<CodecAction>,
+) -> Result<(usize, PreflateParameters, Vec<u8>, CountNonDefaultActions), PreflateError> {
+    match config.verify {
+        VerifyMode::None | VerifyMode::Full | VerifyMode::Streaming => {
+            let (processed, params, plain_text, _original_blocks, blocks, _segments) =
+                read_deflate_with_predictor(
+                    compressed_data,
+                    &mut encoder,
+                    0,
+                    match_predictor,
+                    config,
+                    on_chunk,
+                    observer,
+                )?;
+
+            encoder.finish();
+
+            let mut statistics = encoder.statistics();
+            statistics.blocks = blocks;
+            Ok((processed, params, plain_text, statistics))
+        }
+        VerifyMode::Strided(_) | VerifyMode::Random { .. } => {
+            // record the actions as well so that we can replay the selected blocks afterwards
+            let mut combined_encoder = (VerifyPredictionEncoder::new(), encoder);
+
+            let (processed, params, plain_text, original_blocks, blocks, segments) =
+                read_deflate_with_predictor(
+                    compressed_data,
+                    &mut combined_encoder,
+                    0,
+                    match_predictor,
+                    config,
+                    on_chunk,
+                    observer,
+                )?;
+
+            combined_encoder.finish();
+
+            *sampled_actions = combined_encoder.0.actions();
+            verify_sampled_blocks(
+                &plain_text,
+                &params,
+                &segments,
+                &original_blocks,
+                sampled_actions,
+                config.verify,
+                match_predictor,
+            )?;
+
+            let mut statistics = combined_encoder.statistics();
+            statistics.blocks = blocks;
+            Ok((processed, params, plain_text, statistics))
+        }
+    }
+}
+
 /// recompresses a deflate stream using the cabac_encoded data that was returned from decompress_deflate_stream
 pub fn recompress_deflate_stream(
     plain_text: &[u8],
     cabac_encoded: &[u8],
 ) -> Result<Vec<u8>, PreflateError> {
-    let mut cabac_decoder =
-        PredictionDecoderCabac::new(VP8Reader::new(Cursor::new(&cabac_encoded)).unwrap());
-    let (recompressed, _recreated_blocks) = write_deflate(plain_text, &mut cabac_decoder)?;
+    recompress_deflate_stream_with_config(plain_text, cabac_encoded, &PreflateConfig::default())
+}
+
+/// recompresses a deflate stream from corrections that were created with the same codec as
+/// the one selected in the config
+pub fn recompress_deflate_stream_with_config(
+    plain_text: &[u8],
+    corrections: &[u8],
+    config: &PreflateConfig,
+) -> Result<Vec<u8>, PreflateError> {
+    if config.nested_depth > 0 {
+        return nested_streams::recompress_nested(plain_text, corrections, config);
+    }
+
+    recompress_deflate_stream_with_predictor(
+        plain_text,
+        corrections,
+        config,
+        &ZlibMatchPredictor::default(),
+    )
+}
+
+/// recompresses a deflate stream that was decompressed with decompress_deflate_stream_with_predictor
+pub fn recompress_deflate_stream_with_predictor<M: MatchPredictor + Clone>(
+    plain_text: &[u8],
+    corrections: &[u8],
+    config: &PreflateConfig,
+    match_predictor: &M,
+) -> Result<Vec<u8>, PreflateError> {
+    recompress_with_metrics(plain_text, corrections, config, match_predictor, &mut ())
+}
+
+/// Same as recompress_deflate_stream_with_config, but tells the observer how many corrections
+/// each block needed while it is recreated.
+pub fn recompress_deflate_stream_with_observer(
+    plain_text: &[u8],
+    corrections: &[u8],
+    config: &PreflateConfig,
+    observer: &mut dyn BlockObserver,
+) -> Result<Vec<u8>, PreflateError> {
+    recompress_with_metrics(
+        plain_text,
+        corrections,
+        config,
+        &ZlibMatchPredictor::default(),
+        observer,
+    )
+}
+
+/// checks the limits and recompresses, reporting the metrics of the stream
+fn recompress_with_metrics<M: MatchPredictor + Clone>(
+    plain_text: &[u8],
+    corrections: &[u8],
+    config: &PreflateConfig,
+    match_predictor: &M,
+    observer: &mut dyn BlockObserver,
+) -> Result<Vec<u8>, PreflateError> {
+    PreflateError::check_limit(
+        ResourceLimit::PlainTextSize,
+        plain_text.len() as u64,
+        config.max_plaintext_size as u64,
+    )?;
+    PreflateError::check_limit(
+        ResourceLimit::CorrectionSize,
+        corrections.len() as u64,
+        config.max_correction_size as u64,
+    )?;
+
+    let start = Stopwatch::start();
+    let result = recompress_with_predictor(
+        plain_text,
+        corrections,
+        config,
+        match_predictor,
+        &mut ConfigObserver::new(observer, config),
+    );
+    stream_metrics::record_phase("recompress", start.elapsed());
+
+    match &result {
+        Ok(recompressed) => stream_metrics::record_stream(
+            stream_metrics::RECOMPRESS,
+            recompressed.len(),
+            plain_text.len(),
+            corrections.len(),
+        ),
+        Err(e) => stream_metrics::record_failure(stream_metrics::RECOMPRESS, e),
+    }
+
+    result
+}
+
+/// recompresses without reporting metrics, which is used for the verification of a decompression
+fn recompress_with_predictor<M: MatchPredictor + Clone>(
+    plain_text: &[u8],
+    corrections: &[u8],
+    config: &PreflateConfig,
+    match_predictor: &M,
+    observer: &mut dyn BlockObserver,
+) -> Result<Vec<u8>, PreflateError> {
+    let corrections = &secondary_compression::decompress_corrections(corrections, config)?[..];
+
+    match config.codec {
+        CorrectionCodec::Cabac if cabac_codec::is_unframed_corrections(corrections) => {
+            legacy::recompress_legacy(plain_text, corrections).map_err(|e| {
+                PreflateError::CorruptCorrections(
+                    0,
+                    e.context(
+                        "corrections aren't framed, and don't decode as corrections of the original layout either",
+                    ),
+                )
+            })
+        }
+        CorrectionCodec::Cabac if verbatim_tail::has_verbatim_tail(corrections) => {
+            verbatim_tail::recompress_with_verbatim_tail(
+                plain_text,
+                corrections,
+                config,
+                match_predictor,
+            )
+        }
+        CorrectionCodec::Cabac => with_cabac_decoder!(
+            corrections,
+            config.model_dictionary.as_deref(),
+            |decoder, original| {
+                let recompressed =
+                    recreate_stream(plain_text, &mut decoder, match_predictor, observer)?;
+                original.verify(&recompressed)?;
+                Ok(recompressed)
+            }
+        ),
+        #[cfg(feature = "serde")]
+        CorrectionCodec::Json => recreate_stream(
+            plain_text,
+            &mut json_codec::JsonPredictionDecoder::new(corrections),
+            match_predictor,
+            observer,
+        ),
+    }
+}
+
+/// Decompresses a deflate stream and writes the corrections to a custom encoder instead of one
+/// of the built in codecs. Returns the plaintext and the number of bytes of the compressed data
+/// that were processed. finish is called on the encoder once the whole stream has been read.
+pub fn decompress_deflate_stream_with_encoder<E: PredictionEncoder>(
+    compressed_data: &[u8],
+    encoder: &mut E,
+) -> Result<(Vec<u8>, usize), PreflateError> {
+    let (compressed_processed, _params, plain_text, _original_blocks) =
+        read_deflate(compressed_data, encoder, 0)?;
+
+    encoder.finish();
+
+    Ok((plain_text, compressed_processed))
+}
+
+/// recompresses a deflate stream that was decompressed with decompress_deflate_stream_with_encoder,
+/// reading the corrections from the matching decoder
+pub fn recompress_deflate_stream_with_decoder<D: PredictionDecoder>(
+    plain_text: &[u8],
+    decoder: &mut D,
+) -> Result<Vec<u8>, PreflateError> {
+    recreate_stream(plain_text, decoder, &ZlibMatchPredictor::default(), &mut ())
+}
+
+fn recreate_stream<D: PredictionDecoder, M: MatchPredictor + Clone>(
+    plain_text: &[u8],
+    decoder: &mut D,
+    match_predictor: &M,
+    observer: &mut dyn BlockObserver,
+) -> Result<Vec<u8>, PreflateError> {
+    let (recompressed, _recreated_blocks) =
+        write_deflate_with_predictor(plain_text, decoder, match_predictor, observer)?;
     Ok(recompressed)
 }
 
@@ -107,11 +1037,12 @@ pub fn decompress_deflate_stream_assert(
 
     let mut cabac_encoder =
         PredictionEncoderCabac::new(DebugWriter::new(&mut cabac_encoded).unwrap());
-    let (compressed_processed, _params, plain_text, _original_blocks) =
+    let (compressed_processed, params, plain_text, _original_blocks) =
         read_deflate(compressed_data, &mut cabac_encoder, 0)?;
 
     assert_eq!(compressed_processed, compressed_data.len());
     cabac_encoder.finish();
+    let statistics = cabac_encoder.statistics();
 
     if verify {
         let mut cabac_decoder =
@@ -126,9 +1057,16 @@ pub fn decompress_deflate_stream_assert(
     }
 
     Ok(DecompressResult {
+        plain_text_crc32: crc32fast::hash(&plain_text),
         plain_text,
         cabac_encoded,
         compressed_processed,
+        statistics,
+        profile: CompressorProfileRegistry::default()
+            .identify(&params)
+            .cloned()
+            .unwrap_or_else(|| CompressorProfile::from_parameters(&params)),
+        parameters: params,
     })
 }
 
@@ -0,0 +1,246 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Binary range coder in the style of LZMA. Unlike the VP8 contexts, which count the recent bits,
+//! the probability of a context moves a fixed fraction toward every bit that is coded with it.
+//! This forgets old statistics at a constant rate, which suits streams where the
+//! mispredictions come in bursts, and the probabilities have 12 bits of precision, so the cost
+//! of a long run of defaults gets smaller than with 8 bits.
+
+use std::io::{Read, Result, Write};
+
+use cabac::traits::{CabacReader, CabacWriter};
+
+const PROBABILITY_BITS: u32 = 12;
+const PROBABILITY_ONE: u16 = 1 << PROBABILITY_BITS;
+
+/// how far the probability moves toward each coded bit, as a shift of the distance
+const ADAPTATION_SHIFT: u32 = 4;
+
+/// the range is renormalized once it gets below this
+const TOP: u32 = 1 << 24;
+
+/// context with the probability of the next bit being zero (out of 4096)
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct LzmaContext {
+    probability: u16,
+}
+
+impl Default for LzmaContext {
+    fn default() -> Self {
+        LzmaContext {
+            probability: PROBABILITY_ONE / 2,
+        }
+    }
+}
+
+impl LzmaContext {
+    /// context that starts with the probability of zero given with 8 bits
+    pub fn with_probability(probability: u8) -> Self {
+        LzmaContext {
+            probability: (u16::from(probability) << (PROBABILITY_BITS - 8))
+                .clamp(1, PROBABILITY_ONE - 1),
+        }
+    }
+
+    /// probability of the next bit being zero, scaled down to 8 bits
+    pub fn probability(&self) -> u8 {
+        (self.probability >> (PROBABILITY_BITS - 8)).clamp(1, 255) as u8
+    }
+
+    /// the split of the range for the zero bit, after which the probability is updated
+    fn split_and_update(&mut self, range: u32, bit: bool) -> u32 {
+        let bound = (range >> PROBABILITY_BITS) * u32::from(self.probability);
+        if bit {
+            self.probability -= self.probability >> ADAPTATION_SHIFT;
+        } else {
+            self.probability += (PROBABILITY_ONE - self.probability) >> ADAPTATION_SHIFT;
+        }
+        bound
+    }
+}
+
+pub struct LzmaWriter<W> {
+    low: u64,
+    range: u32,
+    /// the last byte that was produced, which is held back until it is known that the
+    /// carry can't change it
+    cache: u8,
+    /// the number of bytes that are held back, which are the cache and 0xff bytes after it
+    pending: u64,
+    writer: W,
+}
+
+impl<W: Write> LzmaWriter<W> {
+    pub fn new(writer: W) -> Self {
+        LzmaWriter {
+            low: 0,
+            range: u32::MAX,
+            cache: 0,
+            pending: 1,
+            writer,
+        }
+    }
+
+    fn shift_low(&mut self) -> Result<()> {
+        if (self.low as u32) < 0xff000000 || self.low >> 32 != 0 {
+            let carry = (self.low >> 32) as u8;
+            let mut byte = self.cache;
+            while self.pending != 0 {
+                self.writer.write_all(&[byte.wrapping_add(carry)])?;
+                byte = 0xff;
+                self.pending -= 1;
+            }
+            self.cache = (self.low >> 24) as u8;
+        }
+        self.pending += 1;
+        self.low = (self.low & 0xffffff) << 8;
+        Ok(())
+    }
+
+    fn normalize(&mut self) -> Result<()> {
+        while self.range < TOP {
+            self.range <<= 8;
+            self.shift_low()?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> CabacWriter<LzmaContext> for LzmaWriter<W> {
+    fn put_bypass(&mut self, bin_value: bool) -> Result<()> {
+        self.range >>= 1;
+        if bin_value {
+            self.low += u64::from(self.range);
+        }
+        self.normalize()
+    }
+
+    fn put(&mut self, value: bool, cur_ctx: &mut LzmaContext) -> Result<()> {
+        let bound = cur_ctx.split_and_update(self.range, value);
+        if value {
+            self.low += u64::from(bound);
+            self.range -= bound;
+        } else {
+            self.range = bound;
+        }
+        self.normalize()
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        for _ in 0..5 {
+            self.shift_low()?;
+        }
+        self.writer.flush()
+    }
+}
+
+pub struct LzmaReader<R> {
+    code: u32,
+    range: u32,
+    reader: R,
+}
+
+impl<R: Read> LzmaReader<R> {
+    pub fn new(reader: R) -> Result<Self> {
+        let mut r = LzmaReader {
+            code: 0,
+            range: u32::MAX,
+            reader,
+        };
+
+        // the first byte is the empty cache of the writer
+        for _ in 0..5 {
+            r.code = (r.code << 8) | u32::from(r.next_byte()?);
+        }
+
+        Ok(r)
+    }
+
+    /// the next byte of the input, or 0 past its end, since the corrections
+    /// don't record how many bits were coded
+    fn next_byte(&mut self) -> Result<u8> {
+        let mut v = [0u8; 1];
+        match self.reader.read(&mut v)? {
+            0 => Ok(0),
+            _ => Ok(v[0]),
+        }
+    }
+
+    fn normalize(&mut self) -> Result<()> {
+        while self.range < TOP {
+            self.range <<= 8;
+            self.code = (self.code << 8) | u32::from(self.next_byte()?);
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> CabacReader<LzmaContext> for LzmaReader<R> {
+    fn get_bypass(&mut self) -> Result<bool> {
+        self.range >>= 1;
+        let bit = self.code >= self.range;
+        if bit {
+            self.code -= self.range;
+        }
+        self.normalize()?;
+        Ok(bit)
+    }
+
+    fn get(&mut self, cur_ctx: &mut LzmaContext) -> Result<bool> {
+        let bound = (self.range >> PROBABILITY_BITS) * u32::from(cur_ctx.probability);
+        let bit = self.code >= bound;
+        cur_ctx.split_and_update(self.range, bit);
+        if bit {
+            self.code -= bound;
+            self.range -= bound;
+        } else {
+            self.range = bound;
+        }
+        self.normalize()?;
+        Ok(bit)
+    }
+}
+
+#[test]
+fn roundtrip_lzma_coder() {
+    use std::io::Cursor;
+
+    // pseudo random bits with a skewed distribution in a few contexts, and some bypass bits
+    let mut seed = 0x12345678u32;
+    let bits: Vec<(bool, Option<usize>)> = (0..100000)
+        .map(|i| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            let context = (seed >> 24) as usize % 5;
+            let bit = ((seed >> 8) & 0xff) < [2, 20, 128, 230, 254][context];
+            (bit, (i % 7 != 0).then_some(context))
+        })
+        .collect();
+
+    let mut buffer = Vec::new();
+    let mut writer = LzmaWriter::new(&mut buffer);
+    let mut contexts = [LzmaContext::default(); 5];
+    for &(bit, context) in bits.iter() {
+        match context {
+            Some(c) => writer.put(bit, &mut contexts[c]).unwrap(),
+            None => writer.put_bypass(bit).unwrap(),
+        }
+    }
+    writer.finish().unwrap();
+
+    // the skewed contexts compress
+    assert!(buffer.len() < bits.len() / 8 * 3 / 4, "{}", buffer.len());
+
+    let mut reader = LzmaReader::new(Cursor::new(&buffer)).unwrap();
+    let mut contexts = [LzmaContext::default(); 5];
+    for &(bit, context) in bits.iter() {
+        let decoded = match context {
+            Some(c) => reader.get(&mut contexts[c]).unwrap(),
+            None => reader.get_bypass().unwrap(),
+        };
+        assert_eq!(bit, decoded);
+    }
+}
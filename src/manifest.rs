@@ -0,0 +1,256 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    archive_summary::{ArchiveSummary, EntryOutcome},
+    decompress_with_parameters,
+    preflate_config::PreflateConfig,
+    preflate_error::PreflateError,
+    recompress_deflate_stream,
+    stopwatch::Stopwatch,
+};
+
+/// version of the manifest layout, incremented if fields are changed in an incompatible way
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// where a deflate stream was found
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StreamLocation {
+    /// path of the file that contained the stream
+    pub path: String,
+    /// byte offset of the start of the deflate stream inside the file
+    pub offset: u64,
+}
+
+/// maps one of the original deflate streams to the artifacts that can be used to recreate it
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ManifestEntry {
+    pub location: StreamLocation,
+
+    /// length of the original deflate stream
+    pub compressed_length: u64,
+    pub compressed_crc32: u32,
+
+    /// name of the plain text artifact, relative to the artifact directory
+    pub plain_text_artifact: String,
+    pub plain_text_length: u64,
+    pub plain_text_crc32: u32,
+
+    /// name of the corrections artifact, relative to the artifact directory
+    pub corrections_artifact: String,
+    pub corrections_length: u64,
+    pub corrections_crc32: u32,
+}
+
+/// Machine readable description of all the streams that were expanded in a batch, which
+/// contains everything needed to drive the reconstruction of the original streams.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Manifest {
+    pub version: u32,
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Manifest {
+            version: MANIFEST_VERSION,
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl Manifest {
+    #[cfg(feature = "serde")]
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn from_json(json: &str) -> Result<Manifest, PreflateError> {
+        let manifest: Manifest = serde_json::from_str(json).map_err(std::io::Error::from)?;
+
+        if manifest.version != MANIFEST_VERSION {
+            return Err(PreflateError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("unsupported manifest version {}", manifest.version),
+            )));
+        }
+
+        Ok(manifest)
+    }
+}
+
+/// an entry of the manifest that couldn't be recreated from its artifacts
+#[derive(Debug)]
+pub struct EntryFailure {
+    /// index of the entry in the manifest
+    pub index: usize,
+    pub location: StreamLocation,
+    pub error: PreflateError,
+}
+
+impl Manifest {
+    /// Recreates all the entries from the artifacts and checks them against the manifest, which
+    /// can be done at any time after the expansion. The entries are spread over the given number
+    /// of threads (0 uses all the available cores). Every entry is checked even if some of them
+    /// fail, and the failures are returned in the order of the entries.
+    pub fn verify(&self, artifact_dir: &Path, threads: usize) -> Vec<EntryFailure> {
+        let threads = if threads == 0 {
+            std::thread::available_parallelism().map_or(1, |n| n.get())
+        } else {
+            threads
+        }
+        .min(self.entries.len().max(1));
+
+        // the entries can take very different amounts of time, so each thread
+        // picks the next unchecked one instead of getting a fixed share
+        let next = AtomicUsize::new(0);
+
+        let mut failures: Vec<EntryFailure> = std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..threads)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut failures = Vec::new();
+                        loop {
+                            let index = next.fetch_add(1, Ordering::Relaxed);
+                            let Some(entry) = self.entries.get(index) else {
+                                break;
+                            };
+                            if let Err(error) = entry.reconstruct(artifact_dir) {
+                                failures.push(EntryFailure {
+                                    index,
+                                    location: entry.location.clone(),
+                                    error,
+                                });
+                            }
+                        }
+                        failures
+                    })
+                })
+                .collect();
+
+            workers
+                .into_iter()
+                .flat_map(|w| w.join().unwrap())
+                .collect()
+        });
+
+        failures.sort_by_key(|f| f.index);
+        failures
+    }
+}
+
+impl ManifestEntry {
+    /// Recreates the original deflate stream from the artifacts, verifying
+    /// the digests of the artifacts and of the recreated stream.
+    pub fn reconstruct(&self, artifact_dir: &Path) -> Result<Vec<u8>, PreflateError> {
+        let plain_text = read_artifact(
+            artifact_dir,
+            &self.plain_text_artifact,
+            self.plain_text_length,
+            self.plain_text_crc32,
+        )?;
+        let corrections = read_artifact(
+            artifact_dir,
+            &self.corrections_artifact,
+            self.corrections_length,
+            self.corrections_crc32,
+        )?;
+
+        let recompressed = recompress_deflate_stream(&plain_text, &corrections)?;
+
+        if recompressed.len() as u64 != self.compressed_length
+            || crc32fast::hash(&recompressed) != self.compressed_crc32
+        {
+            return Err(PreflateError::Mismatch(anyhow::anyhow!(
+                "recreated stream for {}@{} does not match the manifest",
+                self.location.path,
+                self.location.offset
+            )));
+        }
+
+        Ok(recompressed)
+    }
+}
+
+fn read_artifact(
+    artifact_dir: &Path,
+    name: &str,
+    length: u64,
+    crc32: u32,
+) -> Result<Vec<u8>, PreflateError> {
+    let data = std::fs::read(artifact_dir.join(name))?;
+    if data.len() as u64 != length || crc32fast::hash(&data) != crc32 {
+        return Err(PreflateError::Mismatch(anyhow::anyhow!(
+            "artifact {} does not match the manifest",
+            name
+        )));
+    }
+    Ok(data)
+}
+
+/// Expands each of the streams, writing the plain text and corrections of the streams
+/// that could be processed into the artifact directory. Streams that couldn't be processed
+/// are not part of the manifest, since they need to be kept as they are.
+pub fn expand_streams_with_manifest<'a>(
+    streams: impl IntoIterator<Item = (StreamLocation, &'a [u8])>,
+    artifact_dir: &Path,
+    config: &PreflateConfig,
+) -> Result<(Manifest, ArchiveSummary), PreflateError> {
+    let mut manifest = Manifest::default();
+    let mut summary = ArchiveSummary::default();
+
+    for (index, (location, compressed_data)) in streams.into_iter().enumerate() {
+        let start = Stopwatch::start();
+
+        let (result, params) = match decompress_with_parameters(compressed_data, config) {
+            Ok(r) => r,
+            Err(e) => {
+                summary.record_failed(EntryOutcome::from_error(&e), start.elapsed());
+                continue;
+            }
+        };
+
+        let plain_text_artifact = format!("{:06}.plain", index);
+        let corrections_artifact = format!("{:06}.corrections", index);
+
+        std::fs::write(artifact_dir.join(&plain_text_artifact), &result.plain_text)?;
+        std::fs::write(
+            artifact_dir.join(&corrections_artifact),
+            &result.cabac_encoded,
+        )?;
+
+        manifest.entries.push(ManifestEntry {
+            location,
+            compressed_length: result.compressed_processed as u64,
+            compressed_crc32: crc32fast::hash(&compressed_data[..result.compressed_processed]),
+            plain_text_artifact,
+            plain_text_length: result.plain_text.len() as u64,
+            plain_text_crc32: crc32fast::hash(&result.plain_text),
+            corrections_artifact,
+            corrections_length: result.cabac_encoded.len() as u64,
+            corrections_crc32: crc32fast::hash(&result.cabac_encoded),
+        });
+
+        summary.record_processed(
+            result.compressed_processed,
+            result.plain_text.len(),
+            result.cabac_encoded.len(),
+            &params.encoder_label(),
+            start.elapsed(),
+        );
+    }
+
+    Ok((manifest, summary))
+}
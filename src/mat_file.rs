@@ -0,0 +1,194 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! MATLAB level 5 MAT files consist of a 128 byte header followed by data elements, each of
+//! which starts with a tag that contains its type and size. Variables saved with compression
+//! are stored as miCOMPRESSED elements whose data is a zlib stream.
+
+use std::ops::Range;
+
+use crate::{
+    container::{expand_file, ExpandedFile, StreamExtent},
+    nested_streams::is_zlib_header,
+    preflate_config::PreflateConfig,
+    preflate_error::PreflateError,
+};
+
+const HEADER_SIZE: usize = 128;
+
+/// data type of elements that contain a zlib stream
+pub const MI_COMPRESSED: u32 = 15;
+
+/// a top level data element of a MAT file
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MatElement {
+    /// offset of the tag of the element in the file
+    pub offset: usize,
+    pub data_type: u32,
+    /// where the data of the element is in the file (without the padding)
+    pub data: Range<usize>,
+}
+
+/// iterates over the top level data elements of a MAT file
+pub struct MatElementIterator<'a> {
+    data: &'a [u8],
+    pos: usize,
+    big_endian: bool,
+}
+
+impl<'a> MatElementIterator<'a> {
+    /// checks the header of the file and returns an iterator that starts after it
+    pub fn new(data: &'a [u8]) -> Result<Self, PreflateError> {
+        if data.len() < HEADER_SIZE {
+            return Err(invalid_data("file is shorter than the header"));
+        }
+
+        // the endian indicator is written as "IM" in the byte order of the file
+        let big_endian = match &data[126..128] {
+            b"IM" => false,
+            b"MI" => true,
+            _ => return Err(invalid_data("unknown endian indicator")),
+        };
+
+        Ok(MatElementIterator {
+            data,
+            pos: HEADER_SIZE,
+            big_endian,
+        })
+    }
+
+    fn read_u32(&self, offset: usize) -> Option<u32> {
+        let bytes: [u8; 4] = self.data.get(offset..offset + 4)?.try_into().unwrap();
+        Some(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    fn read_element(&mut self) -> Result<MatElement, PreflateError> {
+        let offset = self.pos;
+        let tag = self
+            .read_u32(offset)
+            .ok_or_else(|| invalid_data("truncated element tag"))?;
+
+        // small elements store the size in the upper half of the type
+        // and the data in the second half of the tag
+        if tag >> 16 != 0 {
+            let size = (tag >> 16) as usize;
+            if size > 4 || offset + 8 > self.data.len() {
+                return Err(invalid_data("invalid small data element"));
+            }
+            self.pos = offset + 8;
+            return Ok(MatElement {
+                offset,
+                data_type: tag & 0xffff,
+                data: offset + 4..offset + 4 + size,
+            });
+        }
+
+        let size = self
+            .read_u32(offset + 4)
+            .ok_or_else(|| invalid_data("truncated element tag"))? as usize;
+        let data = offset + 8..offset + 8 + size;
+        if data.end > self.data.len() {
+            return Err(invalid_data("truncated element"));
+        }
+
+        // everything except compressed elements is padded to a multiple of 8 bytes
+        self.pos = if tag == MI_COMPRESSED {
+            data.end
+        } else {
+            std::cmp::min((data.end + 7) & !7, self.data.len())
+        };
+
+        Ok(MatElement {
+            offset,
+            data_type: tag,
+            data,
+        })
+    }
+}
+
+impl Iterator for MatElementIterator<'_> {
+    type Item = Result<MatElement, PreflateError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.data.len() {
+            return None;
+        }
+
+        let r = self.read_element();
+        if r.is_err() {
+            // the following elements can't be found without a valid size
+            self.pos = self.data.len();
+        }
+        Some(r)
+    }
+}
+
+/// returns the extents of the deflate data of all the compressed elements of the file
+pub fn mat_zlib_extents(data: &[u8]) -> Result<Vec<StreamExtent>, PreflateError> {
+    let mut extents = Vec::new();
+    for element in MatElementIterator::new(data)? {
+        let element = element?;
+        let r = element.data;
+
+        // zlib header and adler32 trailer around the deflate data
+        if element.data_type == MI_COMPRESSED && r.len() > 6 && is_zlib_header(&data[r.clone()]) {
+            extents.push(StreamExtent {
+                offset: r.start + 2,
+                length: r.len() - 6,
+            });
+        }
+    }
+    Ok(extents)
+}
+
+/// Expands all the compressed elements of a MAT file. The file can be recreated
+/// byte for byte with container::recreate_file.
+pub fn expand_mat(data: &[u8], config: &PreflateConfig) -> Result<ExpandedFile, PreflateError> {
+    Ok(expand_file(data, mat_zlib_extents(data)?, config))
+}
+
+fn invalid_data(message: &str) -> PreflateError {
+    PreflateError::Io(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("invalid MAT file: {}", message),
+    ))
+}
+
+#[test]
+fn walks_padded_and_small_elements() {
+    let mut data = vec![b' '; 124];
+    data.extend_from_slice(&[0x00, 0x01]);
+    data.extend_from_slice(b"MI");
+
+    // miINT8 with 5 bytes of data padded to 8, followed by a small miINT32 element
+    data.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 5, 1, 2, 3, 4, 5, 0, 0, 0]);
+    data.extend_from_slice(&[0, 4, 0, 5, 9, 9, 9, 9]);
+
+    let elements: Vec<_> = MatElementIterator::new(&data)
+        .unwrap()
+        .map(|e| e.unwrap())
+        .collect();
+
+    assert_eq!(
+        elements,
+        [
+            MatElement {
+                offset: 128,
+                data_type: 1,
+                data: 136..141
+            },
+            MatElement {
+                offset: 144,
+                data_type: 5,
+                data: 148..152
+            }
+        ]
+    );
+}
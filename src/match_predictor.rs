@@ -0,0 +1,297 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! The match predictor decides which token the compressor would have emitted at the current
+//! position. The rest of the prediction (hash chains, corrections, huffman trees) stays the same,
+//! so an alternative predictor can model a different compressor's parser by implementing this trait.
+
+pub use crate::{
+    hash_chain::RotatingHashTrait,
+    predictor_state::{MatchResult, PredictorState},
+    preflate_parameter_estimator::{PreflateParameters, PreflateStrategy},
+    preflate_token::{IrregularEncoding, PreflateToken, PreflateTokenReference},
+};
+
+use crate::{
+    bit_helper::run_length,
+    preflate_constants::{
+        quantize_distance, quantize_length, DIST_EXTRA_TABLE, LENGTH_EXTRA_TABLE, MAX_MATCH,
+        MIN_MATCH,
+    },
+};
+
+/// Decides which token the compressor would have emitted at the current position of the state.
+/// The same predictor needs to be used when recompressing, since the corrections are relative
+/// to what it predicted. The blocks of a stream can be predicted on several threads with a
+/// copy of the predictor each, which is why it has to be Send.
+pub trait MatchPredictor: Send {
+    /// predicts the token at the current position. This is only called if there are
+    /// enough bytes left for a match, and is called again for the next position after
+    /// the actual token has been committed.
+    fn predict_token<H: RotatingHashTrait>(
+        &mut self,
+        state: &PredictorState<H>,
+        params: &PreflateParameters,
+    ) -> PreflateToken;
+
+    /// called when the actual token differed from the prediction, so that any
+    /// lookahead state that was based on the wrong prediction can be discarded
+    fn reset(&mut self);
+}
+
+/// The predictor for zlib and its derivatives: lazy matching (with the pending match carried
+/// over to the next position), the RLE check, and the cutoff for far length 3 matches. Streams
+/// with the optimal strategy are passed on to the OptimalMatchPredictor.
+#[derive(Default, Debug, Copy, Clone)]
+pub struct ZlibMatchPredictor {
+    pending_reference: Option<PreflateTokenReference>,
+    optimal: OptimalMatchPredictor,
+}
+
+impl MatchPredictor for ZlibMatchPredictor {
+    fn predict_token<H: RotatingHashTrait>(
+        &mut self,
+        state: &PredictorState<H>,
+        params: &PreflateParameters,
+    ) -> PreflateToken {
+        if let PreflateStrategy::Optimal = params.strategy {
+            return self.optimal.predict_token(state, params);
+        }
+
+        let hash = state.calculate_hash();
+
+        let m = if let Some(pending) = self.pending_reference {
+            MatchResult::Success(pending)
+        } else {
+            state.match_token(
+                hash,
+                0,
+                0,
+                if params.zlib_compatible {
+                    0
+                } else {
+                    1 << params.log2_of_max_chain_depth_m1
+                },
+            )
+        };
+
+        self.pending_reference = None;
+
+        if let MatchResult::Success(match_token) = m {
+            if match_token.len() < MIN_MATCH {
+                return PreflateToken::Literal;
+            }
+
+            if params.is_fast_compressor {
+                return PreflateToken::Reference(match_token);
+            }
+
+            // match is too small and far way to be worth encoding as a distance/length pair.
+            if match_token.len() == 3 && match_token.dist() > params.max_dist_3_matches.into() {
+                return PreflateToken::Literal;
+            }
+
+            // Check for a longer match that starts at the next byte, in which case we should
+            // just emit a literal instead of a distance/length pair.
+            if match_token.len() < params.max_lazy
+                && state.available_input_size() >= match_token.len() + 2
+            {
+                let mut match_next;
+                let hash_next = state.calculate_hash_next();
+
+                match_next = state.match_token(
+                    hash_next,
+                    match_token.len(),
+                    1,
+                    if params.zlib_compatible {
+                        0
+                    } else {
+                        2 << params.log2_of_max_chain_depth_m1
+                    },
+                );
+
+                if state.hash_equal(hash_next, hash) {
+                    let max_size = std::cmp::min(state.available_input_size() - 1, MAX_MATCH);
+                    let c = state.input_cursor();
+                    let rle = run_length(&c[1..=max_size as usize], c[0]) as u32;
+
+                    let match_next_len = if let MatchResult::Success(s) = match_next {
+                        s.len()
+                    } else {
+                        0
+                    };
+
+                    if rle > match_token.len() && rle > match_next_len {
+                        match_next = MatchResult::Success(PreflateTokenReference::new(
+                            rle,
+                            1,
+                            IrregularEncoding::Canonical,
+                        ));
+                    }
+                }
+
+                if let MatchResult::Success(m) = match_next {
+                    if m.len() > match_token.len() {
+                        self.pending_reference = Some(m);
+
+                        if !params.zlib_compatible {
+                            self.pending_reference = None;
+                        }
+                        return PreflateToken::Literal;
+                    }
+                }
+            }
+
+            PreflateToken::Reference(match_token)
+        } else {
+            PreflateToken::Literal
+        }
+    }
+
+    fn reset(&mut self) {
+        self.pending_reference = None;
+        self.optimal.reset();
+    }
+}
+
+/// the number of positions that the optimal parse chooses tokens for, of which the ones that
+/// start in the first half are kept before the parse is repeated further on
+const OPTIMAL_WINDOW: u32 = 32;
+const OPTIMAL_KEEP: usize = OPTIMAL_WINDOW as usize / 2;
+
+/// The predictor for optimal parsers such as the one of Zopfli. It finds the longest match at
+/// each position of a short lookahead and chooses the cheapest way through it, where each match
+/// can also be taken shorter, with the fixed huffman code as cost model. The tokens of the
+/// cheapest path are replayed until the stream leaves it or runs past the part that is kept.
+#[derive(Default, Debug, Copy, Clone)]
+pub struct OptimalMatchPredictor {
+    plan_start: u32,
+    /// the token of the cheapest path that starts at each offset from plan_start
+    plan: [Option<PreflateToken>; OPTIMAL_KEEP],
+}
+
+impl OptimalMatchPredictor {
+    /// the bits of a literal in the fixed huffman code
+    fn literal_cost(b: u8) -> u32 {
+        if b <= 143 {
+            8
+        } else {
+            9
+        }
+    }
+
+    /// the bits of a match in the fixed huffman code, including the extra bits
+    fn match_cost(len: u32, dist: u32) -> u32 {
+        let len_code = quantize_length(len);
+        // the length symbols up to 279 have 7 bits, the distance symbols 5
+        let symbols = if len_code <= 279 - 257 { 7 } else { 8 } + 5;
+        symbols
+            + u32::from(LENGTH_EXTRA_TABLE[len_code])
+            + u32::from(DIST_EXTRA_TABLE[quantize_distance(dist)])
+    }
+
+    fn plan_tokens<H: RotatingHashTrait>(
+        &mut self,
+        state: &PredictorState<H>,
+        params: &PreflateParameters,
+    ) {
+        const SIZE: usize = (OPTIMAL_WINDOW + MAX_MATCH) as usize + 1;
+
+        let available = state.available_input_size();
+        let window = OPTIMAL_WINDOW.min(available) as usize;
+        let end = (OPTIMAL_WINDOW + MAX_MATCH).min(available) as usize;
+        let input = state.input_cursor();
+
+        // the cheapest cost to get to each position, and the token that gets there
+        let mut cost = [u32::MAX; SIZE];
+        let mut step = [PreflateToken::Literal; SIZE];
+        cost[0] = 0;
+
+        for i in 0..window {
+            let c = cost[i];
+            let mut relax = |to: usize, c: u32, token: PreflateToken| {
+                if c < cost[to] {
+                    cost[to] = c;
+                    step[to] = token;
+                }
+            };
+
+            relax(
+                i + 1,
+                c + Self::literal_cost(input[i]),
+                PreflateToken::Literal,
+            );
+
+            if (i as u32) + std::cmp::max(MIN_MATCH, H::NUM_HASH_BYTES) > available {
+                continue;
+            }
+            let hash = state.calculate_hash_at(i as u32);
+            if let MatchResult::Success(m) = state.match_token(hash, 0, i as u32, 0) {
+                for len in MIN_MATCH..=m.len() {
+                    if len == MIN_MATCH && m.dist() > params.max_dist_3_matches.into() {
+                        continue;
+                    }
+                    relax(
+                        i + len as usize,
+                        c + Self::match_cost(len, m.dist()),
+                        PreflateToken::Reference(PreflateTokenReference::new(
+                            len,
+                            m.dist(),
+                            IrregularEncoding::Canonical,
+                        )),
+                    );
+                }
+            }
+        }
+
+        // the path ends where it has the lowest cost per byte, since the bytes after it
+        // are only partly covered by matches
+        let mut best_end = window;
+        for e in window..=end {
+            if cost[e] != u32::MAX
+                && u64::from(cost[e]) * (best_end as u64) < u64::from(cost[best_end]) * (e as u64)
+            {
+                best_end = e;
+            }
+        }
+
+        self.plan_start = state.current_input_pos();
+        self.plan = Default::default();
+        let mut pos = best_end;
+        while pos > 0 {
+            let token = step[pos];
+            let start = pos
+                - match token {
+                    PreflateToken::Literal => 1,
+                    PreflateToken::Reference(r) => r.len() as usize,
+                };
+            if start < OPTIMAL_KEEP {
+                self.plan[start] = Some(token);
+            }
+            pos = start;
+        }
+    }
+}
+
+impl MatchPredictor for OptimalMatchPredictor {
+    fn predict_token<H: RotatingHashTrait>(
+        &mut self,
+        state: &PredictorState<H>,
+        params: &PreflateParameters,
+    ) -> PreflateToken {
+        let offset = state.current_input_pos().wrapping_sub(self.plan_start) as usize;
+        if let Some(&Some(token)) = self.plan.get(offset) {
+            return token;
+        }
+
+        self.plan_tokens(state, params);
+        self.plan[0].unwrap_or(PreflateToken::Literal)
+    }
+
+    fn reset(&mut self) {
+        self.plan = Default::default();
+    }
+}
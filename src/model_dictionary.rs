@@ -0,0 +1,182 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Probabilities for the contexts of the adaptive cabac model that were trained on a set of
+//! streams. The adaptive model normally starts every stream from scratch, which costs a lot
+//! on small streams that end before the contexts have learned anything. Streams that were
+//! written by the same compressor tend to mispredict in the same places, so starting the
+//! contexts from a dictionary that was trained on similar streams makes their corrections
+//! smaller.
+//!
+//! The id of the dictionary is recorded in the corrections, and the same dictionary has to be
+//! given in PreflateConfig::model_dictionary when recompressing.
+
+use crate::{
+    cabac_codec::PredictionEncoderCabac,
+    preflate_error::PreflateError,
+    process::read_deflate,
+    static_cabac::{CountingContext, CountingWriter},
+    statistical_codec::{drive_encoder, VerifyPredictionEncoder, CONTEXT_SCHEME_VERSION},
+};
+
+const MAGIC: &[u8; 4] = b"PFMD";
+
+/// size of the magic, the context scheme version and the number of contexts
+const HEADER_SIZE: usize = 10;
+
+/// the probability of zero (out of 256) that each context of the adaptive model starts with
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ModelDictionary {
+    probabilities: Vec<u8>,
+    id: u32,
+}
+
+impl ModelDictionary {
+    /// trains a dictionary on the given deflate streams
+    pub fn train<'a>(
+        streams: impl IntoIterator<Item = &'a [u8]>,
+    ) -> Result<ModelDictionary, PreflateError> {
+        let mut trainer = ModelDictionaryTrainer::new();
+        for stream in streams {
+            trainer.add_stream(stream)?;
+        }
+        Ok(trainer.finish())
+    }
+
+    /// the dictionary as it is stored in a file
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.probabilities.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&CONTEXT_SCHEME_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&(self.probabilities.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.probabilities);
+        bytes
+    }
+
+    /// Reads a dictionary that was written by to_bytes. Fails if the dictionary was trained
+    /// with a different context scheme, since its probabilities then belong to other contexts.
+    pub fn from_bytes(bytes: &[u8]) -> Result<ModelDictionary, PreflateError> {
+        let corrupt = |offset: usize, message: String| {
+            PreflateError::CorruptCorrections(offset, anyhow::anyhow!(message))
+        };
+
+        if bytes.len() < HEADER_SIZE || &bytes[0..4] != MAGIC {
+            return Err(corrupt(0, "not a model dictionary".to_string()));
+        }
+
+        let version = u16::from_le_bytes(bytes[4..6].try_into().unwrap());
+        if version != CONTEXT_SCHEME_VERSION {
+            return Err(corrupt(
+                4,
+                format!(
+                    "model dictionary has context scheme {}, expected {}",
+                    version, CONTEXT_SCHEME_VERSION
+                ),
+            ));
+        }
+
+        let count = u32::from_le_bytes(bytes[6..10].try_into().unwrap()) as usize;
+        if count != context_count() || bytes.len() != HEADER_SIZE + count {
+            return Err(corrupt(
+                6,
+                format!(
+                    "model dictionary has {} contexts in {} bytes, expected {}",
+                    count,
+                    bytes.len() - HEADER_SIZE,
+                    context_count()
+                ),
+            ));
+        }
+
+        Ok(ModelDictionary::new(bytes[HEADER_SIZE..].to_vec()))
+    }
+
+    /// identifies the dictionary in the corrections that were written with it
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub(crate) fn probabilities(&self) -> &[u8] {
+        &self.probabilities
+    }
+
+    fn new(probabilities: Vec<u8>) -> Self {
+        let mut dictionary = ModelDictionary {
+            probabilities,
+            id: 0,
+        };
+        dictionary.id = crc32fast::hash(&dictionary.to_bytes());
+        dictionary
+    }
+}
+
+/// Trains a dictionary on streams that are added one at a time. A dictionary trained on a
+/// single stream contains the probabilities that the adaptive model would learn from it.
+pub struct ModelDictionaryTrainer {
+    encoder: PredictionEncoderCabac<CountingWriter, CountingContext>,
+    streams: usize,
+}
+
+impl Default for ModelDictionaryTrainer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ModelDictionaryTrainer {
+    pub fn new() -> Self {
+        ModelDictionaryTrainer {
+            encoder: PredictionEncoderCabac::new_counting(),
+            streams: 0,
+        }
+    }
+
+    /// Predicts the stream and counts its corrections. A stream that can't be predicted
+    /// is left out of the training and returns the error.
+    pub fn add_stream(&mut self, compressed_data: &[u8]) -> Result<(), PreflateError> {
+        // predict into a recorder first, so that a failing stream doesn't leave half of its
+        // corrections in the counts
+        let mut recorder = VerifyPredictionEncoder::new();
+        read_deflate(compressed_data, &mut recorder, 0)?;
+
+        drive_encoder(&mut self.encoder, &recorder.actions());
+        self.encoder.end_stream();
+        self.streams += 1;
+        Ok(())
+    }
+
+    /// number of streams that were added
+    pub fn streams(&self) -> usize {
+        self.streams
+    }
+
+    pub fn finish(mut self) -> ModelDictionary {
+        ModelDictionary::new(self.encoder.counted_probabilities())
+    }
+}
+
+fn context_count() -> usize {
+    PredictionEncoderCabac::<CountingWriter, CountingContext>::context_count()
+}
+
+#[test]
+fn roundtrip_model_dictionary() {
+    let stream = crate::process::read_file("compressed_zlib_level1.deflate");
+    let dictionary = ModelDictionary::train([&stream[..]]).unwrap();
+
+    let bytes = dictionary.to_bytes();
+    let read = ModelDictionary::from_bytes(&bytes).unwrap();
+    assert_eq!(dictionary, read);
+    assert_eq!(crc32fast::hash(&bytes), read.id());
+
+    // some contexts have been trained
+    assert!(read.probabilities().iter().any(|&p| p != 128));
+
+    assert!(ModelDictionary::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    let mut other_version = bytes.clone();
+    other_version[4] ^= 1;
+    assert!(ModelDictionary::from_bytes(&other_version).is_err());
+}
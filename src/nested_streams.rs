@@ -0,0 +1,888 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Support for deflate streams that are embedded in the plain text of another deflate stream
+//! (for example a zip file that contains gz files or PDFs). The nested streams are expanded
+//! in place, and the corrections of the outer stream are stored together with a tree of the
+//! corrections of the nested streams, which are recompressed inside-out.
+
+use std::{
+    collections::HashMap,
+    io::{Cursor, Read},
+    ops::Range,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::sync_channel,
+    },
+    time::Duration,
+};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use crate::{
+    archive_summary::{ArchiveSummary, EntryOutcome},
+    decompress_with_parameters,
+    deflate_reader::DeflateReader,
+    gzip_header::GzipHeader,
+    match_predictor::ZlibMatchPredictor,
+    preflate_config::PreflateConfig,
+    preflate_error::PreflateError,
+    preflate_parameter_estimator::PreflateParameters,
+    recompress_deflate_stream_with_predictor,
+    stopwatch::Stopwatch,
+    DecompressResult,
+};
+
+/// the kind of header that was used to find an embedded deflate stream
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum EmbeddedStreamKind {
+    /// zlib header (RFC 1950)
+    Zlib,
+    /// gzip member header (RFC 1952)
+    Gzip,
+    /// local file header of a deflated zip entry
+    Zip,
+    /// raw deflate data without a header, found by trial decoding
+    Raw,
+    /// the deflate64 data of a zip entry with compression method 9
+    Deflate64,
+}
+
+/// candidate for the start of a raw deflate stream inside of a larger buffer
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct EmbeddedStream {
+    /// offset of the first byte of the deflate data (after the header)
+    pub offset: usize,
+    pub kind: EmbeddedStreamKind,
+}
+
+/// the first byte of every header we look for: the zip and gzip signatures and the
+/// zlib CMF byte for each of the possible window sizes
+const HEADER_START_BYTES: [u8; 10] = [b'P', 0x1f, 0x78, 0x68, 0x58, 0x48, 0x38, 0x28, 0x18, 0x08];
+
+/// Scans the data for the headers of zlib, gzip and zip streams and returns where the deflate data
+/// would start for each of them, ordered by offset. These are only candidates, the caller
+/// still needs to check whether there actually is a valid deflate stream at that position.
+pub fn find_embedded_streams(data: &[u8]) -> Vec<EmbeddedStream> {
+    // Finding the possible first bytes with the vectorized memchr searches and only parsing
+    // the headers there is much faster than trying every position, which keeps the
+    // scanning of large inputs close to the speed at which they can be read.
+    let mut starts = Vec::new();
+    for needles in HEADER_START_BYTES.chunks(3) {
+        match *needles {
+            [a, b, c] => starts.extend(memchr::memchr3_iter(a, b, c, data)),
+            [a, b] => starts.extend(memchr::memchr2_iter(a, b, data)),
+            [a] => starts.extend(memchr::memchr_iter(a, data)),
+            _ => unreachable!(),
+        }
+    }
+    starts.sort_unstable();
+
+    starts
+        .into_iter()
+        .filter_map(|i| embedded_stream_at(data, i))
+        .collect()
+}
+
+/// a complete deflate stream that scan_for_streams found in the data
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct CatalogEntry {
+    /// offset of the first byte of the deflate data (after the header)
+    pub offset: usize,
+    pub kind: EmbeddedStreamKind,
+    /// length of the deflate data up to the end of the final block
+    pub compressed_len: usize,
+    pub plain_text_len: usize,
+    pub plain_text_crc32: u32,
+}
+
+/// Headers that start near the end of a chunk are parsed with this many bytes of the next chunk,
+/// which is enough for the longest zip local header. Only gzip headers with a longer name or
+/// comment can be missed at a chunk border.
+const CHUNK_OVERLAP: usize = 30 + 2 * 0xffff + 1;
+
+/// Scans a large input (such as a disk image) for embedded deflate streams and returns a catalog
+/// of the ones that decode to the end of their final block, ordered by offset. The input is split
+/// into chunks of chunk_size bytes that are scanned by the given number of threads (0 uses all
+/// the available cores). Streams that start inside of an earlier stream are left out, like
+/// when expanding nested streams. The streams are only decoded, not predicted, so the catalog
+/// can contain streams that don't actually save anything.
+pub fn scan_for_streams(data: &[u8], chunk_size: usize, threads: usize) -> Vec<CatalogEntry> {
+    let chunk_size = chunk_size.max(1);
+    let chunk_count = (data.len() + chunk_size - 1) / chunk_size;
+    let threads = if threads == 0 {
+        std::thread::available_parallelism().map_or(1, |n| n.get())
+    } else {
+        threads
+    }
+    .min(chunk_count.max(1));
+
+    // the number of candidates (and the size of the streams) varies a lot between
+    // chunks, so each thread picks the next chunk instead of getting a fixed share
+    let next = AtomicUsize::new(0);
+
+    let mut found: Vec<CatalogEntry> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut found = Vec::new();
+                    loop {
+                        let chunk = next.fetch_add(1, Ordering::Relaxed);
+                        if chunk >= chunk_count {
+                            break;
+                        }
+
+                        let start = chunk * chunk_size;
+                        let end = (start + chunk_size).min(data.len());
+                        let scanned = &data[start..(end + CHUNK_OVERLAP).min(data.len())];
+
+                        // the stream itself may continue past the chunk, so it is decoded from the whole input
+                        found.extend(
+                            find_embedded_streams(scanned)
+                                .into_iter()
+                                .map(|c| EmbeddedStream {
+                                    offset: start + c.offset,
+                                    kind: c.kind,
+                                })
+                                .filter_map(|c| catalog_entry(data, c)),
+                        );
+                    }
+                    found
+                })
+            })
+            .collect();
+
+        workers
+            .into_iter()
+            .flat_map(|w| w.join().unwrap())
+            .collect()
+    });
+
+    // the headers in the overlap are found by both of the chunks
+    found.sort_by_key(|e| e.offset);
+    found.dedup();
+
+    let mut end = 0;
+    found.retain(|e| {
+        if e.offset < end {
+            return false;
+        }
+        end = e.offset + e.compressed_len;
+        true
+    });
+
+    found
+}
+
+/// a deflate stream that scan_for_deflate_streams found in the data
+pub type DetectedStream = CatalogEntry;
+
+/// Raw deflate data found by trial decoding has to be at least this long, since short runs of
+/// random bytes often happen to decode as a block with the fixed huffman codes.
+const MIN_RAW_STREAM_LEN: usize = 64;
+
+/// the input is scanned for streams with headers in chunks of this size
+const DETECT_CHUNK_SIZE: usize = 16 << 20;
+
+/// Finds the deflate streams in an opaque blob such as an installer, a firmware image or a
+/// game asset, for which there is no dedicated parser. The zlib, gzip and zip streams are found
+/// by their headers like with scan_for_streams, and between them every byte offset is tried as
+/// the start of a raw deflate stream that decodes to the end of its final block. This is much
+/// slower than scan_for_streams, since most of the offsets have to be decoded for a few bytes
+/// before they can be rejected.
+pub fn scan_for_deflate_streams(data: &[u8]) -> Vec<DetectedStream> {
+    let mut found = Vec::new();
+    let mut pos = 0;
+
+    let with_headers = scan_for_streams(data, DETECT_CHUNK_SIZE, 0);
+    for next in with_headers.into_iter().map(Some).chain([None]) {
+        // raw streams can't run into the next stream that has a header
+        let gap_end = next.map_or(data.len(), |e| e.offset);
+        while pos < gap_end {
+            match raw_stream_at(data, pos) {
+                Some(e) if e.offset + e.compressed_len <= gap_end => {
+                    pos = e.offset + e.compressed_len;
+                    found.push(e);
+                }
+                _ => pos += 1,
+            }
+        }
+
+        if let Some(e) = next {
+            pos = e.offset + e.compressed_len;
+            found.push(e);
+        }
+    }
+
+    found
+}
+
+/// tries to decode a raw deflate stream that starts at position i of the data
+fn raw_stream_at(data: &[u8], i: usize) -> Option<CatalogEntry> {
+    // reject the invalid block type and stored blocks with a length that doesn't match its
+    // complement before doing the more expensive decoding
+    match (data[i] >> 1) & 3 {
+        3 => return None,
+        0 => {
+            let len = data.get(i + 1..i + 5)?;
+            if len[0..2] != [!len[2], !len[3]] {
+                return None;
+            }
+        }
+        _ => {}
+    }
+
+    catalog_entry(
+        data,
+        EmbeddedStream {
+            offset: i,
+            kind: EmbeddedStreamKind::Raw,
+        },
+    )
+    .filter(|e| e.compressed_len >= MIN_RAW_STREAM_LEN)
+}
+
+/// decodes the candidate to the end of its final block
+fn catalog_entry(data: &[u8], candidate: EmbeddedStream) -> Option<CatalogEntry> {
+    let mut reader = DeflateReader::new(Cursor::new(&data[candidate.offset..]));
+
+    let mut last = false;
+    while !last {
+        reader.read_block(&mut last).ok()?;
+    }
+
+    let plain_text = reader.plain_text();
+    if plain_text.is_empty() {
+        return None;
+    }
+
+    Some(CatalogEntry {
+        offset: candidate.offset,
+        kind: candidate.kind,
+        compressed_len: ((reader.bit_position() + 7) / 8) as usize,
+        plain_text_len: plain_text.len(),
+        plain_text_crc32: crc32fast::hash(plain_text),
+    })
+}
+
+/// checks whether there is a header at position i of the data
+fn embedded_stream_at(data: &[u8], i: usize) -> Option<EmbeddedStream> {
+    let header = &data[i..];
+
+    if let Some(offset) = parse_zip_local_header(header) {
+        Some(EmbeddedStream {
+            offset: i + offset,
+            kind: EmbeddedStreamKind::Zip,
+        })
+    } else if let Some(offset) = parse_gzip_header(header) {
+        Some(EmbeddedStream {
+            offset: i + offset,
+            kind: EmbeddedStreamKind::Gzip,
+        })
+    } else if is_zlib_header(header) {
+        Some(EmbeddedStream {
+            offset: i + 2,
+            kind: EmbeddedStreamKind::Zlib,
+        })
+    } else {
+        None
+    }
+}
+
+/// deflate method with a window of at most 32k, no preset dictionary and a valid check value
+pub(crate) fn is_zlib_header(header: &[u8]) -> bool {
+    if header.len() < 3 {
+        return false;
+    }
+
+    let cmf = header[0];
+    let flg = header[1];
+
+    cmf & 0x0f == 8
+        && cmf >> 4 <= 7
+        && flg & 0x20 == 0
+        && (u16::from(cmf) * 256 + u16::from(flg)) % 31 == 0
+}
+
+/// returns the length of the gzip header if there is one at the start of the data. The header
+/// itself stays in the plain text of the parent, so all its fields are preserved as they are.
+fn parse_gzip_header(header: &[u8]) -> Option<usize> {
+    let (_, offset) = GzipHeader::parse(header)?;
+    (offset < header.len()).then_some(offset)
+}
+
+/// returns the offset of the data if there is a local file header of a deflated entry at the start
+fn parse_zip_local_header(header: &[u8]) -> Option<usize> {
+    const METHOD_DEFLATE: u16 = 8;
+
+    if header.len() < 30 || header[0..4] != *b"PK\x03\x04" {
+        return None;
+    }
+
+    let method = u16::from_le_bytes([header[8], header[9]]);
+    if method != METHOD_DEFLATE {
+        return None;
+    }
+
+    let name_len = usize::from(u16::from_le_bytes([header[26], header[27]]));
+    let extra_len = usize::from(u16::from_le_bytes([header[28], header[29]]));
+    let offset = 30 + name_len + extra_len;
+
+    (offset < header.len()).then_some(offset)
+}
+
+/// a nested stream that was expanded inside of the plain text of its parent
+pub(crate) struct NestedStream {
+    /// offset of the expanded plain text inside the (expanded) plain text of the parent
+    offset: usize,
+    /// length of the expanded plain text of the nested stream
+    length: usize,
+    /// corrections of the nested stream, which again contain its own nested streams
+    corrections: Vec<u8>,
+    /// The gzip trailer (crc32 and length of the plain text) that followed the stream was removed
+    /// from the plain text of the parent, since it can be recreated from the plain text.
+    gzip_trailer: bool,
+}
+
+/// size of the crc32 and the length of the plain text at the end of a gzip member
+pub(crate) const GZIP_TRAILER_SIZE: usize = 8;
+
+/// the trailer that a gzip member with this plain text ends with (RFC 1952)
+pub(crate) fn gzip_trailer(plain_text: &[u8]) -> [u8; GZIP_TRAILER_SIZE] {
+    let mut trailer = [0; GZIP_TRAILER_SIZE];
+    trailer[0..4].copy_from_slice(&crc32fast::hash(plain_text).to_le_bytes());
+    trailer[4..8].copy_from_slice(&(plain_text.len() as u32).to_le_bytes());
+    trailer
+}
+
+/// Decompresses a deflate stream and then expands the deflate streams that are
+/// embedded in its plain text, up to `depth` levels deep. The returned plain text contains
+/// the plain text of the nested streams instead of their compressed data, and the corrections
+/// contain the corrections of all the streams. The statistics are those of the outer stream.
+/// Also returns the gzip trailer of the plain text before the nested streams were expanded.
+pub(crate) fn decompress_nested(
+    compressed_data: &[u8],
+    config: &PreflateConfig,
+    depth: u32,
+) -> Result<
+    (
+        DecompressResult,
+        PreflateParameters,
+        [u8; GZIP_TRAILER_SIZE],
+    ),
+    PreflateError,
+> {
+    let (outer, params) = decompress_with_parameters(compressed_data, config)?;
+    let trailer = gzip_trailer(&outer.plain_text);
+
+    let (plain_text, nested) = if depth > 0 {
+        // the streams inside of a deflate64 stream are regular deflate streams
+        let inner_config;
+        let config = if config.deflate64 {
+            inner_config = PreflateConfig {
+                deflate64: false,
+                ..config.clone()
+            };
+            &inner_config
+        } else {
+            config
+        };
+
+        let candidates = find_embedded_streams(&outer.plain_text)
+            .into_iter()
+            .map(|c| (c.offset..outer.plain_text.len(), Some(c.kind)));
+
+        // most of the candidates are just random bytes that look like a header,
+        // so there is no point in counting them. Some of them even decode as a short
+        // deflate stream, which is why only streams that save space are expanded.
+        expand_streams(
+            &outer.plain_text,
+            candidates,
+            config,
+            depth - 1,
+            true,
+            1,
+            &mut ArchiveSummary::default(),
+        )
+    } else {
+        (outer.plain_text, Vec::new())
+    };
+
+    let mut cabac_encoded = Vec::new();
+    cabac_encoded.extend_from_slice(&(outer.cabac_encoded.len() as u32).to_le_bytes());
+    cabac_encoded.extend_from_slice(&outer.cabac_encoded);
+    write_streams(&mut cabac_encoded, &nested);
+
+    Ok((
+        DecompressResult {
+            // the nested streams have been replaced in the plain text of the outer stream
+            plain_text_crc32: crc32fast::hash(&plain_text),
+            plain_text,
+            cabac_encoded,
+            compressed_processed: outer.compressed_processed,
+            statistics: outer.statistics,
+            profile: outer.profile,
+            parameters: outer.parameters,
+        },
+        params,
+        trailer,
+    ))
+}
+
+/// Recreates a stream that was decompressed with decompress_nested. The nested streams are
+/// recompressed first, so that the original plain text of the outer stream is available.
+pub(crate) fn recompress_nested(
+    plain_text: &[u8],
+    corrections: &[u8],
+    config: &PreflateConfig,
+) -> Result<Vec<u8>, PreflateError> {
+    Ok(recompress_nested_with_plain_text(plain_text, corrections, config)?.0)
+}
+
+/// same as recompress_nested, but also returns the plain text of the stream with the
+/// nested streams restored, which is needed to recreate the gzip trailer
+fn recompress_nested_with_plain_text(
+    plain_text: &[u8],
+    corrections: &[u8],
+    config: &PreflateConfig,
+) -> Result<(Vec<u8>, Vec<u8>), PreflateError> {
+    let mut reader = Cursor::new(corrections);
+    let (outer_corrections, nested) = read_blob(&mut reader)
+        .and_then(|outer| Ok((outer, read_streams(&mut reader)?)))
+        .map_err(|e| {
+            PreflateError::RecompressFailed(anyhow::anyhow!("invalid nested corrections: {}", e))
+        })?;
+
+    let original = restore_streams(plain_text, &nested, config)?;
+
+    let recompressed = recompress_deflate_stream_with_predictor(
+        &original,
+        &outer_corrections,
+        config,
+        &ZlibMatchPredictor::default(),
+    )?;
+    Ok((recompressed, original))
+}
+
+/// result of decompress_nested for one of the ranges of expand_streams
+type RangeResult = Result<
+    (
+        DecompressResult,
+        PreflateParameters,
+        [u8; GZIP_TRAILER_SIZE],
+    ),
+    PreflateError,
+>;
+
+/// Tries to decompress a deflate stream (including the streams nested in it up to `depth` levels)
+/// in each of the ranges of the data, which have to be ordered by their start. The data
+/// of each stream that could be processed is replaced by its plain text, ranges that start
+/// inside of an earlier stream are ignored. If `require_gain` is set, streams whose corrections
+/// are larger than their compressed data are skipped. The outcome of each range is recorded in the summary.
+/// The ranges come with the kind of header in front of them if it is known, and the trailer
+/// of gzip members is dropped if it matches the plain text, since it can be recreated from it.
+/// With more than one thread the ranges are decompressed by worker threads and added in order
+/// as they come back, so the result is the same as with one thread (0 uses all the available cores).
+pub(crate) fn expand_streams(
+    data: &[u8],
+    ranges: impl IntoIterator<Item = (Range<usize>, Option<EmbeddedStreamKind>)>,
+    config: &PreflateConfig,
+    depth: u32,
+    require_gain: bool,
+    threads: usize,
+    summary: &mut ArchiveSummary,
+) -> (Vec<u8>, Vec<NestedStream>) {
+    let ranges: Vec<_> = ranges.into_iter().collect();
+    let threads = if threads == 0 {
+        std::thread::available_parallelism().map_or(1, |n| n.get())
+    } else {
+        threads
+    }
+    .min(ranges.len());
+
+    let mut expanded = ExpandedStreams {
+        data,
+        plain_text: Vec::with_capacity(data.len()),
+        nested: Vec::new(),
+        pos: 0,
+        require_gain,
+        summary,
+    };
+
+    if threads <= 1 {
+        for (range, kind) in ranges {
+            if range.start < expanded.pos {
+                // inside of a stream that we already expanded
+                continue;
+            }
+
+            let start = Stopwatch::start();
+            let result = decompress_range(data, &range, kind, config, depth);
+            expanded.add(range, kind, result, start.elapsed());
+        }
+    } else {
+        // The workers take the next range, and the results are sent back through a channel
+        // with room for one result per thread, so the workers wait if they get too far ahead.
+        // A range that turns out to be inside of an earlier stream was decompressed for
+        // nothing, but that doesn't happen with the entries of an archive.
+        let next = AtomicUsize::new(0);
+        let (sender, receiver) = sync_channel(threads);
+
+        std::thread::scope(|scope| {
+            for _ in 0..threads {
+                let (ranges, next, sender) = (&ranges, &next, sender.clone());
+                scope.spawn(move || loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let Some((range, kind)) = ranges.get(index) else {
+                        break;
+                    };
+
+                    let start = Stopwatch::start();
+                    let result = decompress_range(data, range, *kind, config, depth);
+                    if sender.send((index, result, start.elapsed())).is_err() {
+                        break;
+                    }
+                });
+            }
+            drop(sender);
+
+            let mut done = HashMap::new();
+            let mut index = 0;
+            for (i, result, elapsed) in receiver {
+                done.insert(i, (result, elapsed));
+                while let Some((result, elapsed)) = done.remove(&index) {
+                    let (range, kind) = ranges[index].clone();
+                    if range.start >= expanded.pos {
+                        expanded.add(range, kind, result, elapsed);
+                    }
+                    index += 1;
+                }
+            }
+        });
+    }
+
+    expanded.plain_text.extend_from_slice(&data[expanded.pos..]);
+    (expanded.plain_text, expanded.nested)
+}
+
+/// decompresses the stream in the range, with deflate64 enabled if the kind asks for it
+fn decompress_range(
+    data: &[u8],
+    range: &Range<usize>,
+    kind: Option<EmbeddedStreamKind>,
+    config: &PreflateConfig,
+    depth: u32,
+) -> RangeResult {
+    // The stream may not read past the end of its range, which is the size that the container
+    // declared for it (or the rest of the data for the streams that were found by scanning),
+    // so a stream that overruns it is corrupt rather than truncated.
+    let range_config = PreflateConfig {
+        deflate64: config.deflate64 || kind == Some(EmbeddedStreamKind::Deflate64),
+        compressed_size: Some(range.len() as u64),
+        ..config.clone()
+    };
+    decompress_nested(&data[range.clone()], &range_config, depth)
+}
+
+/// the output of expand_streams while the ranges are added in order
+struct ExpandedStreams<'a> {
+    data: &'a [u8],
+    plain_text: Vec<u8>,
+    nested: Vec<NestedStream>,
+    /// end of the data that was already added to the plain text
+    pos: usize,
+    require_gain: bool,
+    summary: &'a mut ArchiveSummary,
+}
+
+impl ExpandedStreams<'_> {
+    fn add(
+        &mut self,
+        range: Range<usize>,
+        kind: Option<EmbeddedStreamKind>,
+        result: RangeResult,
+        elapsed: Duration,
+    ) {
+        let (inner, params, trailer) = match result {
+            Ok((r, params, trailer))
+                if !r.plain_text.is_empty()
+                    && (!self.require_gain || r.cabac_encoded.len() < r.compressed_processed) =>
+            {
+                (r, params, trailer)
+            }
+            Ok(_) => {
+                self.summary.record_failed(EntryOutcome::Skipped, elapsed);
+                return;
+            }
+            Err(e) => {
+                self.summary
+                    .record_failed(EntryOutcome::from_error(&e), elapsed);
+                return;
+            }
+        };
+
+        self.summary.record_processed(
+            inner.compressed_processed,
+            inner.plain_text.len(),
+            inner.cabac_encoded.len(),
+            &params.encoder_label(),
+            elapsed,
+        );
+
+        // a trailer that doesn't match means that the member is damaged, so it has to be kept
+        let end = range.start + inner.compressed_processed;
+        let has_gzip_trailer = kind == Some(EmbeddedStreamKind::Gzip)
+            && self.data.get(end..end + GZIP_TRAILER_SIZE) == Some(&trailer[..]);
+
+        self.plain_text
+            .extend_from_slice(&self.data[self.pos..range.start]);
+        self.nested.push(NestedStream {
+            offset: self.plain_text.len(),
+            length: inner.plain_text.len(),
+            corrections: inner.cabac_encoded,
+            gzip_trailer: has_gzip_trailer,
+        });
+        self.plain_text.extend_from_slice(&inner.plain_text);
+
+        self.pos = if has_gzip_trailer {
+            end + GZIP_TRAILER_SIZE
+        } else {
+            end
+        };
+    }
+}
+
+/// inverse of expand_streams, recompresses each of the nested streams and puts it back in
+/// place of its plain text
+pub(crate) fn restore_streams(
+    plain_text: &[u8],
+    nested: &[NestedStream],
+    config: &PreflateConfig,
+) -> Result<Vec<u8>, PreflateError> {
+    let mut original = Vec::with_capacity(plain_text.len());
+    let mut pos = 0;
+    for n in nested {
+        let inner = plain_text
+            .get(n.offset..n.offset + n.length)
+            .filter(|_| n.offset >= pos);
+        let Some(inner) = inner else {
+            return Err(PreflateError::RecompressFailed(anyhow::anyhow!(
+                "nested stream at {} is outside of the plain text",
+                n.offset
+            )));
+        };
+
+        original.extend_from_slice(&plain_text[pos..n.offset]);
+        if n.gzip_trailer {
+            let (recompressed, inner_plain_text) =
+                recompress_nested_with_plain_text(inner, &n.corrections, config)?;
+            original.extend_from_slice(&recompressed);
+            original.extend_from_slice(&gzip_trailer(&inner_plain_text));
+        } else {
+            original.extend_from_slice(&recompress_nested(inner, &n.corrections, config)?);
+        }
+        pos = n.offset + n.length;
+    }
+    original.extend_from_slice(&plain_text[pos..]);
+
+    Ok(original)
+}
+
+/// layout: the number of nested streams and for each of them the offset, length and
+/// length of the corrections followed by the corrections themselves (all numbers are little endian u32)
+/// and a byte that is 1 if the gzip trailer after the stream has to be recreated.
+/// The corrections of a nested stream start with the length and corrections of the stream
+/// itself, followed by its own nested streams in the same layout.
+pub(crate) fn write_streams(result: &mut Vec<u8>, nested: &[NestedStream]) {
+    result.extend_from_slice(&(nested.len() as u32).to_le_bytes());
+    for n in nested {
+        result.extend_from_slice(&(n.offset as u32).to_le_bytes());
+        result.extend_from_slice(&(n.length as u32).to_le_bytes());
+        result.extend_from_slice(&(n.corrections.len() as u32).to_le_bytes());
+        result.extend_from_slice(&n.corrections);
+        result.push(u8::from(n.gzip_trailer));
+    }
+}
+
+pub(crate) fn read_streams(reader: &mut Cursor<&[u8]>) -> std::io::Result<Vec<NestedStream>> {
+    let count = reader.read_u32::<LittleEndian>()?;
+    let mut nested = Vec::new();
+    for _ in 0..count {
+        let offset = reader.read_u32::<LittleEndian>()? as usize;
+        let length = reader.read_u32::<LittleEndian>()? as usize;
+        let corrections = read_blob(reader)?;
+        let gzip_trailer = match reader.read_u8()? {
+            0 => false,
+            1 => true,
+            _ => return Err(std::io::ErrorKind::InvalidData.into()),
+        };
+        nested.push(NestedStream {
+            offset,
+            length,
+            corrections,
+            gzip_trailer,
+        });
+    }
+
+    Ok(nested)
+}
+
+fn read_blob(reader: &mut Cursor<&[u8]>) -> std::io::Result<Vec<u8>> {
+    let len = reader.read_u32::<LittleEndian>()? as usize;
+    let mut blob = Vec::new();
+    reader.take(len as u64).read_to_end(&mut blob)?;
+    if blob.len() != len {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(blob)
+}
+
+#[test]
+fn finds_embedded_headers() {
+    let mut data = b"junk".to_vec();
+    data.extend_from_slice(&[0x78, 0x9c, 0x03]);
+    data.extend_from_slice(&[0x1f, 0x8b, 8, 0x08, 0, 0, 0, 0, 0, 3]);
+    data.extend_from_slice(b"name\0");
+    data.push(0x03);
+
+    let found = find_embedded_streams(&data);
+    assert_eq!(
+        found,
+        [
+            EmbeddedStream {
+                offset: 6,
+                kind: EmbeddedStreamKind::Zlib
+            },
+            EmbeddedStream {
+                offset: 22,
+                kind: EmbeddedStreamKind::Gzip
+            }
+        ]
+    );
+}
+
+#[test]
+fn scanner_matches_exhaustive_search() {
+    // pseudo random data with some real headers mixed in
+    let mut seed = 0x9e3779b9u32;
+    let mut data: Vec<u8> = (0..200000)
+        .map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as u8
+        })
+        .collect();
+    for i in (0..data.len() - 64).step_by(997) {
+        match i % 3 {
+            0 => data[i..i + 2].copy_from_slice(&[0x58, 0x85]),
+            1 => data[i..i + 4].copy_from_slice(&[0x1f, 0x8b, 8, 0]),
+            _ => data[i..i + 10].copy_from_slice(b"PK\x03\x04\x14\x00\x00\x00\x08\x00"),
+        }
+    }
+
+    let expected: Vec<EmbeddedStream> = (0..data.len())
+        .filter_map(|i| embedded_stream_at(&data, i))
+        .collect();
+
+    assert!(expected.len() > 200);
+    assert_eq!(find_embedded_streams(&data), expected);
+}
+
+#[test]
+fn scan_for_streams_across_chunks() {
+    use flate2::{write::GzEncoder, write::ZlibEncoder, Compression};
+    use std::io::Write;
+
+    let plain_text: Vec<u8> = (0..20000u32).map(|i| (i % 251 * i / 97) as u8).collect();
+
+    let mut zlib = ZlibEncoder::new(Vec::new(), Compression::new(6));
+    zlib.write_all(&plain_text).unwrap();
+    let zlib = zlib.finish().unwrap();
+
+    let mut gzip = GzEncoder::new(Vec::new(), Compression::new(9));
+    gzip.write_all(&plain_text[..5000]).unwrap();
+    let gzip = gzip.finish().unwrap();
+
+    let mut data = vec![0u8; 1000];
+    data.extend_from_slice(&zlib);
+    data.extend_from_slice(&[0u8; 3000]);
+    data.extend_from_slice(&gzip);
+    data.extend_from_slice(&[0u8; 500]);
+
+    let expected = [
+        CatalogEntry {
+            offset: 1002,
+            kind: EmbeddedStreamKind::Zlib,
+            compressed_len: zlib.len() - 6,
+            plain_text_len: plain_text.len(),
+            plain_text_crc32: crc32fast::hash(&plain_text),
+        },
+        CatalogEntry {
+            offset: 1000 + zlib.len() + 3000 + 10,
+            kind: EmbeddedStreamKind::Gzip,
+            compressed_len: gzip.len() - 18,
+            plain_text_len: 5000,
+            plain_text_crc32: crc32fast::hash(&plain_text[..5000]),
+        },
+    ];
+
+    // chunk sizes that put the chunk borders inside of the headers and the streams
+    for (chunk_size, threads) in [(data.len(), 1), (1001, 3), (997, 0), (64, 4)] {
+        assert_eq!(
+            scan_for_streams(&data, chunk_size, threads),
+            expected,
+            "chunk size {}",
+            chunk_size
+        );
+    }
+}
+
+#[test]
+fn detects_raw_deflate_streams() {
+    use flate2::{write::DeflateEncoder, write::ZlibEncoder, Compression};
+    use std::io::Write;
+
+    let plain_text = crate::process::read_file("sample1.bin");
+
+    let mut raw = DeflateEncoder::new(Vec::new(), Compression::new(9));
+    raw.write_all(&plain_text).unwrap();
+    let raw = raw.finish().unwrap();
+
+    let mut zlib = ZlibEncoder::new(Vec::new(), Compression::new(1));
+    zlib.write_all(&plain_text[..10000]).unwrap();
+    let zlib = zlib.finish().unwrap();
+
+    // pseudo random data around the streams
+    let mut seed = 0x2545f491u32;
+    let mut random = |len: usize| -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect()
+    };
+
+    let mut data = random(5000);
+    let raw_offset = data.len();
+    data.extend_from_slice(&raw);
+    data.extend_from_slice(&random(3000));
+    let zlib_offset = data.len() + 2;
+    data.extend_from_slice(&zlib);
+    data.extend_from_slice(&random(2000));
+
+    let found = scan_for_deflate_streams(&data);
+    assert_eq!(
+        found
+            .iter()
+            .map(|e| (e.offset, e.kind, e.plain_text_len))
+            .collect::<Vec<_>>(),
+        [
+            (raw_offset, EmbeddedStreamKind::Raw, plain_text.len()),
+            (zlib_offset, EmbeddedStreamKind::Zlib, 10000)
+        ]
+    );
+    assert_eq!(found[0].compressed_len, raw.len());
+}
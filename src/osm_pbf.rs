@@ -0,0 +1,234 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! OpenStreetMap PBF files are a sequence of blobs, each one framed by the big endian length
+//! of a BlobHeader protobuf message, the BlobHeader itself and then the Blob message. The
+//! payload of a Blob is usually stored in its zlib_data field, which is what gets expanded here.
+
+use std::ops::Range;
+
+use crate::{
+    container::{expand_file, ExpandedFile, StreamExtent},
+    nested_streams::is_zlib_header,
+    preflate_config::PreflateConfig,
+    preflate_error::PreflateError,
+};
+
+/// BlobHeaders larger than this are invalid according to the format specification
+const MAX_BLOB_HEADER_SIZE: usize = 64 * 1024;
+
+/// a blob of a PBF file
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PbfBlob {
+    /// offset in the file of the length that precedes the BlobHeader
+    pub offset: usize,
+    /// the type from the BlobHeader, usually OSMHeader or OSMData
+    pub blob_type: String,
+    /// where the zlib_data of the Blob is in the file, if the blob is zlib compressed
+    pub zlib_data: Option<Range<usize>>,
+}
+
+/// iterates over the blobs of a PBF file
+pub struct PbfBlobIterator<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> PbfBlobIterator<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        PbfBlobIterator { data, pos: 0 }
+    }
+
+    fn read_blob(&mut self) -> Result<PbfBlob, PreflateError> {
+        let offset = self.pos;
+
+        let header_len = u32::from_be_bytes(
+            self.data
+                .get(offset..offset + 4)
+                .ok_or_else(|| invalid_data("truncated blob header length"))?
+                .try_into()
+                .unwrap(),
+        ) as usize;
+        if header_len > MAX_BLOB_HEADER_SIZE {
+            return Err(invalid_data("blob header is too large"));
+        }
+
+        let header_start = offset + 4;
+        let header = self
+            .data
+            .get(header_start..header_start + header_len)
+            .ok_or_else(|| invalid_data("truncated blob header"))?;
+
+        let mut blob_type = String::new();
+        let mut data_size = None;
+        for field in ProtobufFields::new(header) {
+            match field? {
+                (1, FieldValue::Bytes(r)) => {
+                    blob_type = String::from_utf8_lossy(&header[r]).into_owned()
+                }
+                (3, FieldValue::Varint(v)) => data_size = Some(v as usize),
+                _ => {}
+            }
+        }
+        let data_size = data_size.ok_or_else(|| invalid_data("blob header without datasize"))?;
+
+        let blob_start = header_start + header_len;
+        let blob = self
+            .data
+            .get(blob_start..blob_start + data_size)
+            .ok_or_else(|| invalid_data("truncated blob"))?;
+
+        let mut zlib_data = None;
+        for field in ProtobufFields::new(blob) {
+            if let (3, FieldValue::Bytes(r)) = field? {
+                zlib_data = Some(blob_start + r.start..blob_start + r.end);
+            }
+        }
+
+        self.pos = blob_start + data_size;
+
+        Ok(PbfBlob {
+            offset,
+            blob_type,
+            zlib_data,
+        })
+    }
+}
+
+impl Iterator for PbfBlobIterator<'_> {
+    type Item = Result<PbfBlob, PreflateError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.data.len() {
+            return None;
+        }
+
+        let r = self.read_blob();
+        if r.is_err() {
+            // don't try to continue after a framing error
+            self.pos = self.data.len();
+        }
+        Some(r)
+    }
+}
+
+/// returns the extents of the deflate data of all the zlib compressed blobs of the file
+pub fn pbf_zlib_extents(data: &[u8]) -> Result<Vec<StreamExtent>, PreflateError> {
+    let mut extents = Vec::new();
+    for blob in PbfBlobIterator::new(data) {
+        if let Some(r) = blob?.zlib_data {
+            // zlib header and adler32 trailer around the deflate data
+            if r.len() > 6 && is_zlib_header(&data[r.clone()]) {
+                extents.push(StreamExtent {
+                    offset: r.start + 2,
+                    length: r.len() - 6,
+                });
+            }
+        }
+    }
+    Ok(extents)
+}
+
+/// Expands all the zlib compressed blobs of a PBF file. The file can be recreated
+/// byte for byte with container::recreate_file, since the framing is kept as it is.
+pub fn expand_pbf(data: &[u8], config: &PreflateConfig) -> Result<ExpandedFile, PreflateError> {
+    Ok(expand_file(data, pbf_zlib_extents(data)?, config))
+}
+
+fn invalid_data(message: &str) -> PreflateError {
+    PreflateError::Io(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("invalid PBF file: {}", message),
+    ))
+}
+
+enum FieldValue {
+    Varint(u64),
+    /// range of the contents of a length delimited field inside of the message
+    Bytes(Range<usize>),
+    Fixed,
+}
+
+/// minimal reader for the fields of a protobuf message
+struct ProtobufFields<'a> {
+    message: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ProtobufFields<'a> {
+    fn new(message: &'a [u8]) -> Self {
+        ProtobufFields { message, pos: 0 }
+    }
+
+    fn read_varint(&mut self) -> Result<u64, PreflateError> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = *self
+                .message
+                .get(self.pos)
+                .ok_or_else(|| invalid_data("truncated varint"))?;
+            self.pos += 1;
+
+            value |= u64::from(b & 0x7f) << shift;
+            if b & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(invalid_data("varint is too long"))
+    }
+
+    fn read_field(&mut self) -> Result<(u32, FieldValue), PreflateError> {
+        let key = self.read_varint()?;
+        let value = match key & 7 {
+            0 => FieldValue::Varint(self.read_varint()?),
+            1 | 5 => {
+                self.pos += if key & 7 == 1 { 8 } else { 4 };
+                FieldValue::Fixed
+            }
+            2 => {
+                let len = self.read_varint()? as usize;
+                let r = self.pos..self.pos.saturating_add(len);
+                self.pos = r.end;
+                FieldValue::Bytes(r)
+            }
+            _ => return Err(invalid_data("unsupported protobuf wire type")),
+        };
+
+        if self.pos > self.message.len() {
+            return Err(invalid_data("truncated protobuf field"));
+        }
+
+        Ok(((key >> 3) as u32, value))
+    }
+}
+
+impl Iterator for ProtobufFields<'_> {
+    type Item = Result<(u32, FieldValue), PreflateError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.message.len() {
+            return None;
+        }
+
+        let r = self.read_field();
+        if r.is_err() {
+            self.pos = self.message.len();
+        }
+        Some(r)
+    }
+}
+
+#[test]
+fn rejects_truncated_pbf() {
+    // BlobHeader with type "OSMData" and a datasize larger than the rest of the file
+    let mut data = vec![0, 0, 0, 11, 0x0a, 7];
+    data.extend_from_slice(b"OSMData");
+    data.extend_from_slice(&[0x18, 100, 0x1a, 2, 0x78]);
+
+    let blobs: Vec<_> = PbfBlobIterator::new(&data).collect();
+    assert_eq!(blobs.len(), 1);
+    assert!(blobs[0].is_err());
+}
@@ -0,0 +1,564 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! A self-contained container for a whole file with its deflate streams expanded, so that the
+//! crate can be used as the recompression layer of a backup tool without it having to keep
+//! track of where the streams were. `pack` finds the streams in the file, and `unpack` recreates
+//! the file byte for byte from the container.
+//!
+//! All numbers are little endian. The container consists of:
+//!
+//! - header (16 bytes): the magic `PFLC`, the format version (1), 3 reserved bytes that are 0
+//!   and the length of the original file as u64
+//! - the sections, which cover the original file in order. Each starts with its kind:
+//!   - raw (0): length as u64, followed by the bytes as they are
+//!   - stream (1): length of the deflate stream, length of the plain text (both u64),
+//!     crc32 of the plain text, length of the parameters as u16 followed by the parameters
+//!     (the compressor profile line of the test vectors, for information only), length of the
+//!     corrections as u64, the plain text and the corrections
+//!   - fallback raw (2): a deflate stream that couldn't be expanded (or didn't get smaller),
+//!     which is kept as it is: the error code as u32 (0 if there was no error), length as u64
+//!     and the bytes
+//! - index: number of sections as u32, and for each section its kind as u8, offset and length
+//!   in the original file and offset of the section in the container (all u64)
+//! - trailer (16 bytes): offset of the index as u64, crc32 of the original file and the magic `PFLI`
+//!
+//! The corrections are written with the codec of the config, so the same codec has to be used
+//! for unpacking. Readers reject containers with a newer format version.
+//!
+//! PreflateReader and PreflateWriter do the same through io::Read and io::Write, so that a file
+//! can be piped through them with io::copy. Neither of them streams: the streams of a file can
+//! only be found and the container only be unpacked once all of it is known, so both keep the
+//! whole file and the whole container in memory.
+
+use std::io::{Cursor, Read, Write};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use crate::{
+    archive_summary::{ArchiveSummary, EntryOutcome},
+    decompress_deflate_stream_with_config,
+    nested_streams::scan_for_streams,
+    preflate_config::PreflateConfig,
+    preflate_error::PreflateError,
+    recompress_deflate_stream_with_config,
+    stopwatch::Stopwatch,
+    test_vector::describe_profile,
+};
+
+/// the version of the container format that is written
+pub const FORMAT_VERSION: u8 = 1;
+
+const MAGIC: &[u8; 4] = b"PFLC";
+const TRAILER_MAGIC: &[u8; 4] = b"PFLI";
+const HEADER_SIZE: usize = 16;
+const TRAILER_SIZE: usize = 16;
+const INDEX_ENTRY_SIZE: usize = 25;
+
+/// the file is scanned for streams in chunks of this size
+const SCAN_CHUNK_SIZE: usize = 16 << 20;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SectionKind {
+    /// bytes of the file that aren't part of a deflate stream
+    Raw,
+    /// a deflate stream that was expanded
+    Stream,
+    /// a deflate stream that is kept as it is
+    Fallback,
+}
+
+impl SectionKind {
+    fn to_byte(self) -> u8 {
+        match self {
+            SectionKind::Raw => 0,
+            SectionKind::Stream => 1,
+            SectionKind::Fallback => 2,
+        }
+    }
+
+    fn from_byte(b: u8) -> Option<Self> {
+        match b {
+            0 => Some(SectionKind::Raw),
+            1 => Some(SectionKind::Stream),
+            2 => Some(SectionKind::Fallback),
+            _ => None,
+        }
+    }
+}
+
+/// an entry of the index of a container
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct SectionInfo {
+    pub kind: SectionKind,
+    /// offset of the section in the original file
+    pub original_offset: u64,
+    /// number of bytes of the original file that the section recreates
+    pub original_len: u64,
+    /// offset of the section in the container
+    pub container_offset: u64,
+}
+
+/// result of pack
+pub struct PackedFile {
+    pub container: Vec<u8>,
+    /// what happened to each of the deflate streams that were found
+    pub summary: ArchiveSummary,
+}
+
+/// Finds the deflate streams in the file and writes the container with each of them expanded.
+/// Streams that fail or don't get smaller are kept as fallback raw sections, so this
+/// can't fail. The nested streams are expanded as well if the config asks for it.
+pub fn pack(data: &[u8], config: &PreflateConfig) -> PackedFile {
+    let mut summary = ArchiveSummary::default();
+
+    let mut container = Vec::new();
+    container.extend_from_slice(MAGIC);
+    container.push(FORMAT_VERSION);
+    container.extend_from_slice(&[0; 3]);
+    container.extend_from_slice(&(data.len() as u64).to_le_bytes());
+
+    let mut index = Vec::new();
+    let mut add_section = |container: &mut Vec<u8>, kind, start: usize, end: usize| {
+        index.push(SectionInfo {
+            kind,
+            original_offset: start as u64,
+            original_len: (end - start) as u64,
+            container_offset: container.len() as u64,
+        });
+        container.push(SectionKind::to_byte(kind));
+    };
+
+    let mut pos = 0;
+    for entry in scan_for_streams(data, SCAN_CHUNK_SIZE, 0) {
+        if entry.offset > pos {
+            add_section(&mut container, SectionKind::Raw, pos, entry.offset);
+            write_blob(&mut container, &data[pos..entry.offset]);
+        }
+
+        let compressed = &data[entry.offset..entry.offset + entry.compressed_len];
+        let start = Stopwatch::start();
+
+        let error_code = match decompress_deflate_stream_with_config(compressed, config) {
+            Ok(r) if r.cabac_encoded.len() < r.compressed_processed => {
+                summary.record_processed(
+                    r.compressed_processed,
+                    r.plain_text.len(),
+                    r.cabac_encoded.len(),
+                    &r.profile.name,
+                    start.elapsed(),
+                );
+
+                pos = entry.offset + r.compressed_processed;
+                add_section(&mut container, SectionKind::Stream, entry.offset, pos);
+
+                let parameters = describe_profile(&r.profile);
+                container.extend_from_slice(&(r.compressed_processed as u64).to_le_bytes());
+                container.extend_from_slice(&(r.plain_text.len() as u64).to_le_bytes());
+                container.extend_from_slice(&r.plain_text_crc32.to_le_bytes());
+                container.extend_from_slice(&(parameters.len() as u16).to_le_bytes());
+                container.extend_from_slice(parameters.as_bytes());
+                container.extend_from_slice(&(r.cabac_encoded.len() as u64).to_le_bytes());
+                container.extend_from_slice(&r.plain_text);
+                container.extend_from_slice(&r.cabac_encoded);
+                continue;
+            }
+            Ok(_) => {
+                summary.record_failed(EntryOutcome::Skipped, start.elapsed());
+                0
+            }
+            Err(e) => {
+                summary.record_failed(EntryOutcome::from_error(&e), start.elapsed());
+                e.to_code()
+            }
+        };
+
+        pos = entry.offset + entry.compressed_len;
+        add_section(&mut container, SectionKind::Fallback, entry.offset, pos);
+        container.extend_from_slice(&error_code.to_le_bytes());
+        write_blob(&mut container, compressed);
+    }
+
+    if pos < data.len() {
+        add_section(&mut container, SectionKind::Raw, pos, data.len());
+        write_blob(&mut container, &data[pos..]);
+    }
+
+    let index_offset = container.len() as u64;
+    container.extend_from_slice(&(index.len() as u32).to_le_bytes());
+    for section in &index {
+        container.push(section.kind.to_byte());
+        container.extend_from_slice(&section.original_offset.to_le_bytes());
+        container.extend_from_slice(&section.original_len.to_le_bytes());
+        container.extend_from_slice(&section.container_offset.to_le_bytes());
+    }
+
+    container.extend_from_slice(&index_offset.to_le_bytes());
+    container.extend_from_slice(&crc32fast::hash(data).to_le_bytes());
+    container.extend_from_slice(TRAILER_MAGIC);
+
+    PackedFile { container, summary }
+}
+
+fn write_blob(container: &mut Vec<u8>, data: &[u8]) {
+    container.extend_from_slice(&(data.len() as u64).to_le_bytes());
+    container.extend_from_slice(data);
+}
+
+fn corrupt(offset: u64, message: &str) -> PreflateError {
+    PreflateError::CorruptCorrections(offset as usize, anyhow::anyhow!("{}", message))
+}
+
+/// the length of the original file and its crc32, after checking the header and trailer
+fn read_header(container: &[u8]) -> Result<(u64, u32, u64), PreflateError> {
+    if container.len() < HEADER_SIZE + TRAILER_SIZE || &container[0..4] != MAGIC {
+        return Err(corrupt(0, "not a preflate container"));
+    }
+    if container[4] > FORMAT_VERSION {
+        return Err(corrupt(
+            4,
+            &format!("unsupported container version {}", container[4]),
+        ));
+    }
+
+    let trailer = &container[container.len() - TRAILER_SIZE..];
+    if &trailer[12..16] != TRAILER_MAGIC {
+        return Err(corrupt(
+            (container.len() - 4) as u64,
+            "container is truncated",
+        ));
+    }
+
+    let original_len = u64::from_le_bytes(container[8..16].try_into().unwrap());
+    let index_offset = u64::from_le_bytes(trailer[0..8].try_into().unwrap());
+    let crc32 = u32::from_le_bytes(trailer[8..12].try_into().unwrap());
+    Ok((original_len, crc32, index_offset))
+}
+
+/// reads the index of the sections, which allows recreating parts of the file on their own
+pub fn read_index(container: &[u8]) -> Result<Vec<SectionInfo>, PreflateError> {
+    let (original_len, _, index_offset) = read_header(container)?;
+
+    let index_end = container.len() - TRAILER_SIZE;
+    let mut reader = Cursor::new(&container[..index_end]);
+    reader.set_position(index_offset);
+
+    let count = reader
+        .read_u32::<LittleEndian>()
+        .map_err(|_| corrupt(index_offset, "index is outside of the container"))?;
+    if (count as usize).saturating_mul(INDEX_ENTRY_SIZE) > index_end {
+        return Err(corrupt(index_offset, "index is too large"));
+    }
+
+    let mut sections = Vec::with_capacity(count as usize);
+    let mut expected_offset = 0;
+    for _ in 0..count {
+        let position = reader.position();
+        let read = |reader: &mut Cursor<&[u8]>| -> std::io::Result<SectionInfo> {
+            let kind = reader.read_u8()?;
+            Ok(SectionInfo {
+                kind: SectionKind::from_byte(kind).ok_or(std::io::ErrorKind::InvalidData)?,
+                original_offset: reader.read_u64::<LittleEndian>()?,
+                original_len: reader.read_u64::<LittleEndian>()?,
+                container_offset: reader.read_u64::<LittleEndian>()?,
+            })
+        };
+        let section = read(&mut reader).map_err(|_| corrupt(position, "damaged index entry"))?;
+
+        // the sections have to cover the original file without gaps
+        if section.original_offset != expected_offset || section.container_offset >= index_offset {
+            return Err(corrupt(position, "index entry is out of order"));
+        }
+        expected_offset += section.original_len;
+        sections.push(section);
+    }
+
+    if expected_offset != original_len {
+        return Err(corrupt(
+            index_offset,
+            "index doesn't cover the original file",
+        ));
+    }
+
+    Ok(sections)
+}
+
+/// recreates the bytes of the original file that belong to the section
+pub fn unpack_section(
+    container: &[u8],
+    section: &SectionInfo,
+    config: &PreflateConfig,
+) -> Result<Vec<u8>, PreflateError> {
+    let offset = section.container_offset;
+    let damaged = |_| corrupt(offset, "damaged section");
+
+    let mut reader = Cursor::new(container);
+    reader.set_position(offset);
+
+    if reader.read_u8().map_err(damaged)? != section.kind.to_byte() {
+        return Err(corrupt(offset, "section doesn't match the index"));
+    }
+
+    let original = match section.kind {
+        SectionKind::Raw => read_slice(&mut reader).map_err(damaged)?.to_vec(),
+        SectionKind::Fallback => {
+            reader.read_u32::<LittleEndian>().map_err(damaged)?;
+            read_slice(&mut reader).map_err(damaged)?.to_vec()
+        }
+        SectionKind::Stream => {
+            let compressed_len = reader.read_u64::<LittleEndian>().map_err(damaged)?;
+            let plain_text_len = reader.read_u64::<LittleEndian>().map_err(damaged)?;
+            let plain_text_crc32 = reader.read_u32::<LittleEndian>().map_err(damaged)?;
+            let parameters_len = reader.read_u16::<LittleEndian>().map_err(damaged)?;
+            take(&mut reader, parameters_len.into()).map_err(damaged)?;
+            let corrections_len = reader.read_u64::<LittleEndian>().map_err(damaged)?;
+            let plain_text = take(&mut reader, plain_text_len).map_err(damaged)?;
+            let corrections = take(&mut reader, corrections_len).map_err(damaged)?;
+
+            if crc32fast::hash(plain_text) != plain_text_crc32 {
+                return Err(corrupt(offset, "plain text of the stream is damaged"));
+            }
+
+            let recompressed =
+                recompress_deflate_stream_with_config(plain_text, corrections, config)?;
+            if recompressed.len() as u64 != compressed_len {
+                return Err(PreflateError::Mismatch(anyhow::anyhow!(
+                    "stream at {} was recreated with the wrong length",
+                    section.original_offset
+                )));
+            }
+            recompressed
+        }
+    };
+
+    if original.len() as u64 != section.original_len {
+        return Err(corrupt(offset, "section doesn't match the index"));
+    }
+
+    Ok(original)
+}
+
+fn read_slice<'a>(reader: &mut Cursor<&'a [u8]>) -> std::io::Result<&'a [u8]> {
+    let len = reader.read_u64::<LittleEndian>()?;
+    take(reader, len)
+}
+
+fn take<'a>(reader: &mut Cursor<&'a [u8]>, len: u64) -> std::io::Result<&'a [u8]> {
+    let data: &'a [u8] = reader.get_ref();
+    let start = reader.position();
+    let slice = start
+        .checked_add(len)
+        .and_then(|end| data.get(usize::try_from(start).ok()?..usize::try_from(end).ok()?))
+        .ok_or(std::io::ErrorKind::UnexpectedEof)?;
+    reader.set_position(start + len);
+    Ok(slice)
+}
+
+/// recreates the original file from a container written by pack
+pub fn unpack(container: &[u8], config: &PreflateConfig) -> Result<Vec<u8>, PreflateError> {
+    let (original_len, crc32, _) = read_header(container)?;
+
+    let mut original = Vec::with_capacity(original_len.min(container.len() as u64 * 4) as usize);
+    for section in read_index(container)? {
+        original.extend_from_slice(&unpack_section(container, &section, config)?);
+    }
+
+    if crc32fast::hash(&original) != crc32 {
+        return Err(PreflateError::Mismatch(anyhow::anyhow!(
+            "unpacked file doesn't match the crc32 of the original"
+        )));
+    }
+
+    Ok(original)
+}
+
+/// Reads a file from the inner reader and returns the container that pack writes for it. The
+/// streams can only be found once the whole file is known, so the first read reads the inner
+/// reader to its end and packs all of it in memory before it returns anything. This needs
+/// memory for the file and the container at the same time, which limits it to files that fit.
+pub struct PreflateReader<R> {
+    inner: R,
+    config: PreflateConfig,
+    /// what was read from the inner reader so far
+    input: Vec<u8>,
+    /// the container, once the whole file was read
+    packed: Option<(Cursor<Vec<u8>>, ArchiveSummary)>,
+}
+
+impl<R: Read> PreflateReader<R> {
+    pub fn new(inner: R, config: &PreflateConfig) -> Self {
+        PreflateReader {
+            inner,
+            config: config.clone(),
+            input: Vec::new(),
+            packed: None,
+        }
+    }
+
+    /// what happened to each of the deflate streams, once the first read returned
+    pub fn summary(&self) -> Option<&ArchiveSummary> {
+        self.packed.as_ref().map(|(_, summary)| summary)
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for PreflateReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.packed.is_none() {
+            // what was read before an error is kept, so a read after it continues from there
+            self.inner.read_to_end(&mut self.input)?;
+
+            let packed = pack(&std::mem::take(&mut self.input), &self.config);
+            self.packed = Some((Cursor::new(packed.container), packed.summary));
+        }
+
+        self.packed.as_mut().unwrap().0.read(buf)
+    }
+}
+
+/// Takes a container through io::Write and writes the original file to the inner writer when
+/// finish is called, since the container can only be unpacked once it is complete. Until then
+/// the whole container is kept in memory.
+///
+/// Like the encoders of flate2, a writer that is dropped without calling finish still unpacks
+/// the container and writes the file, but any error is lost then, including the one for an
+/// incomplete container. Call finish to find out whether the file was written.
+pub struct PreflateWriter<W: Write> {
+    /// None once the file was written by finish
+    inner: Option<W>,
+    config: PreflateConfig,
+    container: Vec<u8>,
+}
+
+impl<W: Write> PreflateWriter<W> {
+    pub fn new(inner: W, config: &PreflateConfig) -> Self {
+        PreflateWriter {
+            inner: Some(inner),
+            config: config.clone(),
+            container: Vec::new(),
+        }
+    }
+
+    /// unpacks the container that was written and writes the original file to the inner writer,
+    /// which is returned afterwards
+    pub fn finish(mut self) -> Result<W, PreflateError> {
+        let mut inner = self.inner.take().unwrap();
+        write_unpacked(&mut inner, &self.container, &self.config)?;
+        Ok(inner)
+    }
+}
+
+impl<W: Write> Drop for PreflateWriter<W> {
+    fn drop(&mut self) {
+        if let Some(mut inner) = self.inner.take() {
+            let _ = write_unpacked(&mut inner, &self.container, &self.config);
+        }
+    }
+}
+
+fn write_unpacked<W: Write>(
+    inner: &mut W,
+    container: &[u8],
+    config: &PreflateConfig,
+) -> Result<(), PreflateError> {
+    let original = unpack(container, config)?;
+    inner.write_all(&original)?;
+    inner.flush()?;
+    Ok(())
+}
+
+impl<W: Write> Write for PreflateWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.container.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        // nothing can be written before the container is complete
+        Ok(())
+    }
+}
+
+#[test]
+fn pack_roundtrip() {
+    let plain_text = crate::process::read_file("sample1.bin");
+
+    let mut zlib = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::new(6));
+    zlib.write_all(&plain_text).unwrap();
+    let zlib = zlib.finish().unwrap();
+
+    let mut data = b"some bytes before the stream".to_vec();
+    data.extend_from_slice(&zlib);
+    data.extend_from_slice(b"and some after it");
+
+    let config = PreflateConfig::default();
+    let packed = pack(&data, &config);
+    assert_eq!(packed.summary.entries_processed, 1);
+
+    let index = read_index(&packed.container).unwrap();
+    let kinds: Vec<SectionKind> = index.iter().map(|s| s.kind).collect();
+    assert_eq!(
+        kinds,
+        [SectionKind::Raw, SectionKind::Stream, SectionKind::Raw]
+    );
+
+    // a stream can be recreated on its own
+    let stream = unpack_section(&packed.container, &index[1], &config).unwrap();
+    assert_eq!(stream, zlib[2..zlib.len() - 4]);
+
+    assert_eq!(unpack(&packed.container, &config).unwrap(), data);
+
+    // a file without streams only has a raw section
+    let packed = pack(b"nothing to see", &config);
+    assert_eq!(
+        unpack(&packed.container, &config).unwrap(),
+        b"nothing to see"
+    );
+
+    assert!(matches!(
+        unpack(&packed.container[..packed.container.len() - 1], &config),
+        Err(PreflateError::CorruptCorrections(..))
+    ));
+}
+
+#[test]
+fn reader_and_writer_adapters() {
+    let plain_text = crate::process::read_file("sample1.bin");
+
+    let mut zlib = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::new(6));
+    zlib.write_all(&plain_text).unwrap();
+    let mut file = b"header ".to_vec();
+    file.extend_from_slice(&zlib.finish().unwrap());
+    file.extend_from_slice(b" footer");
+
+    let config = PreflateConfig::default();
+    let mut reader = PreflateReader::new(&file[..], &config);
+    assert!(reader.summary().is_none());
+
+    let mut container = Vec::new();
+    std::io::copy(&mut reader, &mut container).unwrap();
+    assert_eq!(reader.summary().unwrap().entries_processed, 1);
+    assert_eq!(container, pack(&file, &config).container);
+
+    let mut writer = PreflateWriter::new(Vec::new(), &config);
+    std::io::copy(&mut &container[..], &mut writer).unwrap();
+    assert_eq!(writer.finish().unwrap(), file);
+
+    // dropping the writer without finishing it still writes the file
+    let mut unpacked = Vec::new();
+    PreflateWriter::new(&mut unpacked, &config)
+        .write_all(&container)
+        .unwrap();
+    assert_eq!(unpacked, file);
+
+    // an incomplete container can't be unpacked
+    let mut writer = PreflateWriter::new(Vec::new(), &config);
+    writer.write_all(&container[..container.len() - 1]).unwrap();
+    assert!(writer.finish().is_err());
+}
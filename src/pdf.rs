@@ -0,0 +1,180 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! PDF files contain most of their content in streams, which are usually compressed with the
+//! FlateDecode filter. Each stream follows its dictionary and is enclosed by the `stream` and
+//! `endstream` keywords. The end of a stream is found with the `endstream` keyword rather than
+//! with /Length, since that is often an indirect reference to another object.
+//!
+//! Only the zlib data of the streams is expanded, the dictionaries and the /DecodeParms are
+//! kept as they are, so the predictors of images are left untouched and the file is recreated
+//! byte for byte with container::recreate_file. Encrypted streams can't be decompressed and
+//! are kept as they are.
+
+use crate::{
+    container::{expand_file, ExpandedFile, StreamExtent},
+    nested_streams::is_zlib_header,
+    preflate_config::PreflateConfig,
+    preflate_error::PreflateError,
+};
+
+/// the header has to be within the first 1024 bytes of the file according to the specification
+const MAX_HEADER_OFFSET: usize = 1024;
+
+fn is_whitespace(b: u8) -> bool {
+    matches!(b, b' ' | b'\t' | b'\r' | b'\n' | b'\x0c' | b'\0')
+}
+
+fn is_delimiter(b: u8) -> bool {
+    matches!(
+        b,
+        b'(' | b')' | b'<' | b'>' | b'[' | b']' | b'{' | b'}' | b'/' | b'%'
+    )
+}
+
+fn find(data: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    data.get(from..)?
+        .windows(needle.len())
+        .position(|w| w == needle)
+        .map(|p| p + from)
+}
+
+/// returns the start of the dictionary that ends just before end, taking nested dictionaries
+/// into account
+fn dictionary_start(data: &[u8], end: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut i = end;
+    while i >= 2 {
+        match &data[i - 2..i] {
+            b">>" => {
+                depth += 1;
+                i -= 2;
+            }
+            b"<<" => {
+                depth -= 1;
+                i -= 2;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => i -= 1,
+        }
+    }
+    None
+}
+
+/// whether the first filter of the stream dictionary is FlateDecode, which means that the
+/// data of the stream is zlib compressed
+fn has_flate_filter(dictionary: &[u8]) -> bool {
+    let Some(filter) = find(dictionary, b"/Filter", 0) else {
+        return false;
+    };
+
+    let mut rest = &dictionary[filter + 7..];
+    while let Some((&b, next)) = rest.split_first() {
+        if !is_whitespace(b) && b != b'[' {
+            break;
+        }
+        rest = next;
+    }
+
+    rest.starts_with(b"/FlateDecode")
+        && rest
+            .get(12)
+            .map_or(true, |&b| is_whitespace(b) || is_delimiter(b))
+}
+
+/// returns the extents of the deflate data of all the FlateDecode streams of the file
+pub fn pdf_flate_extents(data: &[u8]) -> Result<Vec<StreamExtent>, PreflateError> {
+    if find(&data[..data.len().min(MAX_HEADER_OFFSET)], b"%PDF-", 0).is_none() {
+        return Err(PreflateError::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "invalid PDF file: no header",
+        )));
+    }
+
+    let mut extents = Vec::new();
+    let mut pos = 0;
+    while let Some(keyword) = find(data, b"stream", pos) {
+        pos = keyword + 6;
+
+        // the keyword is followed by an end of line and preceded by the dictionary
+        let start = match &data[pos..] {
+            [b'\r', b'\n', ..] => pos + 2,
+            [b'\n', ..] => pos + 1,
+            _ => continue,
+        };
+        let dictionary_end = data[..keyword]
+            .iter()
+            .rposition(|&b| !is_whitespace(b))
+            .map_or(0, |p| p + 1);
+        if !data[..dictionary_end].ends_with(b">>") {
+            continue;
+        }
+        let Some(end) = find(data, b"endstream", start) else {
+            break;
+        };
+        pos = end + 9;
+
+        let flate = dictionary_start(data, dictionary_end)
+            .is_some_and(|d| has_flate_filter(&data[d..dictionary_end]));
+        // zlib header and adler32 trailer around the deflate data, the end of line before
+        // endstream is kept as it is
+        if flate && end - start > 6 && is_zlib_header(&data[start..end]) {
+            extents.push(StreamExtent {
+                offset: start + 2,
+                length: end - start - 2,
+            });
+        }
+    }
+
+    Ok(extents)
+}
+
+/// Expands all the FlateDecode streams of a PDF file. The file can be recreated
+/// byte for byte with container::recreate_file, since everything else is kept as it is.
+pub fn expand_pdf(data: &[u8], config: &PreflateConfig) -> Result<ExpandedFile, PreflateError> {
+    Ok(expand_file(data, pdf_flate_extents(data)?, config))
+}
+
+#[test]
+fn pdf_roundtrip() {
+    use std::io::Write;
+
+    let content = crate::process::read_file("sample1.bin");
+    let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::new(6));
+    encoder.write_all(&content).unwrap();
+    let zlib = encoder.finish().unwrap();
+
+    let mut pdf = b"%PDF-1.7\n%\xe2\xe3\xcf\xd3\n".to_vec();
+    pdf.extend_from_slice(b"1 0 obj\n<< /Length 2 0 R /Filter /FlateDecode >>\nstream\r\n");
+    pdf.extend_from_slice(&zlib);
+    pdf.extend_from_slice(b"\r\nendstream\nendobj\n");
+    // an image with a predictor, and the filter in an array with a nested dictionary
+    pdf.extend_from_slice(
+        b"3 0 obj\n<< /Filter [/FlateDecode] /DecodeParms << /Predictor 15 /Columns 4 >> >>\nstream\n",
+    );
+    pdf.extend_from_slice(&zlib);
+    pdf.extend_from_slice(b"\nendstream\nendobj\n");
+    // a stream with another filter is left alone
+    pdf.extend_from_slice(b"4 0 obj\n<< /Filter /DCTDecode >>\nstream\n");
+    pdf.extend_from_slice(&zlib);
+    pdf.extend_from_slice(b"\nendstream\nendobj\ntrailer\n<< /Root 1 0 R >>\n%%EOF\n");
+
+    let extents = pdf_flate_extents(&pdf).unwrap();
+    assert_eq!(extents.len(), 2);
+
+    let config = PreflateConfig::default();
+    let expanded = expand_pdf(&pdf, &config).unwrap();
+    assert_eq!(expanded.summary.entries_processed, 2);
+    assert_eq!(
+        crate::container::recreate_file(&expanded.plain_text, &expanded.corrections, &config)
+            .unwrap(),
+        pdf
+    );
+
+    assert!(expand_pdf(&content, &config).is_err());
+}
@@ -0,0 +1,353 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! The `.pfl` file format, which stores the plain text and the corrections of a stream together
+//! in one file, so that callers don't have to come up with their own framing for them.
+//!
+//! All numbers are little endian. The file consists of:
+//!
+//! - header (16 bytes): the magic `PFLF`, the format version (1), the flags, 2 reserved bytes
+//!   that are 0, the number of chunks as u32 and the crc32 of the chunk table
+//! - chunk table: for each chunk its kind as u8, offset in the file and length (both u64) and
+//!   the crc32 of its data
+//! - the data of the chunks, in the order of the table
+//!
+//! The chunk kinds are the plain text (0) and the corrections (1). Chunks of other kinds are
+//! skipped by the reader, so that later versions can add chunks that older readers don't need.
+//! Flags that the reader doesn't know about and newer format versions are rejected.
+
+use std::io::{Read, Write};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use crate::{
+    preflate_config::{CorrectionCodec, PreflateConfig},
+    preflate_error::PreflateError,
+    DecompressResult,
+};
+
+/// the version of the file format that is written
+pub const FORMAT_VERSION: u8 = 1;
+
+/// the corrections were written with the JSON codec instead of cabac
+pub const FLAG_JSON_CORRECTIONS: u8 = 1;
+
+const KNOWN_FLAGS: u8 = FLAG_JSON_CORRECTIONS;
+
+const MAGIC: &[u8; 4] = b"PFLF";
+const HEADER_SIZE: u64 = 16;
+const CHUNK_ENTRY_SIZE: u64 = 21;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PflChunkKind {
+    PlainText,
+    Corrections,
+    /// a chunk written by a later version, which is skipped
+    Unknown(u8),
+}
+
+impl PflChunkKind {
+    fn to_byte(self) -> u8 {
+        match self {
+            PflChunkKind::PlainText => 0,
+            PflChunkKind::Corrections => 1,
+            PflChunkKind::Unknown(b) => b,
+        }
+    }
+
+    fn from_byte(b: u8) -> Self {
+        match b {
+            0 => PflChunkKind::PlainText,
+            1 => PflChunkKind::Corrections,
+            _ => PflChunkKind::Unknown(b),
+        }
+    }
+}
+
+/// an entry of the chunk table
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PflChunk {
+    pub kind: PflChunkKind,
+    /// offset of the data of the chunk from the start of the file
+    pub offset: u64,
+    pub length: u64,
+    /// crc32 of the data of the chunk
+    pub crc32: u32,
+}
+
+/// the contents of a `.pfl` file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PflFile {
+    /// combination of the FLAG_ constants
+    pub flags: u8,
+    pub plain_text: Vec<u8>,
+    pub corrections: Vec<u8>,
+}
+
+impl PflFile {
+    pub fn new(plain_text: Vec<u8>, corrections: Vec<u8>) -> Self {
+        PflFile {
+            flags: 0,
+            plain_text,
+            corrections,
+        }
+    }
+
+    /// takes the plain text and corrections of a decompressed stream, with the flags set
+    /// for the codec of the config that the stream was decompressed with
+    pub fn from_result(result: DecompressResult, config: &PreflateConfig) -> Self {
+        let flags = match config.codec {
+            CorrectionCodec::Cabac => 0,
+            #[cfg(feature = "serde")]
+            CorrectionCodec::Json => FLAG_JSON_CORRECTIONS,
+        };
+
+        PflFile {
+            flags,
+            ..PflFile::new(result.plain_text, result.cabac_encoded)
+        }
+    }
+
+    /// checks that the corrections were written with the codec of the config, since they
+    /// can only be decoded with the same codec
+    pub fn check_codec(&self, config: &PreflateConfig) -> Result<(), PreflateError> {
+        let json = self.flags & FLAG_JSON_CORRECTIONS != 0;
+        if json != (config.codec != CorrectionCodec::Cabac) {
+            return Err(corrupt(
+                5,
+                "corrections were written with a different codec than the config uses",
+            ));
+        }
+        Ok(())
+    }
+
+    /// the chunk table that write_to writes for this file
+    pub fn chunks(&self) -> Vec<PflChunk> {
+        let parts = [
+            (PflChunkKind::PlainText, &self.plain_text),
+            (PflChunkKind::Corrections, &self.corrections),
+        ];
+
+        let mut offset = HEADER_SIZE + CHUNK_ENTRY_SIZE * parts.len() as u64;
+        parts
+            .iter()
+            .map(|(kind, data)| {
+                let chunk = PflChunk {
+                    kind: *kind,
+                    offset,
+                    length: data.len() as u64,
+                    crc32: crc32fast::hash(data),
+                };
+                offset += chunk.length;
+                chunk
+            })
+            .collect()
+    }
+
+    pub fn write_to(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        let chunks = self.chunks();
+
+        let mut table = Vec::with_capacity(chunks.len() * CHUNK_ENTRY_SIZE as usize);
+        for chunk in &chunks {
+            table.push(chunk.kind.to_byte());
+            table.extend_from_slice(&chunk.offset.to_le_bytes());
+            table.extend_from_slice(&chunk.length.to_le_bytes());
+            table.extend_from_slice(&chunk.crc32.to_le_bytes());
+        }
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[FORMAT_VERSION, self.flags, 0, 0])?;
+        writer.write_all(&(chunks.len() as u32).to_le_bytes())?;
+        writer.write_all(&crc32fast::hash(&table).to_le_bytes())?;
+        writer.write_all(&table)?;
+        writer.write_all(&self.plain_text)?;
+        writer.write_all(&self.corrections)?;
+        Ok(())
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut output = Vec::with_capacity(
+            HEADER_SIZE as usize
+                + 2 * CHUNK_ENTRY_SIZE as usize
+                + self.plain_text.len()
+                + self.corrections.len(),
+        );
+        self.write_to(&mut output).unwrap();
+        output
+    }
+
+    /// Reads a file written by write_to. The reader is only read forward, so it doesn't
+    /// need to support seeking.
+    pub fn read_from(reader: &mut impl Read) -> Result<Self, PreflateError> {
+        let (flags, chunks) = read_chunk_table(reader)?;
+
+        let mut file = PflFile {
+            flags,
+            ..Default::default()
+        };
+
+        let mut position = HEADER_SIZE + CHUNK_ENTRY_SIZE * chunks.len() as u64;
+        for chunk in chunks {
+            if chunk.offset < position {
+                return Err(corrupt(chunk.offset, "chunks overlap"));
+            }
+            std::io::copy(
+                &mut reader.by_ref().take(chunk.offset - position),
+                &mut std::io::sink(),
+            )?;
+
+            let mut data = Vec::new();
+            reader.by_ref().take(chunk.length).read_to_end(&mut data)?;
+            if data.len() as u64 != chunk.length {
+                return Err(corrupt(
+                    chunk.offset + data.len() as u64,
+                    "file is truncated",
+                ));
+            }
+            if crc32fast::hash(&data) != chunk.crc32 {
+                return Err(corrupt(chunk.offset, "chunk doesn't match its crc32"));
+            }
+            position = chunk.offset + chunk.length;
+
+            match chunk.kind {
+                PflChunkKind::PlainText => file.plain_text = data,
+                PflChunkKind::Corrections => file.corrections = data,
+                PflChunkKind::Unknown(_) => {}
+            }
+        }
+
+        Ok(file)
+    }
+
+    pub fn from_slice(mut data: &[u8]) -> Result<Self, PreflateError> {
+        Self::read_from(&mut data)
+    }
+}
+
+fn corrupt(offset: u64, message: &str) -> PreflateError {
+    PreflateError::CorruptCorrections(offset as usize, anyhow::anyhow!("{}", message))
+}
+
+/// reads the header and the chunk table from the start of a `.pfl` file, returning the flags
+/// and the chunks in the order of their data
+pub fn read_chunk_table(reader: &mut impl Read) -> Result<(u8, Vec<PflChunk>), PreflateError> {
+    let mut header = [0; HEADER_SIZE as usize];
+    reader
+        .read_exact(&mut header)
+        .map_err(|_| corrupt(0, "not a pfl file"))?;
+
+    if &header[0..4] != MAGIC {
+        return Err(corrupt(0, "not a pfl file"));
+    }
+    if header[4] > FORMAT_VERSION {
+        return Err(corrupt(
+            4,
+            &format!("unsupported pfl version {}", header[4]),
+        ));
+    }
+    let flags = header[5];
+    if flags & !KNOWN_FLAGS != 0 {
+        return Err(corrupt(5, &format!("unsupported pfl flags {:#x}", flags)));
+    }
+
+    let count = u32::from_le_bytes(header[8..12].try_into().unwrap());
+    let table_crc32 = u32::from_le_bytes(header[12..16].try_into().unwrap());
+
+    let mut table = Vec::new();
+    reader
+        .by_ref()
+        .take(u64::from(count) * CHUNK_ENTRY_SIZE)
+        .read_to_end(&mut table)?;
+    if table.len() as u64 != u64::from(count) * CHUNK_ENTRY_SIZE {
+        return Err(corrupt(
+            HEADER_SIZE + table.len() as u64,
+            "file is truncated",
+        ));
+    }
+    if crc32fast::hash(&table) != table_crc32 {
+        return Err(corrupt(HEADER_SIZE, "chunk table doesn't match its crc32"));
+    }
+
+    let mut entries = &table[..];
+    let mut chunks = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        chunks.push(PflChunk {
+            kind: PflChunkKind::from_byte(entries.read_u8()?),
+            offset: entries.read_u64::<LittleEndian>()?,
+            length: entries.read_u64::<LittleEndian>()?,
+            crc32: entries.read_u32::<LittleEndian>()?,
+        });
+    }
+    chunks.sort_by_key(|c| c.offset);
+
+    Ok((flags, chunks))
+}
+
+#[test]
+fn pfl_file_roundtrip() {
+    let config = PreflateConfig::default();
+    let compressed = crate::process::read_file("compressed_zlib_level6.deflate");
+    let result = crate::decompress_deflate_stream_with_config(&compressed, &config).unwrap();
+
+    let file = PflFile::from_result(result, &config);
+    file.check_codec(&config).unwrap();
+
+    let written = file.to_vec();
+    let read = PflFile::from_slice(&written).unwrap();
+    assert_eq!(read, file);
+    assert_eq!(
+        crate::recompress_deflate_stream_with_config(&read.plain_text, &read.corrections, &config)
+            .unwrap(),
+        compressed
+    );
+
+    // damaged data is caught by the crc32 of the chunk
+    let mut damaged = written.clone();
+    *damaged.last_mut().unwrap() ^= 1;
+    assert!(matches!(
+        PflFile::from_slice(&damaged),
+        Err(PreflateError::CorruptCorrections(..))
+    ));
+
+    // truncated files and unknown flags are rejected
+    assert!(PflFile::from_slice(&written[..written.len() - 1]).is_err());
+    let mut flags = written.clone();
+    flags[5] = 0x80;
+    assert!(PflFile::from_slice(&flags).is_err());
+}
+
+#[test]
+fn pfl_file_skips_unknown_chunks() {
+    let file = PflFile::new(b"plain".to_vec(), b"corrections".to_vec());
+
+    // a file from a later version with an extra chunk after the corrections
+    let mut chunks = file.chunks();
+    for c in chunks.iter_mut() {
+        c.offset += CHUNK_ENTRY_SIZE;
+    }
+    chunks.push(PflChunk {
+        kind: PflChunkKind::Unknown(7),
+        offset: chunks[1].offset + chunks[1].length,
+        length: 3,
+        crc32: crc32fast::hash(b"new"),
+    });
+
+    let mut table = Vec::new();
+    for c in &chunks {
+        table.push(c.kind.to_byte());
+        table.extend_from_slice(&c.offset.to_le_bytes());
+        table.extend_from_slice(&c.length.to_le_bytes());
+        table.extend_from_slice(&c.crc32.to_le_bytes());
+    }
+    let mut written = MAGIC.to_vec();
+    written.extend_from_slice(&[FORMAT_VERSION, 0, 0, 0]);
+    written.extend_from_slice(&3u32.to_le_bytes());
+    written.extend_from_slice(&crc32fast::hash(&table).to_le_bytes());
+    written.extend_from_slice(&table);
+    written.extend_from_slice(b"plaincorrectionsnew");
+
+    assert_eq!(PflFile::from_slice(&written).unwrap(), file);
+}
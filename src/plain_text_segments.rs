@@ -0,0 +1,163 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! The plain text of a stream in parts, for streams that consist mostly of stored blocks. The
+//! data of a stored block is the same in the compressed data and in the plain text, so instead
+//! of keeping a second copy of it the segment borrows it from the compressed data. Only the
+//! plain text of the compressed blocks is owned.
+
+use std::{borrow::Cow, io::Write};
+
+use crate::{
+    compressor_profile::CompressorProfile, statistical_codec::BlockCost, CountNonDefaultActions,
+    PreflateParameters,
+};
+
+/// result of decompress_deflate_stream_segments, the same as DecompressResult except for
+/// the plain text
+pub struct SegmentedDecompressResult<'a> {
+    /// the plaintext that was decompressed from the stream
+    pub plain_text: PlainTextSegments<'a>,
+    /// the extra data that is needed to reconstruct the deflate stream exactly as it was written
+    pub cabac_encoded: Vec<u8>,
+    /// the number of bytes that were processed from the compressed stream
+    pub compressed_processed: usize,
+    /// how many corrections were needed and how many bits were spent on each kind of correction
+    pub statistics: CountNonDefaultActions,
+    /// CRC-32 of the plain text
+    pub plain_text_crc32: u32,
+    /// the compressor settings that the stream was predicted with
+    pub profile: CompressorProfile,
+    /// the parameters that were estimated from the stream
+    pub parameters: PreflateParameters,
+}
+
+/// the plain text of a stream, where the data of the stored blocks is borrowed from the
+/// compressed data
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PlainTextSegments<'a> {
+    /// the parts of the plain text in order
+    pub segments: Vec<Cow<'a, [u8]>>,
+}
+
+impl<'a> PlainTextSegments<'a> {
+    /// length of the whole plain text
+    pub fn len(&self) -> usize {
+        self.segments.iter().map(|s| s.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.iter().all(|s| s.is_empty())
+    }
+
+    /// number of bytes of the plain text that are borrowed instead of copied
+    pub fn borrowed_len(&self) -> usize {
+        self.segments
+            .iter()
+            .filter(|s| matches!(s, Cow::Borrowed(_)))
+            .map(|s| s.len())
+            .sum()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &[u8]> {
+        self.segments.iter().map(|s| &s[..])
+    }
+
+    /// writes the plain text without putting it together first
+    pub fn write_to(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        for s in self.iter() {
+            writer.write_all(s)?;
+        }
+        Ok(())
+    }
+
+    /// copies the whole plain text into one buffer
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut plain_text = Vec::with_capacity(self.len());
+        for s in self.iter() {
+            plain_text.extend_from_slice(s);
+        }
+        plain_text
+    }
+
+    /// Splits the plain text at the stored blocks of the stream. The block boundaries come from
+    /// the length of the plain text of each block and the number of bits of each block. If they
+    /// don't fit the stream (for example because a cached result didn't report the blocks),
+    /// the plain text is kept as a single owned segment.
+    pub(crate) fn split(
+        compressed_data: &'a [u8],
+        plain_text: Vec<u8>,
+        block_lens: &[usize],
+        costs: &[BlockCost],
+    ) -> Self {
+        let stored = if block_lens.len() == costs.len()
+            && block_lens.iter().sum::<usize>() == plain_text.len()
+        {
+            stored_blocks(compressed_data, &plain_text, block_lens, costs)
+        } else {
+            None
+        };
+
+        let stored = match stored {
+            Some(stored) if !stored.is_empty() => stored,
+            _ => {
+                return PlainTextSegments {
+                    segments: vec![Cow::Owned(plain_text)],
+                }
+            }
+        };
+
+        let mut segments = Vec::new();
+        let mut pos = 0;
+        for (start, data) in stored {
+            if start > pos {
+                segments.push(Cow::Owned(plain_text[pos..start].to_vec()));
+            }
+            segments.push(Cow::Borrowed(data));
+            pos = start + data.len();
+        }
+        if pos < plain_text.len() {
+            segments.push(Cow::Owned(plain_text[pos..].to_vec()));
+        }
+
+        PlainTextSegments { segments }
+    }
+}
+
+/// the position in the plain text and the data of each stored block that isn't empty, or None
+/// if the blocks don't match the stream
+fn stored_blocks<'a>(
+    compressed_data: &'a [u8],
+    plain_text: &[u8],
+    block_lens: &[usize],
+    costs: &[BlockCost],
+) -> Option<Vec<(usize, &'a [u8])>> {
+    let bit = |pos: u64| -> Option<u8> {
+        let byte = compressed_data.get((pos / 8) as usize)?;
+        Some((byte >> (pos % 8)) & 1)
+    };
+
+    let mut stored = Vec::new();
+    let mut bit_pos = 0;
+    let mut plain_pos = 0;
+    for (&len, cost) in block_lens.iter().zip(costs) {
+        // BFINAL followed by BTYPE, which is 0 for stored blocks
+        let block_type = bit(bit_pos + 1)? | (bit(bit_pos + 2)? << 1);
+        if block_type == 0 && len > 0 {
+            // the header of a stored block includes the padding to the next byte
+            let offset = ((bit_pos + cost.header_bits) / 8) as usize;
+            let data = compressed_data.get(offset..offset + len)?;
+            if data != &plain_text[plain_pos..plain_pos + len] {
+                return None;
+            }
+            stored.push((plain_pos, data));
+        }
+
+        bit_pos += cost.header_bits + cost.tree_bits + cost.token_bits;
+        plain_pos += len;
+    }
+    Some(stored)
+}
@@ -0,0 +1,242 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! A layout of the corrections for when they are compressed again afterwards with a generic
+//! compressor such as zstd. Instead of arithmetic coding the actions as they come, every kind of
+//! misprediction and correction is written uncompressed to its own plane. Each plane only
+//! contains similar values, which the generic compressor models much better than the
+//! interleaved output of the arithmetic coder (which it can't compress at all).
+
+use std::borrow::Cow;
+
+use crate::statistical_codec::{
+    CodecCorrection, CodecMisprediction, CountNonDefaultActions, PredictionDecoder,
+    PredictionEncoder,
+};
+
+/// one plane per kind of misprediction, one per kind of correction and one for the raw values
+const PLANE_COUNT: usize = CodecMisprediction::MAX as usize + CodecCorrection::MAX as usize + 1;
+
+fn misprediction_plane(misprediction: CodecMisprediction) -> usize {
+    misprediction as usize
+}
+
+fn correction_plane(correction: CodecCorrection) -> usize {
+    CodecMisprediction::MAX as usize + correction as usize
+}
+
+const VALUE_PLANE: usize = PLANE_COUNT - 1;
+
+/// Writes the actions to separate planes: the mispredictions as a byte that is 0 or 1, the
+/// corrections as LEB128 varints and the raw values as 16 bit little endian. The buckets of
+/// the corrections aren't stored, since the decoder passes them in. The verify states are dropped.
+pub struct PlanePredictionEncoder {
+    planes: Vec<Vec<u8>>,
+    count: CountNonDefaultActions,
+}
+
+impl PlanePredictionEncoder {
+    pub fn new() -> Self {
+        PlanePredictionEncoder {
+            planes: vec![Vec::new(); PLANE_COUNT],
+            count: CountNonDefaultActions::default(),
+        }
+    }
+
+    /// Writes the number of planes followed by each plane prefixed with its length (as little
+    /// endian u32), so that a decoder for a later version with more kinds of corrections can
+    /// still find all of them.
+    pub fn write_planes(&self, output: &mut Vec<u8>) {
+        output.push(PLANE_COUNT as u8);
+        for plane in &self.planes {
+            output.extend_from_slice(&(plane.len() as u32).to_le_bytes());
+            output.extend_from_slice(plane);
+        }
+    }
+
+    fn write_varint(&mut self, correction: CodecCorrection, mut value: u32) {
+        let plane = &mut self.planes[correction_plane(correction)];
+        let start = plane.len();
+        loop {
+            if value < 0x80 {
+                plane.push(value as u8);
+                break;
+            }
+            plane.push((value & 0x7f) as u8 | 0x80);
+            value >>= 7;
+        }
+
+        let bits = (plane.len() - start) * 8;
+        self.count.record_correction_cost(correction, bits as f64);
+    }
+}
+
+impl Default for PlanePredictionEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PredictionEncoder for PlanePredictionEncoder {
+    fn encode_correction(&mut self, action: CodecCorrection, value: u32) {
+        self.count.record_correction(action, value);
+        self.write_varint(action, value);
+    }
+
+    fn encode_bucket_correction(&mut self, action: CodecCorrection, _bucket: u8, value: u32) {
+        self.count.record_correction(action, value);
+        self.write_varint(action, value);
+    }
+
+    fn encode_misprediction(&mut self, action: CodecMisprediction, value: bool) {
+        self.planes[misprediction_plane(action)].push(u8::from(value));
+        self.count.record_misprediction(action, value);
+        self.count.record_misprediction_cost(action, 8.0);
+    }
+
+    fn encode_value(&mut self, value: u16, _max_bits: u8) {
+        self.planes[VALUE_PLANE].extend_from_slice(&value.to_le_bytes());
+        self.count.record_value_cost(16.0);
+    }
+
+    fn encode_verify_state(&mut self, _message: &'static str, _checksum: u64) {}
+
+    fn finish(&mut self) {}
+
+    fn statistics(&self) -> CountNonDefaultActions {
+        self.count.clone()
+    }
+}
+
+/// reads the planes written by PlanePredictionEncoder
+pub struct PlanePredictionDecoder<'a> {
+    planes: Vec<Cow<'a, [u8]>>,
+    /// number of bytes that were read from each plane
+    read: Vec<usize>,
+}
+
+impl<'a> PlanePredictionDecoder<'a> {
+    pub fn new(data: &'a [u8]) -> std::io::Result<Self> {
+        let invalid = |message: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+
+        let (&count, mut rest) = data
+            .split_first()
+            .ok_or_else(|| invalid("missing plane count"))?;
+        if usize::from(count) < PLANE_COUNT {
+            return Err(invalid("too few correction planes"));
+        }
+
+        let mut planes = Vec::with_capacity(count.into());
+        for _ in 0..count {
+            let len = rest
+                .get(0..4)
+                .map(|b| u32::from_le_bytes(b.try_into().unwrap()) as usize)
+                .filter(|&len| len <= rest.len() - 4)
+                .ok_or_else(|| invalid("truncated correction plane"))?;
+
+            planes.push(Cow::Borrowed(&rest[4..4 + len]));
+            rest = &rest[4 + len..];
+        }
+
+        Ok(PlanePredictionDecoder {
+            read: vec![0; planes.len()],
+            planes,
+        })
+    }
+
+    /// the same decoder with a copy of the planes, so that it doesn't borrow the data
+    pub fn into_owned(self) -> PlanePredictionDecoder<'static> {
+        PlanePredictionDecoder {
+            planes: self
+                .planes
+                .into_iter()
+                .map(|plane| Cow::Owned(plane.into_owned()))
+                .collect(),
+            read: self.read,
+        }
+    }
+
+    /// Corrections that run out are read as 0, which makes the recompressed stream differ from
+    /// the original. This is caught by the check of the original stream info.
+    fn read_byte(&mut self, plane: usize) -> u8 {
+        match self.planes[plane].get(self.read[plane]) {
+            Some(&b) => {
+                self.read[plane] += 1;
+                b
+            }
+            None => 0,
+        }
+    }
+
+    fn read_varint(&mut self, correction: CodecCorrection) -> u32 {
+        let plane = correction_plane(correction);
+        let mut value = 0u32;
+        for shift in (0..32).step_by(7) {
+            let b = self.read_byte(plane);
+            value |= u32::from(b & 0x7f) << shift;
+            if b & 0x80 == 0 {
+                break;
+            }
+        }
+        value
+    }
+}
+
+impl PredictionDecoder for PlanePredictionDecoder<'_> {
+    fn decode_value(&mut self, _max_bits_orig: u8) -> u16 {
+        u16::from_le_bytes([self.read_byte(VALUE_PLANE), self.read_byte(VALUE_PLANE)])
+    }
+
+    fn decode_correction(&mut self, correction: CodecCorrection) -> u32 {
+        self.read_varint(correction)
+    }
+
+    fn decode_bucket_correction(&mut self, correction: CodecCorrection, _bucket: u8) -> u32 {
+        self.read_varint(correction)
+    }
+
+    fn decode_misprediction(&mut self, misprediction: CodecMisprediction) -> bool {
+        self.read_byte(misprediction_plane(misprediction)) != 0
+    }
+
+    fn decode_verify_state(&mut self, _message: &'static str, _checksum: u64) {}
+}
+
+#[test]
+fn roundtrip_planes() {
+    use crate::statistical_codec::{drive_encoder, verify_decoder, CodecAction};
+
+    let actions = [
+        CodecAction::Value(0xbeef, 16),
+        CodecAction::Misprediction(CodecMisprediction::LiteralPredictionWrong, true),
+        CodecAction::Misprediction(CodecMisprediction::LiteralPredictionWrong, false),
+        CodecAction::Correction(CodecCorrection::TokenCount, 100000),
+        CodecAction::Correction(CodecCorrection::BlockTypeCorrection, 0),
+        CodecAction::BucketCorrection(CodecCorrection::LenCorrection, 3, 127),
+        CodecAction::BucketCorrection(CodecCorrection::LenCorrection, 0, 128),
+        CodecAction::Correction(CodecCorrection::DistOnlyCorrection, u32::MAX),
+        CodecAction::VerifyState("block", 1234),
+        CodecAction::Value(7, 3),
+    ];
+
+    let mut encoder = PlanePredictionEncoder::new();
+    drive_encoder(&mut encoder, &actions);
+    encoder.finish();
+
+    // the same kind of correction ends up next to each other
+    assert_eq!(
+        encoder.planes[correction_plane(CodecCorrection::LenCorrection)],
+        [0x7f, 0x80, 0x01]
+    );
+
+    let mut output = Vec::new();
+    encoder.write_planes(&mut output);
+
+    let mut decoder = PlanePredictionDecoder::new(&output).unwrap();
+    verify_decoder(&mut decoder, &actions);
+
+    assert!(PlanePredictionDecoder::new(&output[..output.len() - 1]).is_err());
+}
@@ -0,0 +1,296 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! PNG files store the image as a single zlib stream that is split over one or more consecutive
+//! IDAT chunks, each with its own crc32. The IDAT chunks are replaced by the plain text of the
+//! stream in the expanded file and all the other chunks are kept as they are, so the file can
+//! be recreated byte for byte including the original splitting of the IDAT chunks. The fdAT
+//! chunks of animated PNGs are left as they are.
+//!
+//! All numbers in the corrections are little endian u32. They consist of the offset of the
+//! first IDAT chunk, the length of the plain text of the stream, the number of IDAT chunks
+//! followed by the length of the data of each chunk and how its crc is recreated (0: computed,
+//! 1: the stored value that follows since it doesn't match), the length and bytes of any data
+//! after the end of the zlib stream and then the corrections of the zlib stream. The
+//! corrections are empty if the stream couldn't be expanded and the file was kept as it is.
+
+use std::ops::Range;
+
+use crate::{
+    archive_summary::{ArchiveSummary, EntryOutcome},
+    container::ExpandedFile,
+    preflate_config::PreflateConfig,
+    preflate_error::PreflateError,
+    stopwatch::Stopwatch,
+    zlib_stream::{decompress_zlib_stream, recompress_zlib_stream},
+};
+
+const PNG_SIGNATURE: &[u8; 8] = b"\x89PNG\r\n\x1a\n";
+
+const CRC_COMPUTED: u8 = 0;
+const CRC_STORED: u8 = 1;
+
+/// a chunk of a PNG file
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PngChunk {
+    /// offset in the file of the length that starts the chunk
+    pub offset: usize,
+    pub chunk_type: [u8; 4],
+    /// where the data of the chunk is in the file
+    pub data: Range<usize>,
+    /// the crc32 that is stored after the data
+    pub crc: u32,
+}
+
+impl PngChunk {
+    /// the offset after the crc of the chunk
+    pub fn end(&self) -> usize {
+        self.data.end + 4
+    }
+}
+
+fn chunk_crc(chunk_type: &[u8], data: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(chunk_type);
+    hasher.update(data);
+    hasher.finalize()
+}
+
+/// Returns the chunks of a PNG file up to and including IEND. Anything after IEND isn't
+/// considered part of the file.
+pub fn png_chunks(data: &[u8]) -> Result<Vec<PngChunk>, PreflateError> {
+    if !data.starts_with(PNG_SIGNATURE) {
+        return Err(invalid_data("no PNG signature"));
+    }
+
+    let mut chunks = Vec::new();
+    let mut pos = PNG_SIGNATURE.len();
+    loop {
+        let header = data
+            .get(pos..pos + 8)
+            .ok_or_else(|| invalid_data("truncated chunk header"))?;
+        let len = u32::from_be_bytes(header[0..4].try_into().unwrap()) as usize;
+        let chunk_type: [u8; 4] = header[4..8].try_into().unwrap();
+
+        let data_range = pos + 8..(pos + 8).saturating_add(len);
+        let crc = data
+            .get(data_range.end..data_range.end.saturating_add(4))
+            .ok_or_else(|| invalid_data("truncated chunk"))?;
+
+        let chunk = PngChunk {
+            offset: pos,
+            chunk_type,
+            crc: u32::from_be_bytes(crc.try_into().unwrap()),
+            data: data_range,
+        };
+        pos = chunk.end();
+        chunks.push(chunk);
+
+        if &chunk_type == b"IEND" {
+            return Ok(chunks);
+        }
+    }
+}
+
+/// Expands the image data of a PNG file. If the zlib stream can't be processed, the file is
+/// kept as it is with empty corrections, this only fails if the chunks can't be read.
+pub fn expand_png(data: &[u8], config: &PreflateConfig) -> Result<ExpandedFile, PreflateError> {
+    let chunks = png_chunks(data)?;
+
+    let idat: Vec<&PngChunk> = chunks.iter().filter(|c| &c.chunk_type == b"IDAT").collect();
+    let (first, last) = match (idat.first(), idat.last()) {
+        (Some(first), Some(last)) => (*first, *last),
+        _ => return Err(invalid_data("no IDAT chunks")),
+    };
+    if idat.windows(2).any(|w| w[0].end() != w[1].offset) {
+        return Err(invalid_data("IDAT chunks aren't consecutive"));
+    }
+
+    let mut zlib = Vec::new();
+    for chunk in &idat {
+        zlib.extend_from_slice(&data[chunk.data.clone()]);
+    }
+
+    let mut summary = ArchiveSummary::default();
+    let start = Stopwatch::start();
+    let r = match decompress_zlib_stream(&zlib, config) {
+        Ok(r) => r,
+        Err(e) => {
+            summary.record_failed(EntryOutcome::from_error(&e), start.elapsed());
+            return Ok(ExpandedFile {
+                plain_text: data.to_vec(),
+                corrections: Vec::new(),
+                summary,
+            });
+        }
+    };
+    summary.record_processed(
+        r.compressed_processed,
+        r.plain_text.len(),
+        r.cabac_encoded.len(),
+        &r.profile.name,
+        start.elapsed(),
+    );
+
+    let mut corrections = Vec::new();
+    corrections.extend_from_slice(&(first.offset as u32).to_le_bytes());
+    corrections.extend_from_slice(&(r.plain_text.len() as u32).to_le_bytes());
+    corrections.extend_from_slice(&(idat.len() as u32).to_le_bytes());
+    for chunk in &idat {
+        corrections.extend_from_slice(&(chunk.data.len() as u32).to_le_bytes());
+        if chunk.crc == chunk_crc(b"IDAT", &data[chunk.data.clone()]) {
+            corrections.push(CRC_COMPUTED);
+        } else {
+            corrections.push(CRC_STORED);
+            corrections.extend_from_slice(&chunk.crc.to_le_bytes());
+        }
+    }
+    let trailing = &zlib[r.compressed_processed..];
+    corrections.extend_from_slice(&(trailing.len() as u32).to_le_bytes());
+    corrections.extend_from_slice(trailing);
+    corrections.extend_from_slice(&r.cabac_encoded);
+
+    let mut plain_text = data[..first.offset].to_vec();
+    plain_text.extend_from_slice(&r.plain_text);
+    plain_text.extend_from_slice(&data[last.end()..]);
+
+    Ok(ExpandedFile {
+        plain_text,
+        corrections,
+        summary,
+    })
+}
+
+fn invalid_corrections() -> PreflateError {
+    PreflateError::RecompressFailed(anyhow::anyhow!("invalid PNG corrections"))
+}
+
+/// takes the next len bytes from the corrections
+fn take<'a>(corrections: &mut &'a [u8], len: usize) -> Result<&'a [u8], PreflateError> {
+    let value = corrections.get(..len).ok_or_else(invalid_corrections)?;
+    *corrections = &corrections[len..];
+    Ok(value)
+}
+
+fn take_u32(corrections: &mut &[u8]) -> Result<usize, PreflateError> {
+    Ok(u32::from_le_bytes(take(corrections, 4)?.try_into().unwrap()) as usize)
+}
+
+/// recreates the PNG file from the result of expand_png
+pub fn recreate_png(
+    plain_text: &[u8],
+    corrections: &[u8],
+    config: &PreflateConfig,
+) -> Result<Vec<u8>, PreflateError> {
+    if corrections.is_empty() {
+        return Ok(plain_text.to_vec());
+    }
+
+    let mut corrections = corrections;
+    let offset = take_u32(&mut corrections)?;
+    let image_len = take_u32(&mut corrections)?;
+    let image = offset
+        .checked_add(image_len)
+        .and_then(|end| plain_text.get(offset..end))
+        .ok_or_else(invalid_corrections)?;
+
+    let chunk_count = take_u32(&mut corrections)?;
+    let mut chunks = Vec::new();
+    for _ in 0..chunk_count {
+        let len = take_u32(&mut corrections)?;
+        let crc = match take(&mut corrections, 1)?[0] {
+            CRC_COMPUTED => None,
+            CRC_STORED => Some(take_u32(&mut corrections)? as u32),
+            _ => return Err(invalid_corrections()),
+        };
+        chunks.push((len, crc));
+    }
+
+    let trailing_len = take_u32(&mut corrections)?;
+    let trailing = take(&mut corrections, trailing_len)?;
+
+    let mut zlib = recompress_zlib_stream(image, corrections, config)?;
+    zlib.extend_from_slice(trailing);
+    if chunks.iter().map(|&(len, _)| len).sum::<usize>() != zlib.len() {
+        return Err(invalid_corrections());
+    }
+
+    let mut output = plain_text[..offset].to_vec();
+    let mut rest = &zlib[..];
+    for (len, crc) in chunks {
+        let (data, next) = rest.split_at(len);
+        output.extend_from_slice(&(len as u32).to_be_bytes());
+        output.extend_from_slice(b"IDAT");
+        output.extend_from_slice(data);
+        let crc = crc.unwrap_or_else(|| chunk_crc(b"IDAT", data));
+        output.extend_from_slice(&crc.to_be_bytes());
+        rest = next;
+    }
+    output.extend_from_slice(&plain_text[offset + image_len..]);
+
+    Ok(output)
+}
+
+fn invalid_data(message: &str) -> PreflateError {
+    PreflateError::Io(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("invalid PNG file: {}", message),
+    ))
+}
+
+#[test]
+fn png_roundtrip() {
+    use std::io::Write;
+
+    fn write_chunk(png: &mut Vec<u8>, chunk_type: &[u8], data: &[u8]) {
+        png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        png.extend_from_slice(chunk_type);
+        png.extend_from_slice(data);
+        png.extend_from_slice(&chunk_crc(chunk_type, data).to_be_bytes());
+    }
+
+    let image = crate::process::read_file("sample1.bin");
+    let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::new(9));
+    encoder.write_all(&image).unwrap();
+    let zlib = encoder.finish().unwrap();
+
+    // the IDAT chunks are split at an odd size, as some encoders do
+    let mut png = PNG_SIGNATURE.to_vec();
+    write_chunk(&mut png, b"IHDR", &[0; 13]);
+    write_chunk(&mut png, b"tEXt", b"Comment\0kept as it is");
+    for data in zlib.chunks(1000) {
+        write_chunk(&mut png, b"IDAT", data);
+    }
+    write_chunk(&mut png, b"IEND", &[]);
+
+    let config = PreflateConfig::default();
+    let expanded = expand_png(&png, &config).unwrap();
+    assert_eq!(expanded.summary.entries_processed, 1);
+    assert!(expanded.plain_text.starts_with(&png[..8]));
+    assert!(expanded
+        .plain_text
+        .windows(image.len())
+        .any(|w| w == &image[..]));
+    assert_eq!(
+        recreate_png(&expanded.plain_text, &expanded.corrections, &config).unwrap(),
+        png
+    );
+
+    // a crc that doesn't match is kept
+    let last_idat = png_chunks(&png)
+        .unwrap()
+        .into_iter()
+        .rfind(|c| &c.chunk_type == b"IDAT")
+        .unwrap();
+    png[last_idat.data.end] ^= 1;
+    let expanded = expand_png(&png, &config).unwrap();
+    assert_eq!(
+        recreate_png(&expanded.plain_text, &expanded.corrections, &config).unwrap(),
+        png
+    );
+
+    assert!(expand_png(&png[..png.len() - 1], &config).is_err());
+}
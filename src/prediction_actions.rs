@@ -0,0 +1,340 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! The corrections of a stream as a plain list of the actions of the predictor, before they are
+//! entropy coded. This is for users that want to code the corrections themselves or analyze the
+//! mispredictions, and can be serialized with serde. The list is tied to the context scheme it
+//! was produced with, since the predictor asks for different actions in other versions.
+
+use crate::{
+    preflate_error::PreflateError,
+    process::{read_deflate, write_deflate},
+    statistical_codec::{
+        CodecCorrection, CodecMisprediction, CountNonDefaultActions, PredictionDecoder,
+        PredictionEncoder, CONTEXT_SCHEME_VERSION,
+    },
+};
+
+/// one action of the predictor, in the order that the predictor asks for them
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(tag = "action", rename_all = "snake_case")
+)]
+pub enum PredictionAction {
+    /// whether the prediction was wrong
+    Misprediction {
+        context: CodecMisprediction,
+        value: bool,
+    },
+    /// a correction, where 0 means that the prediction was right
+    Correction {
+        context: CodecCorrection,
+        value: u32,
+    },
+    /// a correction that is modeled separately for each bucket
+    BucketCorrection {
+        context: CodecCorrection,
+        bucket: u8,
+        value: u32,
+    },
+    /// a value that isn't predicted, which fits into max_bits bits
+    Value { value: u16, max_bits: u8 },
+}
+
+/// All actions of the predictor for one stream. The verify states of the predictor are
+/// left out, so they aren't checked when the stream is recompressed.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PredictionActions {
+    /// the CONTEXT_SCHEME_VERSION that the actions were produced with
+    pub context_scheme_version: u16,
+    pub actions: Vec<PredictionAction>,
+}
+
+impl Default for PredictionActions {
+    fn default() -> Self {
+        Self {
+            context_scheme_version: CONTEXT_SCHEME_VERSION,
+            actions: Vec::new(),
+        }
+    }
+}
+
+impl PredictionActions {
+    /// the actions that were mispredictions or corrections other than 0
+    pub fn non_default(&self) -> impl Iterator<Item = (usize, &PredictionAction)> {
+        self.actions.iter().enumerate().filter(|(_, a)| match a {
+            PredictionAction::Misprediction { value, .. } => *value,
+            PredictionAction::Correction { value, .. }
+            | PredictionAction::BucketCorrection { value, .. } => *value != 0,
+            PredictionAction::Value { .. } => false,
+        })
+    }
+}
+
+impl PredictionEncoder for PredictionActions {
+    fn encode_correction(&mut self, action: CodecCorrection, value: u32) {
+        self.actions.push(PredictionAction::Correction {
+            context: action,
+            value,
+        });
+    }
+
+    fn encode_bucket_correction(&mut self, action: CodecCorrection, bucket: u8, value: u32) {
+        self.actions.push(PredictionAction::BucketCorrection {
+            context: action,
+            bucket,
+            value,
+        });
+    }
+
+    fn encode_misprediction(&mut self, action: CodecMisprediction, value: bool) {
+        self.actions.push(PredictionAction::Misprediction {
+            context: action,
+            value,
+        });
+    }
+
+    fn encode_value(&mut self, value: u16, max_bits: u8) {
+        self.actions
+            .push(PredictionAction::Value { value, max_bits });
+    }
+
+    fn encode_verify_state(&mut self, _message: &'static str, _checksum: u64) {}
+
+    fn finish(&mut self) {}
+
+    /// the counts of the actions, without any cost since nothing is entropy coded
+    fn statistics(&self) -> CountNonDefaultActions {
+        let mut count = CountNonDefaultActions::default();
+        for action in &self.actions {
+            match *action {
+                PredictionAction::Misprediction { context, value } => {
+                    count.record_misprediction(context, value)
+                }
+                PredictionAction::Correction { context, value }
+                | PredictionAction::BucketCorrection { context, value, .. } => {
+                    count.record_correction(context, value)
+                }
+                PredictionAction::Value { .. } => {}
+            }
+        }
+        count
+    }
+}
+
+/// Returns the actions of a list to the predictor. Once an action is asked for that doesn't
+/// match the next one of the list, everything decodes as 0 or false and the index of that
+/// action is kept, since the decoder can't fail.
+pub struct PredictionActionsDecoder<'a> {
+    actions: &'a [PredictionAction],
+    index: usize,
+    divergence: Option<usize>,
+}
+
+impl<'a> PredictionActionsDecoder<'a> {
+    pub fn new(actions: &'a PredictionActions) -> Self {
+        Self {
+            actions: &actions.actions,
+            index: 0,
+            divergence: None,
+        }
+    }
+
+    /// the index of the first action that didn't match what the predictor asked for, or the
+    /// length of the list if the predictor asked for more actions than it has
+    pub fn divergence(&self) -> Option<usize> {
+        self.divergence
+    }
+
+    /// true if all actions were used
+    pub fn is_finished(&self) -> bool {
+        self.index == self.actions.len()
+    }
+
+    fn next(&mut self, f: impl FnOnce(&PredictionAction) -> Option<u32>) -> u32 {
+        if self.divergence.is_some() {
+            return 0;
+        }
+
+        match self.actions.get(self.index).and_then(f) {
+            Some(value) => {
+                self.index += 1;
+                value
+            }
+            None => {
+                self.divergence = Some(self.index);
+                0
+            }
+        }
+    }
+}
+
+impl PredictionDecoder for PredictionActionsDecoder<'_> {
+    fn decode_value(&mut self, max_bits_orig: u8) -> u16 {
+        self.next(|a| match *a {
+            PredictionAction::Value { value, max_bits } if max_bits == max_bits_orig => {
+                Some(u32::from(value))
+            }
+            _ => None,
+        }) as u16
+    }
+
+    fn decode_correction(&mut self, correction: CodecCorrection) -> u32 {
+        self.next(|a| match *a {
+            PredictionAction::Correction { context, value } if context == correction => Some(value),
+            _ => None,
+        })
+    }
+
+    fn decode_bucket_correction(&mut self, correction: CodecCorrection, bucket: u8) -> u32 {
+        self.next(|a| match *a {
+            PredictionAction::BucketCorrection {
+                context,
+                bucket: b,
+                value,
+            } if context == correction && b == bucket => Some(value),
+            _ => None,
+        })
+    }
+
+    fn decode_misprediction(&mut self, misprediction: CodecMisprediction) -> bool {
+        self.next(|a| match *a {
+            PredictionAction::Misprediction { context, value } if context == misprediction => {
+                Some(u32::from(value))
+            }
+            _ => None,
+        }) != 0
+    }
+
+    fn decode_verify_state(&mut self, _message: &'static str, _checksum: u64) {}
+}
+
+/// the plain text of a stream along with the actions that recreate the stream from it
+#[derive(Debug, Clone)]
+pub struct RawDecompressResult {
+    pub plain_text: Vec<u8>,
+    pub actions: PredictionActions,
+    /// the number of bytes of the compressed data that were processed
+    pub compressed_processed: usize,
+}
+
+/// Decompresses a deflate stream and returns the actions of the predictor instead of coding
+/// them with cabac.
+pub fn decompress_deflate_stream_to_actions(
+    compressed_data: &[u8],
+) -> Result<RawDecompressResult, PreflateError> {
+    let mut actions = PredictionActions::default();
+    let (compressed_processed, _params, plain_text, _original_blocks) =
+        read_deflate(compressed_data, &mut actions, 0)?;
+
+    Ok(RawDecompressResult {
+        plain_text,
+        actions,
+        compressed_processed,
+    })
+}
+
+/// Recompresses the stream from the plain text and the actions that were returned by
+/// decompress_deflate_stream_to_actions. Fails with CorruptCorrections, with the index of the
+/// action as the offset, if the actions don't match what the predictor asks for.
+pub fn recompress_deflate_stream_from_actions(
+    plain_text: &[u8],
+    actions: &PredictionActions,
+) -> Result<Vec<u8>, PreflateError> {
+    if actions.context_scheme_version != CONTEXT_SCHEME_VERSION {
+        return Err(PreflateError::CorruptCorrections(
+            0,
+            anyhow::anyhow!(
+                "actions have context scheme {}, expected {}",
+                actions.context_scheme_version,
+                CONTEXT_SCHEME_VERSION
+            ),
+        ));
+    }
+
+    let mut decoder = PredictionActionsDecoder::new(actions);
+    let result = write_deflate(plain_text, &mut decoder);
+
+    if let Some(index) = decoder.divergence() {
+        return Err(PreflateError::CorruptCorrections(
+            index,
+            anyhow::anyhow!("action {} is not the one the predictor asked for", index),
+        ));
+    }
+    let (recompressed, _blocks) = result?;
+
+    if !decoder.is_finished() {
+        return Err(PreflateError::CorruptCorrections(
+            decoder.index,
+            anyhow::anyhow!(
+                "{} actions were left over",
+                actions.actions.len() - decoder.index
+            ),
+        ));
+    }
+
+    Ok(recompressed)
+}
+
+#[test]
+fn roundtrip_actions() {
+    let compressed_data = crate::process::read_file("compressed_zlib_level3.deflate");
+
+    let r = decompress_deflate_stream_to_actions(&compressed_data).unwrap();
+    assert_eq!(r.compressed_processed, compressed_data.len());
+    assert_eq!(
+        r.actions.statistics().total_non_default as usize,
+        r.actions.non_default().count()
+    );
+
+    assert_eq!(
+        recompress_deflate_stream_from_actions(&r.plain_text, &r.actions).unwrap(),
+        compressed_data
+    );
+
+    // a list that was cut off runs out before the stream is done
+    let mut cut = r.actions.clone();
+    cut.actions.truncate(cut.actions.len() / 2);
+    assert!(matches!(
+        recompress_deflate_stream_from_actions(&r.plain_text, &cut),
+        Err(PreflateError::CorruptCorrections(i, _)) if i == cut.actions.len()
+    ));
+
+    // a list with an extra action has one left over
+    let mut extra = r.actions.clone();
+    extra.actions.push(PredictionAction::Value {
+        value: 1,
+        max_bits: 1,
+    });
+    assert!(matches!(
+        recompress_deflate_stream_from_actions(&r.plain_text, &extra),
+        Err(PreflateError::CorruptCorrections(..))
+    ));
+
+    let mut old = r.actions.clone();
+    old.context_scheme_version -= 1;
+    assert!(recompress_deflate_stream_from_actions(&r.plain_text, &old).is_err());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn serialize_actions() {
+    let compressed_data = crate::process::read_file("compressed_zlib_level1.deflate");
+
+    let r = decompress_deflate_stream_to_actions(&compressed_data).unwrap();
+    let json = serde_json::to_string(&r.actions).unwrap();
+    assert!(json.contains(r#"{"action":"misprediction","context":"#));
+
+    let actions: PredictionActions = serde_json::from_str(&json).unwrap();
+    assert_eq!(actions, r.actions);
+    assert_eq!(
+        recompress_deflate_stream_from_actions(&r.plain_text, &actions).unwrap(),
+        compressed_data
+    );
+}
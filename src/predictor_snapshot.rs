@@ -0,0 +1,93 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Snapshots of the predictor at block boundaries. A snapshot contains everything needed to
+//! continue predicting from the start of its block, so the blocks of a stream can be handed
+//! out in ranges to other threads or machines (the snapshots can be serialized with serde).
+
+use crate::{
+    hash_chain::HashChainSnapshot,
+    match_predictor::ZlibMatchPredictor,
+    preflate_error::PreflateError,
+    preflate_parameter_estimator::PreflateParameters,
+    process::{parse_deflate, snapshot_blocks, verify_blocks_from_snapshot},
+};
+
+/// the state of the predictor at the start of a block
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PredictorSnapshot {
+    /// index of the block that is predicted next
+    pub block_index: usize,
+    /// position in the plain text of the start of the block
+    pub input_pos: u32,
+    params: PreflateParameters,
+    hash_chain: HashChainSnapshot,
+}
+
+impl PredictorSnapshot {
+    /// the parameters that were estimated for the whole stream
+    pub fn params(&self) -> &PreflateParameters {
+        &self.params
+    }
+}
+
+/// Decompresses the stream and takes a snapshot of the predictor at the start of every
+/// `interval`-th block. Taking the snapshots only replays the hash chain updates, so
+/// it is much cheaper than predicting the stream.
+pub fn create_predictor_snapshots(
+    compressed_data: &[u8],
+    interval: usize,
+) -> Result<Vec<PredictorSnapshot>, PreflateError> {
+    let (plain_text, blocks, params) = parse_deflate(compressed_data)?;
+
+    Ok(snapshot_blocks(
+        &plain_text,
+        &params,
+        &blocks,
+        interval,
+        &ZlibMatchPredictor::default(),
+    )
+    .into_iter()
+    .map(|(block_index, input_pos, hash_chain)| PredictorSnapshot {
+        block_index,
+        input_pos,
+        params,
+        hash_chain,
+    })
+    .collect())
+}
+
+/// Predicts `block_count` blocks starting at the block of the snapshot, and checks that the
+/// blocks are recreated exactly from the resulting corrections. The stream can be verified by
+/// several workers in parallel by giving each of them a different snapshot.
+pub fn verify_block_range(
+    compressed_data: &[u8],
+    snapshot: &PredictorSnapshot,
+    block_count: usize,
+) -> Result<(), PreflateError> {
+    let (plain_text, blocks, _params) = parse_deflate(compressed_data)?;
+
+    let end = snapshot.block_index.saturating_add(block_count);
+    if end > blocks.len() {
+        return Err(PreflateError::Mismatch(anyhow::anyhow!(
+            "block range {}..{} is past the end of the stream with {} blocks",
+            snapshot.block_index,
+            end,
+            blocks.len()
+        )));
+    }
+
+    verify_blocks_from_snapshot(
+        &plain_text,
+        &snapshot.params,
+        &blocks,
+        snapshot.block_index..end,
+        snapshot.input_pos,
+        &snapshot.hash_chain,
+        &ZlibMatchPredictor::default(),
+    )
+}
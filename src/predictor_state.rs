@@ -5,13 +5,22 @@
  *--------------------------------------------------------------------------------------------*/
 
 use crate::bit_helper::DebugHash;
-use crate::hash_chain::{HashChain, RotatingHashTrait};
+use crate::hash_chain::{HashChain, HashChainSnapshot, RotatingHashTrait};
 use crate::preflate_constants::{MAX_MATCH, MIN_LOOKAHEAD, MIN_MATCH};
+use crate::preflate_error::{TokenContext, TokenError, TokenErrorKind};
 use crate::preflate_input::PreflateInput;
 use crate::preflate_parameter_estimator::PreflateParameters;
-use crate::preflate_token::PreflateTokenReference;
+use crate::preflate_token::{FlushMarker, IrregularEncoding, PreflateTokenReference};
 use std::cmp;
 
+/// largest number of bytes that update_hash inserts with a single reshift check
+const MAX_HASH_BATCH: u32 = 0x180;
+
+/// Most entries of a hash chain that are walked for a single position. Input that degenerates
+/// the chains (such as long runs of the same 3 byte pattern) would otherwise take quadratic
+/// time, so a match that lies deeper than this is corrected with its distance instead of hops.
+pub const MAX_CHAIN_WALK: u32 = 4096;
+
 #[derive(Debug, Copy, Clone)]
 pub enum MatchResult {
     Success(PreflateTokenReference),
@@ -32,6 +41,9 @@ pub struct PredictorState<'a, H: RotatingHashTrait> {
     input: PreflateInput<'a>,
     params: PreflateParameters,
     window_bytes: u32,
+
+    /// number of bytes before the current position that still have to be added to the hash chain
+    pending_hash_update: u32,
 }
 
 impl<'a, H: RotatingHashTrait> PredictorState<'a, H> {
@@ -41,7 +53,31 @@ impl<'a, H: RotatingHashTrait> PredictorState<'a, H> {
             window_bytes: 1 << params.window_bits,
             params: *params,
             input: PreflateInput::new(uncompressed),
+            pending_hash_update: 0,
+        }
+    }
+
+    /// recreates the state at the given position of the input from a snapshot of the hash chain
+    pub fn from_snapshot(
+        uncompressed: &'a [u8],
+        params: &PreflateParameters,
+        input_pos: u32,
+        snapshot: &HashChainSnapshot,
+    ) -> Result<Self, TokenError> {
+        if input_pos as usize > uncompressed.len() {
+            return Err(TokenErrorKind::NotEnoughInput)
+                .with_context(|| format!("snapshot at {}", input_pos));
         }
+
+        let mut r = Self::new(uncompressed, params);
+        r.hash = HashChain::from_snapshot(params.hash_shift, params.hash_mask, snapshot)?;
+        r.input.advance(input_pos);
+        Ok(r)
+    }
+
+    pub fn hash_snapshot(&self) -> HashChainSnapshot {
+        debug_assert!(self.pending_hash_update == 0);
+        self.hash.snapshot()
     }
 
     #[allow(dead_code)]
@@ -54,15 +90,55 @@ impl<'a, H: RotatingHashTrait> PredictorState<'a, H> {
     }
 
     pub fn update_hash(&mut self, length: u32) {
+        self.flush_hash();
         self.hash.update_hash::<false>(length, &self.input);
         self.input.advance(length);
     }
 
     pub fn skip_hash(&mut self, length: u32) {
+        self.flush_hash();
         self.hash.skip_hash::<false>(length, &self.input);
         self.input.advance(length);
     }
 
+    /// updates the hash chain at a flush marker
+    pub fn apply_flush_marker(&mut self, flush: FlushMarker) {
+        self.flush_hash();
+        self.hash.apply_flush_marker::<false>(flush, &self.input);
+    }
+
+    /// Advances the input but only adds the bytes to the hash chain once flush_hash is called,
+    /// so that runs of tokens that are never matched against can be inserted in one go.
+    /// Nothing may query the hash chain until it has been flushed, so this is only for blocks
+    /// that are skipped (see TokenPredictor::skip_block). Predicting or recreating a block
+    /// searches the chain at every position, which needs all of the positions before it.
+    pub fn update_hash_deferred(&mut self, length: u32) {
+        // a batch is always inserted with a single update, and the table needs to be
+        // reshifted at the same token as with separate updates, otherwise the stale
+        // entries (and therefore the state checksum) would differ
+        if self.pending_hash_update + length > MAX_HASH_BATCH
+            || self.hash.needs_reshift(self.input.pos())
+        {
+            self.flush_hash();
+        }
+
+        self.pending_hash_update += length;
+        self.input.advance(length);
+    }
+
+    /// adds the bytes that were deferred by update_hash_deferred to the hash chain. This
+    /// gives the same chain as calling update_hash for each of them separately.
+    pub fn flush_hash(&mut self) {
+        if self.pending_hash_update > 0 {
+            let mut input = self.input;
+            input.rewind(self.pending_hash_update);
+
+            self.hash
+                .update_hash::<false>(self.pending_hash_update, &input);
+            self.pending_hash_update = 0;
+        }
+    }
+
     pub fn current_input_pos(&self) -> u32 {
         self.input.pos()
     }
@@ -99,8 +175,17 @@ impl<'a, H: RotatingHashTrait> PredictorState<'a, H> {
         self.hash.cur_plus_1_hash(&self.input)
     }
 
-    fn prefix_compare(s1: &[u8], s2: &[u8], best_len: u32, max_len: u32) -> u32 {
-        assert!(max_len >= 3 && s1.len() >= max_len as usize && s2.len() >= max_len as usize);
+    /// the hash of the position offset bytes ahead, which needs NUM_HASH_BYTES bytes there
+    pub fn calculate_hash_at(&self, offset: u32) -> H {
+        self.hash.cur_plus_n_hash(&self.input, offset)
+    }
+
+    /// returns the length of the common prefix of both windows, which need to have the same
+    /// length (the maximum match length). Returns 0 if the match can't be longer than best_len.
+    fn prefix_compare(s1: &[u8], s2: &[u8], best_len: u32) -> u32 {
+        let len = s1.len();
+        let s2 = &s2[..len];
+        debug_assert!(len >= 3 && (best_len as usize) < len);
 
         if s1[best_len as usize] != s2[best_len as usize] {
             return 0;
@@ -109,15 +194,23 @@ impl<'a, H: RotatingHashTrait> PredictorState<'a, H> {
             return 0;
         }
 
-        let mut match_len = 3; // Initialize with the length of the fixed prefix
-        for i in 3..max_len {
-            if s1[i as usize] != s2[i as usize] {
-                break;
+        // compare a word at a time, the windows have the same length so
+        // the compiler can drop most of the bounds checks
+        let mut i = 3;
+        while i + 8 <= len {
+            let a = u64::from_le_bytes(s1[i..i + 8].try_into().unwrap());
+            let b = u64::from_le_bytes(s2[i..i + 8].try_into().unwrap());
+            if a != b {
+                return (i + ((a ^ b).trailing_zeros() / 8) as usize) as u32;
             }
-            match_len = i + 1;
+            i += 8;
         }
 
-        match_len
+        while i < len && s1[i] == s2[i] {
+            i += 1;
+        }
+
+        i as u32
     }
 
     pub fn match_token(&self, hash: H, prev_len: u32, offset: u32, max_depth: u32) -> MatchResult {
@@ -148,10 +241,10 @@ impl<'a, H: RotatingHashTrait> PredictorState<'a, H> {
         let mut max_chain;
         let nice_len;
         if max_depth > 0 {
-            max_chain = max_depth;
+            max_chain = cmp::min(max_depth, MAX_CHAIN_WALK);
             nice_len = max_len;
         } else {
-            max_chain = self.params.max_chain; // max hash chain length
+            max_chain = cmp::min(self.params.max_chain, MAX_CHAIN_WALK); // max hash chain length
             nice_len = std::cmp::min(self.params.nice_length, max_len);
 
             if prev_len >= self.params.good_length {
@@ -171,15 +264,22 @@ impl<'a, H: RotatingHashTrait> PredictorState<'a, H> {
 
         let mut best_len = prev_len;
         let mut best_match: Option<PreflateTokenReference> = None;
-        let input = self.input.cur_chars(offset as i32);
+        let input = self.input.cur_window(offset as i32, max_len);
+        let mut depth = 0;
         loop {
             let dist = chain_it.dist();
 
-            let match_start = self.input.cur_chars(offset as i32 - dist as i32);
+            let match_start = self.input.cur_window(offset as i32 - dist as i32, max_len);
 
-            let match_length = Self::prefix_compare(match_start, input, best_len, max_len);
-            if match_length > best_len {
-                let r = PreflateTokenReference::new(match_length, chain_it.dist(), false);
+            let match_length = Self::prefix_compare(match_start, input, best_len);
+            if match_length > best_len
+                && (match_length > MIN_MATCH || depth <= u32::from(self.params.max_depth_3_matches))
+            {
+                let r = PreflateTokenReference::new(
+                    match_length,
+                    chain_it.dist(),
+                    IrregularEncoding::Canonical,
+                );
 
                 if match_length >= nice_len {
                     return MatchResult::Success(r);
@@ -201,6 +301,7 @@ impl<'a, H: RotatingHashTrait> PredictorState<'a, H> {
             }
 
             max_chain -= 1;
+            depth += 1;
 
             if max_chain == 0 {
                 if let Some(r) = best_match {
@@ -213,14 +314,16 @@ impl<'a, H: RotatingHashTrait> PredictorState<'a, H> {
     }
 
     /// Tries to find the match by continuing on the hash chain, returns how many hops we went
-    /// or none if it wasn't found
-    pub fn calculate_hops(&self, target_reference: &PreflateTokenReference) -> anyhow::Result<u32> {
+    /// or none if it isn't within the first MAX_CHAIN_WALK entries of the chain
+    pub fn calculate_hops(
+        &self,
+        target_reference: &PreflateTokenReference,
+    ) -> Result<Option<u32>, TokenError> {
         let hash = self.hash.cur_hash(&self.input);
 
-        let max_len = std::cmp::min(self.available_input_size(), MAX_MATCH);
-
-        if max_len < target_reference.len() {
-            return Err(anyhow::anyhow!("max_len < target_reference.len()"));
+        // deflate64 matches can be longer than MAX_MATCH
+        if self.available_input_size() < target_reference.len() {
+            return Err(TokenErrorKind::NotEnoughInput.into());
         }
 
         let max_dist = self.window_size();
@@ -229,18 +332,17 @@ impl<'a, H: RotatingHashTrait> PredictorState<'a, H> {
 
         let mut chain_it = self.hash.iterate_from_head(hash, cur_pos, cur_max_dist);
         if !chain_it.valid() {
-            return Err(anyhow::anyhow!("no valid chain_it"));
+            return Ok(None);
         }
 
-        let max_chain_org = 0xffff; // max hash chain length
-        let mut max_chain = max_chain_org; // max hash chain length
+        let mut max_chain = MAX_CHAIN_WALK;
         let best_len = target_reference.len();
         let mut hops = 0;
 
+        let input = self.input.cur_window(0, best_len);
         loop {
-            let match_pos = self.input_cursor_offset(-(chain_it.dist() as i32));
-            let match_length =
-                Self::prefix_compare(match_pos, self.input_cursor(), best_len - 1, best_len);
+            let match_pos = self.input.cur_window(-(chain_it.dist() as i32), best_len);
+            let match_length = Self::prefix_compare(match_pos, input, best_len - 1);
 
             if match_length >= best_len {
                 hops += 1;
@@ -248,7 +350,7 @@ impl<'a, H: RotatingHashTrait> PredictorState<'a, H> {
 
             if chain_it.dist() >= target_reference.dist() {
                 if chain_it.dist() == target_reference.dist() {
-                    return Ok(hops);
+                    return Ok(Some(hops));
                 } else {
                     break;
                 }
@@ -261,15 +363,14 @@ impl<'a, H: RotatingHashTrait> PredictorState<'a, H> {
             max_chain -= 1;
         }
 
-        Err(anyhow::anyhow!("no match found"))
+        Ok(None)
     }
 
     /// Does the inverse of calculate_hops, where we start from the predicted token and
     /// get the new distance based on the number of hops
-    pub fn hop_match(&self, len: u32, hops: u32) -> anyhow::Result<u32> {
-        let max_len = std::cmp::min(self.available_input_size(), MAX_MATCH);
-        if max_len < len {
-            return Err(anyhow::anyhow!("not enough data left to match"));
+    pub fn hop_match(&self, len: u32, hops: u32) -> Result<u32, TokenError> {
+        if self.available_input_size() < len {
+            return Err(TokenErrorKind::NotEnoughInput.into());
         }
 
         let cur_pos = self.current_input_pos();
@@ -279,17 +380,18 @@ impl<'a, H: RotatingHashTrait> PredictorState<'a, H> {
 
         let mut chain_it = self.hash.iterate_from_head(hash, cur_pos, cur_max_dist);
         if !chain_it.valid() {
-            return Err(anyhow::anyhow!("no match found"));
+            return Err(TokenErrorKind::NoMatchFound.into());
         }
 
         let mut current_hop = 0;
+        let mut max_chain = MAX_CHAIN_WALK;
 
+        let input = self.input.cur_window(0, len);
         loop {
             let match_length = Self::prefix_compare(
-                self.input_cursor_offset(-(chain_it.dist() as i32)),
-                self.input_cursor(),
+                self.input.cur_window(-(chain_it.dist() as i32), len),
+                input,
                 len - 1,
-                len,
             );
 
             if match_length >= len {
@@ -299,9 +401,11 @@ impl<'a, H: RotatingHashTrait> PredictorState<'a, H> {
                 }
             }
 
-            if !chain_it.next() {
-                return Err(anyhow::anyhow!("no match found"));
+            if !chain_it.next() || max_chain <= 1 {
+                return Err(TokenErrorKind::NoMatchFound.into());
             }
+
+            max_chain -= 1;
         }
     }
 
@@ -0,0 +1,334 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::sync::Arc;
+
+use crate::{
+    compressor_profile::{CompressorProfile, CompressorProfileRegistry},
+    model_dictionary::ModelDictionary,
+    preflate_error::PreflateError,
+    preflate_parse_config::ParserConfigRegistry,
+    progress::{CancellationToken, ProgressCallback},
+    secondary_compression::{SecondaryCompression, SecondaryCompressorRegistry},
+    stream_cache::StreamCache,
+};
+
+/// How much of the roundtrip is checked before decompress_deflate_stream returns
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum VerifyMode {
+    /// no verification, the caller is responsible for checking the result
+    None,
+
+    /// recompress the entire stream from the cabac data and compare it with the original
+    Full,
+
+    /// Same as Full, but the recompressed stream is compared with the original block by block
+    /// as it is produced instead of being collected first. This needs a fixed amount of memory
+    /// on top of the plain text, even for streams of several gigabytes.
+    Streaming,
+
+    /// replay the prediction for every n-th block (plus the final block) and compare
+    /// the recreated tokens and huffman trees with the original ones. The remaining
+    /// blocks are only used to bring the predictor up to date, which is much cheaper than predicting.
+    /// The cabac corrections of all the blocks are decoded and compared with what was predicted,
+    /// and the length and crc32 of the stream stored in them are checked.
+    Strided(u32),
+
+    /// replay the prediction for a pseudo-random subset of the blocks (plus the final block), where
+    /// `percent` is the fraction of blocks that get verified. The same seed always selects the same blocks.
+    Random { percent: u8, seed: u64 },
+}
+
+impl VerifyMode {
+    /// returns true if the block with the given index should be verified
+    pub fn should_verify_block(&self, block_index: usize, block_count: usize) -> bool {
+        if block_index + 1 == block_count {
+            return !matches!(self, VerifyMode::None);
+        }
+
+        match *self {
+            VerifyMode::None => false,
+            VerifyMode::Full | VerifyMode::Streaming => true,
+            VerifyMode::Strided(stride) => stride <= 1 || block_index % stride as usize == 0,
+            VerifyMode::Random { percent, seed } => {
+                // splitmix64 of the block index, so that the selection doesn't depend on the order of calls
+                let mut z =
+                    seed.wrapping_add((block_index as u64 + 1).wrapping_mul(0x9e3779b97f4a7c15));
+                z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+                z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+                z ^= z >> 31;
+
+                z % 100 < u64::from(percent)
+            }
+        }
+    }
+}
+
+/// How the corrections needed to recreate the stream are encoded. Json only exists with the
+/// `serde` feature, so matches outside of the crate need a wildcard arm to build either way.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum CorrectionCodec {
+    /// arithmetic coded, this is the compact format that should normally be used
+    Cabac,
+
+    /// every prediction action written as a line of JSON, used for debugging prediction problems
+    #[cfg(feature = "serde")]
+    Json,
+}
+
+/// how the probabilities of the cabac codec are modeled. The model is stored in the first byte
+/// of the corrections, so it doesn't need to be specified again when recompressing.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ProbabilityModel {
+    /// the probabilities adapt to the corrections seen so far, which gives the smallest output
+    Adaptive = 0,
+
+    /// fixed probabilities that are measured over the whole stream and stored in front of the
+    /// corrections. The output is slightly larger, but the decoder doesn't update a model and
+    /// decoding a correction doesn't depend on the statistics of the corrections before it.
+    Static = 1,
+}
+
+/// The arithmetic coder used by the adaptive probability model of the cabac codec. This is
+/// stored in the first byte of the corrections together with the probability model.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CabacBackend {
+    /// boolean coder from VP8, whose probabilities are the counts of the recent symbols
+    Vp8 = 0,
+
+    /// the CABAC coder from H.265, which tracks the probabilities with a small state machine
+    H265 = 1,
+
+    /// binary range coder in the style of LZMA, whose probabilities move a fixed fraction
+    /// toward every coded bit and have more precision than the ones of VP8
+    Lzma = 2,
+}
+
+/// What happens to the adaptive probabilities of the cabac codec at the start of each deflate
+/// block. This is stored in the first byte of the corrections together with the probability model.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ModelReset {
+    /// the probabilities keep adapting over the whole stream
+    Keep = 0,
+
+    /// every block starts with the initial probabilities, so decoding a block doesn't depend
+    /// on the corrections of the blocks before it
+    Reset = 1,
+
+    /// every block starts with the probabilities learned so far, but with the weight of only a few
+    /// observations, which helps for streams whose statistics change between blocks
+    Rescale = 2,
+}
+
+/// options that control how a deflate stream is processed
+#[derive(Debug, Clone)]
+pub struct PreflateConfig {
+    /// how much verification to do after the stream has been decompressed
+    pub verify: VerifyMode,
+
+    /// the codec used to encode the corrections. The same codec needs to be
+    /// used when recompressing.
+    pub codec: CorrectionCodec,
+
+    /// the probability model used if the codec is cabac
+    pub probability_model: ProbabilityModel,
+
+    /// the arithmetic coder used if the probability model is adaptive
+    pub cabac_backend: CabacBackend,
+
+    /// Store the mispredictions and the corrections of the cabac codec in two separate streams,
+    /// which is smaller and faster to decode. The layout is recorded in the corrections, so
+    /// corrections written with either layout can be recompressed regardless of this setting.
+    pub split_channels: bool,
+
+    /// Write each kind of correction uncompressed to its own plane instead of arithmetic coding
+    /// them, for when the corrections are compressed afterwards with a generic compressor such as
+    /// zstd, which does much better on the planes than on the arithmetic coded output. The
+    /// probability model and the settings that go with it are then ignored. The layout is
+    /// recorded in the corrections, so this is only needed when decompressing.
+    pub correction_planes: bool,
+
+    /// what happens to the probabilities of the adaptive model at block boundaries. The
+    /// static model doesn't adapt, so this has no effect on it.
+    pub model_reset: ModelReset,
+
+    /// how many levels of deflate streams embedded in the plain text (zlib, gzip or zip entries)
+    /// are expanded as well. The corrections then contain the corrections of all the nested
+    /// streams, so the same setting has to be used when recompressing. 0 disables this.
+    pub nested_depth: u32,
+
+    /// Additional good/lazy/nice/chain settings that the estimator considers, for compressors
+    /// with patched level tables. Registering any of them also stops the estimator from matching
+    /// the streams against the compressor profiles. Only needed when decompressing.
+    pub parser_configs: ParserConfigRegistry,
+
+    /// the profiles that can be selected with compressor_profile, which the estimator
+    /// also matches the streams against if no profile is selected
+    pub compressor_profiles: CompressorProfileRegistry,
+
+    /// The name of the profile of the compressor that produced the streams. The parameters are
+    /// then taken from the profile instead of being estimated, which is faster and avoids
+    /// misestimating them for unusual streams. A stream that allows matches the profile rules
+    /// out fails to decompress. Only needed when decompressing.
+    pub compressor_profile: Option<String>,
+
+    /// Estimate the parameters again from the following blocks when the mispredictions of a
+    /// block spike, for streams that change their settings partway such as concatenated streams
+    /// or streams that were flushed and continued at another level. The new parameters are
+    /// stored in the corrections before the block they start at, so this is only needed when
+    /// decompressing. Only applies if the blocks are predicted on a single thread and no
+    /// compressor profile is selected.
+    pub reestimate_parameters: bool,
+
+    /// Accept stored blocks whose NLEN isn't the complement of LEN, which some broken encoders
+    /// write and some inflate implementations accept. The exact NLEN is kept in the corrections,
+    /// so this is only needed when decompressing.
+    pub lenient_stored_len: bool,
+
+    /// Cache for the corrections of streams that were already decompressed, looked up by the
+    /// hash of the compressed data. A stream found in the cache is only inflated, without
+    /// predicting or verifying it again. Only needed when decompressing.
+    pub stream_cache: Option<Arc<dyn StreamCache>>,
+
+    /// Instead of failing on a stream that the predictor can't handle, predict the blocks before
+    /// the one that failed and store the rest of the compressed stream as it is in the
+    /// corrections. Every valid stream can then be recompressed, but the corrections are as large
+    /// as the part that was stored. Only applies to the cabac codec and is only needed when
+    /// decompressing.
+    pub verbatim_fallback: bool,
+
+    /// Read the streams as deflate64, the variant with a 64K window and matches of up to 65538
+    /// bytes that zip uses as compression method 9. The streams are recreated from their tokens,
+    /// so this is only needed when decompressing.
+    pub deflate64: bool,
+
+    /// The compressed size that the container declared for the stream, such as the compressed
+    /// size of a zip entry. The stream then fails to read if it doesn't end within that many
+    /// bytes, instead of reading into the data after it or being treated as truncated. Only
+    /// needed when decompressing.
+    pub compressed_size: Option<u64>,
+
+    /// Number of threads that predict the blocks of each stream, 0 uses all the available cores.
+    /// The blocks are split into groups that are predicted from a snapshot of the predictor at
+    /// their start, so the corrections are the same as with a single thread and this is only
+    /// needed when decompressing.
+    pub prediction_threads: usize,
+
+    /// Largest plain text that a stream may decompress to. Reading stops with LimitExceeded as
+    /// soon as the plain text gets larger, so that a small stream that expands to gigabytes
+    /// can't run the process out of memory. Recompressing fails for larger plain text as well.
+    pub max_plaintext_size: usize,
+
+    /// largest corrections that decompressing may produce, or that recompressing accepts
+    pub max_correction_size: usize,
+
+    /// Largest hash chain length that the estimated parameters may use, which bounds the
+    /// time spent on each match of the plain text. Only needed when decompressing.
+    pub max_chain_limit: u32,
+
+    /// General purpose compressor that the finished corrections are compressed with. Its
+    /// implementation has to be registered in secondary_compressors. The backend is recorded in
+    /// the corrections, so this is only needed when decompressing.
+    pub secondary_compression: SecondaryCompression,
+
+    /// the implementations of the secondary compression backends, which are needed for
+    /// decompressing and recompressing
+    pub secondary_compressors: SecondaryCompressorRegistry,
+
+    /// receives the number of bytes consumed and produced after each block of a stream that is
+    /// decompressed or recompressed
+    pub progress: Option<Arc<dyn ProgressCallback>>,
+
+    /// Stops decompressing or recompressing with PreflateError::Cancelled before the next block
+    /// once it has been cancelled, which lets a long stream be aborted from another thread.
+    pub cancellation: Option<CancellationToken>,
+
+    /// Probabilities that the contexts of the adaptive probability model start with, trained
+    /// on similar streams. The id of the dictionary is recorded in the corrections, and
+    /// recompressing them fails unless the same dictionary is given. Doesn't apply to the
+    /// static model or the correction planes.
+    pub model_dictionary: Option<Arc<ModelDictionary>>,
+}
+
+impl Default for PreflateConfig {
+    fn default() -> Self {
+        PreflateConfig {
+            verify: VerifyMode::Full,
+            codec: CorrectionCodec::Cabac,
+            probability_model: ProbabilityModel::Adaptive,
+            cabac_backend: CabacBackend::Vp8,
+            split_channels: true,
+            correction_planes: false,
+            model_reset: ModelReset::Keep,
+            nested_depth: 0,
+            parser_configs: ParserConfigRegistry::default(),
+            compressor_profiles: CompressorProfileRegistry::default(),
+            compressor_profile: None,
+            reestimate_parameters: false,
+            lenient_stored_len: false,
+            stream_cache: None,
+            verbatim_fallback: false,
+            deflate64: false,
+            compressed_size: None,
+            prediction_threads: 1,
+            max_plaintext_size: usize::MAX,
+            max_correction_size: usize::MAX,
+            max_chain_limit: u32::MAX,
+            secondary_compression: SecondaryCompression::None,
+            secondary_compressors: SecondaryCompressorRegistry::default(),
+            progress: None,
+            cancellation: None,
+            model_dictionary: None,
+        }
+    }
+}
+
+impl PreflateConfig {
+    /// the profile selected by compressor_profile, or an error if no profile with that name
+    /// has been registered
+    pub fn selected_profile(&self) -> Result<Option<&CompressorProfile>, PreflateError> {
+        let Some(name) = &self.compressor_profile else {
+            return Ok(None);
+        };
+
+        match self.compressor_profiles.find(name) {
+            Some(profile) => Ok(Some(profile)),
+            None => Err(PreflateError::UnsupportedCompressor {
+                profile: name.clone(),
+                known: false,
+            }),
+        }
+    }
+}
+
+#[test]
+fn verify_mode_block_selection() {
+    let strided = VerifyMode::Strided(4);
+    let selected: Vec<usize> = (0..10)
+        .filter(|&i| strided.should_verify_block(i, 10))
+        .collect();
+    assert_eq!(selected, [0, 4, 8, 9]);
+
+    // the final block is always verified, regardless of the coverage
+    let random = VerifyMode::Random {
+        percent: 0,
+        seed: 1234,
+    };
+    assert!(random.should_verify_block(99, 100));
+    assert!((0..99).all(|i| !random.should_verify_block(i, 100)));
+
+    let random = VerifyMode::Random {
+        percent: 50,
+        seed: 1234,
+    };
+    let count = (0..1000)
+        .filter(|&i| random.should_verify_block(i, 1000))
+        .count();
+    assert!(count > 400 && count < 600, "count {}", count);
+
+    assert!((0..10).all(|i| !VerifyMode::None.should_verify_block(i, 10)));
+}
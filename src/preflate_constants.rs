@@ -9,6 +9,8 @@ pub const NONLEN_CODE_COUNT: usize = LITERAL_COUNT + 1; // EOB
 pub const LEN_CODE_COUNT: usize = 29;
 pub const LITLEN_CODE_COUNT: usize = NONLEN_CODE_COUNT + LEN_CODE_COUNT;
 pub const DIST_CODE_COUNT: usize = 30;
+/// deflate64 adds two distance codes for the 64K window
+pub const DEFLATE64_DIST_CODE_COUNT: usize = 32;
 pub const LITLENDIST_CODE_COUNT: usize = LITLEN_CODE_COUNT + DIST_CODE_COUNT;
 pub const CODETREE_CODE_COUNT: usize = 19;
 
@@ -17,6 +19,12 @@ pub const MAX_MATCH: u32 = 258;
 
 pub const MIN_LOOKAHEAD: u32 = MAX_MATCH + MIN_MATCH + 1;
 
+/// deflate64 uses length code 285 for lengths of 3 up to 65538 with 16 extra bits
+pub const DEFLATE64_LONG_LENGTH_EXTRA: u8 = 16;
+
+/// the longest match of deflate64, which is also the longest that a token can hold
+pub const DEFLATE64_MAX_MATCH: u32 = MIN_MATCH + u16::MAX as u32;
+
 const DIST_CODE_TABLE: [u8; 512] = [
     0, 1, 2, 3, 4, 4, 5, 5, 6, 6, 6, 6, 7, 7, 7, 7, 8, 8, 8, 8, 8, 8, 8, 8, 9, 9, 9, 9, 9, 9, 9, 9,
     10, 10, 10, 10, 10, 10, 10, 10, 10, 10, 10, 10, 10, 10, 10, 10, 11, 11, 11, 11, 11, 11, 11, 11,
@@ -57,23 +65,28 @@ pub const LENGTH_BASE_TABLE: [u8; LEN_CODE_COUNT] = [
     0, 1, 2, 3, 4, 5, 6, 7, 8, 10, 12, 14, 16, 20, 24, 28, 32, 40, 48, 56, 64, 80, 96, 112, 128,
     160, 192, 224, 255,
 ];
-pub const DIST_BASE_TABLE: [u16; DIST_CODE_COUNT] = [
+/// the last two entries are only used by deflate64
+pub const DIST_BASE_TABLE: [u16; DEFLATE64_DIST_CODE_COUNT] = [
     0, 1, 2, 3, 4, 6, 8, 12, 16, 24, 32, 48, 64, 96, 128, 192, 256, 384, 512, 768, 1024, 1536,
-    2048, 3072, 4096, 6144, 8192, 12288, 16384, 24576,
+    2048, 3072, 4096, 6144, 8192, 12288, 16384, 24576, 32768, 49152,
 ];
 
 pub const LENGTH_EXTRA_TABLE: [u8; LEN_CODE_COUNT] = [
     0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
 ];
-pub const DIST_EXTRA_TABLE: [u8; DIST_CODE_COUNT] = [
+pub const DIST_EXTRA_TABLE: [u8; DEFLATE64_DIST_CODE_COUNT] = [
     0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
-    13,
+    13, 14, 14,
 ];
 pub const TREE_CODE_ORDER_TABLE: [usize; CODETREE_CODE_COUNT] = [
     16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
 ];
 
 pub fn quantize_distance(dist: u32) -> usize {
+    if dist > 32768 {
+        // the deflate64 codes 30 and 31
+        return 28 + ((dist - 1) >> 14) as usize;
+    }
     DIST_CODE_TABLE[if dist <= 256 {
         dist - 1
     } else {
@@ -18,6 +18,327 @@ pub enum PreflateError {
     RecreateBlock(usize, anyhow::Error),
     RecreateTree(usize, anyhow::Error),
     EncodeBlock(usize, anyhow::Error),
+    Io(std::io::Error),
+    /// the corrections are truncated or damaged, along with the offset of the damage
+    CorruptCorrections(usize, anyhow::Error),
+    /// the deflate stream ends before its final block, after the given number of complete blocks
+    Truncated(usize),
+    /// The token at token_index of the block couldn't be predicted from the plain text or
+    /// recreated from the corrections. Unlike the errors while reading, this means that the
+    /// stream was valid but the predictor didn't behave as expected: when decompressing, the
+    /// stream was written by a compressor that the predictor doesn't support.
+    PredictionMismatch {
+        block: usize,
+        token_index: usize,
+        error: TokenError,
+    },
+    /// the stream needs more than one of the limits of the config allows, which is the given maximum
+    LimitExceeded {
+        limit: ResourceLimit,
+        max: u64,
+    },
+    /// the CancellationToken of the config was cancelled before the stream was done
+    Cancelled,
+    /// the block isn't valid deflate, so the input isn't a deflate stream or is damaged
+    CorruptDeflate {
+        block: usize,
+        kind: DeflateError,
+    },
+    /// The compressor profile that the config selected can't be used: it isn't one of the
+    /// profiles of the config if known is false, otherwise the stream wasn't written by it.
+    UnsupportedCompressor {
+        profile: String,
+        known: bool,
+    },
+}
+
+/// what is wrong with a block that isn't valid deflate
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DeflateError {
+    /// the block type is 3, which is reserved
+    InvalidBlockType,
+    /// NLEN of a stored block isn't the complement of LEN
+    StoredLengthMismatch,
+    /// the code lengths of a dynamic block don't form a valid huffman tree
+    InvalidHuffmanTree,
+    /// a length code that deflate doesn't define
+    InvalidLengthCode,
+    /// a distance code that deflate doesn't define
+    InvalidDistanceCode,
+    /// a match refers back to before the start of the plain text
+    DistanceTooFar,
+    /// the stream doesn't end within the compressed size that was declared for it
+    PastDeclaredSize,
+}
+
+impl Display for DeflateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let message = match self {
+            DeflateError::InvalidBlockType => "invalid block type",
+            DeflateError::StoredLengthMismatch => "stored block length mismatch",
+            DeflateError::InvalidHuffmanTree => "invalid huffman tree",
+            DeflateError::InvalidLengthCode => "invalid length code",
+            DeflateError::InvalidDistanceCode => "invalid distance code",
+            DeflateError::DistanceTooFar => "distance is before the start of the plain text",
+            DeflateError::PastDeclaredSize => "stream continues past its declared size",
+        };
+        f.write_str(message)
+    }
+}
+
+impl std::error::Error for DeflateError {}
+
+/// why the token predictor failed on a token
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TokenErrorKind {
+    /// there isn't enough plain text left for the match
+    NotEnoughInput,
+    /// the match isn't on the part of the hash chain that the predictor searches
+    NoMatchFound,
+    /// the distance of a match from the corrections is outside of the window or the plain text
+    InvalidDistance(u32),
+    /// the length correction of a match takes its length out of the range of u32
+    InvalidLengthCorrection(u32),
+    /// the length of a match from the corrections is shorter or longer than deflate64 allows
+    InvalidLength(u32),
+    /// the corrections contain an encoding of a match that isn't known
+    UnknownIrregularEncoding(u32),
+    /// the snapshot of the hash chain doesn't fit the parameters
+    InvalidSnapshot,
+}
+
+/// error of the token predictor, along with what it was doing when it failed
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TokenError {
+    pub kind: TokenErrorKind,
+    /// what the predictor was doing, the outermost first
+    pub context: Vec<String>,
+}
+
+impl TokenError {
+    pub fn new(kind: TokenErrorKind) -> Self {
+        TokenError {
+            kind,
+            context: Vec::new(),
+        }
+    }
+}
+
+impl From<TokenErrorKind> for TokenError {
+    fn from(kind: TokenErrorKind) -> Self {
+        TokenError::new(kind)
+    }
+}
+
+impl Display for TokenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for context in &self.context {
+            write!(f, "{}: ", context)?;
+        }
+        match self.kind {
+            TokenErrorKind::NotEnoughInput => write!(f, "not enough input left for the match"),
+            TokenErrorKind::NoMatchFound => write!(f, "no match found"),
+            TokenErrorKind::InvalidDistance(dist) => write!(f, "invalid distance {}", dist),
+            TokenErrorKind::InvalidLength(len) => write!(f, "invalid length {}", len),
+            TokenErrorKind::InvalidLengthCorrection(value) => {
+                write!(f, "invalid length correction {}", value)
+            }
+            TokenErrorKind::UnknownIrregularEncoding(value) => {
+                write!(f, "unknown irregular encoding {}", value)
+            }
+            TokenErrorKind::InvalidSnapshot => write!(f, "hash chain snapshot has the wrong size"),
+        }
+    }
+}
+
+impl std::error::Error for TokenError {}
+
+/// adds a description of what the predictor was doing to its errors, like anyhow::Context
+pub(crate) trait TokenContext<T> {
+    fn with_context<C: Display>(self, context: impl FnOnce() -> C) -> Result<T, TokenError>;
+}
+
+impl<T, E: Into<TokenError>> TokenContext<T> for Result<T, E> {
+    fn with_context<C: Display>(self, context: impl FnOnce() -> C) -> Result<T, TokenError> {
+        self.map_err(|e| {
+            let mut e = e.into();
+            e.context.insert(0, context().to_string());
+            e
+        })
+    }
+}
+
+/// the limits of PreflateConfig that keep untrusted streams from using too much memory or time
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ResourceLimit {
+    /// max_plaintext_size
+    PlainTextSize,
+    /// max_correction_size
+    CorrectionSize,
+    /// max_chain_limit
+    ChainLimit,
+}
+
+/// Stable numeric codes for each kind of error. These are used by the FFI bindings
+/// and for logging, so existing values must never be changed or reused.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u32)]
+pub enum ErrorCode {
+    ReadDeflate = 1,
+    RecompressFailed = 2,
+    Mismatch = 3,
+    ReadBlock = 4,
+    PredictBlock = 5,
+    PredictTree = 6,
+    RecreateBlock = 7,
+    RecreateTree = 8,
+    EncodeBlock = 9,
+    Io = 10,
+    CorruptCorrections = 11,
+    Truncated = 12,
+    PredictionMismatch = 13,
+    LimitExceeded = 14,
+    Cancelled = 15,
+    CorruptDeflate = 16,
+    UnsupportedCompressor = 17,
+}
+
+impl ErrorCode {
+    /// converts a numeric code back into the error code, if it is known
+    pub fn from_code(code: u32) -> Option<ErrorCode> {
+        use ErrorCode::*;
+
+        [
+            ReadDeflate,
+            RecompressFailed,
+            Mismatch,
+            ReadBlock,
+            PredictBlock,
+            PredictTree,
+            RecreateBlock,
+            RecreateTree,
+            EncodeBlock,
+            Io,
+            CorruptCorrections,
+            Truncated,
+            PredictionMismatch,
+            LimitExceeded,
+            Cancelled,
+            CorruptDeflate,
+            UnsupportedCompressor,
+        ]
+        .into_iter()
+        .find(|&c| c as u32 == code)
+    }
+}
+
+impl PreflateError {
+    /// the error for a block of the deflate stream that couldn't be read, which is Truncated
+    /// if the data ended before the end of the block
+    pub(crate) fn read_block(block: usize, e: anyhow::Error) -> Self {
+        if is_end_of_data(&e) {
+            return PreflateError::Truncated(block);
+        }
+
+        if let Some(&kind) = e.downcast_ref::<DeflateError>() {
+            return PreflateError::CorruptDeflate { block, kind };
+        }
+
+        // the reader stops with this error when the plain text gets too large
+        match e.downcast::<PreflateError>() {
+            Ok(e @ PreflateError::LimitExceeded { .. }) => e,
+            Ok(e) => PreflateError::ReadBlock(block, anyhow::Error::new(e)),
+            Err(e) => PreflateError::ReadBlock(block, e),
+        }
+    }
+
+    /// fails with LimitExceeded if the value is larger than the maximum of the limit
+    pub(crate) fn check_limit(limit: ResourceLimit, value: u64, max: u64) -> Result<(), Self> {
+        if value > max {
+            Err(PreflateError::LimitExceeded { limit, max })
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn error_code(&self) -> ErrorCode {
+        match self {
+            PreflateError::ReadDeflate(_) => ErrorCode::ReadDeflate,
+            PreflateError::RecompressFailed(_) => ErrorCode::RecompressFailed,
+            PreflateError::Mismatch(_) => ErrorCode::Mismatch,
+            PreflateError::ReadBlock(..) => ErrorCode::ReadBlock,
+            PreflateError::PredictBlock(..) => ErrorCode::PredictBlock,
+            PreflateError::PredictTree(..) => ErrorCode::PredictTree,
+            PreflateError::RecreateBlock(..) => ErrorCode::RecreateBlock,
+            PreflateError::RecreateTree(..) => ErrorCode::RecreateTree,
+            PreflateError::EncodeBlock(..) => ErrorCode::EncodeBlock,
+            PreflateError::Io(_) => ErrorCode::Io,
+            PreflateError::CorruptCorrections(..) => ErrorCode::CorruptCorrections,
+            PreflateError::Truncated(_) => ErrorCode::Truncated,
+            PreflateError::PredictionMismatch { .. } => ErrorCode::PredictionMismatch,
+            PreflateError::LimitExceeded { .. } => ErrorCode::LimitExceeded,
+            PreflateError::Cancelled => ErrorCode::Cancelled,
+            PreflateError::CorruptDeflate { .. } => ErrorCode::CorruptDeflate,
+            PreflateError::UnsupportedCompressor { .. } => ErrorCode::UnsupportedCompressor,
+        }
+    }
+
+    /// the block that couldn't be predicted or recreated, if the error is about a single block
+    /// of a stream that could be read
+    pub fn failed_block(&self) -> Option<usize> {
+        match self {
+            PreflateError::PredictBlock(i, _)
+            | PreflateError::PredictTree(i, _)
+            | PreflateError::RecreateBlock(i, _)
+            | PreflateError::RecreateTree(i, _)
+            | PreflateError::EncodeBlock(i, _)
+            | PreflateError::PredictionMismatch { block: i, .. } => Some(*i),
+            _ => None,
+        }
+    }
+
+    /// the stable numeric code for this error
+    pub fn to_code(&self) -> u32 {
+        self.error_code() as u32
+    }
+
+    /// description of what went wrong, without the name of the error kind
+    pub fn message(&self) -> String {
+        match self {
+            PreflateError::ReadDeflate(e)
+            | PreflateError::RecompressFailed(e)
+            | PreflateError::Mismatch(e) => e.to_string(),
+            PreflateError::ReadBlock(i, e)
+            | PreflateError::PredictBlock(i, e)
+            | PreflateError::PredictTree(i, e)
+            | PreflateError::RecreateBlock(i, e)
+            | PreflateError::RecreateTree(i, e)
+            | PreflateError::EncodeBlock(i, e) => format!("block {}: {}", i, e),
+            PreflateError::Io(e) => e.to_string(),
+            PreflateError::CorruptCorrections(offset, e) => format!("offset {}: {}", offset, e),
+            PreflateError::Truncated(blocks) => {
+                format!("stream ends after {} complete blocks", blocks)
+            }
+            PreflateError::PredictionMismatch {
+                block,
+                token_index,
+                error,
+            } => format!("block {} token {}: {}", block, token_index, error),
+            PreflateError::LimitExceeded { limit, max } => {
+                format!("{:?} is above the limit of {}", limit, max)
+            }
+            PreflateError::Cancelled => "the processing was cancelled".to_string(),
+            PreflateError::CorruptDeflate { block, kind } => format!("block {}: {}", block, kind),
+            PreflateError::UnsupportedCompressor {
+                profile,
+                known: false,
+            } => format!("unknown compressor profile {}", profile),
+            PreflateError::UnsupportedCompressor {
+                profile,
+                known: true,
+            } => format!("the stream doesn't fit the compressor profile {}", profile),
+        }
+    }
 }
 
 impl Display for PreflateError {
@@ -32,8 +353,125 @@ impl Display for PreflateError {
             PreflateError::RecreateTree(i, e) => write!(f, "RecreateTree[{}]: {}", i, e),
             PreflateError::EncodeBlock(i, e) => write!(f, "EncodeBlock[{}]: {}", i, e),
             PreflateError::RecompressFailed(e) => write!(f, "RecompressFailed: {}", e),
+            PreflateError::Io(e) => write!(f, "Io: {}", e),
+            PreflateError::CorruptCorrections(offset, e) => {
+                write!(f, "CorruptCorrections[{}]: {}", offset, e)
+            }
+            PreflateError::Truncated(blocks) => {
+                write!(f, "Truncated: stream ends after {} complete blocks", blocks)
+            }
+            PreflateError::PredictionMismatch {
+                block,
+                token_index,
+                error,
+            } => write!(
+                f,
+                "PredictionMismatch[{}:{}]: {}",
+                block, token_index, error
+            ),
+            PreflateError::LimitExceeded { limit, max } => {
+                write!(
+                    f,
+                    "LimitExceeded: {:?} is above the limit of {}",
+                    limit, max
+                )
+            }
+            PreflateError::Cancelled => write!(f, "Cancelled"),
+            PreflateError::CorruptDeflate { block, kind } => {
+                write!(f, "CorruptDeflate[{}]: {}", block, kind)
+            }
+            PreflateError::UnsupportedCompressor { .. } => {
+                write!(f, "UnsupportedCompressor: {}", self.message())
+            }
         }
     }
 }
 
+impl From<std::io::Error> for PreflateError {
+    fn from(e: std::io::Error) -> Self {
+        PreflateError::Io(e)
+    }
+}
+
 impl std::error::Error for PreflateError {}
+
+/// whether reading failed because the data ended rather than because it was invalid
+pub(crate) fn is_end_of_data(e: &anyhow::Error) -> bool {
+    e.chain().any(|c| {
+        c.downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.kind() == std::io::ErrorKind::UnexpectedEof)
+    })
+}
+
+#[test]
+fn error_codes_are_stable() {
+    let e = PreflateError::PredictBlock(3, anyhow::anyhow!("no match"));
+    assert_eq!(e.to_code(), 5);
+    assert_eq!(e.message(), "block 3: no match");
+    assert_eq!(ErrorCode::from_code(5), Some(ErrorCode::PredictBlock));
+
+    assert_eq!(PreflateError::Mismatch(anyhow::anyhow!("x")).to_code(), 3);
+    assert_eq!(
+        PreflateError::Io(std::io::Error::from(std::io::ErrorKind::NotFound)).to_code(),
+        10
+    );
+
+    let e = PreflateError::read_block(
+        2,
+        anyhow::Error::new(std::io::Error::from(std::io::ErrorKind::UnexpectedEof)),
+    );
+    assert_eq!(e.to_code(), 12);
+    assert!(matches!(
+        PreflateError::read_block(2, anyhow::anyhow!("something else")),
+        PreflateError::ReadBlock(2, _)
+    ));
+
+    // the reason is found under the context that was added to it
+    let e = PreflateError::read_block(
+        3,
+        anyhow::Error::new(DeflateError::InvalidDistanceCode).context("decode_block dyn"),
+    );
+    assert!(matches!(
+        e,
+        PreflateError::CorruptDeflate {
+            block: 3,
+            kind: DeflateError::InvalidDistanceCode
+        }
+    ));
+    assert_eq!(e.to_code(), 16);
+    assert_eq!(e.message(), "block 3: invalid distance code");
+
+    let e = Err::<(), _>(TokenErrorKind::NoMatchFound)
+        .with_context(|| "hop_match")
+        .with_context(|| "token 5")
+        .unwrap_err();
+    assert_eq!(e.kind, TokenErrorKind::NoMatchFound);
+    assert_eq!(e.to_string(), "token 5: hop_match: no match found");
+
+    let e = PreflateError::read_block(
+        4,
+        anyhow::Error::new(PreflateError::LimitExceeded {
+            limit: ResourceLimit::PlainTextSize,
+            max: 100,
+        }),
+    );
+    assert_eq!(e.to_code(), 14);
+    assert_eq!(e.message(), "PlainTextSize is above the limit of 100");
+
+    assert_eq!(PreflateError::Cancelled.to_code(), 15);
+
+    let e = PreflateError::UnsupportedCompressor {
+        profile: "zlib".to_string(),
+        known: true,
+    };
+    assert_eq!(e.to_code(), 17);
+    assert_eq!(
+        e.message(),
+        "the stream doesn't fit the compressor profile zlib"
+    );
+
+    for code in 1..=17 {
+        assert_eq!(ErrorCode::from_code(code).unwrap() as u32, code);
+    }
+    assert_eq!(ErrorCode::from_code(0), None);
+}
@@ -3,6 +3,7 @@
  *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
  *  This software incorporates material from third parties. See NOTICE.txt for details.
  *--------------------------------------------------------------------------------------------*/
+#[derive(Copy, Clone)]
 pub struct PreflateInput<'a> {
     data: &'a [u8],
     pos: i32,
@@ -25,6 +26,12 @@ impl<'a> PreflateInput<'a> {
         &self.data[(self.pos + offset) as usize..]
     }
 
+    /// exactly len bytes starting at offset from the current position
+    pub fn cur_window(&self, offset: i32, len: u32) -> &[u8] {
+        let start = (self.pos + offset) as usize;
+        &self.data[start..start + len as usize]
+    }
+
     pub fn cur_char(&self, offset: i32) -> u8 {
         self.data[(self.pos + offset) as usize]
     }
@@ -33,6 +40,10 @@ impl<'a> PreflateInput<'a> {
         self.pos += l as i32;
     }
 
+    pub fn rewind(&mut self, l: u32) {
+        self.pos -= l as i32;
+    }
+
     pub fn remaining(&self) -> u32 {
         self.data.len() as u32 - self.pos as u32
     }
@@ -6,22 +6,39 @@
 
 use crate::{
     bit_helper::bit_length,
-    complevel_estimator::estimate_preflate_comp_level,
+    complevel_estimator::{estimate_preflate_comp_level, profile_chain_info, ProfileChainInfo},
+    compressor_profile::{
+        CompressorProfile, CompressorProfileRegistry, MatchLengths, StreamFingerprint,
+    },
+    hash_chain::{
+        HASH_ALGORITHM_7ZIP, HASH_ALGORITHM_CRC32, HASH_ALGORITHM_ISAL, HASH_ALGORITHM_LIBDEFLATE3,
+        HASH_ALGORITHM_LIBDEFLATE4, HASH_ALGORITHM_MINIZ_FAST, HASH_ALGORITHM_ZLIB,
+    },
+    huffman_calc::HufftreeBitCalc,
     preflate_constants::{self},
+    preflate_parse_config::{
+        ParserConfigRegistry, PreflateParserConfig, FAST_PREFLATE_PARSER_SETTINGS,
+        SLOW_PREFLATE_PARSER_SETTINGS,
+    },
     preflate_stream_info::{extract_preflate_info, PreflateStreamInfo},
     preflate_token::PreflateTokenBlock,
     statistical_codec::{PredictionDecoder, PredictionEncoder},
 };
 
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum PreflateStrategy {
     Default,
     RleOnly,
     HuffOnly,
     Store,
+    /// the matches were chosen by an optimal parser (such as the one of Zopfli), which the
+    /// predictor replays with a cost model instead of taking the longest match
+    Optimal,
 }
 
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum PreflateHuffStrategy {
     Dynamic,
     Mixed,
@@ -29,6 +46,7 @@ pub enum PreflateHuffStrategy {
 }
 
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PreflateParameters {
     pub strategy: PreflateStrategy,
     pub huff_strategy: PreflateHuffStrategy,
@@ -38,6 +56,9 @@ pub struct PreflateParameters {
     pub hash_mask: u16,
     pub max_token_count: u16,
     pub max_dist_3_matches: u16,
+    /// matches of length 3 are only taken this deep in the hash chain, u16::MAX if they can be
+    /// anywhere
+    pub max_depth_3_matches: u16,
     pub very_far_matches_detected: bool,
     pub matches_to_start_detected: bool,
     pub log2_of_max_chain_depth_m1: u32,
@@ -47,6 +68,10 @@ pub struct PreflateParameters {
     pub nice_length: u32,
     pub max_chain: u32,
     pub hash_algorithm: u16,
+    pub huff_calc: HufftreeBitCalc,
+    /// the deflate64 stream writes some matches shorter than 258 with length code 285, so the
+    /// encoding of every match is stored in the corrections
+    pub deflate64_short_lengths: bool,
 }
 
 impl PreflateParameters {
@@ -59,6 +84,7 @@ impl PreflateParameters {
         let hash_mask = decoder.decode_value(16);
         let max_token_count = decoder.decode_value(16);
         let max_dist_3_matches = decoder.decode_value(16);
+        let max_depth_3_matches = decoder.decode_value(16);
         let very_far_matches_detected = decoder.decode_value(1) != 0;
         let matches_to_start_detected = decoder.decode_value(1) != 0;
         let log2_of_max_chain_depth_m1 = decoder.decode_value(16);
@@ -68,6 +94,8 @@ impl PreflateParameters {
         let nice_length = decoder.decode_value(16);
         let max_chain = decoder.decode_value(16);
         let hash_algorithm = decoder.decode_value(16);
+        let huff_calc = decoder.decode_value(4);
+        let deflate64_short_lengths = decoder.decode_value(1) != 0;
 
         PreflateParameters {
             strategy: match strategy {
@@ -75,6 +103,7 @@ impl PreflateParameters {
                 1 => PreflateStrategy::RleOnly,
                 2 => PreflateStrategy::HuffOnly,
                 3 => PreflateStrategy::Store,
+                4 => PreflateStrategy::Optimal,
                 _ => panic!("invalid strategy"),
             },
             huff_strategy: match huff_strategy {
@@ -89,6 +118,7 @@ impl PreflateParameters {
             hash_mask: hash_mask,
             max_token_count: max_token_count,
             max_dist_3_matches,
+            max_depth_3_matches,
             very_far_matches_detected,
             matches_to_start_detected,
             log2_of_max_chain_depth_m1: log2_of_max_chain_depth_m1.into(),
@@ -98,6 +128,15 @@ impl PreflateParameters {
             nice_length: nice_length.into(),
             max_chain: max_chain.into(),
             hash_algorithm,
+            huff_calc: match huff_calc {
+                0 => HufftreeBitCalc::Zlib,
+                1 => HufftreeBitCalc::Miniz,
+                2 => HufftreeBitCalc::SevenZip,
+                3 => HufftreeBitCalc::Zopfli,
+                4 => HufftreeBitCalc::Libdeflate,
+                _ => panic!("invalid huffman calculation"),
+            },
+            deflate64_short_lengths,
         }
     }
 
@@ -110,6 +149,7 @@ impl PreflateParameters {
         encoder.encode_value(u16::try_from(self.hash_mask).unwrap(), 16);
         encoder.encode_value(u16::try_from(self.max_token_count).unwrap(), 16);
         encoder.encode_value(u16::try_from(self.max_dist_3_matches).unwrap(), 16);
+        encoder.encode_value(self.max_depth_3_matches, 16);
         encoder.encode_value(u16::try_from(self.very_far_matches_detected).unwrap(), 1);
         encoder.encode_value(u16::try_from(self.matches_to_start_detected).unwrap(), 1);
         encoder.encode_value(u16::try_from(self.log2_of_max_chain_depth_m1).unwrap(), 16);
@@ -119,6 +159,74 @@ impl PreflateParameters {
         encoder.encode_value(u16::try_from(self.nice_length).unwrap(), 16);
         encoder.encode_value(u16::try_from(self.max_chain).unwrap(), 16);
         encoder.encode_value(u16::try_from(self.hash_algorithm).unwrap(), 16);
+        encoder.encode_value(self.huff_calc as u16, 4);
+        encoder.encode_value(u16::from(self.deflate64_short_lengths), 1);
+    }
+
+    /// short description of the kind of encoder that these parameters correspond to,
+    /// used to group streams by the encoder that most likely produced them
+    pub fn encoder_label(&self) -> String {
+        let label = match self.strategy {
+            PreflateStrategy::Store => return "stored".to_string(),
+            PreflateStrategy::HuffOnly => "huffman-only".to_string(),
+            PreflateStrategy::RleOnly => "rle-only".to_string(),
+            PreflateStrategy::Optimal => format!("optimal-{}", self.max_chain),
+            PreflateStrategy::Default => {
+                if self.hash_algorithm == HASH_ALGORITHM_MINIZ_FAST {
+                    "miniz-fast".to_string()
+                } else if self.hash_algorithm == HASH_ALGORITHM_LIBDEFLATE4
+                    || self.hash_algorithm == HASH_ALGORITHM_LIBDEFLATE3
+                {
+                    format!("libdeflate-{}", self.max_chain)
+                } else if self.hash_algorithm == HASH_ALGORITHM_CRC32 {
+                    format!("crc32-hash-{}", self.max_chain)
+                } else if self.hash_algorithm == HASH_ALGORITHM_7ZIP {
+                    format!("7zip-{}", self.nice_length)
+                } else if self.hash_algorithm == HASH_ALGORITHM_ISAL {
+                    format!("isal-{}", self.max_chain)
+                } else if self.is_fast_compressor {
+                    format!("zlib-fast-{}", self.max_chain)
+                } else {
+                    format!("zlib-slow-{}", self.max_chain)
+                }
+            }
+        };
+
+        if self.zlib_compatible {
+            label
+        } else {
+            label + "-incompatible"
+        }
+    }
+
+    /// The zlib compression level (0 to 9) whose settings the stream was predicted with, or None
+    /// if the parameters don't correspond to any of the levels of zlib. Other compressors with
+    /// the same match finder as zlib are reported with the level that behaves like them.
+    pub fn zlib_level(&self) -> Option<u32> {
+        if let PreflateStrategy::Store = self.strategy {
+            return Some(0);
+        }
+        if self.hash_algorithm != HASH_ALGORITHM_ZLIB {
+            return None;
+        }
+
+        let config = PreflateParserConfig {
+            good_length: self.good_length,
+            max_lazy: self.max_lazy,
+            nice_length: self.nice_length,
+            max_chain: self.max_chain,
+        };
+
+        // levels 1 to 3 use the greedy parser, the first of the fast settings is the one of miniz
+        let (levels, first_level) = if self.is_fast_compressor {
+            (&FAST_PREFLATE_PARSER_SETTINGS[1..], 1)
+        } else {
+            (&SLOW_PREFLATE_PARSER_SETTINGS[..], 4)
+        };
+        levels
+            .iter()
+            .position(|c| *c == config)
+            .map(|i| i as u32 + first_level)
     }
 }
 
@@ -137,7 +245,9 @@ pub fn estimate_preflate_window_bits(max_dist_: u32) -> u32 {
     let mut max_dist = max_dist_;
     max_dist += preflate_constants::MIN_LOOKAHEAD;
     let wbits = bit_length(max_dist - 1);
-    std::cmp::min(std::cmp::max(wbits, 9), 15)
+    // only deflate64 streams refer further back than 32K
+    let max_wbits = if max_dist_ > 1 << 15 { 16 } else { 15 };
+    std::cmp::min(std::cmp::max(wbits, 9), max_wbits)
 }
 
 pub fn estimate_preflate_strategy(info: &PreflateStreamInfo) -> PreflateStrategy {
@@ -163,13 +273,61 @@ pub fn estimate_preflate_huff_strategy(info: &PreflateStreamInfo) -> PreflateHuf
     PreflateHuffStrategy::Mixed
 }
 
+/// Estimates the parameters of the compressor that produced the stream. If one of the compressor
+/// profiles fits the stream, the parameters are taken from it. The profiles describe the
+/// unpatched level tables, so they are only considered if no parser configs have been registered.
 pub fn estimate_preflate_parameters(
     unpacked_output: &[u8],
-    blocks: &Vec<PreflateTokenBlock>,
+    blocks: &[PreflateTokenBlock],
+    parser_configs: &ParserConfigRegistry,
+    profiles: &CompressorProfileRegistry,
 ) -> PreflateParameters {
-    let info = extract_preflate_info(blocks);
+    estimate_parameters(unpacked_output, blocks, 0, parser_configs, profiles)
+}
+
+/// Estimates the parameters of the blocks from first_block on, like estimate_preflate_parameters,
+/// for a stream whose settings changed before that block. The plain text and blocks start with
+/// the history that the predictor replays before it, whose matches were found with the old
+/// settings, so these blocks only fill the hash chains of the estimator.
+pub fn estimate_preflate_parameters_from(
+    unpacked_output: &[u8],
+    blocks: &[PreflateTokenBlock],
+    first_block: usize,
+    parser_configs: &ParserConfigRegistry,
+    profiles: &CompressorProfileRegistry,
+) -> PreflateParameters {
+    estimate_parameters(
+        unpacked_output,
+        blocks,
+        first_block,
+        parser_configs,
+        profiles,
+    )
+}
+
+fn estimate_parameters(
+    unpacked_output: &[u8],
+    blocks: &[PreflateTokenBlock],
+    history_blocks: usize,
+    parser_configs: &ParserConfigRegistry,
+    profiles: &CompressorProfileRegistry,
+) -> PreflateParameters {
+    let info = extract_preflate_info(&blocks[history_blocks..]);
 
     let window_bits = estimate_preflate_window_bits(info.max_dist);
+
+    // the profiles are all of compressors with the 32K window of deflate, not deflate64
+    if parser_configs.is_empty() && window_bits <= 15 {
+        if let Some((profile, chain)) = find_fitting_profile(
+            window_bits,
+            unpacked_output,
+            blocks,
+            history_blocks,
+            profiles,
+        ) {
+            return parameters_from_profile(&info, window_bits, profile, &chain);
+        }
+    }
     let mem_level = estimate_preflate_mem_level(info.max_tokens_per_block);
 
     //let hash_shift = 5;
@@ -177,7 +335,16 @@ pub fn estimate_preflate_parameters(
 
     let max_token_count = (1 << (6 + mem_level)) - 1;
 
-    let cl = estimate_preflate_comp_level(window_bits, mem_level, unpacked_output, blocks);
+    let huff_calc = StreamFingerprint::new(&blocks[history_blocks..]).huff_calc();
+    let cl = estimate_preflate_comp_level(
+        window_bits,
+        mem_level,
+        unpacked_output,
+        blocks,
+        history_blocks,
+        parser_configs,
+        huff_calc,
+    );
 
     let hash_shift = cl.hash_shift;
     let hash_mask = cl.hash_mask;
@@ -191,6 +358,7 @@ pub fn estimate_preflate_parameters(
         huff_strategy: estimate_preflate_huff_strategy(&info),
         zlib_compatible: cl.zlib_compatible,
         max_dist_3_matches: cl.max_dist_3_matches,
+        max_depth_3_matches: u16::MAX,
         very_far_matches_detected: cl.very_far_matches,
         matches_to_start_detected: cl.match_to_start,
         log2_of_max_chain_depth_m1: if cl.max_chain_depth == 0 {
@@ -204,5 +372,110 @@ pub fn estimate_preflate_parameters(
         nice_length: cl.nice_length,
         max_chain: cl.max_chain,
         hash_algorithm: cl.hash_algorithm,
+        huff_calc,
+        deflate64_short_lengths: info.deflate64_short_lengths,
     }
 }
+
+/// The parameters for a stream that is known to come from the compressor of the profile, or
+/// None if the stream contains matches that this compressor can't have found. Instead of
+/// searching the hash chains of all the candidates that the estimator considers, only the hash
+/// chain of the profile is followed through the stream.
+pub fn profile_preflate_parameters(
+    unpacked_output: &[u8],
+    blocks: &[PreflateTokenBlock],
+    profile: &CompressorProfile,
+) -> Option<PreflateParameters> {
+    let info = extract_preflate_info(blocks);
+    let window_bits = estimate_preflate_window_bits(info.max_dist);
+
+    let chain = profile_chain_info(window_bits, unpacked_output, blocks, 0, profile)
+        .filter(|chain| profile.fits_chain(chain))?;
+
+    Some(parameters_from_profile(&info, window_bits, profile, &chain))
+}
+
+/// The parameters of the profile, where the quirks are only enabled if the
+/// stream needs them since the predictor is more accurate without them.
+fn parameters_from_profile(
+    info: &PreflateStreamInfo,
+    window_bits: u32,
+    profile: &CompressorProfile,
+    chain: &ProfileChainInfo,
+) -> PreflateParameters {
+    let mem_level = estimate_preflate_mem_level(info.max_tokens_per_block);
+    let config = &profile.parser_config;
+
+    PreflateParameters {
+        window_bits,
+        hash_shift: profile.hash_shift,
+        hash_mask: profile.hash_mask,
+        max_token_count: (1 << (6 + mem_level)) - 1,
+        strategy: match (
+            estimate_preflate_strategy(info),
+            profile.quirks.match_lengths,
+        ) {
+            (PreflateStrategy::Default, MatchLengths::Shortened) => PreflateStrategy::Optimal,
+            (strategy, _) => strategy,
+        },
+        huff_strategy: estimate_preflate_huff_strategy(info),
+        zlib_compatible: !chain.match_to_start
+            && !chain.very_far_matches
+            && (profile.quirks.max_dist_3_matches <= 4096 || profile.fast_parser),
+        max_dist_3_matches: profile.quirks.max_dist_3_matches,
+        max_depth_3_matches: profile.quirks.max_depth_3_matches,
+        very_far_matches_detected: chain.very_far_matches,
+        matches_to_start_detected: chain.match_to_start,
+        // rounded down, since the predictor searches twice as far when it has to find a match
+        log2_of_max_chain_depth_m1: bit_length(config.max_chain.max(1)) - 1,
+        is_fast_compressor: profile.fast_parser,
+        good_length: config.good_length,
+        max_lazy: config.max_lazy,
+        nice_length: config.nice_length,
+        max_chain: config.max_chain,
+        hash_algorithm: profile.hash_algorithm,
+        huff_calc: profile.huff_calc,
+        deflate64_short_lengths: info.deflate64_short_lengths,
+    }
+}
+
+/// The first profile that fits the fingerprint of the stream and whose hash chain contains all
+/// the matches within its chain length. Profiles that only differ in their chain length
+/// share the same hash chain, so each of these is followed only once.
+fn find_fitting_profile<'a>(
+    window_bits: u32,
+    plain_text: &[u8],
+    blocks: &[PreflateTokenBlock],
+    history_blocks: usize,
+    profiles: &'a CompressorProfileRegistry,
+) -> Option<(&'a CompressorProfile, ProfileChainInfo)> {
+    let fingerprint = StreamFingerprint::new(&blocks[history_blocks..]);
+
+    let mut chains = Vec::new();
+    profiles
+        .profiles()
+        .filter(|p| p.fits_fingerprint(&fingerprint))
+        .find_map(|p| {
+            let key = (
+                p.hash_algorithm,
+                p.hash_shift,
+                p.hash_mask,
+                p.fast_parser.then_some(p.parser_config.max_lazy),
+                p.quirks,
+            );
+
+            let chain = match chains.iter().find(|(k, _)| *k == key) {
+                Some(&(_, chain)) => chain,
+                None => {
+                    let chain =
+                        profile_chain_info(window_bits, plain_text, blocks, history_blocks, p);
+                    chains.push((key, chain));
+                    chain
+                }
+            };
+
+            chain
+                .filter(|c| p.fits_chain(c) && p.parses_like(c))
+                .map(|c| (p, c))
+        })
+}
@@ -4,13 +4,73 @@
  *  This software incorporates material from third parties. See NOTICE.txt for details.
  *--------------------------------------------------------------------------------------------*/
 
+//! The good/lazy/nice/chain settings of the compression levels of zlib and similar compressors.
+//! The estimator matches the hash chain depths and lazy matches it finds in a stream against these
+//! tables, and additional settings can be registered for compressors with patched level tables.
+
+/// the settings of a single compression level (the configuration_table entries in zlib's deflate.c)
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub struct PreflateParserConfig {
+    /// reduce the chain length to a quarter if the previous match is at least this long
     pub good_length: u32,
+    /// For the lazy parser, don't look for a better match if the current one is at least this
+    /// long. For the fast parser, this is the longest match whose positions are all added to the
+    /// hash table.
     pub max_lazy: u32,
+    /// stop searching once a match of this length has been found
     pub nice_length: u32,
+    /// maximum number of entries of the hash chain that are searched
     pub max_chain: u32,
 }
 
+/// The parser settings that the estimator considers in addition to the built in tables.
+/// The registered settings are checked before the built in ones, in the order in which they
+/// were registered, so they take priority if more than one of them fits the stream. The chosen
+/// settings are stored in the corrections, so they don't need to be registered for recompressing.
+#[derive(Debug, Clone, Default)]
+pub struct ParserConfigRegistry {
+    fast: Vec<PreflateParserConfig>,
+    slow: Vec<PreflateParserConfig>,
+}
+
+impl ParserConfigRegistry {
+    /// registers the settings of a level that uses the fast parser, which doesn't do lazy matching
+    pub fn register_fast(&mut self, config: PreflateParserConfig) {
+        self.fast.push(config);
+    }
+
+    /// registers the settings of a level that uses the lazy parser
+    pub fn register_slow(&mut self, config: PreflateParserConfig) {
+        self.slow.push(config);
+    }
+
+    /// true if no settings have been registered
+    pub fn is_empty(&self) -> bool {
+        self.fast.is_empty() && self.slow.is_empty()
+    }
+
+    /// the registered fast parser settings followed by the built in ones
+    pub fn fast_configs(&self) -> impl Iterator<Item = &PreflateParserConfig> {
+        self.fast.iter().chain(FAST_PREFLATE_PARSER_SETTINGS.iter())
+    }
+
+    /// the registered lazy parser settings followed by the built in ones
+    pub fn slow_configs(&self) -> impl Iterator<Item = &PreflateParserConfig> {
+        self.slow.iter().chain(SLOW_PREFLATE_PARSER_SETTINGS.iter())
+    }
+
+    /// the registered fast parser settings followed by the greedy levels of miniz, for streams
+    /// with the huffman trees of miniz
+    pub fn miniz_fast_configs(&self) -> impl Iterator<Item = &PreflateParserConfig> {
+        self.fast.iter().chain(MINIZ_PARSER_SETTINGS[..3].iter())
+    }
+
+    /// the registered lazy parser settings followed by the lazy levels of miniz
+    pub fn miniz_slow_configs(&self) -> impl Iterator<Item = &PreflateParserConfig> {
+        self.slow.iter().chain(MINIZ_PARSER_SETTINGS[3..].iter())
+    }
+}
+
 pub const FAST_PREFLATE_PARSER_SETTINGS: [PreflateParserConfig; 4] = [
     // max speed used by miniz, always match the first entry
     PreflateParserConfig {
@@ -78,3 +138,139 @@ pub const SLOW_PREFLATE_PARSER_SETTINGS: [PreflateParserConfig; 6] = [
         max_chain: 4096,
     }, // max compression
 ];
+
+/// The settings of the levels 1 to 9 of miniz and miniz_oxide. Level 1 uses a separate
+/// compressor that only looks at the head of the chain of its own hash. The other levels check
+/// three entries of the chain per probe (s_tdefl_num_probes), and reduce the number of probes
+/// to a quarter once a match of 32 is found. The greedy levels 2 and 3 insert every position into
+/// the hash table, and the lazy levels take a match of 128 without looking at the next position.
+pub const MINIZ_PARSER_SETTINGS: [PreflateParserConfig; 9] = [
+    FAST_PREFLATE_PARSER_SETTINGS[0],
+    PreflateParserConfig {
+        good_length: 32,
+        max_lazy: 258,
+        nice_length: 258,
+        max_chain: 6,
+    }, // greedy
+    PreflateParserConfig {
+        good_length: 32,
+        max_lazy: 258,
+        nice_length: 258,
+        max_chain: 33,
+    },
+    PreflateParserConfig {
+        good_length: 32,
+        max_lazy: 128,
+        nice_length: 258,
+        max_chain: 18,
+    }, // lazy
+    PreflateParserConfig {
+        good_length: 32,
+        max_lazy: 128,
+        nice_length: 258,
+        max_chain: 33,
+    },
+    PreflateParserConfig {
+        good_length: 32,
+        max_lazy: 128,
+        nice_length: 258,
+        max_chain: 129,
+    },
+    PreflateParserConfig {
+        good_length: 32,
+        max_lazy: 128,
+        nice_length: 258,
+        max_chain: 258,
+    },
+    PreflateParserConfig {
+        good_length: 32,
+        max_lazy: 128,
+        nice_length: 258,
+        max_chain: 513,
+    },
+    PreflateParserConfig {
+        good_length: 32,
+        max_lazy: 128,
+        nice_length: 258,
+        max_chain: 768,
+    },
+];
+
+/// The max_search_depth and nice_match_length of the levels 1 to 12 of libdeflate. Levels 2-4
+/// parse greedily and levels 5-9 lazily, all of them insert every position into the hash
+/// table. Levels 10-12 use a near optimal parser that chooses between the matches found within
+/// these limits.
+pub const LIBDEFLATE_PARSER_SETTINGS: [PreflateParserConfig; 12] = [
+    PreflateParserConfig {
+        good_length: 258,
+        max_lazy: 258,
+        nice_length: 32,
+        max_chain: 2,
+    }, // level 1, two entries per bucket of the hash table
+    PreflateParserConfig {
+        good_length: 258,
+        max_lazy: 258,
+        nice_length: 10,
+        max_chain: 6,
+    }, // greedy
+    PreflateParserConfig {
+        good_length: 258,
+        max_lazy: 258,
+        nice_length: 14,
+        max_chain: 12,
+    },
+    PreflateParserConfig {
+        good_length: 258,
+        max_lazy: 258,
+        nice_length: 30,
+        max_chain: 16,
+    },
+    PreflateParserConfig {
+        good_length: 258,
+        max_lazy: 30,
+        nice_length: 30,
+        max_chain: 16,
+    }, // lazy
+    PreflateParserConfig {
+        good_length: 258,
+        max_lazy: 65,
+        nice_length: 65,
+        max_chain: 35,
+    },
+    PreflateParserConfig {
+        good_length: 258,
+        max_lazy: 130,
+        nice_length: 130,
+        max_chain: 100,
+    },
+    PreflateParserConfig {
+        good_length: 258,
+        max_lazy: 258,
+        nice_length: 258,
+        max_chain: 300,
+    }, // lazy2
+    PreflateParserConfig {
+        good_length: 258,
+        max_lazy: 258,
+        nice_length: 258,
+        max_chain: 600,
+    },
+    PreflateParserConfig {
+        good_length: 258,
+        max_lazy: 75,
+        nice_length: 75,
+        max_chain: 35,
+    }, // near optimal
+    PreflateParserConfig {
+        good_length: 258,
+        max_lazy: 150,
+        nice_length: 150,
+        max_chain: 100,
+    },
+    PreflateParserConfig {
+        good_length: 258,
+        max_lazy: 258,
+        nice_length: 258,
+        max_chain: 300,
+    },
+];
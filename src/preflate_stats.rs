@@ -0,0 +1,180 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! A summary of how a stream was predicted: what kinds of blocks it had, how often each kind
+//! of misprediction and correction was needed and how many bytes of the corrections went to
+//! each of them. This is meant for finding out which compressor a corpus was written with and
+//! why the corrections of a stream are large.
+
+use crate::{
+    deflate_parser::DeflateBlockType,
+    statistical_codec::{ContextCost, CountNonDefaultActions, ALL_CORRECTIONS, ALL_MISPREDICTIONS},
+    CodecCorrection, CodecMisprediction, DecompressResult,
+};
+
+/// how often one kind of misprediction or correction occurred and what it cost
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct KindStats<K> {
+    pub kind: K,
+    /// number of times the prediction was wrong
+    pub count: u32,
+    /// number of times the model of this kind was used, including the correct predictions
+    pub symbols: u64,
+    /// number of bytes of the corrections spent on this model, only known for the encoders
+    /// that produce actual bits
+    pub bytes: f64,
+}
+
+impl<K> KindStats<K> {
+    fn new(kind: K, count: u32, cost: &ContextCost) -> Self {
+        KindStats {
+            kind,
+            count,
+            symbols: cost.symbols,
+            bytes: cost.bits / 8.0,
+        }
+    }
+}
+
+/// statistics of a decompressed stream, see DecompressResult::stats
+#[derive(Debug, Clone, PartialEq)]
+pub struct PreflateStats {
+    pub stored_blocks: usize,
+    pub static_blocks: usize,
+    pub dynamic_blocks: usize,
+    /// number of literals and references over all the blocks
+    pub tokens: u64,
+    /// number of bytes of the deflate stream
+    pub deflate_bytes: u64,
+    /// number of bytes of the corrections
+    pub correction_bytes: usize,
+    pub mispredictions: Vec<KindStats<CodecMisprediction>>,
+    pub corrections: Vec<KindStats<CodecCorrection>>,
+    /// number of bytes spent on the raw values, such as the stream parameters
+    pub value_bytes: f64,
+}
+
+impl PreflateStats {
+    pub fn new(statistics: &CountNonDefaultActions, correction_bytes: usize) -> Self {
+        let mut stats = PreflateStats {
+            stored_blocks: 0,
+            static_blocks: 0,
+            dynamic_blocks: 0,
+            tokens: 0,
+            deflate_bytes: (statistics
+                .blocks
+                .iter()
+                .map(|b| b.deflate_bits())
+                .sum::<u64>()
+                + 7)
+                / 8,
+            correction_bytes,
+            mispredictions: ALL_MISPREDICTIONS
+                .iter()
+                .map(|&k| {
+                    KindStats::new(
+                        k,
+                        statistics.mispredictions_count[k as usize],
+                        &statistics.mispredictions_cost[k as usize],
+                    )
+                })
+                .collect(),
+            corrections: ALL_CORRECTIONS
+                .iter()
+                .map(|&k| {
+                    KindStats::new(
+                        k,
+                        statistics.corrections_count[k as usize],
+                        &statistics.corrections_cost[k as usize],
+                    )
+                })
+                .collect(),
+            value_bytes: statistics.values_cost.bits / 8.0,
+        };
+
+        for block in &statistics.blocks {
+            match block.block_type {
+                Some(DeflateBlockType::Stored) => stats.stored_blocks += 1,
+                Some(DeflateBlockType::StaticHuffman) => stats.static_blocks += 1,
+                Some(DeflateBlockType::DynamicHuffman) => stats.dynamic_blocks += 1,
+                None => {}
+            }
+            stats.tokens += u64::from(block.token_count);
+        }
+
+        stats
+    }
+
+    /// number of times the prediction was wrong over all the kinds
+    pub fn total_mispredictions(&self) -> u64 {
+        self.mispredictions.iter().map(|m| u64::from(m.count)).sum()
+    }
+
+    /// the kinds of corrections that needed any, the most expensive first
+    pub fn costliest_corrections(&self) -> Vec<KindStats<CodecCorrection>> {
+        let mut corrections: Vec<_> = self
+            .corrections
+            .iter()
+            .filter(|c| c.count != 0 || c.bytes > 0.0)
+            .copied()
+            .collect();
+        corrections.sort_by(|a, b| b.bytes.total_cmp(&a.bytes).then(b.count.cmp(&a.count)));
+        corrections
+    }
+}
+
+impl DecompressResult {
+    /// summarizes the blocks of the stream and what the corrections were spent on
+    pub fn stats(&self) -> PreflateStats {
+        PreflateStats::new(&self.statistics, self.cabac_encoded.len())
+    }
+}
+
+#[test]
+fn stats_of_stream() {
+    let compressed = crate::process::read_file("compressed_zlib_level1.deflate");
+    let result = crate::decompress_deflate_stream(&compressed, true).unwrap();
+    let stats = result.stats();
+
+    let report = crate::inspect::inspect_deflate_stream(&compressed).unwrap();
+    let count = |t| report.blocks.iter().filter(|b| b.block_type == t).count();
+    assert_eq!(stats.stored_blocks, count(DeflateBlockType::Stored));
+    assert_eq!(stats.static_blocks, count(DeflateBlockType::StaticHuffman));
+    assert_eq!(
+        stats.dynamic_blocks,
+        count(DeflateBlockType::DynamicHuffman)
+    );
+    assert_eq!(
+        stats.tokens,
+        report
+            .blocks
+            .iter()
+            .map(|b| b.token_count as u64)
+            .sum::<u64>()
+    );
+    assert_eq!(stats.deflate_bytes, result.compressed_processed as u64);
+
+    assert_eq!(stats.mispredictions.len(), CodecMisprediction::MAX as usize);
+    assert_eq!(stats.corrections.len(), CodecCorrection::MAX as usize);
+    assert_eq!(
+        stats.total_mispredictions(),
+        result
+            .statistics
+            .mispredictions_count
+            .iter()
+            .map(|&c| u64::from(c))
+            .sum::<u64>()
+    );
+
+    // the bytes spent on the models make up about all of the corrections
+    let model_bytes = stats.mispredictions.iter().map(|m| m.bytes).sum::<f64>()
+        + stats.corrections.iter().map(|c| c.bytes).sum::<f64>()
+        + stats.value_bytes;
+    assert!(model_bytes > 0.0 && model_bytes <= stats.correction_bytes as f64);
+
+    let costliest = stats.costliest_corrections();
+    assert!(costliest.windows(2).all(|w| w[0].bytes >= w[1].bytes));
+}
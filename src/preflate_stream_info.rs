@@ -4,7 +4,10 @@
  *  This software incorporates material from third parties. See NOTICE.txt for details.
  *--------------------------------------------------------------------------------------------*/
 
-use crate::preflate_token::{BlockType, PreflateToken, PreflateTokenBlock};
+use crate::{
+    preflate_constants::MAX_MATCH,
+    preflate_token::{BlockType, IrregularEncoding, PreflateToken, PreflateTokenBlock},
+};
 
 pub struct PreflateStreamInfo {
     pub token_count: u32,
@@ -17,9 +20,11 @@ pub struct PreflateStreamInfo {
     pub count_huff_blocks: u32,
     pub count_rle_blocks: u32,
     pub count_static_huff_tree_blocks: u32,
+    /// whether a match shorter than 258 was written with the deflate64 length code
+    pub deflate64_short_lengths: bool,
 }
 
-pub fn extract_preflate_info(blocks: &Vec<PreflateTokenBlock>) -> PreflateStreamInfo {
+pub fn extract_preflate_info(blocks: &[PreflateTokenBlock]) -> PreflateStreamInfo {
     let mut result: PreflateStreamInfo = PreflateStreamInfo {
         count_blocks: blocks.len() as u32,
         count_stored_blocks: 0,
@@ -31,6 +36,7 @@ pub fn extract_preflate_info(blocks: &Vec<PreflateTokenBlock>) -> PreflateStream
         max_dist: 0,
         count_huff_blocks: 0,
         count_rle_blocks: 0,
+        deflate64_short_lengths: false,
     };
 
     for i in 0..blocks.len() {
@@ -54,6 +60,8 @@ pub fn extract_preflate_info(blocks: &Vec<PreflateTokenBlock>) -> PreflateStream
                 PreflateToken::Reference(t) => {
                     result.reference_count += 1;
                     block_max_dist = std::cmp::max(block_max_dist, t.dist());
+                    result.deflate64_short_lengths |=
+                        t.len() < MAX_MATCH && t.irregular() == IrregularEncoding::Deflate64Length;
                 }
             }
         }
@@ -5,18 +5,100 @@
  *--------------------------------------------------------------------------------------------*/
 
 use crate::{
+    huffman_calc::{calc_bit_lengths, HufftreeBitCalc},
     huffman_encoding::HuffmanOriginalEncoding,
     preflate_constants::{
-        quantize_distance, quantize_length, DIST_CODE_COUNT, LITLENDIST_CODE_COUNT,
-        NONLEN_CODE_COUNT,
+        quantize_distance, quantize_length, DEFLATE64_DIST_CODE_COUNT, DEFLATE64_LONG_LENGTH_EXTRA,
+        DEFLATE64_MAX_MATCH, LENGTH_BASE_TABLE, LENGTH_EXTRA_TABLE, LEN_CODE_COUNT,
+        LITLENDIST_CODE_COUNT, MAX_MATCH, MIN_MATCH, NONLEN_CODE_COUNT,
     },
+    preflate_error::TokenErrorKind,
 };
 
+/// the longest code of the literal/length and distance trees that deflate allows
+const MAX_CODE_BITS: usize = 15;
+
+/// How a match was encoded in the cases where deflate allows more than one encoding for the
+/// same match. Canonical is the encoding that zlib uses, the others are quirks of some encoders.
+/// This is stored as a correction, so another quirk only needs another variant here.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum IrregularEncoding {
+    #[default]
+    Canonical,
+    /// a length of 258 written as length code 284 with all extra bits set instead of as 285
+    Len258As284,
+    /// a length of 258 written as the deflate64 length code 285 with 16 extra bits. Longer
+    /// matches only exist in deflate64 and are always written that way.
+    Deflate64Length,
+}
+
+impl IrregularEncoding {
+    /// whether a match of this length can be encoded in more than one way, only then is the
+    /// encoding stored in the corrections. Shorter matches only have alternatives in the deflate64
+    /// streams that write some of them with the 16 extra bits of length code 285.
+    pub fn has_alternatives(len: u32, deflate64_short_lengths: bool) -> bool {
+        len == MAX_MATCH || (deflate64_short_lengths && len < MAX_MATCH)
+    }
+
+    /// the encoding of a match of length len that was written with the length code lcode (0-28)
+    pub fn from_length_code(len: u32, lcode: usize) -> Self {
+        if len == MAX_MATCH && lcode != LEN_CODE_COUNT - 1 {
+            IrregularEncoding::Len258As284
+        } else {
+            IrregularEncoding::Canonical
+        }
+    }
+
+    /// the value of the correction for this encoding, 0 for canonical
+    pub fn to_correction(self) -> u32 {
+        self as u32
+    }
+
+    pub fn from_correction(value: u32) -> Option<Self> {
+        match value {
+            0 => Some(IrregularEncoding::Canonical),
+            1 => Some(IrregularEncoding::Len258As284),
+            2 => Some(IrregularEncoding::Deflate64Length),
+            _ => None,
+        }
+    }
+
+    /// whether the length is written with the 16 extra bits of deflate64
+    fn is_deflate64_length(self, len: u32) -> bool {
+        len > MAX_MATCH || self == IrregularEncoding::Deflate64Length
+    }
+
+    /// the length code (0-28) and the value of its extra bits for a match of length len
+    pub fn length_code(self, len: u32) -> (usize, u32) {
+        if self.is_deflate64_length(len) {
+            return (LEN_CODE_COUNT - 1, len - MIN_MATCH);
+        }
+
+        let lcode = match self {
+            IrregularEncoding::Len258As284 => LEN_CODE_COUNT - 2,
+            _ => quantize_length(len),
+        };
+
+        (lcode, len - MIN_MATCH - u32::from(LENGTH_BASE_TABLE[lcode]))
+    }
+
+    /// the number of extra bits after the length code that length_code returns
+    pub fn length_extra_bits(self, len: u32) -> u8 {
+        if self.is_deflate64_length(len) {
+            DEFLATE64_LONG_LENGTH_EXTRA
+        } else {
+            LENGTH_EXTRA_TABLE[self.length_code(len).0]
+        }
+    }
+}
+
+/// A match, with the length and the distance stored as len - 3 and dist - 1 so that the
+/// longest matches and distances of deflate64 still fit in 16 bits.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct PreflateTokenReference {
     len: u16,
     dist: u16,
-    irregular258: bool,
+    irregular: IrregularEncoding,
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -26,34 +108,53 @@ pub enum PreflateToken {
 }
 
 impl PreflateToken {
-    pub fn new_reference(len: u32, dist: u32, irregular258: bool) -> PreflateToken {
-        PreflateToken::Reference(PreflateTokenReference::new(len, dist, irregular258))
+    pub fn new_reference(len: u32, dist: u32, irregular: IrregularEncoding) -> PreflateToken {
+        PreflateToken::Reference(PreflateTokenReference::new(len, dist, irregular))
     }
 }
 
+#[allow(clippy::len_without_is_empty)]
 impl PreflateTokenReference {
-    pub fn new(len: u32, dist: u32, irregular258: bool) -> PreflateTokenReference {
+    pub fn new(len: u32, dist: u32, irregular: IrregularEncoding) -> PreflateTokenReference {
+        debug_assert!((MIN_MATCH..=DEFLATE64_MAX_MATCH).contains(&len));
+        debug_assert!((1..=1 + u32::from(u16::MAX)).contains(&dist));
         PreflateTokenReference {
-            len: len as u16,
-            dist: dist as u16,
-            irregular258,
+            len: (len - MIN_MATCH) as u16,
+            dist: (dist - 1) as u16,
+            irregular,
         }
     }
 
+    /// new for a match that comes from the corrections, which fails if its length or distance
+    /// can't be written by deflate64 (and so can't be packed)
+    pub fn try_new(
+        len: u32,
+        dist: u32,
+        irregular: IrregularEncoding,
+    ) -> Result<PreflateTokenReference, TokenErrorKind> {
+        if !(MIN_MATCH..=DEFLATE64_MAX_MATCH).contains(&len) {
+            return Err(TokenErrorKind::InvalidLength(len));
+        }
+        if !(1..=1 + u32::from(u16::MAX)).contains(&dist) {
+            return Err(TokenErrorKind::InvalidDistance(dist));
+        }
+        Ok(PreflateTokenReference::new(len, dist, irregular))
+    }
+
     pub fn len(&self) -> u32 {
-        self.len as u32
+        u32::from(self.len) + MIN_MATCH
     }
 
     pub fn dist(&self) -> u32 {
-        self.dist as u32
+        u32::from(self.dist) + 1
     }
 
-    pub fn get_irregular258(&self) -> bool {
-        self.irregular258
+    pub fn irregular(&self) -> IrregularEncoding {
+        self.irregular
     }
 
-    pub fn set_irregular258(&mut self, irregular258: bool) {
-        self.irregular258 = irregular258;
+    pub fn set_irregular(&mut self, irregular: IrregularEncoding) {
+        self.irregular = irregular;
     }
 }
 
@@ -65,28 +166,51 @@ pub enum BlockType {
     StaticHuff = 2,
 }
 
-#[derive(Debug)]
+/// What an empty stored block marks, which is where zlib flushed its output with Z_SYNC_FLUSH
+/// or Z_FULL_FLUSH (HTTP and websocket compressors flush after every message). A full flush also
+/// clears the hash table, so none of the matches after it refer back before it.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum FlushMarker {
+    /// the block isn't an empty stored block
+    #[default]
+    None,
+    Sync,
+    Full,
+}
+
+#[derive(Debug, Clone)]
 pub struct PreflateTokenBlock {
     pub block_type: BlockType,
     pub uncompressed_len: u32,
     pub context_len: i32,
     pub padding_bits: u8,
+    /// for stored blocks, the bits in which NLEN differs from the complement of LEN,
+    /// which is only non-zero for streams that were read with lenient_stored_len
+    pub nlen_mismatch: u16,
+    /// for empty stored blocks, the kind of flush. The reader marks all of them as sync
+    /// flushes, see mark_full_flushes.
+    pub flush: FlushMarker,
     pub tokens: Vec<PreflateToken>,
     pub huffman_encoding: HuffmanOriginalEncoding,
     pub freq: TokenFrequency,
 }
 
-#[derive(Debug)]
+/// How often each symbol of the literal/length and distance alphabets is used in a block,
+/// which is what a compressor builds the dynamic huffman trees of the block from.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct TokenFrequency {
+    /// the literals (0-255), the end of block code (256) and the length codes (257-285).
+    /// The entries after 285 are never used.
     pub literal_codes: [u16; LITLENDIST_CODE_COUNT],
-    pub distance_codes: [u16; DIST_CODE_COUNT],
+    /// the distance codes (0-29) and the two codes that only deflate64 uses
+    pub distance_codes: [u16; DEFLATE64_DIST_CODE_COUNT],
 }
 
 impl Default for TokenFrequency {
     fn default() -> Self {
         let mut t = TokenFrequency {
             literal_codes: [0; LITLENDIST_CODE_COUNT],
-            distance_codes: [0; DIST_CODE_COUNT],
+            distance_codes: [0; DEFLATE64_DIST_CODE_COUNT],
         };
 
         // include the end of block code
@@ -96,6 +220,33 @@ impl Default for TokenFrequency {
     }
 }
 
+impl TokenFrequency {
+    /// counts a literal byte
+    pub fn add_literal(&mut self, lit: u8) {
+        self.literal_codes[lit as usize] += 1;
+    }
+
+    /// counts the length and distance codes of a match
+    pub fn add_reference(&mut self, len: u32, dist: u32) {
+        self.add_irregular_reference(len, dist, IrregularEncoding::Canonical);
+    }
+
+    /// counts the codes of a match that was encoded in the given way
+    pub fn add_irregular_reference(&mut self, len: u32, dist: u32, irregular: IrregularEncoding) {
+        self.literal_codes[NONLEN_CODE_COUNT + irregular.length_code(len).0] += 1;
+        self.distance_codes[quantize_distance(dist)] += 1;
+    }
+
+    /// The bit lengths of the literal/length and distance trees that the calculation builds
+    /// for these frequencies, without the trailing unused symbols.
+    pub fn bit_lengths(&self, calc: HufftreeBitCalc) -> (Vec<u8>, Vec<u8>) {
+        (
+            calc_bit_lengths(calc, &self.literal_codes, MAX_CODE_BITS),
+            calc_bit_lengths(calc, &self.distance_codes, MAX_CODE_BITS),
+        )
+    }
+}
+
 impl PreflateTokenBlock {
     pub fn new(block_type: BlockType) -> PreflateTokenBlock {
         PreflateTokenBlock {
@@ -103,6 +254,8 @@ impl PreflateTokenBlock {
             uncompressed_len: 0,
             context_len: 0,
             padding_bits: 0,
+            nlen_mismatch: 0,
+            flush: FlushMarker::None,
             tokens: Vec::new(),
             freq: TokenFrequency::default(),
             huffman_encoding: HuffmanOriginalEncoding::default(),
@@ -111,13 +264,76 @@ impl PreflateTokenBlock {
 
     pub fn add_literal(&mut self, lit: u8) {
         self.tokens.push(PreflateToken::Literal);
-        self.freq.literal_codes[lit as usize] += 1;
+        self.freq.add_literal(lit);
     }
 
-    pub fn add_reference(&mut self, len: u32, dist: u32, irregular258: bool) {
+    pub fn add_reference(&mut self, len: u32, dist: u32, irregular: IrregularEncoding) {
         self.tokens
-            .push(PreflateToken::new_reference(len, dist, irregular258));
-        self.freq.literal_codes[NONLEN_CODE_COUNT + quantize_length(len)] += 1;
-        self.freq.distance_codes[quantize_distance(dist)] += 1;
+            .push(PreflateToken::new_reference(len, dist, irregular));
+        self.freq.add_irregular_reference(len, dist, irregular);
     }
 }
+
+/// Marks the sync flushes that none of the later matches refer back across as full flushes, since
+/// the hash table may have been cleared there. The predictor clears its hash chain at these, which
+/// is only possible because no match needs anything before them. The blocks can also be the
+/// later ones of a stream, whose matches may refer to the plain text before them.
+pub fn mark_full_flushes(blocks: &mut [PreflateTokenBlock]) {
+    let mut end: i64 = blocks.iter().map(|b| i64::from(b.uncompressed_len)).sum();
+
+    // going backwards, the earliest position that any of the matches after the block refers to
+    let mut earliest_referenced = end;
+    for block in blocks.iter_mut().rev() {
+        if block.block_type != BlockType::Stored {
+            for token in block.tokens.iter().rev() {
+                match token {
+                    PreflateToken::Literal => end -= 1,
+                    PreflateToken::Reference(r) => {
+                        end -= i64::from(r.len());
+                        earliest_referenced = earliest_referenced.min(end - i64::from(r.dist()));
+                    }
+                }
+            }
+            continue;
+        }
+
+        end -= i64::from(block.uncompressed_len);
+        if block.flush == FlushMarker::Sync && earliest_referenced >= end {
+            block.flush = FlushMarker::Full;
+        }
+    }
+}
+
+#[test]
+fn token_reference_is_compact() {
+    // the blocks of large streams hold millions of tokens
+    assert_eq!(std::mem::size_of::<PreflateTokenReference>(), 6);
+
+    let r = PreflateTokenReference::new(65538, 65536, IrregularEncoding::Canonical);
+    assert_eq!((r.len(), r.dist()), (65538, 65536));
+    let r = PreflateTokenReference::new(MIN_MATCH, 1, IrregularEncoding::Canonical);
+    assert_eq!((r.len(), r.dist()), (MIN_MATCH, 1));
+}
+
+#[test]
+fn token_reference_from_corrections_is_checked() {
+    // lengths and distances that come from corrections of another plain text can be anything
+    assert_eq!(
+        PreflateTokenReference::try_new(2, 1, IrregularEncoding::Canonical).unwrap_err(),
+        TokenErrorKind::InvalidLength(2)
+    );
+    assert_eq!(
+        PreflateTokenReference::try_new(DEFLATE64_MAX_MATCH + 1, 1, IrregularEncoding::Canonical)
+            .unwrap_err(),
+        TokenErrorKind::InvalidLength(DEFLATE64_MAX_MATCH + 1)
+    );
+    assert_eq!(
+        PreflateTokenReference::try_new(MIN_MATCH, 0, IrregularEncoding::Canonical).unwrap_err(),
+        TokenErrorKind::InvalidDistance(0)
+    );
+    assert_eq!(
+        PreflateTokenReference::try_new(MIN_MATCH, 65537, IrregularEncoding::Canonical)
+            .unwrap_err(),
+        TokenErrorKind::InvalidDistance(65537)
+    );
+}
@@ -4,23 +4,115 @@
  *  This software incorporates material from third parties. See NOTICE.txt for details.
  *--------------------------------------------------------------------------------------------*/
 
-use std::io::{Cursor, Write};
+use std::{
+    cell::Cell,
+    collections::VecDeque,
+    io::Cursor,
+    ops::Range,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 use crate::{
+    bit_writer::BitWriter,
+    block_observer::{BlockObserver, BlockSummary},
+    compressor_profile::CompressorProfileRegistry,
     deflate_reader::DeflateReader,
     deflate_writer::DeflateWriter,
-    hash_chain::{MiniZHash, RotatingHashTrait, ZlibRotatingHash, HASH_ALGORITHM_MINIZ_FAST},
+    hash_chain::{
+        Crc32Hash, HashChainSnapshot, IsalHash, LibdeflateHash3, LibdeflateHash4, MiniZHash,
+        RotatingHashTrait, SevenZipHash, ZlibRotatingHash, HASH_ALGORITHM_7ZIP,
+        HASH_ALGORITHM_CRC32, HASH_ALGORITHM_ISAL, HASH_ALGORITHM_LIBDEFLATE3,
+        HASH_ALGORITHM_LIBDEFLATE4, HASH_ALGORITHM_MINIZ_FAST,
+    },
     huffman_calc::HufftreeBitCalc,
-    preflate_error::PreflateError,
-    preflate_parameter_estimator::{estimate_preflate_parameters, PreflateParameters},
-    preflate_token::{BlockType, PreflateTokenBlock},
+    match_predictor::{MatchPredictor, ZlibMatchPredictor},
+    preflate_config::{PreflateConfig, VerifyMode},
+    preflate_constants,
+    preflate_error::{PreflateError, ResourceLimit},
+    preflate_parameter_estimator::{
+        estimate_preflate_parameters, estimate_preflate_parameters_from,
+        profile_preflate_parameters, PreflateParameters,
+    },
+    preflate_parse_config::ParserConfigRegistry,
+    preflate_token::{mark_full_flushes, BlockType, PreflateTokenBlock},
+    progress::{check_cancelled, CancellationToken, PlainTextCrc, Progress},
     statistical_codec::{
-        CodecCorrection, CodecMisprediction, PredictionDecoder, PredictionEncoder,
+        drive_encoder, BlockCost, CodecAction, CodecCorrection, CodecMisprediction,
+        PredictionDecoder, PredictionEncoder, VerifyPredictionDecoder, VerifyPredictionEncoder,
     },
-    token_predictor::TokenPredictor,
+    token_predictor::{BlockStart, TokenPredictor},
     tree_predictor::{predict_tree_for_block, recreate_tree_for_block},
 };
 
+/// evaluates the expression with the type alias set to the rotating hash that was selected by
+/// the hash algorithm of the parameters, since each hash gets its own instantiation
+macro_rules! with_rotating_hash {
+    ($hash_algorithm:expr, |$hash:ident| $body:expr) => {
+        match $hash_algorithm {
+            HASH_ALGORITHM_MINIZ_FAST => {
+                type $hash = MiniZHash;
+                $body
+            }
+            HASH_ALGORITHM_LIBDEFLATE4 => {
+                type $hash = LibdeflateHash4;
+                $body
+            }
+            HASH_ALGORITHM_CRC32 => {
+                type $hash = Crc32Hash;
+                $body
+            }
+            HASH_ALGORITHM_7ZIP => {
+                type $hash = SevenZipHash;
+                $body
+            }
+            HASH_ALGORITHM_LIBDEFLATE3 => {
+                type $hash = LibdeflateHash3;
+                $body
+            }
+            HASH_ALGORITHM_ISAL => {
+                type $hash = IsalHash;
+                $body
+            }
+            _ => {
+                type $hash = ZlibRotatingHash;
+                $body
+            }
+        }
+    };
+}
+
+/// creates the TokenPredictor for the hash algorithm that was selected in the parameters
+/// (using a copy of the match predictor) and evaluates the expression with it. The predictor
+/// starts at the beginning of the plain text, unless it is given the position at which the
+/// history starts and the blocks of the history, which it goes through with skip_block.
+macro_rules! with_token_predictor {
+    ($plain_text:expr, $params:expr, $match_predictor:expr, |$predictor:ident| $body:expr) => {
+        with_rotating_hash!($params.hash_algorithm, |SelectedHash| {
+            let $predictor = TokenPredictor::<SelectedHash, _>::new(
+                $plain_text,
+                $params,
+                0,
+                $match_predictor.clone(),
+            );
+            $body
+        })
+    };
+    ($plain_text:expr, $params:expr, $history_pos:expr, $history:expr, $match_predictor:expr, |$predictor:ident| $body:expr) => {
+        with_rotating_hash!($params.hash_algorithm, |SelectedHash| {
+            let mut $predictor = TokenPredictor::<SelectedHash, _>::new(
+                $plain_text,
+                $params,
+                $history_pos,
+                $match_predictor.clone(),
+            );
+            for block in $history {
+                $predictor.skip_block(block);
+            }
+            $body
+        })
+    };
+}
+
 /// takes a deflate compressed stream, analyzes it, decoompresses it, and records
 /// any differences in the encoder codec
 pub fn read_deflate<E: PredictionEncoder>(
@@ -28,110 +120,822 @@ pub fn read_deflate<E: PredictionEncoder>(
     encoder: &mut E,
     deflate_info_dump_level: u32,
 ) -> Result<(usize, PreflateParameters, Vec<u8>, Vec<PreflateTokenBlock>), PreflateError> {
+    let (processed, params, plain_text, blocks, _costs, _segments) = read_deflate_with_predictor(
+        compressed_data,
+        encoder,
+        deflate_info_dump_level,
+        &ZlibMatchPredictor::default(),
+        &PreflateConfig::default(),
+        &mut |_| {},
+        &mut (),
+    )?;
+    Ok((processed, params, plain_text, blocks))
+}
+
+/// the result of reading a stream: the number of bytes processed, the parameters, the plain text,
+/// the blocks, what each of the blocks cost and the parameters that were estimated again partway
+type ReadDeflateResult = (
+    usize,
+    PreflateParameters,
+    Vec<u8>,
+    Vec<PreflateTokenBlock>,
+    Vec<BlockCost>,
+    Vec<ParameterSegment>,
+);
+
+/// the index of the block from which on the stream was predicted with the parameters, after they
+/// were estimated again because the mispredictions spiked
+pub type ParameterSegment = (usize, PreflateParameters);
+
+/// Same as read_deflate, but uses the given match predictor to predict the tokens and takes
+/// the parser configs and compressor profiles of the config into account for the parameters.
+/// Also returns the cost of each block in the stream and in the corrections, and calls on_chunk
+/// with the plain text of each block as soon as it has been read. The observer is told how well
+/// each block was predicted.
+pub fn read_deflate_with_predictor<E: PredictionEncoder, M: MatchPredictor + Clone>(
+    compressed_data: &[u8],
+    encoder: &mut E,
+    deflate_info_dump_level: u32,
+    match_predictor: &M,
+    config: &PreflateConfig,
+    on_chunk: &mut dyn FnMut(&[u8]),
+    observer: &mut dyn BlockObserver,
+) -> Result<ReadDeflateResult, PreflateError> {
+    // with dyn_dispatch the predictor is only instantiated once per hash instead of once per
+    // hash and codec, at the cost of a virtual call for every prediction action
+    #[cfg(feature = "dyn_dispatch")]
+    let encoder = &mut (encoder as &mut dyn PredictionEncoder);
+
+    read_deflate_impl(
+        compressed_data,
+        encoder,
+        deflate_info_dump_level,
+        match_predictor,
+        config,
+        on_chunk,
+        observer,
+    )
+}
+
+fn read_deflate_impl<E: PredictionEncoder, M: MatchPredictor + Clone>(
+    compressed_data: &[u8],
+    encoder: &mut E,
+    deflate_info_dump_level: u32,
+    match_predictor: &M,
+    config: &PreflateConfig,
+    on_chunk: &mut dyn FnMut(&[u8]),
+    observer: &mut dyn BlockObserver,
+) -> Result<ReadDeflateResult, PreflateError> {
+    let (blocks, mut costs, plain_text, eof_padding, amount_processed) =
+        read_blocks(compressed_data, deflate_info_dump_level, config, on_chunk)?;
+
+    let params_e = parameters_for(&plain_text, &blocks, config)?;
+    params_e.write(encoder);
+
+    if deflate_info_dump_level > 0 {
+        println!("prediction parameters: {:?}", params_e);
+    }
+
+    let mut segments = Vec::new();
+    let threads = match config.prediction_threads {
+        0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    };
+    if threads > 1 && blocks.len() > 1 {
+        let cancellation = observer.cancellation().cloned();
+        let summaries = predict_blocks_parallel(
+            &plain_text,
+            &params_e,
+            &blocks,
+            &mut costs,
+            match_predictor,
+            encoder,
+            threads,
+            cancellation.as_ref(),
+        )?;
+
+        let (mut deflate_bits, mut produced) = (0, 0);
+        let mut plain_text_crc = PlainTextCrc::new(&plain_text);
+        for (summary, cost) in summaries.iter().zip(&costs) {
+            observer.block_predicted(summary);
+
+            deflate_bits += cost.deflate_bits();
+            produced += u64::from(summary.uncompressed_len);
+            observer.progress(&Progress {
+                consumed: (deflate_bits + 7) / 8,
+                produced,
+                plain_text_crc32: plain_text_crc.up_to(produced),
+            });
+        }
+    } else {
+        let reestimation = (config.reestimate_parameters && config.compressor_profile.is_none())
+            .then_some(Reestimation {
+                plain_text: &plain_text,
+                config,
+                match_predictor,
+                attempts: Cell::new(0),
+            });
+
+        let (mut params, mut first_block, mut input_pos) = (params_e, 0, 0);
+        let mut plain_text_crc = PlainTextCrc::new(&plain_text);
+        loop {
+            let (history_pos, history_start) = history_before(&blocks, first_block, input_pos);
+            let Some((next_block, next_input_pos, next_params)) = with_token_predictor!(
+                &plain_text,
+                &params,
+                history_pos,
+                &blocks[history_start..first_block],
+                match_predictor,
+                |token_predictor| {
+                    predict_blocks(
+                        &blocks,
+                        first_block,
+                        &mut costs,
+                        token_predictor,
+                        encoder,
+                        &params,
+                        observer,
+                        &mut plain_text_crc,
+                        reestimation.as_ref(),
+                    )
+                }
+            )?
+            else {
+                break;
+            };
+
+            if deflate_info_dump_level > 0 {
+                println!(
+                    "prediction parameters from block {}: {:?}",
+                    next_block, next_params
+                );
+            }
+
+            segments.push((next_block, next_params));
+            (params, first_block, input_pos) = (next_params, next_block, next_input_pos);
+        }
+    }
+
+    encoder.encode_misprediction(CodecMisprediction::EOFMisprediction, false);
+
+    encoder.encode_correction(CodecCorrection::NonZeroPadding, eof_padding.into());
+
+    Ok((
+        amount_processed,
+        params_e,
+        plain_text,
+        blocks,
+        costs,
+        segments,
+    ))
+}
+
+/// Estimates the parameters of the blocks, or takes them from the compressor profile that was
+/// selected in the config, and checks them against the chain limit of the config.
+pub fn parameters_for(
+    plain_text: &[u8],
+    blocks: &[PreflateTokenBlock],
+    config: &PreflateConfig,
+) -> Result<PreflateParameters, PreflateError> {
+    let params = match config.selected_profile()? {
+        Some(profile) => {
+            profile_preflate_parameters(plain_text, blocks, profile).ok_or_else(|| {
+                PreflateError::UnsupportedCompressor {
+                    profile: profile.name.clone(),
+                    known: true,
+                }
+            })?
+        }
+        None => estimate_preflate_parameters(
+            plain_text,
+            blocks,
+            &config.parser_configs,
+            &config.compressor_profiles,
+        ),
+    };
+    PreflateError::check_limit(
+        ResourceLimit::ChainLimit,
+        params.max_chain.into(),
+        config.max_chain_limit.into(),
+    )?;
+    Ok(params)
+}
+
+/// reads all the blocks of the stream, returns the blocks, the number of bits of each block, the
+/// plain text, the padding after the last block and the number of bytes of the compressed data
+/// that were used. The plain text of each block is passed to on_chunk as soon as it has been read.
+#[allow(clippy::type_complexity)]
+fn read_blocks(
+    compressed_data: &[u8],
+    deflate_info_dump_level: u32,
+    config: &PreflateConfig,
+    on_chunk: &mut dyn FnMut(&[u8]),
+) -> Result<(Vec<PreflateTokenBlock>, Vec<BlockCost>, Vec<u8>, u8, usize), PreflateError> {
     let mut input_stream = Cursor::new(compressed_data);
-    let mut block_decoder = DeflateReader::new(&mut input_stream);
+    let mut block_decoder = DeflateReader::with_compressed_size(
+        &mut input_stream,
+        config.compressed_size.unwrap_or(u64::MAX),
+    );
+    block_decoder.set_lenient_stored_len(config.lenient_stored_len);
+    block_decoder.set_deflate64(config.deflate64);
+    block_decoder.set_max_plain_text_size(config.max_plaintext_size);
 
     let mut blocks = Vec::new();
+    let mut costs = Vec::new();
+    let mut chunk_start = 0;
     let mut last = false;
     while !last {
         let block = block_decoder
             .read_block(&mut last)
-            .map_err(|e| PreflateError::ReadBlock(blocks.len(), e))?;
+            .map_err(|e| PreflateError::read_block(blocks.len(), e))?;
 
         if deflate_info_dump_level > 0 {
             // Log information about this deflate compressed block
             println!("Block: tokens={}", block.tokens.len());
         }
 
+        on_chunk(&block_decoder.plain_text()[chunk_start..]);
+        chunk_start = block_decoder.plain_text().len();
+
         blocks.push(block);
+        costs.push(block_decoder.last_block_cost());
     }
 
     let eof_padding = block_decoder.read_eof_padding();
+    let plain_text = block_decoder.move_plain_text();
+    mark_full_flushes(&mut blocks);
+
+    Ok((
+        blocks,
+        costs,
+        plain_text,
+        eof_padding,
+        input_stream.position() as usize,
+    ))
+}
 
-    let params_e = estimate_preflate_parameters(block_decoder.get_plain_text(), &blocks);
+/// a block needs at least this many mispredictions and corrections before the parameters are
+/// estimated again
+const MIN_SPIKE_MISPREDICTIONS: u64 = 64;
 
-    params_e.write(encoder);
+/// how many times more mispredictions per token than the blocks before it a block needs to have
+/// for the parameters to be estimated again
+const SPIKE_RATIO: u64 = 4;
 
-    if deflate_info_dump_level > 0 {
-        println!("prediction parameters: {:?}", params_e);
-    }
+/// how often the parameters of a stream are estimated again at most, since each estimation goes
+/// through the rest of the stream
+const MAX_REESTIMATIONS: u32 = 16;
 
-    if params_e.hash_algorithm == HASH_ALGORITHM_MINIZ_FAST {
-        predict_blocks(
-            &blocks,
-            TokenPredictor::<MiniZHash>::new(block_decoder.get_plain_text(), &params_e, 0),
-            encoder,
-        )?;
-    } else {
-        predict_blocks(
-            &blocks,
-            TokenPredictor::<ZlibRotatingHash>::new(block_decoder.get_plain_text(), &params_e, 0),
-            encoder,
-        )?;
-    }
+/// how much of the plain text before the first block of new parameters their predictor goes
+/// through with skip_block, so that its hash chain is filled the way the compressor filled it
+/// with the settings it had before
+const HISTORY_SIZE: u32 = 1 << 16;
 
-    encoder.encode_misprediction(CodecMisprediction::EOFMisprediction, false);
+/// The position in the plain text at which the history before first_block starts and the index
+/// of its first block, where the history is made up of the blocks that cover the last
+/// HISTORY_SIZE bytes of plain text before the input position of first_block.
+fn history_before(
+    blocks: &[PreflateTokenBlock],
+    first_block: usize,
+    input_pos: u32,
+) -> (u32, usize) {
+    let (mut history_pos, mut start) = (input_pos, first_block);
+    while start > 0 && input_pos - history_pos < HISTORY_SIZE {
+        start -= 1;
+        history_pos -= blocks[start].uncompressed_len;
+    }
+    (history_pos, start)
+}
 
-    encoder.encode_correction(CodecCorrection::NonZeroPadding, eof_padding.into());
+/// what is needed to estimate the parameters again partway through the stream
+struct Reestimation<'a, M> {
+    plain_text: &'a [u8],
+    config: &'a PreflateConfig,
+    match_predictor: &'a M,
+    attempts: Cell<u32>,
+}
 
-    let plain_text = block_decoder.move_plain_text();
-    let amount_processed = input_stream.position() as usize;
+impl<M: MatchPredictor + Clone> Reestimation<'_, M> {
+    /// Estimates the parameters again from the block at the input position to the end of the
+    /// stream, after a block that had the given number of mispredictions. The new parameters are
+    /// only returned if they predict the block with at most half as many. The estimator and the
+    /// new predictor both go through the history before the block, see history_before.
+    fn parameters_at(
+        &self,
+        blocks: &[PreflateTokenBlock],
+        block: usize,
+        input_pos: u32,
+        mispredictions: u64,
+    ) -> Option<PreflateParameters> {
+        if self.attempts.get() >= MAX_REESTIMATIONS {
+            return None;
+        }
+        self.attempts.set(self.attempts.get() + 1);
+
+        let (history_pos, history_start) = history_before(blocks, block, input_pos);
+        let params = estimate_preflate_parameters_from(
+            &self.plain_text[history_pos as usize..],
+            &blocks[history_start..],
+            block - history_start,
+            &self.config.parser_configs,
+            &self.config.compressor_profiles,
+        );
+        if params.max_chain > self.config.max_chain_limit {
+            return None;
+        }
 
-    // dump compressed content to file (TODO test code remove)
-    let mut f = std::fs::File::create("dump").unwrap();
-    f.write_all(&compressed_data[0..amount_processed]).unwrap();
+        let mut encoder = VerifyPredictionEncoder::new();
+        with_token_predictor!(
+            self.plain_text,
+            &params,
+            history_pos,
+            &blocks[history_start..block],
+            self.match_predictor,
+            |token_predictor| {
+                token_predictor
+                    .predict_block(
+                        block,
+                        &blocks[block],
+                        &mut encoder,
+                        block == blocks.len() - 1,
+                        &mut (),
+                    )
+                    .ok()?;
+            }
+        );
+        if blocks[block].block_type == BlockType::DynamicHuff {
+            predict_tree_for_block(
+                &blocks[block].huffman_encoding,
+                &blocks[block].freq,
+                &mut encoder,
+                params.huff_calc,
+            )
+            .ok()?;
+        }
 
-    Ok((amount_processed, params_e, plain_text, blocks))
+        (u64::from(encoder.statistics().total_non_default) * 2 <= mispredictions).then_some(params)
+    }
 }
 
-fn predict_blocks<H: RotatingHashTrait, E: PredictionEncoder>(
+/// Predicts each of the blocks from first_block on, adding the bits that the encoder spent on it
+/// to its cost. If the mispredictions of a block spike and the parameters can be estimated
+/// again, the new parameters are written after the block and the index of the next block, its
+/// position in the plain text and the new parameters are returned, so that the rest of the
+/// blocks can be predicted with a predictor for them.
+#[allow(clippy::too_many_arguments)]
+fn predict_blocks<H: RotatingHashTrait, M: MatchPredictor + Clone, E: PredictionEncoder>(
     blocks: &[PreflateTokenBlock],
-    mut token_predictor_in: TokenPredictor<H>,
+    first_block: usize,
+    costs: &mut [BlockCost],
+    mut token_predictor_in: TokenPredictor<H, M>,
     encoder: &mut E,
-) -> Result<(), PreflateError> {
-    for i in 0..blocks.len() {
-        if token_predictor_in.input_eof() {
-            encoder.encode_misprediction(CodecMisprediction::EOFMisprediction, true);
-        }
+    params: &PreflateParameters,
+    observer: &mut dyn BlockObserver,
+    plain_text_crc: &mut PlainTextCrc<'_>,
+    reestimation: Option<&Reestimation<M>>,
+) -> Result<Option<(usize, u32, PreflateParameters)>, PreflateError> {
+    let mut statistics_before = encoder.statistics();
+    let mut deflate_bits = costs[..first_block]
+        .iter()
+        .map(BlockCost::deflate_bits)
+        .sum::<u64>();
+    let (mut segment_mispredictions, mut segment_tokens) = (0, 0);
 
-        token_predictor_in
-            .predict_block(&blocks[i], encoder, i == blocks.len() - 1)
-            .map_err(|e| PreflateError::PredictBlock(i, e))?;
+    if first_block == 0 && token_predictor_in.input_eof() {
+        encoder.encode_misprediction(CodecMisprediction::EOFMisprediction, true);
+    }
+
+    for i in first_block..blocks.len() {
+        check_cancelled(observer)?;
+
+        token_predictor_in.predict_block(
+            i,
+            &blocks[i],
+            encoder,
+            i == blocks.len() - 1,
+            observer,
+        )?;
 
         if blocks[i].block_type == BlockType::DynamicHuff {
             predict_tree_for_block(
                 &blocks[i].huffman_encoding,
                 &blocks[i].freq,
                 encoder,
-                HufftreeBitCalc::Zlib,
+                params.huff_calc,
             )
             .map_err(|e| PreflateError::PredictTree(i, e))?;
         }
+
+        let mispredictions =
+            u64::from(encoder.statistics().total_non_default - statistics_before.total_non_default);
+        let tokens = blocks[i].tokens.len() as u64;
+
+        let mut next_params = None;
+        if i + 1 < blocks.len() {
+            if token_predictor_in.input_eof() {
+                encoder.encode_misprediction(CodecMisprediction::EOFMisprediction, true);
+            }
+
+            if let Some(reestimation) = reestimation {
+                if i > first_block
+                    && mispredictions >= MIN_SPIKE_MISPREDICTIONS
+                    && mispredictions * segment_tokens
+                        > SPIKE_RATIO * segment_mispredictions * tokens
+                {
+                    next_params = reestimation.parameters_at(
+                        blocks,
+                        i + 1,
+                        token_predictor_in.current_input_pos(),
+                        mispredictions,
+                    );
+                }
+            }
+
+            encoder
+                .encode_misprediction(CodecMisprediction::ParametersChanged, next_params.is_some());
+            if let Some(next_params) = &next_params {
+                next_params.write(encoder);
+            }
+        }
+        segment_mispredictions += mispredictions;
+        segment_tokens += tokens;
+
+        let statistics = encoder.statistics();
+        costs[i].correction_bits = statistics.total_bits() - statistics_before.total_bits();
+        statistics_before = statistics;
+
+        deflate_bits += costs[i].deflate_bits();
+        let produced = token_predictor_in.current_input_pos().into();
+        observer.progress(&Progress {
+            consumed: (deflate_bits + 7) / 8,
+            produced,
+            plain_text_crc32: plain_text_crc.up_to(produced),
+        });
+
+        if let Some(next_params) = next_params {
+            return Ok(Some((
+                i + 1,
+                token_predictor_in.current_input_pos(),
+                next_params,
+            )));
+        }
     }
     assert!(token_predictor_in.input_eof());
-    Ok(())
+    Ok(None)
+}
+
+/// number of groups of blocks per thread when predicting in parallel, so that a thread that
+/// got through its groups quickly can take over some of the work of the others
+const GROUPS_PER_THREAD: usize = 4;
+
+/// splits the blocks into about count groups of consecutive blocks with a similar amount of
+/// plain text each
+fn block_groups(blocks: &[PreflateTokenBlock], count: usize) -> Vec<Range<usize>> {
+    let total: u64 = blocks.iter().map(|b| u64::from(b.uncompressed_len)).sum();
+    let target = (total / count.max(1) as u64).max(1);
+
+    let mut groups = Vec::new();
+    let mut start = 0;
+    let mut size = 0;
+    for (i, block) in blocks.iter().enumerate() {
+        size += u64::from(block.uncompressed_len);
+        if size >= target {
+            groups.push(start..i + 1);
+            start = i + 1;
+            size = 0;
+        }
+    }
+    if start < blocks.len() {
+        groups.push(start..blocks.len());
+    }
+    groups
+}
+
+/// Predicts the blocks like predict_blocks, but on several threads. The predictor is brought up
+/// to date with skip_block to take a snapshot at the start of each group of blocks, the groups
+/// are predicted from their snapshot with the actions recorded, and the actions are then given
+/// to the encoder in order. The predictor is in the same state at every block as when predicting
+/// sequentially, so the corrections are the same and recompressing doesn't change. Returns the
+/// summaries of the blocks in order. The threads stop before their next group of blocks once
+/// the cancellation token is cancelled.
+#[allow(clippy::too_many_arguments)]
+fn predict_blocks_parallel<M: MatchPredictor + Clone, E: PredictionEncoder>(
+    plain_text: &[u8],
+    params: &PreflateParameters,
+    blocks: &[PreflateTokenBlock],
+    costs: &mut [BlockCost],
+    match_predictor: &M,
+    encoder: &mut E,
+    threads: usize,
+    cancellation: Option<&CancellationToken>,
+) -> Result<Vec<BlockSummary>, PreflateError> {
+    let groups = block_groups(blocks, threads * GROUPS_PER_THREAD);
+    let snapshots = snapshots_at(
+        plain_text,
+        params,
+        blocks,
+        |i| groups.iter().any(|g| g.start == i),
+        match_predictor,
+    );
+
+    let next = AtomicUsize::new(0);
+    let mut predicted: Vec<_> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads.min(groups.len()))
+            .map(|_| {
+                let (groups, snapshots, next) = (&groups, &snapshots, &next);
+                let match_predictor = match_predictor.clone();
+                scope.spawn(move || {
+                    let mut predicted = Vec::new();
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(range) = groups.get(index) else {
+                            break;
+                        };
+                        let (_, input_pos, snapshot) = &snapshots[index];
+                        predicted.push((
+                            index,
+                            cancellation
+                                .map_or(Ok(()), CancellationToken::check)
+                                .and_then(|()| {
+                                    predict_block_group(
+                                        plain_text,
+                                        params,
+                                        blocks,
+                                        range.clone(),
+                                        *input_pos,
+                                        snapshot,
+                                        &match_predictor,
+                                    )
+                                }),
+                        ));
+                    }
+                    predicted
+                })
+            })
+            .collect();
+
+        workers
+            .into_iter()
+            .flat_map(|w| w.join().unwrap())
+            .collect()
+    });
+    predicted.sort_by_key(|(index, _)| *index);
+
+    // the first error in the order of the blocks is the one that predicting sequentially fails with
+    let mut bits_before = encoder.statistics().total_bits();
+    let mut summaries = Vec::with_capacity(blocks.len());
+    for (_, group) in predicted {
+        for (actions, summary) in group? {
+            drive_encoder(encoder, &actions);
+
+            let bits = encoder.statistics().total_bits();
+            costs[summaries.len()].correction_bits = bits - bits_before;
+            bits_before = bits;
+            summaries.push(summary);
+        }
+    }
+    Ok(summaries)
+}
+
+/// predicts the blocks in the range from a snapshot like predict_blocks, and returns the
+/// actions that were recorded for each block along with its summary
+fn predict_block_group<M: MatchPredictor + Clone>(
+    plain_text: &[u8],
+    params: &PreflateParameters,
+    blocks: &[PreflateTokenBlock],
+    range: Range<usize>,
+    input_pos: u32,
+    snapshot: &HashChainSnapshot,
+    match_predictor: &M,
+) -> Result<Vec<(Vec<CodecAction>, BlockSummary)>, PreflateError> {
+    with_rotating_hash!(params.hash_algorithm, |SelectedHash| {
+        let mut token_predictor = TokenPredictor::<SelectedHash, _>::from_snapshot(
+            plain_text,
+            params,
+            input_pos,
+            snapshot,
+            match_predictor.clone(),
+        )
+        .map_err(|e| PreflateError::RecompressFailed(e.into()))?;
+
+        let mut actions = Vec::new();
+        for i in range {
+            let mut encoder = VerifyPredictionEncoder::new();
+            if i == 0 && token_predictor.input_eof() {
+                encoder.encode_misprediction(CodecMisprediction::EOFMisprediction, true);
+            }
+
+            let mut summary = Vec::with_capacity(1);
+            token_predictor.predict_block(
+                i,
+                &blocks[i],
+                &mut encoder,
+                i == blocks.len() - 1,
+                &mut summary,
+            )?;
+
+            if blocks[i].block_type == BlockType::DynamicHuff {
+                predict_tree_for_block(
+                    &blocks[i].huffman_encoding,
+                    &blocks[i].freq,
+                    &mut encoder,
+                    params.huff_calc,
+                )
+                .map_err(|e| PreflateError::PredictTree(i, e))?;
+            }
+            encode_next_block(&token_predictor, i, blocks.len(), &mut encoder);
+
+            actions.push((encoder.into_actions(), summary[0]));
+        }
+        Ok(actions)
+    })
+}
+
+/// Writes what predict_blocks writes after each block but the last when the parameters
+/// stay the same, which is whether the plain text ended early and that they didn't change.
+fn encode_next_block<H: RotatingHashTrait, M: MatchPredictor, E: PredictionEncoder>(
+    token_predictor: &TokenPredictor<H, M>,
+    block: usize,
+    block_count: usize,
+    encoder: &mut E,
+) {
+    if block + 1 < block_count {
+        if token_predictor.input_eof() {
+            encoder.encode_misprediction(CodecMisprediction::EOFMisprediction, true);
+        }
+        encoder.encode_misprediction(CodecMisprediction::ParametersChanged, false);
+    }
+}
+
+/// the snapshot of a predictor at the start of the plain text, for predict_next_blocks
+pub fn initial_snapshot<M: MatchPredictor + Clone>(
+    plain_text: &[u8],
+    params: &PreflateParameters,
+    match_predictor: &M,
+) -> HashChainSnapshot {
+    with_token_predictor!(plain_text, params, match_predictor, |token_predictor| {
+        token_predictor.hash_snapshot()
+    })
+}
+
+/// Predicts the next blocks of a stream that is read in pieces, for
+/// streaming::DeflateStreamDecompressor. The predictor continues from the snapshot that was
+/// taken after the block before first_block, and the snapshot after the blocks is returned.
+/// Unless the blocks end with the final one, the plain text has to extend far enough past them
+/// that the predictor doesn't notice that the rest is missing. The actions are also recorded
+/// and replayed to verify the blocks if verify is set, and the bits that the encoder spent on
+/// each block are added to its cost.
+#[allow(clippy::too_many_arguments)]
+pub fn predict_next_blocks<E: PredictionEncoder, M: MatchPredictor + Clone>(
+    plain_text: &[u8],
+    params: &PreflateParameters,
+    (blocks, costs): (&[PreflateTokenBlock], &mut [BlockCost]),
+    first_block: usize,
+    ends_with_final: bool,
+    input_pos: u32,
+    snapshot: &HashChainSnapshot,
+    encoder: &mut E,
+    verify: bool,
+    match_predictor: &M,
+    observer: &mut dyn BlockObserver,
+) -> Result<HashChainSnapshot, PreflateError> {
+    // the blocks after the ones that have been read aren't known yet, but they exist
+    let block_count = if ends_with_final {
+        first_block + blocks.len()
+    } else {
+        usize::MAX
+    };
+
+    with_rotating_hash!(params.hash_algorithm, |SelectedHash| {
+        let token_predictor = || {
+            TokenPredictor::<SelectedHash, _>::from_snapshot(
+                plain_text,
+                params,
+                input_pos,
+                snapshot,
+                match_predictor.clone(),
+            )
+            .map_err(|e| PreflateError::RecompressFailed(e.into()))
+        };
+
+        if !verify {
+            return predict_next_blocks_with(
+                token_predictor()?,
+                params.huff_calc,
+                (blocks, costs),
+                first_block,
+                block_count,
+                encoder,
+                observer,
+            );
+        }
+
+        let mut recorder = VerifyPredictionEncoder::new();
+        let snapshot_after = predict_next_blocks_with(
+            token_predictor()?,
+            params.huff_calc,
+            (blocks, costs),
+            first_block,
+            block_count,
+            &mut (&mut recorder, &mut *encoder),
+            observer,
+        )?;
+
+        let actions = recorder.actions();
+        let mut block_starts: Vec<usize> = actions
+            .iter()
+            .enumerate()
+            .filter(|(_, a)| **a == CodecAction::VerifyState("blocktypestart", 0))
+            .map(|(i, _)| i)
+            .collect();
+        block_starts.push(actions.len());
+
+        verify_blocks(
+            token_predictor()?,
+            blocks,
+            0..blocks.len(),
+            &actions,
+            &block_starts,
+            VerifyMode::Full,
+            params.huff_calc,
+        )?;
+        Ok(snapshot_after)
+    })
+}
+
+/// predicts the blocks for predict_next_blocks and returns the snapshot after them
+fn predict_next_blocks_with<H: RotatingHashTrait, M: MatchPredictor, E: PredictionEncoder>(
+    mut token_predictor: TokenPredictor<H, M>,
+    huff_calc: HufftreeBitCalc,
+    (blocks, costs): (&[PreflateTokenBlock], &mut [BlockCost]),
+    first_block: usize,
+    block_count: usize,
+    encoder: &mut E,
+    observer: &mut dyn BlockObserver,
+) -> Result<HashChainSnapshot, PreflateError> {
+    if first_block == 0 && token_predictor.input_eof() {
+        encoder.encode_misprediction(CodecMisprediction::EOFMisprediction, true);
+    }
+
+    let mut bits_before = encoder.statistics().total_bits();
+    for (j, block) in blocks.iter().enumerate() {
+        check_cancelled(observer)?;
+
+        let i = first_block + j;
+        token_predictor.predict_block(i, block, encoder, i + 1 == block_count, observer)?;
+
+        if block.block_type == BlockType::DynamicHuff {
+            predict_tree_for_block(&block.huffman_encoding, &block.freq, encoder, huff_calc)
+                .map_err(|e| PreflateError::PredictTree(i, e))?;
+        }
+        encode_next_block(&token_predictor, i, block_count, encoder);
+
+        let bits = encoder.statistics().total_bits();
+        costs[j].correction_bits = bits - bits_before;
+        bits_before = bits;
+    }
+    Ok(token_predictor.hash_snapshot())
 }
 
 pub fn write_deflate<D: PredictionDecoder>(
     plain_text: &[u8],
     decoder: &mut D,
+) -> Result<(Vec<u8>, Vec<PreflateTokenBlock>), PreflateError> {
+    write_deflate_with_predictor(plain_text, decoder, &ZlibMatchPredictor::default(), &mut ())
+}
+
+/// same as write_deflate, but uses the given match predictor, which has to be
+/// the same one that was used when the stream was read. The observer is told how many
+/// corrections each block needed.
+pub fn write_deflate_with_predictor<D: PredictionDecoder, M: MatchPredictor + Clone>(
+    plain_text: &[u8],
+    decoder: &mut D,
+    match_predictor: &M,
+    observer: &mut dyn BlockObserver,
+) -> Result<(Vec<u8>, Vec<PreflateTokenBlock>), PreflateError> {
+    #[cfg(feature = "dyn_dispatch")]
+    let decoder = &mut (decoder as &mut dyn PredictionDecoder);
+
+    write_deflate_impl(plain_text, decoder, match_predictor, observer)
+}
+
+fn write_deflate_impl<D: PredictionDecoder, M: MatchPredictor + Clone>(
+    plain_text: &[u8],
+    decoder: &mut D,
+    match_predictor: &M,
+    observer: &mut dyn BlockObserver,
 ) -> Result<(Vec<u8>, Vec<PreflateTokenBlock>), PreflateError> {
     let params = PreflateParameters::read(decoder);
     let mut deflate_writer: DeflateWriter<'_> = DeflateWriter::new(plain_text);
 
-    let output_blocks = if params.hash_algorithm == HASH_ALGORITHM_MINIZ_FAST {
-        recreate_blocks(
-            TokenPredictor::<MiniZHash>::new(plain_text, &params, 0),
-            decoder,
-            &mut deflate_writer,
-        )?
-    } else {
-        recreate_blocks(
-            TokenPredictor::<ZlibRotatingHash>::new(plain_text, &params, 0),
-            decoder,
-            &mut deflate_writer,
-        )?
-    };
+    let output_blocks = recreate_blocks(
+        plain_text,
+        params,
+        decoder,
+        match_predictor,
+        &mut deflate_writer,
+        observer,
+    )?;
 
     // flush the last byte, which may be incomplete and normally
     // padded with zeros, but maybe not
@@ -141,35 +945,752 @@ pub fn write_deflate<D: PredictionDecoder>(
     Ok((deflate_writer.detach_output(), output_blocks))
 }
 
-fn recreate_blocks<H: RotatingHashTrait, D: PredictionDecoder>(
-    mut token_predictor: TokenPredictor<H>,
+/// Recreates the stream from the corrections like write_deflate_with_predictor, but passes the
+/// output to on_chunk block by block as it is produced instead of collecting it. The recreated
+/// blocks aren't kept either, so apart from the plain text this only needs as much memory as
+/// the largest block.
+pub fn write_deflate_chunked<D: PredictionDecoder, M: MatchPredictor + Clone>(
+    plain_text: &[u8],
     decoder: &mut D,
+    match_predictor: &M,
+    on_chunk: &mut dyn FnMut(&[u8]) -> Result<(), PreflateError>,
+    observer: &mut dyn BlockObserver,
+) -> Result<(), PreflateError> {
+    #[cfg(feature = "dyn_dispatch")]
+    let decoder = &mut (decoder as &mut dyn PredictionDecoder);
+
+    let params = PreflateParameters::read(decoder);
+    let mut deflate_writer: DeflateWriter<'_> = DeflateWriter::new(plain_text);
+
+    recreate_segments(
+        plain_text,
+        params,
+        decoder,
+        match_predictor,
+        &mut deflate_writer,
+        observer,
+        |_block, deflate_writer| on_chunk(&deflate_writer.detach_output()),
+    )?;
+
+    let padding = decoder.decode_correction(CodecCorrection::NonZeroPadding) as u8;
+    deflate_writer.flush_with_padding(padding);
+    on_chunk(&deflate_writer.detach_output())
+}
+
+/// How much plain text has to follow the most that the next block can cover before it is
+/// recreated from a plain text that arrives in chunks. The predictor looks a few hundred bytes
+/// past the position that it predicts.
+const RECREATION_LOOKAHEAD: u64 = 4096;
+
+/// How far recreating a stream from a plain text that arrives in chunks has got, for
+/// streaming::DeflateStreamRecompressor. The positions are in the plain text that is passed to
+/// recreate_next_blocks, the start of which can be dropped with drop_leading.
+pub struct ChunkedRecreation {
+    params: PreflateParameters,
+    /// the longest match, which bounds the plain text of a block
+    max_match: u32,
+    /// the predictor at the start of the next block, None before the first one
+    snapshot: Option<HashChainSnapshot>,
+    /// where the next block starts in the plain text
+    input_pos: u32,
+    block_index: usize,
+    /// the start of the next block once it has been decoded
+    next: Option<BlockStart>,
+    /// the blocks of the history before the next block (see history_before), for the
+    /// predictor of new parameters
+    history: VecDeque<PreflateTokenBlock>,
+    /// the bits of the last byte of the output that isn't complete yet
+    pending_bits: BitWriter,
+    /// set once the final block has been written
+    done: bool,
+}
+
+impl ChunkedRecreation {
+    /// starts with the parameters at the start of the corrections
+    pub fn new<D: PredictionDecoder>(decoder: &mut D, deflate64: bool) -> Self {
+        ChunkedRecreation {
+            params: PreflateParameters::read(decoder),
+            max_match: if deflate64 {
+                preflate_constants::DEFLATE64_MAX_MATCH
+            } else {
+                preflate_constants::MAX_MATCH
+            },
+            snapshot: None,
+            input_pos: 0,
+            block_index: 0,
+            next: None,
+            history: VecDeque::new(),
+            pending_bits: BitWriter::default(),
+            done: false,
+        }
+    }
+
+    /// how much of the start of the plain text isn't needed anymore, which is everything before
+    /// the history of the next block and before what the snapshot refers to
+    pub fn unreferenced_len(&self) -> u32 {
+        let history_pos =
+            self.input_pos - self.history.iter().map(|b| b.uncompressed_len).sum::<u32>();
+        self.snapshot
+            .as_ref()
+            .map_or(0, |s| s.unreferenced_len().min(history_pos))
+    }
+
+    /// drops the first len bytes of the plain text, at most unreferenced_len
+    pub fn drop_leading(&mut self, len: u32) {
+        if let Some(snapshot) = &mut self.snapshot {
+            snapshot.drop_leading(len);
+        }
+        self.input_pos -= len;
+    }
+
+    /// Whether the next block can be recreated, which is once the plain text covers the most
+    /// that it can cover with RECREATION_LOOKAHEAD to spare, or once all of it is there. Before
+    /// the first block, this also decides whether the stream has any.
+    fn next_block_ready<D: PredictionDecoder>(
+        &mut self,
+        plain_text_len: usize,
+        complete: bool,
+        decoder: &mut D,
+    ) -> Result<bool, PreflateError> {
+        if self.done {
+            return Ok(false);
+        }
+        if self.block_index == 0 && self.next.is_none() {
+            if plain_text_len == 0 && !complete {
+                return Ok(false);
+            }
+            if plain_text_len == 0
+                && !decoder.decode_misprediction(CodecMisprediction::EOFMisprediction)
+            {
+                self.done = true;
+                return Ok(false);
+            }
+        }
+
+        let next = match self.next {
+            Some(next) => next,
+            None => *self
+                .next
+                .insert(BlockStart::decode(self.block_index, decoder, &self.params)?),
+        };
+        Ok(complete
+            || plain_text_len as u64
+                >= u64::from(self.input_pos)
+                    + next.max_uncompressed_len(self.max_match)
+                    + RECREATION_LOOKAHEAD)
+    }
+}
+
+/// Recreates the blocks of the stream that are covered by the plain text so far, see
+/// ChunkedRecreation, and passes their output to on_chunk. Once the plain text is complete, the
+/// rest of the stream is recreated, including the padding after the final block.
+pub fn recreate_next_blocks<D: PredictionDecoder, M: MatchPredictor + Clone>(
+    recreation: &mut ChunkedRecreation,
+    plain_text: &[u8],
+    complete: bool,
+    decoder: &mut D,
+    match_predictor: &M,
+    on_chunk: &mut dyn FnMut(&[u8]) -> Result<(), PreflateError>,
+    observer: &mut dyn BlockObserver,
+) -> Result<(), PreflateError> {
+    while recreation.next_block_ready(plain_text.len(), complete, decoder)? {
+        let params = recreation.params;
+        let params_changed = with_rotating_hash!(params.hash_algorithm, |SelectedHash| {
+            let token_predictor = match &recreation.snapshot {
+                Some(snapshot) => TokenPredictor::<SelectedHash, _>::from_snapshot(
+                    plain_text,
+                    &params,
+                    recreation.input_pos,
+                    snapshot,
+                    match_predictor.clone(),
+                )
+                .map_err(|e| PreflateError::RecompressFailed(e.into()))?,
+                None => TokenPredictor::<SelectedHash, _>::new(
+                    plain_text,
+                    &params,
+                    0,
+                    match_predictor.clone(),
+                ),
+            };
+            recreate_next_blocks_with(
+                token_predictor,
+                recreation,
+                plain_text,
+                complete,
+                decoder,
+                on_chunk,
+                observer,
+            )
+        })?;
+
+        if params_changed {
+            // like recreate_segments, the predictor for the new parameters goes through the
+            // history before the next block
+            recreation.params = PreflateParameters::read(decoder);
+            let history_pos = recreation.input_pos
+                - recreation
+                    .history
+                    .iter()
+                    .map(|b| b.uncompressed_len)
+                    .sum::<u32>();
+            recreation.snapshot = Some(with_token_predictor!(
+                plain_text,
+                &recreation.params,
+                history_pos,
+                &recreation.history,
+                match_predictor,
+                |token_predictor| token_predictor.hash_snapshot()
+            ));
+        }
+    }
+
+    if recreation.done && complete {
+        let mut deflate_writer =
+            DeflateWriter::with_pending_bits(&[], std::mem::take(&mut recreation.pending_bits));
+        let padding = decoder.decode_correction(CodecCorrection::NonZeroPadding) as u8;
+        deflate_writer.flush_with_padding(padding);
+        on_chunk(&deflate_writer.detach_output())?;
+    }
+    Ok(())
+}
+
+/// Recreates the blocks for recreate_next_blocks as long as the plain text covers them, and
+/// returns whether the parameters change before the next block.
+fn recreate_next_blocks_with<H: RotatingHashTrait, M: MatchPredictor, D: PredictionDecoder>(
+    mut token_predictor: TokenPredictor<H, M>,
+    recreation: &mut ChunkedRecreation,
+    plain_text: &[u8],
+    complete: bool,
+    decoder: &mut D,
+    on_chunk: &mut dyn FnMut(&[u8]) -> Result<(), PreflateError>,
+    observer: &mut dyn BlockObserver,
+) -> Result<bool, PreflateError> {
+    let mut deflate_writer = DeflateWriter::with_pending_bits(
+        &plain_text[recreation.input_pos as usize..],
+        std::mem::take(&mut recreation.pending_bits),
+    );
+
+    let params_changed = loop {
+        check_cancelled(observer)?;
+
+        let block_index = recreation.block_index;
+        let start = recreation.next.take().unwrap();
+        let mut block =
+            token_predictor.recreate_block_from(start, block_index, decoder, observer)?;
+
+        if block.block_type == BlockType::DynamicHuff {
+            block.huffman_encoding =
+                recreate_tree_for_block(&block.freq, decoder, recreation.params.huff_calc)
+                    .map_err(|e| PreflateError::RecreateTree(block_index, e))?;
+        }
+
+        let is_eof = token_predictor.input_eof()
+            && !decoder.decode_misprediction(CodecMisprediction::EOFMisprediction);
+        let params_changed =
+            !is_eof && decoder.decode_misprediction(CodecMisprediction::ParametersChanged);
+
+        deflate_writer
+            .encode_block(&block, is_eof)
+            .map_err(|e| PreflateError::EncodeBlock(block_index, e))?;
+        on_chunk(&deflate_writer.detach_output())?;
+
+        let input_pos = token_predictor.current_input_pos();
+        block.uncompressed_len = input_pos - recreation.input_pos;
+        recreation.history.push_back(block);
+        let history_len = recreation.history.len();
+        let history_start =
+            history_before(recreation.history.make_contiguous(), history_len, input_pos).1;
+        recreation.history.drain(..history_start);
+
+        recreation.block_index += 1;
+        recreation.input_pos = input_pos;
+        recreation.done = is_eof;
+
+        if is_eof
+            || params_changed
+            || !recreation.next_block_ready(plain_text.len(), complete, decoder)?
+        {
+            break params_changed;
+        }
+    };
+
+    recreation.snapshot = Some(token_predictor.hash_snapshot());
+    recreation.pending_bits = deflate_writer.into_pending_bits();
+    Ok(params_changed)
+}
+
+/// Recreates the stream from the corrections and compares the output with the original stream
+/// block by block as it is produced, without keeping the recompressed stream.
+pub fn verify_deflate_streaming<D: PredictionDecoder, M: MatchPredictor + Clone>(
+    plain_text: &[u8],
+    original: &[u8],
+    decoder: &mut D,
+    match_predictor: &M,
+    observer: &mut dyn BlockObserver,
+) -> Result<(), PreflateError> {
+    let mut compared = 0;
+    write_deflate_chunked(
+        plain_text,
+        decoder,
+        match_predictor,
+        &mut |chunk| compare_chunk(original, &mut compared, chunk),
+        observer,
+    )?;
+
+    if compared != original.len() {
+        return Err(PreflateError::Mismatch(anyhow::anyhow!(
+            "recompressed data ends after {} bytes, expected {}",
+            compared,
+            original.len()
+        )));
+    }
+    Ok(())
+}
+
+/// compares the next chunk of recompressed data with the original and advances the position
+fn compare_chunk(original: &[u8], position: &mut usize, chunk: &[u8]) -> Result<(), PreflateError> {
+    let expected = &original[(*position).min(original.len())..];
+    let expected = &expected[..chunk.len().min(expected.len())];
+
+    if let Some(offset) = chunk.iter().zip(expected).position(|(a, b)| a != b) {
+        return Err(PreflateError::Mismatch(anyhow::anyhow!(
+            "recompressed data differs from the original at offset {}",
+            *position + offset
+        )));
+    }
+    if expected.len() < chunk.len() {
+        return Err(PreflateError::Mismatch(anyhow::anyhow!(
+            "recompressed data is longer than the original {} bytes",
+            original.len()
+        )));
+    }
+
+    *position += chunk.len();
+    Ok(())
+}
+
+/// recreates the blocks and writes them to the deflate writer, returning the blocks
+fn recreate_blocks<D: PredictionDecoder, M: MatchPredictor + Clone>(
+    plain_text: &[u8],
+    params: PreflateParameters,
+    decoder: &mut D,
+    match_predictor: &M,
     deflate_writer: &mut DeflateWriter,
+    observer: &mut dyn BlockObserver,
 ) -> Result<Vec<PreflateTokenBlock>, PreflateError> {
     let mut output_blocks = Vec::new();
-    let mut is_eof = token_predictor.input_eof()
+    recreate_segments(
+        plain_text,
+        params,
+        decoder,
+        match_predictor,
+        deflate_writer,
+        observer,
+        |block, _| {
+            output_blocks.push(block);
+            Ok(())
+        },
+    )?;
+    Ok(output_blocks)
+}
+
+/// how far recreating the blocks got, which carries over to the predictor for the new
+/// parameters when they were estimated again partway through the stream
+struct RecreatedSoFar<'a> {
+    block_index: usize,
+    input_pos: u32,
+    produced: u64,
+    plain_text_crc: PlainTextCrc<'a>,
+    /// the blocks of the history before the next block (see history_before), with the length
+    /// of their plain text
+    history: VecDeque<PreflateTokenBlock>,
+}
+
+/// Recreates the blocks and writes them to the deflate writer, handing each one to the callback
+/// once it has been written. Whenever the parameters change, the rest of the blocks are recreated
+/// with a new predictor for them that starts where the previous one stopped.
+fn recreate_segments<D: PredictionDecoder, M: MatchPredictor + Clone>(
+    plain_text: &[u8],
+    mut params: PreflateParameters,
+    decoder: &mut D,
+    match_predictor: &M,
+    deflate_writer: &mut DeflateWriter,
+    observer: &mut dyn BlockObserver,
+    mut written: impl FnMut(PreflateTokenBlock, &mut DeflateWriter) -> Result<(), PreflateError>,
+) -> Result<(), PreflateError> {
+    let mut so_far = RecreatedSoFar {
+        block_index: 0,
+        input_pos: 0,
+        produced: 0,
+        plain_text_crc: PlainTextCrc::new(plain_text),
+        history: VecDeque::new(),
+    };
+    while let Some(next_params) = with_token_predictor!(
+        plain_text,
+        &params,
+        so_far.input_pos
+            - so_far
+                .history
+                .iter()
+                .map(|b| b.uncompressed_len)
+                .sum::<u32>(),
+        &so_far.history,
+        match_predictor,
+        |token_predictor| {
+            recreate_blocks_with(
+                token_predictor,
+                decoder,
+                deflate_writer,
+                params.huff_calc,
+                observer,
+                &mut so_far,
+                &mut written,
+            )
+        }
+    )? {
+        params = next_params;
+    }
+    Ok(())
+}
+
+/// recreates the blocks and writes them to the deflate writer, handing each one to
+/// the callback once it has been written. Stops after the last block, or returns the new
+/// parameters if they change before the next block.
+fn recreate_blocks_with<H: RotatingHashTrait, M: MatchPredictor, D: PredictionDecoder>(
+    mut token_predictor: TokenPredictor<H, M>,
+    decoder: &mut D,
+    deflate_writer: &mut DeflateWriter,
+    huff_calc: HufftreeBitCalc,
+    observer: &mut dyn BlockObserver,
+    so_far: &mut RecreatedSoFar<'_>,
+    mut written: impl FnMut(PreflateTokenBlock, &mut DeflateWriter) -> Result<(), PreflateError>,
+) -> Result<Option<PreflateParameters>, PreflateError> {
+    let mut is_eof = so_far.block_index == 0
+        && token_predictor.input_eof()
         && !decoder.decode_misprediction(CodecMisprediction::EOFMisprediction);
     while !is_eof {
-        let mut block = token_predictor
-            .recreate_block(decoder)
-            .map_err(|e| PreflateError::RecreateBlock(output_blocks.len(), e))?;
+        check_cancelled(observer)?;
+
+        let block_index = so_far.block_index;
+        let mut block = token_predictor.recreate_block(block_index, decoder, observer)?;
 
         if block.block_type == BlockType::DynamicHuff {
-            block.huffman_encoding =
-                recreate_tree_for_block(&block.freq, decoder, HufftreeBitCalc::Zlib)
-                    .map_err(|e| PreflateError::RecreateTree(output_blocks.len(), e))?;
+            block.huffman_encoding = recreate_tree_for_block(&block.freq, decoder, huff_calc)
+                .map_err(|e| PreflateError::RecreateTree(block_index, e))?;
         }
 
         is_eof = token_predictor.input_eof()
             && !decoder.decode_misprediction(CodecMisprediction::EOFMisprediction);
+        let params_changed =
+            !is_eof && decoder.decode_misprediction(CodecMisprediction::ParametersChanged);
 
+        let start = deflate_writer.output_len();
         deflate_writer
             .encode_block(&block, is_eof)
-            .map_err(|e| PreflateError::EncodeBlock(output_blocks.len(), e))?;
+            .map_err(|e| PreflateError::EncodeBlock(block_index, e))?;
+
+        so_far.produced += (deflate_writer.output_len() - start) as u64;
+        let consumed = token_predictor.current_input_pos().into();
+        observer.progress(&Progress {
+            consumed,
+            produced: so_far.produced,
+            plain_text_crc32: so_far.plain_text_crc.up_to(consumed),
+        });
 
-        output_blocks.push(block);
+        let input_pos = token_predictor.current_input_pos();
+        let mut history_block = block.clone();
+        history_block.uncompressed_len = input_pos - so_far.input_pos;
+        so_far.history.push_back(history_block);
+        let history_len = so_far.history.len();
+        let history_start =
+            history_before(so_far.history.make_contiguous(), history_len, input_pos).1;
+        so_far.history.drain(..history_start);
+
+        written(block, deflate_writer)?;
+        so_far.block_index += 1;
+        so_far.input_pos = input_pos;
+
+        if params_changed {
+            return Ok(Some(PreflateParameters::read(decoder)));
+        }
     }
-    Ok(output_blocks)
+    Ok(None)
+}
+
+/// Verifies the blocks selected by the verify mode by replaying the actions that were
+/// recorded while predicting them and comparing the recreated blocks with the originals.
+/// Blocks that are not selected are only used to advance the predictor, which avoids
+/// the expensive match searching for them. The blocks from each of the segments on are replayed
+/// with a new predictor for the parameters of the segment, like when they were predicted.
+pub fn verify_sampled_blocks<M: MatchPredictor + Clone>(
+    plain_text: &[u8],
+    params: &PreflateParameters,
+    segments: &[ParameterSegment],
+    blocks: &[PreflateTokenBlock],
+    actions: &[CodecAction],
+    mode: VerifyMode,
+    match_predictor: &M,
+) -> Result<(), PreflateError> {
+    // every block starts with a verify marker, so we can use it to find the actions for each block
+    let mut block_starts: Vec<usize> = actions
+        .iter()
+        .enumerate()
+        .filter(|(_, a)| **a == CodecAction::VerifyState("blocktypestart", 0))
+        .map(|(i, _)| i)
+        .collect();
+
+    if block_starts.len() != blocks.len() {
+        return Err(PreflateError::Mismatch(anyhow::anyhow!(
+            "recorded {} blocks but stream contains {}",
+            block_starts.len(),
+            blocks.len()
+        )));
+    }
+
+    block_starts.push(actions.len());
+
+    let mut input_pos = 0;
+    let starts = std::iter::once((0, *params)).chain(segments.iter().copied());
+    let ends = segments.iter().map(|s| s.0).chain([blocks.len()]);
+    for ((first_block, params), end) in starts.zip(ends) {
+        let (history_pos, history_start) = history_before(blocks, first_block, input_pos);
+        with_token_predictor!(
+            plain_text,
+            &params,
+            history_pos,
+            &blocks[history_start..first_block],
+            match_predictor,
+            |token_predictor| {
+                verify_blocks(
+                    token_predictor,
+                    blocks,
+                    first_block..end,
+                    actions,
+                    &block_starts[first_block..],
+                    mode,
+                    params.huff_calc,
+                )
+            }
+        )?;
+
+        input_pos += blocks[first_block..end]
+            .iter()
+            .map(|b| b.uncompressed_len)
+            .sum::<u32>();
+    }
+    Ok(())
+}
+
+/// verifies the blocks in the range, where block_starts are the positions in the actions at which
+/// the blocks of the range start
+fn verify_blocks<H: RotatingHashTrait, M: MatchPredictor>(
+    mut token_predictor: TokenPredictor<H, M>,
+    blocks: &[PreflateTokenBlock],
+    range: Range<usize>,
+    actions: &[CodecAction],
+    block_starts: &[usize],
+    mode: VerifyMode,
+    huff_calc: HufftreeBitCalc,
+) -> Result<(), PreflateError> {
+    for i in range.clone() {
+        let original = &blocks[i];
+        if !mode.should_verify_block(i, blocks.len()) {
+            token_predictor.skip_block(original);
+            continue;
+        }
+
+        let j = i - range.start;
+        let mut decoder =
+            VerifyPredictionDecoder::new(actions[block_starts[j]..block_starts[j + 1]].to_vec());
+
+        let mut block = token_predictor.recreate_block(i, &mut decoder, &mut ())?;
+
+        if block.block_type == BlockType::DynamicHuff {
+            block.huffman_encoding = recreate_tree_for_block(&block.freq, &mut decoder, huff_calc)
+                .map_err(|e| PreflateError::RecreateTree(i, e))?;
+        }
+
+        // the uncompressed length is only tracked for stored blocks when recreating
+        if block.block_type != original.block_type
+            || (block.block_type == BlockType::Stored
+                && (block.uncompressed_len != original.uncompressed_len
+                    || block.nlen_mismatch != original.nlen_mismatch
+                    || block.flush != original.flush))
+            || block.padding_bits != original.padding_bits
+            || block.tokens != original.tokens
+            || block.huffman_encoding != original.huffman_encoding
+        {
+            return Err(PreflateError::Mismatch(anyhow::anyhow!(
+                "recreated block {} does not match original",
+                i
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Parses the stream and estimates the parameters, without predicting anything. Returns the
+/// plain text, the blocks and the parameters.
+pub fn parse_deflate(
+    compressed_data: &[u8],
+) -> Result<(Vec<u8>, Vec<PreflateTokenBlock>, PreflateParameters), PreflateError> {
+    let (blocks, _costs, plain_text, _eof_padding, _processed) =
+        read_blocks(compressed_data, 0, &PreflateConfig::default(), &mut |_| {})?;
+    let params = estimate_preflate_parameters(
+        &plain_text,
+        &blocks,
+        &ParserConfigRegistry::default(),
+        &CompressorProfileRegistry::default(),
+    );
+    Ok((plain_text, blocks, params))
+}
+
+/// Brings the predictor up to date with each block without predicting it, and takes a
+/// snapshot of the predictor at the start of every `interval`-th block. Returns the index of the
+/// block, the position in the plain text and the hash chain for each snapshot.
+pub fn snapshot_blocks<M: MatchPredictor + Clone>(
+    plain_text: &[u8],
+    params: &PreflateParameters,
+    blocks: &[PreflateTokenBlock],
+    interval: usize,
+    match_predictor: &M,
+) -> Vec<(usize, u32, HashChainSnapshot)> {
+    snapshots_at(
+        plain_text,
+        params,
+        blocks,
+        |i| i % interval.max(1) == 0,
+        match_predictor,
+    )
+}
+
+/// takes a snapshot of the predictor at the start of each block for which is_start is true,
+/// see snapshot_blocks
+fn snapshots_at<M: MatchPredictor + Clone>(
+    plain_text: &[u8],
+    params: &PreflateParameters,
+    blocks: &[PreflateTokenBlock],
+    is_start: impl Fn(usize) -> bool,
+    match_predictor: &M,
+) -> Vec<(usize, u32, HashChainSnapshot)> {
+    with_token_predictor!(plain_text, params, match_predictor, |token_predictor| {
+        let mut token_predictor = token_predictor;
+        let mut snapshots = Vec::new();
+        for (i, block) in blocks.iter().enumerate() {
+            if is_start(i) {
+                snapshots.push((
+                    i,
+                    token_predictor.current_input_pos(),
+                    token_predictor.hash_snapshot(),
+                ));
+            }
+            token_predictor.skip_block(block);
+        }
+        snapshots
+    })
+}
+
+/// Predicts the blocks in the range with a predictor that continues from the snapshot
+/// and returns the recorded actions. These are the same as the actions recorded for these
+/// blocks when predicting the whole stream, since the predictor is in the same state.
+fn predict_blocks_from_snapshot<M: MatchPredictor + Clone>(
+    plain_text: &[u8],
+    params: &PreflateParameters,
+    blocks: &[PreflateTokenBlock],
+    range: Range<usize>,
+    input_pos: u32,
+    snapshot: &HashChainSnapshot,
+    match_predictor: &M,
+) -> Result<Vec<CodecAction>, PreflateError> {
+    let mut encoder = VerifyPredictionEncoder::new();
+
+    with_rotating_hash!(params.hash_algorithm, |SelectedHash| {
+        let mut token_predictor = TokenPredictor::<SelectedHash, _>::from_snapshot(
+            plain_text,
+            params,
+            input_pos,
+            snapshot,
+            match_predictor.clone(),
+        )
+        .map_err(|e| PreflateError::RecompressFailed(e.into()))?;
+
+        for i in range {
+            token_predictor.predict_block(
+                i,
+                &blocks[i],
+                &mut encoder,
+                i == blocks.len() - 1,
+                &mut (),
+            )?;
+
+            if blocks[i].block_type == BlockType::DynamicHuff {
+                predict_tree_for_block(
+                    &blocks[i].huffman_encoding,
+                    &blocks[i].freq,
+                    &mut encoder,
+                    params.huff_calc,
+                )
+                .map_err(|e| PreflateError::PredictTree(i, e))?;
+            }
+            encode_next_block(&token_predictor, i, blocks.len(), &mut encoder);
+        }
+
+        Ok::<(), PreflateError>(())
+    })?;
+
+    Ok(encoder.actions())
+}
+
+/// Predicts the blocks in the range starting from the snapshot, and then recreates them from the
+/// recorded actions (again starting from the snapshot) and compares them with the original blocks.
+pub fn verify_blocks_from_snapshot<M: MatchPredictor + Clone>(
+    plain_text: &[u8],
+    params: &PreflateParameters,
+    blocks: &[PreflateTokenBlock],
+    range: Range<usize>,
+    input_pos: u32,
+    snapshot: &HashChainSnapshot,
+    match_predictor: &M,
+) -> Result<(), PreflateError> {
+    let actions = predict_blocks_from_snapshot(
+        plain_text,
+        params,
+        blocks,
+        range.clone(),
+        input_pos,
+        snapshot,
+        match_predictor,
+    )?;
+
+    let mut block_starts: Vec<usize> = actions
+        .iter()
+        .enumerate()
+        .filter(|(_, a)| **a == CodecAction::VerifyState("blocktypestart", 0))
+        .map(|(i, _)| i)
+        .collect();
+    block_starts.push(actions.len());
+
+    with_rotating_hash!(params.hash_algorithm, |SelectedHash| {
+        let token_predictor = TokenPredictor::<SelectedHash, _>::from_snapshot(
+            plain_text,
+            params,
+            input_pos,
+            snapshot,
+            match_predictor.clone(),
+        )
+        .map_err(|e| PreflateError::RecompressFailed(e.into()))?;
+
+        verify_blocks(
+            token_predictor,
+            blocks,
+            range.clone(),
+            &actions,
+            &block_starts,
+            VerifyMode::Full,
+            params.huff_calc,
+        )
+    })
 }
 
 #[cfg(test)]
@@ -414,3 +1935,493 @@ fn verify_miniz_compressed() {
         do_analyze(None, &v, false);
     }
 }
+
+/// the generic estimate of the streams of miniz_oxide takes the settings of its levels instead
+/// of the ones of zlib, and each level is identified by its profile
+#[test]
+fn verify_miniz_levels() {
+    use crate::complevel_estimator::estimate_preflate_comp_level;
+    use crate::preflate_parse_config::MINIZ_PARSER_SETTINGS;
+
+    for level in 1..=9 {
+        let v = read_file(&format!("compressed_flate2_level{}.deflate", level));
+
+        let (blocks, _costs, plain_text, _, _) =
+            read_blocks(&v, 0, &PreflateConfig::default(), &mut |_| {}).unwrap();
+        let cl = estimate_preflate_comp_level(
+            15,
+            8,
+            &plain_text,
+            &blocks,
+            0,
+            &ParserConfigRegistry::default(),
+            HufftreeBitCalc::Miniz,
+        );
+        let config = &MINIZ_PARSER_SETTINGS[level - 1];
+        assert_eq!(cl.fast_compressor, level <= 3, "level {}", level);
+        if level > 1 {
+            assert_eq!(
+                (cl.good_length, cl.max_lazy, cl.nice_length, cl.max_chain),
+                (
+                    config.good_length,
+                    config.max_lazy,
+                    config.nice_length,
+                    config.max_chain
+                ),
+                "level {}",
+                level
+            );
+        }
+
+        let result = crate::decompress_deflate_stream(&v, true).unwrap();
+        assert_eq!(result.profile.name, format!("miniz-{}", level));
+    }
+}
+
+// we don't have samples compressed with a 4 byte hash, so generate the blocks by letting the
+// predictor compress the plain text without any corrections, which should then be predicted perfectly
+#[test]
+fn verify_four_byte_hashes() {
+    use crate::statistical_codec::{
+        DefaultOnlyDecoder, VerifyPredictionDecoder, VerifyPredictionEncoder,
+    };
+
+    let compressed_data = read_file("compressed_zlib_level6.deflate");
+
+    let (_, params, plain_text, _) =
+        read_deflate(&compressed_data, &mut VerifyPredictionEncoder::new(), 0).unwrap();
+
+    for hash_algorithm in [HASH_ALGORITHM_LIBDEFLATE4, HASH_ALGORITHM_CRC32] {
+        let params = PreflateParameters {
+            hash_algorithm,
+            ..params
+        };
+
+        let mut deflate_writer = DeflateWriter::new(&plain_text);
+        let blocks = recreate_blocks(
+            &plain_text,
+            params,
+            &mut DefaultOnlyDecoder {},
+            &ZlibMatchPredictor::default(),
+            &mut deflate_writer,
+            &mut (),
+        )
+        .unwrap();
+        assert!(blocks.iter().any(|b| b
+            .tokens
+            .iter()
+            .any(|t| matches!(t, crate::preflate_token::PreflateToken::Reference(_)))));
+
+        let mut encoder = VerifyPredictionEncoder::new();
+        with_token_predictor!(
+            &plain_text[..],
+            &params,
+            ZlibMatchPredictor::default(),
+            |token_predictor| predict_blocks(
+                &blocks,
+                0,
+                &mut vec![BlockCost::default(); blocks.len()],
+                token_predictor,
+                &mut encoder,
+                &params,
+                &mut (),
+                &mut PlainTextCrc::new(&plain_text),
+                None
+            )
+        )
+        .unwrap();
+        encoder.encode_misprediction(CodecMisprediction::EOFMisprediction, false);
+
+        assert_eq!(encoder.count_nondefault_actions(), 0);
+
+        let mut decoder = VerifyPredictionDecoder::new(encoder.actions());
+        let mut deflate_writer = DeflateWriter::new(&plain_text);
+        let recreated_blocks = recreate_blocks(
+            &plain_text,
+            params,
+            &mut decoder,
+            &ZlibMatchPredictor::default(),
+            &mut deflate_writer,
+            &mut (),
+        )
+        .unwrap();
+
+        assert_eq!(blocks.len(), recreated_blocks.len());
+        for (a, b) in blocks.iter().zip(recreated_blocks.iter()) {
+            assert!(a.tokens == b.tokens);
+        }
+    }
+}
+
+/// Streams with the hash and the huffman trees of 7-Zip are detected as such. There are no
+/// streams written by 7-Zip itself among the samples, so this one is recreated by the predictor
+/// with the settings of the profile. It only shows that the profile is found again, not how
+/// well its chain search approximates the binary tree and optimal parse of 7-Zip.
+#[test]
+fn verify_seven_zip_profile() {
+    use crate::statistical_codec::{DefaultOnlyDecoder, VerifyPredictionEncoder};
+
+    let compressed_data = read_file("compressed_zlib_level6.deflate");
+
+    let (_, params, plain_text, _) =
+        read_deflate(&compressed_data, &mut VerifyPredictionEncoder::new(), 0).unwrap();
+
+    let params = PreflateParameters {
+        hash_algorithm: HASH_ALGORITHM_7ZIP,
+        hash_mask: 0xffff,
+        huff_calc: HufftreeBitCalc::SevenZip,
+        ..params
+    };
+
+    let mut deflate_writer = DeflateWriter::new(&plain_text);
+    recreate_blocks(
+        &plain_text,
+        params,
+        &mut DefaultOnlyDecoder {},
+        &ZlibMatchPredictor::default(),
+        &mut deflate_writer,
+        &mut (),
+    )
+    .unwrap();
+    deflate_writer.flush_with_padding(0);
+    let seven_zip_data = deflate_writer.detach_output();
+
+    let (_, estimated, _, _) =
+        read_deflate(&seven_zip_data, &mut VerifyPredictionEncoder::new(), 0).unwrap();
+    assert_eq!(estimated.hash_algorithm, HASH_ALGORITHM_7ZIP);
+    assert_eq!(estimated.huff_calc, HufftreeBitCalc::SevenZip);
+    assert!(estimated.encoder_label().starts_with("7zip-"));
+
+    let result = crate::decompress_deflate_stream(&seven_zip_data, true).unwrap();
+    let recompressed =
+        crate::recompress_deflate_stream(&result.plain_text, &result.cabac_encoded).unwrap();
+    assert!(recompressed == seven_zip_data);
+}
+
+/// Streams written by libdeflate 1.14 at level 1 are detected as such. The trees of libdeflate
+/// limit the literal/length codes to 14 bits, and its greedy levels look up the matches of 4
+/// bytes or more deeper in the chain than the profiles follow, so the other levels are only
+/// checked to recompress exactly.
+#[test]
+fn verify_libdeflate_profile() {
+    use crate::statistical_codec::VerifyPredictionEncoder;
+
+    let compressed_data = read_file("compressed_libdeflate_level1.deflate");
+
+    let (_, estimated, _, _) =
+        read_deflate(&compressed_data, &mut VerifyPredictionEncoder::new(), 0).unwrap();
+    assert_eq!(estimated.hash_algorithm, HASH_ALGORITHM_LIBDEFLATE4);
+    assert_eq!(estimated.huff_calc, HufftreeBitCalc::Libdeflate);
+
+    // the parse and the trees are predicted, so only a few corrections are left
+    let result = crate::decompress_deflate_stream(&compressed_data, true).unwrap();
+    assert!(result.cabac_encoded.len() < compressed_data.len() / 100);
+
+    for level in [1, 6, 12] {
+        let compressed_data = read_file(&format!("compressed_libdeflate_level{}.deflate", level));
+        let result = crate::decompress_deflate_stream(&compressed_data, true).unwrap();
+        let recompressed =
+            crate::recompress_deflate_stream(&result.plain_text, &result.cabac_encoded).unwrap();
+        assert!(recompressed == compressed_data);
+    }
+}
+
+/// Streams with the 4 byte hash of ISA-L are detected as such, even though its trees aren't
+/// reproduced by any of the huffman calculations. ISA-L isn't available to write a sample with,
+/// so the stream is recreated by the predictor from the profile, which checks the detection
+/// but not how closely the profile follows the parser of ISA-L.
+#[test]
+fn verify_isal_profile() {
+    use crate::compressor_profile::CompressorProfile;
+    use crate::statistical_codec::{DefaultOnlyDecoder, VerifyPredictionEncoder};
+
+    let compressed_data = read_file("compressed_zlib_level6.deflate");
+
+    let (_, params, plain_text, _) =
+        read_deflate(&compressed_data, &mut VerifyPredictionEncoder::new(), 0).unwrap();
+
+    let profile = CompressorProfile::isal("isal-1", 1);
+    let params = PreflateParameters {
+        hash_algorithm: profile.hash_algorithm,
+        hash_mask: profile.hash_mask,
+        huff_calc: profile.huff_calc,
+        is_fast_compressor: profile.fast_parser,
+        good_length: profile.parser_config.good_length,
+        max_lazy: profile.parser_config.max_lazy,
+        nice_length: profile.parser_config.nice_length,
+        max_chain: profile.parser_config.max_chain,
+        max_dist_3_matches: profile.quirks.max_dist_3_matches,
+        ..params
+    };
+
+    let mut deflate_writer = DeflateWriter::new(&plain_text);
+    recreate_blocks(
+        &plain_text,
+        params,
+        &mut DefaultOnlyDecoder {},
+        &ZlibMatchPredictor::default(),
+        &mut deflate_writer,
+        &mut (),
+    )
+    .unwrap();
+    deflate_writer.flush_with_padding(0);
+    let isal_data = deflate_writer.detach_output();
+
+    let (_, estimated, _, _) =
+        read_deflate(&isal_data, &mut VerifyPredictionEncoder::new(), 0).unwrap();
+    assert_eq!(estimated.hash_algorithm, HASH_ALGORITHM_ISAL);
+
+    let result = crate::decompress_deflate_stream(&isal_data, true).unwrap();
+    assert_eq!(result.profile.name, "isal-1");
+    let recompressed =
+        crate::recompress_deflate_stream(&result.plain_text, &result.cabac_encoded).unwrap();
+    assert!(recompressed == isal_data);
+}
+
+/// A stream written by Zopfli (the Rust port, with its default 15 iterations) is detected as
+/// such, and its optimal parse leaves fewer corrections than predicting it like zlib does.
+#[test]
+fn verify_zopfli_profile() {
+    use crate::preflate_parse_config::PreflateParserConfig;
+    use crate::statistical_codec::VerifyPredictionEncoder;
+
+    let compressed_data = read_file("compressed_zopfli.deflate");
+
+    let (_, estimated, _, _) =
+        read_deflate(&compressed_data, &mut VerifyPredictionEncoder::new(), 0).unwrap();
+    assert_eq!(estimated.huff_calc, HufftreeBitCalc::Zopfli);
+
+    let result =
+        crate::decompress_deflate_stream_with_config(&compressed_data, &PreflateConfig::default())
+            .unwrap();
+
+    // registering a level stops the profiles from being considered, which leaves the lazy
+    // parser of zlib
+    let mut config = PreflateConfig::default();
+    config.parser_configs.register_slow(PreflateParserConfig {
+        good_length: 32,
+        max_lazy: 258,
+        nice_length: 258,
+        max_chain: 4096,
+    });
+    let zlib_result =
+        crate::decompress_deflate_stream_with_config(&compressed_data, &config).unwrap();
+    assert!(result.cabac_encoded.len() < zlib_result.cabac_encoded.len());
+
+    let recompressed =
+        crate::recompress_deflate_stream(&result.plain_text, &result.cabac_encoded).unwrap();
+    assert!(recompressed == compressed_data);
+}
+
+#[test]
+fn reads_context_scheme_of_older_formats() {
+    use crate::cabac_codec::{CorrectionsHeader, OriginalStream, PredictionEncoderCabac};
+    use crate::preflate_config::PreflateConfig;
+    use crate::statistical_codec::{PredictionEncoder, CONTEXT_SCHEME_VERSION};
+    use cabac::vp8::VP8Writer;
+
+    let compressed_data = read_file("compressed_zlib_level1.deflate");
+
+    let mut encoder = VerifyPredictionEncoder::new();
+    let (_, _, plain_text, _) = read_deflate(&compressed_data, &mut encoder, 0).unwrap();
+
+    // the current format only records the scheme in front of the header byte
+    let actions = encoder.actions();
+    assert!(!matches!(actions[0], CodecAction::Value(_, 8)));
+
+    // format version 1 started the coded corrections with the scheme as well
+    let header = CorrectionsHeader::from_config(&PreflateConfig {
+        split_channels: false,
+        ..PreflateConfig::default()
+    });
+    let format1 = |scheme: u16| {
+        let mut corrections = vec![header.to_byte()];
+        corrections.extend_from_slice(&OriginalStream::of(&compressed_data).to_bytes());
+
+        let mut coded = Vec::new();
+        let mut encoder = PredictionEncoderCabac::new(VP8Writer::new(&mut coded).unwrap())
+            .with_model_dictionary(None);
+        encoder.encode_value(scheme, 8);
+        drive_encoder(&mut encoder, &actions);
+        encoder.finish();
+        corrections.extend_from_slice(&coded);
+
+        let mut versioned = vec![0x06, 1];
+        versioned.extend_from_slice(&CONTEXT_SCHEME_VERSION.to_le_bytes());
+        versioned.extend_from_slice(&corrections);
+
+        let mut framed = (versioned.len() as u32).to_le_bytes().to_vec();
+        framed.extend_from_slice(&versioned);
+        framed.extend_from_slice(&crc32fast::hash(&versioned).to_le_bytes());
+        framed.extend_from_slice(&0u32.to_le_bytes());
+        framed.extend_from_slice(&crc32fast::hash(&versioned).to_le_bytes());
+        framed
+    };
+
+    let recompressed =
+        crate::recompress_deflate_stream(&plain_text, &format1(CONTEXT_SCHEME_VERSION)).unwrap();
+    assert!(recompressed == compressed_data);
+
+    // corrections from another scheme would decode into garbage, so they have to be rejected
+    let r = crate::recompress_deflate_stream(&plain_text, &format1(CONTEXT_SCHEME_VERSION - 1));
+    assert!(matches!(r, Err(PreflateError::RecompressFailed(_))));
+}
+
+#[test]
+fn snapshot_predicts_same_actions() {
+    use crate::statistical_codec::PredictionEncoder;
+
+    let compressed_data = read_file("compressed_zlib_level1.deflate");
+
+    let mut encoder = VerifyPredictionEncoder::new();
+    read_deflate(&compressed_data, &mut encoder, 0).unwrap();
+    encoder.finish();
+    let actions = encoder.actions();
+
+    let block_starts: Vec<usize> = actions
+        .iter()
+        .enumerate()
+        .filter(|(_, a)| **a == CodecAction::VerifyState("blocktypestart", 0))
+        .map(|(i, _)| i)
+        .collect();
+
+    let (plain_text, blocks, params) = parse_deflate(&compressed_data).unwrap();
+    assert!(blocks.len() > 4, "need a stream with several blocks");
+
+    let match_predictor = ZlibMatchPredictor::default();
+    let snapshots = snapshot_blocks(&plain_text, &params, &blocks, 2, &match_predictor);
+    assert_eq!(snapshots.len(), (blocks.len() + 1) / 2);
+
+    // every snapshot (except the one of the final block) continues exactly like the whole stream
+    for (block, input_pos, snapshot) in snapshots {
+        if block + 1 >= blocks.len() {
+            continue;
+        }
+
+        let from_snapshot = predict_blocks_from_snapshot(
+            &plain_text,
+            &params,
+            &blocks,
+            block..block + 1,
+            input_pos,
+            &snapshot,
+            &match_predictor,
+        )
+        .unwrap();
+
+        assert!(from_snapshot[..] == actions[block_starts[block]..block_starts[block + 1]]);
+
+        verify_blocks_from_snapshot(
+            &plain_text,
+            &params,
+            &blocks,
+            block..block + 2,
+            input_pos,
+            &snapshot,
+            &match_predictor,
+        )
+        .unwrap();
+    }
+}
+
+#[test]
+fn streaming_verify_finds_differences() {
+    use crate::statistical_codec::VerifyPredictionEncoder;
+
+    let compressed_data = read_file("compressed_zlib_level6.deflate");
+
+    let mut encoder = VerifyPredictionEncoder::new();
+    let (_, _, plain_text, _) = read_deflate(&compressed_data, &mut encoder, 0).unwrap();
+    let actions = encoder.actions();
+
+    let verify = |original: &[u8]| {
+        verify_deflate_streaming(
+            &plain_text,
+            original,
+            &mut VerifyPredictionDecoder::new(actions.clone()),
+            &ZlibMatchPredictor::default(),
+            &mut (),
+        )
+    };
+
+    verify(&compressed_data).unwrap();
+
+    let mut damaged = compressed_data.clone();
+    damaged[1000] ^= 1;
+    assert!(matches!(verify(&damaged), Err(PreflateError::Mismatch(_))));
+
+    // the recreated stream is longer or shorter than the original
+    assert!(matches!(
+        verify(&compressed_data[..compressed_data.len() - 1]),
+        Err(PreflateError::Mismatch(_))
+    ));
+    let mut extended = compressed_data.clone();
+    extended.push(0);
+    assert!(matches!(verify(&extended), Err(PreflateError::Mismatch(_))));
+}
+
+#[test]
+fn deep_match_is_corrected_with_distance() {
+    // a run of the same byte puts every position on the same hash chain, so a match that
+    // refers back further than the chain is walked can only be corrected with its distance
+    let plain_text = vec![b'a'; 10000];
+
+    let mut block = PreflateTokenBlock::new(BlockType::StaticHuff);
+    for _ in 0..9997 {
+        block.add_literal(b'a');
+    }
+    block.add_reference(3, 9000, crate::preflate_token::IrregularEncoding::Canonical);
+
+    let mut deflate_writer = DeflateWriter::new(&plain_text);
+    deflate_writer.encode_block(&block, true).unwrap();
+    deflate_writer.flush_with_padding(0);
+    let compressed_data = deflate_writer.detach_output();
+
+    let mut encoder = VerifyPredictionEncoder::new();
+    read_deflate(&compressed_data, &mut encoder, 0).unwrap();
+    let actions = encoder.actions();
+    assert!(actions.contains(&CodecAction::Correction(
+        CodecCorrection::DeepMatchDistance,
+        9000
+    )));
+
+    let (recompressed, _) =
+        write_deflate(&plain_text, &mut VerifyPredictionDecoder::new(actions)).unwrap();
+    assert_eq!(recompressed, compressed_data);
+}
+
+#[test]
+fn irregular_encoding_roundtrip() {
+    use crate::preflate_token::{IrregularEncoding, PreflateToken};
+
+    let plain_text = vec![b'a'; 1 + 2 * 258];
+
+    let mut block = PreflateTokenBlock::new(BlockType::StaticHuff);
+    block.add_literal(b'a');
+    block.add_reference(258, 1, IrregularEncoding::Len258As284);
+    block.add_reference(258, 1, IrregularEncoding::Canonical);
+
+    let mut deflate_writer = DeflateWriter::new(&plain_text);
+    deflate_writer.encode_block(&block, true).unwrap();
+    deflate_writer.flush_with_padding(0);
+    let compressed_data = deflate_writer.detach_output();
+
+    let mut encoder = VerifyPredictionEncoder::new();
+    let (_, _, decoded, blocks) = read_deflate(&compressed_data, &mut encoder, 0).unwrap();
+    assert_eq!(decoded, plain_text);
+    assert_eq!(blocks[0].tokens, block.tokens);
+    assert!(matches!(
+        blocks[0].tokens[1],
+        PreflateToken::Reference(r) if r.irregular() == IrregularEncoding::Len258As284
+    ));
+
+    let actions = encoder.actions();
+    assert!(actions.contains(&CodecAction::Correction(
+        CodecCorrection::IrregularEncoding,
+        1
+    )));
+
+    let (recompressed, _) =
+        write_deflate(&plain_text, &mut VerifyPredictionDecoder::new(actions)).unwrap();
+    assert_eq!(recompressed, compressed_data);
+}
@@ -0,0 +1,167 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Feedback while a long stream is processed and a way to abort it. Both are checked between
+//! the blocks of the stream, so a single very large block isn't interrupted.
+
+use std::{
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use crate::{
+    block_observer::{BlockObserver, BlockSummary},
+    preflate_config::PreflateConfig,
+    preflate_error::PreflateError,
+};
+
+/// how far the processing of a stream has gotten after a block
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct Progress {
+    /// bytes of the input that are done: the deflate stream when decompressing, the plain
+    /// text when recompressing
+    pub consumed: u64,
+    /// bytes of the output so far: the plain text when decompressing, the deflate stream when
+    /// recompressing
+    pub produced: u64,
+    /// CRC-32 of the plain text up to where the stream is: the plain text that was consumed
+    /// when recompressing, or produced when decompressing
+    pub plain_text_crc32: u32,
+}
+
+/// CRC-32 of the plain text up to the end of the last block, which is updated block by block
+pub(crate) struct PlainTextCrc<'a> {
+    plain_text: &'a [u8],
+    hasher: crc32fast::Hasher,
+    len: usize,
+}
+
+impl<'a> PlainTextCrc<'a> {
+    pub fn new(plain_text: &'a [u8]) -> Self {
+        PlainTextCrc {
+            plain_text,
+            hasher: crc32fast::Hasher::new(),
+            len: 0,
+        }
+    }
+
+    /// the CRC-32 of the first len bytes of the plain text
+    pub fn up_to(&mut self, len: u64) -> u32 {
+        let len = len as usize;
+        self.hasher.update(&self.plain_text[self.len..len]);
+        self.len = len;
+        self.hasher.clone().finalize()
+    }
+}
+
+/// receives the progress of the streams that are processed with the config
+pub trait ProgressCallback: Debug + Send + Sync {
+    fn progress(&self, progress: Progress);
+}
+
+/// Stops the processing of a stream with PreflateError::Cancelled before its next block once
+/// it has been cancelled. The clones of a token share the same state, so one can be kept by the
+/// thread that decides to cancel.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// fails with Cancelled if the token has been cancelled
+    pub fn check(&self) -> Result<(), PreflateError> {
+        if self.is_cancelled() {
+            Err(PreflateError::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// fails with Cancelled if the observer has a token that has been cancelled
+pub(crate) fn check_cancelled(observer: &dyn BlockObserver) -> Result<(), PreflateError> {
+    observer
+        .cancellation()
+        .map_or(Ok(()), CancellationToken::check)
+}
+
+/// passes the blocks on to the observer of the caller, and the progress and cancellation on
+/// to the ones of the config as well
+pub(crate) struct ConfigObserver<'a> {
+    inner: &'a mut dyn BlockObserver,
+    progress: Option<&'a dyn ProgressCallback>,
+    cancellation: Option<&'a CancellationToken>,
+}
+
+impl<'a> ConfigObserver<'a> {
+    pub fn new(inner: &'a mut dyn BlockObserver, config: &'a PreflateConfig) -> Self {
+        ConfigObserver {
+            inner,
+            progress: config.progress.as_deref(),
+            cancellation: config.cancellation.as_ref(),
+        }
+    }
+
+    /// only cancels, for the passes that don't count towards the progress such as verifying
+    pub fn cancellation_only(inner: &'a mut dyn BlockObserver, config: &'a PreflateConfig) -> Self {
+        ConfigObserver {
+            inner,
+            progress: None,
+            cancellation: config.cancellation.as_ref(),
+        }
+    }
+}
+
+impl BlockObserver for ConfigObserver<'_> {
+    fn block_predicted(&mut self, summary: &BlockSummary) {
+        self.inner.block_predicted(summary);
+    }
+
+    fn block_recreated(&mut self, summary: &BlockSummary) {
+        self.inner.block_recreated(summary);
+    }
+
+    fn progress(&mut self, progress: &Progress) {
+        self.inner.progress(progress);
+        if let Some(callback) = self.progress {
+            callback.progress(*progress);
+        }
+    }
+
+    fn cancellation(&self) -> Option<&CancellationToken> {
+        self.cancellation.or_else(|| self.inner.cancellation())
+    }
+}
+
+#[test]
+fn cancellation_token_is_shared() {
+    let token = CancellationToken::new();
+    let observer_token = token.clone();
+    assert!(check_cancelled(&observer_token).is_ok());
+
+    token.cancel();
+    assert!(observer_token.is_cancelled());
+    assert!(matches!(
+        check_cancelled(&observer_token),
+        Err(PreflateError::Cancelled)
+    ));
+    assert!(check_cancelled(&()).is_ok());
+}
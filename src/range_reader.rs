@@ -0,0 +1,488 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Access to archives that are only read by ranges, for example objects in S3 or GCS. For a zip
+//! file only the end of central directory record, the central directory and the ranges of the
+//! entries that are processed are fetched, so a multi-gigabyte archive doesn't have to be
+//! downloaded to preflate it.
+//!
+//! The parsing of the zip structures is shared between the sync and async variants, which only
+//! differ in how the ranges are fetched. Zip64 archives aren't supported yet.
+
+use std::{future::Future, io::ErrorKind, pin::Pin, sync::Mutex};
+
+use crate::{
+    archive_summary::ArchiveSummary, decompress_deflate_streams, preflate_config::PreflateConfig,
+    preflate_error::PreflateError, DecompressResult,
+};
+
+/// reads ranges of bytes from an object of a known length
+pub trait RangeReader {
+    /// the total length of the object
+    fn object_len(&self) -> std::io::Result<u64>;
+
+    /// Reads exactly len bytes starting at offset. A range that goes past the end of the
+    /// object is an error.
+    fn read_range(&self, offset: u64, len: usize) -> std::io::Result<Vec<u8>>;
+}
+
+/// the future returned by the methods of AsyncRangeReader
+pub type RangeFuture<'a, T> = Pin<Box<dyn Future<Output = std::io::Result<T>> + Send + 'a>>;
+
+/// async variant of RangeReader, for clients that fetch the ranges over the network
+pub trait AsyncRangeReader: Sync {
+    /// the total length of the object
+    fn object_len(&self) -> RangeFuture<'_, u64>;
+
+    /// Reads exactly len bytes starting at offset. A range that goes past the end of the
+    /// object is an error.
+    fn read_range(&self, offset: u64, len: usize) -> RangeFuture<'_, Vec<u8>>;
+}
+
+fn slice_range(data: &[u8], offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+    usize::try_from(offset)
+        .ok()
+        .and_then(|start| data.get(start..start.checked_add(len)?))
+        .map(|range| range.to_vec())
+        .ok_or_else(|| ErrorKind::UnexpectedEof.into())
+}
+
+impl RangeReader for [u8] {
+    fn object_len(&self) -> std::io::Result<u64> {
+        Ok(self.len() as u64)
+    }
+
+    fn read_range(&self, offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        slice_range(self, offset, len)
+    }
+}
+
+impl RangeReader for Vec<u8> {
+    fn object_len(&self) -> std::io::Result<u64> {
+        Ok(self.len() as u64)
+    }
+
+    fn read_range(&self, offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        slice_range(self, offset, len)
+    }
+}
+
+/// a local file read by ranges, which is mostly useful to test code written for remote objects
+impl RangeReader for Mutex<std::fs::File> {
+    fn object_len(&self) -> std::io::Result<u64> {
+        Ok(self.lock().unwrap().metadata()?.len())
+    }
+
+    fn read_range(&self, offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        use std::io::{Read, Seek, SeekFrom};
+
+        let mut file = self.lock().unwrap();
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = vec![0; len];
+        file.read_exact(&mut data)?;
+        Ok(data)
+    }
+}
+
+impl AsyncRangeReader for Vec<u8> {
+    fn object_len(&self) -> RangeFuture<'_, u64> {
+        Box::pin(std::future::ready(RangeReader::object_len(self)))
+    }
+
+    fn read_range(&self, offset: u64, len: usize) -> RangeFuture<'_, Vec<u8>> {
+        Box::pin(std::future::ready(slice_range(self, offset, len)))
+    }
+}
+
+/// an entry of the central directory of a zip file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZipEntry {
+    /// the file name as it is stored, which is usually utf-8 or code page 437
+    pub name: Vec<u8>,
+    /// compression method, 8 for deflate and 9 for deflate64
+    pub method: u16,
+    /// crc32 of the uncompressed data
+    pub crc32: u32,
+    /// size of the compressed data
+    pub compressed_size: u64,
+    /// size of the uncompressed data
+    pub uncompressed_size: u64,
+    /// offset of the local file header of the entry
+    pub local_header_offset: u64,
+}
+
+impl ZipEntry {
+    pub const METHOD_DEFLATE: u16 = 8;
+    pub const METHOD_DEFLATE64: u16 = 9;
+
+    pub fn is_deflated(&self) -> bool {
+        self.method == Self::METHOD_DEFLATE
+    }
+
+    /// whether the entry is compressed with deflate64, which some zip tools use for large files
+    pub fn is_deflate64(&self) -> bool {
+        self.method == Self::METHOD_DEFLATE64
+    }
+}
+
+const EOCD_SIGNATURE: &[u8; 4] = b"PK\x05\x06";
+const CENTRAL_SIGNATURE: &[u8; 4] = b"PK\x01\x02";
+const LOCAL_SIGNATURE: &[u8; 4] = b"PK\x03\x04";
+
+const EOCD_SIZE: usize = 22;
+const CENTRAL_HEADER_SIZE: usize = 46;
+const LOCAL_HEADER_SIZE: usize = 30;
+
+/// the end of central directory record is followed by a comment of at most 64k
+const MAX_EOCD_SEARCH: u64 = EOCD_SIZE as u64 + 0xffff;
+
+fn invalid_zip(message: &str) -> PreflateError {
+    PreflateError::Io(std::io::Error::new(ErrorKind::InvalidData, message))
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// where the end of central directory record is searched for
+fn eocd_search_range(object_len: u64) -> (u64, usize) {
+    let start = object_len.saturating_sub(MAX_EOCD_SEARCH);
+    (start, (object_len - start) as usize)
+}
+
+/// Finds the end of central directory record at the end of the object and returns the offset
+/// and size of the central directory.
+fn parse_eocd(tail: &[u8]) -> Result<(u64, usize), PreflateError> {
+    let eocd = (0..tail.len().saturating_sub(EOCD_SIZE - 1))
+        .rev()
+        .find(|&i| &tail[i..i + 4] == EOCD_SIGNATURE)
+        .map(|i| &tail[i..])
+        .ok_or_else(|| invalid_zip("no end of central directory record"))?;
+
+    let size = u32_at(eocd, 12);
+    let offset = u32_at(eocd, 16);
+    if size == u32::MAX || offset == u32::MAX || u16_at(eocd, 10) == u16::MAX {
+        return Err(invalid_zip("zip64 archives are not supported"));
+    }
+
+    Ok((offset.into(), size as usize))
+}
+
+fn parse_central_directory(directory: &[u8]) -> Result<Vec<ZipEntry>, PreflateError> {
+    let mut entries = Vec::new();
+    let mut rest = directory;
+
+    while !rest.is_empty() {
+        if rest.len() < CENTRAL_HEADER_SIZE || &rest[0..4] != CENTRAL_SIGNATURE {
+            return Err(invalid_zip("damaged central directory"));
+        }
+
+        let name_len = usize::from(u16_at(rest, 28));
+        let extra_len = usize::from(u16_at(rest, 30));
+        let comment_len = usize::from(u16_at(rest, 32));
+        let header_len = CENTRAL_HEADER_SIZE + name_len + extra_len + comment_len;
+        if rest.len() < header_len {
+            return Err(invalid_zip("damaged central directory"));
+        }
+
+        entries.push(ZipEntry {
+            name: rest[CENTRAL_HEADER_SIZE..CENTRAL_HEADER_SIZE + name_len].to_vec(),
+            method: u16_at(rest, 10),
+            crc32: u32_at(rest, 16),
+            compressed_size: u32_at(rest, 20).into(),
+            uncompressed_size: u32_at(rest, 24).into(),
+            local_header_offset: u32_at(rest, 42).into(),
+        });
+
+        rest = &rest[header_len..];
+    }
+
+    Ok(entries)
+}
+
+/// returns the length of the local header (which can have a different extra field than the
+/// central directory), after which the data of the entry starts
+fn local_header_len(header: &[u8]) -> Result<u64, PreflateError> {
+    if header.len() < LOCAL_HEADER_SIZE || &header[0..4] != LOCAL_SIGNATURE {
+        return Err(invalid_zip("damaged local file header"));
+    }
+
+    Ok(
+        (LOCAL_HEADER_SIZE + usize::from(u16_at(header, 26)) + usize::from(u16_at(header, 28)))
+            as u64,
+    )
+}
+
+/// reads the central directory of a zip file
+pub fn zip_entries(reader: &(impl RangeReader + ?Sized)) -> Result<Vec<ZipEntry>, PreflateError> {
+    let (start, len) = eocd_search_range(reader.object_len()?);
+    let (offset, size) = parse_eocd(&reader.read_range(start, len)?)?;
+    parse_central_directory(&reader.read_range(offset, size)?)
+}
+
+/// the offset of the compressed data of an entry, which follows its local header
+pub(crate) fn zip_entry_data_offset(
+    reader: &(impl RangeReader + ?Sized),
+    entry: &ZipEntry,
+) -> Result<u64, PreflateError> {
+    let header = reader.read_range(entry.local_header_offset, LOCAL_HEADER_SIZE)?;
+    Ok(entry.local_header_offset + local_header_len(&header)?)
+}
+
+/// reads the compressed data of an entry
+pub fn read_zip_entry(
+    reader: &(impl RangeReader + ?Sized),
+    entry: &ZipEntry,
+) -> Result<Vec<u8>, PreflateError> {
+    let data_offset = zip_entry_data_offset(reader, entry)?;
+    Ok(reader.read_range(data_offset, entry.compressed_size as usize)?)
+}
+
+/// async variant of zip_entries
+pub async fn zip_entries_async(
+    reader: &(impl AsyncRangeReader + ?Sized),
+) -> Result<Vec<ZipEntry>, PreflateError> {
+    let (start, len) = eocd_search_range(reader.object_len().await?);
+    let (offset, size) = parse_eocd(&reader.read_range(start, len).await?)?;
+    parse_central_directory(&reader.read_range(offset, size).await?)
+}
+
+/// async variant of read_zip_entry
+pub async fn read_zip_entry_async(
+    reader: &(impl AsyncRangeReader + ?Sized),
+    entry: &ZipEntry,
+) -> Result<Vec<u8>, PreflateError> {
+    let header = reader
+        .read_range(entry.local_header_offset, LOCAL_HEADER_SIZE)
+        .await?;
+    let data_offset = entry.local_header_offset + local_header_len(&header)?;
+    Ok(reader
+        .read_range(data_offset, entry.compressed_size as usize)
+        .await?)
+}
+
+/// the result of each deflated or deflate64 entry of a zip file
+pub type ZipEntryResults = Vec<(ZipEntry, Result<DecompressResult, PreflateError>)>;
+
+/// Decompresses each deflated or deflate64 entry of the zip file, fetching one entry at a time,
+/// and returns the results along with a summary over all of them. Other entries are left out.
+pub fn decompress_zip_entries(
+    reader: &(impl RangeReader + ?Sized),
+    config: &PreflateConfig,
+) -> Result<(ZipEntryResults, ArchiveSummary), PreflateError> {
+    let mut summary = ArchiveSummary::default();
+    let mut results = Vec::new();
+
+    for entry in zip_entries(reader)? {
+        if !entry.is_deflated() && !entry.is_deflate64() {
+            continue;
+        }
+
+        // an entry whose stream doesn't end within its declared size is corrupt
+        let entry_config = PreflateConfig {
+            deflate64: config.deflate64 || entry.is_deflate64(),
+            compressed_size: Some(entry.compressed_size),
+            ..config.clone()
+        };
+
+        let compressed = read_zip_entry(reader, &entry)?;
+        let (mut result, entry_summary) =
+            decompress_deflate_streams(std::iter::once(&compressed[..]), &entry_config);
+        summary.merge(&entry_summary);
+        results.push((entry, result.remove(0)));
+    }
+
+    Ok((results, summary))
+}
+
+#[cfg(test)]
+pub(crate) fn build_zip(entries: &[(&str, &[u8])]) -> Vec<u8> {
+    use std::io::Write;
+
+    let entries: Vec<_> = entries
+        .iter()
+        .map(|&(name, plain_text)| {
+            let mut encoder =
+                flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::new(6));
+            encoder.write_all(plain_text).unwrap();
+            let compressed = encoder.finish().unwrap();
+            (name, ZipEntry::METHOD_DEFLATE, compressed, plain_text)
+        })
+        .collect();
+    build_zip_with_methods(&entries)
+}
+
+/// builds a zip file from entries that are already compressed with the given method
+#[cfg(test)]
+pub(crate) fn build_zip_with_methods(entries: &[(&str, u16, Vec<u8>, &[u8])]) -> Vec<u8> {
+    let mut zip = Vec::new();
+    let mut directory = Vec::new();
+    for (name, method, compressed, plain_text) in entries {
+        let crc = crc32fast::hash(plain_text);
+
+        let mut fields = Vec::new();
+        fields.extend_from_slice(&20u16.to_le_bytes()); // version needed
+        fields.extend_from_slice(&0u16.to_le_bytes()); // flags
+        fields.extend_from_slice(&method.to_le_bytes());
+        fields.extend_from_slice(&0u32.to_le_bytes()); // time and date
+        fields.extend_from_slice(&crc.to_le_bytes());
+        fields.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+        fields.extend_from_slice(&(plain_text.len() as u32).to_le_bytes());
+        fields.extend_from_slice(&(name.len() as u16).to_le_bytes());
+
+        directory.extend_from_slice(CENTRAL_SIGNATURE);
+        directory.extend_from_slice(&20u16.to_le_bytes()); // version made by
+        directory.extend_from_slice(&fields);
+        directory.extend_from_slice(&[0; 12]); // extra, comment, disk, attributes
+        directory.extend_from_slice(&(zip.len() as u32).to_le_bytes());
+        directory.extend_from_slice(name.as_bytes());
+
+        zip.extend_from_slice(LOCAL_SIGNATURE);
+        zip.extend_from_slice(&fields);
+        zip.extend_from_slice(&0u16.to_le_bytes()); // extra
+        zip.extend_from_slice(name.as_bytes());
+        zip.extend_from_slice(compressed);
+    }
+
+    let directory_offset = zip.len() as u32;
+    zip.extend_from_slice(&directory);
+    zip.extend_from_slice(EOCD_SIGNATURE);
+    zip.extend_from_slice(&[0; 4]); // disk numbers
+    zip.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+    zip.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+    zip.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+    zip.extend_from_slice(&directory_offset.to_le_bytes());
+    zip.extend_from_slice(&3u16.to_le_bytes());
+    zip.extend_from_slice(b"abc");
+    zip
+}
+
+/// changes the compressed size that the first entry declares in its local header and in the
+/// central directory, without changing its data
+#[cfg(test)]
+pub(crate) fn declare_first_compressed_size(zip: &mut [u8], compressed_size: u32) {
+    let (directory_offset, _) = eocd_search_range(zip.len() as u64);
+    let (directory_offset, _) = parse_eocd(&zip[directory_offset as usize..]).unwrap();
+    let central = directory_offset as usize;
+    let local = u32_at(&zip[central..], 42) as usize;
+
+    zip[central + 20..central + 24].copy_from_slice(&compressed_size.to_le_bytes());
+    zip[local + 18..local + 22].copy_from_slice(&compressed_size.to_le_bytes());
+}
+
+#[test]
+fn entry_overruns_declared_size() {
+    let first = crate::process::read_file("sample1.bin");
+    let mut zip = build_zip(&[
+        ("first.bin", &first[..]),
+        ("second.txt", b"hello hello hello"),
+    ]);
+    let entries = zip_entries(&zip).unwrap();
+    declare_first_compressed_size(&mut zip, entries[0].compressed_size as u32 - 10);
+
+    // the stream of the first entry continues past its declared size, which makes it corrupt
+    // instead of truncated, while the second entry is fine
+    let (results, summary) = decompress_zip_entries(&zip, &PreflateConfig::default()).unwrap();
+    assert_eq!(summary.entries_total(), 2);
+    assert!(matches!(
+        results[0].1,
+        Err(PreflateError::CorruptDeflate {
+            kind: crate::preflate_error::DeflateError::PastDeclaredSize,
+            ..
+        })
+    ));
+    assert_eq!(
+        results[1].1.as_ref().unwrap().plain_text,
+        b"hello hello hello"
+    );
+}
+
+#[test]
+fn reads_only_the_needed_ranges() {
+    use std::cell::RefCell;
+
+    /// remembers the ranges that were read
+    struct CountingReader {
+        data: Vec<u8>,
+        ranges: RefCell<Vec<(u64, usize)>>,
+    }
+
+    impl RangeReader for CountingReader {
+        fn object_len(&self) -> std::io::Result<u64> {
+            Ok(self.data.len() as u64)
+        }
+
+        fn read_range(&self, offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+            self.ranges.borrow_mut().push((offset, len));
+            slice_range(&self.data, offset, len)
+        }
+    }
+
+    let first = crate::process::read_file("sample1.bin");
+    let zip = build_zip(&[
+        ("first.bin", &first[..]),
+        ("second.txt", b"hello hello hello"),
+    ]);
+
+    let reader = CountingReader {
+        data: zip.clone(),
+        ranges: RefCell::new(Vec::new()),
+    };
+
+    let entries = zip_entries(&reader).unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[1].name, b"second.txt");
+    assert_eq!(reader.ranges.borrow().len(), 2);
+
+    // only the local header and the data of the entry are read
+    let data = read_zip_entry(&reader, &entries[1]).unwrap();
+    assert_eq!(data.len() as u64, entries[1].compressed_size);
+    assert_eq!(reader.ranges.borrow().len(), 4);
+
+    let (results, summary) = decompress_zip_entries(&reader, &PreflateConfig::default()).unwrap();
+    assert_eq!(summary.entries_total(), 2);
+    assert_eq!(results[0].1.as_ref().unwrap().plain_text, first);
+    assert_eq!(
+        results[1].1.as_ref().unwrap().plain_text,
+        b"hello hello hello"
+    );
+
+    // the async variant reads the same entries
+    let async_entries = poll_ready(zip_entries_async(&zip)).unwrap();
+    assert_eq!(async_entries, entries);
+    assert_eq!(
+        poll_ready(read_zip_entry_async(&zip, &entries[1])).unwrap(),
+        data
+    );
+
+    assert!(zip_entries(&zip[..zip.len() - 30]).is_err());
+}
+
+/// polls a future that doesn't wait for anything
+#[cfg(test)]
+fn poll_ready<F: Future>(future: F) -> F::Output {
+    use std::{
+        sync::Arc,
+        task::{Context, Poll, Wake, Waker},
+    };
+
+    struct NoopWaker;
+
+    impl Wake for NoopWaker {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    let waker = Waker::from(Arc::new(NoopWaker));
+    let mut future = Box::pin(future);
+    match future.as_mut().poll(&mut Context::from_waker(&waker)) {
+        Poll::Ready(output) => output,
+        Poll::Pending => panic!("the future wasn't ready"),
+    }
+}
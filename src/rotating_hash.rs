@@ -0,0 +1,371 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Hash functions used to build the hash chains. In order for the hop count predictions
+//! to line up, we need to use exactly the same hash function as the compressor that produced
+//! the stream, since the chain order depends on which positions collide.
+
+pub const HASH_ALGORITHM_ZLIB: u16 = 0;
+pub const HASH_ALGORITHM_MINIZ_FAST: u16 = 1;
+pub const HASH_ALGORITHM_LIBDEFLATE4: u16 = 2;
+pub const HASH_ALGORITHM_CRC32: u16 = 3;
+pub const HASH_ALGORITHM_7ZIP: u16 = 4;
+pub const HASH_ALGORITHM_LIBDEFLATE3: u16 = 5;
+pub const HASH_ALGORITHM_ISAL: u16 = 6;
+
+/// A hash that is updated one byte at a time as we move through the input.
+pub trait RotatingHashTrait: Default + Copy + Clone {
+    /// number of bytes at each position that are covered by the hash
+    const NUM_HASH_BYTES: u32 = 3;
+
+    fn hash(&self, mask: u16) -> u16;
+    fn append(&self, c: u8, hash_shift: u32) -> Self;
+
+    /// the value that is stored in PreflateParameters to select this hash
+    fn hash_algorithm() -> u16;
+
+    /// the internal state of the hash, so that it can be stored in a predictor snapshot
+    fn state(&self) -> u32;
+
+    /// recreates the hash from the value that was returned by state
+    fn from_state(state: u32) -> Self;
+}
+
+/// the rolling hash used by zlib, where the hash_shift is chosen so that
+/// the bytes older than 3 positions are shifted out of the mask
+#[derive(Default, Debug, Copy, Clone)]
+pub struct ZlibRotatingHash {
+    hash: u16,
+}
+
+impl RotatingHashTrait for ZlibRotatingHash {
+    fn hash(&self, mask: u16) -> u16 {
+        self.hash & mask
+    }
+
+    fn append(&self, c: u8, hash_shift: u32) -> ZlibRotatingHash {
+        ZlibRotatingHash {
+            hash: (self.hash << hash_shift) ^ u16::from(c),
+        }
+    }
+
+    fn hash_algorithm() -> u16 {
+        HASH_ALGORITHM_ZLIB
+    }
+
+    fn state(&self) -> u32 {
+        self.hash.into()
+    }
+
+    fn from_state(state: u32) -> Self {
+        Self { hash: state as u16 }
+    }
+}
+
+/// the 3 byte hash used by the fast level of miniz, which looks up a table of 4096 entries
+/// regardless of the mask
+#[derive(Default, Debug, Copy, Clone)]
+pub struct MiniZHash {
+    hash: u32,
+}
+
+impl RotatingHashTrait for MiniZHash {
+    fn hash(&self, _mask: u16) -> u16 {
+        ((self.hash ^ (self.hash >> 17)) & 4095) as u16
+    }
+
+    fn append(&self, c: u8, _hash_shift: u32) -> Self {
+        MiniZHash {
+            hash: (c as u32) << 16 | (self.hash >> 8),
+        }
+    }
+
+    fn hash_algorithm() -> u16 {
+        HASH_ALGORITHM_MINIZ_FAST
+    }
+
+    fn state(&self) -> u32 {
+        self.hash
+    }
+
+    fn from_state(state: u32) -> Self {
+        Self { hash: state }
+    }
+}
+
+/// the multiplicative 4 byte hash used by the libdeflate matchfinders
+#[derive(Default, Debug, Copy, Clone)]
+pub struct LibdeflateHash4 {
+    /// last 4 bytes, loaded little endian like libdeflate does
+    window: u32,
+}
+
+impl RotatingHashTrait for LibdeflateHash4 {
+    const NUM_HASH_BYTES: u32 = 4;
+
+    fn hash(&self, mask: u16) -> u16 {
+        let hash_bits = mask.count_ones();
+        (self.window.wrapping_mul(0x1E35A7BD) >> (32 - hash_bits)) as u16 & mask
+    }
+
+    fn append(&self, c: u8, _hash_shift: u32) -> Self {
+        LibdeflateHash4 {
+            window: (self.window >> 8) | (u32::from(c) << 24),
+        }
+    }
+
+    fn hash_algorithm() -> u16 {
+        HASH_ALGORITHM_LIBDEFLATE4
+    }
+
+    fn state(&self) -> u32 {
+        self.window
+    }
+
+    fn from_state(state: u32) -> Self {
+        Self { window: state }
+    }
+}
+
+/// the multiplicative 3 byte hash of the table that libdeflate finds its matches of length 3 in
+#[derive(Default, Debug, Copy, Clone)]
+pub struct LibdeflateHash3 {
+    /// last 3 bytes, loaded little endian like libdeflate does
+    window: u32,
+}
+
+impl RotatingHashTrait for LibdeflateHash3 {
+    fn hash(&self, mask: u16) -> u16 {
+        let hash_bits = mask.count_ones();
+        (self.window.wrapping_mul(0x1E35A7BD) >> (32 - hash_bits)) as u16 & mask
+    }
+
+    fn append(&self, c: u8, _hash_shift: u32) -> Self {
+        LibdeflateHash3 {
+            window: (self.window >> 8) | (u32::from(c) << 16),
+        }
+    }
+
+    fn hash_algorithm() -> u16 {
+        HASH_ALGORITHM_LIBDEFLATE3
+    }
+
+    fn state(&self) -> u32 {
+        self.window
+    }
+
+    fn from_state(state: u32) -> Self {
+        Self { window: state }
+    }
+}
+
+/// hash of the next 4 bytes that folds them with CRC32, as used by
+/// the compressors that have a hardware CRC instruction available
+#[derive(Default, Debug, Copy, Clone)]
+pub struct Crc32Hash {
+    window: u32,
+}
+
+impl RotatingHashTrait for Crc32Hash {
+    const NUM_HASH_BYTES: u32 = 4;
+
+    fn hash(&self, mask: u16) -> u16 {
+        crc32fast::hash(&self.window.to_le_bytes()) as u16 & mask
+    }
+
+    fn append(&self, c: u8, _hash_shift: u32) -> Self {
+        Crc32Hash {
+            window: (self.window >> 8) | (u32::from(c) << 24),
+        }
+    }
+
+    fn hash_algorithm() -> u16 {
+        HASH_ALGORITHM_CRC32
+    }
+
+    fn state(&self) -> u32 {
+        self.window
+    }
+
+    fn from_state(state: u32) -> Self {
+        Self { window: state }
+    }
+}
+
+/// the table of a reflected crc with the given polynomial, one entry per byte
+const fn crc_table(poly: u32) -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut r = i as u32;
+        let mut j = 0;
+        while j < 8 {
+            r = (r >> 1) ^ (poly & 0u32.wrapping_sub(r & 1));
+            j += 1;
+        }
+        table[i] = r;
+        i += 1;
+    }
+    table
+}
+
+/// the crc32 table that 7-Zip mixes the middle byte of its hash with
+const CRC_TABLE: [u32; 256] = crc_table(0xEDB88320);
+
+/// the table of CRC32C (Castagnoli), which is what the crc32 instruction of SSE4.2 calculates
+const CRC32C_TABLE: [u32; 256] = crc_table(0x82F63B78);
+
+/// the 3 byte hash of the binary tree matchfinder that the 7-Zip deflate encoder uses
+#[derive(Default, Debug, Copy, Clone)]
+pub struct SevenZipHash {
+    /// last 3 bytes, the oldest one in the highest byte
+    window: u32,
+}
+
+impl RotatingHashTrait for SevenZipHash {
+    fn hash(&self, mask: u16) -> u16 {
+        let b0 = self.window >> 16;
+        let b1 = (self.window >> 8) & 0xff;
+        let b2 = self.window & 0xff;
+        ((b2 | (b0 << 8)) ^ CRC_TABLE[b1 as usize]) as u16 & mask
+    }
+
+    fn append(&self, c: u8, _hash_shift: u32) -> Self {
+        SevenZipHash {
+            window: ((self.window << 8) | u32::from(c)) & 0xffffff,
+        }
+    }
+
+    fn hash_algorithm() -> u16 {
+        HASH_ALGORITHM_7ZIP
+    }
+
+    fn state(&self) -> u32 {
+        self.window
+    }
+
+    fn from_state(state: u32) -> Self {
+        Self { window: state }
+    }
+}
+
+/// the 4 byte hash of Intel ISA-L, which is the CRC32C of the next 4 bytes loaded little
+/// endian with the crc32 instruction (without the inversions of the standard CRC32C)
+#[derive(Default, Debug, Copy, Clone)]
+pub struct IsalHash {
+    window: u32,
+}
+
+impl IsalHash {
+    fn crc32c_byte(crc: u32, b: u8) -> u32 {
+        CRC32C_TABLE[((crc ^ u32::from(b)) & 0xff) as usize] ^ (crc >> 8)
+    }
+}
+
+impl RotatingHashTrait for IsalHash {
+    const NUM_HASH_BYTES: u32 = 4;
+
+    fn hash(&self, mask: u16) -> u16 {
+        self.window
+            .to_le_bytes()
+            .into_iter()
+            .fold(0, Self::crc32c_byte) as u16
+            & mask
+    }
+
+    fn append(&self, c: u8, _hash_shift: u32) -> Self {
+        IsalHash {
+            window: (self.window >> 8) | (u32::from(c) << 24),
+        }
+    }
+
+    fn hash_algorithm() -> u16 {
+        HASH_ALGORITHM_ISAL
+    }
+
+    fn state(&self) -> u32 {
+        self.window
+    }
+
+    fn from_state(state: u32) -> Self {
+        Self { window: state }
+    }
+}
+
+#[test]
+fn miniz_hash_is_level1_hash() {
+    // the first trigram is loaded little endian, and folded into the 12 bits of the table
+    let h = b"xabc"
+        .iter()
+        .fold(MiniZHash::default(), |h, &c| h.append(c, 5));
+    let t = u32::from_le_bytes([b'a', b'b', b'c', 0]);
+    assert_eq!(h.hash(0x7fff), ((t ^ (t >> 17)) & 4095) as u16);
+    assert_eq!(h.hash(0x7fff), 0x250);
+}
+
+#[test]
+fn four_byte_hashes_only_depend_on_last_bytes() {
+    fn check<H: RotatingHashTrait>() {
+        let a = b"xyzabcd".iter().fold(H::default(), |h, &c| h.append(c, 5));
+        let b = b"qabcd".iter().fold(H::default(), |h, &c| h.append(c, 5));
+        assert_eq!(a.hash(0x7fff), b.hash(0x7fff));
+
+        let c = b"qabce".iter().fold(H::default(), |h, &c| h.append(c, 5));
+        assert_ne!(a.hash(0x7fff), c.hash(0x7fff));
+    }
+
+    check::<LibdeflateHash4>();
+    check::<Crc32Hash>();
+    check::<IsalHash>();
+}
+
+#[test]
+fn isal_hash_is_crc32c() {
+    // the check value of CRC32C, with the inversions that the hash leaves out
+    let crc = !b"123456789"
+        .iter()
+        .fold(!0, |crc, &b| IsalHash::crc32c_byte(crc, b));
+    assert_eq!(crc, 0xE3069283);
+
+    // _mm_crc32_u32(0, 0x64636261) is 0xdaaf41f6
+    let h = b"abcd"
+        .iter()
+        .fold(IsalHash::default(), |h, &c| h.append(c, 5));
+    assert_eq!(h.hash(0xffff), 0x41f6);
+    assert_eq!(h.hash(0x1fff), 0x01f6);
+}
+
+#[test]
+fn libdeflate_hash3_only_depends_on_last_bytes() {
+    let a = b"xyzabc"
+        .iter()
+        .fold(LibdeflateHash3::default(), |h, &c| h.append(c, 5));
+    let b = b"qabc"
+        .iter()
+        .fold(LibdeflateHash3::default(), |h, &c| h.append(c, 5));
+    assert_eq!(a.hash(0x7fff), b.hash(0x7fff));
+
+    // same as lz_hash of libdeflate on the 3 bytes loaded little endian
+    let v = u32::from_le_bytes([b'a', b'b', b'c', 0]);
+    assert_eq!(a.hash(0x7fff), (v.wrapping_mul(0x1E35A7BD) >> 17) as u16);
+    assert_eq!(a.hash(0xffff), (v.wrapping_mul(0x1E35A7BD) >> 16) as u16);
+}
+
+#[test]
+fn seven_zip_hash_only_depends_on_last_bytes() {
+    let a = b"xyzabc"
+        .iter()
+        .fold(SevenZipHash::default(), |h, &c| h.append(c, 5));
+    let b = b"qabc"
+        .iter()
+        .fold(SevenZipHash::default(), |h, &c| h.append(c, 5));
+    assert_eq!(a.hash(0xffff), b.hash(0xffff));
+
+    // same as the hash of 7-Zip computed on the bytes directly
+    let expected = (u32::from(b'c') | (u32::from(b'a') << 8)) ^ CRC_TABLE[usize::from(b'b')];
+    assert_eq!(a.hash(0xffff), expected as u16);
+    assert_eq!(CRC_TABLE[1], 0x77073096);
+}
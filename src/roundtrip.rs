@@ -0,0 +1,96 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Checks that a deflate stream survives a decompression and recompression, for fuzzing the
+//! library with arbitrary input (see the targets in the fuzz directory) or running it over a
+//! corpus of files. Most arbitrary input is rejected when it is decompressed, which is fine.
+//! What must never happen is that a stream is accepted and then can't be recreated exactly.
+
+use crate::{
+    decompress_deflate_stream_verified, preflate_config::PreflateConfig,
+    preflate_error::PreflateError,
+};
+
+/// what happened to a stream in roundtrip_check
+#[derive(Debug)]
+pub enum RoundtripOutcome {
+    /// the stream was decompressed and recompressed to the same bytes
+    Identical,
+    /// The stream was decompressed, but recreating it from the plain text and the corrections
+    /// failed or produced different bytes. This is a bug in the library.
+    Mismatch(PreflateError),
+    /// The input was rejected when it was decompressed, for example since it isn't a valid
+    /// deflate stream, the predictor can't handle it or it needs more than the limits of the
+    /// config allow.
+    Error(PreflateError),
+}
+
+impl RoundtripOutcome {
+    /// whether the outcome points to a bug in the library, which a fuzzer should report
+    pub fn is_bug(&self) -> bool {
+        matches!(self, RoundtripOutcome::Mismatch(_))
+    }
+}
+
+/// Decompresses the data as a deflate stream and recompresses it from the plain text and the
+/// corrections, with the default config.
+pub fn roundtrip_check(compressed_data: &[u8]) -> RoundtripOutcome {
+    roundtrip_check_with_config(compressed_data, &PreflateConfig::default())
+}
+
+/// Same as roundtrip_check, with the config used for both directions. The verify mode is
+/// ignored, since the stream is always recompressed and compared with the original (up to
+/// where the final block ends).
+pub fn roundtrip_check_with_config(
+    compressed_data: &[u8],
+    config: &PreflateConfig,
+) -> RoundtripOutcome {
+    let verified = match decompress_deflate_stream_verified(compressed_data, config) {
+        Ok(verified) => verified,
+        // This includes streams that are valid but that the predictor can't handle, which is a
+        // limitation rather than a bug. Only a stream that was accepted and then recreated
+        // differently is a mismatch.
+        Err(e) => return RoundtripOutcome::Error(e),
+    };
+
+    if verified.verified {
+        return RoundtripOutcome::Identical;
+    }
+
+    RoundtripOutcome::Mismatch(
+        match (verified.recompress_error, verified.mismatch_offset) {
+            (Some(e), _) => e,
+            (None, offset) => PreflateError::Mismatch(anyhow::anyhow!(
+                "recompressed stream differs from the original at offset {}",
+                offset.unwrap_or_default()
+            )),
+        },
+    )
+}
+
+#[test]
+fn roundtrip_check_outcomes() {
+    let compressed_data = crate::process::read_file("compressed_zlib_level6.deflate");
+    assert!(matches!(
+        roundtrip_check(&compressed_data),
+        RoundtripOutcome::Identical
+    ));
+
+    // trailing data after the final block isn't part of the stream
+    let mut trailing = compressed_data.clone();
+    trailing.extend_from_slice(b"trailing");
+    assert!(matches!(
+        roundtrip_check(&trailing),
+        RoundtripOutcome::Identical
+    ));
+
+    // a block type of 3 is invalid
+    let outcome = roundtrip_check(&[0x07, 0x00]);
+    assert!(matches!(outcome, RoundtripOutcome::Error(_)));
+    assert!(!outcome.is_bug());
+
+    assert!(matches!(roundtrip_check(&[]), RoundtripOutcome::Error(_)));
+}
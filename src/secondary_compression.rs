@@ -0,0 +1,240 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Compression of the finished corrections with a general purpose compressor. The cabac coded
+//! corrections still contain the stream parameters, the trees and the framing, which compress
+//! further. The crate doesn't depend on any of the compressors, the application registers the
+//! implementation of the ones it wants to use in the config.
+//!
+//! Compressed corrections start with `PFZ`, the SecondaryCompression as u8 and the length of
+//! the corrections as u64 (little endian), followed by the compressed corrections. Framed
+//! corrections start with the length of their first segment, which is never this large, so
+//! corrections that weren't compressed are passed through as they are.
+
+use std::{borrow::Cow, fmt::Debug, sync::Arc};
+
+use crate::{preflate_config::PreflateConfig, preflate_error::PreflateError};
+
+const MAGIC: &[u8; 3] = b"PFZ";
+const HEADER_SIZE: usize = 12;
+
+/// the general purpose compressor that the corrections are compressed with
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SecondaryCompression {
+    /// the corrections are returned as they are
+    None,
+    Zstd,
+    Lzma,
+}
+
+impl SecondaryCompression {
+    fn to_byte(self) -> u8 {
+        match self {
+            SecondaryCompression::None => 0,
+            SecondaryCompression::Zstd => 1,
+            SecondaryCompression::Lzma => 2,
+        }
+    }
+
+    fn from_byte(b: u8) -> Option<Self> {
+        match b {
+            1 => Some(SecondaryCompression::Zstd),
+            2 => Some(SecondaryCompression::Lzma),
+            _ => None,
+        }
+    }
+}
+
+/// an implementation of one of the SecondaryCompression backends
+pub trait SecondaryCompressor: Debug + Send + Sync {
+    fn compress(&self, data: &[u8]) -> std::io::Result<Vec<u8>>;
+
+    fn decompress(&self, data: &[u8]) -> std::io::Result<Vec<u8>>;
+}
+
+/// The implementations of the backends that can be selected in the PreflateConfig. The backend
+/// that compressed the corrections has to be registered for recompressing as well.
+#[derive(Debug, Clone, Default)]
+pub struct SecondaryCompressorRegistry {
+    compressors: Vec<(SecondaryCompression, Arc<dyn SecondaryCompressor>)>,
+}
+
+impl SecondaryCompressorRegistry {
+    /// registers the implementation of a backend, replacing a previously registered one
+    pub fn register(
+        &mut self,
+        kind: SecondaryCompression,
+        compressor: Arc<dyn SecondaryCompressor>,
+    ) {
+        self.compressors.retain(|(k, _)| *k != kind);
+        self.compressors.push((kind, compressor));
+    }
+
+    pub fn find(&self, kind: SecondaryCompression) -> Option<&Arc<dyn SecondaryCompressor>> {
+        self.compressors
+            .iter()
+            .find(|(k, _)| *k == kind)
+            .map(|(_, c)| c)
+    }
+
+    fn get(
+        &self,
+        kind: SecondaryCompression,
+    ) -> Result<&Arc<dyn SecondaryCompressor>, PreflateError> {
+        self.find(kind).ok_or_else(|| {
+            PreflateError::RecompressFailed(anyhow::anyhow!(
+                "no compressor is registered for {:?}",
+                kind
+            ))
+        })
+    }
+}
+
+/// Compresses the corrections with the backend selected in the config. They are kept as they
+/// are if they don't get smaller.
+pub(crate) fn compress_corrections(
+    corrections: Vec<u8>,
+    config: &PreflateConfig,
+) -> Result<Vec<u8>, PreflateError> {
+    let kind = config.secondary_compression;
+    if kind == SecondaryCompression::None || corrections.is_empty() {
+        return Ok(corrections);
+    }
+
+    let compressed = config
+        .secondary_compressors
+        .get(kind)?
+        .compress(&corrections)?;
+    if compressed.len() + HEADER_SIZE >= corrections.len() {
+        return Ok(corrections);
+    }
+
+    let mut output = Vec::with_capacity(HEADER_SIZE + compressed.len());
+    output.extend_from_slice(MAGIC);
+    output.push(kind.to_byte());
+    output.extend_from_slice(&(corrections.len() as u64).to_le_bytes());
+    output.extend_from_slice(&compressed);
+    Ok(output)
+}
+
+/// the backend that the corrections were compressed with, None if they weren't compressed
+pub fn secondary_compression(corrections: &[u8]) -> SecondaryCompression {
+    match corrections {
+        [b'P', b'F', b'Z', kind, ..] if corrections.len() >= HEADER_SIZE => {
+            SecondaryCompression::from_byte(*kind).unwrap_or(SecondaryCompression::None)
+        }
+        _ => SecondaryCompression::None,
+    }
+}
+
+/// undoes compress_corrections with the backend registered in the config, corrections that
+/// weren't compressed are returned as they are
+pub(crate) fn decompress_corrections<'a>(
+    corrections: &'a [u8],
+    config: &PreflateConfig,
+) -> Result<Cow<'a, [u8]>, PreflateError> {
+    let kind = secondary_compression(corrections);
+    if kind == SecondaryCompression::None {
+        return Ok(Cow::Borrowed(corrections));
+    }
+
+    let len = u64::from_le_bytes(corrections[4..HEADER_SIZE].try_into().unwrap());
+    PreflateError::check_limit(
+        crate::preflate_error::ResourceLimit::CorrectionSize,
+        len,
+        config.max_correction_size as u64,
+    )?;
+
+    let decompressed = config
+        .secondary_compressors
+        .get(kind)?
+        .decompress(&corrections[HEADER_SIZE..])
+        .map_err(|e| PreflateError::CorruptCorrections(HEADER_SIZE, e.into()))?;
+    if decompressed.len() as u64 != len {
+        return Err(PreflateError::CorruptCorrections(
+            4,
+            anyhow::anyhow!(
+                "corrections decompressed to {} bytes, expected {}",
+                decompressed.len(),
+                len
+            ),
+        ));
+    }
+    Ok(Cow::Owned(decompressed))
+}
+
+/// a backend for the tests that run-length encodes the bytes
+#[cfg(test)]
+#[derive(Debug)]
+pub(crate) struct RunLengthCompressor;
+
+#[cfg(test)]
+impl SecondaryCompressor for RunLengthCompressor {
+    fn compress(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut output: Vec<u8> = Vec::new();
+        for &b in data {
+            match output.len() {
+                n if n >= 2 && output[n - 1] == b && output[n - 2] < 255 => output[n - 2] += 1,
+                _ => output.extend_from_slice(&[1, b]),
+            }
+        }
+        Ok(output)
+    }
+
+    fn decompress(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        if data.len() % 2 != 0 {
+            return Err(std::io::ErrorKind::InvalidData.into());
+        }
+        Ok(data
+            .chunks(2)
+            .flat_map(|p| std::iter::repeat(p[1]).take(usize::from(p[0])))
+            .collect())
+    }
+}
+
+#[test]
+fn secondary_compression_roundtrip() {
+    let mut config = PreflateConfig {
+        secondary_compression: SecondaryCompression::Zstd,
+        ..PreflateConfig::default()
+    };
+
+    // the backend has to be registered
+    assert!(compress_corrections(vec![0; 100], &config).is_err());
+
+    config
+        .secondary_compressors
+        .register(SecondaryCompression::Zstd, Arc::new(RunLengthCompressor));
+
+    let corrections = [vec![0; 1000], vec![1, 2, 3], vec![7; 300]].concat();
+    let compressed = compress_corrections(corrections.clone(), &config).unwrap();
+    assert!(compressed.len() < 50);
+    assert_eq!(
+        secondary_compression(&compressed),
+        SecondaryCompression::Zstd
+    );
+    assert_eq!(
+        decompress_corrections(&compressed, &config).unwrap(),
+        &corrections[..]
+    );
+
+    // corrections that don't get smaller are kept as they are
+    let incompressible: Vec<u8> = (0..=255).collect();
+    let kept = compress_corrections(incompressible.clone(), &config).unwrap();
+    assert_eq!(kept, incompressible);
+    assert!(matches!(
+        decompress_corrections(&kept, &config).unwrap(),
+        Cow::Borrowed(_)
+    ));
+
+    // a damaged length is caught
+    let mut damaged = compressed.clone();
+    damaged[4] ^= 1;
+    assert!(matches!(
+        decompress_corrections(&damaged, &config),
+        Err(PreflateError::CorruptCorrections(..))
+    ));
+}
@@ -0,0 +1,271 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Boolean arithmetic coder with fixed probabilities. This uses the same bit layout as the VP8 coder,
+//! but the contexts never adapt, so there is no per symbol model update and the probability
+//! of each symbol doesn't depend on anything that was coded before it.
+
+use std::io::{Read, Result, Write};
+
+use cabac::traits::{CabacReader, CabacWriter};
+
+/// context that always returns the same probability of the next bit being zero (out of 256)
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct StaticContext(u8);
+
+impl StaticContext {
+    pub fn probability(&self) -> u8 {
+        self.0
+    }
+
+    pub const fn new(probability: u8) -> Self {
+        StaticContext(probability)
+    }
+}
+
+impl Default for StaticContext {
+    fn default() -> Self {
+        StaticContext(128)
+    }
+}
+
+/// context that only counts the bits that were written with it, used to
+/// measure the probabilities for the static contexts
+#[derive(Debug, Default, Copy, Clone)]
+pub struct CountingContext {
+    zeros: u32,
+    ones: u32,
+}
+
+impl CountingContext {
+    pub fn add(&mut self, other: &CountingContext) {
+        self.zeros += other.zeros;
+        self.ones += other.ones;
+    }
+
+    /// static context with the probability that best fits the counted bits
+    pub fn to_static(self) -> StaticContext {
+        let total = u64::from(self.zeros) + u64::from(self.ones);
+        if total == 0 {
+            return StaticContext::default();
+        }
+
+        let p = (u64::from(self.zeros) * 256 + total / 2) / total;
+        StaticContext::new(p.clamp(1, 255) as u8)
+    }
+}
+
+/// writer that doesn't write anything, but records the bits in the contexts
+pub struct CountingWriter;
+
+impl CabacWriter<CountingContext> for CountingWriter {
+    fn put_bypass(&mut self, _bin_value: bool) -> Result<()> {
+        Ok(())
+    }
+
+    fn put(&mut self, value: bool, cur_ctx: &mut CountingContext) -> Result<()> {
+        if value {
+            cur_ctx.ones += 1;
+        } else {
+            cur_ctx.zeros += 1;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub struct StaticWriter<W> {
+    low_value: u32,
+    range: u32,
+    bits_left: i32,
+    buffer: Vec<u8>,
+    writer: W,
+}
+
+impl<W: Write> StaticWriter<W> {
+    pub fn new(writer: W) -> Self {
+        let mut retval = StaticWriter {
+            low_value: 0,
+            range: 255,
+            bits_left: -24,
+            buffer: Vec::new(),
+            writer,
+        };
+
+        // marker bit so that a carry never has to propagate past the first byte
+        retval.put_with_probability(false, 128);
+
+        retval
+    }
+
+    fn put_with_probability(&mut self, value: bool, probability: u8) {
+        let split = 1 + (((self.range - 1) * u32::from(probability)) >> 8);
+
+        if value {
+            self.low_value += split;
+            self.range -= split;
+        } else {
+            self.range = split;
+        }
+
+        let mut shift = self.range.leading_zeros() as i32 - 24;
+        self.range <<= shift;
+        self.bits_left += shift;
+
+        if self.bits_left >= 0 {
+            let offset = shift - self.bits_left;
+
+            if ((self.low_value << (offset - 1)) & 0x80000000) != 0 {
+                let mut x = self.buffer.len() - 1;
+                while self.buffer[x] == 0xff {
+                    self.buffer[x] = 0;
+                    x -= 1;
+                }
+                self.buffer[x] += 1;
+            }
+
+            self.buffer.push((self.low_value >> (24 - offset)) as u8);
+
+            self.low_value <<= offset;
+            shift = self.bits_left;
+            self.low_value &= 0xffffff;
+            self.bits_left -= 8;
+        }
+
+        self.low_value <<= shift;
+    }
+}
+
+impl<W: Write> CabacWriter<StaticContext> for StaticWriter<W> {
+    fn put_bypass(&mut self, bin_value: bool) -> Result<()> {
+        self.put_with_probability(bin_value, 128);
+        Ok(())
+    }
+
+    fn put(&mut self, value: bool, cur_ctx: &mut StaticContext) -> Result<()> {
+        self.put_with_probability(value, cur_ctx.0);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        for _ in 0..32 {
+            self.put_with_probability(false, 128);
+        }
+
+        self.writer.write_all(&self.buffer)?;
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+pub struct StaticReader<R> {
+    value: u64,
+    count: i32,
+    range: u32,
+    reader: R,
+}
+
+impl<R: Read> StaticReader<R> {
+    pub fn new(reader: R) -> Result<Self> {
+        let mut r = StaticReader {
+            value: 0,
+            count: -8,
+            range: 255,
+            reader,
+        };
+
+        r.fill()?;
+        r.get_with_probability(128)?; // marker bit
+
+        Ok(r)
+    }
+
+    fn fill(&mut self) -> Result<()> {
+        let mut shift = 56 - (self.count + 8);
+
+        while shift >= 0 {
+            let mut v = [0u8; 1];
+            if self.reader.read(&mut v)? == 0 {
+                break;
+            }
+
+            self.value |= u64::from(v[0]) << shift;
+            shift -= 8;
+            self.count += 8;
+        }
+
+        Ok(())
+    }
+
+    fn get_with_probability(&mut self, probability: u8) -> Result<bool> {
+        if self.count < 0 {
+            self.fill()?;
+        }
+
+        let split = 1 + (((self.range - 1) * u32::from(probability)) >> 8);
+        let big_split = u64::from(split) << 56;
+
+        let bit = self.value >= big_split;
+        if bit {
+            self.range -= split;
+            self.value -= big_split;
+        } else {
+            self.range = split;
+        }
+
+        let shift = self.range.leading_zeros() as i32 - 24;
+        self.value <<= shift;
+        self.range <<= shift;
+        self.count -= shift;
+
+        Ok(bit)
+    }
+}
+
+impl<R: Read> CabacReader<StaticContext> for StaticReader<R> {
+    fn get_bypass(&mut self) -> Result<bool> {
+        self.get_with_probability(128)
+    }
+
+    fn get(&mut self, cur_ctx: &mut StaticContext) -> Result<bool> {
+        self.get_with_probability(cur_ctx.0)
+    }
+}
+
+#[test]
+fn roundtrip_static_coder() {
+    use std::io::Cursor;
+
+    // pseudo random bits with a skewed distribution to exercise the carry propagation
+    let mut seed = 0x12345678u32;
+    let bits: Vec<(bool, u8)> = (0..100000)
+        .map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            let probability = ((seed >> 8) & 0xff).max(1) as u8;
+            (((seed >> 16) & 0xff) as u8 >= probability, probability)
+        })
+        .collect();
+
+    let mut buffer = Vec::new();
+    let mut writer = StaticWriter::new(&mut buffer);
+    for &(bit, probability) in bits.iter() {
+        writer
+            .put(bit, &mut StaticContext::new(probability))
+            .unwrap();
+    }
+    writer.finish().unwrap();
+
+    let mut reader = StaticReader::new(Cursor::new(&buffer)).unwrap();
+    for &(bit, probability) in bits.iter() {
+        assert_eq!(
+            bit,
+            reader.get(&mut StaticContext::new(probability)).unwrap()
+        );
+    }
+}
@@ -4,22 +4,52 @@
  *  This software incorporates material from third parties. See NOTICE.txt for details.
  *--------------------------------------------------------------------------------------------*/
 
+//! The transport layer between the predictor and the encoding of the corrections.
+//!
+//! The predictor describes everything that differs from what it predicted as a sequence
+//! of actions (mispredictions, corrections and raw values) that it hands to a [`PredictionEncoder`].
+//! When recompressing, it asks a [`PredictionDecoder`] for the same actions in the same order.
+//! The encoder is free to store the actions in any way, as long as the decoder returns exactly
+//! the values that were encoded. The built in cabac and JSON codecs are implementations of these
+//! traits, and other formats can provide their own implementation to share one storage format
+//! for the corrections of several recompressors.
+//!
+//! The traits and the action enums are part of the stable API and follow semver. New kinds
+//! of mispredictions and corrections may be added in minor versions (which is why the enums are
+//! non exhaustive), but the meaning of the existing ones won't change. Which actions the
+//! predictor emits for a given stream is versioned separately with [`CONTEXT_SCHEME_VERSION`].
+
+use crate::{deflate_parser::DeflateBlockType, preflate_error::PreflateError};
+
 /// boolean misprediction indictions
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
 pub enum CodecMisprediction {
     EOFMisprediction,
     LiteralPredictionWrong,
     ReferencePredictionWrong,
-    IrregularLen258,
 
     TreeCodeCountMisprediction,
     LiteralCountMisprediction,
     DistanceCountMisprediction,
+
+    /// the parameters were estimated again before this block, and the new ones follow
+    ParametersChanged,
+
+    /// the stored block isn't an empty flush marker, and its length follows
+    StoredBlockNotEmpty,
+    /// the empty stored block was written by a full flush, which cleared the hash chain
+    FullFlush,
+
+    /// number of kinds of mispredictions, not an actual misprediction
     MAX,
 }
 
 /// correction indictions, which are followed by a 16 bit value
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
 pub enum CodecCorrection {
     TokenCount,
     NonZeroPadding,
@@ -31,23 +61,99 @@ pub enum CodecCorrection {
     LDTypeCorrection,
     RepeatCountCorrection,
     LDBitLengthCorrection,
+    /// the bits in which the NLEN of a stored block differs from the complement of LEN
+    StoredNLenCorrection,
+    /// the distance of a match that lies deeper on the hash chain than is walked
+    DeepMatchDistance,
+    /// how a match that deflate allows to be encoded in more than one way was encoded,
+    /// 0 for the canonical encoding
+    IrregularEncoding,
+
+    /// number of kinds of corrections, not an actual correction
     MAX,
 }
 
+/// every kind of misprediction, in the order of their values
+pub(crate) const ALL_MISPREDICTIONS: [CodecMisprediction; CodecMisprediction::MAX as usize] = [
+    CodecMisprediction::EOFMisprediction,
+    CodecMisprediction::LiteralPredictionWrong,
+    CodecMisprediction::ReferencePredictionWrong,
+    CodecMisprediction::TreeCodeCountMisprediction,
+    CodecMisprediction::LiteralCountMisprediction,
+    CodecMisprediction::DistanceCountMisprediction,
+    CodecMisprediction::ParametersChanged,
+    CodecMisprediction::StoredBlockNotEmpty,
+    CodecMisprediction::FullFlush,
+];
+
+/// every kind of correction, in the order of their values
+pub(crate) const ALL_CORRECTIONS: [CodecCorrection; CodecCorrection::MAX as usize] = [
+    CodecCorrection::TokenCount,
+    CodecCorrection::NonZeroPadding,
+    CodecCorrection::BlockTypeCorrection,
+    CodecCorrection::LenCorrection,
+    CodecCorrection::DistOnlyCorrection,
+    CodecCorrection::DistAfterLenCorrection,
+    CodecCorrection::TreeCodeBitLengthCorrection,
+    CodecCorrection::LDTypeCorrection,
+    CodecCorrection::RepeatCountCorrection,
+    CodecCorrection::LDBitLengthCorrection,
+    CodecCorrection::StoredNLenCorrection,
+    CodecCorrection::DeepMatchDistance,
+    CodecCorrection::IrregularEncoding,
+];
+
+/// number of buckets a correction can be split into by encode_bucket_correction
+pub const MAX_CORRECTION_BUCKETS: usize = 32;
+
+/// version of the way the corrections are split into contexts. This is written in the version
+/// info of the framed corrections, outside of the coded corrections, since corrections
+/// written with a different scheme cannot be decoded.
+pub const CONTEXT_SCHEME_VERSION: u16 = 14;
+
+/// Receives the actions of the predictor while a stream is decompressed. Most of the values
+/// are zero or false when the prediction was right, so an encoder should make these cheap.
 pub trait PredictionEncoder {
+    /// encodes a correction, where 0 means that the prediction was right
     fn encode_correction(&mut self, action: CodecCorrection, value: u32);
+
+    /// encodes a correction that is modeled separately for each bucket. The bucket has to be
+    /// derived from information that the decoder also has (and be less than MAX_CORRECTION_BUCKETS).
+    fn encode_bucket_correction(&mut self, action: CodecCorrection, bucket: u8, value: u32);
+
+    /// encodes whether the prediction was wrong
     fn encode_misprediction(&mut self, action: CodecMisprediction, value: bool);
+
+    /// encodes a value that isn't predicted, which fits into max_bits bits
     fn encode_value(&mut self, value: u16, max_bits: u8);
 
+    /// Checksum of the predictor state for debugging. Encoders may drop this, but if they store it,
+    /// the decoder can detect where the recompression started to differ from the original.
     fn encode_verify_state(&mut self, message: &'static str, checksum: u64);
 
+    /// called after the last action, so that any buffered data can be written out
     fn finish(&mut self);
+
+    /// statistics about what has been encoded so far
+    fn statistics(&self) -> CountNonDefaultActions;
 }
 
+/// Returns the actions that were given to the matching PredictionEncoder, in the same order
+/// and with the same arguments, while a stream is recompressed.
 pub trait PredictionDecoder {
+    /// decodes a value that was written with encode_value
     fn decode_value(&mut self, max_bits_orig: u8) -> u16;
+
+    /// decodes a correction that was written with encode_correction
     fn decode_correction(&mut self, correction: CodecCorrection) -> u32;
+
+    /// decodes a correction that was written with encode_bucket_correction
+    fn decode_bucket_correction(&mut self, correction: CodecCorrection, bucket: u8) -> u32;
+
+    /// decodes a misprediction that was written with encode_misprediction
     fn decode_misprediction(&mut self, misprediction: CodecMisprediction) -> bool;
+
+    /// checks the state of the predictor against the one that was encoded, if it was stored
     fn decode_verify_state(&mut self, message: &'static str, checksum: u64);
 }
 
@@ -55,18 +161,135 @@ pub trait PredictionDecoder {
 pub enum CodecAction {
     Misprediction(CodecMisprediction, bool),
     Correction(CodecCorrection, u32),
+    BucketCorrection(CodecCorrection, u8, u32),
     Value(u16, u8),
     VerifyState(&'static str, u64),
 }
 
-#[derive(Default)]
+/// the amount of information that went into one codec context
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct ContextCost {
+    /// number of times something was encoded with this context
+    pub symbols: u64,
+    /// the number of bits spent on these symbols, according to the probabilities of the
+    /// contexts. The arithmetic coder adds a bit of overhead on top of this for very skewed
+    /// probabilities, so the output is somewhat larger than the sum over all the contexts.
+    pub bits: f64,
+}
+
+impl ContextCost {
+    fn record(&mut self, bits: f64) {
+        self.symbols += 1;
+        self.bits += bits;
+    }
+
+    fn add(&mut self, other: &ContextCost) {
+        self.symbols += other.symbols;
+        self.bits += other.bits;
+    }
+}
+
+/// how many bits one deflate block took up in the compressed stream and in the corrections
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct BlockCost {
+    /// the BFINAL and BTYPE bits, and for a stored block also the padding, LEN and NLEN
+    pub header_bits: u64,
+    /// the description of the huffman codes of a dynamic block
+    pub tree_bits: u64,
+    /// the tokens including the end of block code, or the bytes of a stored block
+    pub token_bits: u64,
+    /// The bits of the corrections spent on this block, only filled in by encoders that
+    /// produce actual bits. The bits of a run of correct predictions are counted with the
+    /// symbol that ends the run, which can be in the next block.
+    pub correction_bits: f64,
+    /// the kind of the block, None if it wasn't recorded
+    pub block_type: Option<DeflateBlockType>,
+    /// number of literals and references in the block
+    pub token_count: u32,
+}
+
+impl BlockCost {
+    /// number of bits the block took up in the compressed stream
+    pub fn deflate_bits(&self) -> u64 {
+        self.header_bits + self.tree_bits + self.token_bits
+    }
+}
+
+#[derive(Debug, Default, Clone)]
 pub struct CountNonDefaultActions {
     pub total_non_default: u32,
     pub mispredictions_count: [u32; CodecMisprediction::MAX as usize],
     pub corrections_count: [u32; CodecCorrection::MAX as usize],
+
+    /// entropy spent per context, only filled in by encoders that produce actual bits. The run of
+    /// correct predictions before a symbol is written together with it, so those bits are
+    /// attributed to the context of the symbol that ended the run.
+    pub mispredictions_cost: [ContextCost; CodecMisprediction::MAX as usize],
+    pub corrections_cost: [ContextCost; CodecCorrection::MAX as usize],
+    pub values_cost: ContextCost,
+
+    /// the cost of each block in the order of the stream. This is filled in for the whole stream
+    /// after it has been predicted, so it isn't combined by add.
+    pub blocks: Vec<BlockCost>,
 }
 
 impl CountNonDefaultActions {
+    pub fn record_correction_cost(&mut self, correction: CodecCorrection, bits: f64) {
+        self.corrections_cost[correction as usize].record(bits);
+    }
+
+    pub fn record_misprediction_cost(&mut self, misprediction: CodecMisprediction, bits: f64) {
+        self.mispredictions_cost[misprediction as usize].record(bits);
+    }
+
+    pub fn record_value_cost(&mut self, bits: f64) {
+        self.values_cost.record(bits);
+    }
+
+    /// total number of bits spent over all the contexts
+    pub fn total_bits(&self) -> f64 {
+        self.mispredictions_cost
+            .iter()
+            .chain(self.corrections_cost.iter())
+            .map(|c| c.bits)
+            .sum::<f64>()
+            + self.values_cost.bits
+    }
+
+    /// adds the counts and costs of other, used when the actions were split over several encoders
+    pub fn add(&mut self, other: &CountNonDefaultActions) {
+        self.total_non_default += other.total_non_default;
+        for (a, b) in self
+            .mispredictions_count
+            .iter_mut()
+            .zip(&other.mispredictions_count)
+        {
+            *a += b;
+        }
+        for (a, b) in self
+            .corrections_count
+            .iter_mut()
+            .zip(&other.corrections_count)
+        {
+            *a += b;
+        }
+        for (a, b) in self
+            .mispredictions_cost
+            .iter_mut()
+            .zip(&other.mispredictions_cost)
+        {
+            a.add(b);
+        }
+        for (a, b) in self
+            .corrections_cost
+            .iter_mut()
+            .zip(&other.corrections_cost)
+        {
+            a.add(b);
+        }
+        self.values_cost.add(&other.values_cost);
+    }
+
     pub fn record_correction(&mut self, correction: CodecCorrection, value: u32) {
         if value != 0 {
             self.corrections_count[correction as usize] += 1;
@@ -95,29 +318,49 @@ impl CountNonDefaultActions {
             RepeatCountCorrection,
             LDBitLengthCorrection,
             NonZeroPadding,
+            StoredNLenCorrection,
+            DeepMatchDistance,
+            IrregularEncoding,
         ];
 
         let mispred = [
             EOFMisprediction,
             LiteralPredictionWrong,
             ReferencePredictionWrong,
-            IrregularLen258,
             TreeCodeCountMisprediction,
             LiteralCountMisprediction,
             DistanceCountMisprediction,
+            ParametersChanged,
+            StoredBlockNotEmpty,
+            FullFlush,
         ];
 
         for i in corr {
-            if self.corrections_count[i as usize] != 0 {
-                println!("{:?}: {}", i, self.corrections_count[i as usize]);
+            let cost = &self.corrections_cost[i as usize];
+            if self.corrections_count[i as usize] != 0 || cost.bits > 0.0 {
+                println!(
+                    "{:?}: {} ({} symbols, {:.0} bits)",
+                    i, self.corrections_count[i as usize], cost.symbols, cost.bits
+                );
             }
         }
 
         for i in mispred {
-            if self.mispredictions_count[i as usize] != 0 {
-                println!("{:?}: {}", i, self.mispredictions_count[i as usize]);
+            let cost = &self.mispredictions_cost[i as usize];
+            if self.mispredictions_count[i as usize] != 0 || cost.bits > 0.0 {
+                println!(
+                    "{:?}: {} ({} symbols, {:.0} bits)",
+                    i, self.mispredictions_count[i as usize], cost.symbols, cost.bits
+                );
             }
         }
+
+        if self.values_cost.symbols != 0 {
+            println!(
+                "values: {} symbols, {:.0} bits",
+                self.values_cost.symbols, self.values_cost.bits
+            );
+        }
     }
 }
 
@@ -146,6 +389,10 @@ impl VerifyPredictionEncoder {
         self.actions.clone()
     }
 
+    pub fn into_actions(self) -> Vec<CodecAction> {
+        self.actions
+    }
+
     pub fn print(&self) {
         self.count.print();
     }
@@ -170,11 +417,21 @@ impl PredictionEncoder for VerifyPredictionEncoder {
         self.count.record_correction(action, value);
     }
 
+    fn encode_bucket_correction(&mut self, action: CodecCorrection, bucket: u8, value: u32) {
+        self.actions
+            .push(CodecAction::BucketCorrection(action, bucket, value));
+        self.count.record_correction(action, value);
+    }
+
     fn encode_misprediction(&mut self, action: CodecMisprediction, value: bool) {
         self.actions.push(CodecAction::Misprediction(action, value));
         self.count.record_misprediction(action, value);
     }
 
+    fn statistics(&self) -> CountNonDefaultActions {
+        self.count.clone()
+    }
+
     fn finish(&mut self) {}
 }
 
@@ -225,6 +482,16 @@ impl PredictionDecoder for VerifyPredictionDecoder {
         unreachable!("{:?}", x);
     }
 
+    fn decode_bucket_correction(&mut self, correction: CodecCorrection, bucket: u8) -> u32 {
+        let x = self.pop().unwrap();
+        if let CodecAction::BucketCorrection(c, b, value) = x {
+            assert_eq!(correction, c);
+            assert_eq!(bucket, b);
+            return value;
+        }
+        unreachable!("{:?}", x);
+    }
+
     fn decode_misprediction(&mut self, misprediction: CodecMisprediction) -> bool {
         let x = self.pop().unwrap();
         if let CodecAction::Misprediction(m, value) = x {
@@ -235,47 +502,73 @@ impl PredictionDecoder for VerifyPredictionDecoder {
     }
 }
 
-#[cfg(test)]
+/// replays the recorded actions into the encoder
 pub fn drive_encoder<T: PredictionEncoder>(encoder: &mut T, actions: &[CodecAction]) {
     for action in actions {
-        match action {
-            &CodecAction::Value(value, max_bits) => {
+        match *action {
+            CodecAction::Value(value, max_bits) => {
                 encoder.encode_value(value, max_bits);
             }
-            &CodecAction::Correction(correction, value) => {
+            CodecAction::Correction(correction, value) => {
                 encoder.encode_correction(correction, value);
             }
-            &CodecAction::Misprediction(misprediction, value) => {
+            CodecAction::BucketCorrection(correction, bucket, value) => {
+                encoder.encode_bucket_correction(correction, bucket, value);
+            }
+            CodecAction::Misprediction(misprediction, value) => {
                 encoder.encode_misprediction(misprediction, value);
             }
-            &CodecAction::VerifyState(message, checksum) => {
+            CodecAction::VerifyState(message, checksum) => {
                 encoder.encode_verify_state(message, checksum);
             }
         }
     }
 }
 
-#[cfg(test)]
-pub fn verify_decoder<T: PredictionDecoder>(decoder: &mut T, actions: &[CodecAction]) {
-    for action in actions {
-        match action {
-            &CodecAction::Value(value, max_bits) => {
-                let x = decoder.decode_value(max_bits);
-                assert_eq!(x, value);
-            }
-            &CodecAction::Correction(correction, value) => {
-                let x = decoder.decode_correction(correction);
-                assert_eq!(x, value);
+/// Decodes the recorded actions with the decoder and fails at the first one that decodes to a
+/// different value, which checks the coded corrections without running the predictor.
+pub fn check_decoder<T: PredictionDecoder>(
+    decoder: &mut T,
+    actions: &[CodecAction],
+) -> Result<(), PreflateError> {
+    for (i, &action) in actions.iter().enumerate() {
+        let decoded = match action {
+            CodecAction::Value(_, max_bits) => {
+                CodecAction::Value(decoder.decode_value(max_bits), max_bits)
             }
-            &CodecAction::Misprediction(misprediction, value) => {
-                let x = decoder.decode_misprediction(misprediction);
-                assert_eq!(x, value);
+            CodecAction::Correction(correction, _) => {
+                CodecAction::Correction(correction, decoder.decode_correction(correction))
             }
-            &CodecAction::VerifyState(message, checksum) => {
+            CodecAction::BucketCorrection(correction, bucket, _) => CodecAction::BucketCorrection(
+                correction,
+                bucket,
+                decoder.decode_bucket_correction(correction, bucket),
+            ),
+            CodecAction::Misprediction(misprediction, _) => CodecAction::Misprediction(
+                misprediction,
+                decoder.decode_misprediction(misprediction),
+            ),
+            CodecAction::VerifyState(message, checksum) => {
                 decoder.decode_verify_state(message, checksum);
+                action
             }
+        };
+
+        if decoded != action {
+            return Err(PreflateError::Mismatch(anyhow::anyhow!(
+                "action {} decodes to {:?} instead of {:?}",
+                i,
+                decoded,
+                action
+            )));
         }
     }
+    Ok(())
+}
+
+#[cfg(test)]
+pub fn verify_decoder<T: PredictionDecoder>(decoder: &mut T, actions: &[CodecAction]) {
+    check_decoder(decoder, actions).unwrap();
 }
 
 #[cfg(test)]
@@ -291,6 +584,10 @@ impl PredictionDecoder for DefaultOnlyDecoder {
         0
     }
 
+    fn decode_bucket_correction(&mut self, _correction: CodecCorrection, _bucket: u8) -> u32 {
+        0
+    }
+
     fn decode_misprediction(&mut self, _misprediction: CodecMisprediction) -> bool {
         false
     }
@@ -298,6 +595,62 @@ impl PredictionDecoder for DefaultOnlyDecoder {
     fn decode_verify_state(&mut self, _message: &'static str, _checksum: u64) {}
 }
 
+/// Forwards to the referenced encoder, which allows passing trait objects
+/// where a generic encoder is expected
+impl<T: PredictionEncoder + ?Sized> PredictionEncoder for &mut T {
+    fn encode_correction(&mut self, action: CodecCorrection, value: u32) {
+        (**self).encode_correction(action, value);
+    }
+
+    fn encode_bucket_correction(&mut self, action: CodecCorrection, bucket: u8, value: u32) {
+        (**self).encode_bucket_correction(action, bucket, value);
+    }
+
+    fn encode_misprediction(&mut self, action: CodecMisprediction, value: bool) {
+        (**self).encode_misprediction(action, value);
+    }
+
+    fn encode_value(&mut self, value: u16, max_bits: u8) {
+        (**self).encode_value(value, max_bits);
+    }
+
+    fn encode_verify_state(&mut self, message: &'static str, checksum: u64) {
+        (**self).encode_verify_state(message, checksum);
+    }
+
+    fn finish(&mut self) {
+        (**self).finish();
+    }
+
+    fn statistics(&self) -> CountNonDefaultActions {
+        (**self).statistics()
+    }
+}
+
+/// Forwards to the referenced decoder, which allows passing trait objects
+/// where a generic decoder is expected
+impl<T: PredictionDecoder + ?Sized> PredictionDecoder for &mut T {
+    fn decode_value(&mut self, max_bits_orig: u8) -> u16 {
+        (**self).decode_value(max_bits_orig)
+    }
+
+    fn decode_correction(&mut self, correction: CodecCorrection) -> u32 {
+        (**self).decode_correction(correction)
+    }
+
+    fn decode_bucket_correction(&mut self, correction: CodecCorrection, bucket: u8) -> u32 {
+        (**self).decode_bucket_correction(correction, bucket)
+    }
+
+    fn decode_misprediction(&mut self, misprediction: CodecMisprediction) -> bool {
+        (**self).decode_misprediction(misprediction)
+    }
+
+    fn decode_verify_state(&mut self, message: &'static str, checksum: u64) {
+        (**self).decode_verify_state(message, checksum);
+    }
+}
+
 /// This implements a prediction encoder that tees the input to two different
 /// encoders. This allows us to verify that the behavior of two encoders is the same
 impl<A, B> PredictionEncoder for (A, B)
@@ -320,6 +673,11 @@ where
         self.1.encode_correction(action, value);
     }
 
+    fn encode_bucket_correction(&mut self, action: CodecCorrection, bucket: u8, value: u32) {
+        self.0.encode_bucket_correction(action, bucket, value);
+        self.1.encode_bucket_correction(action, bucket, value);
+    }
+
     fn encode_misprediction(&mut self, action: CodecMisprediction, value: bool) {
         self.0.encode_misprediction(action, value);
         self.1.encode_misprediction(action, value);
@@ -329,6 +687,12 @@ where
         self.0.finish();
         self.1.finish();
     }
+
+    /// the second encoder is the one that produces the output (the first one is usually
+    /// just recording), so its statistics are returned
+    fn statistics(&self) -> CountNonDefaultActions {
+        self.1.statistics()
+    }
 }
 
 /// Implement the same for decoders, where we verify that the output
@@ -352,6 +716,13 @@ where
         a
     }
 
+    fn decode_bucket_correction(&mut self, correction: CodecCorrection, bucket: u8) -> u32 {
+        let a = self.0.decode_bucket_correction(correction, bucket);
+        let b = self.1.decode_bucket_correction(correction, bucket);
+        assert_eq!(a, b);
+        a
+    }
+
     fn decode_misprediction(&mut self, misprediction: CodecMisprediction) -> bool {
         let a = self.0.decode_misprediction(misprediction);
         let b = self.1.decode_misprediction(misprediction);
@@ -0,0 +1,216 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Caching of the corrections of streams that have already been processed. The same library
+//! or resource file is often found in many archives, and with a cache its stream only has to
+//! be predicted the first time it is seen. Later copies are only inflated to get the plain text.
+
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    fmt::Debug,
+    hash::Hasher,
+    io::Cursor,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    compressor_profile::CompressorProfile, deflate_reader::DeflateReader,
+    preflate_config::PreflateConfig, preflate_parameter_estimator::PreflateParameters,
+    CountNonDefaultActions,
+};
+
+/// identifies the compressed data of a stream by its length and two independent hashes
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct StreamKey {
+    pub length: u64,
+    pub crc32: u32,
+    pub hash: u64,
+}
+
+impl StreamKey {
+    pub fn of(compressed_data: &[u8]) -> Self {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(compressed_data);
+
+        StreamKey {
+            length: compressed_data.len() as u64,
+            crc32: crc32fast::hash(compressed_data),
+            hash: hasher.finish(),
+        }
+    }
+}
+
+/// what is kept of a stream that was decompressed, everything except the plain text
+#[derive(Debug, Clone)]
+pub struct CachedStream {
+    /// the corrections that were returned for the stream
+    pub corrections: Vec<u8>,
+    /// number of bytes of the compressed data that the corrections recreate
+    pub compressed_processed: usize,
+    pub statistics: CountNonDefaultActions,
+    pub profile: CompressorProfile,
+    pub(crate) params: PreflateParameters,
+}
+
+impl CachedStream {
+    /// Inflates the compressed data that this entry was created from to get its plain text
+    /// back. Returns None if the data doesn't decode to exactly the length that was cached.
+    pub(crate) fn plain_text(
+        &self,
+        compressed_data: &[u8],
+        config: &PreflateConfig,
+    ) -> Option<Vec<u8>> {
+        let mut reader = DeflateReader::with_compressed_size(
+            Cursor::new(compressed_data),
+            config.compressed_size.unwrap_or(u64::MAX),
+        );
+        reader.set_lenient_stored_len(config.lenient_stored_len);
+        reader.set_deflate64(config.deflate64);
+
+        let mut last = false;
+        while !last {
+            reader.read_block(&mut last).ok()?;
+        }
+
+        let processed = ((reader.bit_position() + 7) / 8) as usize;
+        (processed == self.compressed_processed).then(|| reader.move_plain_text())
+    }
+}
+
+/// Storage for the corrections of the streams that have been decompressed, set in
+/// PreflateConfig::stream_cache. The corrections depend on the config they were created
+/// with, so a cache should only be shared between runs that use the same config.
+pub trait StreamCache: Send + Sync + Debug {
+    fn get(&self, key: &StreamKey) -> Option<Arc<CachedStream>>;
+
+    fn insert(&self, key: StreamKey, stream: Arc<CachedStream>);
+}
+
+/// keeps the streams that were used most recently in memory
+#[derive(Debug)]
+pub struct LruStreamCache {
+    capacity: usize,
+    state: Mutex<LruState>,
+}
+
+#[derive(Debug, Default)]
+struct LruState {
+    /// the cached streams and when they were last used
+    entries: HashMap<StreamKey, (Arc<CachedStream>, u64)>,
+    /// the keys of the entries ordered by when they were last used
+    by_use: BTreeMap<u64, StreamKey>,
+    tick: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl LruStreamCache {
+    /// creates a cache that holds at most `capacity` streams
+    pub fn new(capacity: usize) -> Self {
+        LruStreamCache {
+            capacity,
+            state: Mutex::new(LruState::default()),
+        }
+    }
+
+    /// number of streams in the cache
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// number of lookups that found a stream and number of lookups that didn't
+    pub fn hits_and_misses(&self) -> (u64, u64) {
+        let state = self.state.lock().unwrap();
+        (state.hits, state.misses)
+    }
+}
+
+impl LruState {
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+impl StreamCache for LruStreamCache {
+    fn get(&self, key: &StreamKey) -> Option<Arc<CachedStream>> {
+        let mut state = self.state.lock().unwrap();
+        let tick = state.next_tick();
+
+        let LruState {
+            entries, by_use, ..
+        } = &mut *state;
+
+        let Some((stream, last_used)) = entries.get_mut(key) else {
+            state.misses += 1;
+            return None;
+        };
+
+        by_use.remove(last_used);
+        by_use.insert(tick, *key);
+        *last_used = tick;
+        let stream = stream.clone();
+
+        state.hits += 1;
+        Some(stream)
+    }
+
+    fn insert(&self, key: StreamKey, stream: Arc<CachedStream>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let tick = state.next_tick();
+
+        if let Some((_, last_used)) = state.entries.insert(key, (stream, tick)) {
+            state.by_use.remove(&last_used);
+        }
+        state.by_use.insert(tick, key);
+
+        while state.entries.len() > self.capacity {
+            let (_, oldest) = state.by_use.pop_first().unwrap();
+            state.entries.remove(&oldest);
+        }
+    }
+}
+
+#[test]
+fn lru_evicts_least_recently_used() {
+    // an empty final block with fixed huffman codes
+    let (_, _, params) = crate::process::parse_deflate(&[0x03, 0x00]).unwrap();
+
+    let cache = LruStreamCache::new(2);
+    let stream = Arc::new(CachedStream {
+        corrections: vec![1, 2, 3],
+        compressed_processed: 2,
+        statistics: CountNonDefaultActions::default(),
+        profile: CompressorProfile::from_parameters(&params),
+        params,
+    });
+
+    let keys: Vec<StreamKey> = [&b"a"[..], b"b", b"c"]
+        .iter()
+        .map(|d| StreamKey::of(d))
+        .collect();
+
+    cache.insert(keys[0], stream.clone());
+    cache.insert(keys[1], stream.clone());
+
+    // using the first one makes the second one the least recently used
+    assert!(cache.get(&keys[0]).is_some());
+    cache.insert(keys[2], stream);
+
+    assert_eq!(cache.len(), 2);
+    assert!(cache.get(&keys[1]).is_none());
+    assert!(cache.get(&keys[0]).is_some());
+    assert!(cache.get(&keys[2]).is_some());
+    assert_eq!(cache.hits_and_misses(), (3, 1));
+}
@@ -0,0 +1,160 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Counters and histograms of the processed streams, reported through the `metrics` facade when
+//! the `metrics` feature is enabled, so that a service can pick them up with whatever recorder
+//! it installs. Without the feature all of these are empty and compile away.
+//!
+//! The reported metrics are:
+//! - `preflate_streams_total{operation}`: streams that were decompressed or recompressed
+//! - `preflate_failures_total{operation, kind}`: failed streams by the kind of the error
+//! - `preflate_compressed_bytes_total{operation}`: deflate bytes read or written
+//! - `preflate_plain_text_bytes_total{operation}`: plain text bytes written or read
+//! - `preflate_correction_bytes_total{operation}`: bytes of the corrections written or read
+//! - `preflate_correction_ratio`: size of the corrections relative to the deflate stream
+//! - `preflate_phase_seconds{phase}`: time spent in predict, verify and recompress
+//! - `preflate_stream_cache_hits_total`: streams that were served from the stream cache
+
+use std::time::Duration;
+
+use crate::preflate_error::PreflateError;
+
+pub(crate) const DECOMPRESS: &str = "decompress";
+pub(crate) const RECOMPRESS: &str = "recompress";
+
+/// records a stream that was processed successfully
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn record_stream(
+    operation: &'static str,
+    compressed_len: usize,
+    plain_text_len: usize,
+    corrections_len: usize,
+) {
+    #[cfg(feature = "metrics")]
+    {
+        metrics::counter!("preflate_streams_total", "operation" => operation).increment(1);
+        metrics::counter!("preflate_compressed_bytes_total", "operation" => operation)
+            .increment(compressed_len as u64);
+        metrics::counter!("preflate_plain_text_bytes_total", "operation" => operation)
+            .increment(plain_text_len as u64);
+        metrics::counter!("preflate_correction_bytes_total", "operation" => operation)
+            .increment(corrections_len as u64);
+
+        if operation == DECOMPRESS && compressed_len > 0 {
+            metrics::histogram!("preflate_correction_ratio")
+                .record(corrections_len as f64 / compressed_len as f64);
+        }
+    }
+}
+
+/// records a stream that failed, labelled with the kind of the error
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn record_failure(operation: &'static str, error: &PreflateError) {
+    #[cfg(feature = "metrics")]
+    {
+        metrics::counter!(
+            "preflate_failures_total",
+            "operation" => operation,
+            "kind" => format!("{:?}", error.error_code())
+        )
+        .increment(1);
+    }
+}
+
+/// records the time that was spent in one phase of processing a stream
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn record_phase(phase: &'static str, duration: Duration) {
+    #[cfg(feature = "metrics")]
+    {
+        metrics::histogram!("preflate_phase_seconds", "phase" => phase).record(duration);
+    }
+}
+
+/// records a stream that was served from the stream cache instead of being predicted again
+pub(crate) fn record_cache_hit() {
+    #[cfg(feature = "metrics")]
+    {
+        metrics::counter!("preflate_stream_cache_hits_total").increment(1);
+    }
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use metrics::{
+        Counter, Gauge, Histogram, Key, KeyName, Metadata, Recorder, SharedString, Unit,
+    };
+
+    /// remembers the keys of the metrics that were registered
+    #[derive(Default)]
+    struct KeyRecorder {
+        keys: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl KeyRecorder {
+        fn remember(&self, key: &Key) {
+            let labels: Vec<String> = key
+                .labels()
+                .map(|l| format!("{}={}", l.key(), l.value()))
+                .collect();
+            self.keys
+                .lock()
+                .unwrap()
+                .push(format!("{}{{{}}}", key.name(), labels.join(",")));
+        }
+    }
+
+    impl Recorder for KeyRecorder {
+        fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+            self.remember(key);
+            Counter::noop()
+        }
+
+        fn register_gauge(&self, key: &Key, _: &Metadata<'_>) -> Gauge {
+            self.remember(key);
+            Gauge::noop()
+        }
+
+        fn register_histogram(&self, key: &Key, _: &Metadata<'_>) -> Histogram {
+            self.remember(key);
+            Histogram::noop()
+        }
+    }
+
+    #[test]
+    fn reports_streams_and_failures() {
+        let recorder = KeyRecorder::default();
+        let keys = recorder.keys.clone();
+
+        metrics::with_local_recorder(&recorder, || {
+            let compressed = crate::process::read_file("compressed_zlib_level1.deflate");
+            let config = crate::preflate_config::PreflateConfig::default();
+            let r = crate::decompress_deflate_stream_with_config(&compressed, &config).unwrap();
+            crate::recompress_deflate_stream_with_config(&r.plain_text, &r.cabac_encoded, &config)
+                .unwrap();
+
+            assert!(crate::decompress_deflate_stream_with_config(&[0xff; 16], &config).is_err());
+        });
+
+        let keys = keys.lock().unwrap();
+        for expected in [
+            "preflate_streams_total{operation=decompress}",
+            "preflate_streams_total{operation=recompress}",
+            "preflate_correction_ratio{}",
+            "preflate_phase_seconds{phase=predict}",
+            "preflate_phase_seconds{phase=verify}",
+            "preflate_phase_seconds{phase=recompress}",
+            "preflate_failures_total{operation=decompress,kind=CorruptDeflate}",
+        ] {
+            assert!(keys.iter().any(|k| k == expected), "missing {}", expected);
+        }
+    }
+}
@@ -0,0 +1,1081 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Decompression of deflate streams that arrive in chunks, for example from a network socket,
+//! so that the caller doesn't have to collect the whole stream first, and the counterpart that
+//! writes the recompressed stream out in chunks.
+//!
+//! The plain text of each block is handed out as soon as the block is complete. A block is
+//! predicted once enough of the plain text after it has arrived that the predictor sees the same
+//! as it would with the whole stream, after which only the window before the next block is kept,
+//! so the memory that is needed doesn't grow with the stream. The parameters of the prediction
+//! are estimated from the start of the stream for this (unless it is shorter than that), so the
+//! corrections may be somewhat larger than for the whole stream at once. They are recompressed
+//! the same way though. The recompressor takes the corrections up front and recreates each
+//! block the same way, once enough of the plain text after it has arrived, and writes it out
+//! right away.
+//!
+//! With the `async` feature, PreflateDecoder and PreflateEncoder wrap these as tokio AsyncRead
+//! and AsyncWrite adapters, so that a proxy can transform deflate content as it passes through.
+//! Each poll only predicts or recreates the blocks that the chunk it reads or writes completes,
+//! which keeps the time that it takes bounded, and PreflateEncoder takes no more plain text
+//! until the blocks before have been written to its inner writer. For io::Read and io::Write,
+//! see PreflateReader and PreflateWriter in pack, which pack a whole file.
+
+use std::io::{Cursor, ErrorKind, Write};
+#[cfg(feature = "async")]
+use std::{
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+#[cfg(feature = "async")]
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{
+    cabac_codec::{
+        encode_static_corrections, encode_static_corrections_split, frame_corrections,
+        is_unframed_corrections, owned_cabac_decoder, CorrectionsHeader, OriginalStream,
+        OwnedAdaptiveEncoder, OwnedPredictionDecoder,
+    },
+    deflate_reader::{DeflateReader, PartialBlock},
+    hash_chain::HashChainSnapshot,
+    match_predictor::ZlibMatchPredictor,
+    plane_codec::PlanePredictionEncoder,
+    preflate_config::{CorrectionCodec, PreflateConfig, ProbabilityModel, VerifyMode},
+    preflate_error::{is_end_of_data, PreflateError, ResourceLimit},
+    preflate_parameter_estimator::PreflateParameters,
+    preflate_token::{mark_full_flushes, FlushMarker, PreflateTokenBlock},
+    process::{
+        initial_snapshot, parameters_for, predict_next_blocks, recreate_next_blocks,
+        ChunkedRecreation,
+    },
+    profile_of,
+    progress::ConfigObserver,
+    recompress_deflate_stream_with_config,
+    statistical_codec::{
+        BlockCost, CodecCorrection, CodecMisprediction, CountNonDefaultActions, PredictionEncoder,
+        VerifyPredictionEncoder,
+    },
+    DecompressResult,
+};
+
+/// How much plain text has to follow a block before it is predicted. The matches of the window
+/// after a sync flush decide whether it counts as a full flush (see mark_full_flushes), and the
+/// predictor looks up to a few hundred bytes past the position that it predicts.
+const PREDICTION_LOOKAHEAD: usize = (1 << 16) + 4096;
+
+/// how much of the plain text the parameters are estimated from, if the stream is longer
+const ESTIMATION_SIZE: usize = 1 << 20;
+
+/// the plain text that is kept before the next block that is predicted, the window of deflate64
+const MAX_WINDOW: usize = 1 << 16;
+
+/// Decompresses a deflate stream that is passed in chunks with write, the result with the
+/// corrections is returned by finish.
+///
+/// The blocks are predicted with the parameters that were estimated from the start of the
+/// stream, so they aren't estimated again partway through (see
+/// PreflateConfig::reestimate_parameters) and the prediction isn't spread over threads.
+/// Every block is verified unless the verify mode is None, and the stream cache, the verbatim
+/// fallback and nested streams aren't supported.
+pub struct DeflateStreamDecompressor {
+    config: PreflateConfig,
+    /// set once a write failed, the stream can't be continued after that
+    failed: bool,
+    /// the received data that hasn't been read yet, which starts with the byte in which the
+    /// next block starts
+    compressed: Vec<u8>,
+    /// number of bits of the first byte of compressed that belong to the blocks before
+    bit_offset: u32,
+    /// number of bytes of the stream before compressed, and their crc32
+    consumed: u64,
+    consumed_crc: crc32fast::Hasher,
+    /// the block that the data ended in, which continues at the start of compressed so that
+    /// a large block that arrives in small chunks isn't decoded again for every one
+    partial: Option<PartialBlock>,
+    /// the plain text that is still needed, which is the window before the next block that is
+    /// predicted followed by the plain text of the blocks that were read after it, and of the
+    /// tokens of the partial block
+    plain_text: Vec<u8>,
+    /// where the plain text of the partial block starts in plain_text
+    complete_len: usize,
+    /// number of bytes that were dropped from the start of plain_text
+    dropped: u64,
+    plain_text_crc: crc32fast::Hasher,
+    /// the blocks that were read but not predicted yet, and where they start in plain_text
+    pending: Vec<PreflateTokenBlock>,
+    pending_pos: usize,
+    /// the cost of each block that was read
+    costs: Vec<BlockCost>,
+    /// None until the parameters have been estimated
+    prediction: Option<Prediction>,
+    /// None until the final block has been read
+    end: Option<StreamEnd>,
+    /// the plain text that the parameters are estimated from
+    estimation_size: usize,
+}
+
+/// the state of the prediction between the writes
+struct Prediction {
+    params: PreflateParameters,
+    encoder: CorrectionsEncoder,
+    /// the predictor at the start of the first pending block
+    snapshot: HashChainSnapshot,
+}
+
+/// what follows the final block once it has been read
+struct StreamEnd {
+    /// the bits after the final block in its last byte
+    padding: u8,
+    /// the data that was written after the end of the stream
+    trailing: Vec<u8>,
+}
+
+impl DeflateStreamDecompressor {
+    pub fn new(config: &PreflateConfig) -> Self {
+        DeflateStreamDecompressor {
+            config: config.clone(),
+            failed: false,
+            compressed: Vec::new(),
+            bit_offset: 0,
+            consumed: 0,
+            consumed_crc: crc32fast::Hasher::new(),
+            partial: None,
+            plain_text: Vec::new(),
+            complete_len: 0,
+            dropped: 0,
+            plain_text_crc: crc32fast::Hasher::new(),
+            pending: Vec::new(),
+            pending_pos: 0,
+            costs: Vec::new(),
+            prediction: None,
+            end: None,
+            estimation_size: ESTIMATION_SIZE,
+        }
+    }
+
+    /// Passes the next chunk of the stream and returns the plain text of the blocks that were
+    /// completed by it. An invalid stream fails as soon as the block that is damaged has been
+    /// received.
+    pub fn write(&mut self, data: &[u8]) -> Result<&[u8], PreflateError> {
+        if self.failed {
+            return Err(PreflateError::ReadDeflate(anyhow::anyhow!(
+                "the stream can't be continued after an earlier error"
+            )));
+        }
+        if let Some(end) = &mut self.end {
+            end.trailing.extend_from_slice(data);
+            return Ok(&[]);
+        }
+
+        // all of the plain text was returned by the writes before
+        self.drop_predicted_plain_text();
+        let returned = self.complete_len;
+
+        self.compressed.extend_from_slice(data);
+        if let Err(e) = self.read_blocks().and_then(|()| self.predict_blocks()) {
+            self.failed = true;
+            return Err(e);
+        }
+        Ok(&self.plain_text[returned..self.complete_len])
+    }
+
+    /// whether the final block has been received
+    pub fn is_complete(&self) -> bool {
+        self.end.is_some()
+    }
+
+    /// the data that was written after the end of the final block
+    pub fn trailing_data(&self) -> &[u8] {
+        self.end.as_ref().map_or(&[], |end| &end.trailing)
+    }
+
+    /// Returns the corrections for the stream, which recompress it the same way as the ones
+    /// from decompress_deflate_stream_with_config. The plain text of the result is empty, since
+    /// it was returned by write. Fails if the final block hasn't been received.
+    pub fn finish(self) -> Result<DecompressResult, PreflateError> {
+        if self.failed {
+            return Err(PreflateError::ReadDeflate(anyhow::anyhow!(
+                "the stream can't be finished after an earlier error"
+            )));
+        }
+        let (Some(end), Some(prediction)) = (self.end, self.prediction) else {
+            return Err(PreflateError::Truncated(self.costs.len()));
+        };
+
+        let mut encoder = prediction.encoder;
+        let e = encoder.encoder();
+        e.encode_misprediction(CodecMisprediction::EOFMisprediction, false);
+        e.encode_correction(CodecCorrection::NonZeroPadding, end.padding.into());
+
+        let original = OriginalStream {
+            length: self.consumed,
+            crc32: self.consumed_crc.finalize(),
+        };
+        let (cabac_encoded, mut statistics) = encoder.finish(original);
+        statistics.blocks = self.costs;
+
+        let cabac_encoded =
+            crate::secondary_compression::compress_corrections(cabac_encoded, &self.config)?;
+        PreflateError::check_limit(
+            ResourceLimit::CorrectionSize,
+            cabac_encoded.len() as u64,
+            self.config.max_correction_size as u64,
+        )?;
+
+        Ok(DecompressResult {
+            plain_text: Vec::new(),
+            cabac_encoded,
+            compressed_processed: self.consumed as usize,
+            statistics,
+            plain_text_crc32: self.plain_text_crc.finalize(),
+            profile: profile_of(&prediction.params, &self.config)?,
+            parameters: prediction.params,
+        })
+    }
+
+    /// reads the blocks that are complete in the data received so far
+    fn read_blocks(&mut self) -> Result<(), PreflateError> {
+        if self.config.nested_depth > 0 {
+            return Err(PreflateError::Io(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "nested streams can't be decompressed in chunks",
+            )));
+        }
+        let mut input = Cursor::new(&self.compressed[..]);
+        let mut reader = DeflateReader::with_plain_text_and_size(
+            &mut input,
+            std::mem::take(&mut self.plain_text),
+            self.config
+                .compressed_size
+                .map_or(u64::MAX, |size| size.saturating_sub(self.consumed)),
+        );
+        reader.set_lenient_stored_len(self.config.lenient_stored_len);
+        reader.set_deflate64(self.config.deflate64);
+        reader.set_max_plain_text_size(
+            self.config
+                .max_plaintext_size
+                .saturating_sub(self.dropped as usize),
+        );
+
+        let mut complete_len = self.complete_len;
+        let mut complete_bits = u64::from(self.bit_offset);
+        let mut result = reader.skip_bits(self.bit_offset);
+        if let Some(partial) = self.partial.take() {
+            reader.resume_block(partial);
+        }
+        let mut last = false;
+        while result.is_ok() && !last {
+            result = reader.read_block(&mut last).map(|block| {
+                self.plain_text_crc
+                    .update(&reader.plain_text()[complete_len..]);
+                complete_len = reader.plain_text().len();
+                complete_bits = reader.bit_position();
+                self.pending.push(block);
+                self.costs.push(reader.last_block_cost());
+            });
+        }
+
+        match result {
+            Ok(()) => {
+                let padding = reader.read_eof_padding();
+                self.plain_text = reader.move_plain_text();
+                self.complete_len = complete_len;
+
+                let processed = (input.position() as usize).min(self.compressed.len());
+                self.consume(processed);
+                self.end = Some(StreamEnd {
+                    padding,
+                    trailing: std::mem::take(&mut self.compressed),
+                });
+                Ok(())
+            }
+            Err(e) if is_end_of_data(&e) => {
+                // the tokens of a partial block are kept, a block whose header was cut off is
+                // read again from its start
+                self.partial = reader.take_partial_block();
+                self.plain_text = reader.move_plain_text();
+                let resume_bits = match &self.partial {
+                    Some(partial) => partial.bit_position(),
+                    None => {
+                        self.plain_text.truncate(complete_len);
+                        complete_bits
+                    }
+                };
+                self.complete_len = complete_len;
+
+                self.consume((resume_bits / 8) as usize);
+                self.bit_offset = (resume_bits % 8) as u32;
+                Ok(())
+            }
+            Err(e) => Err(PreflateError::read_block(self.costs.len(), e)),
+        }
+    }
+
+    /// drops the first len bytes of the compressed data that have been read
+    fn consume(&mut self, len: usize) {
+        self.consumed_crc.update(&self.compressed[..len]);
+        self.consumed += len as u64;
+        self.compressed.drain(..len);
+    }
+
+    /// predicts the blocks that are followed by enough of the plain text, or all of them once
+    /// the final block has been read
+    fn predict_blocks(&mut self) -> Result<(), PreflateError> {
+        let complete = self.end.is_some();
+
+        // a sync flush may turn out not to be a full one once more of the matches after it
+        // are known, so the flushes of the blocks that are still pending are marked again
+        for block in &mut self.pending {
+            if block.flush == FlushMarker::Full {
+                block.flush = FlushMarker::Sync;
+            }
+        }
+        mark_full_flushes(&mut self.pending);
+
+        let prediction = match &mut self.prediction {
+            Some(prediction) => prediction,
+            None if complete || self.complete_len >= self.estimation_size => {
+                self.prediction.insert(self.start_prediction(complete)?)
+            }
+            None => return Ok(()),
+        };
+
+        let mut ready = self.pending.len();
+        if !complete {
+            let mut end = self.pending_pos;
+            ready = self
+                .pending
+                .iter()
+                .take_while(|block| {
+                    end += block.uncompressed_len as usize;
+                    end + PREDICTION_LOOKAHEAD <= self.plain_text.len()
+                })
+                .count();
+        }
+        if ready == 0 {
+            return Ok(());
+        }
+
+        let first_block = self.costs.len() - self.pending.len();
+        prediction.snapshot = predict_next_blocks(
+            &self.plain_text,
+            &prediction.params,
+            (
+                &self.pending[..ready],
+                &mut self.costs[first_block..first_block + ready],
+            ),
+            first_block,
+            complete,
+            self.pending_pos as u32,
+            &prediction.snapshot,
+            &mut prediction.encoder.encoder(),
+            self.config.verify != VerifyMode::None,
+            &ZlibMatchPredictor::default(),
+            &mut ConfigObserver::new(&mut (), &self.config),
+        )?;
+
+        for block in self.pending.drain(..ready) {
+            self.pending_pos += block.uncompressed_len as usize;
+        }
+        Ok(())
+    }
+
+    /// estimates the parameters from what has been read so far and starts the corrections
+    fn start_prediction(&self, complete: bool) -> Result<Prediction, PreflateError> {
+        let mut params = parameters_for(
+            &self.plain_text[..self.complete_len],
+            &self.pending,
+            &self.config,
+        )?;
+        if !complete {
+            // the matches that haven't been read yet may go further back than the ones so far,
+            // up to the whole window, which the predictor otherwise can't find at all
+            params.window_bits =
+                params
+                    .window_bits
+                    .max(if self.config.deflate64 { 16 } else { 15 });
+            params.very_far_matches_detected = true;
+        }
+
+        let mut encoder = CorrectionsEncoder::new(&self.config);
+        params.write(&mut encoder.encoder());
+
+        Ok(Prediction {
+            snapshot: initial_snapshot(&self.plain_text, &params, &ZlibMatchPredictor::default()),
+            params,
+            encoder,
+        })
+    }
+
+    /// Drops the plain text that the predictor doesn't need anymore, keeping the window before
+    /// the next block. The JSON codec keeps all of it, since the positions are part of the
+    /// checksums that it writes.
+    fn drop_predicted_plain_text(&mut self) {
+        let Some(prediction) = &mut self.prediction else {
+            return;
+        };
+        #[cfg(feature = "serde")]
+        if matches!(prediction.encoder, CorrectionsEncoder::Json(_)) {
+            return;
+        }
+
+        let len = self
+            .pending_pos
+            .saturating_sub(MAX_WINDOW)
+            .min(prediction.snapshot.unreferenced_len() as usize);
+        prediction.snapshot.drop_leading(len as u32);
+        self.plain_text.drain(..len);
+        self.complete_len -= len;
+        self.pending_pos -= len;
+        self.dropped += len as u64;
+    }
+}
+
+/// the encoder of the corrections for the codec and probability model of the config, which is
+/// kept between the writes of DeflateStreamDecompressor
+enum CorrectionsEncoder {
+    Adaptive(CorrectionsHeader, OwnedAdaptiveEncoder),
+    Planes(CorrectionsHeader, PlanePredictionEncoder),
+    /// the probabilities are trained on the corrections of the whole stream, so they are
+    /// recorded until the end
+    Static(CorrectionsHeader, VerifyPredictionEncoder),
+    #[cfg(feature = "serde")]
+    Json(crate::json_codec::JsonPredictionEncoder<Vec<u8>>),
+}
+
+impl CorrectionsEncoder {
+    fn new(config: &PreflateConfig) -> Self {
+        match config.codec {
+            CorrectionCodec::Cabac => {
+                let header = CorrectionsHeader::from_config(config);
+                match config.probability_model {
+                    _ if header.planes => {
+                        CorrectionsEncoder::Planes(header, PlanePredictionEncoder::new())
+                    }
+                    ProbabilityModel::Adaptive => CorrectionsEncoder::Adaptive(
+                        header,
+                        OwnedAdaptiveEncoder::new(header, config.model_dictionary.as_deref()),
+                    ),
+                    ProbabilityModel::Static => {
+                        CorrectionsEncoder::Static(header, VerifyPredictionEncoder::new())
+                    }
+                }
+            }
+            #[cfg(feature = "serde")]
+            CorrectionCodec::Json => {
+                CorrectionsEncoder::Json(crate::json_codec::JsonPredictionEncoder::new(Vec::new()))
+            }
+        }
+    }
+
+    fn encoder(&mut self) -> &mut dyn PredictionEncoder {
+        match self {
+            CorrectionsEncoder::Adaptive(_, encoder) => encoder.encoder(),
+            CorrectionsEncoder::Planes(_, encoder) => encoder,
+            CorrectionsEncoder::Static(_, recorder) => recorder,
+            #[cfg(feature = "serde")]
+            CorrectionsEncoder::Json(encoder) => encoder,
+        }
+    }
+
+    /// finishes the corrections in the same layout as decompress_deflate_stream_with_config,
+    /// before the secondary compression
+    fn finish(self, original: OriginalStream) -> (Vec<u8>, CountNonDefaultActions) {
+        // the length of the stream follows the header byte like in the corrections of the
+        // whole stream at once
+        let start = |header: CorrectionsHeader| {
+            let mut cabac_encoded = vec![header.to_byte()];
+            cabac_encoded.extend_from_slice(&original.to_bytes());
+            cabac_encoded
+        };
+
+        let (cabac_encoded, statistics) = match self {
+            CorrectionsEncoder::Adaptive(header, encoder) => {
+                let mut cabac_encoded = start(header);
+                let statistics = encoder.finish_into(&mut cabac_encoded);
+                (cabac_encoded, statistics)
+            }
+            CorrectionsEncoder::Planes(header, mut encoder) => {
+                let mut cabac_encoded = start(header);
+                encoder.finish();
+                encoder.write_planes(&mut cabac_encoded);
+                (cabac_encoded, encoder.statistics())
+            }
+            CorrectionsEncoder::Static(header, recorder) => {
+                let mut cabac_encoded = start(header);
+                let statistics = if header.split_channels {
+                    encode_static_corrections_split(&recorder.actions(), &mut cabac_encoded)
+                } else {
+                    encode_static_corrections(&recorder.actions(), &mut cabac_encoded)
+                };
+                (cabac_encoded, statistics)
+            }
+            #[cfg(feature = "serde")]
+            CorrectionsEncoder::Json(mut encoder) => {
+                encoder.finish();
+                let statistics = encoder.statistics();
+                return (encoder.into_inner(), statistics);
+            }
+        };
+        (frame_corrections(&cabac_encoded), statistics)
+    }
+}
+
+/// Recompresses a stream from its corrections and the plain text that is passed in chunks, and
+/// writes the stream to the output block by block as the plain text for each block arrives.
+///
+/// A block is recreated once the plain text covers the most that it can cover, with a few
+/// kilobytes after that for the predictor, and after that only the window before the next block
+/// is kept. That is 258 bytes for each token of the block (about 4 MiB for the largest blocks of
+/// zlib), so the output trails the plain text by up to that much. Corrections of the original layout and of nested streams are recreated in one go by
+/// finish instead, since the nested streams have to be put back into the plain text before the
+/// outer stream can be recreated.
+pub struct DeflateStreamRecompressor<W> {
+    config: PreflateConfig,
+    /// set once a write failed, the stream can't be continued after that
+    failed: bool,
+    recreation: Recreation,
+    /// the plain text from the window before the next block on, unless it is all recreated by
+    /// finish
+    plain_text: Vec<u8>,
+    /// the length of all of the plain text so far
+    plain_text_len: u64,
+    output: W,
+    /// the length and crc32 of what was written to the output
+    written: u64,
+    written_crc: crc32fast::Hasher,
+}
+
+/// how DeflateStreamRecompressor recreates the stream
+enum Recreation {
+    /// the corrections as they were passed, which are recreated with all of the plain text
+    AllAtOnce(Vec<u8>),
+    Blocks {
+        decoder: OwnedPredictionDecoder,
+        chunked: ChunkedRecreation,
+        /// the length and crc32 of the original stream, which the JSON codec doesn't record
+        original: Option<OriginalStream>,
+        /// the JSON codec keeps all of the plain text, since the positions are part of the
+        /// checksums that it reads
+        keep_plain_text: bool,
+    },
+}
+
+impl<W: Write> DeflateStreamRecompressor<W> {
+    /// Starts the recompression with the corrections of the stream, which fails if they can't
+    /// be read.
+    pub fn new(
+        config: &PreflateConfig,
+        corrections: &[u8],
+        output: W,
+    ) -> Result<Self, PreflateError> {
+        PreflateError::check_limit(
+            ResourceLimit::CorrectionSize,
+            corrections.len() as u64,
+            config.max_correction_size as u64,
+        )?;
+        let decompressed =
+            crate::secondary_compression::decompress_corrections(corrections, config)?;
+
+        let recreation = if config.nested_depth > 0 || is_unframed_corrections(&decompressed) {
+            Recreation::AllAtOnce(corrections.to_vec())
+        } else {
+            let (mut decoder, original, keep_plain_text): (OwnedPredictionDecoder, _, _) =
+                match config.codec {
+                    CorrectionCodec::Cabac => {
+                        let (decoder, original) =
+                            owned_cabac_decoder(&decompressed, config.model_dictionary.as_deref())?;
+                        (decoder, Some(original), false)
+                    }
+                    #[cfg(feature = "serde")]
+                    CorrectionCodec::Json => (
+                        Box::new(crate::json_codec::JsonPredictionDecoder::new(Cursor::new(
+                            decompressed.into_owned(),
+                        ))),
+                        None,
+                        true,
+                    ),
+                };
+            let chunked = ChunkedRecreation::new(&mut decoder.as_mut(), config.deflate64);
+            Recreation::Blocks {
+                decoder,
+                chunked,
+                original,
+                keep_plain_text,
+            }
+        };
+
+        Ok(DeflateStreamRecompressor {
+            config: config.clone(),
+            failed: false,
+            recreation,
+            plain_text: Vec::new(),
+            plain_text_len: 0,
+            output,
+            written: 0,
+            written_crc: crc32fast::Hasher::new(),
+        })
+    }
+
+    /// Passes the next chunk of the plain text, and writes the blocks that it completes to the
+    /// output.
+    pub fn write_plain_text(&mut self, data: &[u8]) -> Result<(), PreflateError> {
+        if self.failed {
+            return Err(PreflateError::RecompressFailed(anyhow::anyhow!(
+                "the stream can't be continued after an earlier error"
+            )));
+        }
+        self.plain_text_len += data.len() as u64;
+        self.plain_text.extend_from_slice(data);
+
+        let result = PreflateError::check_limit(
+            ResourceLimit::PlainTextSize,
+            self.plain_text_len,
+            self.config.max_plaintext_size as u64,
+        )
+        .and_then(|()| self.recreate_blocks(false));
+        if result.is_err() {
+            self.failed = true;
+        }
+        result
+    }
+
+    /// Recreates the rest of the stream and writes it to the output, which is returned
+    /// afterwards. The stream is checked against the length and crc32 of the original in the
+    /// corrections only once it has been written, so the output has to be discarded if this
+    /// fails.
+    pub fn finish(mut self) -> Result<W, PreflateError> {
+        if self.failed {
+            return Err(PreflateError::RecompressFailed(anyhow::anyhow!(
+                "the stream can't be finished after an earlier error"
+            )));
+        }
+
+        if let Recreation::AllAtOnce(corrections) = &self.recreation {
+            let recompressed =
+                recompress_deflate_stream_with_config(&self.plain_text, corrections, &self.config)?;
+            self.output.write_all(&recompressed)?;
+            return Ok(self.output);
+        }
+
+        self.recreate_blocks(true)?;
+        if let Recreation::Blocks {
+            original: Some(original),
+            ..
+        } = &self.recreation
+        {
+            original.verify_info(OriginalStream {
+                length: self.written,
+                crc32: self.written_crc.clone().finalize(),
+            })?;
+        }
+        Ok(self.output)
+    }
+
+    /// writes the blocks that the plain text covers, and all of them once it is complete, and
+    /// then drops the plain text that isn't needed anymore
+    fn recreate_blocks(&mut self, complete: bool) -> Result<(), PreflateError> {
+        let Recreation::Blocks {
+            decoder,
+            chunked,
+            keep_plain_text,
+            ..
+        } = &mut self.recreation
+        else {
+            return Ok(());
+        };
+
+        let (output, written, written_crc) =
+            (&mut self.output, &mut self.written, &mut self.written_crc);
+        let mut on_chunk = |chunk: &[u8]| -> Result<(), PreflateError> {
+            *written += chunk.len() as u64;
+            written_crc.update(chunk);
+            Ok(output.write_all(chunk)?)
+        };
+        recreate_next_blocks(
+            chunked,
+            &self.plain_text,
+            complete,
+            &mut decoder.as_mut(),
+            &ZlibMatchPredictor::default(),
+            &mut on_chunk,
+            &mut ConfigObserver::new(&mut (), &self.config),
+        )?;
+
+        if !*keep_plain_text {
+            let len = chunked.unreferenced_len();
+            chunked.drop_leading(len);
+            self.plain_text.drain(..len as usize);
+        }
+        Ok(())
+    }
+}
+
+/// size of the chunks that PreflateDecoder reads from the compressed data
+#[cfg(feature = "async")]
+const DECODER_READ_SIZE: usize = 64 * 1024;
+
+/// Reads a deflate stream from the inner reader and returns its plain text through AsyncRead,
+/// as soon as each block has been received. The corrections are available from finish once the
+/// whole plain text was read.
+#[cfg(feature = "async")]
+pub struct PreflateDecoder<R> {
+    inner: R,
+    decompressor: DeflateStreamDecompressor,
+    /// the plain text of the last write to the decompressor, and how much of it was already
+    /// returned by poll_read
+    plain_text: Vec<u8>,
+    returned: usize,
+    buffer: Vec<u8>,
+}
+
+#[cfg(feature = "async")]
+impl<R: AsyncRead + Unpin> PreflateDecoder<R> {
+    pub fn new(inner: R, config: &PreflateConfig) -> Self {
+        PreflateDecoder {
+            inner,
+            decompressor: DeflateStreamDecompressor::new(config),
+            plain_text: Vec::new(),
+            returned: 0,
+            buffer: vec![0; DECODER_READ_SIZE],
+        }
+    }
+
+    /// the data that was read from the inner reader after the end of the final block
+    pub fn trailing_data(&self) -> &[u8] {
+        self.decompressor.trailing_data()
+    }
+
+    /// Returns the result with the corrections, which fails if the final block wasn't read. The
+    /// plain text of the result is empty, since it was returned by poll_read.
+    pub fn finish(self) -> Result<DecompressResult, PreflateError> {
+        self.decompressor.finish()
+    }
+}
+
+#[cfg(feature = "async")]
+impl<R: AsyncRead + Unpin> AsyncRead for PreflateDecoder<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            let available = &this.plain_text[this.returned..];
+            if !available.is_empty() || this.decompressor.is_complete() || buf.remaining() == 0 {
+                let len = available.len().min(buf.remaining());
+                buf.put_slice(&available[..len]);
+                this.returned += len;
+                return Poll::Ready(Ok(()));
+            }
+
+            // a pending read of the inner reader is passed on before anything changed
+            let mut read_buf = ReadBuf::new(&mut this.buffer);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read_buf))?;
+            let read = read_buf.filled().len();
+            if read == 0 {
+                return Poll::Ready(Err(std::io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "the deflate stream ends before its final block",
+                )));
+            }
+
+            let plain_text = this
+                .decompressor
+                .write(&this.buffer[..read])
+                .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+            this.plain_text.clear();
+            this.plain_text.extend_from_slice(plain_text);
+            this.returned = 0;
+        }
+    }
+}
+
+/// Takes the plain text of a stream through AsyncWrite and writes the recreated deflate stream
+/// to the inner writer block by block, see DeflateStreamRecompressor. The corrections have to be
+/// known up front. poll_shutdown writes the rest of the stream, and fails if it doesn't match
+/// the original, after which the inner writer is returned by into_inner.
+#[cfg(feature = "async")]
+pub struct PreflateEncoder<W> {
+    inner: W,
+    /// None once the rest of the stream was recreated by poll_shutdown
+    recompressor: Option<DeflateStreamRecompressor<Vec<u8>>>,
+    /// the recreated stream that wasn't passed on to the inner writer yet, and how much of it
+    /// was already written
+    pending: Vec<u8>,
+    written: usize,
+}
+
+#[cfg(feature = "async")]
+impl<W: AsyncWrite + Unpin> PreflateEncoder<W> {
+    pub fn new(
+        inner: W,
+        corrections: &[u8],
+        config: &PreflateConfig,
+    ) -> Result<Self, PreflateError> {
+        Ok(PreflateEncoder {
+            inner,
+            recompressor: Some(DeflateStreamRecompressor::new(
+                config,
+                corrections,
+                Vec::new(),
+            )?),
+            pending: Vec::new(),
+            written: 0,
+        })
+    }
+
+    /// the inner writer, to which the whole stream was written once poll_shutdown succeeded
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    /// writes the pending part of the recreated stream to the inner writer
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        while self.written < self.pending.len() {
+            let written =
+                ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending[self.written..]))?;
+            if written == 0 {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+            self.written += written;
+        }
+        self.pending.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "async")]
+impl<W: AsyncWrite + Unpin> AsyncWrite for PreflateEncoder<W> {
+    /// Recreates the blocks that the plain text completes. The plain text is only taken once
+    /// the blocks of the write before have been passed on to the inner writer.
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_pending(cx))?;
+
+        let Some(recompressor) = &mut this.recompressor else {
+            return Poll::Ready(Err(std::io::Error::new(
+                ErrorKind::BrokenPipe,
+                "the stream was already shut down",
+            )));
+        };
+        recompressor
+            .write_plain_text(buf)
+            .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+        this.pending = std::mem::take(&mut recompressor.output);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    /// Passes the blocks that were recreated on to the inner writer and flushes it. The blocks
+    /// that the plain text doesn't cover yet can't be written before more of it arrives.
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_pending(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    /// Recreates the rest of the stream, checks it against the original (see
+    /// DeflateStreamRecompressor::finish) and writes it to the inner writer before shutting it
+    /// down.
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_pending(cx))?;
+        if let Some(recompressor) = this.recompressor.take() {
+            this.pending = recompressor
+                .finish()
+                .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+            ready!(this.poll_write_pending(cx))?;
+        }
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[test]
+fn decompress_in_chunks() {
+    let compressed = crate::process::read_file("compressed_zlib_level3.deflate");
+    let config = PreflateConfig::default();
+    let expected = crate::decompress_deflate_stream_with_config(&compressed, &config).unwrap();
+
+    let mut data = compressed.clone();
+    data.extend_from_slice(b"trailer");
+
+    let mut decompressor = DeflateStreamDecompressor::new(&config);
+    let mut plain_text = Vec::new();
+    for chunk in data.chunks(1000) {
+        assert!(!decompressor.is_complete());
+        plain_text.extend_from_slice(decompressor.write(chunk).unwrap());
+    }
+    assert!(decompressor.is_complete());
+    assert_eq!(plain_text, expected.plain_text);
+    assert_eq!(decompressor.trailing_data(), b"trailer");
+
+    // the stream is shorter than the plain text that the parameters are estimated from, so
+    // they are the same as for the whole stream at once
+    let result = decompressor.finish().unwrap();
+    assert_eq!(result.cabac_encoded, expected.cabac_encoded);
+    assert_eq!(result.compressed_processed, compressed.len());
+    assert_eq!(result.plain_text_crc32, expected.plain_text_crc32);
+
+    // with the parameters estimated from the start, the blocks are predicted while the stream
+    // arrives and only the plain text that the predictor still needs is kept
+    let mut decompressor = DeflateStreamDecompressor::new(&config);
+    decompressor.estimation_size = 64 * 1024;
+    let mut plain_text = Vec::new();
+    let mut kept = 0;
+    for chunk in compressed.chunks(1000) {
+        plain_text.extend_from_slice(decompressor.write(chunk).unwrap());
+        kept = kept.max(decompressor.plain_text.len());
+    }
+    assert_eq!(plain_text, expected.plain_text);
+    assert!(kept < plain_text.len() / 2, "kept {} bytes", kept);
+
+    let result = decompressor.finish().unwrap();
+    assert_eq!(
+        crate::recompress_deflate_stream_with_config(&plain_text, &result.cabac_encoded, &config)
+            .unwrap(),
+        compressed
+    );
+
+    // the stream can't be finished before the final block
+    let mut decompressor = DeflateStreamDecompressor::new(&config);
+    decompressor
+        .write(&compressed[..compressed.len() / 2])
+        .unwrap();
+    assert!(matches!(
+        decompressor.finish(),
+        Err(PreflateError::Truncated(_))
+    ));
+}
+
+#[test]
+fn recompress_in_chunks() {
+    let compressed = crate::process::read_file("compressed_zlib_level3.deflate");
+    let config = PreflateConfig::default();
+    let result = crate::decompress_deflate_stream_with_config(&compressed, &config).unwrap();
+
+    let mut recompressor =
+        DeflateStreamRecompressor::new(&config, &result.cabac_encoded, Vec::new()).unwrap();
+    for chunk in result.plain_text.chunks(1000) {
+        recompressor.write_plain_text(chunk).unwrap();
+    }
+    assert_eq!(recompressor.finish().unwrap(), compressed);
+
+    // A block can cover up to 258 bytes per token, so the blocks of this stream are written
+    // before finish only if they are short. With a sync flush after every 1K, they are written
+    // as the plain text arrives, and only the window before the next block is kept.
+    let mut encoder =
+        flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+    for chunk in result.plain_text.chunks(1024) {
+        encoder.write_all(chunk).unwrap();
+        encoder.flush().unwrap();
+    }
+    let flushed = encoder.finish().unwrap();
+    let flushed_result = crate::decompress_deflate_stream_with_config(&flushed, &config).unwrap();
+
+    let mut recompressor =
+        DeflateStreamRecompressor::new(&config, &flushed_result.cabac_encoded, Vec::new()).unwrap();
+    let mut kept = 0;
+    for chunk in result.plain_text.chunks(1000) {
+        recompressor.write_plain_text(chunk).unwrap();
+        kept = kept.max(recompressor.plain_text.len());
+    }
+    assert!(
+        recompressor.output.len() > flushed.len() / 2,
+        "wrote {} bytes before finish",
+        recompressor.output.len()
+    );
+    assert!(kept < result.plain_text.len() / 2, "kept {} bytes", kept);
+    assert_eq!(recompressor.finish().unwrap(), flushed);
+
+    // the plain text of another stream doesn't match the original stream info
+    let mut recompressor =
+        DeflateStreamRecompressor::new(&config, &result.cabac_encoded, Vec::new()).unwrap();
+    recompressor
+        .write_plain_text(&result.plain_text[..result.plain_text.len() - 1])
+        .unwrap();
+    assert!(recompressor.finish().is_err());
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn decoder_and_encoder_adapters() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// returns the data in small pieces, and isn't ready before each of them
+    struct NotReady<'a> {
+        data: &'a [u8],
+        ready: bool,
+    }
+
+    impl AsyncRead for NotReady<'_> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            self.ready = !self.ready;
+            if !self.ready {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            let len = self.data.len().min(buf.remaining()).min(777);
+            buf.put_slice(&self.data[..len]);
+            self.data = &self.data[len..];
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    let compressed = crate::process::read_file("compressed_zlib_level3.deflate");
+    let config = PreflateConfig::default();
+    let expected = crate::decompress_deflate_stream_with_config(&compressed, &config).unwrap();
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let mut decoder = PreflateDecoder::new(
+            NotReady {
+                data: &compressed,
+                ready: false,
+            },
+            &config,
+        );
+        let mut plain_text = Vec::new();
+        decoder.read_to_end(&mut plain_text).await.unwrap();
+        assert_eq!(plain_text, expected.plain_text);
+        let corrections = decoder.finish().unwrap().cabac_encoded;
+        assert_eq!(corrections, expected.cabac_encoded);
+
+        let mut encoder = PreflateEncoder::new(Vec::new(), &corrections, &config).unwrap();
+        tokio::io::copy(&mut &plain_text[..], &mut encoder)
+            .await
+            .unwrap();
+        encoder.shutdown().await.unwrap();
+        assert_eq!(encoder.into_inner(), compressed);
+
+        // the plain text of another stream fails once the stream is shut down
+        let mut encoder = PreflateEncoder::new(Vec::new(), &corrections, &config).unwrap();
+        encoder
+            .write_all(&plain_text[..plain_text.len() - 1])
+            .await
+            .unwrap();
+        let e = encoder.shutdown().await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+
+        // a stream that is cut off is an error once the inner reader is at its end
+        let mut decoder = PreflateDecoder::new(&compressed[..compressed.len() / 2], &config);
+        let e = decoder.read_to_end(&mut Vec::new()).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+    });
+}
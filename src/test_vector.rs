@@ -0,0 +1,430 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Conformance test vectors: everything this implementation outputs for a deflate stream,
+//! written as text so that bindings and other implementations can check that they give the
+//! same results. The format is one record per line:
+//! ```text
+//! preflate-test-vector 1
+//! scheme <CONTEXT_SCHEME_VERSION>
+//! compressed <length> <crc32 as 8 hex digits> <bytes processed>
+//! plain-text <length> <crc32 as 8 hex digits>
+//! profile <fast 0|1> <good> <lazy> <nice> <chain> <hash algorithm> <hash shift> <hash mask> <huffman calc> <name>
+//! block <stored|static|dynamic> <last 0|1> <uncompressed length> <tokens> <header bits> <tree bits> <token bits>
+//! tree <literal lengths as hex digits> <distance lengths as hex digits>
+//! corrections <corrections as hex>
+//! ```
+//!
+//! There is a block line for every block, each dynamic one is followed by its tree line.
+
+use std::fmt::Write;
+
+use crate::{
+    compressor_profile::CompressorProfile,
+    decompress_deflate_stream_with_config,
+    inspect::{inspect_deflate_stream, InspectBlockType},
+    preflate_config::PreflateConfig,
+    preflate_error::PreflateError,
+    recompress_deflate_stream_with_config, CONTEXT_SCHEME_VERSION,
+};
+
+const FORMAT_HEADER: &str = "preflate-test-vector 1";
+
+/// a block of the stream as it is recorded in the test vector
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestVectorBlock {
+    pub block_type: InspectBlockType,
+    pub last: bool,
+    pub uncompressed_len: u32,
+    pub token_count: usize,
+    pub header_bits: u64,
+    pub tree_bits: u64,
+    pub token_bits: u64,
+    /// the bit lengths of the literal/length and distance codes of a dynamic block
+    pub literal_lengths: Vec<u8>,
+    pub distance_lengths: Vec<u8>,
+}
+
+/// the expected outputs for a deflate stream, see export_test_vector
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestVector {
+    pub context_scheme_version: u16,
+    pub compressed_len: usize,
+    pub compressed_crc32: u32,
+    pub compressed_processed: usize,
+    pub plain_text_len: usize,
+    pub plain_text_crc32: u32,
+    /// the settings of the compressor profile that the stream was predicted with, in the
+    /// format of the profile line
+    pub profile: String,
+    pub blocks: Vec<TestVectorBlock>,
+    pub corrections: Vec<u8>,
+}
+
+pub(crate) fn describe_profile(profile: &CompressorProfile) -> String {
+    let p = &profile.parser_config;
+    format!(
+        "{} {} {} {} {} {} {} {} {:?} {}",
+        u8::from(profile.fast_parser),
+        p.good_length,
+        p.max_lazy,
+        p.nice_length,
+        p.max_chain,
+        profile.hash_algorithm,
+        profile.hash_shift,
+        profile.hash_mask,
+        profile.huff_calc,
+        profile.name
+    )
+}
+
+/// one hex digit per value, for the bit lengths which are never more than 15
+fn hex_digits(values: &[u8]) -> String {
+    values.iter().map(|v| format!("{:x}", v)).collect()
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn parse_hex_digits(text: &str) -> Option<Vec<u8>> {
+    text.chars()
+        .map(|c| c.to_digit(16).map(|d| d as u8))
+        .collect()
+}
+
+fn parse_hex_bytes(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+impl TestVector {
+    /// writes the test vector in the text format of the module
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        writeln!(text, "{}", FORMAT_HEADER).unwrap();
+        writeln!(text, "scheme {}", self.context_scheme_version).unwrap();
+        writeln!(
+            text,
+            "compressed {} {:08x} {}",
+            self.compressed_len, self.compressed_crc32, self.compressed_processed
+        )
+        .unwrap();
+        writeln!(
+            text,
+            "plain-text {} {:08x}",
+            self.plain_text_len, self.plain_text_crc32
+        )
+        .unwrap();
+        writeln!(text, "profile {}", self.profile).unwrap();
+
+        for block in &self.blocks {
+            writeln!(
+                text,
+                "block {} {} {} {} {} {} {}",
+                match block.block_type {
+                    InspectBlockType::Stored => "stored",
+                    InspectBlockType::StaticHuffman => "static",
+                    InspectBlockType::DynamicHuffman => "dynamic",
+                },
+                u8::from(block.last),
+                block.uncompressed_len,
+                block.token_count,
+                block.header_bits,
+                block.tree_bits,
+                block.token_bits
+            )
+            .unwrap();
+
+            if block.block_type == InspectBlockType::DynamicHuffman {
+                writeln!(
+                    text,
+                    "tree {} {}",
+                    hex_digits(&block.literal_lengths),
+                    hex_digits(&block.distance_lengths)
+                )
+                .unwrap();
+            }
+        }
+
+        writeln!(text, "corrections {}", hex_bytes(&self.corrections)).unwrap();
+        text
+    }
+
+    /// parses the text format of the module
+    pub fn parse(text: &str) -> Result<Self, PreflateError> {
+        let mut lines = text.lines().enumerate();
+        let invalid_data = |message: String| {
+            PreflateError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                message,
+            ))
+        };
+        let invalid = |line_number: usize, line: &str| {
+            invalid_data(format!(
+                "invalid test vector line {}: {}",
+                line_number + 1,
+                line
+            ))
+        };
+        let ended = || invalid_data("test vector ends early".to_string());
+
+        let mut next = |kind: &str| -> Result<(usize, &str, Vec<&str>), PreflateError> {
+            let (n, line) = lines.next().ok_or_else(ended)?;
+            let mut fields = line.split_ascii_whitespace();
+            if fields.next() != Some(kind) {
+                return Err(invalid(n, line));
+            }
+            Ok((n, line, fields.collect()))
+        };
+
+        let (n, line, fields) = next("preflate-test-vector")?;
+        if fields != ["1"] {
+            return Err(invalid(n, line));
+        }
+
+        let (n, line, fields) = next("scheme")?;
+        let context_scheme_version = match fields[..] {
+            [version] => version.parse().ok(),
+            _ => None,
+        }
+        .ok_or_else(|| invalid(n, line))?;
+
+        let (n, line, fields) = next("compressed")?;
+        let (compressed_len, compressed_crc32, compressed_processed) = match fields[..] {
+            [len, crc, processed] => (|| {
+                Some((
+                    len.parse().ok()?,
+                    u32::from_str_radix(crc, 16).ok()?,
+                    processed.parse().ok()?,
+                ))
+            })(),
+            _ => None,
+        }
+        .ok_or_else(|| invalid(n, line))?;
+
+        let (n, line, fields) = next("plain-text")?;
+        let (plain_text_len, plain_text_crc32) = match fields[..] {
+            [len, crc] => (|| Some((len.parse().ok()?, u32::from_str_radix(crc, 16).ok()?)))(),
+            _ => None,
+        }
+        .ok_or_else(|| invalid(n, line))?;
+
+        let (n, line, _) = next("profile")?;
+        let profile = line
+            .split_once(' ')
+            .map(|(_, p)| p.to_string())
+            .ok_or_else(|| invalid(n, line))?;
+
+        let mut blocks: Vec<TestVectorBlock> = Vec::new();
+        let corrections = loop {
+            let (n, line) = lines.next().ok_or_else(ended)?;
+            let fields: Vec<&str> = line.split_ascii_whitespace().collect();
+
+            match fields[..] {
+                ["block", block_type, last, uncompressed_len, token_count, header_bits, tree_bits, token_bits] =>
+                {
+                    blocks.push(
+                        (|| {
+                            Some(TestVectorBlock {
+                                block_type: match block_type {
+                                    "stored" => InspectBlockType::Stored,
+                                    "static" => InspectBlockType::StaticHuffman,
+                                    "dynamic" => InspectBlockType::DynamicHuffman,
+                                    _ => return None,
+                                },
+                                last: match last {
+                                    "0" => false,
+                                    "1" => true,
+                                    _ => return None,
+                                },
+                                uncompressed_len: uncompressed_len.parse().ok()?,
+                                token_count: token_count.parse().ok()?,
+                                header_bits: header_bits.parse().ok()?,
+                                tree_bits: tree_bits.parse().ok()?,
+                                token_bits: token_bits.parse().ok()?,
+                                literal_lengths: Vec::new(),
+                                distance_lengths: Vec::new(),
+                            })
+                        })()
+                        .ok_or_else(|| invalid(n, line))?,
+                    );
+                }
+                ["tree", literal_lengths, distance_lengths] => {
+                    let block = blocks
+                        .last_mut()
+                        .filter(|b| {
+                            b.block_type == InspectBlockType::DynamicHuffman
+                                && b.literal_lengths.is_empty()
+                        })
+                        .ok_or_else(|| invalid(n, line))?;
+                    block.literal_lengths =
+                        parse_hex_digits(literal_lengths).ok_or_else(|| invalid(n, line))?;
+                    block.distance_lengths =
+                        parse_hex_digits(distance_lengths).ok_or_else(|| invalid(n, line))?;
+                }
+                ["corrections", corrections] => {
+                    break parse_hex_bytes(corrections).ok_or_else(|| invalid(n, line))?
+                }
+                _ => return Err(invalid(n, line)),
+            }
+        };
+
+        Ok(TestVector {
+            context_scheme_version,
+            compressed_len,
+            compressed_crc32,
+            compressed_processed,
+            plain_text_len,
+            plain_text_crc32,
+            profile,
+            blocks,
+            corrections,
+        })
+    }
+}
+
+/// Decompresses the stream with the config and packages everything that comes out of it into
+/// a test vector: the digests of the data, the corrections, the compressor profile and the
+/// layout of the blocks.
+pub fn export_test_vector(
+    compressed_data: &[u8],
+    config: &PreflateConfig,
+) -> Result<TestVector, PreflateError> {
+    Ok(export_with_plain_text(compressed_data, config)?.0)
+}
+
+fn export_with_plain_text(
+    compressed_data: &[u8],
+    config: &PreflateConfig,
+) -> Result<(TestVector, Vec<u8>), PreflateError> {
+    let result = decompress_deflate_stream_with_config(compressed_data, config)?;
+    let report = inspect_deflate_stream(compressed_data)?;
+
+    let vector = TestVector {
+        context_scheme_version: CONTEXT_SCHEME_VERSION,
+        compressed_len: compressed_data.len(),
+        compressed_crc32: crc32fast::hash(compressed_data),
+        compressed_processed: result.compressed_processed,
+        plain_text_len: result.plain_text.len(),
+        plain_text_crc32: result.plain_text_crc32,
+        profile: describe_profile(&result.profile),
+        blocks: report
+            .blocks
+            .into_iter()
+            .map(|b| {
+                let (literal_lengths, distance_lengths) = b
+                    .dynamic_header
+                    .map(|h| (h.literal_lengths, h.distance_lengths))
+                    .unwrap_or_default();
+
+                TestVectorBlock {
+                    block_type: b.block_type,
+                    last: b.last,
+                    uncompressed_len: b.uncompressed_len,
+                    token_count: b.token_count,
+                    header_bits: b.cost.header_bits,
+                    tree_bits: b.cost.tree_bits,
+                    token_bits: b.cost.token_bits,
+                    literal_lengths,
+                    distance_lengths,
+                }
+            })
+            .collect(),
+        corrections: result.cabac_encoded,
+    };
+
+    Ok((vector, result.plain_text))
+}
+
+/// Checks this implementation against a test vector: exporting the stream again has to give
+/// the same vector, and the corrections of the vector have to recompress the plain text into
+/// the stream. Returns a Mismatch naming the first part that differs.
+pub fn check_test_vector(
+    vector: &TestVector,
+    compressed_data: &[u8],
+    config: &PreflateConfig,
+) -> Result<(), PreflateError> {
+    let mismatch =
+        |what: &str| PreflateError::Mismatch(anyhow::anyhow!("test vector differs in {}", what));
+
+    let (actual, plain_text) = export_with_plain_text(compressed_data, config)?;
+
+    if actual.context_scheme_version != vector.context_scheme_version {
+        return Err(mismatch("the context scheme version"));
+    }
+    if (actual.compressed_len, actual.compressed_crc32)
+        != (vector.compressed_len, vector.compressed_crc32)
+    {
+        return Err(mismatch("the compressed data"));
+    }
+    if actual.compressed_processed != vector.compressed_processed {
+        return Err(mismatch("the number of bytes processed"));
+    }
+    if (actual.plain_text_len, actual.plain_text_crc32)
+        != (vector.plain_text_len, vector.plain_text_crc32)
+    {
+        return Err(mismatch("the plain text"));
+    }
+    if actual.profile != vector.profile {
+        return Err(mismatch("the profile"));
+    }
+    if let Some(i) = (0..actual.blocks.len().max(vector.blocks.len()))
+        .find(|&i| actual.blocks.get(i) != vector.blocks.get(i))
+    {
+        return Err(mismatch(&format!("block {}", i)));
+    }
+    if actual.corrections != vector.corrections {
+        return Err(mismatch("the corrections"));
+    }
+
+    // the plain text is the same, so the corrections of the vector have to recreate the stream
+    let recompressed =
+        recompress_deflate_stream_with_config(&plain_text, &vector.corrections, config)?;
+    if recompressed[..] != compressed_data[..vector.compressed_processed] {
+        return Err(mismatch("the recompressed stream"));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_vector_roundtrip() {
+    let compressed_data = crate::process::read_file("compressed_zlib_level6.deflate");
+    let config = PreflateConfig::default();
+
+    let vector = export_test_vector(&compressed_data, &config).unwrap();
+    assert!(
+        vector
+            .blocks
+            .iter()
+            .any(|b| b.block_type == InspectBlockType::DynamicHuffman
+                && !b.literal_lengths.is_empty())
+    );
+
+    let text = vector.to_text();
+    assert!(text.starts_with(FORMAT_HEADER));
+    let parsed = TestVector::parse(&text).unwrap();
+    assert_eq!(parsed, vector);
+
+    check_test_vector(&parsed, &compressed_data, &config).unwrap();
+
+    // a vector from another implementation that has different corrections
+    let mut other = parsed.clone();
+    other.corrections[10] ^= 1;
+    assert!(matches!(
+        check_test_vector(&other, &compressed_data, &config),
+        Err(PreflateError::Mismatch(_))
+    ));
+
+    assert!(TestVector::parse(&text[..text.len() / 2]).is_err());
+    assert!(TestVector::parse(&text.replace("scheme", "schema")).is_err());
+}
@@ -4,16 +4,20 @@
  *  This software incorporates material from third parties. See NOTICE.txt for details.
  *--------------------------------------------------------------------------------------------*/
 
-use anyhow::Context;
-
 use crate::{
     bit_helper::DebugHash,
+    block_observer::{BlockObserver, BlockSummary, CountingCodec},
     cabac_codec::{decode_difference, encode_difference},
-    hash_chain::RotatingHashTrait,
-    predictor_state::{MatchResult, PredictorState},
-    preflate_constants::{MAX_MATCH, MIN_MATCH},
+    hash_chain::{HashChainSnapshot, RotatingHashTrait},
+    match_predictor::{MatchPredictor, ZlibMatchPredictor},
+    predictor_state::{MatchResult, PredictorState, MAX_CHAIN_WALK},
+    preflate_constants::{DEFLATE64_MAX_MATCH, MIN_MATCH},
+    preflate_error::{PreflateError, TokenContext, TokenError, TokenErrorKind},
     preflate_parameter_estimator::PreflateParameters,
-    preflate_token::{BlockType, PreflateToken, PreflateTokenBlock, PreflateTokenReference},
+    preflate_token::{
+        BlockType, FlushMarker, IrregularEncoding, PreflateToken, PreflateTokenBlock,
+        PreflateTokenReference,
+    },
     statistical_codec::{
         CodecCorrection, CodecMisprediction, PredictionDecoder, PredictionEncoder,
     },
@@ -21,16 +25,147 @@ use crate::{
 
 const VERIFY: bool = false;
 
-pub struct TokenPredictor<'a, H: RotatingHashTrait> {
+/// The type of a block and the number of its tokens, which are the first things that are
+/// decoded for it. When the plain text arrives in chunks, they tell how much of it the block
+/// can cover before the rest of it is recreated.
+#[derive(Copy, Clone, Debug)]
+pub struct BlockStart {
+    block_type: BlockType,
+    /// the number of tokens of a Huffman block, 0 for a stored one
+    block_size: u32,
+    /// the corrections that were needed for them, for the BlockSummary
+    corrections: u32,
+}
+
+impl BlockStart {
+    /// the most plain text that the block can cover, where max_match is the longest match
+    pub fn max_uncompressed_len(&self, max_match: u32) -> u64 {
+        match self.block_type {
+            BlockType::Stored => u16::MAX.into(),
+            _ => u64::from(self.block_size) * u64::from(max_match),
+        }
+    }
+
+    /// decodes the start of the next block, which is predicted with the parameters
+    pub fn decode<D: PredictionDecoder>(
+        block_index: usize,
+        codec: &mut D,
+        params: &PreflateParameters,
+    ) -> Result<Self, PreflateError> {
+        const BT_STORED: u32 = BlockType::Stored as u32;
+        const BT_DYNAMICHUFF: u32 = BlockType::DynamicHuff as u32;
+        const BT_STATICHUFF: u32 = BlockType::StaticHuff as u32;
+
+        let mut counting = CountingCodec::new(codec);
+        counting.decode_verify_state("blocktypestart", 0);
+
+        let bt = decode_difference(
+            BT_DYNAMICHUFF,
+            counting.decode_correction(CodecCorrection::BlockTypeCorrection),
+        );
+        let block_type = match bt {
+            Some(BT_STORED) => BlockType::Stored,
+            Some(BT_STATICHUFF) => BlockType::StaticHuff,
+            Some(BT_DYNAMICHUFF) => BlockType::DynamicHuff,
+            _ => {
+                return Err(PreflateError::RecreateBlock(
+                    block_index,
+                    anyhow::anyhow!("Invalid block type {:?}", bt),
+                ));
+            }
+        };
+
+        let block_size = if block_type == BlockType::Stored {
+            0
+        } else {
+            match counting.decode_correction(CodecCorrection::TokenCount) {
+                0 => params.max_token_count.into(),
+                blocksize => blocksize - 1,
+            }
+        };
+
+        Ok(BlockStart {
+            block_type,
+            block_size,
+            corrections: counting.corrections,
+        })
+    }
+}
+
+/// Hop correction that means the match wasn't within the part of the hash chain that is walked,
+/// and that its distance follows as a DeepMatchDistance correction. No actual hop count can
+/// be this large, since there is at most one hop per entry walked.
+const DEEP_MATCH_HOPS: u32 = MAX_CHAIN_WALK + 1;
+
+/// context bucket for the length and distance corrections. Short matches are mispredicted
+/// much more often (and differently) than long ones, and static huffman blocks tend to
+/// come from a different encoder configuration than dynamic ones, so each combination
+/// gets its own statistics.
+fn correction_bucket(len: u32, block_type: BlockType) -> u8 {
+    let len_bucket = match len {
+        0..=3 => 0,
+        4..=5 => 1,
+        6..=15 => 2,
+        _ => 3,
+    };
+
+    len_bucket * 2 + u8::from(block_type == BlockType::StaticHuff)
+}
+
+/// context bucket for the length corrections, which additionally splits by whether the
+/// reference is the last token of the block, since matches there are cut short when the block
+/// was flushed before the lookahead of the compressor was full, and by whether the token was
+/// predicted to be a literal, since the length of such a reference is a guess.
+fn len_correction_bucket(
+    len: u32,
+    block_type: BlockType,
+    last_token: bool,
+    repredicted: bool,
+) -> u8 {
+    correction_bucket(len, block_type) + 8 * u8::from(last_token) + 16 * u8::from(repredicted)
+}
+
+/// context bucket for the distance corrections, which splits by how far away the predicted match
+/// is, since far matches need a lot more hops to correct than close ones, and by whether the
+/// token was predicted to be a literal. Unlike the lengths, the distances are corrected the same
+/// way in static and dynamic huffman blocks.
+fn dist_correction_bucket(len: u32, predicted_dist: u32, repredicted: bool) -> u8 {
+    let dist_bucket = match predicted_dist {
+        0..=256 => 0,
+        257..=4096 => 1,
+        _ => 2,
+    };
+
+    dist_bucket * 8 + correction_bucket(len, BlockType::DynamicHuff) + u8::from(repredicted)
+}
+
+/// wraps an error about the token at token_index of the block
+fn token_error(block: usize, token_index: usize) -> impl Fn(TokenError) -> PreflateError + Copy {
+    move |error| PreflateError::PredictionMismatch {
+        block,
+        token_index,
+        error,
+    }
+}
+
+pub struct TokenPredictor<'a, H: RotatingHashTrait, M: MatchPredictor = ZlibMatchPredictor> {
     state: PredictorState<'a, H>,
     params: PreflateParameters,
-    pending_reference: Option<PreflateTokenReference>,
+    match_predictor: M,
     current_token_count: u32,
     max_token_count: u32,
+    /// the number of tokens of the current block as the decoder knows it, which is the maximum
+    /// if the size of the block wasn't encoded
+    block_size: u32,
 }
 
-impl<'a, H: RotatingHashTrait> TokenPredictor<'a, H> {
-    pub fn new(uncompressed: &'a [u8], params: &PreflateParameters, offset: u32) -> Self {
+impl<'a, H: RotatingHashTrait, M: MatchPredictor> TokenPredictor<'a, H, M> {
+    pub fn new(
+        uncompressed: &'a [u8],
+        params: &PreflateParameters,
+        offset: u32,
+        match_predictor: M,
+    ) -> Self {
         // Implement constructor logic for PreflateTokenPredictor
         // Initialize fields as necessary
         // Create and initialize PreflatePredictorState, PreflateHashChainExt, and PreflateSeqChain instances
@@ -39,37 +174,104 @@ impl<'a, H: RotatingHashTrait> TokenPredictor<'a, H> {
         let mut r = Self {
             state: PredictorState::<'a>::new(uncompressed, params),
             params: *params,
-            pending_reference: None,
+            match_predictor,
             current_token_count: 0,
             max_token_count: params.max_token_count.into(),
+            block_size: 0,
         };
 
-        if r.state.available_input_size() >= 2 {
-            let b0 = r.state.input_cursor()[0];
-            let b1 = r.state.input_cursor()[1];
-
-            r.state.update_running_hash(b0);
-            r.state.update_running_hash(b1);
+        // prime the running hash with the bytes before the lookahead of the first position
+        let prime_len = H::NUM_HASH_BYTES as usize - 1;
+        if r.state.available_input_size() >= prime_len as u32 {
+            for i in 0..prime_len {
+                let b = r.state.input_cursor()[i];
+                r.state.update_running_hash(b);
+            }
         }
         r.state.update_hash(offset);
 
         r
     }
 
+    /// Recreates the predictor at the start of a block from a snapshot that was taken
+    /// with hash_snapshot. Nothing else needs to be stored, since the rest of the
+    /// predictor is reset at the start of every block.
+    pub fn from_snapshot(
+        uncompressed: &'a [u8],
+        params: &PreflateParameters,
+        input_pos: u32,
+        snapshot: &HashChainSnapshot,
+        match_predictor: M,
+    ) -> Result<Self, TokenError> {
+        Ok(Self {
+            state: PredictorState::from_snapshot(uncompressed, params, input_pos, snapshot)?,
+            params: *params,
+            match_predictor,
+            current_token_count: 0,
+            max_token_count: params.max_token_count.into(),
+            block_size: 0,
+        })
+    }
+
+    /// snapshot of the hash chain, only valid between blocks
+    pub fn hash_snapshot(&self) -> HashChainSnapshot {
+        self.state.hash_snapshot()
+    }
+
+    pub fn current_input_pos(&self) -> u32 {
+        self.state.current_input_pos()
+    }
+
     pub fn checksum(&self) -> DebugHash {
         let mut c = DebugHash::default();
         self.state.checksum(&mut c);
         c
     }
 
+    /// the context bucket of the length correction of the reference at the current token
+    fn len_correction_bucket(&self, len: u32, block_type: BlockType, repredicted: bool) -> u8 {
+        len_correction_bucket(
+            len,
+            block_type,
+            self.current_token_count + 1 >= self.block_size,
+            repredicted,
+        )
+    }
+
+    /// encodes the differences between the block and what the predictor would have written,
+    /// and tells the observer how well the block was predicted
     pub fn predict_block<D: PredictionEncoder>(
         &mut self,
+        block_index: usize,
+        block: &PreflateTokenBlock,
+        codec: &mut D,
+        last_block: bool,
+        observer: &mut dyn BlockObserver,
+    ) -> Result<(), PreflateError> {
+        let start_pos = self.current_input_pos();
+        let mut counting = CountingCodec::new(codec);
+        self.predict_block_tokens(block_index, block, &mut counting, last_block)?;
+
+        observer.block_predicted(&BlockSummary {
+            index: block_index,
+            block_type: block.block_type.into(),
+            token_count: block.tokens.len(),
+            uncompressed_len: self.current_input_pos() - start_pos,
+            mispredictions: counting.mispredictions,
+            corrections: counting.corrections,
+        });
+        Ok(())
+    }
+
+    fn predict_block_tokens<D: PredictionEncoder>(
+        &mut self,
+        block_index: usize,
         block: &PreflateTokenBlock,
         codec: &mut D,
         last_block: bool,
-    ) -> anyhow::Result<()> {
+    ) -> Result<(), PreflateError> {
         self.current_token_count = 0;
-        self.pending_reference = None;
+        self.match_predictor.reset();
 
         codec.encode_verify_state("blocktypestart", 0);
 
@@ -79,11 +281,31 @@ impl<'a, H: RotatingHashTrait> TokenPredictor<'a, H> {
         );
 
         if block.block_type == BlockType::Stored {
-            codec.encode_value(block.uncompressed_len as u16, 16);
+            // stored blocks are predicted to be the empty ones of flushes, which are much
+            // more frequent than the others in streams that have them
+            codec.encode_misprediction(
+                CodecMisprediction::StoredBlockNotEmpty,
+                block.uncompressed_len != 0,
+            );
+            if block.uncompressed_len != 0 {
+                codec.encode_value(block.uncompressed_len as u16, 16);
+            }
 
             codec.encode_correction(CodecCorrection::NonZeroPadding, block.padding_bits.into());
+            codec.encode_correction(
+                CodecCorrection::StoredNLenCorrection,
+                block.nlen_mismatch.into(),
+            );
             self.state.update_hash(block.uncompressed_len);
 
+            if block.uncompressed_len == 0 {
+                codec.encode_misprediction(
+                    CodecMisprediction::FullFlush,
+                    block.flush == FlushMarker::Full,
+                );
+            }
+            self.state.apply_flush_marker(block.flush);
+
             return Ok(());
         }
 
@@ -92,11 +314,10 @@ impl<'a, H: RotatingHashTrait> TokenPredictor<'a, H> {
         if (!last_block && block.tokens.len() != self.max_token_count as usize)
             || block.tokens.len() > self.max_token_count as usize
         {
-            codec.encode_correction(
-                CodecCorrection::TokenCount,
-                u32::try_from(block.tokens.len()).unwrap() + 1,
-            );
+            self.block_size = u32::try_from(block.tokens.len()).unwrap();
+            codec.encode_correction(CodecCorrection::TokenCount, self.block_size + 1);
         } else {
+            self.block_size = self.max_token_count;
             codec.encode_correction(CodecCorrection::TokenCount, 0);
         }
 
@@ -158,20 +379,20 @@ impl<'a, H: RotatingHashTrait> TokenPredictor<'a, H> {
                     }
                 }
                 PreflateToken::Reference(target_ref) => {
-                    let predicted_ref = match predicted_token {
+                    let (predicted_ref, repredicted) = match predicted_token {
                         PreflateToken::Literal => {
                             // target had a reference, so we were wrong if we predicted a literal
                             codec.encode_misprediction(
                                 CodecMisprediction::LiteralPredictionWrong,
                                 true,
                             );
-                            self.repredict_reference(Some(*target_ref))
+                            let r = self
+                                .repredict_reference(Some(*target_ref))
                                 .with_context(|| {
-                                    format!(
-                                        "repredict_reference target={:?} index={}",
-                                        target_ref, i
-                                    )
-                                })?
+                                    format!("repredict_reference target={:?}", target_ref)
+                                })
+                                .map_err(token_error(block_index, i))?;
+                            (r, true)
                         }
                         PreflateToken::Reference(r) => {
                             // we predicted a reference correctly, so verify that the length/dist was correct
@@ -179,33 +400,56 @@ impl<'a, H: RotatingHashTrait> TokenPredictor<'a, H> {
                                 CodecMisprediction::ReferencePredictionWrong,
                                 false,
                             );
-                            r
+                            (r, false)
                         }
                     };
 
-                    codec.encode_correction(
+                    codec.encode_bucket_correction(
                         CodecCorrection::LenCorrection,
+                        self.len_correction_bucket(
+                            predicted_ref.len(),
+                            block.block_type,
+                            repredicted,
+                        ),
                         encode_difference(predicted_ref.len(), target_ref.len()),
                     );
 
+                    let dist_bucket =
+                        dist_correction_bucket(target_ref.len(), predicted_ref.dist(), repredicted);
+
                     if predicted_ref.len() != target_ref.len() {
-                        let rematch = self.state.calculate_hops(target_ref).with_context(|| {
-                            format!("calculate_hops p={:?}, t={:?}", predicted_ref, target_ref)
-                        })?;
-                        codec.encode_correction(CodecCorrection::DistAfterLenCorrection, rematch);
+                        self.encode_hops(
+                            codec,
+                            CodecCorrection::DistAfterLenCorrection,
+                            dist_bucket,
+                            &predicted_ref,
+                            target_ref,
+                        )
+                        .map_err(token_error(block_index, i))?;
                     } else if target_ref.dist() != predicted_ref.dist() {
-                        let rematch = self.state.calculate_hops(target_ref).with_context(|| {
-                            format!("calculate_hops p={:?}, t={:?}", predicted_ref, target_ref)
-                        })?;
-                        codec.encode_correction(CodecCorrection::DistOnlyCorrection, rematch);
+                        self.encode_hops(
+                            codec,
+                            CodecCorrection::DistOnlyCorrection,
+                            dist_bucket,
+                            &predicted_ref,
+                            target_ref,
+                        )
+                        .map_err(token_error(block_index, i))?;
                     } else {
-                        codec.encode_correction(CodecCorrection::DistOnlyCorrection, 0);
+                        codec.encode_bucket_correction(
+                            CodecCorrection::DistOnlyCorrection,
+                            dist_bucket,
+                            0,
+                        );
                     }
 
-                    if target_ref.len() == 258 {
-                        codec.encode_misprediction(
-                            CodecMisprediction::IrregularLen258,
-                            target_ref.get_irregular258(),
+                    if IrregularEncoding::has_alternatives(
+                        target_ref.len(),
+                        self.params.deflate64_short_lengths,
+                    ) {
+                        codec.encode_correction(
+                            CodecCorrection::IrregularEncoding,
+                            target_ref.irregular().to_correction(),
                         );
                     }
                 }
@@ -219,56 +463,82 @@ impl<'a, H: RotatingHashTrait> TokenPredictor<'a, H> {
         Ok(())
     }
 
+    /// recreates the block from the corrections and tells the observer how many of them
+    /// were needed
     pub fn recreate_block<D: PredictionDecoder>(
         &mut self,
+        block_index: usize,
         codec: &mut D,
-    ) -> anyhow::Result<PreflateTokenBlock> {
-        let mut block;
-        self.current_token_count = 0;
-        self.pending_reference = None;
+        observer: &mut dyn BlockObserver,
+    ) -> Result<PreflateTokenBlock, PreflateError> {
+        let start = BlockStart::decode(block_index, codec, &self.params)?;
+        self.recreate_block_from(start, block_index, codec, observer)
+    }
 
-        const BT_STORED: u32 = BlockType::Stored as u32;
-        const BT_DYNAMICHUFF: u32 = BlockType::DynamicHuff as u32;
-        const BT_STATICHUFF: u32 = BlockType::StaticHuff as u32;
+    /// recreates the rest of the block whose start was decoded by BlockStart::decode
+    pub fn recreate_block_from<D: PredictionDecoder>(
+        &mut self,
+        start: BlockStart,
+        block_index: usize,
+        codec: &mut D,
+        observer: &mut dyn BlockObserver,
+    ) -> Result<PreflateTokenBlock, PreflateError> {
+        let start_pos = self.current_input_pos();
+        let mut counting = CountingCodec::new(codec);
+        let block = self.recreate_block_tokens(start, block_index, &mut counting)?;
+
+        observer.block_recreated(&BlockSummary {
+            index: block_index,
+            block_type: block.block_type.into(),
+            token_count: block.tokens.len(),
+            uncompressed_len: self.current_input_pos() - start_pos,
+            mispredictions: counting.mispredictions,
+            corrections: start.corrections + counting.corrections,
+        });
+        Ok(block)
+    }
 
-        codec.decode_verify_state("blocktypestart", 0);
+    fn recreate_block_tokens<D: PredictionDecoder>(
+        &mut self,
+        start: BlockStart,
+        block_index: usize,
+        codec: &mut D,
+    ) -> Result<PreflateTokenBlock, PreflateError> {
+        let mut block = PreflateTokenBlock::new(start.block_type);
+        self.current_token_count = 0;
+        self.match_predictor.reset();
 
-        let bt = decode_difference(
-            BT_DYNAMICHUFF,
-            codec.decode_correction(CodecCorrection::BlockTypeCorrection),
-        );
-        match bt {
-            BT_STORED => {
-                block = PreflateTokenBlock::new(BlockType::Stored);
-                block.uncompressed_len = codec.decode_value(16).into();
+        match start.block_type {
+            BlockType::Stored => {
+                if codec.decode_misprediction(CodecMisprediction::StoredBlockNotEmpty) {
+                    block.uncompressed_len = codec.decode_value(16).into();
+                }
                 block.padding_bits = codec.decode_correction(CodecCorrection::NonZeroPadding) as u8;
+                block.nlen_mismatch =
+                    codec.decode_correction(CodecCorrection::StoredNLenCorrection) as u16;
 
                 self.state.update_hash(block.uncompressed_len);
+
+                if block.uncompressed_len == 0 {
+                    block.flush = if codec.decode_misprediction(CodecMisprediction::FullFlush) {
+                        FlushMarker::Full
+                    } else {
+                        FlushMarker::Sync
+                    };
+                }
+                self.state.apply_flush_marker(block.flush);
                 return Ok(block);
             }
-            BT_STATICHUFF => {
-                block = PreflateTokenBlock::new(BlockType::StaticHuff);
-            }
-            BT_DYNAMICHUFF => {
-                block = PreflateTokenBlock::new(BlockType::DynamicHuff);
-            }
-            _ => {
-                return Err(anyhow::Error::msg(format!("Invalid block type {}", bt)));
-            }
+            BlockType::StaticHuff | BlockType::DynamicHuff => {}
         }
 
-        let mut blocksize = codec.decode_correction(CodecCorrection::TokenCount);
-        if blocksize == 0 {
-            blocksize = self.max_token_count;
-        } else {
-            blocksize -= 1;
-        }
+        self.block_size = start.block_size;
 
-        block.tokens.reserve(blocksize as usize);
+        block.tokens.reserve(self.block_size as usize);
 
         codec.decode_verify_state("start", self.checksum().hash());
 
-        while !self.input_eof() && self.current_token_count < blocksize {
+        while !self.input_eof() && self.current_token_count < self.block_size {
             codec.decode_verify_state(
                 "token",
                 if VERIFY {
@@ -278,7 +548,10 @@ impl<'a, H: RotatingHashTrait> TokenPredictor<'a, H> {
                 },
             );
 
+            let mismatch = token_error(block_index, self.current_token_count as usize);
+
             let mut predicted_ref: PreflateTokenReference;
+            let repredicted;
             match self.predict_token() {
                 PreflateToken::Literal => {
                     let not_ok =
@@ -288,12 +561,16 @@ impl<'a, H: RotatingHashTrait> TokenPredictor<'a, H> {
                         continue;
                     }
 
-                    predicted_ref = self.repredict_reference(None).with_context(|| {
-                        format!(
-                            "repredict_reference token_count={:?}",
-                            self.current_token_count
-                        )
-                    })?;
+                    predicted_ref = self
+                        .repredict_reference(None)
+                        .with_context(|| {
+                            format!(
+                                "repredict_reference token_count={:?}",
+                                self.current_token_count
+                            )
+                        })
+                        .map_err(mismatch)?;
+                    repredicted = true;
                 }
                 PreflateToken::Reference(r) => {
                     let not_ok =
@@ -304,40 +581,65 @@ impl<'a, H: RotatingHashTrait> TokenPredictor<'a, H> {
                     }
 
                     predicted_ref = r;
+                    repredicted = false;
                 }
             }
 
-            let new_len = decode_difference(
-                predicted_ref.len(),
-                codec.decode_correction(CodecCorrection::LenCorrection),
+            let len_correction = codec.decode_bucket_correction(
+                CodecCorrection::LenCorrection,
+                self.len_correction_bucket(predicted_ref.len(), block.block_type, repredicted),
             );
+            let new_len =
+                decode_difference(predicted_ref.len(), len_correction).ok_or_else(|| {
+                    mismatch(TokenErrorKind::InvalidLengthCorrection(len_correction).into())
+                })?;
+            // the hash chain is searched for a match of the new length, so it has to be one
+            // that deflate64 can write
+            if !(MIN_MATCH..=DEFLATE64_MAX_MATCH).contains(&new_len) {
+                return Err(mismatch(TokenErrorKind::InvalidLength(new_len).into()));
+            }
+
+            let dist_bucket = dist_correction_bucket(new_len, predicted_ref.dist(), repredicted);
+
             if new_len != predicted_ref.len() {
-                let hops = codec.decode_correction(CodecCorrection::DistAfterLenCorrection);
+                let hops = codec
+                    .decode_bucket_correction(CodecCorrection::DistAfterLenCorrection, dist_bucket);
 
-                predicted_ref = PreflateTokenReference::new(
+                predicted_ref = PreflateTokenReference::try_new(
                     new_len,
-                    self.state
-                        .hop_match(new_len, hops)
-                        .with_context(|| format!("hop_match l={} {:?}", new_len, predicted_ref))?,
-                    false,
-                );
+                    self.decode_hops(codec, new_len, hops)
+                        .with_context(|| format!("hop_match l={} {:?}", new_len, predicted_ref))
+                        .map_err(mismatch)?,
+                    IrregularEncoding::Canonical,
+                )
+                .map_err(|kind| mismatch(kind.into()))?;
             } else {
-                let hops = codec.decode_correction(CodecCorrection::DistOnlyCorrection);
+                let hops = codec
+                    .decode_bucket_correction(CodecCorrection::DistOnlyCorrection, dist_bucket);
                 if hops != 0 {
                     let new_dist = self
-                        .state
-                        .hop_match(predicted_ref.len(), hops)
+                        .decode_hops(codec, predicted_ref.len(), hops)
                         .with_context(|| {
                             format!("recalculate_distance token {}", self.current_token_count)
-                        })?;
-                    predicted_ref = PreflateTokenReference::new(new_len, new_dist, false);
+                        })
+                        .map_err(mismatch)?;
+                    predicted_ref = PreflateTokenReference::try_new(
+                        new_len,
+                        new_dist,
+                        IrregularEncoding::Canonical,
+                    )
+                    .map_err(|kind| mismatch(kind.into()))?;
                 }
             }
 
-            if predicted_ref.len() == 258
-                && codec.decode_misprediction(CodecMisprediction::IrregularLen258)
-            {
-                predicted_ref.set_irregular258(true);
+            if IrregularEncoding::has_alternatives(
+                predicted_ref.len(),
+                self.params.deflate64_short_lengths,
+            ) {
+                let value = codec.decode_correction(CodecCorrection::IrregularEncoding);
+                predicted_ref.set_irregular(IrregularEncoding::from_correction(value).ok_or_else(
+                    || mismatch(TokenErrorKind::UnknownIrregularEncoding(value).into()),
+                )?);
             }
 
             self.commit_token(&PreflateToken::Reference(predicted_ref), Some(&mut block));
@@ -348,106 +650,101 @@ impl<'a, H: RotatingHashTrait> TokenPredictor<'a, H> {
         Ok(block)
     }
 
-    pub fn input_eof(&self) -> bool {
-        // Return a boolean indicating whether input has reached EOF
-        self.state.available_input_size() == 0
-    }
-
-    fn predict_token(&mut self) -> PreflateToken {
-        if self.state.current_input_pos() == 0 || self.state.available_input_size() < MIN_MATCH {
-            return PreflateToken::Literal;
-        }
-
-        let hash = self.state.calculate_hash();
-
-        let m = if let Some(pending) = self.pending_reference {
-            MatchResult::Success(pending)
+    /// encodes how many matches of the target length to skip on the hash chain to get to the
+    /// target, or its distance if it lies deeper than the part of the chain that is walked
+    fn encode_hops<D: PredictionEncoder>(
+        &self,
+        codec: &mut D,
+        correction: CodecCorrection,
+        dist_bucket: u8,
+        predicted_ref: &PreflateTokenReference,
+        target_ref: &PreflateTokenReference,
+    ) -> Result<(), TokenError> {
+        let rematch = self
+            .state
+            .calculate_hops(target_ref)
+            .with_context(|| format!("calculate_hops p={:?}, t={:?}", predicted_ref, target_ref))?;
+
+        if let Some(hops) = rematch {
+            codec.encode_bucket_correction(correction, dist_bucket, hops);
         } else {
-            self.state.match_token(
-                hash,
-                0,
-                0,
-                if self.params.zlib_compatible {
-                    0
-                } else {
-                    1 << self.params.log2_of_max_chain_depth_m1
-                },
-            )
-        };
+            codec.encode_bucket_correction(correction, dist_bucket, DEEP_MATCH_HOPS);
+            codec.encode_correction(CodecCorrection::DeepMatchDistance, target_ref.dist());
+        }
 
-        self.pending_reference = None;
+        Ok(())
+    }
 
-        if let MatchResult::Success(match_token) = m {
-            if match_token.len() < MIN_MATCH {
-                return PreflateToken::Literal;
-            }
+    /// the inverse of encode_hops, returns the distance of the match
+    fn decode_hops<D: PredictionDecoder>(
+        &self,
+        codec: &mut D,
+        len: u32,
+        hops: u32,
+    ) -> Result<u32, TokenError> {
+        if hops != DEEP_MATCH_HOPS {
+            return self.state.hop_match(len, hops);
+        }
 
-            if self.params.is_fast_compressor {
-                return PreflateToken::Reference(match_token);
-            }
+        let dist = codec.decode_correction(CodecCorrection::DeepMatchDistance);
+        if dist == 0
+            || dist > self.state.current_input_pos()
+            || dist > self.state.window_size()
+            || len > self.state.available_input_size()
+        {
+            return Err(TokenErrorKind::InvalidDistance(dist))
+                .with_context(|| "deep match distance");
+        }
 
-            // match is too small and far way to be worth encoding as a distance/length pair.
-            if match_token.len() == 3 && match_token.dist() > self.params.max_dist_3_matches.into()
-            {
-                return PreflateToken::Literal;
-            }
+        Ok(dist)
+    }
 
-            // Check for a longer match that starts at the next byte, in which case we should
-            // just emit a literal instead of a distance/length pair.
-            if match_token.len() < self.params.max_lazy
-                && self.state.available_input_size() >= match_token.len() + 2
-            {
-                let mut match_next;
-                let hash_next = self.state.calculate_hash_next();
-
-                match_next = self.state.match_token(
-                    hash_next,
-                    match_token.len(),
-                    1,
-                    if self.params.zlib_compatible {
-                        0
-                    } else {
-                        2 << self.params.log2_of_max_chain_depth_m1
-                    },
-                );
+    /// Advances the predictor past a block whose tokens are already known without
+    /// doing any prediction. The state afterwards is the same as if the block had been
+    /// predicted or recreated, but this only costs the hash chain updates.
+    pub fn skip_block(&mut self, block: &PreflateTokenBlock) {
+        self.current_token_count = 0;
+        self.match_predictor.reset();
 
-                if self.state.hash_equal(hash_next, hash) {
-                    let max_size = std::cmp::min(self.state.available_input_size() - 1, MAX_MATCH);
-                    let mut rle = 0;
-                    let c = self.state.input_cursor();
-                    let b = c[0];
-                    while rle < max_size && c[1 + rle as usize] == b {
-                        rle += 1;
-                    }
+        if block.block_type == BlockType::Stored {
+            self.state.update_hash(block.uncompressed_len);
+            self.state.apply_flush_marker(block.flush);
+            return;
+        }
 
-                    let match_next_len = if let MatchResult::Success(s) = match_next {
-                        s.len()
+        // nothing is matched against the hash chain while skipping, so
+        // the insertions can be batched up until the end of the block
+        for token in &block.tokens {
+            match token {
+                PreflateToken::Literal => self.state.update_hash_deferred(1),
+                PreflateToken::Reference(t) => {
+                    if self.params.is_fast_compressor && t.len() > self.params.max_lazy {
+                        self.state.skip_hash(t.len());
                     } else {
-                        0
-                    };
-
-                    if rle > match_token.len() && rle > match_next_len {
-                        match_next =
-                            MatchResult::Success(PreflateTokenReference::new(rle, 1, false));
+                        self.state.update_hash_deferred(t.len());
                     }
                 }
+            }
+            self.current_token_count += 1;
+        }
 
-                if let MatchResult::Success(m) = match_next {
-                    if m.len() > match_token.len() {
-                        self.pending_reference = Some(m);
+        self.state.flush_hash();
+    }
 
-                        if !self.params.zlib_compatible {
-                            self.pending_reference = None;
-                        }
-                        return PreflateToken::Literal;
-                    }
-                }
-            }
+    pub fn input_eof(&self) -> bool {
+        // Return a boolean indicating whether input has reached EOF
+        self.state.available_input_size() == 0
+    }
 
-            PreflateToken::Reference(match_token)
-        } else {
-            PreflateToken::Literal
+    fn predict_token(&mut self) -> PreflateToken {
+        if self.state.current_input_pos() == 0
+            || self.state.available_input_size() < std::cmp::max(MIN_MATCH, H::NUM_HASH_BYTES)
+        {
+            return PreflateToken::Literal;
         }
+
+        self.match_predictor
+            .predict_token(&self.state, &self.params)
     }
 
     /// When the predicted token was a literal, but the actual token was a reference, try again
@@ -455,11 +752,11 @@ impl<'a, H: RotatingHashTrait> TokenPredictor<'a, H> {
     fn repredict_reference(
         &mut self,
         dist_match: Option<PreflateTokenReference>,
-    ) -> anyhow::Result<PreflateTokenReference> {
-        if self.state.current_input_pos() == 0 || self.state.available_input_size() < MIN_MATCH {
-            return Err(anyhow::Error::msg(
-                "Not enough space left to find a reference",
-            ));
+    ) -> Result<PreflateTokenReference, TokenError> {
+        if self.state.current_input_pos() == 0
+            || self.state.available_input_size() < std::cmp::max(MIN_MATCH, H::NUM_HASH_BYTES)
+        {
+            return Err(TokenErrorKind::NotEnoughInput.into());
         }
 
         if let Some(x) = dist_match {
@@ -473,7 +770,7 @@ impl<'a, H: RotatingHashTrait> TokenPredictor<'a, H> {
             self.state
                 .match_token(hash, 0, 0, 2 << self.params.log2_of_max_chain_depth_m1);
 
-        self.pending_reference = None;
+        self.match_predictor.reset();
 
         if let MatchResult::Success(m) = match_token {
             if m.len() >= MIN_MATCH {
@@ -483,10 +780,7 @@ impl<'a, H: RotatingHashTrait> TokenPredictor<'a, H> {
 
         //self.state.verify_hash(dist_match);
 
-        Err(anyhow::Error::msg(format!(
-            "Didnt find a match {:?}",
-            match_token
-        )))
+        Err(TokenErrorKind::NoMatchFound).with_context(|| format!("match_token {:?}", match_token))
     }
 
     fn commit_token(&mut self, token: &PreflateToken, block: Option<&mut PreflateTokenBlock>) {
@@ -500,7 +794,7 @@ impl<'a, H: RotatingHashTrait> TokenPredictor<'a, H> {
             }
             PreflateToken::Reference(t) => {
                 if let Some(block) = block {
-                    block.add_reference(t.len(), t.dist(), t.get_irregular258());
+                    block.add_reference(t.len(), t.dist(), t.irregular());
                 }
 
                 // max_lazy is reused by the fast compressor to mean that if a match is larger than a
@@ -516,3 +810,62 @@ impl<'a, H: RotatingHashTrait> TokenPredictor<'a, H> {
         self.current_token_count += 1;
     }
 }
+
+#[test]
+fn correction_buckets_in_range() {
+    use crate::statistical_codec::MAX_CORRECTION_BUCKETS;
+
+    let mut len_buckets = std::collections::HashSet::new();
+    let mut dist_buckets = std::collections::HashSet::new();
+    for len in [3, 4, 6, 16, 258] {
+        for flag in [false, true] {
+            for block_type in [BlockType::StaticHuff, BlockType::DynamicHuff] {
+                for last_token in [false, true] {
+                    len_buckets.insert(len_correction_bucket(len, block_type, last_token, flag));
+                }
+            }
+            for dist in [1, 300, 32768] {
+                dist_buckets.insert(dist_correction_bucket(len, dist, flag));
+            }
+        }
+    }
+
+    // every combination gets its own bucket
+    assert_eq!(len_buckets.len(), 32);
+    assert_eq!(dist_buckets.len(), 24);
+    assert!(len_buckets
+        .iter()
+        .chain(dist_buckets.iter())
+        .all(|&b| usize::from(b) < MAX_CORRECTION_BUCKETS));
+}
+
+#[test]
+fn skip_block_matches_committed_tokens() {
+    use crate::rotating_hash::{ZlibRotatingHash, HASH_ALGORITHM_ZLIB};
+
+    let compressed = crate::process::read_file("compressed_zlib_level1.deflate");
+    let (plain_text, blocks, params) = crate::process::parse_deflate(&compressed).unwrap();
+    assert_eq!(params.hash_algorithm, HASH_ALGORITHM_ZLIB);
+
+    // the batched insertions of skip_block give the same hash chain as inserting the
+    // positions of each token right away
+    let mut skipped =
+        TokenPredictor::<ZlibRotatingHash>::new(&plain_text, &params, 0, Default::default());
+    let mut committed =
+        TokenPredictor::<ZlibRotatingHash>::new(&plain_text, &params, 0, Default::default());
+    for block in &blocks {
+        skipped.skip_block(block);
+
+        committed.current_token_count = 0;
+        if block.block_type == BlockType::Stored {
+            committed.state.update_hash(block.uncompressed_len);
+            committed.state.apply_flush_marker(block.flush);
+        } else {
+            for token in &block.tokens {
+                committed.commit_token(token, None);
+            }
+        }
+
+        assert_eq!(skipped.checksum().hash(), committed.checksum().hash());
+    }
+}
@@ -5,7 +5,7 @@
  *--------------------------------------------------------------------------------------------*/
 
 use crate::{
-    cabac_codec::{decode_difference, encode_difference},
+    cabac_codec::{checked_decode_difference, encode_difference},
     huffman_calc::{calc_bit_lengths, HufftreeBitCalc},
     huffman_encoding::{HuffmanOriginalEncoding, TreeCodeType},
     preflate_constants::{CODETREE_CODE_COUNT, NONLEN_CODE_COUNT, TREE_CODE_ORDER_TABLE},
@@ -15,6 +15,12 @@ use crate::{
     },
 };
 
+/// the longest bit length of the literal/length and distance trees
+const MAX_CODE_BITS: u32 = 15;
+
+/// the longest bit length of the tree of the code lengths, which are written with 3 bits
+const MAX_CODE_LENGTH_BITS: u32 = 7;
+
 pub fn predict_tree_for_block<D: PredictionEncoder>(
     huffman_encoding: &HuffmanOriginalEncoding,
     freq: &TokenFrequency,
@@ -150,10 +156,17 @@ pub fn recreate_tree_for_block<D: PredictionDecoder>(
     tc_code_tree.resize(CODETREE_CODE_COUNT, 0);
 
     for i in 0..tc_code_tree_len {
-        result.code_lengths[TREE_CODE_ORDER_TABLE[i]] = decode_difference(
+        let code_length = checked_decode_difference(
             tc_code_tree[TREE_CODE_ORDER_TABLE[i]].into(),
             codec.decode_correction(CodecCorrection::TreeCodeBitLengthCorrection),
-        ) as u8;
+        )?;
+        if code_length > MAX_CODE_LENGTH_BITS {
+            return Err(anyhow::anyhow!(
+                "code length tree bit length {} is too long",
+                code_length
+            ));
+        }
+        result.code_lengths[TREE_CODE_ORDER_TABLE[i]] = code_length as u8;
     }
 
     Ok(result)
@@ -163,9 +176,16 @@ pub fn recreate_tree_for_block<D: PredictionDecoder>(
 /// order to optimize the chance of removing trailing zeros, we need to calculate
 /// the effective encoding size of the length codes
 fn calc_tc_lengths_without_trailing_zeros(bit_lengths: &[u8]) -> usize {
-    let mut len = bit_lengths.len();
+    // the bit lengths leave out the unused symbols at the end, which aren't at the end
+    // of TREE_CODE_ORDER_TABLE (e.g. 15 comes last)
+    let mut len = CODETREE_CODE_COUNT;
     // remove trailing zeros
-    while len > 4 && bit_lengths[TREE_CODE_ORDER_TABLE[len - 1]] == 0 {
+    while len > 4
+        && matches!(
+            bit_lengths.get(TREE_CODE_ORDER_TABLE[len - 1]),
+            None | Some(0)
+        )
+    {
         len -= 1;
     }
 
@@ -252,10 +272,10 @@ fn reconstruct_ld_trees<D: PredictionDecoder>(
         let predicted_tree_code_type = predict_code_type(symbols, prev_code);
         prev_code = Some(symbols[0]);
 
-        let predicted_tree_code_type_u32 = decode_difference(
+        let predicted_tree_code_type_u32 = checked_decode_difference(
             predicted_tree_code_type as u32,
             decoder.decode_correction(CodecCorrection::LDTypeCorrection),
-        );
+        )?;
 
         const TC_CODE: u32 = TreeCodeType::Code as u32;
         const TC_REPEAT: u32 = TreeCodeType::Repeat as u32;
@@ -270,26 +290,46 @@ fn reconstruct_ld_trees<D: PredictionDecoder>(
             _ => return Err(anyhow::anyhow!("Reconstruction failed")),
         };
 
-        let mut predicted_tree_code_data = predict_code_data(symbols, predicted_tree_code_type);
+        let predicted_tree_code_data = predict_code_data(symbols, predicted_tree_code_type);
 
-        if predicted_tree_code_type != TreeCodeType::Code {
-            predicted_tree_code_data = decode_difference(
+        // the corrections may not belong to the plain text, so the code length or repeat count
+        // has to be one that deflate can write
+        let predicted_tree_code_data = if predicted_tree_code_type != TreeCodeType::Code {
+            let count = checked_decode_difference(
                 predicted_tree_code_data.into(),
                 decoder.decode_correction(CodecCorrection::RepeatCountCorrection),
-            ) as u8;
+            )?;
+            let (sub, bits) =
+                HuffmanOriginalEncoding::get_tree_code_adjustment(predicted_tree_code_type);
+            let min_count = u32::from(sub);
+            if !(min_count..min_count + (1 << bits)).contains(&count) {
+                return Err(anyhow::anyhow!(
+                    "repeat count {} of {:?} is out of range",
+                    count,
+                    predicted_tree_code_type
+                ));
+            }
+            count as u8
         } else {
-            predicted_tree_code_data = decode_difference(
+            let bit_length = checked_decode_difference(
                 predicted_tree_code_data.into(),
                 decoder.decode_correction(CodecCorrection::LDBitLengthCorrection),
-            ) as u8;
-        }
+            )?;
+            if bit_length > MAX_CODE_BITS {
+                return Err(anyhow::anyhow!("bit length {} is too long", bit_length));
+            }
+            bit_length as u8
+        };
 
         result.push((predicted_tree_code_type, predicted_tree_code_data));
 
         if predicted_tree_code_type == TreeCodeType::Code {
             symbols = &symbols[1..];
         } else {
-            symbols = &symbols[predicted_tree_code_data as usize..];
+            // only happens if the corrections don't belong to the plain text
+            symbols = symbols
+                .get(predicted_tree_code_data as usize..)
+                .ok_or_else(|| anyhow::anyhow!("repeat runs past the end of the tree"))?;
         }
     }
 
@@ -390,7 +430,13 @@ fn encode_roundtrip_perfect() {
     use crate::statistical_codec::DefaultOnlyDecoder;
     use crate::statistical_codec::VerifyPredictionEncoder;
 
-    for huffcalc in [HufftreeBitCalc::Miniz, HufftreeBitCalc::Zlib] {
+    for huffcalc in [
+        HufftreeBitCalc::Miniz,
+        HufftreeBitCalc::Zlib,
+        HufftreeBitCalc::SevenZip,
+        HufftreeBitCalc::Zopfli,
+        HufftreeBitCalc::Libdeflate,
+    ] {
         let mut freq = TokenFrequency::default();
         freq.literal_codes[0] = 100;
         freq.literal_codes[1] = 50;
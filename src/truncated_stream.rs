@@ -0,0 +1,148 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Deflate streams that end before their final block, for example because a download was
+//! interrupted or a stream was carved from a damaged disk. Everything that could be decoded is
+//! returned along with the state needed to continue once more of the stream is available, and
+//! the block that wasn't complete yet continues where its data ended when resuming.
+
+use std::io::Cursor;
+
+use crate::{
+    decompress_deflate_stream_with_config,
+    deflate_reader::{DeflateReader, PartialBlock},
+    preflate_config::PreflateConfig,
+    preflate_error::{is_end_of_data, PreflateError},
+    DecompressResult,
+};
+
+/// the result of decompress_resumable and TruncatedStream::resume
+pub enum StreamProgress {
+    /// the final block has been read, this is the same result as from
+    /// decompress_deflate_stream_with_config
+    Complete(Box<DecompressResult>),
+    /// the data ended before the final block
+    Truncated(TruncatedStream),
+}
+
+/// what has been read of a stream whose data ended before the final block
+pub struct TruncatedStream {
+    /// all the compressed data received so far, which is needed for the prediction once the
+    /// stream is complete
+    compressed: Vec<u8>,
+    /// bit position in compressed where the reading continues, which is the start of the first
+    /// block that wasn't complete or the part of it that is still to come
+    resume_bit_position: u64,
+    /// what was read of the block that wasn't complete, if the data ended after its header
+    partial: Option<Box<PartialBlock>>,
+    /// number of blocks that were complete
+    complete_blocks: usize,
+    /// the plain text of the complete blocks followed by what could be decoded of the next one
+    plain_text: Vec<u8>,
+    /// length of the plain text of the complete blocks
+    complete_plain_text_len: usize,
+}
+
+impl TruncatedStream {
+    /// The plain text decoded so far, including the part of the incomplete block that could be
+    /// decoded. Only complete_plain_text is certain to be the start of the final plain text if
+    /// the data was damaged rather than cut off.
+    pub fn plain_text(&self) -> &[u8] {
+        &self.plain_text
+    }
+
+    /// the plain text of the blocks that were complete
+    pub fn complete_plain_text(&self) -> &[u8] {
+        &self.plain_text[..self.complete_plain_text_len]
+    }
+
+    /// number of blocks that were complete
+    pub fn complete_blocks(&self) -> usize {
+        self.complete_blocks
+    }
+
+    /// number of bytes of the stream that have been received so far
+    pub fn compressed_len(&self) -> usize {
+        self.compressed.len()
+    }
+
+    /// Continues with the next bytes of the stream, which follow the ones received so far.
+    /// Once the final block has been read, the whole stream is decompressed with the config.
+    pub fn resume(
+        mut self,
+        more_data: &[u8],
+        config: &PreflateConfig,
+    ) -> Result<StreamProgress, PreflateError> {
+        self.compressed.extend_from_slice(more_data);
+        self.read_blocks(config)
+    }
+
+    fn read_blocks(mut self, config: &PreflateConfig) -> Result<StreamProgress, PreflateError> {
+        let start_byte = (self.resume_bit_position / 8) as usize;
+
+        let mut reader = DeflateReader::with_plain_text(
+            Cursor::new(&self.compressed[start_byte..]),
+            std::mem::take(&mut self.plain_text),
+        );
+        reader.set_lenient_stored_len(config.lenient_stored_len);
+        reader.set_deflate64(config.deflate64);
+        reader.set_max_plain_text_size(config.max_plaintext_size);
+
+        let mut result = reader.skip_bits((self.resume_bit_position % 8) as u32);
+        if let Some(partial) = self.partial.take() {
+            reader.resume_block(*partial);
+        }
+        let mut last = false;
+        while result.is_ok() {
+            result = reader.read_block(&mut last).map(|_| ());
+            if result.is_ok() {
+                self.complete_blocks += 1;
+                self.complete_plain_text_len = reader.plain_text().len();
+                self.resume_bit_position = start_byte as u64 * 8 + reader.bit_position();
+
+                if last {
+                    return Ok(StreamProgress::Complete(Box::new(
+                        decompress_deflate_stream_with_config(&self.compressed, config)?,
+                    )));
+                }
+            }
+        }
+
+        match result {
+            Err(e) if is_end_of_data(&e) => {
+                // the plain text of the block that wasn't complete is the one of the tokens
+                // that the partial block has read
+                self.partial = reader.take_partial_block().map(Box::new);
+                if let Some(partial) = &self.partial {
+                    self.resume_bit_position = start_byte as u64 * 8 + partial.bit_position();
+                }
+                self.plain_text = reader.move_plain_text();
+                Ok(StreamProgress::Truncated(self))
+            }
+            Err(e) => Err(PreflateError::read_block(self.complete_blocks, e)),
+            Ok(()) => unreachable!(),
+        }
+    }
+}
+
+/// Decompresses a stream that may be cut off before its final block. A complete stream gives
+/// the same result as decompress_deflate_stream_with_config, otherwise the state that is
+/// returned can be resumed when more data arrives. Data that isn't a valid deflate stream is
+/// still an error.
+pub fn decompress_resumable(
+    compressed_data: &[u8],
+    config: &PreflateConfig,
+) -> Result<StreamProgress, PreflateError> {
+    TruncatedStream {
+        compressed: compressed_data.to_vec(),
+        resume_bit_position: 0,
+        partial: None,
+        complete_blocks: 0,
+        plain_text: Vec::new(),
+        complete_plain_text_len: 0,
+    }
+    .read_blocks(config)
+}
@@ -0,0 +1,276 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Fallback for streams that the predictor can't handle to the end. The blocks before the one
+//! that failed are predicted as a shortened stream, which is the same stream cut off after them
+//! with the BFINAL bit of the last one set, and the rest of the compressed stream is stored as
+//! it is. If the very first block fails, the whole stream is stored.
+//!
+//! The corrections are framed like the cabac corrections and start with a header byte that only
+//! has VERBATIM_TAIL set and the original stream info. They are followed by little endian u64s
+//! with the length of the plain text of the predicted blocks, the bit position of the first
+//! stored block, the bit position of the last predicted block and the length of the stored
+//! bytes, then the stored bytes (starting at the byte that contains the first stored bit) and
+//! the corrections of the shortened stream, which are empty if no block was predicted.
+
+use std::io::Cursor;
+
+use crate::{
+    cabac_codec::{
+        frame_corrections, framed_header_byte, unframe_corrections, OriginalStream, VERBATIM_TAIL,
+    },
+    compressor_profile::CompressorProfile,
+    deflate_reader::DeflateReader,
+    match_predictor::MatchPredictor,
+    predict_and_verify,
+    preflate_config::{PreflateConfig, VerifyMode},
+    preflate_error::PreflateError,
+    preflate_parameter_estimator::{estimate_preflate_parameters, PreflateParameters},
+    recompress_with_predictor, DecompressResult,
+};
+
+/// the start of a block as the bit position in the compressed stream and the length of the
+/// plain text before it
+#[derive(Copy, Clone)]
+struct BlockStart {
+    bit_position: u64,
+    plain_text_len: usize,
+}
+
+/// the plain text, the blocks along with where each of them starts and the number of bytes of
+/// the compressed data that belong to the stream
+type ReadStream = (
+    Vec<u8>,
+    Vec<crate::preflate_token::PreflateTokenBlock>,
+    Vec<BlockStart>,
+    usize,
+);
+
+fn read_stream(
+    compressed_data: &[u8],
+    config: &PreflateConfig,
+) -> Result<ReadStream, PreflateError> {
+    let mut input_stream = Cursor::new(compressed_data);
+    let mut reader = DeflateReader::with_compressed_size(
+        &mut input_stream,
+        config.compressed_size.unwrap_or(u64::MAX),
+    );
+    reader.set_lenient_stored_len(config.lenient_stored_len);
+    reader.set_deflate64(config.deflate64);
+
+    let mut blocks = Vec::new();
+    let mut starts = Vec::new();
+    let mut last = false;
+    while !last {
+        starts.push(BlockStart {
+            bit_position: reader.bit_position(),
+            plain_text_len: reader.plain_text().len(),
+        });
+        blocks.push(
+            reader
+                .read_block(&mut last)
+                .map_err(|e| PreflateError::read_block(blocks.len(), e))?,
+        );
+    }
+
+    reader.read_eof_padding();
+    let plain_text = reader.move_plain_text();
+
+    Ok((plain_text, blocks, starts, input_stream.position() as usize))
+}
+
+/// the stream cut off before the first stored block, with the BFINAL bit set on the block
+/// before it and the unused bits of the last byte set to zero
+fn shortened_stream(compressed_data: &[u8], last_block: BlockStart, end: BlockStart) -> Vec<u8> {
+    let mut stream = compressed_data[..((end.bit_position + 7) / 8) as usize].to_vec();
+    if end.bit_position % 8 != 0 {
+        *stream.last_mut().unwrap() &= (1 << (end.bit_position % 8)) - 1;
+    }
+    stream[(last_block.bit_position / 8) as usize] |= 1 << (last_block.bit_position % 8);
+    stream
+}
+
+/// Decompresses a stream whose prediction failed at failed_block by predicting the blocks
+/// before it and storing the rest. Predicting fewer blocks changes the end of the plain text,
+/// so if that fails at an earlier block, the stream is split there instead.
+pub(crate) fn decompress_with_verbatim_tail<M: MatchPredictor + Clone>(
+    compressed_data: &[u8],
+    config: &PreflateConfig,
+    match_predictor: &M,
+    failed_block: usize,
+) -> Result<(DecompressResult, PreflateParameters), PreflateError> {
+    let (plain_text, blocks, starts, compressed_processed) = read_stream(compressed_data, config)?;
+
+    let mut predicted_blocks = failed_block.min(blocks.len() - 1);
+    let (shortened, params) = loop {
+        if predicted_blocks == 0 {
+            let params = estimate_preflate_parameters(
+                &plain_text,
+                &blocks,
+                &config.parser_configs,
+                &config.compressor_profiles,
+            );
+            break (None, params);
+        }
+
+        let stream = shortened_stream(
+            compressed_data,
+            starts[predicted_blocks - 1],
+            starts[predicted_blocks],
+        );
+        match predict_and_verify(&stream, config, match_predictor, &mut |_| {}, &mut ()) {
+            Ok((r, params)) => break (Some(r), params),
+            Err(e) => match e.failed_block() {
+                Some(block) if block < predicted_blocks => predicted_blocks = block,
+                _ => return Err(e),
+            },
+        }
+    };
+
+    let end = starts[predicted_blocks];
+    let last_block_bit = match predicted_blocks {
+        0 => 0,
+        n => starts[n - 1].bit_position,
+    };
+    let tail = &compressed_data[(end.bit_position / 8) as usize..compressed_processed];
+
+    let mut corrections = vec![VERBATIM_TAIL];
+    corrections.extend_from_slice(
+        &OriginalStream::of(&compressed_data[..compressed_processed]).to_bytes(),
+    );
+    for value in [
+        end.plain_text_len as u64,
+        end.bit_position,
+        last_block_bit,
+        tail.len() as u64,
+    ] {
+        corrections.extend_from_slice(&value.to_le_bytes());
+    }
+    corrections.extend_from_slice(tail);
+    if let Some(shortened) = &shortened {
+        corrections.extend_from_slice(&shortened.cabac_encoded);
+    }
+    let cabac_encoded = frame_corrections(&corrections);
+
+    if config.verify == VerifyMode::Full {
+        let recompressed =
+            recompress_with_verbatim_tail(&plain_text, &cabac_encoded, config, match_predictor)?;
+        if recompressed[..] != compressed_data[..compressed_processed] {
+            return Err(PreflateError::Mismatch(anyhow::anyhow!(
+                "recompressed data does not match original"
+            )));
+        }
+    }
+
+    let (statistics, profile) = match shortened {
+        Some(r) => (r.statistics, r.profile),
+        None => (
+            Default::default(),
+            CompressorProfile::from_parameters(&params),
+        ),
+    };
+
+    Ok((
+        DecompressResult {
+            plain_text_crc32: crc32fast::hash(&plain_text),
+            plain_text,
+            cabac_encoded,
+            compressed_processed,
+            statistics,
+            profile,
+            parameters: params,
+        },
+        params,
+    ))
+}
+
+/// whether the framed corrections were written by decompress_with_verbatim_tail, which is
+/// recorded in the header byte in the first segment
+pub(crate) fn has_verbatim_tail(corrections: &[u8]) -> bool {
+    framed_header_byte(corrections).is_some_and(|h| h & VERBATIM_TAIL != 0)
+}
+
+fn invalid_tail() -> PreflateError {
+    PreflateError::RecompressFailed(anyhow::anyhow!("invalid verbatim tail in the corrections"))
+}
+
+/// takes the next len bytes from the corrections
+fn take<'a>(corrections: &mut &'a [u8], len: usize) -> Result<&'a [u8], PreflateError> {
+    let value = corrections.get(..len).ok_or_else(invalid_tail)?;
+    *corrections = &corrections[len..];
+    Ok(value)
+}
+
+fn take_u64(corrections: &mut &[u8]) -> Result<u64, PreflateError> {
+    Ok(u64::from_le_bytes(
+        take(corrections, 8)?.try_into().unwrap(),
+    ))
+}
+
+/// recreates a stream that was decompressed with decompress_with_verbatim_tail
+pub(crate) fn recompress_with_verbatim_tail<M: MatchPredictor + Clone>(
+    plain_text: &[u8],
+    corrections: &[u8],
+    config: &PreflateConfig,
+    match_predictor: &M,
+) -> Result<Vec<u8>, PreflateError> {
+    let unframed = unframe_corrections(corrections)?;
+    let mut rest = unframed.get(1..).ok_or_else(invalid_tail)?;
+    let original = OriginalStream::from_bytes(take(&mut rest, OriginalStream::SIZE)?)
+        .ok_or_else(invalid_tail)?;
+
+    let plain_text_len = take_u64(&mut rest)? as usize;
+    let bit_offset = take_u64(&mut rest)?;
+    let last_block_bit = take_u64(&mut rest)?;
+    let tail_len = take_u64(&mut rest)? as usize;
+    let tail = take(&mut rest, tail_len)?;
+
+    let mut output = Vec::new();
+    if !rest.is_empty() {
+        let predicted = plain_text.get(..plain_text_len).ok_or_else(invalid_tail)?;
+        output = recompress_with_predictor(predicted, rest, config, match_predictor, &mut ())?;
+
+        let kept = (bit_offset / 8) as usize;
+        if last_block_bit >= bit_offset || output.len() < kept {
+            return Err(invalid_tail());
+        }
+        output[(last_block_bit / 8) as usize] &= !(1 << (last_block_bit % 8));
+        output.truncate(kept);
+    }
+    output.extend_from_slice(tail);
+
+    original.verify(&output)?;
+    Ok(output)
+}
+
+#[test]
+fn verbatim_tail_roundtrip() {
+    use crate::{match_predictor::ZlibMatchPredictor, recompress_deflate_stream};
+
+    let compressed_data = crate::process::read_file("compressed_zlib_level1.deflate");
+    let (_, blocks, starts, _) = read_stream(&compressed_data, &PreflateConfig::default()).unwrap();
+    assert!(blocks.len() > 2);
+    assert!(starts[1].bit_position % 8 != 0);
+
+    // the whole stream stored, a split within a byte and only the final block stored
+    let config = PreflateConfig::default();
+    let predictor = ZlibMatchPredictor::default();
+    for failed_block in [0, 1, blocks.len() - 1] {
+        let (r, _) =
+            decompress_with_verbatim_tail(&compressed_data, &config, &predictor, failed_block)
+                .unwrap();
+        assert!(has_verbatim_tail(&r.cabac_encoded));
+        assert_eq!(r.compressed_processed, compressed_data.len());
+        assert_eq!(
+            recompress_deflate_stream(&r.plain_text, &r.cabac_encoded).unwrap(),
+            compressed_data
+        );
+    }
+
+    // normal corrections aren't mistaken for ones with a verbatim tail
+    let r = crate::decompress_deflate_stream(&compressed_data, false).unwrap();
+    assert!(!has_verbatim_tail(&r.cabac_encoded));
+}
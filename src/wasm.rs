@@ -0,0 +1,118 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Bindings for using the library from JavaScript, when it is built for wasm32-unknown-unknown
+//! with the `wasm` feature. The byte arrays are passed in and out as `Uint8Array`, and the
+//! errors are thrown as a `PreflateWasmError` with the ErrorCode of the error:
+//!
+//! ```js
+//! import init, { decompress, recompress } from "./pkg/preflate_rs.js";
+//!
+//! await init();
+//! try {
+//!     const result = decompress(compressed, true);
+//!     const restored = recompress(result.plainText, result.corrections);
+//! } catch (e) {
+//!     console.log(e.code, e.message);
+//! }
+//! ```
+//!
+//! The module is built as a cdylib and the JavaScript glue is generated from it by wasm-bindgen:
+//!
+//! ```sh
+//! cargo rustc --lib --release --target wasm32-unknown-unknown --features wasm --crate-type cdylib
+//! wasm-bindgen --target web --out-dir pkg target/wasm32-unknown-unknown/release/preflate_rs.wasm
+//! ```
+
+use wasm_bindgen::prelude::*;
+
+use crate::{decompress_deflate_stream, preflate_error::PreflateError, recompress_deflate_stream};
+
+/// the plain text and corrections that decompress returns
+#[wasm_bindgen]
+#[derive(Debug)]
+pub struct DecompressResult {
+    plain_text: Vec<u8>,
+    corrections: Vec<u8>,
+}
+
+#[wasm_bindgen]
+impl DecompressResult {
+    /// the uncompressed content of the deflate stream
+    #[wasm_bindgen(getter, js_name = plainText)]
+    pub fn plain_text(&self) -> Vec<u8> {
+        self.plain_text.clone()
+    }
+
+    /// what recompress needs besides the plain text to recreate the deflate stream
+    #[wasm_bindgen(getter)]
+    pub fn corrections(&self) -> Vec<u8> {
+        self.corrections.clone()
+    }
+}
+
+/// the error that decompress and recompress throw
+#[wasm_bindgen]
+#[derive(Debug)]
+pub struct PreflateWasmError {
+    code: u32,
+    message: String,
+}
+
+#[wasm_bindgen]
+impl PreflateWasmError {
+    /// the ErrorCode of the error
+    #[wasm_bindgen(getter)]
+    pub fn code(&self) -> u32 {
+        self.code
+    }
+
+    /// description of the error
+    #[wasm_bindgen(getter)]
+    pub fn message(&self) -> String {
+        self.message.clone()
+    }
+}
+
+impl From<PreflateError> for PreflateWasmError {
+    fn from(e: PreflateError) -> Self {
+        PreflateWasmError {
+            code: e.to_code(),
+            message: e.to_string(),
+        }
+    }
+}
+
+/// Decompresses a raw deflate stream, verifying that it can be recompressed if verify is set.
+#[wasm_bindgen]
+pub fn decompress(compressed: &[u8], verify: bool) -> Result<DecompressResult, PreflateWasmError> {
+    let r = decompress_deflate_stream(compressed, verify)?;
+    Ok(DecompressResult {
+        plain_text: r.plain_text,
+        corrections: r.cabac_encoded,
+    })
+}
+
+/// Recompresses the deflate stream from the plain text and corrections that decompress returned.
+#[wasm_bindgen]
+pub fn recompress(plain_text: &[u8], corrections: &[u8]) -> Result<Vec<u8>, PreflateWasmError> {
+    Ok(recompress_deflate_stream(plain_text, corrections)?)
+}
+
+#[test]
+fn wasm_bindings_roundtrip() {
+    let compressed = crate::process::read_file("compressed_zlib_level6.deflate");
+
+    let result = decompress(&compressed, true).unwrap();
+    assert_eq!(
+        recompress(&result.plain_text(), &result.corrections()).unwrap(),
+        compressed
+    );
+
+    let e = decompress(&[], true).unwrap_err();
+    assert_ne!(e.code(), 0);
+    assert!(!e.message().is_empty());
+}
@@ -0,0 +1,204 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Expansion of a whole zip archive. The deflated entries are found through the central
+//! directory rather than by scanning for streams. The local headers, extra fields, data
+//! descriptors, the central directory and the comments are kept as they are, so the archive
+//! is restored byte for byte. Entries that are compressed with deflate64 (method 9) are
+//! expanded as well. Zip64 archives aren't supported yet.
+
+use crate::{
+    container::{expand_file_with_kinds, recreate_file, ExpandedFile, StreamExtent},
+    nested_streams::EmbeddedStreamKind,
+    preflate_config::PreflateConfig,
+    preflate_error::PreflateError,
+    range_reader::{zip_entries, zip_entry_data_offset},
+};
+
+/// the extents of the deflated and deflate64 entries, along with the kind of the latter
+fn zip_stream_extents(
+    data: &[u8],
+) -> Result<Vec<(StreamExtent, Option<EmbeddedStreamKind>)>, PreflateError> {
+    let mut extents = Vec::new();
+    for entry in zip_entries(data)? {
+        let kind = if entry.is_deflated() {
+            None
+        } else if entry.is_deflate64() {
+            Some(EmbeddedStreamKind::Deflate64)
+        } else {
+            continue;
+        };
+
+        if entry.compressed_size > 0 {
+            let extent = StreamExtent {
+                offset: zip_entry_data_offset(data, &entry)? as usize,
+                length: entry.compressed_size as usize,
+            };
+            extents.push((extent, kind));
+        }
+    }
+    Ok(extents)
+}
+
+/// returns the extents of the compressed data of all the deflated entries of the archive
+pub fn zip_deflate_extents(data: &[u8]) -> Result<Vec<StreamExtent>, PreflateError> {
+    Ok(zip_stream_extents(data)?
+        .into_iter()
+        .filter(|(_, kind)| kind.is_none())
+        .map(|(extent, _)| extent)
+        .collect())
+}
+
+/// Expands each deflated and deflate64 entry of the zip archive. Entries that can't be processed
+/// are kept as they are, this only fails if the structure of the archive can't be read.
+pub fn expand_zip_archive(
+    data: &[u8],
+    config: &PreflateConfig,
+) -> Result<ExpandedFile, PreflateError> {
+    expand_zip_archive_with_threads(data, config, 1)
+}
+
+/// Like expand_zip_archive, but the entries are expanded by thread_count worker threads
+/// (0 uses all the available cores). The entries are independent of each other, and the
+/// result is the same as with a single thread.
+pub fn expand_zip_archive_with_threads(
+    data: &[u8],
+    config: &PreflateConfig,
+    thread_count: usize,
+) -> Result<ExpandedFile, PreflateError> {
+    Ok(expand_file_with_kinds(
+        data,
+        zip_stream_extents(data)?,
+        config,
+        thread_count,
+    ))
+}
+
+/// restores the zip archive from the result of expand_zip_archive
+pub fn restore_zip_archive(
+    plain_text: &[u8],
+    corrections: &[u8],
+    config: &PreflateConfig,
+) -> Result<Vec<u8>, PreflateError> {
+    recreate_file(plain_text, corrections, config)
+}
+
+#[test]
+fn zip_archive_roundtrip() {
+    let first = crate::process::read_file("sample1.bin");
+    let second = b"hello hello hello hello hello hello";
+    let zip = crate::range_reader::build_zip(&[("first.bin", &first[..]), ("second.txt", second)]);
+
+    let extents = zip_deflate_extents(&zip).unwrap();
+    assert_eq!(extents.len(), 2);
+
+    let config = PreflateConfig::default();
+    let expanded = expand_zip_archive(&zip, &config).unwrap();
+    assert_eq!(expanded.summary.entries_processed, 2);
+
+    // the plain text of the entries is followed by the rest of the archive as it is
+    let second_end =
+        expanded.plain_text.len() - (zip.len() - extents[1].offset - extents[1].length);
+    assert_eq!(
+        &expanded.plain_text[second_end - second.len()..second_end],
+        second
+    );
+    assert!(expanded.plain_text.ends_with(b"abc"));
+
+    assert_eq!(
+        restore_zip_archive(&expanded.plain_text, &expanded.corrections, &config).unwrap(),
+        zip
+    );
+
+    assert!(expand_zip_archive(&first, &config).is_err());
+}
+
+#[test]
+fn zip_archive_overrun() {
+    use crate::range_reader::{build_zip, declare_first_compressed_size};
+
+    let first = crate::process::read_file("sample1.bin");
+    let second = b"hello hello hello hello hello hello";
+    let mut zip = build_zip(&[("first.bin", &first[..]), ("second.txt", second)]);
+    let extents = zip_deflate_extents(&zip).unwrap();
+    declare_first_compressed_size(&mut zip, extents[0].length as u32 - 10);
+
+    // the first entry doesn't end within its declared size, so it is kept as it is, along with
+    // the rest of its stream that is outside of the entry
+    let config = PreflateConfig::default();
+    let expanded = expand_zip_archive(&zip, &config).unwrap();
+    assert_eq!(expanded.summary.entries_processed, 1);
+    assert_eq!(expanded.summary.entries_skipped, 1);
+    let first_end = extents[0].offset + extents[0].length;
+    assert!(expanded.plain_text.starts_with(&zip[..first_end]));
+
+    assert_eq!(
+        restore_zip_archive(&expanded.plain_text, &expanded.corrections, &config).unwrap(),
+        zip
+    );
+}
+
+#[test]
+fn zip_archive_deflate64() {
+    use crate::range_reader::{build_zip_with_methods, ZipEntry};
+
+    let (compressed, plain_text) = crate::deflate_writer::deflate64_test_stream();
+    let second = b"hello hello hello hello hello hello";
+    let zip = build_zip_with_methods(&[
+        (
+            "large.bin",
+            ZipEntry::METHOD_DEFLATE64,
+            compressed,
+            &plain_text[..],
+        ),
+        ("stored.txt", 0, second.to_vec(), second),
+    ]);
+
+    // only regular deflate entries are deflate extents
+    assert!(zip_deflate_extents(&zip).unwrap().is_empty());
+
+    let config = PreflateConfig::default();
+    let expanded = expand_zip_archive(&zip, &config).unwrap();
+    assert_eq!(expanded.summary.entries_processed, 1);
+    assert!(expanded
+        .plain_text
+        .windows(plain_text.len())
+        .any(|w| w == &plain_text[..]));
+
+    assert_eq!(
+        restore_zip_archive(&expanded.plain_text, &expanded.corrections, &config).unwrap(),
+        zip
+    );
+}
+
+#[test]
+fn zip_archive_threads() {
+    let sample = crate::process::read_file("sample1.bin");
+    let names: Vec<String> = (0..7).map(|i| format!("entry{}.bin", i)).collect();
+    let entries: Vec<(&str, &[u8])> = names
+        .iter()
+        .enumerate()
+        .map(|(i, name)| (name.as_str(), &sample[i * 1000..]))
+        .collect();
+    let zip = crate::range_reader::build_zip(&entries);
+
+    let config = PreflateConfig::default();
+    let single = expand_zip_archive(&zip, &config).unwrap();
+    assert_eq!(single.summary.entries_processed, 7);
+
+    // the entries come back in any order, but the result doesn't depend on it
+    for thread_count in [0, 2, 3, 16] {
+        let expanded = expand_zip_archive_with_threads(&zip, &config, thread_count).unwrap();
+        assert_eq!(expanded.summary.entries_processed, 7);
+        assert!(expanded.plain_text == single.plain_text);
+        assert!(expanded.corrections == single.corrections);
+    }
+
+    assert_eq!(
+        restore_zip_archive(&single.plain_text, &single.corrections, &config).unwrap(),
+        zip
+    );
+}
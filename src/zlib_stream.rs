@@ -0,0 +1,243 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the Apache License, Version 2.0. See LICENSE.txt in the project root for license information.
+ *  This software incorporates material from third parties. See NOTICE.txt for details.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Deflate streams with the zlib wrapper (RFC 1950) around them. The header is kept exactly as
+//! it was read, including the FLEVEL bits and the id of a preset dictionary, and the Adler-32
+//! trailer is recreated from the plain text if it matches, so that the whole wrapped stream
+//! comes back byte for byte.
+//!
+//! The corrections start with the length of the header and the header itself, followed by how
+//! the trailer is recreated (0: the Adler-32 of the plain text, 1: the stored value that follows
+//! as big endian u32 since it doesn't match, 2: the stream has no trailer) and then the
+//! corrections of the deflate stream.
+
+use crate::{
+    decompress_deflate_stream_with_config, preflate_config::PreflateConfig,
+    preflate_error::PreflateError, recompress_deflate_stream_with_config, DecompressResult,
+};
+
+const FDICT: u8 = 0x20;
+
+/// the header of a zlib stream
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ZlibHeader {
+    /// compression method and info (the window size)
+    pub cmf: u8,
+    /// the FLEVEL bits, FDICT and the check bits
+    pub flg: u8,
+    /// the Adler-32 of the preset dictionary if FDICT is set
+    pub dictionary_id: Option<u32>,
+}
+
+impl ZlibHeader {
+    /// Parses the header at the start of the data and returns it along with its length. Returns
+    /// None if there is no complete header with the deflate method and a valid check value.
+    pub fn parse(data: &[u8]) -> Option<(ZlibHeader, usize)> {
+        let (&cmf, &flg) = (data.first()?, data.get(1)?);
+        if cmf & 0x0f != 8 || cmf >> 4 > 7 || (u16::from(cmf) * 256 + u16::from(flg)) % 31 != 0 {
+            return None;
+        }
+
+        if flg & FDICT == 0 {
+            return Some((
+                ZlibHeader {
+                    cmf,
+                    flg,
+                    dictionary_id: None,
+                },
+                2,
+            ));
+        }
+
+        let dictionary_id = u32::from_be_bytes(data.get(2..6)?.try_into().unwrap());
+        Some((
+            ZlibHeader {
+                cmf,
+                flg,
+                dictionary_id: Some(dictionary_id),
+            },
+            6,
+        ))
+    }
+
+    pub fn write(&self, output: &mut Vec<u8>) {
+        output.extend_from_slice(&[self.cmf, self.flg]);
+        if let Some(dictionary_id) = self.dictionary_id {
+            output.extend_from_slice(&dictionary_id.to_be_bytes());
+        }
+    }
+
+    /// the compression level that the compressor claims to have used, from 0 (fastest) to 3 (best)
+    pub fn level(&self) -> u8 {
+        self.flg >> 6
+    }
+
+    /// the base 2 logarithm of the window size
+    pub fn window_bits(&self) -> u8 {
+        (self.cmf >> 4) + 8
+    }
+}
+
+/// the Adler-32 checksum of the data (RFC 1950)
+pub fn adler32(data: &[u8]) -> u32 {
+    const MOD_ADLER: u32 = 65521;
+    // the largest number of bytes that can be summed up before b overflows
+    const CHUNK: usize = 5552;
+
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(CHUNK) {
+        for &byte in chunk {
+            a += u32::from(byte);
+            b += a;
+        }
+        a %= MOD_ADLER;
+        b %= MOD_ADLER;
+    }
+    (b << 16) | a
+}
+
+const TRAILER_MATCHES: u8 = 0;
+const TRAILER_STORED: u8 = 1;
+const TRAILER_MISSING: u8 = 2;
+
+/// Decompresses a zlib stream (the deflate data with the zlib header and Adler-32 trailer around
+/// it). The corrections contain what is needed to recreate the header and trailer as well, and
+/// compressed_processed includes them. Streams that use a preset dictionary can only be
+/// decompressed if they don't refer to it.
+pub fn decompress_zlib_stream(
+    compressed_data: &[u8],
+    config: &PreflateConfig,
+) -> Result<DecompressResult, PreflateError> {
+    let (header, header_len) = ZlibHeader::parse(compressed_data).ok_or_else(|| {
+        PreflateError::ReadDeflate(anyhow::anyhow!("data doesn't start with a zlib header"))
+    })?;
+
+    let inner = decompress_deflate_stream_with_config(&compressed_data[header_len..], config)?;
+
+    let mut cabac_encoded = vec![header_len as u8];
+    header.write(&mut cabac_encoded);
+
+    let end = header_len + inner.compressed_processed;
+    let compressed_processed = match compressed_data.get(end..end + 4) {
+        Some(trailer) if trailer == adler32(&inner.plain_text).to_be_bytes() => {
+            cabac_encoded.push(TRAILER_MATCHES);
+            end + 4
+        }
+        Some(trailer) => {
+            cabac_encoded.push(TRAILER_STORED);
+            cabac_encoded.extend_from_slice(trailer);
+            end + 4
+        }
+        None => {
+            cabac_encoded.push(TRAILER_MISSING);
+            end
+        }
+    };
+    cabac_encoded.extend_from_slice(&inner.cabac_encoded);
+
+    Ok(DecompressResult {
+        cabac_encoded,
+        compressed_processed,
+        ..inner
+    })
+}
+
+/// recreates a zlib stream that was decompressed with decompress_zlib_stream
+pub fn recompress_zlib_stream(
+    plain_text: &[u8],
+    corrections: &[u8],
+    config: &PreflateConfig,
+) -> Result<Vec<u8>, PreflateError> {
+    let invalid = || {
+        PreflateError::RecompressFailed(anyhow::anyhow!("invalid zlib wrapper in the corrections"))
+    };
+
+    let (&header_len, rest) = corrections.split_first().ok_or_else(invalid)?;
+    let header_bytes = rest.get(..usize::from(header_len)).ok_or_else(invalid)?;
+    let (header, _) = ZlibHeader::parse(header_bytes)
+        .filter(|&(_, len)| len == usize::from(header_len))
+        .ok_or_else(invalid)?;
+    let rest = &rest[usize::from(header_len)..];
+
+    let (trailer, deflate_corrections) = match rest.split_first() {
+        Some((&TRAILER_MATCHES, rest)) => (Some(adler32(plain_text).to_be_bytes()), rest),
+        Some((&TRAILER_STORED, rest)) if rest.len() >= 4 => {
+            (Some(rest[..4].try_into().unwrap()), &rest[4..])
+        }
+        Some((&TRAILER_MISSING, rest)) => (None, rest),
+        _ => return Err(invalid()),
+    };
+
+    let mut output = Vec::new();
+    header.write(&mut output);
+    output.extend_from_slice(&recompress_deflate_stream_with_config(
+        plain_text,
+        deflate_corrections,
+        config,
+    )?);
+    if let Some(trailer) = trailer {
+        output.extend_from_slice(&trailer);
+    }
+
+    Ok(output)
+}
+
+#[test]
+fn zlib_stream_roundtrip() {
+    use std::io::Write;
+
+    let plain_text = crate::process::read_file("sample1.bin");
+    let config = PreflateConfig::default();
+
+    for level in [1, 6, 9] {
+        let mut encoder =
+            flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::new(level));
+        encoder.write_all(&plain_text).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        assert_eq!(
+            adler32(&plain_text).to_be_bytes(),
+            compressed[compressed.len() - 4..]
+        );
+
+        let r = decompress_zlib_stream(&compressed, &config).unwrap();
+        assert_eq!(r.plain_text, plain_text);
+        assert_eq!(r.compressed_processed, compressed.len());
+        assert_eq!(
+            recompress_zlib_stream(&r.plain_text, &r.cabac_encoded, &config).unwrap(),
+            compressed
+        );
+    }
+
+    let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::new(6));
+    encoder.write_all(b"hello hello hello hello").unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    // a trailer that doesn't match and a missing trailer are kept as they were
+    let mut damaged = compressed.clone();
+    *damaged.last_mut().unwrap() ^= 1;
+    let truncated = &compressed[..compressed.len() - 4];
+    for data in [&damaged[..], truncated] {
+        let r = decompress_zlib_stream(data, &config).unwrap();
+        assert_eq!(r.compressed_processed, data.len());
+        assert_eq!(
+            recompress_zlib_stream(&r.plain_text, &r.cabac_encoded, &config).unwrap(),
+            data
+        );
+    }
+
+    // the FLEVEL bits and the dictionary id are kept
+    let header = [0x78, 0xbb, 0x12, 0x34, 0x56, 0x78];
+    let (parsed, len) = ZlibHeader::parse(&header).unwrap();
+    assert_eq!(len, 6);
+    assert_eq!(parsed.level(), 2);
+    assert_eq!(parsed.dictionary_id, Some(0x12345678));
+    let mut written = Vec::new();
+    parsed.write(&mut written);
+    assert_eq!(written, header);
+
+    assert!(decompress_zlib_stream(&compressed[2..], &config).is_err());
+}
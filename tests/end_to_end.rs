@@ -9,7 +9,14 @@ use std::io::{Cursor, Read, Write};
 use std::path::Path;
 
 use flate2::{read::ZlibEncoder, Compression};
-use preflate_rs::{decompress_deflate_stream, recompress_deflate_stream};
+use preflate_rs::{
+    decompress_deflate_stream, decompress_deflate_stream_with_chunks,
+    decompress_deflate_stream_with_config, decompress_deflate_streams,
+    manifest::{expand_streams_with_manifest, Manifest, StreamLocation},
+    preflate_config::{PreflateConfig, ProbabilityModel, VerifyMode},
+    recompress_deflate_stream, recompress_deflate_stream_from_reader,
+    recompress_deflate_stream_with_config,
+};
 
 #[cfg(test)]
 pub fn read_file(filename: &str) -> Vec<u8> {
@@ -36,6 +43,374 @@ fn end_to_end_compressed() {
     }
 }
 
+/// corrections that were written before they were framed, by the version of the crate that
+/// first wrote the original layout, still have to recompress the same streams
+#[test]
+fn end_to_end_original_layout() {
+    use preflate_rs::{
+        corrections_text::dump_corrections, corrections_version,
+        streaming::DeflateStreamRecompressor, CorrectionsVersion,
+    };
+
+    for name in [
+        "compressed_zlib_level0",
+        "compressed_zlib_level1",
+        "compressed_zlib_level9",
+        "compressed_flate2_level1",
+        "compressed_flate2_level8",
+        "dump571",
+    ] {
+        let compressed_data = read_file(&format!("{}.deflate", name));
+        let corrections = read_file(&format!("original_layout/{}.corrections", name));
+
+        let plain_text = decompress_deflate_stream(&compressed_data, false)
+            .unwrap()
+            .plain_text;
+        assert_eq!(
+            corrections_version(&corrections).unwrap(),
+            CorrectionsVersion::ORIGINAL_LAYOUT
+        );
+
+        let recompressed = recompress_deflate_stream(&plain_text, &corrections).unwrap();
+        assert!(recompressed == compressed_data, "{}", name);
+
+        let mut recompressor =
+            DeflateStreamRecompressor::new(&PreflateConfig::default(), &corrections, Vec::new())
+                .unwrap();
+        recompressor.write_plain_text(&plain_text).unwrap();
+        assert!(
+            recompressor.finish().unwrap() == compressed_data,
+            "{}",
+            name
+        );
+
+        // they don't have the actions of the current predictor
+        assert!(dump_corrections(&plain_text, &corrections).is_err());
+
+        // there is no checksum in the original layout, so damage has to fail without panicking
+        let truncated = &corrections[..corrections.len() / 2];
+        assert!(recompress_deflate_stream(&plain_text, truncated).is_err());
+    }
+}
+
+#[test]
+fn end_to_end_streaming_verify() {
+    for filename in [
+        "compressed_zlib_level6.deflate",
+        "compressed_flate2_level1.deflate",
+        "dump571.deflate",
+    ] {
+        let compressed_data = read_file(filename);
+        let result = decompress_deflate_stream_with_config(
+            &compressed_data,
+            &PreflateConfig {
+                verify: VerifyMode::Streaming,
+                ..PreflateConfig::default()
+            },
+        )
+        .unwrap();
+
+        let recomp = recompress_deflate_stream(&result.plain_text, &result.cabac_encoded).unwrap();
+        assert_eq!(compressed_data, recomp);
+    }
+}
+
+#[test]
+fn end_to_end_sampled_verify() {
+    for mode in [
+        VerifyMode::Strided(3),
+        VerifyMode::Random {
+            percent: 30,
+            seed: 17,
+        },
+    ] {
+        for filename in [
+            "compressed_zlib_level6.deflate",
+            "compressed_flate2_level1.deflate",
+            "dump571.deflate",
+        ] {
+            let compressed_data = read_file(filename);
+            let result = decompress_deflate_stream_with_config(
+                &compressed_data,
+                &PreflateConfig {
+                    verify: mode,
+                    ..PreflateConfig::default()
+                },
+            )
+            .unwrap();
+
+            let recomp =
+                recompress_deflate_stream(&result.plain_text, &result.cabac_encoded).unwrap();
+            assert_eq!(compressed_data, recomp);
+        }
+    }
+}
+
+#[test]
+fn end_to_end_statistics() {
+    for filename in [
+        "compressed_zlib_level1.deflate",
+        "compressed_flate2_level9.deflate",
+        "dump571.deflate",
+    ] {
+        let compressed_data = read_file(filename);
+        let result = decompress_deflate_stream(&compressed_data, true).unwrap();
+
+        result.statistics.print();
+
+        // the measured entropy is a lower bound, since the arithmetic coder loses some
+        // precision for very skewed probabilities, but should be in the same ballpark
+        let measured_bytes = result.statistics.total_bits() / 8.0;
+        let actual_bytes = result.cabac_encoded.len() as f64;
+        println!(
+            "{}: measured {:.0} actual {}",
+            filename, measured_bytes, actual_bytes
+        );
+        assert!(measured_bytes <= actual_bytes);
+        assert!(measured_bytes > actual_bytes / 2.0);
+    }
+}
+
+#[test]
+fn end_to_end_static_probabilities() {
+    for filename in [
+        "compressed_zlib_level1.deflate",
+        "compressed_flate2_level9.deflate",
+        "dump571.deflate",
+    ] {
+        let compressed_data = read_file(filename);
+
+        let adaptive = decompress_deflate_stream(&compressed_data, true).unwrap();
+        let result = decompress_deflate_stream_with_config(
+            &compressed_data,
+            &PreflateConfig {
+                probability_model: ProbabilityModel::Static,
+                ..PreflateConfig::default()
+            },
+        )
+        .unwrap();
+
+        println!(
+            "{}: adaptive {} bytes, static {} bytes",
+            filename,
+            adaptive.cabac_encoded.len(),
+            result.cabac_encoded.len()
+        );
+
+        // the model is read from the header, so the default config works for recompressing
+        let recomp = recompress_deflate_stream(&result.plain_text, &result.cabac_encoded).unwrap();
+        assert_eq!(compressed_data, recomp);
+    }
+}
+
+#[test]
+#[cfg(feature = "serde")]
+fn end_to_end_json_codec() {
+    use preflate_rs::preflate_config::CorrectionCodec;
+
+    let config = PreflateConfig {
+        codec: CorrectionCodec::Json,
+        ..PreflateConfig::default()
+    };
+
+    let compressed_data = read_file("compressed_zlib_level6.deflate");
+    let result = decompress_deflate_stream_with_config(&compressed_data, &config).unwrap();
+
+    // every line of the corrections should be a standalone JSON object
+    let text = std::str::from_utf8(&result.cabac_encoded).unwrap();
+    assert!(text
+        .lines()
+        .all(|l| serde_json::from_str::<serde_json::Value>(l).is_ok()));
+
+    let recomp =
+        recompress_deflate_stream_with_config(&result.plain_text, &result.cabac_encoded, &config)
+            .unwrap();
+    assert_eq!(compressed_data, recomp);
+}
+
+#[test]
+fn end_to_end_custom_match_predictor() {
+    use preflate_rs::match_predictor::{
+        MatchPredictor, PredictorState, PreflateParameters, PreflateToken, RotatingHashTrait,
+        ZlibMatchPredictor,
+    };
+    use preflate_rs::{
+        decompress_deflate_stream_with_predictor, recompress_deflate_stream_with_predictor,
+    };
+
+    /// greedy predictor that never defers a match to the next position
+    #[derive(Default, Clone)]
+    struct GreedyPredictor {
+        inner: ZlibMatchPredictor,
+    }
+
+    impl MatchPredictor for GreedyPredictor {
+        fn predict_token<H: RotatingHashTrait>(
+            &mut self,
+            state: &PredictorState<H>,
+            params: &PreflateParameters,
+        ) -> PreflateToken {
+            let params = PreflateParameters {
+                is_fast_compressor: true,
+                ..*params
+            };
+            self.inner.predict_token(state, &params)
+        }
+
+        fn reset(&mut self) {
+            self.inner.reset();
+        }
+    }
+
+    let config = PreflateConfig::default();
+    let compressed_data = read_file("compressed_zlib_level6.deflate");
+
+    let greedy = decompress_deflate_stream_with_predictor(
+        &compressed_data,
+        &config,
+        &GreedyPredictor::default(),
+    )
+    .unwrap();
+    let lazy = decompress_deflate_stream(&compressed_data, true).unwrap();
+
+    // level 6 uses lazy matching, so the greedy predictor needs more corrections
+    assert!(greedy.cabac_encoded.len() > lazy.cabac_encoded.len());
+
+    let recomp = recompress_deflate_stream_with_predictor(
+        &greedy.plain_text,
+        &greedy.cabac_encoded,
+        &config,
+        &GreedyPredictor::default(),
+    )
+    .unwrap();
+    assert_eq!(compressed_data, recomp);
+}
+
+#[test]
+fn end_to_end_archive_summary() {
+    let level1 = read_file("compressed_zlib_level1.deflate");
+    let level9 = read_file("compressed_zlib_level9.deflate");
+    let garbage = [0xffu8; 16];
+
+    let (results, summary) = decompress_deflate_streams(
+        [&level1[..], &garbage[..], &level9[..]],
+        &PreflateConfig::default(),
+    );
+
+    assert_eq!(results.len(), 3);
+    assert!(results[1].is_err());
+    assert_eq!(summary.entries_processed, 2);
+    assert_eq!(summary.entries_skipped, 1);
+    assert_eq!(summary.entries_fallback, 0);
+    assert_eq!(
+        summary.total_compressed_bytes,
+        (level1.len() + level9.len()) as u64
+    );
+    assert_eq!(
+        summary.total_correction_bytes,
+        results
+            .iter()
+            .flatten()
+            .map(|r| r.cabac_encoded.len() as u64)
+            .sum::<u64>()
+    );
+    assert_eq!(summary.encoder_mix.values().sum::<u32>(), 2);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn end_to_end_manifest() {
+    let artifact_dir =
+        std::env::temp_dir().join(format!("preflate_manifest_{}", std::process::id()));
+    std::fs::create_dir_all(&artifact_dir).unwrap();
+
+    let level2 = read_file("compressed_zlib_level2.deflate");
+    let level7 = read_file("compressed_flate2_level7.deflate");
+    let garbage = [0xffu8; 16];
+
+    let (manifest, summary) = expand_streams_with_manifest(
+        [
+            ("a.bin", 0, &level2[..]),
+            ("b.bin", 100, &garbage[..]),
+            ("b.bin", 200, &level7[..]),
+        ]
+        .map(|(path, offset, data)| {
+            (
+                StreamLocation {
+                    path: path.to_string(),
+                    offset,
+                },
+                data,
+            )
+        }),
+        &artifact_dir,
+        &PreflateConfig::default(),
+    )
+    .unwrap();
+
+    assert_eq!(summary.entries_skipped, 1);
+    assert_eq!(manifest.entries.len(), 2);
+    assert_eq!(manifest.entries[1].location.offset, 200);
+
+    // reconstruction is driven only by the serialized manifest
+    let manifest = Manifest::from_json(&manifest.to_json()).unwrap();
+
+    assert_eq!(
+        manifest.entries[0].reconstruct(&artifact_dir).unwrap(),
+        level2
+    );
+    assert_eq!(
+        manifest.entries[1].reconstruct(&artifact_dir).unwrap(),
+        level7
+    );
+
+    std::fs::remove_dir_all(&artifact_dir).unwrap();
+}
+
+#[test]
+fn end_to_end_manifest_verify() {
+    let artifact_dir =
+        std::env::temp_dir().join(format!("preflate_manifest_verify_{}", std::process::id()));
+    std::fs::create_dir_all(&artifact_dir).unwrap();
+
+    let streams: Vec<_> = (1..=6)
+        .map(|level| read_file(&format!("compressed_zlib_level{}.deflate", level)))
+        .collect();
+
+    let (mut manifest, _) = expand_streams_with_manifest(
+        streams.iter().enumerate().map(|(i, data)| {
+            (
+                StreamLocation {
+                    path: "a.bin".to_string(),
+                    offset: i as u64 * 1000,
+                },
+                &data[..],
+            )
+        }),
+        &artifact_dir,
+        &PreflateConfig::default(),
+    )
+    .unwrap();
+    assert_eq!(manifest.entries.len(), 6);
+
+    for threads in [0, 1, 4] {
+        assert!(manifest.verify(&artifact_dir, threads).is_empty());
+    }
+
+    // damage two of the entries, both of them have to be reported
+    let corrections = artifact_dir.join(&manifest.entries[1].corrections_artifact);
+    let mut data = std::fs::read(&corrections).unwrap();
+    data[10] ^= 1;
+    std::fs::write(&corrections, data).unwrap();
+    manifest.entries[4].compressed_crc32 ^= 1;
+
+    let failures = manifest.verify(&artifact_dir, 3);
+    assert_eq!(failures.iter().map(|f| f.index).collect::<Vec<_>>(), [1, 4]);
+    assert_eq!(failures[1].location.offset, 4000);
+
+    std::fs::remove_dir_all(&artifact_dir).unwrap();
+}
+
 #[test]
 fn test_matchnotfound() {
     test_file("sample3.bin");
@@ -117,3 +492,1900 @@ fn test_file(filename: &str) {
         verifyresult(minusheader);
     }
 }
+
+#[test]
+fn end_to_end_nested_streams() {
+    use flate2::read::{DeflateEncoder, GzEncoder};
+
+    let sample = read_file("sample1.bin");
+    let (text, gzip_text) = (&sample[..50000], &sample[50000..80000]);
+    let mut zlib_stream = Vec::new();
+    ZlibEncoder::new(Cursor::new(text), Compression::new(6))
+        .read_to_end(&mut zlib_stream)
+        .unwrap();
+
+    let mut gzip_stream = Vec::new();
+    GzEncoder::new(Cursor::new(gzip_text), Compression::new(9))
+        .read_to_end(&mut gzip_stream)
+        .unwrap();
+
+    let mut outer_plain = b"some header bytes".to_vec();
+    outer_plain.extend_from_slice(&zlib_stream);
+    outer_plain.extend_from_slice(b"between the streams");
+    outer_plain.extend_from_slice(&gzip_stream);
+    outer_plain.extend_from_slice(b"trailer");
+
+    let mut compressed_data = Vec::new();
+    DeflateEncoder::new(Cursor::new(&outer_plain), Compression::new(9))
+        .read_to_end(&mut compressed_data)
+        .unwrap();
+
+    let config = PreflateConfig {
+        nested_depth: 2,
+        ..PreflateConfig::default()
+    };
+
+    let result = decompress_deflate_stream_with_config(&compressed_data, &config).unwrap();
+
+    // the nested streams are replaced by their plain text
+    assert!(result.plain_text.windows(text.len()).any(|w| w == text));
+    assert!(result
+        .plain_text
+        .windows(gzip_text.len())
+        .any(|w| w == gzip_text));
+
+    // the gzip trailer is recreated from the plain text, so it isn't stored
+    assert!(result
+        .plain_text
+        .ends_with(&[gzip_text, b"trailer"].concat()));
+
+    let recomp =
+        recompress_deflate_stream_with_config(&result.plain_text, &result.cabac_encoded, &config)
+            .unwrap();
+    assert_eq!(compressed_data, recomp);
+}
+
+#[test]
+fn end_to_end_lenient_stored_len() {
+    // a stored block with LEN 5 and an NLEN that isn't its complement, followed by a final
+    // stored block that is correct
+    let mut compressed_data = vec![0x00, 0x05, 0x00, 0x34, 0x12];
+    compressed_data.extend_from_slice(b"hello");
+    compressed_data.extend_from_slice(&[0x01, 0x06, 0x00, 0xf9, 0xff]);
+    compressed_data.extend_from_slice(b" world");
+
+    assert!(decompress_deflate_stream(&compressed_data, true).is_err());
+
+    let config = PreflateConfig {
+        lenient_stored_len: true,
+        ..PreflateConfig::default()
+    };
+    let result = decompress_deflate_stream_with_config(&compressed_data, &config).unwrap();
+    assert_eq!(result.plain_text, b"hello world");
+
+    // the corrections contain the exact NLEN, so the option isn't needed to recompress
+    let recomp = recompress_deflate_stream(&result.plain_text, &result.cabac_encoded).unwrap();
+    assert_eq!(compressed_data, recomp);
+}
+
+#[test]
+fn end_to_end_truncated_stream() {
+    use preflate_rs::truncated_stream::{decompress_resumable, StreamProgress};
+
+    let compressed_data = read_file("compressed_zlib_level6.deflate");
+    let config = PreflateConfig::default();
+    let expected = decompress_deflate_stream(&compressed_data, true).unwrap();
+
+    for cut in [0, 1, compressed_data.len() / 3, compressed_data.len() - 1] {
+        let mut truncated = match decompress_resumable(&compressed_data[..cut], &config).unwrap() {
+            StreamProgress::Truncated(t) => t,
+            StreamProgress::Complete(_) => panic!("stream cut at {} is complete", cut),
+        };
+        assert!(expected.plain_text.starts_with(truncated.plain_text()));
+        assert!(truncated
+            .plain_text()
+            .starts_with(truncated.complete_plain_text()));
+
+        // feed the rest in a few pieces
+        let mut pos = cut;
+        let result = loop {
+            let next = (pos + 1000).min(compressed_data.len());
+            match truncated
+                .resume(&compressed_data[pos..next], &config)
+                .unwrap()
+            {
+                StreamProgress::Complete(r) => break r,
+                StreamProgress::Truncated(t) => {
+                    assert!(next < compressed_data.len());
+                    assert!(expected.plain_text.starts_with(t.plain_text()));
+                    truncated = t;
+                }
+            }
+            pos = next;
+        };
+
+        assert_eq!(result.plain_text, expected.plain_text);
+        let recomp = recompress_deflate_stream(&result.plain_text, &result.cabac_encoded).unwrap();
+        assert_eq!(recomp, compressed_data);
+    }
+
+    // invalid data is still an error rather than a truncated stream
+    assert!(decompress_resumable(&[0x07, 0, 0, 0], &config).is_err());
+}
+
+#[test]
+fn end_to_end_stream_cache() {
+    use preflate_rs::stream_cache::LruStreamCache;
+    use std::sync::Arc;
+
+    let cache = Arc::new(LruStreamCache::new(16));
+    let config = PreflateConfig {
+        stream_cache: Some(cache.clone()),
+        ..PreflateConfig::default()
+    };
+
+    let level6 = read_file("compressed_zlib_level6.deflate");
+    let level1 = read_file("compressed_zlib_level1.deflate");
+
+    let (results, summary) = decompress_deflate_streams(
+        [&level6[..], &level1[..], &level6[..], &level6[..]],
+        &config,
+    );
+    assert_eq!(summary.entries_processed, 4);
+    assert_eq!(cache.len(), 2);
+    assert_eq!(cache.hits_and_misses(), (2, 2));
+
+    let results: Vec<_> = results.into_iter().map(|r| r.unwrap()).collect();
+    for cached in &results[2..] {
+        assert_eq!(cached.plain_text, results[0].plain_text);
+        assert_eq!(cached.cabac_encoded, results[0].cabac_encoded);
+        assert_eq!(cached.plain_text_crc32, results[0].plain_text_crc32);
+
+        let recomp = recompress_deflate_stream(&cached.plain_text, &cached.cabac_encoded).unwrap();
+        assert_eq!(recomp, level6);
+    }
+}
+
+#[test]
+fn end_to_end_block_costs() {
+    let compressed_data = read_file("compressed_zlib_level1.deflate");
+    let result = decompress_deflate_stream(&compressed_data, false).unwrap();
+    let blocks = &result.statistics.blocks;
+    assert!(!blocks.is_empty());
+
+    // the blocks cover the whole stream except the padding after the last one
+    let deflate_bits: u64 = blocks.iter().map(|b| b.deflate_bits()).sum();
+    let processed_bits = result.compressed_processed as u64 * 8;
+    assert!(deflate_bits <= processed_bits && deflate_bits + 8 > processed_bits);
+
+    for b in blocks {
+        assert_eq!(b.header_bits, 3);
+        assert!(b.tree_bits > 0 && b.token_bits > 0);
+        assert!(b.correction_bits >= 0.0);
+    }
+
+    // the parameters at the start aren't part of any block
+    let correction_bits: f64 = blocks.iter().map(|b| b.correction_bits).sum();
+    assert!(correction_bits > 0.0 && correction_bits <= result.statistics.total_bits());
+}
+
+#[test]
+fn end_to_end_inspect_dynamic_headers() {
+    use preflate_rs::inspect::{inspect_deflate_stream, CodeLengthSymbol, InspectBlockType};
+
+    let compressed_data = read_file("compressed_zlib_level6.deflate");
+    let report = inspect_deflate_stream(&compressed_data).unwrap();
+    let plain_text = decompress_deflate_stream(&compressed_data, false)
+        .unwrap()
+        .plain_text;
+
+    assert_eq!(report.plain_text_len, plain_text.len());
+    assert!(report.blocks.last().unwrap().last);
+    assert!(report.blocks.iter().any(|b| b.dynamic_header.is_some()));
+
+    for block in &report.blocks {
+        let Some(header) = &block.dynamic_header else {
+            assert_ne!(block.block_type, InspectBlockType::DynamicHuffman);
+            continue;
+        };
+
+        assert!((257..=286).contains(&header.hlit));
+        assert!((1..=32).contains(&header.hdist));
+        assert!((4..=19).contains(&header.hclen));
+        assert_eq!(header.literal_lengths.len(), header.hlit);
+        assert_eq!(header.distance_lengths.len(), header.hdist);
+
+        // the end of block code is always needed
+        assert_ne!(header.literal_lengths[256], 0);
+
+        // expanding the run length encoding gives the decoded lengths
+        let mut lengths = Vec::new();
+        for s in &header.code_length_symbols {
+            match *s {
+                CodeLengthSymbol::Length(l) => lengths.push(l),
+                CodeLengthSymbol::RepeatPrevious(n) => {
+                    let prev = *lengths.last().unwrap();
+                    lengths.extend(std::iter::repeat(prev).take(n.into()));
+                }
+                CodeLengthSymbol::RepeatZeroShort(n) | CodeLengthSymbol::RepeatZeroLong(n) => {
+                    lengths.extend(std::iter::repeat(0).take(n.into()))
+                }
+            }
+        }
+        assert_eq!(lengths[..header.hlit], header.literal_lengths[..]);
+        assert_eq!(lengths[header.hlit..], header.distance_lengths[..]);
+    }
+}
+
+#[test]
+fn end_to_end_correction_planes() {
+    let config = PreflateConfig {
+        correction_planes: true,
+        ..PreflateConfig::default()
+    };
+
+    for file in [
+        "compressed_zlib_level1.deflate",
+        "compressed_zlib_level6.deflate",
+        "compressed_flate2_level9.deflate",
+    ] {
+        let compressed_data = read_file(file);
+        let result = decompress_deflate_stream_with_config(&compressed_data, &config).unwrap();
+        assert!(result.statistics.total_bits() > 0.0);
+
+        // the layout is recorded in the corrections, so the default config can recompress them
+        let recomp = recompress_deflate_stream(&result.plain_text, &result.cabac_encoded).unwrap();
+        assert_eq!(recomp, compressed_data, "{}", file);
+    }
+}
+
+#[test]
+fn end_to_end_plain_text_chunks() {
+    let compressed_data = read_file("compressed_zlib_level6.deflate");
+    let config = PreflateConfig::default();
+
+    let mut chunks = 0;
+    let mut hasher = crc32fast::Hasher::new();
+    let result = decompress_deflate_stream_with_chunks(&compressed_data, &config, |chunk| {
+        chunks += 1;
+        hasher.update(chunk);
+    })
+    .unwrap();
+    assert_eq!(chunks, result.statistics.blocks.len());
+    assert_eq!(hasher.finalize(), result.plain_text_crc32);
+
+    let mut read = Vec::new();
+    let recomp = recompress_deflate_stream_from_reader(
+        Cursor::new(&result.plain_text),
+        &result.cabac_encoded,
+        &config,
+        |chunk| read.extend_from_slice(chunk),
+    )
+    .unwrap();
+    assert_eq!(recomp, compressed_data);
+    assert_eq!(read, result.plain_text);
+}
+
+#[test]
+fn serve_expands_and_reconstructs() {
+    use std::process::{Command, Stdio};
+
+    fn payload(request: &mut Vec<u8>, data: &[u8]) {
+        request.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        request.extend_from_slice(data);
+    }
+
+    fn read_u64(r: &mut impl Read) -> u64 {
+        let mut b = [0; 8];
+        r.read_exact(&mut b).unwrap();
+        u64::from_le_bytes(b)
+    }
+
+    fn read_payload(r: &mut impl Read) -> Vec<u8> {
+        let mut data = vec![0; read_u64(r) as usize];
+        r.read_exact(&mut data).unwrap();
+        data
+    }
+
+    fn read_header(r: &mut impl Read) -> (u32, u8) {
+        let mut b = [0; 5];
+        r.read_exact(&mut b).unwrap();
+        (u32::from_le_bytes(b[..4].try_into().unwrap()), b[4])
+    }
+
+    let compressed = read_file("compressed_zlib_level3.deflate");
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_preflate_util"))
+        .args(["serve", "--threads", "2"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = child.stdout.take().unwrap();
+
+    let mut request = 7u32.to_le_bytes().to_vec();
+    request.push(b'E');
+    payload(&mut request, &compressed);
+    stdin.write_all(&request).unwrap();
+    stdin.flush().unwrap();
+
+    assert_eq!(read_header(&mut stdout), (7, 0));
+    let plain_text = read_payload(&mut stdout);
+    let corrections = read_payload(&mut stdout);
+    assert_eq!(read_u64(&mut stdout), compressed.len() as u64);
+
+    let mut request = 8u32.to_le_bytes().to_vec();
+    request.push(b'R');
+    payload(&mut request, &plain_text);
+    payload(&mut request, &corrections);
+
+    // a stream that isn't deflate fails without ending the session
+    request.extend_from_slice(&9u32.to_le_bytes());
+    request.push(b'E');
+    payload(&mut request, &[0xff; 16]);
+    stdin.write_all(&request).unwrap();
+    drop(stdin);
+
+    let mut responses = Vec::new();
+    for _ in 0..2 {
+        let (id, status) = read_header(&mut stdout);
+        if status == 0 {
+            responses.push((id, read_payload(&mut stdout)));
+        } else {
+            let mut code = [0; 4];
+            stdout.read_exact(&mut code).unwrap();
+            assert_ne!(u32::from_le_bytes(code), 0);
+            read_payload(&mut stdout);
+            responses.push((id, Vec::new()));
+        }
+    }
+    responses.sort();
+    assert_eq!(responses, [(8, compressed.clone()), (9, Vec::new())]);
+
+    assert!(child.wait().unwrap().success());
+
+    // more requests than the queue of a single thread holds are all answered
+    let mut child = Command::new(env!("CARGO_BIN_EXE_preflate_util"))
+        .args(["serve", "--threads", "1"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = child.stdout.take().unwrap();
+
+    let sender = std::thread::spawn(move || {
+        for id in 0..10u32 {
+            let mut request = id.to_le_bytes().to_vec();
+            request.push(b'E');
+            payload(&mut request, &compressed);
+            stdin.write_all(&request).unwrap();
+        }
+    });
+
+    let mut ids = Vec::new();
+    for _ in 0..10 {
+        let (id, status) = read_header(&mut stdout);
+        assert_eq!(status, 0);
+        read_payload(&mut stdout);
+        read_payload(&mut stdout);
+        read_u64(&mut stdout);
+        ids.push(id);
+    }
+    sender.join().unwrap();
+    ids.sort();
+    assert_eq!(ids, (0..10).collect::<Vec<_>>());
+
+    assert!(child.wait().unwrap().success());
+}
+
+#[test]
+fn public_huffman_bit_lengths() {
+    use preflate_rs::huffman_calc::{calc_bit_lengths, HufftreeBitCalc, TokenFrequency};
+
+    let mut freq = TokenFrequency::default();
+    for &b in b"abracadabra" {
+        freq.add_literal(b);
+    }
+    freq.add_reference(3, 7);
+    freq.add_reference(4, 7);
+
+    for calc in [
+        HufftreeBitCalc::Zlib,
+        HufftreeBitCalc::Miniz,
+        HufftreeBitCalc::SevenZip,
+        HufftreeBitCalc::Zopfli,
+        HufftreeBitCalc::Libdeflate,
+    ] {
+        let (literals, distances) = freq.bit_lengths(calc);
+
+        // the last used symbol is the length code 258 of the match of 4
+        assert_eq!(literals.len(), 259);
+        for (symbol, &bits) in literals.iter().enumerate() {
+            assert_eq!(bits != 0, freq.literal_codes[symbol] != 0, "{}", symbol);
+        }
+
+        // the codes of each tree have to form a prefix code
+        for lengths in [&literals, &distances] {
+            let kraft: f64 = lengths
+                .iter()
+                .filter(|&&b| b != 0)
+                .map(|&b| 0.5f64.powi(b.into()))
+                .sum();
+            assert!(kraft <= 1.0);
+        }
+
+        assert_eq!(
+            literals,
+            calc_bit_lengths(calc, &freq.literal_codes, 15),
+            "{:?}",
+            calc
+        );
+    }
+
+    // the code length limit is enforced
+    let skewed: Vec<u16> = (0..20).map(|i| 1 << (i % 16)).collect();
+    assert!(calc_bit_lengths(HufftreeBitCalc::Zlib, &skewed, 7)
+        .iter()
+        .all(|&b| b <= 7));
+}
+
+#[test]
+fn end_to_end_plain_text_crc32() {
+    use flate2::{bufread::GzEncoder, Compression};
+
+    let sample = read_file("sample1.bin");
+
+    let mut gzip_stream = Vec::new();
+    GzEncoder::new(Cursor::new(&sample), Compression::new(6))
+        .read_to_end(&mut gzip_stream)
+        .unwrap();
+
+    // the deflate data sits between the 10 byte header and the 8 byte trailer
+    let deflate_data = &gzip_stream[10..gzip_stream.len() - 8];
+    let trailer_crc32 = u32::from_le_bytes(
+        gzip_stream[gzip_stream.len() - 8..gzip_stream.len() - 4]
+            .try_into()
+            .unwrap(),
+    );
+
+    let result = decompress_deflate_stream(deflate_data, true).unwrap();
+    assert_eq!(result.plain_text_crc32, trailer_crc32);
+    assert_eq!(result.plain_text_crc32, crc32fast::hash(&result.plain_text));
+}
+
+#[test]
+fn end_to_end_nested_gzip_header_fields() {
+    use flate2::{read::DeflateEncoder, GzBuilder};
+    use preflate_rs::gzip_header::GzipHeader;
+
+    let sample = read_file("sample1.bin");
+    let gzip_text = &sample[..30000];
+
+    let mut encoder = GzBuilder::new()
+        .filename("original name.bin")
+        .comment("some comment")
+        .extra(b"XY\x03\x00abc".to_vec())
+        .mtime(0x5f5e100)
+        .operating_system(11)
+        .write(Vec::new(), Compression::new(9));
+    encoder.write_all(gzip_text).unwrap();
+    let gzip_stream = encoder.finish().unwrap();
+
+    let (header, header_len) = GzipHeader::parse(&gzip_stream).unwrap();
+    assert_eq!(header.name.as_deref(), Some(&b"original name.bin"[..]));
+    assert_eq!(header.os, 11);
+
+    let mut outer_plain = b"header".to_vec();
+    outer_plain.extend_from_slice(&gzip_stream);
+
+    let mut compressed_data = Vec::new();
+    DeflateEncoder::new(Cursor::new(&outer_plain), Compression::new(9))
+        .read_to_end(&mut compressed_data)
+        .unwrap();
+
+    let config = PreflateConfig {
+        nested_depth: 1,
+        ..PreflateConfig::default()
+    };
+
+    // the header fields stay in the plain text in front of the expanded member
+    let result = decompress_deflate_stream_with_config(&compressed_data, &config).unwrap();
+    assert!(result
+        .plain_text
+        .ends_with(&[&gzip_stream[..header_len], gzip_text].concat()));
+
+    let recomp =
+        recompress_deflate_stream_with_config(&result.plain_text, &result.cabac_encoded, &config)
+            .unwrap();
+    assert_eq!(compressed_data, recomp);
+}
+
+#[test]
+fn end_to_end_nested_gzip_damaged_trailer() {
+    use flate2::read::{DeflateEncoder, GzEncoder};
+
+    let sample = read_file("sample1.bin");
+    let gzip_text = &sample[..30000];
+
+    let mut gzip_stream = Vec::new();
+    GzEncoder::new(Cursor::new(gzip_text), Compression::new(9))
+        .read_to_end(&mut gzip_stream)
+        .unwrap();
+
+    // a trailer with the wrong crc32 can't be recreated, so it has to be kept as it is
+    let crc_pos = gzip_stream.len() - 8;
+    gzip_stream[crc_pos] ^= 0xff;
+
+    let mut outer_plain = b"header".to_vec();
+    outer_plain.extend_from_slice(&gzip_stream);
+
+    let mut compressed_data = Vec::new();
+    DeflateEncoder::new(Cursor::new(&outer_plain), Compression::new(9))
+        .read_to_end(&mut compressed_data)
+        .unwrap();
+
+    let config = PreflateConfig {
+        nested_depth: 1,
+        ..PreflateConfig::default()
+    };
+
+    let result = decompress_deflate_stream_with_config(&compressed_data, &config).unwrap();
+    assert!(result.plain_text.ends_with(&gzip_stream[crc_pos..]));
+
+    let recomp =
+        recompress_deflate_stream_with_config(&result.plain_text, &result.cabac_encoded, &config)
+            .unwrap();
+    assert_eq!(compressed_data, recomp);
+}
+
+#[test]
+fn end_to_end_hdf5_chunks() {
+    use preflate_rs::{
+        container::recreate_file,
+        hdf5::{expand_hdf5_chunks, Hdf5Chunk},
+    };
+
+    let text = read_file("sample1.bin");
+
+    // fake file with a header and some chunks in between other data
+    let mut file = b"\x89HDF\r\n\x1a\n superblock and object headers".to_vec();
+    let mut chunks = Vec::new();
+    for (i, level) in [1, 6, 9].into_iter().enumerate() {
+        let mut chunk = Vec::new();
+        ZlibEncoder::new(
+            Cursor::new(&text[i * 1000..i * 1000 + 20000]),
+            Compression::new(level),
+        )
+        .read_to_end(&mut chunk)
+        .unwrap();
+
+        chunks.push(Hdf5Chunk {
+            address: file.len() as u64,
+            size: chunk.len() as u64,
+        });
+        file.extend_from_slice(&chunk);
+        file.extend_from_slice(b"btree node");
+    }
+
+    // chunk stored without the deflate filter and one that is outside of the file
+    chunks.push(Hdf5Chunk {
+        address: 0,
+        size: 16,
+    });
+    chunks.push(Hdf5Chunk {
+        address: file.len() as u64 - 4,
+        size: 100,
+    });
+
+    let config = PreflateConfig::default();
+    let expanded = expand_hdf5_chunks(&file, &chunks, &config);
+
+    assert_eq!(expanded.summary.entries_processed, 3);
+    assert!(expanded.plain_text.len() > 60000);
+
+    let recreated = recreate_file(&expanded.plain_text, &expanded.corrections, &config).unwrap();
+    assert_eq!(file, recreated);
+}
+
+#[test]
+fn end_to_end_osm_pbf() {
+    use preflate_rs::{
+        container::recreate_file,
+        osm_pbf::{expand_pbf, PbfBlobIterator},
+    };
+
+    fn varint(out: &mut Vec<u8>, mut v: u64) {
+        while v >= 0x80 {
+            out.push(v as u8 | 0x80);
+            v >>= 7;
+        }
+        out.push(v as u8);
+    }
+
+    fn bytes_field(out: &mut Vec<u8>, field: u64, data: &[u8]) {
+        varint(out, field << 3 | 2);
+        varint(out, data.len() as u64);
+        out.extend_from_slice(data);
+    }
+
+    fn write_blob(file: &mut Vec<u8>, blob_type: &str, blob: &[u8]) {
+        let mut header = Vec::new();
+        bytes_field(&mut header, 1, blob_type.as_bytes());
+        varint(&mut header, 3 << 3);
+        varint(&mut header, blob.len() as u64);
+
+        file.extend_from_slice(&(header.len() as u32).to_be_bytes());
+        file.extend_from_slice(&header);
+        file.extend_from_slice(blob);
+    }
+
+    let sample = read_file("sample1.bin");
+
+    let mut file = Vec::new();
+
+    // header blob stored uncompressed
+    let mut blob = Vec::new();
+    bytes_field(&mut blob, 1, b"OsmSchema-V0.6 DenseNodes");
+    write_blob(&mut file, "OSMHeader", &blob);
+
+    for chunk in sample[..90000].chunks(30000) {
+        let mut zlib_data = Vec::new();
+        ZlibEncoder::new(Cursor::new(chunk), Compression::new(6))
+            .read_to_end(&mut zlib_data)
+            .unwrap();
+
+        let mut blob = Vec::new();
+        varint(&mut blob, 2 << 3);
+        varint(&mut blob, chunk.len() as u64);
+        bytes_field(&mut blob, 3, &zlib_data);
+        write_blob(&mut file, "OSMData", &blob);
+    }
+
+    let blobs: Vec<_> = PbfBlobIterator::new(&file).map(|b| b.unwrap()).collect();
+    assert_eq!(blobs.len(), 4);
+    assert_eq!(blobs[0].blob_type, "OSMHeader");
+    assert!(blobs[0].zlib_data.is_none());
+    assert!(blobs[1..].iter().all(|b| b.zlib_data.is_some()));
+
+    let config = PreflateConfig::default();
+    let expanded = expand_pbf(&file, &config).unwrap();
+    assert_eq!(expanded.summary.entries_processed, 3);
+
+    let recreated = recreate_file(&expanded.plain_text, &expanded.corrections, &config).unwrap();
+    assert_eq!(file, recreated);
+}
+
+#[test]
+fn end_to_end_mat_file() {
+    use preflate_rs::{
+        container::recreate_file,
+        mat_file::{expand_mat, MatElementIterator, MI_COMPRESSED},
+    };
+
+    let sample = read_file("sample1.bin");
+
+    let mut file = b"MATLAB 5.0 MAT-file, Platform: GLNXA64".to_vec();
+    file.resize(116, b' ');
+    file.extend_from_slice(&[0; 8]);
+    file.extend_from_slice(&[0x00, 0x01]);
+    file.extend_from_slice(b"IM");
+
+    // an uncompressed miMATRIX element that needs padding
+    file.extend_from_slice(&14u32.to_le_bytes());
+    file.extend_from_slice(&13u32.to_le_bytes());
+    file.extend_from_slice(&[7; 13]);
+    file.extend_from_slice(&[0; 3]);
+
+    for (i, level) in [1, 6, 9].into_iter().enumerate() {
+        let mut element = Vec::new();
+        ZlibEncoder::new(
+            Cursor::new(&sample[i * 25000..(i + 1) * 25000]),
+            Compression::new(level),
+        )
+        .read_to_end(&mut element)
+        .unwrap();
+
+        // compressed elements are not padded
+        file.extend_from_slice(&MI_COMPRESSED.to_le_bytes());
+        file.extend_from_slice(&(element.len() as u32).to_le_bytes());
+        file.extend_from_slice(&element);
+    }
+
+    let elements: Vec<_> = MatElementIterator::new(&file)
+        .unwrap()
+        .map(|e| e.unwrap())
+        .collect();
+    assert_eq!(elements.len(), 4);
+    assert_eq!(elements[0].data_type, 14);
+    assert!(elements[1..].iter().all(|e| e.data_type == MI_COMPRESSED));
+
+    let config = PreflateConfig::default();
+    let expanded = expand_mat(&file, &config).unwrap();
+    assert_eq!(expanded.summary.entries_processed, 3);
+
+    let recreated = recreate_file(&expanded.plain_text, &expanded.corrections, &config).unwrap();
+    assert_eq!(file, recreated);
+}
+
+#[test]
+fn end_to_end_worker_threads() {
+    let config = PreflateConfig {
+        verify: VerifyMode::Strided(4),
+        ..PreflateConfig::default()
+    };
+
+    let files = [
+        "compressed_zlib_level6.deflate",
+        "compressed_flate2_level1.deflate",
+        "dump571.deflate",
+    ];
+
+    // the config is shared between the workers and the results are sent back to the caller
+    let results: Vec<_> = std::thread::scope(|s| {
+        let workers: Vec<_> = files
+            .iter()
+            .map(|f| {
+                let config = &config;
+                s.spawn(move || {
+                    let compressed_data = read_file(f);
+                    let result =
+                        decompress_deflate_stream_with_config(&compressed_data, config).unwrap();
+                    (compressed_data, result)
+                })
+            })
+            .collect();
+
+        workers.into_iter().map(|w| w.join().unwrap()).collect()
+    });
+
+    for (compressed_data, result) in results {
+        let recomp = recompress_deflate_stream(&result.plain_text, &result.cabac_encoded).unwrap();
+        assert_eq!(compressed_data, recomp);
+    }
+}
+
+#[test]
+#[cfg(feature = "serde")]
+fn end_to_end_predictor_snapshots() {
+    use preflate_rs::predictor_snapshot::{
+        create_predictor_snapshots, verify_block_range, PredictorSnapshot,
+    };
+
+    let compressed_data = read_file("compressed_zlib_level1.deflate");
+    let snapshots = create_predictor_snapshots(&compressed_data, 3).unwrap();
+    assert!(snapshots.len() > 1);
+
+    // hand each range to a worker as it would be sent to a remote machine
+    std::thread::scope(|s| {
+        for (i, snapshot) in snapshots.iter().enumerate() {
+            let json = serde_json::to_string(snapshot).unwrap();
+            let next_block = snapshots.get(i + 1).map(|n| n.block_index);
+            let compressed_data = &compressed_data;
+
+            s.spawn(move || {
+                let snapshot: PredictorSnapshot = serde_json::from_str(&json).unwrap();
+                let block_count = next_block.map_or(1, |n| n - snapshot.block_index);
+                verify_block_range(compressed_data, &snapshot, block_count).unwrap();
+            });
+        }
+    });
+
+    // ranges past the end of the stream are rejected
+    let last = snapshots.last().unwrap();
+    assert!(verify_block_range(&compressed_data, last, 1000).is_err());
+}
+
+#[test]
+fn end_to_end_custom_codec() {
+    use preflate_rs::{
+        decompress_deflate_stream_with_encoder, recompress_deflate_stream_with_decoder,
+        CodecCorrection, CodecMisprediction, CountNonDefaultActions, PredictionDecoder,
+        PredictionEncoder,
+    };
+    use std::collections::VecDeque;
+
+    /// stores every action as a plain number, as a downstream transport layer might
+    #[derive(Default)]
+    struct NumberCodec {
+        values: VecDeque<u32>,
+        statistics: CountNonDefaultActions,
+    }
+
+    impl PredictionEncoder for NumberCodec {
+        fn encode_correction(&mut self, action: CodecCorrection, value: u32) {
+            self.statistics.record_correction(action, value);
+            self.values.push_back(value);
+        }
+
+        fn encode_bucket_correction(&mut self, action: CodecCorrection, _bucket: u8, value: u32) {
+            self.encode_correction(action, value);
+        }
+
+        fn encode_misprediction(&mut self, action: CodecMisprediction, value: bool) {
+            self.statistics.record_misprediction(action, value);
+            self.values.push_back(value.into());
+        }
+
+        fn encode_value(&mut self, value: u16, _max_bits: u8) {
+            self.values.push_back(value.into());
+        }
+
+        fn encode_verify_state(&mut self, _message: &'static str, _checksum: u64) {}
+
+        fn finish(&mut self) {}
+
+        fn statistics(&self) -> CountNonDefaultActions {
+            self.statistics.clone()
+        }
+    }
+
+    impl PredictionDecoder for NumberCodec {
+        fn decode_value(&mut self, _max_bits_orig: u8) -> u16 {
+            self.values.pop_front().unwrap() as u16
+        }
+
+        fn decode_correction(&mut self, _correction: CodecCorrection) -> u32 {
+            self.values.pop_front().unwrap()
+        }
+
+        fn decode_bucket_correction(&mut self, _correction: CodecCorrection, _bucket: u8) -> u32 {
+            self.values.pop_front().unwrap()
+        }
+
+        fn decode_misprediction(&mut self, _misprediction: CodecMisprediction) -> bool {
+            self.values.pop_front().unwrap() != 0
+        }
+
+        fn decode_verify_state(&mut self, _message: &'static str, _checksum: u64) {}
+    }
+
+    let compressed_data = read_file("compressed_zlib_level1.deflate");
+
+    let mut codec = NumberCodec::default();
+    let (plain_text, processed) =
+        decompress_deflate_stream_with_encoder(&compressed_data, &mut codec).unwrap();
+    assert_eq!(processed, compressed_data.len());
+
+    let recompressed = recompress_deflate_stream_with_decoder(&plain_text, &mut codec).unwrap();
+    assert_eq!(compressed_data, recompressed);
+    assert!(codec.values.is_empty());
+}
+
+#[test]
+fn end_to_end_split_channels() {
+    let compressed_data = read_file("compressed_zlib_level6.deflate");
+
+    for probability_model in [ProbabilityModel::Adaptive, ProbabilityModel::Static] {
+        let mut sizes = Vec::new();
+        for split_channels in [false, true] {
+            let config = PreflateConfig {
+                probability_model,
+                split_channels,
+                ..PreflateConfig::default()
+            };
+            let r = decompress_deflate_stream_with_config(&compressed_data, &config).unwrap();
+            sizes.push(r.cabac_encoded.len());
+
+            // the layout is read from the corrections, so either one can be recompressed
+            // with the default config
+            let recomp = recompress_deflate_stream(&r.plain_text, &r.cabac_encoded).unwrap();
+            assert_eq!(compressed_data, recomp);
+        }
+        println!("{:?} interleaved/split: {:?}", probability_model, sizes);
+    }
+}
+
+#[test]
+fn end_to_end_model_reset() {
+    use preflate_rs::preflate_config::ModelReset;
+
+    let compressed_data = read_file("compressed_flate2_level3.deflate");
+
+    for split_channels in [false, true] {
+        for model_reset in [ModelReset::Keep, ModelReset::Reset, ModelReset::Rescale] {
+            let config = PreflateConfig {
+                split_channels,
+                model_reset,
+                ..PreflateConfig::default()
+            };
+            let r = decompress_deflate_stream_with_config(&compressed_data, &config).unwrap();
+            println!(
+                "split {} {:?}: {} bytes",
+                split_channels,
+                model_reset,
+                r.cabac_encoded.len()
+            );
+
+            // the reset mode is recorded in the header
+            let recomp = recompress_deflate_stream(&r.plain_text, &r.cabac_encoded).unwrap();
+            assert_eq!(compressed_data, recomp);
+        }
+    }
+}
+
+#[test]
+fn end_to_end_cabac_backend() {
+    use preflate_rs::preflate_config::CabacBackend;
+
+    let compressed_data = read_file("compressed_flate2_level3.deflate");
+
+    for cabac_backend in [CabacBackend::Vp8, CabacBackend::H265, CabacBackend::Lzma] {
+        for split_channels in [false, true] {
+            let config = PreflateConfig {
+                cabac_backend,
+                split_channels,
+                ..PreflateConfig::default()
+            };
+            let r = decompress_deflate_stream_with_config(&compressed_data, &config).unwrap();
+            println!(
+                "{:?} split {}: {} bytes",
+                cabac_backend,
+                split_channels,
+                r.cabac_encoded.len()
+            );
+
+            // the backend is recorded in the header
+            let recomp = recompress_deflate_stream(&r.plain_text, &r.cabac_encoded).unwrap();
+            assert_eq!(compressed_data, recomp);
+        }
+    }
+}
+
+#[test]
+fn end_to_end_corrupt_corrections() {
+    use preflate_rs::preflate_error::{ErrorCode, PreflateError};
+
+    let compressed_data = read_file("compressed_zlib_level6.deflate");
+    let r = decompress_deflate_stream(&compressed_data, true).unwrap();
+
+    // truncated corrections are detected before anything is decoded
+    for len in [
+        0,
+        1,
+        5,
+        r.cabac_encoded.len() / 2,
+        r.cabac_encoded.len() - 1,
+    ] {
+        let e = recompress_deflate_stream(&r.plain_text, &r.cabac_encoded[..len]).unwrap_err();
+        assert_eq!(e.error_code(), ErrorCode::CorruptCorrections, "len {}", len);
+    }
+
+    // a flipped bit anywhere is caught by the checksums and reported at or before the damage
+    for offset in [
+        0,
+        1,
+        4,
+        r.cabac_encoded.len() / 2,
+        r.cabac_encoded.len() - 1,
+    ] {
+        let mut damaged = r.cabac_encoded.clone();
+        damaged[offset] ^= 0x10;
+
+        match recompress_deflate_stream(&r.plain_text, &damaged) {
+            Err(PreflateError::CorruptCorrections(damage_offset, _)) => {
+                assert!(damage_offset <= offset, "{} > {}", damage_offset, offset)
+            }
+            r => panic!(
+                "offset {}: unexpected result {:?}",
+                offset,
+                r.map(|v| v.len())
+            ),
+        }
+    }
+
+    // trailing data is rejected as well
+    let mut extended = r.cabac_encoded.clone();
+    extended.push(0);
+    let e = recompress_deflate_stream(&r.plain_text, &extended).unwrap_err();
+    assert_eq!(e.error_code(), ErrorCode::CorruptCorrections);
+}
+
+#[test]
+fn end_to_end_mispaired_plain_text() {
+    use preflate_rs::preflate_error::ErrorCode;
+
+    let compressed_data = read_file("compressed_zlib_level6.deflate");
+    let r = decompress_deflate_stream(&compressed_data, true).unwrap();
+
+    // Changing a byte of the plain text changes the predictions from there on, which reads the
+    // corrections with other contexts. Either recreating the stream fails, or it isn't the
+    // original one, which the length and crc32 in the corrections catch.
+    let mut plain_text = r.plain_text.clone();
+    plain_text[100] ^= 1;
+
+    let e = recompress_deflate_stream(&plain_text, &r.cabac_encoded).unwrap_err();
+    assert!(
+        matches!(
+            e.error_code(),
+            ErrorCode::Mismatch
+                | ErrorCode::RecreateBlock
+                | ErrorCode::RecreateTree
+                | ErrorCode::PredictionMismatch
+        ),
+        "{:?}",
+        e
+    );
+}
+
+#[test]
+fn end_to_end_truncated_or_invalid_stream() {
+    use preflate_rs::preflate_error::{DeflateError, ErrorCode, PreflateError};
+
+    let compressed_data = read_file("compressed_zlib_level6.deflate");
+
+    // a stream that is cut off isn't confused with one that is invalid
+    match decompress_deflate_stream(&compressed_data[..compressed_data.len() / 2], true) {
+        Err(e) => assert_eq!(e.error_code(), ErrorCode::Truncated),
+        Ok(_) => panic!("a truncated stream was decompressed"),
+    }
+
+    // not a deflate stream at all, since the first block has the reserved type 3
+    assert!(matches!(
+        decompress_deflate_stream(&[0xff; 16], true),
+        Err(PreflateError::CorruptDeflate {
+            block: 0,
+            kind: DeflateError::InvalidBlockType
+        })
+    ));
+}
+
+#[test]
+fn end_to_end_parameters() {
+    for level in 1..=9 {
+        let compressed_data = read_file(&format!("compressed_zlib_level{}.deflate", level));
+        let r = decompress_deflate_stream(&compressed_data, true).unwrap();
+
+        assert_eq!(r.parameters.window_bits, 15);
+        assert!(r.parameters.zlib_compatible);
+        assert_eq!(r.parameters.zlib_level(), Some(level));
+    }
+}
+
+#[test]
+fn end_to_end_prediction_threads() {
+    for filename in ["compressed_zlib_level1.deflate", "sample1.bin"] {
+        let mut compressed_data = read_file(filename);
+        if filename == "sample1.bin" {
+            compressed_data = zlib_raw_deflate(&compressed_data, 6, 15);
+        }
+
+        let single = decompress_deflate_stream(&compressed_data, true).unwrap();
+
+        // the corrections don't depend on the number of threads
+        for threads in [0, 3, 8] {
+            let config = PreflateConfig {
+                verify: VerifyMode::Full,
+                prediction_threads: threads,
+                ..PreflateConfig::default()
+            };
+            let r = decompress_deflate_stream_with_config(&compressed_data, &config).unwrap();
+            assert_eq!(r.cabac_encoded, single.cabac_encoded);
+            assert_eq!(r.plain_text, single.plain_text);
+        }
+
+        let recompressed =
+            recompress_deflate_stream(&single.plain_text, &single.cabac_encoded).unwrap();
+        assert_eq!(recompressed, compressed_data);
+    }
+}
+
+#[test]
+fn end_to_end_plain_text_segments() {
+    use preflate_rs::decompress_deflate_stream_segments;
+
+    let sample = read_file("sample1.bin");
+    let config = PreflateConfig::default();
+
+    // level 0 only writes stored blocks, so none of the plain text has to be copied
+    let stored = zlib_raw_deflate(&sample, 0, 15);
+    let r = decompress_deflate_stream_segments(&stored, &config).unwrap();
+    assert!(r.plain_text.segments.len() > 1);
+    assert_eq!(r.plain_text.borrowed_len(), sample.len());
+    assert_eq!(r.plain_text.to_vec(), sample);
+
+    let mut written = Vec::new();
+    r.plain_text.write_to(&mut written).unwrap();
+    assert_eq!(written, sample);
+    assert_eq!(
+        recompress_deflate_stream(&written, &r.cabac_encoded).unwrap(),
+        stored
+    );
+
+    // without stored blocks the plain text is kept in one piece
+    let compressed = zlib_raw_deflate(&sample, 6, 15);
+    let r = decompress_deflate_stream_segments(&compressed, &config).unwrap();
+    assert_eq!(r.plain_text.segments.len(), 1);
+    assert_eq!(r.plain_text.borrowed_len(), 0);
+    assert_eq!(r.plain_text.to_vec(), sample);
+}
+
+#[test]
+fn end_to_end_resource_limits() {
+    use preflate_rs::preflate_error::{PreflateError, ResourceLimit};
+
+    let sample = read_file("sample1.bin");
+    let compressed_data = zlib_raw_deflate(&sample, 9, 15);
+    let r = decompress_deflate_stream(&compressed_data, true).unwrap();
+
+    let limited = |limit| match limit {
+        ResourceLimit::PlainTextSize => PreflateConfig {
+            max_plaintext_size: sample.len() - 1,
+            ..PreflateConfig::default()
+        },
+        ResourceLimit::CorrectionSize => PreflateConfig {
+            max_correction_size: r.cabac_encoded.len() - 1,
+            ..PreflateConfig::default()
+        },
+        ResourceLimit::ChainLimit => PreflateConfig {
+            max_chain_limit: r.parameters.max_chain - 1,
+            ..PreflateConfig::default()
+        },
+    };
+
+    for limit in [
+        ResourceLimit::PlainTextSize,
+        ResourceLimit::CorrectionSize,
+        ResourceLimit::ChainLimit,
+    ] {
+        let config = limited(limit);
+        match decompress_deflate_stream_with_config(&compressed_data, &config) {
+            Err(PreflateError::LimitExceeded { limit: l, .. }) => assert_eq!(l, limit),
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("{:?} wasn't enforced", limit),
+        }
+    }
+
+    // recompressing checks the size of its inputs as well
+    for limit in [ResourceLimit::PlainTextSize, ResourceLimit::CorrectionSize] {
+        assert!(matches!(
+            recompress_deflate_stream_with_config(&r.plain_text, &r.cabac_encoded, &limited(limit)),
+            Err(PreflateError::LimitExceeded { limit: l, .. }) if l == limit
+        ));
+    }
+
+    // exactly at the limits is fine
+    let config = PreflateConfig {
+        max_plaintext_size: sample.len(),
+        max_correction_size: r.cabac_encoded.len(),
+        max_chain_limit: r.parameters.max_chain,
+        ..PreflateConfig::default()
+    };
+    let r = decompress_deflate_stream_with_config(&compressed_data, &config).unwrap();
+    assert_eq!(
+        recompress_deflate_stream_with_config(&r.plain_text, &r.cabac_encoded, &config).unwrap(),
+        compressed_data
+    );
+}
+
+#[test]
+fn end_to_end_decompress_verified() {
+    use preflate_rs::decompress_deflate_stream_verified;
+
+    let sample = read_file("sample1.bin");
+    for level in [0, 1, 6, 9] {
+        // the data after the end of the stream isn't part of what is compared
+        let mut compressed_data = zlib_raw_deflate(&sample, level, 15);
+        let stream_len = compressed_data.len();
+        compressed_data.extend_from_slice(b"trailing data");
+
+        let r = decompress_deflate_stream_verified(&compressed_data, &PreflateConfig::default())
+            .unwrap();
+        assert!(r.verified);
+        assert_eq!(r.mismatch_offset, None);
+        assert!(r.recompress_error.is_none());
+        assert_eq!(r.result.compressed_processed, stream_len);
+        assert_eq!(r.result.plain_text, sample);
+    }
+
+    assert!(decompress_deflate_stream_verified(&[0xff; 16], &PreflateConfig::default()).is_err());
+}
+
+#[test]
+fn end_to_end_corrections_version() {
+    use preflate_rs::{corrections_version, CorrectionsVersion, CONTEXT_SCHEME_VERSION};
+
+    let compressed_data = read_file("compressed_zlib_level6.deflate");
+    let r = decompress_deflate_stream(&compressed_data, true).unwrap();
+
+    let version = corrections_version(&r.cabac_encoded).unwrap();
+    assert_eq!(version, CorrectionsVersion::CURRENT);
+    assert_eq!(version.context_scheme, Some(CONTEXT_SCHEME_VERSION));
+
+    assert_eq!(
+        recompress_deflate_stream(&r.plain_text, &r.cabac_encoded).unwrap(),
+        compressed_data
+    );
+}
+
+#[test]
+fn end_to_end_secondary_compression() {
+    use preflate_rs::{
+        preflate_error::PreflateError,
+        secondary_compression::{secondary_compression, SecondaryCompression, SecondaryCompressor},
+    };
+    use std::sync::Arc;
+
+    /// zlib stands in for the backend, since the crate doesn't depend on zstd
+    #[derive(Debug)]
+    struct ZlibCompressor;
+
+    impl SecondaryCompressor for ZlibCompressor {
+        fn compress(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+            let mut output = Vec::new();
+            ZlibEncoder::new(data, Compression::best()).read_to_end(&mut output)?;
+            Ok(output)
+        }
+
+        fn decompress(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+            let mut output = Vec::new();
+            flate2::read::ZlibDecoder::new(data).read_to_end(&mut output)?;
+            Ok(output)
+        }
+    }
+
+    let compressed_data = read_file("compressed_zlib_level1.deflate");
+
+    let mut config = PreflateConfig {
+        secondary_compression: SecondaryCompression::Zstd,
+        ..PreflateConfig::default()
+    };
+    config
+        .secondary_compressors
+        .register(SecondaryCompression::Zstd, Arc::new(ZlibCompressor));
+
+    let plain = decompress_deflate_stream(&compressed_data, true).unwrap();
+    let r = decompress_deflate_stream_with_config(&compressed_data, &config).unwrap();
+    println!(
+        "corrections {} bytes, {} with secondary compression",
+        plain.cabac_encoded.len(),
+        r.cabac_encoded.len()
+    );
+
+    if r.cabac_encoded != plain.cabac_encoded {
+        assert_eq!(
+            secondary_compression(&r.cabac_encoded),
+            SecondaryCompression::Zstd
+        );
+
+        // recompressing needs the backend to be registered
+        assert!(matches!(
+            recompress_deflate_stream(&r.plain_text, &r.cabac_encoded),
+            Err(PreflateError::RecompressFailed(_))
+        ));
+    }
+
+    // the backend is recorded, so it doesn't need to be selected for recompressing
+    config.secondary_compression = SecondaryCompression::None;
+    assert_eq!(
+        recompress_deflate_stream_with_config(&r.plain_text, &r.cabac_encoded, &config).unwrap(),
+        compressed_data
+    );
+}
+
+#[test]
+fn end_to_end_block_observer() {
+    use preflate_rs::{
+        block_observer::BlockSummary, decompress_deflate_stream_with_observer,
+        inspect::inspect_deflate_stream, recompress_deflate_stream_with_observer,
+    };
+
+    let compressed_data = read_file("compressed_zlib_level1.deflate");
+    let report = inspect_deflate_stream(&compressed_data).unwrap();
+
+    let mut predicted: Vec<BlockSummary> = Vec::new();
+    let r = decompress_deflate_stream_with_observer(
+        &compressed_data,
+        &PreflateConfig::default(),
+        &mut predicted,
+    )
+    .unwrap();
+
+    assert_eq!(predicted.len(), report.blocks.len());
+    for (i, (summary, block)) in predicted.iter().zip(&report.blocks).enumerate() {
+        assert_eq!(summary.index, i);
+        assert_eq!(summary.block_type, block.block_type);
+        assert_eq!(summary.token_count, block.token_count);
+    }
+    assert_eq!(
+        predicted
+            .iter()
+            .map(|s| s.uncompressed_len as usize)
+            .sum::<usize>(),
+        r.plain_text.len()
+    );
+
+    // predicting on several threads reports the same blocks in the same order
+    let mut parallel = Vec::new();
+    decompress_deflate_stream_with_observer(
+        &compressed_data,
+        &PreflateConfig {
+            prediction_threads: 4,
+            ..PreflateConfig::default()
+        },
+        &mut parallel,
+    )
+    .unwrap();
+    assert_eq!(parallel, predicted);
+
+    // the decoder reads the same corrections that the encoder wrote
+    let mut recreated = Vec::new();
+    let recompressed = recompress_deflate_stream_with_observer(
+        &r.plain_text,
+        &r.cabac_encoded,
+        &PreflateConfig::default(),
+        &mut recreated,
+    )
+    .unwrap();
+    assert_eq!(recompressed, compressed_data);
+    assert_eq!(recreated, predicted);
+}
+
+#[test]
+fn end_to_end_progress_and_cancellation() {
+    use preflate_rs::{
+        preflate_error::PreflateError,
+        progress::{CancellationToken, Progress, ProgressCallback},
+    };
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug, Default)]
+    struct Recorder(Mutex<Vec<Progress>>);
+
+    impl ProgressCallback for Recorder {
+        fn progress(&self, progress: Progress) {
+            self.0.lock().unwrap().push(progress);
+        }
+    }
+
+    let compressed_data = read_file("compressed_zlib_level1.deflate");
+
+    let recorder = Arc::new(Recorder::default());
+    let config = PreflateConfig {
+        verify: VerifyMode::None,
+        progress: Some(recorder.clone()),
+        ..PreflateConfig::default()
+    };
+
+    let r = decompress_deflate_stream_with_config(&compressed_data, &config).unwrap();
+    let decompressed = std::mem::take(&mut *recorder.0.lock().unwrap());
+    assert!(decompressed.len() > 1);
+    assert!(decompressed
+        .windows(2)
+        .all(|w| w[0].consumed <= w[1].consumed && w[0].produced < w[1].produced));
+    assert_eq!(
+        decompressed.last().unwrap(),
+        &Progress {
+            consumed: r.compressed_processed as u64,
+            produced: r.plain_text.len() as u64,
+            plain_text_crc32: crc32fast::hash(&r.plain_text),
+        }
+    );
+
+    recompress_deflate_stream_with_config(&r.plain_text, &r.cabac_encoded, &config).unwrap();
+    let recompressed = recorder.0.lock().unwrap().clone();
+    assert_eq!(recompressed.len(), decompressed.len());
+    let last = recompressed.last().unwrap();
+    assert_eq!(last.consumed, r.plain_text.len() as u64);
+    assert!(last.produced <= compressed_data.len() as u64);
+    assert_eq!(last.plain_text_crc32, crc32fast::hash(&r.plain_text));
+
+    // a cancelled token stops both directions, with one thread or several
+    let cancellation = CancellationToken::new();
+    cancellation.cancel();
+    for prediction_threads in [1, 4] {
+        let cancelled = PreflateConfig {
+            cancellation: Some(cancellation.clone()),
+            prediction_threads,
+            ..PreflateConfig::default()
+        };
+        assert!(matches!(
+            decompress_deflate_stream_with_config(&compressed_data, &cancelled),
+            Err(PreflateError::Cancelled)
+        ));
+        assert!(matches!(
+            recompress_deflate_stream_with_config(&r.plain_text, &r.cabac_encoded, &cancelled),
+            Err(PreflateError::Cancelled)
+        ));
+    }
+}
+
+/// compresses the data into a raw deflate stream with zlib, using a smaller window than the default
+fn zlib_raw_deflate(data: &[u8], level: i32, window_bits: i32) -> Vec<u8> {
+    use libz_sys::{
+        deflate, deflateEnd, deflateInit2_, z_stream, zlibVersion, Z_DEFAULT_STRATEGY, Z_DEFLATED,
+        Z_FINISH, Z_OK, Z_STREAM_END,
+    };
+
+    let mut output = vec![0u8; data.len() + 1000];
+
+    unsafe {
+        // zlib fills in the default allocator for the null function pointers
+        let mut stream = std::mem::MaybeUninit::<z_stream>::zeroed();
+        let stream = stream.as_mut_ptr();
+        let err = deflateInit2_(
+            stream,
+            level,
+            Z_DEFLATED,
+            -window_bits,
+            8,
+            Z_DEFAULT_STRATEGY,
+            zlibVersion(),
+            std::mem::size_of::<z_stream>() as i32,
+        );
+        assert_eq!(err, Z_OK);
+
+        (*stream).next_in = data.as_ptr() as *mut _;
+        (*stream).avail_in = data.len() as u32;
+        (*stream).next_out = output.as_mut_ptr();
+        (*stream).avail_out = output.len() as u32;
+
+        assert_eq!(deflate(stream, Z_FINISH), Z_STREAM_END);
+        output.truncate((*stream).total_out as usize);
+        deflateEnd(stream);
+    }
+
+    output
+}
+
+/// compresses the data into a raw deflate stream with zlib, switching to the second level after
+/// the first half of the data like a compressor that is reconfigured partway
+fn zlib_raw_deflate_switching(data: &[u8], first_level: i32, second_level: i32) -> Vec<u8> {
+    use libz_sys::{
+        deflate, deflateEnd, deflateInit2_, deflateParams, z_stream, zlibVersion,
+        Z_DEFAULT_STRATEGY, Z_DEFLATED, Z_FINISH, Z_NO_FLUSH, Z_OK, Z_STREAM_END,
+    };
+
+    let mut output = vec![0u8; data.len() + 1000];
+    let (first, second) = data.split_at(data.len() / 2);
+
+    unsafe {
+        let mut stream = std::mem::MaybeUninit::<z_stream>::zeroed();
+        let stream = stream.as_mut_ptr();
+        let err = deflateInit2_(
+            stream,
+            first_level,
+            Z_DEFLATED,
+            -15,
+            8,
+            Z_DEFAULT_STRATEGY,
+            zlibVersion(),
+            std::mem::size_of::<z_stream>() as i32,
+        );
+        assert_eq!(err, Z_OK);
+
+        (*stream).next_out = output.as_mut_ptr();
+        (*stream).avail_out = output.len() as u32;
+
+        (*stream).next_in = first.as_ptr() as *mut _;
+        (*stream).avail_in = first.len() as u32;
+        assert_eq!(deflate(stream, Z_NO_FLUSH), Z_OK);
+
+        // flushes what was compressed with the first level into its own blocks
+        assert_eq!(
+            deflateParams(stream, second_level, Z_DEFAULT_STRATEGY),
+            Z_OK
+        );
+
+        (*stream).next_in = second.as_ptr() as *mut _;
+        (*stream).avail_in = second.len() as u32;
+        assert_eq!(deflate(stream, Z_FINISH), Z_STREAM_END);
+        output.truncate((*stream).total_out as usize);
+        deflateEnd(stream);
+    }
+
+    output
+}
+
+/// compresses the data with zlib, flushing with the given mode after every chunk
+fn zlib_raw_deflate_flushing(data: &[u8], level: i32, chunk_size: usize, flush: i32) -> Vec<u8> {
+    use libz_sys::{
+        deflate, deflateEnd, deflateInit2_, z_stream, zlibVersion, Z_DEFAULT_STRATEGY, Z_DEFLATED,
+        Z_FINISH, Z_OK, Z_STREAM_END,
+    };
+
+    let mut output = vec![0u8; data.len() * 2 + 1000];
+
+    unsafe {
+        let mut stream = std::mem::MaybeUninit::<z_stream>::zeroed();
+        let stream = stream.as_mut_ptr();
+        let err = deflateInit2_(
+            stream,
+            level,
+            Z_DEFLATED,
+            -15,
+            8,
+            Z_DEFAULT_STRATEGY,
+            zlibVersion(),
+            std::mem::size_of::<z_stream>() as i32,
+        );
+        assert_eq!(err, Z_OK);
+
+        (*stream).next_out = output.as_mut_ptr();
+        (*stream).avail_out = output.len() as u32;
+
+        for chunk in data.chunks(chunk_size) {
+            (*stream).next_in = chunk.as_ptr() as *mut _;
+            (*stream).avail_in = chunk.len() as u32;
+            assert_eq!(deflate(stream, flush), Z_OK);
+        }
+
+        assert_eq!(deflate(stream, Z_FINISH), Z_STREAM_END);
+        output.truncate((*stream).total_out as usize);
+        deflateEnd(stream);
+    }
+
+    output
+}
+
+#[test]
+fn end_to_end_flushes() {
+    use libz_sys::{Z_FULL_FLUSH, Z_SYNC_FLUSH};
+
+    let v = read_file("sample1.bin");
+
+    for flush in [Z_SYNC_FLUSH, Z_FULL_FLUSH] {
+        for level in [1, 6, 9] {
+            let compressed_data = zlib_raw_deflate_flushing(&v, level, 2000, flush);
+
+            let result = decompress_deflate_stream(&compressed_data, true).unwrap();
+            println!(
+                "flush {} level {}: compressed {} cabac {}",
+                flush,
+                level,
+                compressed_data.len(),
+                result.cabac_encoded.len()
+            );
+            result.statistics.print();
+
+            let recompressed =
+                recompress_deflate_stream(&result.plain_text, &result.cabac_encoded).unwrap();
+            assert!(recompressed == compressed_data);
+        }
+    }
+}
+
+#[test]
+fn end_to_end_reestimated_parameters() {
+    let v = read_file("sample2.bin");
+    let compressed_data = zlib_raw_deflate_switching(&v, 9, 1);
+
+    let global = decompress_deflate_stream(&compressed_data, true).unwrap();
+
+    for verify in [VerifyMode::Full, VerifyMode::Strided(3)] {
+        let config = PreflateConfig {
+            reestimate_parameters: true,
+            verify,
+            ..PreflateConfig::default()
+        };
+        let result = decompress_deflate_stream_with_config(&compressed_data, &config).unwrap();
+        println!(
+            "compressed {} global parameters {} reestimated {}",
+            compressed_data.len(),
+            global.cabac_encoded.len(),
+            result.cabac_encoded.len()
+        );
+
+        // the second half is predicted with the parameters of the level it was compressed with
+        assert!(result.cabac_encoded.len() < global.cabac_encoded.len());
+
+        let recompressed =
+            recompress_deflate_stream(&result.plain_text, &result.cabac_encoded).unwrap();
+        assert!(recompressed == compressed_data);
+    }
+}
+
+#[test]
+fn end_to_end_small_window() {
+    let v = read_file("sample1.bin");
+
+    // zlib with a small window reaches the maximum distance for the window much more often,
+    // which shouldn't be mistaken for an encoder that matches further than zlib can
+    for window_bits in 9..=14 {
+        for level in [1, 6] {
+            let compressed_data = zlib_raw_deflate(&v, level, window_bits);
+
+            let result = decompress_deflate_stream(&compressed_data, true).unwrap();
+            println!(
+                "window {} level {}: compressed {} cabac {}",
+                window_bits,
+                level,
+                compressed_data.len(),
+                result.cabac_encoded.len()
+            );
+
+            assert!(
+                result.statistics.corrections_count.iter().all(|&c| c == 0),
+                "window {} level {}: {:?}",
+                window_bits,
+                level,
+                result.statistics.corrections_count
+            );
+        }
+    }
+}
+
+#[test]
+fn end_to_end_registered_parser_config() {
+    use preflate_rs::preflate_parse_config::PreflateParserConfig;
+
+    let compressed_data = read_file("compressed_zlib_level6.deflate");
+
+    let (_, summary) =
+        decompress_deflate_streams([&compressed_data[..]], &PreflateConfig::default());
+    assert_eq!(
+        summary.encoder_mix.keys().collect::<Vec<_>>(),
+        ["zlib-slow-128"]
+    );
+
+    // a zlib build with a patched level table, which is considered before the built in levels
+    let mut config = PreflateConfig::default();
+    config.parser_configs.register_slow(PreflateParserConfig {
+        good_length: 8,
+        max_lazy: 16,
+        nice_length: 128,
+        max_chain: 160,
+    });
+
+    let (results, summary) = decompress_deflate_streams([&compressed_data[..]], &config);
+    assert_eq!(
+        summary.encoder_mix.keys().collect::<Vec<_>>(),
+        ["zlib-slow-160"]
+    );
+
+    // the chosen settings are stored in the corrections, so recompressing doesn't need them
+    let r = results.into_iter().next().unwrap().unwrap();
+    let recomp = recompress_deflate_stream(&r.plain_text, &r.cabac_encoded).unwrap();
+    assert_eq!(compressed_data, recomp);
+}
+
+#[test]
+fn end_to_end_compressor_profile() {
+    use preflate_rs::compressor_profile::CompressorProfile;
+    use preflate_rs::preflate_error::PreflateError;
+    use preflate_rs::preflate_parse_config::PreflateParserConfig;
+
+    let compressed_data = read_file("compressed_zlib_level6.deflate");
+
+    let estimated =
+        decompress_deflate_stream_with_config(&compressed_data, &PreflateConfig::default())
+            .unwrap();
+
+    let mut config = PreflateConfig::default();
+    config.compressor_profiles.register(CompressorProfile::zlib(
+        "zlib-6",
+        PreflateParserConfig {
+            good_length: 8,
+            max_lazy: 16,
+            nice_length: 128,
+            max_chain: 128,
+        },
+        false,
+    ));
+
+    // selecting a profile that doesn't exist is an error rather than silently estimating
+    config.compressor_profile = Some("zlib-10".to_string());
+    assert!(matches!(
+        decompress_deflate_stream_with_config(&compressed_data, &config),
+        Err(PreflateError::UnsupportedCompressor { profile, known: false }) if profile == "zlib-10"
+    ));
+
+    config.compressor_profile = Some("zlib-6".to_string());
+    let profiled = decompress_deflate_stream_with_config(&compressed_data, &config).unwrap();
+    assert!(
+        profiled.cabac_encoded.len() <= estimated.cabac_encoded.len() + 16,
+        "profile {} estimated {}",
+        profiled.cabac_encoded.len(),
+        estimated.cabac_encoded.len()
+    );
+
+    // the profile is not needed to recompress
+    let recomp = recompress_deflate_stream(&profiled.plain_text, &profiled.cabac_encoded).unwrap();
+    assert_eq!(compressed_data, recomp);
+}
+
+#[test]
+fn end_to_end_builtin_profiles() {
+    use preflate_rs::preflate_error::PreflateError;
+    use preflate_rs::preflate_parse_config::SLOW_PREFLATE_PARSER_SETTINGS;
+
+    // miniz doesn't use the zlib level table, so the generic estimate is much worse
+    let compressed_data = read_file("compressed_flate2_level6.deflate");
+
+    let matched =
+        decompress_deflate_stream_with_config(&compressed_data, &PreflateConfig::default())
+            .unwrap();
+
+    // registering a parser config turns off matching against the profiles
+    let mut config = PreflateConfig::default();
+    config
+        .parser_configs
+        .register_slow(SLOW_PREFLATE_PARSER_SETTINGS[2]);
+    let estimated = decompress_deflate_stream_with_config(&compressed_data, &config).unwrap();
+
+    assert!(
+        matched.cabac_encoded.len() < estimated.cabac_encoded.len(),
+        "matched {} estimated {}",
+        matched.cabac_encoded.len(),
+        estimated.cabac_encoded.len()
+    );
+
+    let recomp = recompress_deflate_stream(&matched.plain_text, &matched.cabac_encoded).unwrap();
+    assert_eq!(compressed_data, recomp);
+
+    // a stream that has matches the selected profile can't have found is rejected
+    let config = PreflateConfig {
+        compressor_profile: Some("miniz-1".to_string()),
+        ..PreflateConfig::default()
+    };
+    assert!(matches!(
+        decompress_deflate_stream_with_config(&compressed_data, &config),
+        Err(PreflateError::UnsupportedCompressor { profile, known: true }) if profile == "miniz-1"
+    ));
+}
+
+/// streams written by java.util.zip.DeflaterOutputStream on OpenJDK 17 are detected as the
+/// levels of zlib, which the java profiles are aliases of, and selecting those fits them
+#[test]
+fn end_to_end_java_profiles() {
+    for level in [1, 6] {
+        let compressed_data = read_file(&format!("compressed_java_level{}.deflate", level));
+
+        let detected =
+            decompress_deflate_stream_with_config(&compressed_data, &PreflateConfig::default())
+                .unwrap();
+        assert_eq!(detected.profile.name, format!("zlib-{}", level));
+
+        let config = PreflateConfig {
+            compressor_profile: Some(format!("java-{}", level)),
+            ..PreflateConfig::default()
+        };
+        let profiled = decompress_deflate_stream_with_config(&compressed_data, &config).unwrap();
+        assert_eq!(profiled.cabac_encoded, detected.cabac_encoded);
+
+        let recomp =
+            recompress_deflate_stream(&profiled.plain_text, &profiled.cabac_encoded).unwrap();
+        assert_eq!(compressed_data, recomp);
+    }
+}
+
+#[test]
+fn end_to_end_refine_profile() {
+    let compressed_data = read_file("compressed_zlib_level6.deflate");
+
+    // start with a profile that searches much further than the compressor did
+    let config = PreflateConfig {
+        compressor_profile: Some("zlib-9".to_string()),
+        ..PreflateConfig::default()
+    };
+    let start = decompress_deflate_stream_with_config(&compressed_data, &config).unwrap();
+    assert_eq!(start.profile.name, "zlib-9");
+
+    let refinements = start.profile.refinements(&start.statistics);
+    assert!(!refinements.is_empty());
+
+    // one round of the outer loop, some of the suggestions may not fit the stream
+    let best = refinements
+        .into_iter()
+        .filter_map(|profile| {
+            let mut config = PreflateConfig {
+                compressor_profile: Some(profile.name.clone()),
+                ..PreflateConfig::default()
+            };
+            config.compressor_profiles.register(profile);
+            decompress_deflate_stream_with_config(&compressed_data, &config).ok()
+        })
+        .min_by_key(|r| r.cabac_encoded.len())
+        .unwrap();
+
+    assert!(
+        best.cabac_encoded.len() < start.cabac_encoded.len(),
+        "refined {} started with {}",
+        best.cabac_encoded.len(),
+        start.cabac_encoded.len()
+    );
+
+    let recomp = recompress_deflate_stream(&best.plain_text, &best.cabac_encoded).unwrap();
+    assert_eq!(compressed_data, recomp);
+}
+
+#[test]
+fn identify_compressors() {
+    use preflate_rs::{identify::Compressor, identify_deflate_compressor};
+
+    for level in 1..=9 {
+        let zlib = identify_deflate_compressor(&read_file(&format!(
+            "compressed_zlib_level{}.deflate",
+            level
+        )))
+        .unwrap();
+        assert_eq!(zlib.compressor, Compressor::Zlib { level });
+        assert!(zlib.confidence > 0.9, "zlib level {}", level);
+
+        let miniz = identify_deflate_compressor(&read_file(&format!(
+            "compressed_flate2_level{}.deflate",
+            level
+        )))
+        .unwrap();
+        assert_eq!(miniz.compressor, Compressor::Miniz { level });
+        assert_eq!(miniz.profile.unwrap().name, format!("miniz-{}", level));
+    }
+
+    let stored = identify_deflate_compressor(&read_file("compressed_zlib_level0.deflate")).unwrap();
+    assert_eq!(stored.compressor, Compressor::Stored);
+
+    assert!(identify_deflate_compressor(&[0xff, 0xff, 0xff]).is_err());
+}
+
+#[test]
+fn end_to_end_model_dictionary() {
+    use preflate_rs::{preflate_config::CabacBackend, preflate_error::ErrorCode, ModelDictionary};
+    use std::sync::Arc;
+
+    // small streams from the same compressor, which end before the adaptive model has learned much
+    let v = read_file("sample1.bin");
+    let streams: Vec<Vec<u8>> = v
+        .chunks(v.len() / 8)
+        .map(|chunk| {
+            let mut compressed = Vec::new();
+            flate2::read::DeflateEncoder::new(Cursor::new(chunk), Compression::new(6))
+                .read_to_end(&mut compressed)
+                .unwrap();
+            compressed
+        })
+        .collect();
+
+    let dictionary = Arc::new(ModelDictionary::train(streams[..6].iter().map(|s| &s[..])).unwrap());
+    let other_dictionary = Arc::new(ModelDictionary::train([&streams[0][..]]).unwrap());
+    assert_ne!(dictionary.id(), other_dictionary.id());
+
+    for backend in [CabacBackend::Vp8, CabacBackend::Lzma] {
+        let config = PreflateConfig {
+            cabac_backend: backend,
+            ..PreflateConfig::default()
+        };
+        let dictionary_config = PreflateConfig {
+            model_dictionary: Some(dictionary.clone()),
+            ..config.clone()
+        };
+
+        for compressed_data in streams[6..].iter() {
+            let without = decompress_deflate_stream_with_config(compressed_data, &config).unwrap();
+            let with =
+                decompress_deflate_stream_with_config(compressed_data, &dictionary_config).unwrap();
+            println!(
+                "{:?}: without dictionary {} with dictionary {}",
+                backend,
+                without.cabac_encoded.len(),
+                with.cabac_encoded.len()
+            );
+            assert!(with.cabac_encoded.len() < without.cabac_encoded.len());
+
+            let recompressed = recompress_deflate_stream_with_config(
+                &with.plain_text,
+                &with.cabac_encoded,
+                &dictionary_config,
+            )
+            .unwrap();
+            assert!(recompressed == *compressed_data);
+
+            // the corrections can't be recompressed without the same dictionary
+            for wrong_config in [
+                config.clone(),
+                PreflateConfig {
+                    model_dictionary: Some(other_dictionary.clone()),
+                    ..config.clone()
+                },
+            ] {
+                let e = recompress_deflate_stream_with_config(
+                    &with.plain_text,
+                    &with.cabac_encoded,
+                    &wrong_config,
+                )
+                .unwrap_err();
+                assert_eq!(e.error_code(), ErrorCode::RecompressFailed);
+            }
+        }
+    }
+}